@@ -0,0 +1,6 @@
+-- Deleting a user keeps their links around as anonymous links instead of failing on the
+-- foreign key.
+ALTER TABLE
+    "urls" DROP CONSTRAINT "urls_created_by_foreign";
+ALTER TABLE
+    "urls" ADD CONSTRAINT "urls_created_by_foreign" FOREIGN KEY("created_by") REFERENCES "users"("id") ON DELETE SET NULL;
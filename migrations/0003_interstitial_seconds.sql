@@ -0,0 +1,3 @@
+-- NULL means no countdown page, so existing rows don't need a backfill
+ALTER TABLE
+    "urls" ADD COLUMN "interstitial_seconds" SMALLINT NULL;
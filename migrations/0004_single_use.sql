@@ -0,0 +1,5 @@
+-- Both NULL for existing rows: they are not single use and so never consumed
+ALTER TABLE
+    "urls" ADD COLUMN "single_use" BOOLEAN NULL;
+ALTER TABLE
+    "urls" ADD COLUMN "consumed_at" TIMESTAMPTZ NULL;
@@ -0,0 +1,4 @@
+-- no-transaction
+-- Sweeps over anonymous links only have to read these rows
+CREATE INDEX CONCURRENTLY "urls_anonymous_index" ON
+    "urls"("id") WHERE "created_by" IS NULL;
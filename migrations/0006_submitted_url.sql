@@ -0,0 +1,3 @@
+-- The destination as it was typed, kept only when normalizing it dropped something
+ALTER TABLE
+    "urls" ADD COLUMN "submitted_url" TEXT NULL;
@@ -0,0 +1,4 @@
+-- no-transaction
+-- Hash rather than btree since destinations can be longer than a btree entry allows
+CREATE INDEX CONCURRENTLY "urls_longurl_index" ON
+    "urls" USING HASH ("longurl");
@@ -0,0 +1,3 @@
+-- NULL for existing rows: they never expire
+ALTER TABLE
+    "urls" ADD COLUMN "expires_at" TIMESTAMPTZ NULL;
@@ -0,0 +1,3 @@
+-- NULL for existing rows: they have no click limit
+ALTER TABLE
+    "urls" ADD COLUMN "max_clicks" BIGINT NULL;
@@ -0,0 +1,3 @@
+-- Salted hash of the passphrase visitors have to give. NULL for links anyone can open.
+ALTER TABLE
+    "urls" ADD COLUMN "passphrase_hash" TEXT NULL;
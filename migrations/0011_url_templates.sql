@@ -0,0 +1,29 @@
+-- Families of codes like inv-{customer} that redirect to a destination with the captured value
+-- filled in. Codes match prefix + value + suffix.
+CREATE TABLE "url_templates"(
+    "id" bigserial NOT NULL,
+    "owner" BIGINT NOT NULL,
+    "prefix" TEXT NOT NULL,
+    "placeholder" TEXT NOT NULL,
+    "suffix" TEXT NOT NULL,
+    "destination" TEXT NOT NULL,
+    "value_pattern" TEXT NULL,
+    "max_value_len" INTEGER NOT NULL,
+    "clicks" BIGINT NOT NULL DEFAULT 0
+);
+ALTER TABLE
+    "url_templates" ADD PRIMARY KEY("id");
+ALTER TABLE
+    "url_templates" ADD CONSTRAINT "url_templates_owner_foreign" FOREIGN KEY("owner") REFERENCES "users"("id") ON DELETE CASCADE;
+CREATE INDEX "url_templates_owner_index" ON
+    "url_templates"("owner");
+-- Clicks on each value a template was opened with
+CREATE TABLE "url_template_clicks"(
+    "template_id" BIGINT NOT NULL,
+    "value" TEXT NOT NULL,
+    "clicks" BIGINT NOT NULL
+);
+ALTER TABLE
+    "url_template_clicks" ADD PRIMARY KEY("template_id", "value");
+ALTER TABLE
+    "url_template_clicks" ADD CONSTRAINT "url_template_clicks_template_id_foreign" FOREIGN KEY("template_id") REFERENCES "url_templates"("id") ON DELETE CASCADE;
@@ -0,0 +1,5 @@
+-- When the link was made. Links from before this migration keep NULL, since their age is unknown.
+ALTER TABLE
+    "urls" ADD COLUMN "created_at" TIMESTAMPTZ NULL;
+ALTER TABLE
+    "urls" ALTER COLUMN "created_at" SET DEFAULT now();
@@ -0,0 +1,10 @@
+-- Clicks on each link per UTC day, next to the running total in urls.clicks
+CREATE TABLE "url_clicks_daily"(
+    "url_id" BIGINT NOT NULL,
+    "day" DATE NOT NULL,
+    "count" BIGINT NOT NULL
+);
+ALTER TABLE
+    "url_clicks_daily" ADD PRIMARY KEY("url_id", "day");
+ALTER TABLE
+    "url_clicks_daily" ADD CONSTRAINT "url_clicks_daily_url_id_foreign" FOREIGN KEY("url_id") REFERENCES "urls"("id") ON DELETE CASCADE;
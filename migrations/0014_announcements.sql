@@ -0,0 +1,13 @@
+-- Messages shown as a banner on the site between starts_at and ends_at. ends_at NULL means until
+-- removed. Non public ones are only shown to signed in users.
+CREATE TABLE "announcements"(
+    "id" bigserial NOT NULL,
+    "message" TEXT NOT NULL,
+    "severity" TEXT NOT NULL CHECK ("severity" IN ('info', 'warning', 'critical')),
+    "starts_at" TIMESTAMPTZ NOT NULL DEFAULT now(),
+    "ends_at" TIMESTAMPTZ NULL,
+    "dismissible" BOOLEAN NOT NULL DEFAULT TRUE,
+    "public" BOOLEAN NOT NULL DEFAULT FALSE
+);
+ALTER TABLE
+    "announcements" ADD PRIMARY KEY("id");
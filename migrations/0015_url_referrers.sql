@@ -0,0 +1,10 @@
+-- Clicks on each link per referring origin, 'direct' for visits without a usable Referer
+CREATE TABLE "url_referrers"(
+    "url_id" BIGINT NOT NULL,
+    "referrer" TEXT NOT NULL,
+    "count" BIGINT NOT NULL
+);
+ALTER TABLE
+    "url_referrers" ADD PRIMARY KEY("url_id", "referrer");
+ALTER TABLE
+    "url_referrers" ADD CONSTRAINT "url_referrers_url_id_foreign" FOREIGN KEY("url_id") REFERENCES "urls"("id") ON DELETE CASCADE;
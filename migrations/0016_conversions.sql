@@ -0,0 +1,33 @@
+-- Clicks with the visitor who made them, as a hash that changes every UTC day. Only kept for the
+-- attribution window, to match conversions against.
+CREATE TABLE "url_click_visitors"(
+    "id" bigserial NOT NULL,
+    "url_id" BIGINT NOT NULL,
+    "visitor" TEXT NOT NULL,
+    "clicked_at" TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+ALTER TABLE
+    "url_click_visitors" ADD PRIMARY KEY("id");
+ALTER TABLE
+    "url_click_visitors" ADD CONSTRAINT "url_click_visitors_url_id_foreign" FOREIGN KEY("url_id") REFERENCES "urls"("id") ON DELETE CASCADE;
+CREATE INDEX "url_click_visitors_url_id_visitor_index" ON
+    "url_click_visitors"("url_id", "visitor", "clicked_at");
+CREATE INDEX "url_click_visitors_clicked_at_index" ON
+    "url_click_visitors"("clicked_at");
+-- A click followed by the conversion pixel. click_id has no foreign key since clicks are pruned
+-- long before their conversions are.
+CREATE TABLE "url_conversions"(
+    "id" bigserial NOT NULL,
+    "url_id" BIGINT NOT NULL,
+    "click_id" BIGINT NOT NULL,
+    "clicked_at" TIMESTAMPTZ NOT NULL,
+    "converted_at" TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+ALTER TABLE
+    "url_conversions" ADD PRIMARY KEY("id");
+ALTER TABLE
+    "url_conversions" ADD CONSTRAINT "url_conversions_click_id_unique" UNIQUE("click_id");
+ALTER TABLE
+    "url_conversions" ADD CONSTRAINT "url_conversions_url_id_foreign" FOREIGN KEY("url_id") REFERENCES "urls"("id") ON DELETE CASCADE;
+CREATE INDEX "url_conversions_url_id_index" ON
+    "url_conversions"("url_id");
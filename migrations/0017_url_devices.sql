@@ -0,0 +1,10 @@
+-- Clicks on each link per kind of client: desktop, mobile, bot or other
+CREATE TABLE "url_devices"(
+    "url_id" BIGINT NOT NULL,
+    "device" TEXT NOT NULL,
+    "count" BIGINT NOT NULL
+);
+ALTER TABLE
+    "url_devices" ADD PRIMARY KEY("url_id", "device");
+ALTER TABLE
+    "url_devices" ADD CONSTRAINT "url_devices_url_id_foreign" FOREIGN KEY("url_id") REFERENCES "urls"("id") ON DELETE CASCADE;
@@ -0,0 +1,4 @@
+-- Status the link redirects with when it shouldn't use the instance's redirect_status. NULL for
+-- existing rows, which keep following the instance.
+ALTER TABLE
+    "urls" ADD COLUMN "redirect_status" SMALLINT NULL;
@@ -0,0 +1,4 @@
+-- no-transaction
+-- Lookups when case_insensitive_slugs is on compare lowercased codes
+CREATE INDEX CONCURRENTLY "urls_shorturl_lower_index" ON
+    "urls"(LOWER("shorturl"));
@@ -0,0 +1,2 @@
+-- Values for codes made with slug_strategy = "sequential"
+CREATE SEQUENCE "urls_code_seq" AS BIGINT START 1;
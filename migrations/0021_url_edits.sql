@@ -0,0 +1,21 @@
+-- Changes owners made to their links, kept for the link's history. edited_by is cleared rather
+-- than the edit deleted when the user is.
+CREATE TABLE "url_edits"(
+    "id" bigserial NOT NULL,
+    "url_id" BIGINT NOT NULL,
+    "edited_by" BIGINT,
+    "field" TEXT NOT NULL,
+    "old_value" TEXT,
+    "new_value" TEXT,
+    "edited_at" TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+ALTER TABLE
+    "url_edits" ADD PRIMARY KEY("id");
+ALTER TABLE
+    "url_edits" ADD CONSTRAINT "url_edits_url_id_foreign" FOREIGN KEY("url_id") REFERENCES "urls"("id") ON DELETE CASCADE;
+ALTER TABLE
+    "url_edits" ADD CONSTRAINT "url_edits_edited_by_foreign" FOREIGN KEY("edited_by") REFERENCES "users"("id") ON DELETE SET NULL;
+CREATE INDEX "url_edits_url_id_index" ON
+    "url_edits"("url_id", "edited_at");
+CREATE INDEX "url_edits_edited_at_index" ON
+    "url_edits"("edited_at");
@@ -0,0 +1,7 @@
+-- Backstop for url_db::MAX_LONG_URL_LEN; the handlers refuse long destinations before this. NOT
+-- VALID, so existing rows aren't scanned (and any oversized ones don't stop the migration), but
+-- every insert and update is checked from now on.
+ALTER TABLE
+    "urls" ADD CONSTRAINT "urls_longurl_length" CHECK (char_length("longurl") <= 32768) NOT VALID;
+ALTER TABLE
+    "urls" ADD CONSTRAINT "urls_submitted_url_length" CHECK (char_length("submitted_url") <= 32768) NOT VALID;
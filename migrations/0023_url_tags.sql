@@ -0,0 +1,12 @@
+-- Categories owners file their links under, already normalized by tags::parse. Deleted with the
+-- link.
+CREATE TABLE "url_tags"(
+    "url_id" BIGINT NOT NULL,
+    "tag" TEXT NOT NULL
+);
+ALTER TABLE
+    "url_tags" ADD PRIMARY KEY("url_id", "tag");
+ALTER TABLE
+    "url_tags" ADD CONSTRAINT "url_tags_url_id_foreign" FOREIGN KEY("url_id") REFERENCES "urls"("id") ON DELETE CASCADE;
+CREATE INDEX "url_tags_tag_index" ON
+    "url_tags"("tag");
@@ -0,0 +1,7 @@
+-- Free text notes owners keep on their links, never shown to visitors. The handlers clean and
+-- limit it to url_db::MAX_DESCRIPTION_LEN; the constraint is the backstop, NOT VALID as the column
+-- starts out empty.
+ALTER TABLE
+    "urls" ADD COLUMN "description" TEXT NULL;
+ALTER TABLE
+    "urls" ADD CONSTRAINT "urls_description_length" CHECK (char_length("description") <= 500) NOT VALID;
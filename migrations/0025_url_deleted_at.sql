@@ -0,0 +1,4 @@
+-- Set when a link is archived instead of deleted. Archived links stop redirecting but keep their
+-- clicks and their code until they are restored or purged.
+ALTER TABLE
+    "urls" ADD COLUMN "deleted_at" TIMESTAMPTZ NULL;
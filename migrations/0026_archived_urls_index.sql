@@ -0,0 +1,4 @@
+-- no-transaction
+-- Purging archived links past their retention only has to read these rows
+CREATE INDEX CONCURRENTLY "urls_archived_index" ON
+    "urls"("deleted_at") WHERE "deleted_at" IS NOT NULL;
@@ -0,0 +1,25 @@
+-- The destinations of links that rotate between several, each picked in proportion to its weight
+-- and counting the clicks it was picked for. Deleted with the link. urls.longurl stays the first
+-- of them, so listings and duplicate checks keep working.
+CREATE TABLE "url_destinations"(
+    "id" bigserial NOT NULL,
+    "url_id" BIGINT NOT NULL,
+    "position" INTEGER NOT NULL,
+    "longurl" TEXT NOT NULL,
+    "weight" INTEGER NOT NULL,
+    "clicks" BIGINT NOT NULL DEFAULT 0
+);
+ALTER TABLE
+    "url_destinations" ADD PRIMARY KEY("id");
+ALTER TABLE
+    "url_destinations" ADD CONSTRAINT "url_destinations_url_id_foreign" FOREIGN KEY("url_id") REFERENCES "urls"("id") ON DELETE CASCADE;
+ALTER TABLE
+    "url_destinations" ADD CONSTRAINT "url_destinations_weight_positive" CHECK ("weight" > 0);
+ALTER TABLE
+    "url_destinations" ADD CONSTRAINT "url_destinations_longurl_length" CHECK (char_length("longurl") <= 32768);
+CREATE INDEX "url_destinations_url_id_index" ON
+    "url_destinations"("url_id", "position");
+-- Set on links with rows above, so redirecting every other link needs no lookup of them. NULL for
+-- existing rows: none of them rotate.
+ALTER TABLE
+    "urls" ADD COLUMN "rotates" BOOLEAN NULL;
@@ -0,0 +1,30 @@
+-- Destinations a link sends visitors from one country to instead of its long url, keyed on the
+-- ISO 3166-1 alpha-2 code in uppercase. Deleted with the link.
+CREATE TABLE "url_geo_targets"(
+    "url_id" BIGINT NOT NULL,
+    "country" TEXT NOT NULL,
+    "longurl" TEXT NOT NULL
+);
+ALTER TABLE
+    "url_geo_targets" ADD PRIMARY KEY("url_id", "country");
+ALTER TABLE
+    "url_geo_targets" ADD CONSTRAINT "url_geo_targets_url_id_foreign" FOREIGN KEY("url_id") REFERENCES "urls"("id") ON DELETE CASCADE;
+ALTER TABLE
+    "url_geo_targets" ADD CONSTRAINT "url_geo_targets_country_code" CHECK ("country" ~ '^[A-Z]{2}$');
+ALTER TABLE
+    "url_geo_targets" ADD CONSTRAINT "url_geo_targets_longurl_length" CHECK (char_length("longurl") <= 32768);
+-- Clicks on geo-targeted links by the branch they took: the country whose destination they went
+-- to, or `default` for the long url.
+CREATE TABLE "url_geo_clicks"(
+    "url_id" BIGINT NOT NULL,
+    "branch" TEXT NOT NULL,
+    "count" BIGINT NOT NULL
+);
+ALTER TABLE
+    "url_geo_clicks" ADD PRIMARY KEY("url_id", "branch");
+ALTER TABLE
+    "url_geo_clicks" ADD CONSTRAINT "url_geo_clicks_url_id_foreign" FOREIGN KEY("url_id") REFERENCES "urls"("id") ON DELETE CASCADE;
+-- Set on links with rows in url_geo_targets, so redirecting every other link needs neither the
+-- visitor's country nor a lookup of them. NULL for existing rows: none of them are targeted.
+ALTER TABLE
+    "urls" ADD COLUMN "geo_targeted" BOOLEAN NULL;
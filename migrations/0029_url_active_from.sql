@@ -0,0 +1,4 @@
+-- When a link scheduled ahead of its launch starts redirecting. NULL for links that redirect from
+-- the start, which is every existing row.
+ALTER TABLE
+    "urls" ADD COLUMN "active_from" TIMESTAMPTZ NULL;
@@ -0,0 +1,4 @@
+-- Visits from crawlers, link unfurlers and scripts, counted apart from clicks so those stay the
+-- visits of people. NULL until a bot opens the link; bot visits from before this are in clicks.
+ALTER TABLE
+    "urls" ADD COLUMN "bot_clicks" BIGINT NULL;
@@ -0,0 +1,4 @@
+-- When a link rolling out a new destination to part of its visitors sends everyone there. NULL
+-- for links that aren't rolling one out, which is every existing row.
+ALTER TABLE
+    "urls" ADD COLUMN "rollout_until" TIMESTAMPTZ NULL;
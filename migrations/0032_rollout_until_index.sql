@@ -0,0 +1,4 @@
+-- no-transaction
+-- Finishing rollouts that are due only has to read the links rolling one out
+CREATE INDEX CONCURRENTLY "urls_rollout_until_index" ON
+    "urls"("rollout_until") WHERE "rollout_until" IS NOT NULL;
@@ -0,0 +1,34 @@
+-- CSV imports too large to run while the upload waits. The file is spooled to `path` and imported
+-- in batches, each updating the counts, so `processed` is where an interrupted import resumes.
+CREATE TABLE "import_jobs"(
+    "id" bigserial NOT NULL,
+    "user_id" BIGINT NOT NULL,
+    "path" TEXT NOT NULL,
+    "status" TEXT NOT NULL DEFAULT 'queued' CHECK (
+        "status" IN ('queued', 'running', 'finished', 'cancelled', 'failed')
+    ),
+    "parsed" BIGINT NULL,
+    "processed" BIGINT NOT NULL DEFAULT 0,
+    "imported" BIGINT NOT NULL DEFAULT 0,
+    "skipped" BIGINT NOT NULL DEFAULT 0,
+    "invalid" BIGINT NOT NULL DEFAULT 0,
+    "cancel_requested" BOOLEAN NOT NULL DEFAULT FALSE,
+    "error" TEXT NULL,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT now(),
+    "finished_at" TIMESTAMPTZ NULL
+);
+ALTER TABLE
+    "import_jobs" ADD PRIMARY KEY("id");
+ALTER TABLE
+    "import_jobs" ADD CONSTRAINT "import_jobs_user_id_foreign" FOREIGN KEY("user_id") REFERENCES "users"("id") ON DELETE CASCADE;
+-- The rows of an import that weren't imported, with why
+CREATE TABLE "import_job_errors"(
+    "job_id" BIGINT NOT NULL,
+    "line" BIGINT NOT NULL,
+    "outcome" TEXT NOT NULL CHECK ("outcome" IN ('skipped', 'invalid')),
+    "reason" TEXT NOT NULL
+);
+CREATE INDEX "import_job_errors_job_id_index" ON
+    "import_job_errors"("job_id", "line");
+ALTER TABLE
+    "import_job_errors" ADD CONSTRAINT "import_job_errors_job_id_foreign" FOREIGN KEY("job_id") REFERENCES "import_jobs"("id") ON DELETE CASCADE;
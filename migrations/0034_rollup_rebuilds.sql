@@ -0,0 +1,21 @@
+-- Rebuilds of url_clicks_daily from the clicks in url_click_visitors, one day at a time.
+-- next_day is the first day not rebuilt yet, so an interrupted rebuild resumes from it. Only one
+-- link or the links of one user are rebuilt when url_id or user_id is set, every link otherwise.
+CREATE TABLE "rollup_rebuilds"(
+    "id" bigserial NOT NULL,
+    "url_id" BIGINT NULL,
+    "user_id" BIGINT NULL,
+    "from_day" DATE NOT NULL,
+    "to_day" DATE NOT NULL,
+    "next_day" DATE NOT NULL,
+    "started_by" TEXT NOT NULL,
+    "started_at" TIMESTAMPTZ NOT NULL DEFAULT now(),
+    "finished_at" TIMESTAMPTZ NULL,
+    CHECK ("from_day" <= "to_day")
+);
+ALTER TABLE
+    "rollup_rebuilds" ADD PRIMARY KEY("id");
+ALTER TABLE
+    "rollup_rebuilds" ADD CONSTRAINT "rollup_rebuilds_url_id_foreign" FOREIGN KEY("url_id") REFERENCES "urls"("id") ON DELETE CASCADE;
+ALTER TABLE
+    "rollup_rebuilds" ADD CONSTRAINT "rollup_rebuilds_user_id_foreign" FOREIGN KEY("user_id") REFERENCES "users"("id") ON DELETE CASCADE;
@@ -0,0 +1,12 @@
+-- Click counters of hot links, split over several rows so concurrent clicks on one link don't all
+-- wait on the lock of its row in urls. A link is hot while it has shards; its clicks are
+-- urls.clicks plus the clicks of its shards, which are folded back into urls.clicks every so often.
+CREATE TABLE "url_click_shards"(
+    "url_id" BIGINT NOT NULL,
+    "shard" INTEGER NOT NULL,
+    "clicks" BIGINT NOT NULL DEFAULT 0
+);
+ALTER TABLE
+    "url_click_shards" ADD PRIMARY KEY("url_id", "shard");
+ALTER TABLE
+    "url_click_shards" ADD CONSTRAINT "url_click_shards_url_id_foreign" FOREIGN KEY("url_id") REFERENCES "urls"("id") ON DELETE CASCADE;
@@ -0,0 +1,282 @@
+//! Structural accessibility checks over every page the server renders or serves from html/. Not a
+//! full validator: each rule is a regex over the markup, which is enough for the templates here
+//! since they are written with double quoted attributes.
+
+use std::fs;
+
+use askama::Template;
+use regex::Regex;
+
+use crate::{
+    appearance::Appearance,
+    history::EditView,
+    theme,
+    url_db::UrlRow,
+    url_view::{UrlRowView, Viewer},
+    AnnouncementTemplate, ConsumedTemplate, CountdownTemplate, ExhaustedTemplate, ExpiredTemplate,
+    LinkHistoryTemplate, PassphraseTemplate, PendingTemplate, PreviewTemplate, SingleUseTemplate,
+};
+
+/// Pieces of a page rather than whole documents, so the document rules don't apply to them
+const FRAGMENTS: [&str; 4] = [
+    "announcement.html",
+    "appearance-toggle.html",
+    "link-history.html",
+    "url-table-row.html",
+];
+/// Layouts that only render through the pages extending them
+const LAYOUTS: [&str; 1] = ["base.html"];
+/// Pages served as is from html/. navbar.html is a fragment fetched into the others.
+const STATIC_PAGES: [&str; 4] = ["404.html", "announcements.html", "index.html", "login.html"];
+
+/// The value of the `name` attribute on `tag`, if it has one
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    Regex::new(&format!(r#"\s{name}="([^"]*)""#))
+        .unwrap()
+        .captures(tag)
+        .map(|found| found.get(1).unwrap().as_str())
+}
+
+/// Every rule `html` breaks, one line each. `document` adds the rules for whole pages.
+fn problems(html: &str, document: bool) -> Vec<String> {
+    let mut problems = Vec::new();
+    let tags = |name: &str| -> Vec<String> {
+        Regex::new(&format!(r"<{name}\b[^>]*>"))
+            .unwrap()
+            .find_iter(html)
+            .map(|tag| tag.as_str().to_string())
+            .collect()
+    };
+    let labelled: Vec<String> = tags("label")
+        .iter()
+        .filter_map(|label| attribute(label, "for").map(String::from))
+        .collect();
+
+    for field in ["input", "select", "textarea"].into_iter().flat_map(tags) {
+        let kind = attribute(&field, "type").unwrap_or("text");
+        if ["hidden", "submit", "button", "reset"].contains(&kind)
+            || attribute(&field, "aria-label").is_some()
+        {
+            continue;
+        }
+        match attribute(&field, "id") {
+            Some(id) if labelled.iter().any(|label| label == id) => {}
+            _ => problems.push(format!("no label for {field}")),
+        }
+    }
+    for image in tags("img") {
+        if attribute(&image, "alt").is_none() {
+            problems.push(format!("no alt text on {image}"));
+        }
+    }
+    for header in tags("th") {
+        if attribute(&header, "scope").is_none() {
+            problems.push(format!("no scope on {header}"));
+        }
+    }
+    if document {
+        if tags("html")
+            .first()
+            .and_then(|root| attribute(root, "lang"))
+            .is_none_or(str::is_empty)
+        {
+            problems.push(String::from("no lang on <html>"));
+        }
+        let headings = tags("h1").len();
+        if headings != 1 {
+            problems.push(format!("{headings} <h1> instead of one"));
+        }
+        if tags("main").len() != 1 {
+            problems.push(String::from("no single <main> landmark"));
+        }
+        if !tags("a").iter().any(|link| {
+            attribute(link, "class") == Some("skip-link")
+                && attribute(link, "href") == Some("#main")
+        }) {
+            problems.push(String::from("no skip link to #main"));
+        }
+    }
+    problems
+}
+
+/// Every template in templates/, rendered with fixture data, as (file, html)
+fn rendered_templates() -> Vec<(&'static str, String)> {
+    let appearance = Appearance::default();
+    let back = || String::from("/abc");
+    let brand = || theme::Brand::new("Acme", "Links by Acme");
+    let row = UrlRow::test_row("abc", "https://example.com").with_clicks(3);
+    let edits = [EditView {
+        field: String::from("destination"),
+        old_value: Some(String::from("https://example.com")),
+        new_value: Some(String::from("https://example.org")),
+        edited_by: None,
+        edited_at: chrono::Utc::now(),
+    }];
+    vec![
+        (
+            "countdown.html",
+            CountdownTemplate {
+                target: "https://example.com",
+                seconds: 3,
+                appearance,
+                brand: brand(),
+            }
+            .render(),
+        ),
+        (
+            "single-use.html",
+            SingleUseTemplate {
+                short_url: "abc",
+                appearance,
+                brand: brand(),
+            }
+            .render(),
+        ),
+        (
+            "consumed.html",
+            ConsumedTemplate {
+                at: String::from("2026-01-01 00:00 UTC"),
+                appearance,
+                brand: brand(),
+                back: back(),
+            }
+            .render(),
+        ),
+        (
+            "passphrase.html",
+            PassphraseTemplate {
+                short_url: "abc",
+                appearance,
+                brand: brand(),
+                wrong: true,
+            }
+            .render(),
+        ),
+        (
+            "exhausted.html",
+            ExhaustedTemplate {
+                max_clicks: 5,
+                appearance,
+                brand: brand(),
+                back: back(),
+            }
+            .render(),
+        ),
+        (
+            "preview.html",
+            PreviewTemplate {
+                short_url: "abc",
+                destination: Some("https://example.com"),
+                clicks: 1,
+                appearance,
+                brand: brand(),
+                back: back(),
+            }
+            .render(),
+        ),
+        (
+            "expired.html",
+            ExpiredTemplate {
+                at: String::from("2026-01-01 00:00 UTC"),
+                appearance,
+                brand: brand(),
+                back: back(),
+            }
+            .render(),
+        ),
+        (
+            "pending.html",
+            PendingTemplate {
+                at: String::from("2026-01-01 00:00 UTC"),
+                appearance,
+                brand: brand(),
+                back: back(),
+            }
+            .render(),
+        ),
+        (
+            "announcement.html",
+            AnnouncementTemplate {
+                id: 1,
+                message: "Maintenance tonight",
+                severity: "info",
+                dismissible: true,
+            }
+            .render(),
+        ),
+        (
+            "link-history.html",
+            LinkHistoryTemplate {
+                short_url: "abc",
+                edits: &edits,
+            }
+            .render(),
+        ),
+        (
+            "url-table-row.html",
+            UrlRowView::assemble(&row, "sho.rt", Viewer::Owner).render(),
+        ),
+    ]
+    .into_iter()
+    .map(|(file, html)| (file, html.unwrap()))
+    .collect()
+}
+
+#[test]
+fn every_template_is_checked() {
+    let rendered: Vec<&str> = rendered_templates().iter().map(|(file, _)| *file).collect();
+    for entry in fs::read_dir("templates").unwrap() {
+        let file = entry.unwrap().file_name().into_string().unwrap();
+        // Included into the pages, so checked through them
+        let included = file == "appearance-toggle.html";
+        assert!(
+            rendered.contains(&file.as_str()) || LAYOUTS.contains(&file.as_str()) || included,
+            "{file} isn't rendered by rendered_templates()"
+        );
+    }
+}
+
+#[test]
+fn templates_pass() {
+    for (file, html) in rendered_templates() {
+        let document = !FRAGMENTS.contains(&file);
+        assert_eq!(problems(&html, document), Vec::<String>::new(), "{file}");
+    }
+}
+
+#[test]
+fn static_pages_pass() {
+    for file in STATIC_PAGES {
+        let html = fs::read_to_string(format!("html/{file}")).unwrap();
+        assert_eq!(problems(&html, true), Vec::<String>::new(), "{file}");
+    }
+}
+
+#[test]
+fn default_theme_is_readable() {
+    assert_eq!(
+        theme::contrast_problems(&Default::default()),
+        Vec::<String>::new()
+    );
+}
+
+#[test]
+fn catches_broken_markup() {
+    let html = "<html><body><h1>One</h1><h1>Two</h1>\
+        <input type=\"text\" id=\"unlabelled\"><img src=\"/logo.png\"><th>Name</th></body></html>";
+    assert_eq!(
+        problems(html, true),
+        [
+            "no label for <input type=\"text\" id=\"unlabelled\">",
+            "no alt text on <img src=\"/logo.png\">",
+            "no scope on <th>",
+            "no lang on <html>",
+            "2 <h1> instead of one",
+            "no single <main> landmark",
+            "no skip link to #main",
+        ]
+    );
+    let labelled = "<label for=\"name\">Name</label><input type=\"text\" id=\"name\">\
+        <input type=\"hidden\" name=\"back\"><input aria-label=\"Search\">";
+    assert_eq!(problems(labelled, false), Vec::<String>::new());
+}
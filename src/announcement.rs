@@ -0,0 +1,428 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use axum::http::{header::COOKIE, HeaderMap};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgExecutor, PgPool};
+
+use crate::{dry_run::Executor, locked::ReadMostly};
+
+/// Ids of the announcements a visitor dismissed, separated by dots
+pub const DISMISSED_COOKIE_NAME: &str = "dismissed_announcements";
+/// Only the most recent dismissals are kept, older announcements have long ended by then
+const MAX_DISMISSED: usize = 20;
+const DISMISSED_COOKIE_MAX_AGE: u32 = 60 * 60 * 24 * 365;
+/// Longest message an announcement can have, it has to fit in a banner
+pub const MAX_MESSAGE_LEN: usize = 500;
+
+/// How loud the banner is. Each has its own class in `/theme.css`. When several announcements are
+/// active at once the most severe one is shown.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+    fn parse(value: &str) -> Self {
+        match value {
+            "warning" => Severity::Warning,
+            "critical" => Severity::Critical,
+            _ => Severity::Info,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Announcement {
+    id: i64,
+    message: String,
+    severity: Severity,
+    starts_at: DateTime<Utc>,
+    ends_at: Option<DateTime<Utc>>,
+    dismissible: bool,
+    /// Shown to signed out visitors as well
+    public: bool,
+}
+
+impl Announcement {
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+    pub fn severity(&self) -> Severity {
+        self.severity
+    }
+    pub fn dismissible(&self) -> bool {
+        self.dismissible
+    }
+    fn is_live(&self, now: DateTime<Utc>) -> bool {
+        self.starts_at <= now && self.ends_at.is_none_or(|ends_at| now < ends_at)
+    }
+}
+
+/// The row as stored, severity being plain text
+struct AnnouncementRow {
+    id: i64,
+    message: String,
+    severity: String,
+    starts_at: DateTime<Utc>,
+    ends_at: Option<DateTime<Utc>>,
+    dismissible: bool,
+    public: bool,
+}
+
+impl From<AnnouncementRow> for Announcement {
+    fn from(row: AnnouncementRow) -> Self {
+        Announcement {
+            id: row.id,
+            message: row.message,
+            severity: Severity::parse(&row.severity),
+            starts_at: row.starts_at,
+            ends_at: row.ends_at,
+            dismissible: row.dismissible,
+            public: row.public,
+        }
+    }
+}
+
+/// An announcement as admins write it. Creating and editing both take every field.
+#[derive(Deserialize, Debug)]
+pub struct AnnouncementInput {
+    message: String,
+    severity: Severity,
+    /// Defaults to now
+    #[serde(default)]
+    starts_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    ends_at: Option<DateTime<Utc>>,
+    #[serde(default = "dismissible_by_default")]
+    dismissible: bool,
+    #[serde(default)]
+    public: bool,
+}
+
+fn dismissible_by_default() -> bool {
+    true
+}
+
+impl AnnouncementInput {
+    /// Why the announcement can't be saved, if it can't
+    pub fn problem(&self, now: DateTime<Utc>) -> Option<String> {
+        let message = self.message.trim();
+        if message.is_empty() {
+            return Some(String::from("The message is empty"));
+        }
+        if message.chars().count() > MAX_MESSAGE_LEN {
+            return Some(format!(
+                "The message is longer than {MAX_MESSAGE_LEN} characters"
+            ));
+        }
+        let starts_at = self.starts_at.unwrap_or(now);
+        if self.ends_at.is_some_and(|ends_at| ends_at <= starts_at) {
+            return Some(String::from("The announcement has to end after it starts"));
+        }
+        None
+    }
+}
+
+/// The announcements that haven't ended, loaded once and kept until an admin changes something, so
+/// showing the banner doesn't cost a query per page
+#[derive(Default)]
+pub struct Announcements {
+    cached: ReadMostly<Option<Arc<Vec<Announcement>>>>,
+    /// Bumped by every invalidation, so a load that raced with one isn't cached
+    generation: AtomicU64,
+}
+
+impl Announcements {
+    /// The one to show right now, or None. `dismissed` is what [dismissed_ids] found.
+    pub async fn current(
+        &self,
+        signed_in: bool,
+        dismissed: &[i64],
+        pool: &PgPool,
+    ) -> Result<Option<Announcement>, sqlx::Error> {
+        let cached = self.cached.read(Option::clone);
+        let announcements = match cached {
+            Some(announcements) => announcements,
+            None => {
+                let generation = self.generation.load(Ordering::Acquire);
+                let loaded = Arc::new(unended(pool).await?);
+                self.cached.write(|cached| {
+                    if self.generation.load(Ordering::Acquire) == generation {
+                        *cached = Some(loaded.clone());
+                    }
+                });
+                loaded
+            }
+        };
+        Ok(active(&announcements, Utc::now(), signed_in, dismissed).cloned())
+    }
+
+    /// Drops the cached announcements so the next page loads them again
+    pub fn invalidate(&self) {
+        self.cached.write(|cached| {
+            self.generation.fetch_add(1, Ordering::AcqRel);
+            *cached = None;
+        });
+    }
+}
+
+/// Picks the announcement to show at `now` from `announcements`: the most severe of the ones
+/// running, newest first among equals, leaving out dismissed ones and, for signed out visitors,
+/// the ones that aren't public
+pub fn active<'a>(
+    announcements: &'a [Announcement],
+    now: DateTime<Utc>,
+    signed_in: bool,
+    dismissed: &[i64],
+) -> Option<&'a Announcement> {
+    announcements
+        .iter()
+        .filter(|announcement| announcement.is_live(now))
+        .filter(|announcement| signed_in || announcement.public)
+        .filter(|announcement| !(announcement.dismissible && dismissed.contains(&announcement.id)))
+        .max_by_key(|announcement| (announcement.severity, announcement.starts_at))
+}
+
+/// The announcement ids in the visitor's dismissal cookie
+pub fn dismissed_ids(headers: &HeaderMap) -> Vec<i64> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == DISMISSED_COOKIE_NAME)
+        .map(|(_, value)| value.split('.').filter_map(|id| id.parse().ok()).collect())
+        .unwrap_or_default()
+}
+
+/// The dismissal cookie with `id` added to the ones already dismissed
+pub fn dismiss_cookie(mut dismissed: Vec<i64>, id: i64) -> String {
+    dismissed.retain(|dismissed| *dismissed != id);
+    dismissed.push(id);
+    let skip = dismissed.len().saturating_sub(MAX_DISMISSED);
+    let ids: Vec<String> = dismissed[skip..].iter().map(i64::to_string).collect();
+    format!(
+        "{DISMISSED_COOKIE_NAME}={}; Max-Age={DISMISSED_COOKIE_MAX_AGE}; Path=/; Secure; HttpOnly; \
+         SameSite=Lax",
+        ids.join(".")
+    )
+}
+
+/// Every announcement, most recently started first
+pub async fn list(db: impl PgExecutor<'_>) -> Result<Vec<Announcement>, sqlx::Error> {
+    let rows = sqlx::query_as!(
+        AnnouncementRow,
+        "SELECT id, message, severity, starts_at, ends_at, dismissible, public
+        FROM announcements ORDER BY starts_at DESC, id DESC"
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows.into_iter().map(Announcement::from).collect())
+}
+
+/// The announcements that are running or still to come
+async fn unended(db: impl PgExecutor<'_>) -> Result<Vec<Announcement>, sqlx::Error> {
+    let rows = sqlx::query_as!(
+        AnnouncementRow,
+        "SELECT id, message, severity, starts_at, ends_at, dismissible, public
+        FROM announcements WHERE ends_at IS NULL OR ends_at > now()"
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows.into_iter().map(Announcement::from).collect())
+}
+
+pub async fn create(
+    input: &AnnouncementInput,
+    db: impl PgExecutor<'_>,
+) -> Result<Announcement, sqlx::Error> {
+    let row = sqlx::query_as!(
+        AnnouncementRow,
+        "INSERT INTO announcements (message, severity, starts_at, ends_at, dismissible, public)
+        VALUES ($1, $2, COALESCE($3, now()), $4, $5, $6)
+        RETURNING id, message, severity, starts_at, ends_at, dismissible, public",
+        input.message.trim(),
+        input.severity.as_str(),
+        input.starts_at,
+        input.ends_at,
+        input.dismissible,
+        input.public
+    )
+    .fetch_one(db)
+    .await?;
+    Ok(row.into())
+}
+
+/// Replaces every field of an announcement. Returns None if there is no announcement `id`.
+pub async fn update(
+    id: i64,
+    input: &AnnouncementInput,
+    db: impl PgExecutor<'_>,
+) -> Result<Option<Announcement>, sqlx::Error> {
+    let row = sqlx::query_as!(
+        AnnouncementRow,
+        "UPDATE announcements
+        SET message = $2, severity = $3, starts_at = COALESCE($4, now()), ends_at = $5,
+            dismissible = $6, public = $7
+        WHERE id = $1
+        RETURNING id, message, severity, starts_at, ends_at, dismissible, public",
+        id,
+        input.message.trim(),
+        input.severity.as_str(),
+        input.starts_at,
+        input.ends_at,
+        input.dismissible,
+        input.public
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(row.map(Announcement::from))
+}
+
+/// Deletes announcement `id` through `executor`. Returns whether there was one, so whether it was
+/// deleted unless it was a dry run.
+pub async fn delete(id: i64, executor: &dyn Executor, pool: &PgPool) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let found = sqlx::query_scalar!("SELECT id FROM announcements WHERE id = $1 FOR UPDATE", id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .is_some();
+    if found {
+        executor
+            .act(Box::pin(async {
+                let deleted = sqlx::query!("DELETE FROM announcements WHERE id = $1", id)
+                    .execute(&mut *tx)
+                    .await?;
+                Ok(deleted.rows_affected())
+            }))
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+    use chrono::Duration;
+
+    use super::*;
+
+    fn announcement(
+        id: i64,
+        severity: Severity,
+        starts_in: i64,
+        ends_in: Option<i64>,
+    ) -> Announcement {
+        let now = Utc::now();
+        Announcement {
+            id,
+            message: format!("announcement {id}"),
+            severity,
+            starts_at: now + Duration::hours(starts_in),
+            ends_at: ends_in.map(|hours| now + Duration::hours(hours)),
+            dismissible: true,
+            public: true,
+        }
+    }
+
+    fn active_id(
+        announcements: &[Announcement],
+        signed_in: bool,
+        dismissed: &[i64],
+    ) -> Option<i64> {
+        active(announcements, Utc::now(), signed_in, dismissed).map(Announcement::id)
+    }
+
+    #[test]
+    fn only_running_announcements_are_active() {
+        let announcements = [
+            announcement(1, Severity::Critical, -2, Some(-1)),
+            announcement(2, Severity::Critical, 1, None),
+            announcement(3, Severity::Info, -1, Some(1)),
+        ];
+        assert_eq!(active_id(&announcements, true, &[]), Some(3));
+        assert_eq!(active_id(&announcements[..2], true, &[]), None);
+    }
+
+    #[test]
+    fn most_severe_then_newest_wins() {
+        let announcements = [
+            announcement(1, Severity::Info, -1, None),
+            announcement(2, Severity::Warning, -3, None),
+            announcement(3, Severity::Warning, -2, None),
+        ];
+        assert_eq!(active_id(&announcements, true, &[]), Some(3));
+        // Dismissing it falls back to the next one in line
+        assert_eq!(active_id(&announcements, true, &[3]), Some(2));
+        assert_eq!(active_id(&announcements, true, &[2, 3]), Some(1));
+    }
+
+    #[test]
+    fn dismissal_and_audience_are_respected() {
+        let mut sticky = announcement(1, Severity::Critical, -1, None);
+        sticky.dismissible = false;
+        assert_eq!(active_id(&[sticky.clone()], true, &[1]), Some(1));
+
+        let mut members_only = announcement(2, Severity::Info, -1, None);
+        members_only.public = false;
+        assert_eq!(active_id(&[members_only.clone()], false, &[]), None);
+        assert_eq!(active_id(&[members_only], true, &[]), Some(2));
+    }
+
+    #[test]
+    fn dismissal_cookie_round_trips() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            COOKIE,
+            HeaderValue::from_static("appearance=dark; dismissed_announcements=4.x.7"),
+        );
+        assert_eq!(dismissed_ids(&headers), [4, 7]);
+        assert_eq!(dismissed_ids(&HeaderMap::new()), Vec::<i64>::new());
+
+        let cookie = dismiss_cookie(vec![4, 7], 4);
+        assert!(cookie.starts_with("dismissed_announcements=7.4;"));
+        let cookie = dismiss_cookie((1..=30).collect(), 31);
+        let ids = cookie.split(';').next().unwrap().split('=').nth(1).unwrap();
+        assert_eq!(ids.split('.').count(), MAX_DISMISSED);
+        assert!(ids.ends_with(".31"));
+        assert!(ids.starts_with("12."));
+    }
+
+    #[test]
+    fn input_is_validated() {
+        let now = Utc::now();
+        let input = |message: &str, ends_in: Option<i64>| AnnouncementInput {
+            message: message.to_string(),
+            severity: Severity::Info,
+            starts_at: None,
+            ends_at: ends_in.map(|hours| now + Duration::hours(hours)),
+            dismissible: true,
+            public: false,
+        };
+        assert_eq!(input("Maintenance at 10pm", Some(2)).problem(now), None);
+        assert!(input("  ", None).problem(now).is_some());
+        assert!(input(&"a".repeat(MAX_MESSAGE_LEN + 1), None)
+            .problem(now)
+            .is_some());
+        assert!(input("Over already", Some(-1)).problem(now).is_some());
+    }
+}
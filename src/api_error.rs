@@ -0,0 +1,89 @@
+use axum::{
+    extract::rejection::JsonRejection,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Serialize, Serializer};
+
+/// Header carrying [ApiError]'s code on plain responses
+pub const ERROR_CODE: &str = "x-error-code";
+
+/// The body every JSON endpoint answers with when something goes wrong:
+/// `{"error": {"code": "...", "message": "..."}}`. `code` is stable and meant for programs,
+/// `message` is for people.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    error: Body<'a>,
+}
+
+#[derive(Serialize)]
+struct Body<'a> {
+    code: &'a str,
+    message: &'a str,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+    /// The server gave up on the request, e.g. because it took too long
+    pub fn unavailable(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::SERVICE_UNAVAILABLE, "unavailable", message)
+    }
+    /// What went wrong, for people
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+    /// The same error for a client that shows the body to a person as is, like the htmx forms.
+    /// The code goes in the X-Error-Code header so the page can point at the field at fault.
+    pub fn into_plain_response(self) -> Response {
+        (self.status, [(ERROR_CODE, self.code)], self.message).into_response()
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let envelope = Envelope {
+            error: Body {
+                code: self.code,
+                message: &self.message,
+            },
+        };
+        (self.status, Json(envelope)).into_response()
+    }
+}
+
+/// Just `{"code": "...", "message": "..."}`, for errors reported inside another body, like the
+/// items of a bulk request
+impl Serialize for ApiError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Body {
+            code: self.code,
+            message: &self.message,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// A body that isn't the JSON the endpoint takes, whatever the reason
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_json",
+            rejection.body_text(),
+        )
+    }
+}
@@ -0,0 +1,183 @@
+use std::fmt::Display;
+
+use axum::{
+    body::{Body, Bytes},
+    http::{
+        header::{COOKIE, LOCATION, SET_COOKIE},
+        HeaderMap, StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+
+use crate::user::cookie;
+
+/// Remembers the light/dark choice of a visitor, signed in or not
+pub const APPEARANCE_COOKIE_NAME: &str = "appearance";
+/// A year, refreshed whenever the visitor changes it
+const APPEARANCE_COOKIE_MAX_AGE: u32 = 60 * 60 * 24 * 365;
+
+/// Which palette pages use. Auto follows the browser's `prefers-color-scheme`.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Appearance {
+    #[default]
+    Auto,
+    Light,
+    Dark,
+}
+
+impl Appearance {
+    /// The visitor's choice from the request cookies, or auto if they haven't made one
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|header| header.to_str().ok())
+            .flat_map(|header| header.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(name, _)| *name == APPEARANCE_COOKIE_NAME)
+            .and_then(|(_, value)| Appearance::parse(value))
+            .unwrap_or_default()
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "auto" => Some(Appearance::Auto),
+            "light" => Some(Appearance::Light),
+            "dark" => Some(Appearance::Dark),
+            _ => None,
+        }
+    }
+
+    /// Value of the `data-appearance` attribute on `<html>`, which `/theme.css` keys off
+    pub fn attribute(&self) -> &'static str {
+        match self {
+            Appearance::Auto => "auto",
+            Appearance::Light => "light",
+            Appearance::Dark => "dark",
+        }
+    }
+
+    /// Content of the `color-scheme` meta tag, so form controls and scrollbars match as well
+    pub fn color_scheme(&self) -> &'static str {
+        match self {
+            Appearance::Auto => "light dark",
+            Appearance::Light => "light",
+            Appearance::Dark => "dark",
+        }
+    }
+}
+
+impl Display for Appearance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.attribute())
+    }
+}
+
+#[derive(Deserialize)]
+struct AppearanceForm {
+    appearance: Appearance,
+    /// Where to go afterwards. Only paths on this site are followed.
+    back: Option<String>,
+}
+
+/// Handles the footer toggle, which is a plain form so it works without JavaScript. Stores the
+/// choice in a cookie and sends the visitor back to the page they were on.
+pub async fn set_appearance(body: Bytes) -> Response {
+    let Ok(form) = serde_html_form::from_bytes::<AppearanceForm>(&body) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let back = form
+        .back
+        .filter(|back| is_local_path(back))
+        .unwrap_or_else(|| String::from("/"));
+    // Auto is what no cookie means, so choosing it just clears the cookie
+    let cookie = match form.appearance {
+        Appearance::Auto => cookie::expired_cookie(APPEARANCE_COOKIE_NAME),
+        appearance => format!(
+            "{APPEARANCE_COOKIE_NAME}={appearance}; Max-Age={APPEARANCE_COOKIE_MAX_AGE}; Path=/; \
+             Secure; HttpOnly; SameSite=Lax"
+        ),
+    };
+    Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, back)
+        .header(SET_COOKIE, cookie)
+        .body(Body::empty())
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+}
+
+/// Whether `path` stays on this site. `//host` and `/\host` are read by browsers as other hosts.
+fn is_local_path(path: &str) -> bool {
+    path.starts_with('/') && !path.starts_with("//") && !path.contains('\\')
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn with_cookies(cookies: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_str(cookies).unwrap());
+        headers
+    }
+
+    #[test]
+    fn reads_the_cookie() {
+        assert_eq!(
+            Appearance::from_headers(&HeaderMap::new()),
+            Appearance::Auto
+        );
+        assert_eq!(
+            Appearance::from_headers(&with_cookies("__Host-jwt=abc; appearance=dark")),
+            Appearance::Dark
+        );
+        assert_eq!(
+            Appearance::from_headers(&with_cookies("appearance=light")),
+            Appearance::Light
+        );
+        assert_eq!(
+            Appearance::from_headers(&with_cookies("appearance=neon")),
+            Appearance::Auto
+        );
+    }
+
+    #[tokio::test]
+    async fn toggle_round_trips() {
+        for (appearance, expected) in [
+            (Appearance::Dark, Appearance::Dark),
+            (Appearance::Light, Appearance::Light),
+            (Appearance::Auto, Appearance::Auto),
+        ] {
+            let form = format!("appearance={appearance}&back=%2Fabc");
+            let resp = set_appearance(Bytes::from(form)).await;
+            assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+            assert_eq!(resp.headers()[LOCATION], "/abc");
+
+            // Send the cookie back the way a browser would
+            let set_cookie = resp.headers()[SET_COOKIE].to_str().unwrap();
+            let (pair, _) = set_cookie.split_once(';').unwrap();
+            assert_eq!(Appearance::from_headers(&with_cookies(pair)), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn only_goes_back_to_this_site() {
+        for back in [
+            "https%3A%2F%2Fevil.example",
+            "%2F%2Fevil.example",
+            "%2F%5Cevil.example",
+        ] {
+            let form = format!("appearance=dark&back={back}");
+            let resp = set_appearance(Bytes::from(form)).await;
+            assert_eq!(resp.headers()[LOCATION], "/", "{back}");
+        }
+        let resp = set_appearance(Bytes::from("appearance=dark")).await;
+        assert_eq!(resp.headers()[LOCATION], "/");
+        let resp = set_appearance(Bytes::from("appearance=neon")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,99 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tracing::{info, warn};
+
+use crate::{
+    dry_run::{Apply, Executor},
+    url_db,
+};
+
+/// How often links archived longer than the retention are purged
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How often expired anonymous links are archived
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How long an expired anonymous link stays live, so visitors are told it expired for a while
+/// before it is gone
+const EXPIRED_GRACE: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// How long archived links are kept, with their clicks and codes, before they are deleted for good
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Default)]
+#[serde(default)]
+pub struct ArchivePrefs {
+    /// Archived links are purged this many days after they were archived. Unset keeps them until
+    /// an admin purges them.
+    retention_days: Option<u32>,
+}
+
+impl ArchivePrefs {
+    pub fn retention(&self) -> Option<Duration> {
+        self.retention_days
+            .map(|days| Duration::from_secs(u64::from(days) * 60 * 60 * 24))
+    }
+}
+
+/// Purges links archived at least `retention` ago, every [PURGE_INTERVAL]
+pub async fn purge(pool: PgPool, retention: Duration) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match purge_archived(retention, &Apply, &pool).await {
+            Ok(purged) if purged.is_empty() => {}
+            Ok(purged) => info!("Purged {} links archived past retention", purged.len()),
+            Err(e) => warn!("Couldn't purge links archived past retention: {e}"),
+        }
+    }
+}
+
+/// Deletes the links archived at least `retention` ago for good through `executor`, freeing their
+/// codes. Returns the codes of the links it picked, all of which were deleted unless it was a dry
+/// run.
+pub async fn purge_archived(
+    retention: Duration,
+    executor: &dyn Executor,
+    pool: &PgPool,
+) -> Result<Vec<String>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let (ids, codes): (Vec<i64>, Vec<String>) = url_db::archived_past(retention, &mut *tx)
+        .await?
+        .into_iter()
+        .unzip();
+    if !ids.is_empty() {
+        executor
+            .act(Box::pin(url_db::delete_urls(&ids, &mut *tx)))
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(codes)
+}
+
+/// Archives anonymous links [EXPIRED_GRACE] after they expire, every [EXPIRE_INTERVAL]. Only run
+/// while [crate::preferences::Preferences::anonymous_link_ttl] is set, as that is what gives them
+/// an expiry.
+pub async fn expire_anonymous(pool: PgPool) {
+    let mut interval = tokio::time::interval(EXPIRE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match url_db::archive_expired_anonymous(EXPIRED_GRACE, &pool).await {
+            Ok(0) => {}
+            Ok(archived) => info!("Archived {archived} expired anonymous links"),
+            Err(e) => warn!("Couldn't archive expired anonymous links: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retention_is_in_days() {
+        assert_eq!(ArchivePrefs::default().retention(), None);
+        let prefs: ArchivePrefs = toml::from_str("retention_days = 30").unwrap();
+        assert_eq!(
+            prefs.retention(),
+            Some(Duration::from_secs(30 * 24 * 60 * 60))
+        );
+    }
+}
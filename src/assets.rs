@@ -0,0 +1,141 @@
+use std::{fs, path::PathBuf};
+
+use axum::{
+    extract::Path,
+    http::{
+        header::{CONTENT_TYPE, LOCATION},
+        HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{cache::CacheClass, not_found_handler};
+
+/// Where the files served under /static live
+const ASSET_DIR: &str = "html";
+/// Files browsers and crawlers look for at the root, whatever the pages link to
+pub const ROOT_FILES: [&str; 3] = ["favicon.ico", "robots.txt", "apple-touch-icon.png"];
+
+/// What to do with a request for a file at its old place next to the short codes, e.g.
+/// `/index.css` rather than `/static/index.css`. Meant for a deprecation window while pages
+/// elsewhere still link to the old paths.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LegacyAssetPaths {
+    /// Serve the file and log the request
+    #[default]
+    Serve,
+    /// Redirect to the file under /static and log the request
+    Redirect,
+    /// Treat the path like any other short code
+    Off,
+}
+
+/// `file` under [ASSET_DIR], or None for anything that could reach outside it
+fn asset_path(file: &str) -> Option<PathBuf> {
+    let inside = !file.is_empty()
+        && file
+            .split('/')
+            .all(|segment| !segment.is_empty() && segment != "..");
+    inside.then(|| PathBuf::from(ASSET_DIR).join(file))
+}
+
+/// Whether `file` is an asset, which also makes it unusable as an alias
+pub fn exists(file: &str) -> bool {
+    asset_path(file).is_some_and(|path| path.is_file())
+}
+
+fn content_type(file: &str) -> &'static str {
+    let extension = file.rsplit_once('.').map_or("", |(_, extension)| extension);
+    match extension.to_ascii_lowercase().as_str() {
+        "html" => "text/html; charset=utf-8",
+        "css" => "text/css",
+        "js" => "text/javascript",
+        "txt" => "text/plain; charset=utf-8",
+        "ico" => "image/x-icon",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "xml" => "application/xml",
+        "csv" => "text/csv",
+        "webmanifest" => "application/manifest+json",
+        "wasm" => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Serves a file from [ASSET_DIR]. Any name and extension works, since nothing else is routed
+/// under /static.
+pub async fn static_file(Path(file): Path<String>) -> Response {
+    serve(&file).await
+}
+
+/// Serves a file from [ASSET_DIR], e.g. one of [ROOT_FILES] from where browsers expect it
+pub async fn serve(file: &str) -> Response {
+    let Some(path) = asset_path(file) else {
+        return StatusCode::FORBIDDEN.into_response();
+    };
+    debug!("Loading file at {}", path.display());
+    match fs::read(&path) {
+        // Missing files are private, like every response that doesn't declare a class
+        Ok(contents) => (
+            CacheClass::Static,
+            [(CONTENT_TYPE, HeaderValue::from_static(content_type(file)))],
+            contents,
+        )
+            .into_response(),
+        Err(_) => not_found_handler().await,
+    }
+}
+
+/// Answers a request for a file at its old path according to `policy`, or None if `path` isn't
+/// an asset or old paths are turned off
+pub async fn legacy(path: &str, policy: LegacyAssetPaths) -> Option<Response> {
+    if policy == LegacyAssetPaths::Off || !exists(path) {
+        return None;
+    }
+    warn!("/{path} was requested at its old path; link to /static/{path} instead");
+    Some(match policy {
+        LegacyAssetPaths::Redirect => {
+            let location = HeaderValue::from_str(&format!("/static/{path}"))
+                .expect("asset names are valid header values");
+            (StatusCode::PERMANENT_REDIRECT, [(LOCATION, location)]).into_response()
+        }
+        _ => serve(path).await,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stays_inside_the_asset_dir() {
+        assert_eq!(
+            asset_path("css/site.css"),
+            Some(PathBuf::from("html/css/site.css"))
+        );
+        for outside in [
+            "",
+            "..",
+            "../config.toml",
+            "a/../../b",
+            "/etc/passwd",
+            "a//b",
+        ] {
+            assert_eq!(asset_path(outside), None, "{outside}");
+        }
+    }
+
+    #[test]
+    fn guesses_content_types() {
+        assert_eq!(content_type("index.css"), "text/css");
+        assert_eq!(content_type("LOGO.PNG"), "image/png");
+        assert_eq!(content_type("robots.txt"), "text/plain; charset=utf-8");
+        assert_eq!(content_type("data.bin"), "application/octet-stream");
+        assert_eq!(content_type("LICENSE"), "application/octet-stream");
+    }
+}
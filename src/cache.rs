@@ -0,0 +1,297 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{
+        header::{CACHE_CONTROL, ETAG, IF_NONE_MATCH},
+        HeaderValue,
+    },
+    middleware::Next,
+    response::{IntoResponseParts, Response, ResponseParts},
+};
+use serde::{Deserialize, Serialize};
+
+/// How long the cacheable classes may be kept, in seconds
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct CachePrefs {
+    /// Files from html/
+    static_max_age_secs: u64,
+    /// Responses describing the instance, like /api/features
+    discovery_max_age_secs: u64,
+    /// Files whose name is a hash of their contents
+    fingerprinted_max_age_secs: u64,
+}
+
+impl Default for CachePrefs {
+    fn default() -> Self {
+        CachePrefs {
+            static_max_age_secs: 300,
+            discovery_max_age_secs: 300,
+            fingerprinted_max_age_secs: 60 * 60 * 24 * 365,
+        }
+    }
+}
+
+/// What a response is, as far as caches are concerned. Handlers declare it by returning it as
+/// part of the response, e.g. `(CacheClass::Static, body)`, and [apply] turns it into headers.
+/// Responses that don't declare one are [CacheClass::Private].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheClass {
+    /// Redirects, interstitials, anything tied to a session or that counts a visit. Never stored.
+    Private,
+    /// Describes the instance and is the same for everyone
+    Discovery,
+    /// Has an ETag and is cheap to check, so it is revalidated on every use
+    Revalidate,
+    /// Files from html/
+    Static,
+    /// Content-hashed names, which change whenever the content does, and anything else its URL
+    /// fully determines, like QR codes
+    Fingerprinted,
+}
+
+impl IntoResponseParts for CacheClass {
+    type Error = std::convert::Infallible;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        res.extensions_mut().insert(self);
+        Ok(res)
+    }
+}
+
+/// The caching policy of the instance: the class to header map plus the cache generation
+#[derive(Default)]
+pub struct Caching {
+    prefs: CachePrefs,
+    generation: AtomicU64,
+}
+
+impl Caching {
+    pub fn new(prefs: CachePrefs) -> Self {
+        Caching {
+            prefs,
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// The Cache-Control value for a class. This is the only place those values are decided.
+    pub fn cache_control(&self, class: CacheClass) -> String {
+        match class {
+            CacheClass::Private => String::from("no-store"),
+            CacheClass::Discovery => {
+                format!("public, max-age={}", self.prefs.discovery_max_age_secs)
+            }
+            CacheClass::Revalidate => String::from("public, no-cache"),
+            CacheClass::Static => format!("public, max-age={}", self.prefs.static_max_age_secs),
+            CacheClass::Fingerprinted => format!(
+                "public, max-age={}, immutable",
+                self.prefs.fingerprinted_max_age_secs
+            ),
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    /// Changes every ETag at once, so caches downstream refetch everything they revalidate.
+    /// Returns the new generation.
+    pub fn bump(&self) -> u64 {
+        self.generation.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// `etag` as sent to clients, with the generation mixed in
+    fn outgoing_etag(&self, etag: &str) -> Option<HeaderValue> {
+        let inner = etag.trim_matches('"');
+        HeaderValue::from_str(&format!("\"{inner}.g{}\"", self.generation())).ok()
+    }
+
+    /// The handler's own ETag for a client's If-None-Match, or None when it is from an older
+    /// generation and has to be treated as not matching
+    fn incoming_etag(&self, etag: &str) -> Option<HeaderValue> {
+        let suffix = format!(".g{}\"", self.generation());
+        let inner = etag.strip_suffix(&suffix)?;
+        HeaderValue::from_str(&format!("{inner}\"")).ok()
+    }
+}
+
+/// Applies the caching policy to every response. Handlers keep dealing in their own ETags; this
+/// adds the generation on the way out and takes it off again on the way in.
+pub async fn apply(State(caching): State<Arc<Caching>>, mut req: Request, next: Next) -> Response {
+    if let Some(sent) = req.headers_mut().remove(IF_NONE_MATCH) {
+        if let Some(own) = sent
+            .to_str()
+            .ok()
+            .and_then(|sent| caching.incoming_etag(sent))
+        {
+            req.headers_mut().insert(IF_NONE_MATCH, own);
+        }
+    }
+
+    let mut resp = next.run(req).await;
+
+    let class = resp
+        .extensions()
+        .get::<CacheClass>()
+        .copied()
+        .unwrap_or(CacheClass::Private);
+    let cache_control = HeaderValue::from_str(&caching.cache_control(class))
+        .expect("Cache-Control values are plain ASCII");
+    resp.headers_mut().insert(CACHE_CONTROL, cache_control);
+    let etag = resp
+        .headers()
+        .get(ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .and_then(|etag| caching.outgoing_etag(etag));
+    match etag {
+        Some(etag) if class != CacheClass::Private => {
+            resp.headers_mut().insert(ETAG, etag);
+        }
+        // Nothing revalidates a response that is never stored
+        _ => {
+            resp.headers_mut().remove(ETAG);
+        }
+    }
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{HeaderMap, StatusCode},
+        middleware,
+        response::IntoResponse,
+        routing::get,
+        Router,
+    };
+    use std::collections::HashSet;
+
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::locked::{contend, Locked};
+
+    /// Serves an ETag of "v1" the way theme.css does
+    async fn tagged(headers: HeaderMap) -> Response {
+        let etag = HeaderValue::from_static("\"v1\"");
+        let status = if headers.get(IF_NONE_MATCH) == Some(&etag) {
+            StatusCode::NOT_MODIFIED
+        } else {
+            StatusCode::OK
+        };
+        (status, CacheClass::Revalidate, [(ETAG, etag)]).into_response()
+    }
+
+    fn app(caching: Arc<Caching>) -> Router {
+        Router::new()
+            .route("/private", get(|| async { "private" }))
+            .route(
+                "/discovery",
+                get(|| async { (CacheClass::Discovery, "{}") }),
+            )
+            .route("/static", get(|| async { (CacheClass::Static, "css") }))
+            .route(
+                "/fingerprinted",
+                get(|| async { (CacheClass::Fingerprinted, "logo") }),
+            )
+            .route("/tagged", get(tagged))
+            // A handler can't make a private response revalidatable by giving it an ETag
+            .route(
+                "/private-tagged",
+                get(|| async { [(ETAG, HeaderValue::from_static("\"x\""))] }),
+            )
+            .layer(middleware::from_fn_with_state(caching, apply))
+    }
+
+    async fn get_with(caching: &Arc<Caching>, path: &str, etag: Option<&str>) -> Response {
+        let mut req = Request::get(path);
+        if let Some(etag) = etag {
+            req = req.header(IF_NONE_MATCH, etag);
+        }
+        app(caching.clone())
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn each_class_gets_its_headers() {
+        let caching = Arc::new(Caching::default());
+        for (path, cache_control, etag) in [
+            ("/private", "no-store", None),
+            ("/private-tagged", "no-store", None),
+            ("/discovery", "public, max-age=300", None),
+            ("/static", "public, max-age=300", None),
+            (
+                "/fingerprinted",
+                "public, max-age=31536000, immutable",
+                None,
+            ),
+            ("/tagged", "public, no-cache", Some("\"v1.g0\"")),
+        ] {
+            let resp = get_with(&caching, path, None).await;
+            assert_eq!(resp.headers()[CACHE_CONTROL], cache_control, "{path}");
+            assert_eq!(
+                resp.headers().get(ETAG).map(|etag| etag.to_str().unwrap()),
+                etag,
+                "{path}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn preferences_override_max_ages() {
+        let prefs: CachePrefs =
+            toml::from_str("static_max_age_secs = 60\nfingerprinted_max_age_secs = 600").unwrap();
+        let caching = Arc::new(Caching::new(prefs));
+        let resp = get_with(&caching, "/static", None).await;
+        assert_eq!(resp.headers()[CACHE_CONTROL], "public, max-age=60");
+        let resp = get_with(&caching, "/fingerprinted", None).await;
+        assert_eq!(
+            resp.headers()[CACHE_CONTROL],
+            "public, max-age=600, immutable"
+        );
+        let resp = get_with(&caching, "/discovery", None).await;
+        assert_eq!(resp.headers()[CACHE_CONTROL], "public, max-age=300");
+    }
+
+    #[tokio::test]
+    async fn bumping_the_generation_changes_etags() {
+        let caching = Arc::new(Caching::default());
+        let resp = get_with(&caching, "/tagged", Some("\"v1.g0\"")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers()[ETAG], "\"v1.g0\"");
+
+        assert_eq!(caching.bump(), 1);
+        let resp = get_with(&caching, "/tagged", Some("\"v1.g0\"")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[ETAG], "\"v1.g1\"");
+        let resp = get_with(&caching, "/tagged", Some("\"v1.g1\"")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        // The handler's own ETag never matches from outside
+        let resp = get_with(&caching, "/tagged", Some("\"v1\"")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn concurrent_bumps_are_all_distinct() {
+        const THREADS: usize = 16;
+        const BUMPS: usize = 1000;
+        let caching = Arc::new(Caching::default());
+        let seen: Arc<Locked<HashSet<u64>>> = Arc::default();
+        let (shared, generations) = (caching.clone(), seen.clone());
+        contend(THREADS, move |_| {
+            for _ in 0..BUMPS {
+                let generation = shared.bump();
+                assert!(generations.with(|seen| seen.insert(generation)));
+            }
+        });
+        assert_eq!(caching.generation(), (THREADS * BUMPS) as u64);
+        assert_eq!(seen.with(|seen| seen.len()), THREADS * BUMPS);
+    }
+}
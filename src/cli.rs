@@ -0,0 +1,732 @@
+use std::fs;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use sqlx::{PgConnection, PgPool};
+use zeroize::Zeroizing;
+
+use crate::{
+    archive, checked_destination,
+    dry_run::{self, Executor},
+    features::{Feature, FeatureSet},
+    history,
+    link_config::{self, Change, LinkFile, NewSpec, PlanItem},
+    link_import::{self, ImportRow, Importer, Outcome, Report},
+    preferences::Preferences,
+    rollup,
+    safety::SafetyCheck,
+    smoke, tags,
+    url_db::{self, OwnerFilter, UrlOptions, UrlRow},
+    user,
+    user_archive::{self, OnConflict},
+};
+
+const USAGE: &str = "Usage:
+    url_shortner                                    Run the server
+    url_shortner apply <file.toml> --user <name> [--yes | --plan-only | --dry-run]
+    url_shortner export --format=toml --user <name> [<file.toml>]
+    url_shortner import <links.csv> [--dry-run]
+    url_shortner export-user <username> <archive> --passphrase-file <file>
+    url_shortner import-user <archive> --passphrase-file <file> [--merge | --rename <name>] [--new-password]
+    url_shortner purge-archived [--older-than-days <n>] [--dry-run]
+    url_shortner rebuild-rollups --from <day> --to <day> [--link <code> | --user <name>] [--dry-run]
+    url_shortner rebuild-rollups --resume <id>
+    url_shortner smoke --base-url <url> --token <session token>";
+
+/// Runs a subcommand if one was given on the command line. Returns the process exit code, or None
+/// if the server should start as usual.
+pub async fn run(args: &[String], pool: &PgPool, prefs: &Preferences) -> Option<i32> {
+    let command = args.get(1)?;
+    let rest = &args[2..];
+    let code = match command.as_str() {
+        "apply" => apply(rest, pool, prefs).await,
+        "export" => export(rest, pool).await,
+        "import" => import(rest, pool, prefs).await,
+        "export-user" => export_user(rest, pool).await,
+        "import-user" => import_user(rest, pool, prefs).await,
+        "purge-archived" => purge_archived(rest, pool, prefs).await,
+        "rebuild-rollups" => rebuild_rollups(rest, pool, prefs).await,
+        "smoke" => run_smoke(rest).await,
+        "help" | "--help" | "-h" => {
+            println!("{USAGE}");
+            0
+        }
+        _ => {
+            eprintln!("Unknown command `{command}`\n{USAGE}");
+            1
+        }
+    };
+    Some(code)
+}
+
+/// Returns the value following `--name` or given as `--name=value`
+fn flag_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    let prefix = format!("{name}=");
+    for (i, arg) in args.iter().enumerate() {
+        if arg == name {
+            return args.get(i + 1).map(String::as_str);
+        }
+        if let Some(value) = arg.strip_prefix(prefix.as_str()) {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Arguments that aren't flags or flag values
+fn positional(args: &[String]) -> Vec<&str> {
+    let mut positional = Vec::new();
+    let mut skip_next = false;
+    for arg in args {
+        if skip_next {
+            skip_next = false;
+        } else if ["--user", "--format", "--passphrase-file", "--rename"].contains(&arg.as_str()) {
+            skip_next = true;
+        } else if !arg.starts_with("--") {
+            positional.push(arg.as_str());
+        }
+    }
+    positional
+}
+
+async fn service_user_id(args: &[String], pool: &PgPool) -> Result<i64, i32> {
+    let Some(username) = flag_value(args, "--user") else {
+        eprintln!("Missing --user <name>\n{USAGE}");
+        return Err(1);
+    };
+    match user::retrieve_user_by_name(username, pool).await {
+        Ok(user) => Ok(*user.id()),
+        Err(err) => {
+            eprintln!("Couldn't find user `{username}`: {err}");
+            Err(1)
+        }
+    }
+}
+
+/// Diffs a link file against the links owned by the service user and, with --yes, applies it.
+/// With --plan-only the exit code is 2 when there are pending changes, for use in CI, and
+/// --dry-run only prints the plan even with --yes. Links are
+/// checked as if they were made on the site and the plan is applied all at once: if any item is
+/// rejected, nothing is.
+async fn apply(args: &[String], pool: &PgPool, prefs: &Preferences) -> i32 {
+    let Some(path) = positional(args).first().copied() else {
+        eprintln!("Missing link file\n{USAGE}");
+        return 1;
+    };
+    let owner = match service_user_id(args, pool).await {
+        Ok(id) => id,
+        Err(code) => return code,
+    };
+    let username = flag_value(args, "--user").unwrap_or_default();
+    let desired = match fs::read_to_string(path).map(|contents| LinkFile::from_toml(&contents)) {
+        Ok(Ok(file)) => file,
+        Ok(Err(err)) => {
+            eprintln!("Error parsing {path}: {err}");
+            return 1;
+        }
+        Err(err) => {
+            eprintln!("Error reading {path}: {err}");
+            return 1;
+        }
+    };
+
+    let aliases: Vec<String> = desired.links().keys().cloned().collect();
+    let (owned, existing) = match tokio::try_join!(
+        url_db::retrieve_urls_by_owner(OwnerFilter::User(owner), pool),
+        url_db::retrieve_url_objs(&aliases, pool)
+    ) {
+        Ok(rows) => rows,
+        Err(err) => {
+            eprintln!("Error reading current links: {err}");
+            return 1;
+        }
+    };
+    let ids: Vec<i64> = existing.iter().map(UrlRow::id).collect();
+    let current_tags = match tags::for_links(&ids, pool).await {
+        Ok(tags) => tags,
+        Err(err) => {
+            eprintln!("Error reading current tags: {err}");
+            return 1;
+        }
+    };
+
+    let items = link_config::plan(&desired, &owned, &existing, &current_tags, owner);
+    let changes = items.iter().filter(|item| item.is_change()).count();
+    for item in &items {
+        println!("{item}");
+    }
+    println!("{changes} change(s) planned");
+
+    if args.iter().any(|arg| arg == "--plan-only") {
+        return if changes > 0 { 2 } else { 0 };
+    }
+    if !args.iter().any(|arg| arg == "--yes") || args.iter().any(|arg| arg == "--dry-run") {
+        println!("Nothing applied. Run again with --yes to apply this plan.");
+        return 0;
+    }
+
+    // All or nothing, so a rejected item doesn't leave the plan half applied
+    let mut tx = match pool.begin().await {
+        Ok(tx) => tx,
+        Err(err) => {
+            eprintln!("Error connecting to the database: {err}");
+            return 1;
+        }
+    };
+    let safety = SafetyCheck::from_prefs(prefs.safety());
+    let mut importer = Importer::new(prefs, false);
+    for item in items.iter().filter(|item| item.is_change()) {
+        let applied = match item {
+            PlanItem::Create { alias, spec } => {
+                create(
+                    alias,
+                    spec,
+                    username,
+                    prefs,
+                    &safety,
+                    &mut importer,
+                    &mut tx,
+                )
+                .await
+            }
+            PlanItem::Update { id, changes, .. } => {
+                let mut applied = Ok(());
+                for change in changes {
+                    applied = apply_change(*id, owner, change, prefs, &safety, &mut tx).await;
+                    if applied.is_err() {
+                        break;
+                    }
+                }
+                applied
+            }
+            PlanItem::Archive { id, .. } => url_db::archive_url(*id, &mut *tx)
+                .await
+                .map(|_| ())
+                .map_err(|err| err.to_string()),
+            PlanItem::Conflict { .. } | PlanItem::Invalid { .. } => Ok(()),
+        };
+        if let Err(problem) = applied {
+            eprintln!("Failed to apply `{item}`: {problem}");
+            eprintln!("Nothing was applied");
+            return 1;
+        }
+    }
+    match tx.commit().await {
+        Ok(_) => 0,
+        Err(err) => {
+            eprintln!("Error applying the plan: {err}");
+            1
+        }
+    }
+}
+
+/// The stored form of a destination from a link file, checked like one typed into the site
+async fn checked(
+    destination: &str,
+    prefs: &Preferences,
+    safety: &SafetyCheck,
+) -> Result<(String, Option<String>), String> {
+    let (destination, submitted_url) =
+        checked_destination(destination, prefs).map_err(|problem| problem.message().to_string())?;
+    if let Some(problem) = safety.problem(&destination).await {
+        return Err(problem.message().to_string());
+    }
+    Ok((destination, submitted_url))
+}
+
+/// Makes link `alias` as `spec` describes, owned by `username`, checked like an imported link
+async fn create(
+    alias: &str,
+    spec: &NewSpec,
+    username: &str,
+    prefs: &Preferences,
+    safety: &SafetyCheck,
+    importer: &mut Importer<'_>,
+    conn: &mut PgConnection,
+) -> Result<(), String> {
+    checked(&spec.destination, prefs, safety).await?;
+    if let Some(expires_at) = spec.expires_at {
+        not_passed(expires_at)?;
+    }
+    let row = ImportRow {
+        line: 0,
+        long_url: spec.destination.clone(),
+        short_url: Some(alias.to_string()),
+        created_by: Some(username.to_string()),
+    };
+    let options = UrlOptions {
+        expires_at: spec.expires_at,
+        redirect_status: spec.redirect_status,
+        ..UrlOptions::default()
+    };
+    match importer.import_with(&row, options, &mut *conn).await {
+        Ok((Outcome::Imported, Some(created))) => tags::set(created.id(), &spec.tags, conn)
+            .await
+            .map_err(|err| err.to_string()),
+        Ok((Outcome::Imported, None)) => Ok(()),
+        Ok((Outcome::Skipped(problem) | Outcome::Invalid(problem), _)) => Err(problem),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Makes one change to link `id`, recording a new destination as made by `owner`
+async fn apply_change(
+    id: i64,
+    owner: i64,
+    change: &Change,
+    prefs: &Preferences,
+    safety: &SafetyCheck,
+    conn: &mut PgConnection,
+) -> Result<(), String> {
+    let stored = match change {
+        Change::Destination { old, new } => {
+            let (destination, submitted_url) = checked(new, prefs, safety).await?;
+            update(id, owner, old, &destination, submitted_url, conn).await
+        }
+        Change::Tags { new, .. } => tags::set(id, new, conn).await,
+        Change::ExpiresAt { new, .. } => {
+            not_passed(*new)?;
+            url_db::update_url_expiry(id, *new, conn).await.map(|_| ())
+        }
+        Change::RedirectStatus { new, .. } => url_db::update_url_redirect_status(id, *new, conn)
+            .await
+            .map(|_| ()),
+    };
+    stored.map_err(|err| err.to_string())
+}
+
+/// Refuses an expiry from a link file that has already passed, like one typed into the site
+fn not_passed(expires_at: DateTime<Utc>) -> Result<(), String> {
+    match expires_at <= Utc::now() {
+        true => Err(String::from("The expiry time has already passed")),
+        false => Ok(()),
+    }
+}
+
+/// Points link `id` at `destination`, recording the change as made by `owner`
+async fn update(
+    id: i64,
+    owner: i64,
+    old_destination: &str,
+    destination: &str,
+    submitted_url: Option<String>,
+    conn: &mut PgConnection,
+) -> Result<(), sqlx::Error> {
+    url_db::update_url_destination(id, destination, submitted_url.as_deref(), &mut *conn).await?;
+    history::record(
+        id,
+        owner,
+        history::DESTINATION,
+        Some(old_destination),
+        Some(destination),
+        &mut *conn,
+    )
+    .await
+}
+
+/// Writes the links owned by a user as a link file, to stdout or the given path
+async fn export(args: &[String], pool: &PgPool) -> i32 {
+    match flag_value(args, "--format") {
+        Some("toml") | None => {}
+        Some(other) => {
+            eprintln!("Unsupported export format `{other}`");
+            return 1;
+        }
+    }
+    let owner = match service_user_id(args, pool).await {
+        Ok(id) => id,
+        Err(code) => return code,
+    };
+    let rows = match url_db::retrieve_urls_by_owner(OwnerFilter::User(owner), pool).await {
+        Ok(rows) => rows,
+        Err(err) => {
+            eprintln!("Error reading links: {err}");
+            return 1;
+        }
+    };
+    let ids: Vec<i64> = rows.iter().map(UrlRow::id).collect();
+    let tags = match tags::for_links(&ids, pool).await {
+        Ok(tags) => tags,
+        Err(err) => {
+            eprintln!("Error reading tags: {err}");
+            return 1;
+        }
+    };
+    let contents = match LinkFile::from_rows(&rows, &tags).to_toml() {
+        Ok(contents) => contents,
+        Err(err) => {
+            eprintln!("Error writing links as TOML: {err}");
+            return 1;
+        }
+    };
+
+    match positional(args).first() {
+        Some(path) => match fs::write(path, contents) {
+            Ok(_) => 0,
+            Err(err) => {
+                eprintln!("Error writing {path}: {err}");
+                1
+            }
+        },
+        None => {
+            print!("{contents}");
+            0
+        }
+    }
+}
+
+/// Imports links from a CSV file of `longurl[,shorturl][,created_by]` rows, e.g. one exported by
+/// another shortener, reporting every row that wasn't imported. With --dry-run every row is
+/// checked but none are stored.
+async fn import(args: &[String], pool: &PgPool, prefs: &Preferences) -> i32 {
+    let Some(path) = positional(args).first().copied() else {
+        eprintln!("Missing CSV file\n{USAGE}");
+        return 1;
+    };
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    let rows = match fs::read_to_string(path).map(|contents| link_import::read_rows(&contents)) {
+        Ok(Ok(rows)) => rows,
+        Ok(Err(err)) => {
+            eprintln!("Error parsing {path}: {err}");
+            return 1;
+        }
+        Err(err) => {
+            eprintln!("Error reading {path}: {err}");
+            return 1;
+        }
+    };
+    let mut conn = match pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            eprintln!("Error connecting to the database: {err}");
+            return 1;
+        }
+    };
+
+    let mut importer = Importer::new(prefs, dry_run);
+    let mut report = Report::default();
+    for row in &rows {
+        let outcome = match importer.import(row, &mut conn).await {
+            Ok(outcome) => outcome,
+            Err(err) => {
+                eprintln!("Error importing line {}: {err}", row.line);
+                println!("Stopped after {report}");
+                return 1;
+            }
+        };
+        match &outcome {
+            Outcome::Imported => {}
+            Outcome::Skipped(reason) => println!("line {} skipped: {reason}", row.line),
+            Outcome::Invalid(reason) => println!("line {} invalid: {reason}", row.line),
+        }
+        report.count(&outcome);
+    }
+    if dry_run {
+        println!("{report}. Nothing was stored, run again without --dry-run to import.");
+    } else {
+        println!("{report}");
+    }
+    0
+}
+
+/// The passphrase in the file given with --passphrase-file, without its trailing newline. Read
+/// from a file so it stays out of the shell history and the process list.
+fn passphrase(args: &[String]) -> Result<Zeroizing<String>, i32> {
+    let Some(path) = flag_value(args, "--passphrase-file") else {
+        eprintln!("Missing --passphrase-file <file>\n{USAGE}");
+        return Err(1);
+    };
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => Zeroizing::new(contents),
+        Err(err) => {
+            eprintln!("Error reading {path}: {err}");
+            return Err(1);
+        }
+    };
+    let passphrase = contents.trim_end_matches(['\r', '\n']);
+    if passphrase.is_empty() {
+        eprintln!("{path} has no passphrase in it");
+        return Err(1);
+    }
+    Ok(Zeroizing::new(passphrase.to_string()))
+}
+
+/// Writes a user with their links, templates and click counts to an archive encrypted with the
+/// passphrase, for import-user on another instance
+async fn export_user(args: &[String], pool: &PgPool) -> i32 {
+    let (Some(username), Some(path)) = (
+        positional(args).first().copied(),
+        positional(args).get(1).copied(),
+    ) else {
+        eprintln!("Missing username or archive\n{USAGE}");
+        return 1;
+    };
+    let passphrase = match passphrase(args) {
+        Ok(passphrase) => passphrase,
+        Err(code) => return code,
+    };
+    let archive = match user_archive::export(username, pool).await {
+        Ok(archive) => archive,
+        Err(sqlx::Error::RowNotFound) => {
+            eprintln!("Couldn't find user `{username}`");
+            return 1;
+        }
+        Err(err) => {
+            eprintln!("Error reading user `{username}`: {err}");
+            return 1;
+        }
+    };
+    if let Err(err) = fs::write(path, user_archive::seal(&archive, &passphrase)) {
+        eprintln!("Error writing {path}: {err}");
+        return 1;
+    }
+    println!(
+        "Exported {username} with {} links and {} templates to {path}",
+        archive.links.len(),
+        archive.templates.len()
+    );
+    0
+}
+
+/// Imports a user from an archive export-user made, printing the codes that were taken here with
+/// the ones the links got instead. If the username or email is taken, --merge adds the links to
+/// that user and --rename imports as a new user with another name. A new user keeps their password
+/// unless --new-password is given, in which case one is made and printed to pass on to them.
+async fn import_user(args: &[String], pool: &PgPool, prefs: &Preferences) -> i32 {
+    let Some(path) = positional(args).first().copied() else {
+        eprintln!("Missing archive\n{USAGE}");
+        return 1;
+    };
+    let on_conflict = match (
+        args.iter().any(|arg| arg == "--merge"),
+        flag_value(args, "--rename"),
+    ) {
+        (true, Some(_)) => {
+            eprintln!("Give one of --merge or --rename, not both");
+            return 1;
+        }
+        (true, None) => OnConflict::Merge,
+        (false, Some(username)) => OnConflict::Rename(username.to_string()),
+        (false, None) => OnConflict::Refuse,
+    };
+    let new_password = args.iter().any(|arg| arg == "--new-password");
+    let passphrase = match passphrase(args) {
+        Ok(passphrase) => passphrase,
+        Err(code) => return code,
+    };
+    let archive = match fs::read(path).map(|sealed| user_archive::open(&sealed, &passphrase)) {
+        Ok(Ok(archive)) => archive,
+        Ok(Err(err)) => {
+            eprintln!("Error opening {path}: {err}");
+            return 1;
+        }
+        Err(err) => {
+            eprintln!("Error reading {path}: {err}");
+            return 1;
+        }
+    };
+
+    let imported =
+        match user_archive::import(&archive, &on_conflict, new_password, prefs, pool).await {
+            Ok(imported) => imported,
+            Err(err) => {
+                eprintln!("Error importing {path}: {err}");
+                return 1;
+            }
+        };
+    for (old, new) in &imported.recoded {
+        println!("{old} -> {new}");
+    }
+    for (alias, reason) in &imported.skipped_templates {
+        println!("template {alias} skipped: {reason}");
+    }
+    let user = if imported.created {
+        format!("new user {}", imported.username)
+    } else {
+        format!("existing user {}", imported.username)
+    };
+    println!(
+        "Imported {} links, {} with new codes, and {} templates into {user}",
+        imported.links,
+        imported.recoded.len(),
+        imported.templates
+    );
+    if let Some(password) = &imported.password {
+        println!(
+            "{} signs in with the password {password}",
+            imported.username
+        );
+    }
+    0
+}
+
+/// Deletes the links archived at least --older-than-days ago for good, or the configured retention
+/// when not given, printing their codes. With --dry-run the codes are printed and nothing is
+/// deleted.
+async fn purge_archived(args: &[String], pool: &PgPool, prefs: &Preferences) -> i32 {
+    let retention = match flag_value(args, "--older-than-days").map(str::parse::<u64>) {
+        Some(Ok(days)) => std::time::Duration::from_secs(days * 60 * 60 * 24),
+        Some(Err(_)) => {
+            eprintln!("--older-than-days takes a number of days\n{USAGE}");
+            return 1;
+        }
+        None => match prefs.archive().retention() {
+            Some(retention) => retention,
+            None => {
+                eprintln!("Missing --older-than-days <n>, no retention is configured\n{USAGE}");
+                return 1;
+            }
+        },
+    };
+    let executor = dry_run::executor(args.iter().any(|arg| arg == "--dry-run"));
+    match archive::purge_archived(retention, executor, pool).await {
+        Ok(codes) => {
+            for code in &codes {
+                println!("{code}");
+            }
+            executor.record("cli", &format!("purged {} archived links", codes.len()));
+            if executor.dry_run() {
+                println!(
+                    "{} link(s) to purge. Nothing was deleted, run again without --dry-run to purge.",
+                    codes.len()
+                );
+            } else {
+                println!("Purged {} link(s)", codes.len());
+            }
+            0
+        }
+        Err(err) => {
+            eprintln!("Error purging archived links: {err}");
+            1
+        }
+    }
+}
+
+/// Recomputes the daily clicks of links from the clicks recorded for conversion tracking, printing
+/// each day as it's done, see [rollup::start]. Every link is rebuilt unless one link or user is
+/// given. An interrupted rebuild goes on from the day it was on with --resume. With --dry-run the
+/// days it would change are printed and nothing is started.
+async fn rebuild_rollups(args: &[String], pool: &PgPool, prefs: &Preferences) -> i32 {
+    let id = match flag_value(args, "--resume") {
+        Some(id) => match id.parse::<i64>() {
+            Ok(id) => id,
+            Err(_) => {
+                eprintln!("`{id}` isn't a rebuild id\n{USAGE}");
+                return 1;
+            }
+        },
+        None => {
+            let executor = dry_run::executor(args.iter().any(|arg| arg == "--dry-run"));
+            match start_rebuild(args, pool, prefs, executor).await {
+                Ok(Some(id)) => id,
+                Ok(None) => return 0,
+                Err(code) => return code,
+            }
+        }
+    };
+    loop {
+        match rollup::next_day(id, pool).await {
+            Ok(progress) => {
+                if let Ok(Some(rebuild)) = rollup::rebuild(id, pool).await {
+                    if rebuild.next_day > rebuild.from_day {
+                        println!(
+                            "Rebuilt {}",
+                            rebuild.next_day.pred_opt().unwrap_or_default()
+                        );
+                    }
+                }
+                if progress == rollup::Progress::Done {
+                    println!("Rebuild {id} finished");
+                    return 0;
+                }
+            }
+            Err(err) => {
+                eprintln!("Error rebuilding: {err}");
+                eprintln!("Run again with --resume {id} to go on from where it stopped");
+                return 1;
+            }
+        }
+    }
+}
+
+/// Returns the id of the rebuild started, None on a dry run
+async fn start_rebuild(
+    args: &[String],
+    pool: &PgPool,
+    prefs: &Preferences,
+    executor: &dyn Executor,
+) -> Result<Option<i64>, i32> {
+    if !FeatureSet::from_prefs(prefs.features()).enabled(Feature::Conversions) {
+        eprintln!("Clicks are only recorded to rebuild from while conversion tracking is on");
+        return Err(1);
+    }
+    let day = |flag| match flag_value(args, flag).map(|day| day.parse::<NaiveDate>()) {
+        Some(Ok(day)) => Ok(day),
+        _ => {
+            eprintln!("Missing {flag} <day>, like 2024-06-30\n{USAGE}");
+            Err(1)
+        }
+    };
+    let (from, to) = (day("--from")?, day("--to")?);
+    let scope = match (flag_value(args, "--link"), flag_value(args, "--user")) {
+        (Some(_), Some(_)) => {
+            eprintln!("Give either --link or --user, not both\n{USAGE}");
+            return Err(1);
+        }
+        (Some(code), None) => match url_db::retrieve_url_obj(code, prefs.slug_case(), pool).await {
+            Ok(row) => rollup::Scope::Link(row.id()),
+            Err(err) => {
+                eprintln!("Couldn't find link `{code}`: {err}");
+                return Err(1);
+            }
+        },
+        (None, Some(_)) => rollup::Scope::User(service_user_id(args, pool).await?),
+        (None, None) => rollup::Scope::All,
+    };
+    match rollup::start(scope, from, to, "cli", executor, pool).await {
+        Ok(started) => {
+            for change in &started.changes {
+                println!(
+                    "link {} on {}: {} -> {} clicks",
+                    change.url_id, change.day, change.recorded, change.rebuilt
+                );
+            }
+            println!("{} day(s) of links to change", started.changes.len());
+            executor.record(
+                "cli",
+                &format!("started a rollup rebuild of {from} to {to}"),
+            );
+            match started.rebuild {
+                Some(rebuild) => {
+                    println!("Started rebuild {}", rebuild.id);
+                    Ok(Some(rebuild.id))
+                }
+                None => {
+                    println!("Nothing was started, run again without --dry-run to rebuild.");
+                    Ok(None)
+                }
+            }
+        }
+        Err(err) => {
+            eprintln!("Couldn't start the rebuild: {err}");
+            Err(1)
+        }
+    }
+}
+
+/// Checks a running instance end to end, see [smoke::run], printing how each step went. The token
+/// is the value of a signed in user's session cookie. Exits with 1 if any step failed.
+async fn run_smoke(args: &[String]) -> i32 {
+    let (Some(base_url), Some(token)) =
+        (flag_value(args, "--base-url"), flag_value(args, "--token"))
+    else {
+        eprintln!("Missing --base-url <url> or --token <session token>\n{USAGE}");
+        return 1;
+    };
+    let steps = smoke::run(base_url, token).await;
+    for step in &steps {
+        println!("{step}");
+    }
+    let passed = steps.iter().filter(|step| step.passed()).count();
+    println!("{passed} of {} steps passed", steps.len());
+    i32::from(passed < steps.len())
+}
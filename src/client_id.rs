@@ -0,0 +1,124 @@
+use std::{
+    fmt::{self, Display},
+    net::{IpAddr, Ipv6Addr},
+};
+
+use chrono::NaiveDate;
+use hmac::Mac;
+
+use crate::user::jwt::HmacSha256;
+
+/// The client a request came from, as worked out once per request from the connection and the
+/// trusted proxy's header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Client {
+    /// Only for lookups made on the spot, like the visitor's country. Never kept or logged;
+    /// whatever keeps track of clients takes [Client::id] instead.
+    pub address: IpAddr,
+    pub id: ClientId,
+}
+
+impl Client {
+    /// `address` as seen on `day`, its id hashed with `secret` when there is one, see
+    /// [crate::preferences::Preferences::anonymize_clients]
+    pub fn new(address: IpAddr, day: NaiveDate, secret: Option<&str>) -> Self {
+        let address = address.to_canonical();
+        let id = match secret {
+            Some(secret) => ClientId::Anonymous(anonymized(secret, day, address)),
+            None => ClientId::Address(address),
+        };
+        Client { address, id }
+    }
+}
+
+/// Who a request came from, for everything that keeps track of clients: the rate limits,
+/// repeated clicks and conversion visitors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ClientId {
+    Address(IpAddr),
+    /// A keyed hash of the address's [network] that changes every day, so clients can be told
+    /// apart for the day but not followed from one day to the next or traced back to an address
+    Anonymous([u8; 16]),
+}
+
+impl ClientId {
+    /// The id rate limits go by, see [network]. Anonymous ids are hashed from it already.
+    pub fn network(self) -> ClientId {
+        match self {
+            ClientId::Address(address) => ClientId::Address(network(address)),
+            anonymous => anonymous,
+        }
+    }
+}
+
+impl Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientId::Address(address) => address.fmt(f),
+            ClientId::Anonymous(hashed) => f.write_str(&hex::encode(hashed)),
+        }
+    }
+}
+
+/// The part of an address that stands for one client. IPv6 ones are taken by their /64, which
+/// anyone with one address usually has all of.
+fn network(address: IpAddr) -> IpAddr {
+    match address.to_canonical() {
+        IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !u128::from(u64::MAX))),
+        v4 => v4,
+    }
+}
+
+/// The address's network hashed with a key made from `secret` and `day`, so last week's ids can't
+/// be matched with today's even by someone holding them all
+fn anonymized(secret: &str, day: NaiveDate, address: IpAddr) -> [u8; 16] {
+    let mut day_key =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    day_key.update(format!("client\n{day}").as_bytes());
+    let mut mac = HmacSha256::new_from_slice(&day_key.finalize().into_bytes())
+        .expect("HMAC takes keys of any size");
+    mac.update(network(address).to_string().as_bytes());
+    let mut hashed = [0; 16];
+    hashed.copy_from_slice(&mac.finalize().into_bytes()[..16]);
+    hashed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn networks_are_ipv6_64s() {
+        assert_eq!(
+            network("2001:db8:1:2:3:4:5:6".parse().unwrap()),
+            "2001:db8:1:2::".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            network("::ffff:203.0.113.7".parse().unwrap()),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn anonymous_ids_last_a_day() {
+        let address: IpAddr = "203.0.113.7".parse().unwrap();
+        let monday: NaiveDate = "2024-03-04".parse().unwrap();
+        let tuesday = monday.succ_opt().unwrap();
+        let id = |address, day, secret| Client::new(address, day, secret).id;
+
+        assert_eq!(id(address, monday, None), ClientId::Address(address));
+        let anonymous = id(address, monday, Some("secret"));
+        assert!(matches!(anonymous, ClientId::Anonymous(_)));
+        assert_eq!(anonymous, id(address, monday, Some("secret")));
+        assert_ne!(anonymous, id(address, tuesday, Some("secret")));
+        assert_ne!(anonymous, id(address, monday, Some("other")));
+        let elsewhere = "203.0.113.8".parse().unwrap();
+        assert_ne!(anonymous, id(elsewhere, monday, Some("secret")));
+        assert!(!anonymous.to_string().contains("203.0.113.7"));
+        assert_eq!(anonymous.network(), anonymous);
+
+        let phone = id("2001:db8::1".parse().unwrap(), monday, Some("secret"));
+        let laptop = id("2001:db8::2".parse().unwrap(), monday, Some("secret"));
+        assert_eq!(phone, laptop);
+    }
+}
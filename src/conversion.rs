@@ -0,0 +1,146 @@
+use std::time::Duration;
+
+use axum::http::HeaderMap;
+use chrono::NaiveDate;
+use hmac::Mac;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgExecutor, PgPool};
+use tracing::{info, warn};
+
+use crate::{client_id::ClientId, user::jwt::HmacSha256, user_agent};
+
+/// A 1x1 transparent GIF, what the conversion pixel serves whatever happens
+pub const PIXEL: [u8; 43] = [
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xFF, 0xFF, 0xFF, 0x21, 0xF9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2C, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3B,
+];
+/// How often clicks older than the attribution window are deleted
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Conversion tracking, turned on with the `conversions` feature.
+///
+/// Visitors are recognised by a hash of their address and user agent that changes at midnight
+/// UTC, so a click and a conversion only match when both happen on the same UTC day, however long
+/// the window is.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct ConversionPrefs {
+    /// How long after a click a conversion is still credited to it, in seconds
+    attribution_window_secs: u64,
+}
+
+impl Default for ConversionPrefs {
+    fn default() -> Self {
+        ConversionPrefs {
+            attribution_window_secs: 60 * 60 * 24,
+        }
+    }
+}
+
+impl ConversionPrefs {
+    pub fn attribution_window(&self) -> Duration {
+        Duration::from_secs(self.attribution_window_secs)
+    }
+    #[cfg(test)]
+    pub fn with_window(attribution_window_secs: u64) -> Self {
+        ConversionPrefs {
+            attribution_window_secs,
+        }
+    }
+}
+
+/// Who is visiting, without storing who they are: a keyed hash of the address and user agent that
+/// changes every `day`
+pub fn visitor_id(secret: &str, day: NaiveDate, client: ClientId, headers: &HeaderMap) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    let agent = user_agent::user_agent(headers).unwrap_or_default();
+    mac.update(format!("visitor\n{day}\n{client}\n{agent}").as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Credits a conversion to the latest click on `code` by `visitor` within `window`. Each click
+/// converts at most once. Returns whether a conversion was recorded.
+pub async fn record(
+    code: &str,
+    visitor: &str,
+    window: Duration,
+    db: impl PgExecutor<'_>,
+) -> Result<bool, sqlx::Error> {
+    let window_secs = i64::try_from(window.as_secs()).unwrap_or(i64::MAX);
+    let recorded = sqlx::query!(
+        "INSERT INTO url_conversions (url_id, click_id, clicked_at)
+        SELECT clicks.url_id, clicks.id, clicks.clicked_at
+        FROM url_click_visitors clicks JOIN urls ON urls.id = clicks.url_id
+        WHERE urls.shorturl = $1 AND clicks.visitor = $2
+            AND clicks.clicked_at > now() - make_interval(secs => $3)
+        ORDER BY clicks.clicked_at DESC
+        LIMIT 1
+        ON CONFLICT (click_id) DO NOTHING",
+        code,
+        visitor,
+        window_secs as f64
+    )
+    .execute(db)
+    .await?
+    .rows_affected();
+    Ok(recorded == 1)
+}
+
+/// Conversions credited to a link over its whole life
+pub async fn count(url_id: i64, db: impl PgExecutor<'_>) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM url_conversions WHERE url_id = $1"#,
+        url_id
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// Deletes clicks that are too old to be converted, every [PRUNE_INTERVAL]
+pub async fn prune(pool: PgPool, window: Duration) {
+    let window_secs = i64::try_from(window.as_secs()).unwrap_or(i64::MAX) as f64;
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let pruned = sqlx::query!(
+            "DELETE FROM url_click_visitors WHERE clicked_at <= now() - make_interval(secs => $1)",
+            window_secs
+        )
+        .execute(&pool)
+        .await;
+        match pruned {
+            Ok(done) if done.rows_affected() > 0 => {
+                info!("Pruned {} clicks past attribution", done.rows_affected())
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Couldn't prune clicks past attribution: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header::USER_AGENT, HeaderValue};
+
+    use super::*;
+
+    #[test]
+    fn visitor_ids_rotate_daily() {
+        let mut phone = HeaderMap::new();
+        phone.insert(USER_AGENT, HeaderValue::from_static("Phone"));
+        let client = ClientId::Address("203.0.113.7".parse().unwrap());
+        let monday: NaiveDate = "2024-03-04".parse().unwrap();
+        let tuesday = monday.succ_opt().unwrap();
+
+        let id = visitor_id("secret", monday, client, &phone);
+        assert_eq!(id, visitor_id("secret", monday, client, &phone));
+        assert_ne!(id, visitor_id("secret", tuesday, client, &phone));
+        assert_ne!(id, visitor_id("other", monday, client, &phone));
+        assert_ne!(id, visitor_id("secret", monday, client, &HeaderMap::new()));
+        let elsewhere = ClientId::Address("203.0.113.8".parse().unwrap());
+        assert_ne!(id, visitor_id("secret", monday, elsewhere, &phone));
+        assert!(!id.contains("203.0.113.7"));
+    }
+}
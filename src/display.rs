@@ -0,0 +1,79 @@
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+const ELLIPSIS: &str = "…";
+
+/// Shortens text to at most `max` user visible characters (grapheme clusters), counting the
+/// ellipsis that is added when anything was cut. Emoji sequences and combining marks are never
+/// split.
+pub fn truncate_graphemes(text: &str, max: usize) -> String {
+    if text.graphemes(true).nth(max).is_none() {
+        return text.to_string();
+    }
+    let mut truncated: String = text.graphemes(true).take(max.saturating_sub(1)).collect();
+    truncated.push_str(ELLIPSIS);
+    truncated
+}
+
+/// Shortens text to fit in `columns` terminal columns, counting wide (e.g. CJK) characters as two
+/// so plain text output stays aligned. The ellipsis takes one column.
+#[allow(dead_code)]
+pub fn truncate_width(text: &str, columns: usize) -> String {
+    if text.width() <= columns {
+        return text.to_string();
+    }
+    let mut truncated = String::new();
+    let mut width = 0;
+    for grapheme in text.graphemes(true) {
+        let grapheme_width = grapheme.width();
+        if width + grapheme_width + 1 > columns {
+            break;
+        }
+        width += grapheme_width;
+        truncated.push_str(grapheme);
+    }
+    truncated.push_str(ELLIPSIS);
+    truncated
+}
+
+/// Askama filters. Templates pick these up when this module is in scope as `filters`.
+pub mod filters {
+    use std::fmt::Display;
+
+    /// `{{ value|truncate_graphemes(40) }}`. The output is still escaped by askama.
+    pub fn truncate_graphemes<T: Display>(value: T, max: usize) -> askama::Result<String> {
+        Ok(super::truncate_graphemes(&value.to_string(), max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_short_text() {
+        assert_eq!(truncate_graphemes("hello", 5), "hello");
+        assert_eq!(truncate_graphemes("", 5), "");
+        assert_eq!(truncate_width("hello", 5), "hello");
+    }
+
+    #[test]
+    fn never_splits_graphemes() {
+        let family = "👨‍👩‍👧‍👦";
+        let text = format!("{family}{family}{family}");
+        assert_eq!(truncate_graphemes(&text, 3), text);
+        assert_eq!(truncate_graphemes(&text, 2), format!("{family}…"));
+
+        let combined = "e\u{301}e\u{301}e\u{301}";
+        assert_eq!(truncate_graphemes(combined, 2), "e\u{301}…");
+        assert_eq!(truncate_graphemes("abcdef", 4), "abc…");
+    }
+
+    #[test]
+    fn counts_wide_characters() {
+        assert_eq!(truncate_width("日本語テキスト", 7), "日本語…");
+        assert_eq!(truncate_width("日本語", 6), "日本語");
+        assert_eq!(truncate_width("abcdef", 4), "abc…");
+        assert_eq!(truncate_width("👨‍👩‍👧‍👦 family", 4), "👨‍👩‍👧‍👦 …");
+    }
+}
@@ -0,0 +1,108 @@
+use futures_util::future::BoxFuture;
+use serde::Serialize;
+use tracing::info;
+
+/// Makes the change a destructive admin operation picked its rows for. Operations select first
+/// and hand the change to an executor, so a dry run goes through the same selection and reports
+/// the same rows a real run changes, only without changing them.
+pub trait Executor: Send + Sync {
+    /// Whether changes are only reported
+    fn dry_run(&self) -> bool;
+    /// Runs `act`, answering with the rows it changed, or drops it without starting it
+    fn act<'a>(
+        &self,
+        act: BoxFuture<'a, Result<u64, sqlx::Error>>,
+    ) -> BoxFuture<'a, Result<u64, sqlx::Error>>;
+    /// Logs that `actor` did `action`, e.g. `purged the link abc`, with dry runs set apart
+    fn record(&self, actor: &str, action: &str) {
+        if self.dry_run() {
+            info!(
+                dry_run = true,
+                "{actor} {action} (dry run, nothing was changed)"
+            );
+        } else {
+            info!("{actor} {action}");
+        }
+    }
+}
+
+/// Makes the changes
+pub struct Apply;
+
+impl Executor for Apply {
+    fn dry_run(&self) -> bool {
+        false
+    }
+    fn act<'a>(
+        &self,
+        act: BoxFuture<'a, Result<u64, sqlx::Error>>,
+    ) -> BoxFuture<'a, Result<u64, sqlx::Error>> {
+        act
+    }
+}
+
+/// Leaves everything as it is
+pub struct DryRun;
+
+impl Executor for DryRun {
+    fn dry_run(&self) -> bool {
+        true
+    }
+    fn act<'a>(
+        &self,
+        _act: BoxFuture<'a, Result<u64, sqlx::Error>>,
+    ) -> BoxFuture<'a, Result<u64, sqlx::Error>> {
+        Box::pin(async { Ok(0) })
+    }
+}
+
+/// The executor for a request's `dry_run` parameter
+pub fn executor(dry_run: bool) -> &'static dyn Executor {
+    if dry_run {
+        &DryRun
+    } else {
+        &Apply
+    }
+}
+
+/// What a destructive operation picked, as answered to admins. `affected` is the same whether or
+/// not it was a dry run.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Affected<T> {
+    pub dry_run: bool,
+    pub count: usize,
+    pub affected: Vec<T>,
+}
+
+impl<T> Affected<T> {
+    pub fn new(executor: &dyn Executor, affected: Vec<T>) -> Self {
+        Affected {
+            dry_run: executor.dry_run(),
+            count: affected.len(),
+            affected,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn dry_runs_never_start_the_change() {
+        let started = AtomicBool::new(false);
+        let act = || -> BoxFuture<'_, Result<u64, sqlx::Error>> {
+            Box::pin(async {
+                started.store(true, Ordering::SeqCst);
+                Ok(3)
+            })
+        };
+
+        assert_eq!(executor(true).act(act()).await.unwrap(), 0);
+        assert!(!started.load(Ordering::SeqCst));
+        assert_eq!(executor(false).act(act()).await.unwrap(), 3);
+        assert!(started.load(Ordering::SeqCst));
+    }
+}
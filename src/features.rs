@@ -0,0 +1,138 @@
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+/// Optional parts of the service an instance can turn off. Everything that existed before these
+/// switches is on by default so existing configs keep behaving the same. Features that track
+/// visitors are off until an instance opts in.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct FeaturePrefs {
+    /// Links that redirect once and then stop working
+    single_use: bool,
+    /// GET /api/preflight/:code
+    preflight: bool,
+    /// GET /api/stream/clicks
+    click_stream: bool,
+    /// Recording lookups of codes that don't exist, and the admin page listing them
+    missed_lookups: bool,
+    /// Codes like `inv-{customer}` resolved from a template, and /api/templates to manage them
+    link_templates: bool,
+    /// Matching visits from the same visitor to conversions reported by GET /px/:code.gif
+    conversions: bool,
+}
+
+impl Default for FeaturePrefs {
+    fn default() -> Self {
+        FeaturePrefs {
+            single_use: true,
+            preflight: true,
+            click_stream: true,
+            missed_lookups: true,
+            link_templates: true,
+            conversions: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Feature {
+    SingleUse,
+    Preflight,
+    ClickStream,
+    MissedLookups,
+    LinkTemplates,
+    Conversions,
+}
+
+/// The features enabled on this instance. Handlers and the [guard] layer both ask this rather than
+/// reading the config, so a feature is either on everywhere or off everywhere.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(transparent)]
+pub struct FeatureSet(FeaturePrefs);
+
+impl FeatureSet {
+    pub fn from_prefs(prefs: &FeaturePrefs) -> Self {
+        FeatureSet(*prefs)
+    }
+
+    pub fn enabled(&self, feature: Feature) -> bool {
+        match feature {
+            Feature::SingleUse => self.0.single_use,
+            Feature::Preflight => self.0.preflight,
+            Feature::ClickStream => self.0.click_stream,
+            Feature::MissedLookups => self.0.missed_lookups,
+            Feature::LinkTemplates => self.0.link_templates,
+            Feature::Conversions => self.0.conversions,
+        }
+    }
+}
+
+/// Route layer answering 404 for every route it wraps while `feature` is off, the same as a route
+/// that doesn't exist. Use with `middleware::from_fn_with_state((features, feature), guard)`.
+pub async fn guard(
+    State((features, feature)): State<(FeatureSet, Feature)>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if features.enabled(feature) {
+        next.run(req).await
+    } else {
+        StatusCode::NOT_FOUND.into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn app(features: FeatureSet) -> Router {
+        Router::new()
+            .route("/preflight", get(|| async { "preflight" }))
+            .route_layer(middleware::from_fn_with_state(
+                (features, Feature::Preflight),
+                guard,
+            ))
+            .route("/always", get(|| async { "always" }))
+    }
+
+    async fn status(app: Router, path: &str) -> StatusCode {
+        let req = Request::get(path).body(Body::empty()).unwrap();
+        app.oneshot(req).await.unwrap().status()
+    }
+
+    #[test]
+    fn everything_but_tracking_is_on_by_default() {
+        let features = FeatureSet::from_prefs(&toml::from_str("").unwrap());
+        for feature in [
+            Feature::SingleUse,
+            Feature::Preflight,
+            Feature::ClickStream,
+            Feature::MissedLookups,
+            Feature::LinkTemplates,
+        ] {
+            assert!(features.enabled(feature), "{feature:?}");
+        }
+        assert!(!features.enabled(Feature::Conversions));
+    }
+
+    #[tokio::test]
+    async fn disabled_routes_look_missing() {
+        let off = FeatureSet::from_prefs(&toml::from_str("preflight = false").unwrap());
+        assert!(!off.enabled(Feature::Preflight));
+        assert!(off.enabled(Feature::ClickStream));
+
+        assert_eq!(status(app(off), "/preflight").await, StatusCode::NOT_FOUND);
+        assert_eq!(status(app(off), "/nothing").await, StatusCode::NOT_FOUND);
+        assert_eq!(status(app(off), "/always").await, StatusCode::OK);
+        let on = FeatureSet::from_prefs(&FeaturePrefs::default());
+        assert_eq!(status(app(on), "/preflight").await, StatusCode::OK);
+    }
+}
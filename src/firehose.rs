@@ -0,0 +1,395 @@
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::body::Bytes;
+use chrono::{DateTime, Utc};
+use futures_util::{stream, Stream};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    time::{interval_at, Instant, Interval},
+};
+
+use crate::{link_template::LinkTemplate, locked::Locked, url_db::UrlRow};
+
+/// Events kept for each stream before a slow one starts missing them, unless sized otherwise
+pub const BUFFERED_EVENTS: usize = 1024;
+/// Often enough that proxies don't take a quiet stream for a dead one
+const HEARTBEAT: Duration = Duration::from_secs(15);
+/// Open click streams per user
+pub const MAX_STREAMS_PER_USER: usize = 3;
+
+/// One counted click
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ClickEvent {
+    code: String,
+    at: DateTime<Utc>,
+    /// The value captured from `code` when it matched a link template
+    #[serde(skip_serializing_if = "Option::is_none")]
+    template_value: Option<String>,
+    #[serde(skip)]
+    owner: Option<i64>,
+}
+
+impl ClickEvent {
+    pub fn new(row: &UrlRow) -> Self {
+        ClickEvent {
+            code: row.clone_short_url(),
+            at: Utc::now(),
+            template_value: None,
+            owner: row.created_by(),
+        }
+    }
+
+    /// A click on `code`, one of `template`'s codes, which captured `value`
+    pub fn from_template(template: &LinkTemplate, code: &str, value: &str) -> Self {
+        ClickEvent {
+            code: code.to_string(),
+            at: Utc::now(),
+            template_value: Some(value.to_string()),
+            owner: Some(template.owner()),
+        }
+    }
+}
+
+/// What a stream sends, one per line
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Message<'a> {
+    Click(&'a ClickEvent),
+    /// The stream fell behind and this many clicks were skipped
+    Dropped {
+        count: u64,
+    },
+    Heartbeat,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Newline delimited JSON
+    #[default]
+    Ndjson,
+    /// Server-sent events, for EventSource
+    Sse,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Ndjson => "application/x-ndjson",
+            Format::Sse => "text/event-stream",
+        }
+    }
+}
+
+/// Which clicks a stream gets
+#[derive(Debug, Clone, Default)]
+pub struct Scope {
+    /// Only clicks on this user's links. None for every link, which is for admins.
+    pub owner: Option<i64>,
+    /// Only clicks on these short codes
+    pub codes: Option<HashSet<String>>,
+}
+
+impl Scope {
+    fn covers(&self, event: &ClickEvent) -> bool {
+        self.owner.is_none_or(|owner| event.owner == Some(owner))
+            && self
+                .codes
+                .as_ref()
+                .is_none_or(|codes| codes.contains(&event.code))
+    }
+}
+
+/// Fans clicks out to everyone streaming them. Publishing never waits: streams that can't keep up
+/// skip events and are told how many they missed.
+pub struct Firehose {
+    sender: broadcast::Sender<ClickEvent>,
+    streams: Arc<Locked<HashMap<i64, usize>>>,
+    heartbeat: Duration,
+}
+
+impl Default for Firehose {
+    fn default() -> Self {
+        Firehose::new(BUFFERED_EVENTS, HEARTBEAT)
+    }
+}
+
+impl Firehose {
+    /// Buffering `buffered_events` for slow streams, see [crate::resources]
+    pub fn with_buffer(buffered_events: usize) -> Self {
+        Firehose::new(buffered_events.max(1), HEARTBEAT)
+    }
+
+    pub fn new(buffered_events: usize, heartbeat: Duration) -> Self {
+        Firehose {
+            sender: broadcast::channel(buffered_events).0,
+            streams: Arc::default(),
+            heartbeat,
+        }
+    }
+
+    /// Sends a click to every open stream. Cheap when nobody is listening.
+    pub fn publish(&self, event: ClickEvent) {
+        // Only fails when there are no streams, which is fine
+        let _ = self.sender.send(event);
+    }
+
+    /// Opens a stream for `user`, or returns None if they already have
+    /// [MAX_STREAMS_PER_USER] open. The stream gives its slot back when it is dropped.
+    pub fn subscribe(
+        &self,
+        user: i64,
+        scope: Scope,
+        format: Format,
+    ) -> Option<impl Stream<Item = Result<Bytes, Infallible>>> {
+        let claimed = self.streams.with(|streams| {
+            let open = streams.entry(user).or_default();
+            let free = *open < MAX_STREAMS_PER_USER;
+            if free {
+                *open += 1;
+            }
+            free
+        });
+        if !claimed {
+            return None;
+        }
+        let state = StreamState {
+            receiver: self.sender.subscribe(),
+            heartbeat: interval_at(Instant::now() + self.heartbeat, self.heartbeat),
+            scope,
+            format,
+            _slot: Slot {
+                user,
+                streams: self.streams.clone(),
+            },
+        };
+        Some(stream::unfold(state, |mut state| async move {
+            let message = state.next_message().await?;
+            Some((Ok(message), state))
+        }))
+    }
+}
+
+struct StreamState {
+    receiver: broadcast::Receiver<ClickEvent>,
+    heartbeat: Interval,
+    scope: Scope,
+    format: Format,
+    _slot: Slot,
+}
+
+impl StreamState {
+    /// The next chunk to send, or None once the firehose is gone
+    async fn next_message(&mut self) -> Option<Bytes> {
+        loop {
+            tokio::select! {
+                received = self.receiver.recv() => match received {
+                    Ok(event) if self.scope.covers(&event) => {
+                        return Some(self.frame(&Message::Click(&event)));
+                    }
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(count)) => {
+                        return Some(self.frame(&Message::Dropped { count }));
+                    }
+                    Err(RecvError::Closed) => return None,
+                },
+                _ = self.heartbeat.tick() => {
+                    return Some(match self.format {
+                        Format::Ndjson => self.frame(&Message::Heartbeat),
+                        Format::Sse => Bytes::from_static(b": heartbeat\n\n"),
+                    });
+                }
+            }
+        }
+    }
+
+    fn frame(&self, message: &Message) -> Bytes {
+        let json = serde_json::to_string(message).expect("Click messages always serialize");
+        match self.format {
+            Format::Ndjson => Bytes::from(format!("{json}\n")),
+            Format::Sse => Bytes::from(format!("data: {json}\n\n")),
+        }
+    }
+}
+
+/// A user's claim on one of their stream slots
+struct Slot {
+    user: i64,
+    streams: Arc<Locked<HashMap<i64, usize>>>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.streams.with(|streams| {
+            if let Some(open) = streams.get_mut(&self.user) {
+                *open -= 1;
+                if *open == 0 {
+                    streams.remove(&self.user);
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::locked::contend;
+
+    const QUIET: Duration = Duration::from_secs(60);
+
+    fn click(code: &str, owner: Option<i64>) -> ClickEvent {
+        ClickEvent::new(&UrlRow::test_row(code, "https://example.com").with_owner(owner))
+    }
+
+    async fn next_line(
+        stream: &mut (impl Stream<Item = Result<Bytes, Infallible>> + Unpin),
+    ) -> String {
+        let chunk = timeout(Duration::from_secs(1), stream.next())
+            .await
+            .expect("nothing arrived")
+            .unwrap()
+            .unwrap();
+        String::from_utf8(chunk.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn streams_only_scoped_clicks() {
+        let firehose = Firehose::new(16, QUIET);
+        let owner = Scope {
+            owner: Some(1),
+            codes: None,
+        };
+        let filtered = Scope {
+            owner: None,
+            codes: Some(HashSet::from([String::from("b")])),
+        };
+        let mut mine = Box::pin(firehose.subscribe(1, owner, Format::Ndjson).unwrap());
+        let mut admin = Box::pin(
+            firehose
+                .subscribe(2, Scope::default(), Format::Ndjson)
+                .unwrap(),
+        );
+        let mut only_b = Box::pin(firehose.subscribe(2, filtered, Format::Sse).unwrap());
+
+        firehose.publish(click("theirs", Some(2)));
+        firehose.publish(click("anon", None));
+        firehose.publish(click("a", Some(1)));
+        firehose.publish(click("b", Some(1)));
+
+        assert!(next_line(&mut mine).await.contains(r#""code":"a""#));
+        assert!(next_line(&mut mine).await.contains(r#""code":"b""#));
+        for code in ["theirs", "anon", "a", "b"] {
+            let line = next_line(&mut admin).await;
+            assert!(line.starts_with(r#"{"type":"click","code":""#), "{line}");
+            assert!(line.contains(&format!(r#""code":"{code}""#)));
+            assert!(line.ends_with("}\n"));
+        }
+        let line = next_line(&mut only_b).await;
+        assert!(
+            line.starts_with(r#"data: {"type":"click","code":"b""#),
+            "{line}"
+        );
+        assert!(line.ends_with("}\n\n"));
+    }
+
+    #[tokio::test]
+    async fn slow_streams_are_told_what_they_missed() {
+        let firehose = Firehose::new(2, QUIET);
+        let mut slow = Box::pin(
+            firehose
+                .subscribe(1, Scope::default(), Format::Ndjson)
+                .unwrap(),
+        );
+        for code in ["1", "2", "3", "4", "5"] {
+            firehose.publish(click(code, None));
+        }
+
+        assert_eq!(
+            next_line(&mut slow).await,
+            "{\"type\":\"dropped\",\"count\":3}\n"
+        );
+        assert!(next_line(&mut slow).await.contains(r#""code":"4""#));
+        assert!(next_line(&mut slow).await.contains(r#""code":"5""#));
+    }
+
+    #[tokio::test]
+    async fn quiet_streams_get_heartbeats() {
+        let heartbeat = Duration::from_millis(50);
+        let firehose = Firehose::new(16, heartbeat);
+        let mut ndjson = Box::pin(
+            firehose
+                .subscribe(1, Scope::default(), Format::Ndjson)
+                .unwrap(),
+        );
+        let mut sse = Box::pin(
+            firehose
+                .subscribe(1, Scope::default(), Format::Sse)
+                .unwrap(),
+        );
+
+        let started = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(next_line(&mut ndjson).await, "{\"type\":\"heartbeat\"}\n");
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= heartbeat * 3, "{elapsed:?}");
+        assert!(elapsed < heartbeat * 6, "{elapsed:?}");
+        assert_eq!(next_line(&mut sse).await, ": heartbeat\n\n");
+    }
+
+    #[tokio::test]
+    async fn caps_streams_per_user() {
+        let firehose = Firehose::new(16, QUIET);
+        let open: Vec<_> = (0..MAX_STREAMS_PER_USER)
+            .map(|_| firehose.subscribe(1, Scope::default(), Format::Ndjson))
+            .collect();
+        assert!(open.iter().all(Option::is_some));
+        assert!(firehose
+            .subscribe(1, Scope::default(), Format::Ndjson)
+            .is_none());
+        assert!(firehose
+            .subscribe(2, Scope::default(), Format::Ndjson)
+            .is_some());
+
+        drop(open);
+        assert!(firehose
+            .subscribe(1, Scope::default(), Format::Ndjson)
+            .is_some());
+    }
+
+    #[test]
+    fn stream_slots_hold_under_contention() {
+        const THREADS: usize = 16;
+        const USERS: usize = 4;
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let firehose = Arc::new(Firehose::new(16, QUIET));
+        let handle = runtime.handle().clone();
+        let shared = firehose.clone();
+        contend(THREADS, move |thread| {
+            let _runtime = handle.enter();
+            let user = (thread % USERS) as i64;
+            let scope = || Scope {
+                owner: Some(user),
+                codes: None,
+            };
+            for _ in 0..1000 {
+                let stream = shared.subscribe(user, scope(), Format::Ndjson);
+                let open = shared.streams.with(|streams| streams.get(&user).copied());
+                if stream.is_some() {
+                    assert!(open.is_some_and(|open| (1..=MAX_STREAMS_PER_USER).contains(&open)));
+                }
+            }
+        });
+        assert!(firehose.streams.with(|streams| streams.is_empty()));
+    }
+}
@@ -1,10 +1,237 @@
-use axum::response::{IntoResponse, Response};
-
-pub async fn is_valid_url(input: String) -> Response {
-    let body = r###"
-				<div id="input-box-div">
-					<input type="text" name="url" id="long_url_input" hx-post="/verify/url" hx-target="#input-box-div"
-						placeholder="https://example.com">
-				</div>
-        "###;
+use std::{collections::HashSet, fmt::Display, sync::LazyLock};
+
+use percent_encoding::percent_decode_str;
+use url::{Host, Url};
+
+/// Top level domains from https://data.iana.org/TLD/tlds-alpha-by-domain.txt, lowercased.
+/// Internationalized ones are in their xn-- form, which is how [Url] gives hosts back.
+static TLDS: LazyLock<HashSet<String>> = LazyLock::new(|| {
+    include_str!("../tlds-alpha-by-domain.txt")
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_ascii_lowercase)
+        .collect()
+});
+
+/// Why a destination isn't a URL links can point at
+#[derive(Debug, PartialEq)]
+pub enum ValidationError {
+    /// Not a URL at all, or one with a malformed scheme, host or port
+    Unparseable(url::ParseError),
+    /// A URL without a host to send visitors to, like `mailto:` ones
+    NoHost,
+    /// The host's last label isn't a top level domain IANA knows about
+    UnknownTld(String),
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationError::Unparseable(e) => write!(f, "it can't be read as a URL ({e})"),
+            ValidationError::NoHost => f.write_str("it has no host to send visitors to"),
+            ValidationError::UnknownTld(tld) => write!(f, ".{tld} isn't a top level domain"),
+        }
+    }
+}
+
+/// Parses a destination and checks that it has a host that could exist: an IP address, or a
+/// domain ending in a known top level domain. International hosts are checked in punycode. A destination without a scheme is read as http,
+/// which is how it is sent.
+pub fn is_valid_url(input: &str) -> Result<Url, ValidationError> {
+    let url = match Url::parse(input) {
+        Err(url::ParseError::RelativeUrlWithoutBase) => Url::parse(&format!("http://{input}")),
+        // `example.com:8080` reads as the scheme example.com
+        Ok(url) if url.scheme().contains('.') => Url::parse(&format!("http://{input}")),
+        parsed => parsed,
+    }
+    .map_err(ValidationError::Unparseable)?;
+    match url.host() {
+        None => Err(ValidationError::NoHost),
+        Some(Host::Ipv4(_) | Host::Ipv6(_)) => Ok(url),
+        Some(Host::Domain(domain)) => {
+            // Hosts of http(s) urls already are punycode, other schemes percent-encode what was typed
+            let typed = percent_decode_str(domain).decode_utf8_lossy();
+            let domain = idna::domain_to_ascii(&typed).unwrap_or_else(|_| domain.to_string());
+            let domain = domain.strip_suffix('.').unwrap_or(&domain);
+            let tld = domain.rsplit('.').next().unwrap_or_default();
+            if domain.contains('.') && TLDS.contains(&tld.to_ascii_lowercase()) {
+                Ok(url)
+            } else {
+                Err(ValidationError::UnknownTld(tld.to_string()))
+            }
+        }
+    }
+}
+
+/// The scheme `input` starts with, lowercased. None for destinations without one, which are read
+/// as http, see [is_valid_url], and for protocol-relative ones like `//example.com`.
+pub fn given_scheme(input: &str) -> Option<String> {
+    match Url::parse(input) {
+        Ok(url) if !url.scheme().contains('.') => Some(url.scheme().to_string()),
+        _ => None,
+    }
+}
+
+/// The entry of `blocked` that covers `host`, if one does. An entry blocks the domain itself and
+/// every subdomain of it, so `evil.com` blocks `a.evil.com` but not `notevil.com`. Entries may be
+/// written in unicode or punycode.
+pub fn blocked_by<'a>(host: &str, blocked: &'a [String]) -> Option<&'a str> {
+    let ascii = |domain: &str| {
+        let domain = domain.trim().trim_end_matches('.');
+        idna::domain_to_ascii(domain).unwrap_or_else(|_| domain.to_ascii_lowercase())
+    };
+    let host = ascii(host);
+    blocked
+        .iter()
+        .find(|entry| {
+            let entry = ascii(entry);
+            !entry.is_empty()
+                && host
+                    .strip_suffix(entry.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+        })
+        .map(String::as_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_known_tlds() {
+        for input in [
+            "https://example.com/a?b=c",
+            "http://sub.example.co.uk:8080",
+            "HTTPS://EXAMPLE.ORG",
+            "https://example.com./",
+            "https://bücher.de",
+            "https://example.xn--p1ai",
+            "ftp://files.example.net/pub",
+        ] {
+            assert!(is_valid_url(input).is_ok(), "{input}");
+        }
+    }
+
+    #[test]
+    fn accepts_international_hosts() {
+        for (input, host) in [
+            ("https://münchen.de/straße", "xn--mnchen-3ya.de"),
+            ("https://☕.ws", "xn--53h.ws"),
+            ("https://i❤️.ws/", "xn--i-7iq.ws"),
+            // Mixed scripts: the first a is Cyrillic
+            ("https://pаypal.com", "xn--pypal-4ve.com"),
+            ("https://пример.рф", "xn--e1afmkfd.xn--p1ai"),
+            ("https://例え.jp", "xn--r8jz45g.jp"),
+            ("müller.de", "xn--mller-kva.de"),
+        ] {
+            let url = is_valid_url(input).unwrap();
+            assert_eq!(url.host_str(), Some(host), "{input}");
+        }
+        assert!(is_valid_url("foo://пример.рф").is_ok());
+        assert_eq!(
+            is_valid_url("https://пример.испытание"),
+            Err(ValidationError::UnknownTld(String::from(
+                "xn--80akhbyknj4f"
+            )))
+        );
+        assert_eq!(
+            is_valid_url("https://☕.☕"),
+            Err(ValidationError::UnknownTld(String::from("xn--53h")))
+        );
+    }
+
+    #[test]
+    fn accepts_ip_literals() {
+        for input in [
+            "http://127.0.0.1/admin",
+            "https://192.168.0.1:8443",
+            "http://[2001:db8::1]/",
+            "http://[::1]:8080/a",
+        ] {
+            let url = is_valid_url(input).unwrap();
+            assert!(
+                matches!(url.host(), Some(Host::Ipv4(_) | Host::Ipv6(_))),
+                "{input}"
+            );
+        }
+        assert!(matches!(
+            is_valid_url("http://[2001:db8::1"),
+            Err(ValidationError::Unparseable(_))
+        ));
+        assert!(matches!(
+            is_valid_url("http://300.1.1.1"),
+            Err(ValidationError::Unparseable(_))
+        ));
+    }
+
+    #[test]
+    fn reads_missing_schemes_as_http() {
+        for input in ["example.com", "example.com/a?b=c", "example.com:8080/a"] {
+            let url = is_valid_url(input).unwrap();
+            assert_eq!(url.scheme(), "http", "{input}");
+            assert_eq!(url.host_str(), Some("example.com"), "{input}");
+        }
+        assert_eq!(
+            is_valid_url("example.invalidtld"),
+            Err(ValidationError::UnknownTld(String::from("invalidtld")))
+        );
+        assert!(is_valid_url("://example.com").is_err());
+    }
+
+    #[test]
+    fn reads_given_schemes() {
+        for (input, scheme) in [
+            ("https://example.com", Some("https")),
+            ("HTTP://EXAMPLE.COM", Some("http")),
+            ("javascript:alert(1)", Some("javascript")),
+            ("data:text/html,<script>alert(1)</script>", Some("data")),
+            ("example.com/page", None),
+            ("example.com:8080", None),
+            ("//evil.com", None),
+        ] {
+            assert_eq!(given_scheme(input).as_deref(), scheme, "{input}");
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_tlds_and_hostless_urls() {
+        for (input, tld) in [
+            ("https://example.invalidtld", "invalidtld"),
+            ("https://localhost/", "localhost"),
+            ("https://example.c0m", "c0m"),
+            ("http://intranet", "intranet"),
+        ] {
+            assert_eq!(
+                is_valid_url(input),
+                Err(ValidationError::UnknownTld(String::from(tld))),
+                "{input}"
+            );
+        }
+        assert_eq!(
+            is_valid_url("mailto:someone@example.com"),
+            Err(ValidationError::NoHost)
+        );
+        for input in ["", "https://", "https://exa mple.com", "not a url"] {
+            assert!(is_valid_url(input).is_err(), "{input}");
+        }
+    }
+
+    #[test]
+    fn blocks_domains_and_their_subdomains() {
+        let blocked = [String::from("evil.com"), String::from("Bücher.DE.")];
+        for (host, entry) in [
+            ("evil.com", Some("evil.com")),
+            ("a.evil.com", Some("evil.com")),
+            ("A.B.Evil.Com.", Some("evil.com")),
+            ("notevil.com", None),
+            ("evil.com.au", None),
+            ("xn--bcher-kva.de", Some("Bücher.DE.")),
+            ("shop.bücher.de", Some("Bücher.DE.")),
+            ("example.com", None),
+        ] {
+            assert_eq!(blocked_by(host, &blocked), entry, "{host}");
+        }
+        assert_eq!(blocked_by("example.com", &[String::new()]), None);
+    }
 }
@@ -0,0 +1,131 @@
+use std::net::IpAddr;
+
+use axum::http::{HeaderMap, HeaderName};
+use maxminddb::{geoip2, Reader};
+use serde::{Deserialize, Serialize};
+
+use crate::preferences::PrefError;
+
+/// The branch a click on a geo-targeted link took when it went to the long url, because the
+/// visitor's country has no destination of its own or couldn't be told. Other branches are
+/// country codes.
+pub const DEFAULT_BRANCH: &str = "default";
+
+/// Where visitors' countries come from, for links that send some countries elsewhere. With
+/// neither set every visitor gets a link's long url.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Default)]
+#[serde(default)]
+pub struct GeoPrefs {
+    /// Header the reverse proxy puts the visitor's country code in, e.g. `CF-IPCountry` or
+    /// `X-Country`. Visitors can send it themselves, so only set it behind a proxy that replaces
+    /// it.
+    country_header: Option<String>,
+    /// MaxMind GeoLite2 or GeoIP2 Country (or City) database, for visitors the header doesn't
+    /// place
+    maxmind_db_path: Option<String>,
+}
+
+impl GeoPrefs {
+    #[cfg(test)]
+    pub fn with_country_header(header: &str) -> Self {
+        GeoPrefs {
+            country_header: Some(header.to_string()),
+            maxmind_db_path: None,
+        }
+    }
+}
+
+/// Tells which country a visitor is in, the way [GeoPrefs] says to
+pub struct GeoLookup {
+    header: Option<HeaderName>,
+    database: Option<Reader<Vec<u8>>>,
+}
+
+impl GeoLookup {
+    /// Loads the MaxMind database into memory, if there is one
+    pub fn from_prefs(prefs: &GeoPrefs) -> Result<Self, PrefError> {
+        let header = prefs
+            .country_header
+            .as_deref()
+            .map(|name| {
+                HeaderName::try_from(name).map_err(|_| {
+                    PrefError::InvalidValue(format!("country_header `{name}` isn't a header name"))
+                })
+            })
+            .transpose()?;
+        let database = prefs
+            .maxmind_db_path
+            .as_deref()
+            .map(|path| {
+                Reader::open_readfile(path).map_err(|e| {
+                    PrefError::InvalidValue(format!(
+                        "maxmind_db_path `{path}` couldn't be opened: {e}"
+                    ))
+                })
+            })
+            .transpose()?;
+        Ok(GeoLookup { header, database })
+    }
+
+    /// The visitor's country code, from the proxy's header or else looked up by their address.
+    /// None when neither says.
+    pub fn country(&self, headers: &HeaderMap, client: Option<IpAddr>) -> Option<String> {
+        let from_header = self
+            .header
+            .as_ref()
+            .and_then(|header| headers.get(header))
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_country);
+        from_header.or_else(|| {
+            let found: geoip2::Country = self.database.as_ref()?.lookup(client?).ok()?;
+            parse_country(found.country?.iso_code?)
+        })
+    }
+}
+
+/// `code` as countries are stored: an ISO 3166-1 alpha-2 code, in uppercase. Proxies answer `XX`
+/// for addresses they can't place and `T1` for Tor, neither of which is a country.
+pub fn parse_country(code: &str) -> Option<String> {
+    let code = code.trim().to_ascii_uppercase();
+    let is_code = code.len() == 2 && code.bytes().all(|letter| letter.is_ascii_uppercase());
+    (is_code && code != "XX").then_some(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn parses_country_codes() {
+        assert_eq!(parse_country("us").as_deref(), Some("US"));
+        assert_eq!(parse_country(" DE ").as_deref(), Some("DE"));
+        for bad in ["", "XX", "T1", "USA", "ü1", "--"] {
+            assert_eq!(parse_country(bad), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn reads_the_country_header() {
+        let lookup = GeoLookup::from_prefs(&GeoPrefs::with_country_header("CF-IPCountry")).unwrap();
+        let mut headers = HeaderMap::new();
+        assert_eq!(lookup.country(&headers, None), None);
+        headers.insert("cf-ipcountry", HeaderValue::from_static("gb"));
+        assert_eq!(lookup.country(&headers, None).as_deref(), Some("GB"));
+        headers.insert("cf-ipcountry", HeaderValue::from_static("XX"));
+        assert_eq!(lookup.country(&headers, None), None);
+
+        let unset = GeoLookup::from_prefs(&GeoPrefs::default()).unwrap();
+        headers.insert("cf-ipcountry", HeaderValue::from_static("gb"));
+        assert_eq!(unset.country(&headers, None), None);
+    }
+
+    #[test]
+    fn refuses_a_missing_database() {
+        let prefs: GeoPrefs = toml::from_str("maxmind_db_path = \"/nonexistent.mmdb\"").unwrap();
+        assert!(GeoLookup::from_prefs(&prefs).is_err());
+        let prefs: GeoPrefs = toml::from_str("country_header = \"not a header\"").unwrap();
+        assert!(GeoLookup::from_prefs(&prefs).is_err());
+    }
+}
@@ -0,0 +1,268 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use futures_util::future::{join_all, BoxFuture};
+use serde::Serialize;
+use sqlx::{Connection, PgPool};
+use tracing::{info, warn};
+
+use crate::{
+    locked::{Locked, ReadMostly},
+    readiness::{Readiness, ReadyState},
+    recycle::Failure,
+};
+
+/// How long the database gets to answer a readiness check
+const DATABASE_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How a single component is doing. The order is the precedence when aggregating: the worst
+/// component decides.
+#[derive(Serialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Health {
+    Healthy,
+    Degraded,
+    Unhealthy,
+}
+
+/// What the instance as a whole reports to load balancers. Degraded still takes traffic.
+#[derive(Serialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregate {
+    Ready,
+    Degraded,
+    NotReady,
+}
+
+impl Aggregate {
+    fn of(components: &[Component]) -> Self {
+        match components.iter().map(|component| component.status).max() {
+            None | Some(Health::Healthy) => Aggregate::Ready,
+            Some(Health::Degraded) => Aggregate::Degraded,
+            Some(Health::Unhealthy) => Aggregate::NotReady,
+        }
+    }
+}
+
+/// A contributor's answer to a check
+#[derive(Debug, PartialEq, Clone)]
+pub struct Report {
+    status: Health,
+    reason: Option<String>,
+}
+
+impl Report {
+    pub fn healthy() -> Self {
+        Report {
+            status: Health::Healthy,
+            reason: None,
+        }
+    }
+    pub fn degraded(reason: impl Into<String>) -> Self {
+        Report {
+            status: Health::Degraded,
+            reason: Some(reason.into()),
+        }
+    }
+    pub fn unhealthy(reason: impl Into<String>) -> Self {
+        Report {
+            status: Health::Unhealthy,
+            reason: Some(reason.into()),
+        }
+    }
+}
+
+/// Something whose state decides whether the instance should take traffic. Registering one with
+/// [HealthState::register] is all it takes for /readyz to account for it.
+pub trait Contributor: Send + Sync {
+    /// Shown in the breakdown and the logs, unique among the registered contributors
+    fn name(&self) -> &'static str;
+    fn check(&self) -> BoxFuture<'_, Report>;
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct Component {
+    name: &'static str,
+    status: Health,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Checked {
+    pub health: Aggregate,
+    pub components: Vec<Component>,
+}
+
+/// A component changing status since the previous check. `from` is None the first time a
+/// component is checked.
+#[derive(Debug, PartialEq)]
+pub struct Transition {
+    pub component: &'static str,
+    pub from: Option<Health>,
+    pub to: Health,
+}
+
+/// The registered contributors and what each reported last time
+#[derive(Default)]
+pub struct HealthState {
+    contributors: ReadMostly<Vec<Arc<dyn Contributor>>>,
+    last: Locked<HashMap<&'static str, Health>>,
+}
+
+impl HealthState {
+    pub fn register(&self, contributor: impl Contributor + 'static) {
+        self.contributors
+            .write(|contributors| contributors.push(Arc::new(contributor)));
+    }
+
+    /// Checks every contributor at once and logs the ones whose status changed
+    pub async fn check(&self) -> (Checked, Vec<Transition>) {
+        let contributors = self.contributors.read(Vec::clone);
+        let reports = join_all(contributors.iter().map(|contributor| contributor.check())).await;
+        let components: Vec<Component> = contributors
+            .iter()
+            .zip(reports)
+            .map(|(contributor, report)| Component {
+                name: contributor.name(),
+                status: report.status,
+                reason: report.reason,
+            })
+            .collect();
+        let transitions = self.record(&components);
+        let checked = Checked {
+            health: Aggregate::of(&components),
+            components,
+        };
+        (checked, transitions)
+    }
+
+    fn record(&self, components: &[Component]) -> Vec<Transition> {
+        self.last.with(|last| record_changes(last, components))
+    }
+}
+
+/// Records each component's status in `last`, logging and returning the ones that changed
+fn record_changes(
+    last: &mut HashMap<&'static str, Health>,
+    components: &[Component],
+) -> Vec<Transition> {
+    let mut transitions = Vec::new();
+    for component in components {
+        let from = last.insert(component.name, component.status);
+        if from == Some(component.status) {
+            continue;
+        }
+        let reason = component.reason.as_deref().unwrap_or("no reason given");
+        match component.status {
+            Health::Healthy => info!("Health: {} is healthy", component.name),
+            status => warn!("Health: {} is {status:?}: {reason}", component.name),
+        }
+        transitions.push(Transition {
+            component: component.name,
+            from,
+            to: component.status,
+        });
+    }
+    transitions
+}
+
+/// Unhealthy when no connection can be had or it doesn't answer a ping
+pub struct Database(pub PgPool);
+
+impl Contributor for Database {
+    fn name(&self) -> &'static str {
+        "database"
+    }
+    fn check(&self) -> BoxFuture<'_, Report> {
+        Box::pin(async move {
+            let pinged = tokio::time::timeout(DATABASE_CHECK_TIMEOUT, async {
+                self.0.acquire().await?.ping().await
+            })
+            .await;
+            match pinged {
+                Ok(Ok(())) => Report::healthy(),
+                Ok(Err(e)) => Report::unhealthy(Failure::classify(&e).to_string()),
+                Err(_) => Report::unhealthy(format!("no answer within {DATABASE_CHECK_TIMEOUT:?}")),
+            }
+        })
+    }
+}
+
+/// Degraded until the pool has been warmed up
+pub struct WarmUp(pub Arc<Readiness>);
+
+impl Contributor for WarmUp {
+    fn name(&self) -> &'static str {
+        "warm_up"
+    }
+    fn check(&self) -> BoxFuture<'_, Report> {
+        Box::pin(async move {
+            match self.0.state() {
+                ReadyState::Warm => Report::healthy(),
+                ReadyState::Minimal => Report::degraded("the connection pool is still opening"),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fixed(&'static str, Report);
+
+    impl Contributor for Fixed {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+        fn check(&self) -> BoxFuture<'_, Report> {
+            Box::pin(async move { self.1.clone() })
+        }
+    }
+
+    #[tokio::test]
+    async fn worst_component_decides() {
+        let health = HealthState::default();
+        assert_eq!(health.check().await.0.health, Aggregate::Ready);
+
+        health.register(Fixed("a", Report::healthy()));
+        assert_eq!(health.check().await.0.health, Aggregate::Ready);
+        health.register(Fixed("b", Report::degraded("slow")));
+        assert_eq!(health.check().await.0.health, Aggregate::Degraded);
+        health.register(Fixed("c", Report::unhealthy("down")));
+        health.register(Fixed("d", Report::degraded("slow")));
+        let (checked, _) = health.check().await;
+        assert_eq!(checked.health, Aggregate::NotReady);
+        let names: Vec<_> = checked.components.iter().map(|c| c.name).collect();
+        assert_eq!(names, ["a", "b", "c", "d"]);
+        assert_eq!(checked.components[2].reason.as_deref(), Some("down"));
+    }
+
+    #[test]
+    fn only_changes_are_transitions() {
+        let health = HealthState::default();
+        let component = |status| Component {
+            name: "db",
+            status,
+            reason: None,
+        };
+        assert_eq!(
+            health.record(&[component(Health::Healthy)]),
+            [Transition {
+                component: "db",
+                from: None,
+                to: Health::Healthy
+            }]
+        );
+        assert_eq!(health.record(&[component(Health::Healthy)]), []);
+        assert_eq!(
+            health.record(&[component(Health::Unhealthy)]),
+            [Transition {
+                component: "db",
+                from: Some(Health::Healthy),
+                to: Health::Unhealthy
+            }]
+        );
+        assert_eq!(health.record(&[component(Health::Unhealthy)]), []);
+    }
+}
@@ -0,0 +1,147 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgExecutor, PgPool};
+use tracing::{info, warn};
+
+use crate::display;
+
+/// How often edits older than the retention are deleted
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Longest old or new value shown in a link's history, in characters. Destinations can be very
+/// long and only need to be recognisable there.
+pub const DISPLAY_LEN: usize = 80;
+
+/// The fields of a link an edit changed, as stored in url_edits
+pub const DESTINATION: &str = "destination";
+pub const DESTINATIONS: &str = "destinations";
+pub const GEO_TARGETS: &str = "geo_targets";
+pub const DESCRIPTION: &str = "description";
+/// A transfer to another user, with both usernames
+pub const OWNER: &str = "owner";
+
+/// How long the history of changes to each link is kept
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Default)]
+#[serde(default)]
+pub struct HistoryPrefs {
+    /// Edits older than this are deleted, in days. Unset keeps them for as long as the link.
+    retention_days: Option<u32>,
+}
+
+impl HistoryPrefs {
+    pub fn retention(&self) -> Option<Duration> {
+        self.retention_days
+            .map(|days| Duration::from_secs(u64::from(days) * 60 * 60 * 24))
+    }
+}
+
+/// One change to a link, with who made it. `edited_by` is None once that user is deleted.
+#[derive(Debug, Clone)]
+pub struct LinkEdit {
+    pub field: String,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+    pub edited_by: Option<String>,
+    pub edited_at: DateTime<Utc>,
+}
+
+/// A [LinkEdit] as owners are shown it, with the values cut to [DISPLAY_LEN]
+#[derive(Serialize, Debug, PartialEq)]
+pub struct EditView {
+    pub field: String,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+    pub edited_by: Option<String>,
+    pub edited_at: DateTime<Utc>,
+}
+
+impl From<LinkEdit> for EditView {
+    fn from(edit: LinkEdit) -> Self {
+        let shown = |value: Option<String>| {
+            value.map(|value| display::truncate_graphemes(&value, DISPLAY_LEN))
+        };
+        EditView {
+            field: edit.field,
+            old_value: shown(edit.old_value),
+            new_value: shown(edit.new_value),
+            edited_by: edit.edited_by,
+            edited_at: edit.edited_at,
+        }
+    }
+}
+
+/// Records that `editor` changed `field` of a link. Run it in the transaction making the change,
+/// so the history can't say something the link doesn't.
+pub async fn record(
+    url_id: i64,
+    editor: i64,
+    field: &str,
+    old_value: Option<&str>,
+    new_value: Option<&str>,
+    db: impl PgExecutor<'_>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO url_edits (url_id, edited_by, field, old_value, new_value)
+        VALUES ($1, $2, $3, $4, $5)",
+        url_id,
+        editor,
+        field,
+        old_value,
+        new_value
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Every recorded change to a link, oldest first
+pub async fn for_link(url_id: i64, db: impl PgExecutor<'_>) -> Result<Vec<LinkEdit>, sqlx::Error> {
+    sqlx::query_as!(
+        LinkEdit,
+        r#"SELECT field, old_value, new_value, users.username AS "edited_by?", edited_at
+        FROM url_edits LEFT JOIN users ON users.id = url_edits.edited_by
+        WHERE url_id = $1
+        ORDER BY edited_at, url_edits.id"#,
+        url_id
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Deletes edits older than `retention`, every [PRUNE_INTERVAL]
+pub async fn prune(pool: PgPool, retention: Duration) {
+    let retention_secs = i64::try_from(retention.as_secs()).unwrap_or(i64::MAX) as f64;
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let pruned = sqlx::query!(
+            "DELETE FROM url_edits WHERE edited_at <= now() - make_interval(secs => $1)",
+            retention_secs
+        )
+        .execute(&pool)
+        .await;
+        match pruned {
+            Ok(done) if done.rows_affected() > 0 => {
+                info!("Pruned {} link edits past retention", done.rows_affected())
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Couldn't prune link edits past retention: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retention_is_in_days() {
+        assert_eq!(HistoryPrefs::default().retention(), None);
+        let prefs: HistoryPrefs = toml::from_str("retention_days = 2").unwrap();
+        assert_eq!(
+            prefs.retention(),
+            Some(Duration::from_secs(2 * 24 * 60 * 60))
+        );
+    }
+}
@@ -0,0 +1,251 @@
+use std::{collections::HashSet, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{
+        header::{HOST, LOCATION},
+        HeaderValue, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::locked::ReadMostly;
+
+/// Paths that answer on any host, so health checks can hit the server directly
+const HOST_AGNOSTIC_PATHS: [&str; 2] = ["/healthz", "/readyz"];
+
+/// What to do with requests for a host the instance doesn't serve, such as a random subdomain
+/// caught by wildcard DNS.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownHostPolicy {
+    /// Serve the site anyway
+    #[default]
+    Pass,
+    /// Answer 421 Misdirected Request
+    Misdirected,
+    /// Redirect to the same path on the configured domain
+    Redirect,
+}
+
+/// The hosts this instance answers for and what to do with every other host.
+pub struct HostPolicy {
+    policy: UnknownHostPolicy,
+    canonical: String,
+    scheme: &'static str,
+    hosts: ReadMostly<HashSet<String>>,
+}
+
+impl HostPolicy {
+    /// `canonical` is the configured domain name, which is always allowed and is where unknown
+    /// hosts are redirected to. Like any configured domain it may carry a scheme, see
+    /// [domain_host].
+    pub fn new(policy: UnknownHostPolicy, canonical: &str, https: bool) -> Self {
+        let canonical = domain_host(canonical);
+        HostPolicy {
+            policy,
+            scheme: if https { "https" } else { "http" },
+            hosts: ReadMostly::new(HashSet::from([canonical.clone()])),
+            canonical,
+        }
+    }
+    /// Replaces the allowed hosts (besides the canonical one), e.g. when domains are added
+    pub fn set_hosts(&self, hosts: impl IntoIterator<Item = String>) {
+        let mut allowed: HashSet<String> = hosts.into_iter().map(|h| domain_host(&h)).collect();
+        allowed.insert(self.canonical.clone());
+        self.hosts.write(|hosts| *hosts = allowed);
+    }
+    fn allows(&self, host: &str) -> bool {
+        let host = normalize_host(host);
+        self.hosts.read(|hosts| hosts.contains(&host))
+    }
+}
+
+/// Middleware applying the [HostPolicy] in its state to every request
+pub async fn check(State(policy): State<Arc<HostPolicy>>, req: Request, next: Next) -> Response {
+    if policy.policy == UnknownHostPolicy::Pass || HOST_AGNOSTIC_PATHS.contains(&req.uri().path()) {
+        return next.run(req).await;
+    }
+    let host = req
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| req.uri().host());
+    if host.is_some_and(|host| policy.allows(host)) {
+        return next.run(req).await;
+    }
+
+    match policy.policy {
+        UnknownHostPolicy::Redirect => {
+            let path = req
+                .uri()
+                .path_and_query()
+                .map(|path| path.as_str())
+                .unwrap_or("/");
+            let location = format!("{}://{}{path}", policy.scheme, policy.canonical);
+            match HeaderValue::from_str(&location) {
+                Ok(location) => Response::builder()
+                    .status(StatusCode::PERMANENT_REDIRECT)
+                    .header(LOCATION, location)
+                    .body(Body::empty())
+                    .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR.into_response()),
+                Err(_) => StatusCode::MISDIRECTED_REQUEST.into_response(),
+            }
+        }
+        UnknownHostPolicy::Misdirected | UnknownHostPolicy::Pass => {
+            StatusCode::MISDIRECTED_REQUEST.into_response()
+        }
+    }
+}
+
+/// Lowercases a host and drops any port and trailing dot, so `Short.Example.:8443` and
+/// `short.example` compare equal
+pub fn normalize_host(host: &str) -> String {
+    let host = host.trim();
+    let without_port = match host.strip_prefix('[') {
+        Some(v6) => v6.split(']').next().unwrap_or(v6),
+        None => match host.rsplit_once(':') {
+            Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+            _ => host,
+        },
+    };
+    without_port.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// The host of a configured domain, which may carry a scheme, port or path, as [normalize_host]
+/// gives it
+pub fn domain_host(domain: &str) -> String {
+    let without_scheme = domain.split_once("://").map_or(domain, |(_, rest)| rest);
+    normalize_host(without_scheme.split('/').next().unwrap_or_default())
+}
+
+/// Whether `host` is one of `domains`, or one of them with or without `www.`, whatever the case,
+/// port, trailing dot or unicode spelling of either
+pub fn is_own_host<'a>(host: &str, domains: impl IntoIterator<Item = &'a str>) -> bool {
+    let site = |host: String| {
+        let host = idna::domain_to_ascii(&host).unwrap_or(host);
+        match host.strip_prefix("www.") {
+            Some(bare) => bare.to_string(),
+            None => host,
+        }
+    };
+    let host = site(normalize_host(host));
+    domains
+        .into_iter()
+        .any(|domain| site(domain_host(domain)) == host)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn app(policy: UnknownHostPolicy) -> Router {
+        let policy = Arc::new(HostPolicy::new(policy, "short.example", true));
+        Router::new()
+            .route("/:code", get(|| async { "site" }))
+            .layer(middleware::from_fn_with_state(policy, check))
+    }
+
+    async fn request(policy: UnknownHostPolicy, path: &str, host: Option<&str>) -> Response {
+        let mut req = Request::builder().uri(path);
+        if let Some(host) = host {
+            req = req.header(HOST, host);
+        }
+        app(policy)
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn known_hosts_are_served() {
+        for policy in [
+            UnknownHostPolicy::Pass,
+            UnknownHostPolicy::Misdirected,
+            UnknownHostPolicy::Redirect,
+        ] {
+            for host in ["short.example", "Short.Example.", "short.example:8443"] {
+                let resp = request(policy, "/abc", Some(host)).await;
+                assert_eq!(resp.status(), StatusCode::OK, "{policy:?} {host}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_hosts_follow_policy() {
+        for host in [Some("random.short.example"), Some("evil.example:80"), None] {
+            let pass = request(UnknownHostPolicy::Pass, "/abc", host).await;
+            assert_eq!(pass.status(), StatusCode::OK);
+
+            let misdirected = request(UnknownHostPolicy::Misdirected, "/abc", host).await;
+            assert_eq!(misdirected.status(), StatusCode::MISDIRECTED_REQUEST);
+
+            let redirect = request(UnknownHostPolicy::Redirect, "/abc?x=1", host).await;
+            assert_eq!(redirect.status(), StatusCode::PERMANENT_REDIRECT);
+            assert_eq!(
+                redirect.headers().get(LOCATION).unwrap(),
+                "https://short.example/abc?x=1"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn health_checks_ignore_host() {
+        let resp = request(
+            UnknownHostPolicy::Misdirected,
+            "/readyz",
+            Some("10.0.0.5:8080"),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn refreshed_hosts_are_allowed() {
+        let policy = HostPolicy::new(UnknownHostPolicy::Misdirected, "short.example", true);
+        assert!(!policy.allows("links.customer.example"));
+
+        policy.set_hosts([String::from("Links.Customer.Example")]);
+        assert!(policy.allows("links.customer.example:443"));
+        assert!(policy.allows("short.example"));
+    }
+
+    #[test]
+    fn configured_domains_may_carry_a_scheme() {
+        let policy = HostPolicy::new(
+            UnknownHostPolicy::Misdirected,
+            "https://Short.Example:8443/",
+            true,
+        );
+        assert!(policy.allows("short.example"));
+        assert!(policy.allows("short.example:8443"));
+        assert_eq!(policy.canonical, "short.example");
+
+        policy.set_hosts([String::from("http://links.customer.example")]);
+        assert!(policy.allows("links.customer.example"));
+        assert!(!policy.allows("https"));
+    }
+
+    #[test]
+    fn own_hosts_match_in_any_form() {
+        let domains = ["https://Short.Example/", "bücher.example:8443"];
+        for host in [
+            "short.example",
+            "SHORT.EXAMPLE.",
+            "www.short.example:443",
+            "xn--bcher-kva.example",
+            "www.bücher.example",
+        ] {
+            assert!(is_own_host(host, domains), "{host}");
+        }
+        for host in ["a.short.example", "short.example.com", "notshort.example"] {
+            assert!(!is_own_host(host, domains), "{host}");
+        }
+    }
+}
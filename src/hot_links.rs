@@ -0,0 +1,248 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tracing::{info, warn};
+
+use crate::locked::Locked;
+
+/// How often the clicks counted by [ClickRates] are checked against
+/// [HotLinkPrefs::auto_flag_per_second]
+const RATE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Sharded click counters, for links clicked so often that waiting on the lock of their row in
+/// urls holds up redirects. Hot links count their clicks in url_click_shards, each click in a
+/// shard picked at random, and the shards are folded back into urls.clicks every so often.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct HotLinkPrefs {
+    /// Counter rows each hot link gets
+    shards: u16,
+    /// Links clicked at least this many times a second on one server are made hot. Unset, links
+    /// are only made hot by an admin.
+    auto_flag_per_second: Option<u32>,
+    /// How often the shards of hot links are folded back into their counts, in seconds
+    fold_interval_secs: u64,
+}
+
+impl Default for HotLinkPrefs {
+    fn default() -> Self {
+        HotLinkPrefs {
+            shards: 16,
+            auto_flag_per_second: None,
+            fold_interval_secs: 60,
+        }
+    }
+}
+
+impl HotLinkPrefs {
+    pub fn shards(&self) -> i32 {
+        i32::from(self.shards.max(1))
+    }
+    pub fn auto_flag_per_second(&self) -> Option<u32> {
+        self.auto_flag_per_second
+    }
+    pub fn fold_interval(&self) -> Duration {
+        Duration::from_secs(self.fold_interval_secs.max(1))
+    }
+    #[cfg(test)]
+    pub fn set_auto_flag_per_second(&mut self, per_second: Option<u32>) {
+        self.auto_flag_per_second = per_second;
+    }
+}
+
+/// Clicks on each link since the rates were last checked, on this server only. Only counted while
+/// [HotLinkPrefs::auto_flag_per_second] is set.
+#[derive(Default)]
+pub struct ClickRates {
+    clicks: Locked<HashMap<i64, u64>>,
+}
+
+impl ClickRates {
+    pub fn record(&self, url_id: i64) {
+        self.clicks
+            .with(|clicks| *clicks.entry(url_id).or_default() += 1);
+    }
+
+    /// The links clicked at least `per_second` times a second over `elapsed`, the time since the
+    /// last call. Counting starts over.
+    pub fn take_hot(&self, per_second: u32, elapsed: Duration) -> Vec<i64> {
+        let clicks = self.clicks.with(std::mem::take);
+        let threshold = f64::from(per_second) * elapsed.as_secs_f64();
+        let mut hot: Vec<i64> = clicks
+            .into_iter()
+            .filter(|(_, count)| *count as f64 >= threshold)
+            .map(|(id, _)| id)
+            .collect();
+        hot.sort_unstable();
+        hot
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Flagged {
+    Now,
+    Already,
+    /// The link has a click limit or is single use, or there is no such link
+    Limited,
+}
+
+/// Makes link `id` hot by giving it `shards` counters, if it isn't already. Links with a click
+/// limit or single use can't be, as the limit is checked against a single count.
+pub async fn flag(id: i64, shards: i32, pool: &PgPool) -> Result<Flagged, sqlx::Error> {
+    let flagged = sqlx::query!(
+        r#"WITH link AS (
+            SELECT id FROM urls WHERE id = $1 AND max_clicks IS NULL AND single_use IS NOT TRUE
+        ), added AS (
+            INSERT INTO url_click_shards (url_id, shard)
+            SELECT id, generate_series(0, $2 - 1) FROM link
+            ON CONFLICT DO NOTHING
+            RETURNING url_id
+        )
+        SELECT EXISTS (SELECT 1 FROM link) AS "hot!", EXISTS (SELECT 1 FROM added) AS "added!""#,
+        id,
+        shards,
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(match (flagged.hot, flagged.added) {
+        (false, _) => Flagged::Limited,
+        (true, true) => Flagged::Now,
+        (true, false) => Flagged::Already,
+    })
+}
+
+/// Folds the shards of link `id` back into its count and removes them, so its clicks go to its
+/// row in urls again. Returns the clicks folded.
+pub async fn unflag(id: i64, pool: &PgPool) -> Result<i64, sqlx::Error> {
+    fold_shards(id, true, pool).await
+}
+
+/// Folds the clicks in the shards of link `id` into its count in urls. Returns the clicks folded.
+pub async fn fold(id: i64, pool: &PgPool) -> Result<i64, sqlx::Error> {
+    fold_shards(id, false, pool).await
+}
+
+/// The shards are locked, emptied or removed and their clicks added to urls.clicks in one
+/// transaction. Reads sum both in one statement, so they see the clicks either in the shards or in
+/// urls, never in both or neither. Clicks waiting on a locked shard meanwhile are counted in it
+/// once the fold is done, or in urls if it was removed.
+async fn fold_shards(id: i64, remove: bool, pool: &PgPool) -> Result<i64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let folded = sqlx::query_scalar!(
+        r#"SELECT COALESCE(SUM(clicks), 0)::BIGINT AS "folded!" FROM (
+            SELECT clicks FROM url_click_shards WHERE url_id = $1 ORDER BY shard FOR UPDATE
+        ) shards"#,
+        id,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if remove {
+        sqlx::query!("DELETE FROM url_click_shards WHERE url_id = $1", id)
+            .execute(&mut *tx)
+            .await?;
+    } else if folded > 0 {
+        sqlx::query!(
+            "UPDATE url_click_shards SET clicks = 0 WHERE url_id = $1 AND clicks > 0",
+            id,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    if folded > 0 {
+        sqlx::query!(
+            "UPDATE urls SET clicks = clicks + $2 WHERE id = $1",
+            id,
+            folded,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(folded)
+}
+
+/// Folds the shards of every hot link that has clicks in them, every `interval`
+pub async fn compact(pool: PgPool, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let ids = sqlx::query_scalar!(
+            "SELECT DISTINCT url_id FROM url_click_shards WHERE clicks > 0 ORDER BY url_id"
+        )
+        .fetch_all(&pool)
+        .await;
+        let ids = match ids {
+            Ok(ids) => ids,
+            Err(e) => {
+                warn!("Couldn't look for hot links to fold: {e}");
+                continue;
+            }
+        };
+        for id in ids {
+            if let Err(e) = fold(id, &pool).await {
+                warn!("Couldn't fold the click shards of link {id}: {e}");
+            }
+        }
+    }
+}
+
+/// Makes the links clicked at least `per_second` times a second over `elapsed` hot. Returns the
+/// ids of the links that weren't hot before.
+pub async fn flag_busy(
+    rates: &ClickRates,
+    per_second: u32,
+    elapsed: Duration,
+    shards: i32,
+    pool: &PgPool,
+) -> Result<Vec<i64>, sqlx::Error> {
+    let mut flagged = Vec::new();
+    for id in rates.take_hot(per_second, elapsed) {
+        if flag(id, shards, pool).await? == Flagged::Now {
+            flagged.push(id);
+        }
+    }
+    Ok(flagged)
+}
+
+/// Makes links hot once they are clicked [HotLinkPrefs::auto_flag_per_second] times a second,
+/// checking every [RATE_INTERVAL]. Only run while that is set.
+pub async fn watch(rates: Arc<ClickRates>, prefs: HotLinkPrefs, pool: PgPool) {
+    let Some(per_second) = prefs.auto_flag_per_second() else {
+        return;
+    };
+    let mut interval = tokio::time::interval(RATE_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match flag_busy(&rates, per_second, RATE_INTERVAL, prefs.shards(), &pool).await {
+            Ok(flagged) => {
+                for id in flagged {
+                    info!("Link {id} is clicked {per_second} times a second or more, made it hot");
+                }
+            }
+            Err(e) => warn!("Couldn't make busy links hot: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_links_past_the_rate_are_hot() {
+        let rates = ClickRates::default();
+        for _ in 0..50 {
+            rates.record(1);
+        }
+        for _ in 0..49 {
+            rates.record(2);
+        }
+        rates.record(3);
+
+        assert_eq!(rates.take_hot(5, Duration::from_secs(10)), [1]);
+        // Counting starts over
+        assert!(rates.take_hot(5, Duration::from_secs(10)).is_empty());
+    }
+}
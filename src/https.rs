@@ -0,0 +1,352 @@
+use std::{net::IpAddr, str::FromStr, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{
+        header::{LOCATION, STRICT_TRANSPORT_SECURITY},
+        HeaderValue, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{host_policy::domain_host, preferences::Preferences, public_suffix};
+
+/// Lowest max-age the HSTS preload list accepts, one year
+pub const PRELOAD_MIN_MAX_AGE: u64 = 60 * 60 * 24 * 365;
+
+/// The plain HTTP listener and Strict-Transport-Security. None of it applies without a
+/// certificate.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct HttpsPrefs {
+    /// Port of a plain HTTP listener that redirects everything to HTTPS, usually 80. Unset for no
+    /// listener.
+    redirect_port: Option<u16>,
+    /// Paths the HTTP listener answers itself instead of redirecting, e.g. load balancer probes.
+    /// Ones ending in `/` cover everything under them. The app has no route for ACME http-01
+    /// challenges, which are redirected like anything else and followed by ACME servers.
+    redirect_exempt: Vec<String>,
+    /// 0 sends no Strict-Transport-Security header
+    hsts_max_age_secs: u64,
+    hsts_include_subdomains: bool,
+    /// Sends the header the HSTS preload list asks for, whatever the two settings above say:
+    /// at least a year, includeSubDomains and preload
+    hsts_preload: bool,
+}
+
+impl Default for HttpsPrefs {
+    fn default() -> Self {
+        HttpsPrefs {
+            redirect_port: None,
+            redirect_exempt: vec![String::from("/healthz"), String::from("/readyz")],
+            hsts_max_age_secs: 0,
+            hsts_include_subdomains: false,
+            hsts_preload: false,
+        }
+    }
+}
+
+impl HttpsPrefs {
+    #[cfg(test)]
+    pub fn set_redirect_exempt(&mut self, redirect_exempt: Vec<String>) {
+        self.redirect_exempt = redirect_exempt;
+    }
+
+    pub fn redirect_port(&self) -> Option<u16> {
+        self.redirect_port
+    }
+
+    /// The Strict-Transport-Security value to send over HTTPS, or None for no header
+    pub fn hsts(&self) -> Option<String> {
+        if self.hsts_preload {
+            let max_age = self.hsts_max_age_secs.max(PRELOAD_MIN_MAX_AGE);
+            return Some(format!("max-age={max_age}; includeSubDomains; preload"));
+        }
+        if self.hsts_max_age_secs == 0 {
+            return None;
+        }
+        let mut hsts = format!("max-age={}", self.hsts_max_age_secs);
+        if self.hsts_include_subdomains {
+            hsts.push_str("; includeSubDomains");
+        }
+        Some(hsts)
+    }
+
+    fn is_exempt(&self, path: &str) -> bool {
+        self.redirect_exempt.iter().any(|exempt| {
+            if exempt.ends_with('/') {
+                path.starts_with(exempt.as_str())
+            } else {
+                path == exempt
+            }
+        })
+    }
+}
+
+/// Where the HTTP listener sends requests, and which ones it keeps
+pub struct Redirect {
+    prefs: HttpsPrefs,
+    /// Host, plus the port when it isn't 443
+    authority: String,
+}
+
+impl Redirect {
+    pub fn new(prefs: &HttpsPrefs, domain_name: &str, https_port: u32) -> Self {
+        let host = domain_host(domain_name);
+        let authority = match https_port {
+            443 => host,
+            port => format!("{host}:{port}"),
+        };
+        Redirect {
+            prefs: prefs.clone(),
+            authority,
+        }
+    }
+}
+
+/// Middleware for the HTTP listener: exempt paths go on to the app, everything else is sent to
+/// the same path and query over HTTPS on the configured domain
+pub async fn to_https(State(redirect): State<Arc<Redirect>>, req: Request, next: Next) -> Response {
+    if redirect.prefs.is_exempt(req.uri().path()) {
+        return next.run(req).await;
+    }
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    let location = format!("https://{}{path}", redirect.authority);
+    match HeaderValue::from_str(&location) {
+        Ok(location) => Response::builder()
+            .status(StatusCode::PERMANENT_REDIRECT)
+            .header(LOCATION, location)
+            .body(Body::empty())
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR.into_response()),
+        Err(_) => StatusCode::BAD_REQUEST.into_response(),
+    }
+}
+
+/// Middleware for the HTTPS listener adding the Strict-Transport-Security header from
+/// [HttpsPrefs::hsts]
+pub async fn add_hsts(State(hsts): State<HeaderValue>, req: Request, next: Next) -> Response {
+    let mut resp = next.run(req).await;
+    resp.headers_mut().insert(STRICT_TRANSPORT_SECURITY, hsts);
+    resp
+}
+
+/// Whether the domain can be submitted to the HSTS preload list as configured, and if not, why
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PreloadReadiness {
+    pub ready: bool,
+    /// The header HTTPS responses carry, if any
+    pub header: Option<String>,
+    pub failures: Vec<String>,
+}
+
+/// Checks the instance's own configuration against the preload list's requirements. Only what
+/// the configuration decides is checked: that every subdomain serves HTTPS can't be seen from here.
+pub fn preload_readiness(prefs: &Preferences) -> PreloadReadiness {
+    let https = prefs.https();
+    let mut failures = Vec::new();
+    if prefs.https_cert_path().is_none() || prefs.https_key_path().is_none() {
+        failures.push(String::from(
+            "No certificate is configured, so nothing is served over HTTPS",
+        ));
+    }
+    if prefs.port() != 443 {
+        failures.push(format!("HTTPS is served on port {}, not 443", prefs.port()));
+    }
+    match https.redirect_port {
+        None => failures.push(String::from(
+            "There is no HTTP listener redirecting to HTTPS, set https.redirect_port = 80",
+        )),
+        Some(80) => {}
+        Some(port) => failures.push(format!(
+            "HTTP is redirected from port {port}, browsers use port 80"
+        )),
+    }
+
+    let domain = domain_host(prefs.domain_name());
+    if IpAddr::from_str(&domain).is_ok() || !domain.contains('.') {
+        failures.push(format!("{domain} isn't a public domain name"));
+    } else {
+        let registrable = public_suffix::host_group(&domain);
+        if registrable.as_str() != domain {
+            failures.push(format!(
+                "{domain} is a subdomain, only {registrable} itself can be preloaded"
+            ));
+        }
+    }
+
+    let header = https.hsts();
+    match header.as_deref().map(directives) {
+        None => failures.push(String::from("No Strict-Transport-Security header is sent")),
+        Some(hsts) => {
+            if hsts.max_age < PRELOAD_MIN_MAX_AGE {
+                failures.push(format!(
+                    "max-age is {}, it has to be at least {PRELOAD_MIN_MAX_AGE}",
+                    hsts.max_age
+                ));
+            }
+            if !hsts.include_subdomains {
+                failures.push(String::from("The header lacks includeSubDomains"));
+            }
+            if !hsts.preload {
+                failures.push(String::from(
+                    "The header lacks the preload directive, set https.hsts_preload = true",
+                ));
+            }
+        }
+    }
+    PreloadReadiness {
+        ready: failures.is_empty(),
+        header,
+        failures,
+    }
+}
+
+struct Directives {
+    max_age: u64,
+    include_subdomains: bool,
+    preload: bool,
+}
+
+/// Reads a Strict-Transport-Security value back, the way the preload list does
+fn directives(hsts: &str) -> Directives {
+    let mut directives = Directives {
+        max_age: 0,
+        include_subdomains: false,
+        preload: false,
+    };
+    for directive in hsts.split(';').map(str::trim) {
+        if let Some(max_age) = directive.strip_prefix("max-age=") {
+            directives.max_age = max_age.parse().unwrap_or(0);
+        } else if directive.eq_ignore_ascii_case("includeSubDomains") {
+            directives.include_subdomains = true;
+        } else if directive.eq_ignore_ascii_case("preload") {
+            directives.preload = true;
+        }
+    }
+    directives
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn https_prefs(toml: &str) -> HttpsPrefs {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn redirects_keep_a_port_other_than_443() {
+        let redirect = Redirect::new(&HttpsPrefs::default(), "Short.Example", 443);
+        assert_eq!(redirect.authority, "short.example");
+        let elsewhere = Redirect::new(&HttpsPrefs::default(), "https://short.example/", 8443);
+        assert_eq!(elsewhere.authority, "short.example:8443");
+    }
+
+    #[test]
+    fn hsts_headers() {
+        assert_eq!(HttpsPrefs::default().hsts(), None);
+        assert_eq!(
+            https_prefs("hsts_max_age_secs = 600").hsts().as_deref(),
+            Some("max-age=600")
+        );
+        assert_eq!(
+            https_prefs("hsts_max_age_secs = 600\nhsts_include_subdomains = true")
+                .hsts()
+                .as_deref(),
+            Some("max-age=600; includeSubDomains")
+        );
+        // Preload brings everything up to what the list requires
+        assert_eq!(
+            https_prefs("hsts_max_age_secs = 600\nhsts_preload = true")
+                .hsts()
+                .as_deref(),
+            Some("max-age=31536000; includeSubDomains; preload")
+        );
+        assert_eq!(
+            https_prefs("hsts_max_age_secs = 63072000\nhsts_preload = true")
+                .hsts()
+                .as_deref(),
+            Some("max-age=63072000; includeSubDomains; preload")
+        );
+    }
+
+    fn readiness(domain: &str, port: u32, certificate: bool, https: &str) -> PreloadReadiness {
+        let certificate = if certificate {
+            "https_cert_path = \"cert.pem\"\nhttps_key_path = \"key.pem\""
+        } else {
+            ""
+        };
+        let config = format!(
+            r#"
+            url_len = 6
+            domain_name = "{domain}"
+            http_ip = "0.0.0.0"
+            port = {port}
+            db_ip = "127.0.0.1"
+            db_name = "shortener"
+            db_user = "postgres"
+            db_pass = "pw"
+            db_port = 5432
+            db_pool_size = 10
+            jwt_secret = "secret"
+            {certificate}
+            [https]
+            {https}
+            "#
+        );
+        let prefs: Preferences = toml::from_str(&config).unwrap();
+        preload_readiness(&prefs)
+    }
+
+    #[test]
+    fn preload_readiness_names_each_failure() {
+        let preload = "redirect_port = 80\nhsts_preload = true";
+        let ready = readiness("example.com", 443, true, preload);
+        assert_eq!(ready.failures, Vec::<String>::new());
+        assert!(ready.ready);
+        assert_eq!(
+            ready.header.as_deref(),
+            Some("max-age=31536000; includeSubDomains; preload")
+        );
+        assert!(readiness("https://Example.com/", 443, true, preload).ready);
+
+        let not_ready = readiness(
+            "short.example.com",
+            443,
+            true,
+            "redirect_port = 8080\nhsts_max_age_secs = 86400\nhsts_include_subdomains = true",
+        );
+        assert!(!not_ready.ready);
+        assert_eq!(
+            not_ready.failures,
+            [
+                "HTTP is redirected from port 8080, browsers use port 80",
+                "short.example.com is a subdomain, only example.com itself can be preloaded",
+                "max-age is 86400, it has to be at least 31536000",
+                "The header lacks the preload directive, set https.hsts_preload = true",
+            ]
+        );
+
+        let plain = readiness("localhost", 8080, false, "");
+        assert_eq!(
+            plain.failures,
+            [
+                "No certificate is configured, so nothing is served over HTTPS",
+                "HTTPS is served on port 8080, not 443",
+                "There is no HTTP listener redirecting to HTTPS, set https.redirect_port = 80",
+                "localhost isn't a public domain name",
+                "No Strict-Transport-Security header is sent",
+            ]
+        );
+        assert_eq!(plain.header, None);
+        let ip = readiness("203.0.113.7", 443, true, preload);
+        assert_eq!(ip.failures, ["203.0.113.7 isn't a public domain name"]);
+    }
+}
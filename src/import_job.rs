@@ -0,0 +1,632 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use axum::body::Body;
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgExecutor, PgPool};
+use tokio::io::AsyncWriteExt;
+use tracing::{error, info, warn};
+
+use crate::{
+    link_import::{self, ImportRow, Importer, Outcome, Report},
+    preferences::Preferences,
+    user,
+};
+
+/// Rows imported in each transaction. Progress and cancellation are only looked at in between.
+const BATCH_ROWS: usize = 500;
+/// How often finished imports past their retention are deleted, with their spooled files
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Limits on CSV uploads, and when they are imported in the background
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct ImportPrefs {
+    /// Largest upload accepted, in bytes, 20 MiB unless set
+    max_bytes: u64,
+    /// Uploads up to this many bytes are imported while the request waits, the rest become a job
+    sync_max_bytes: u64,
+    /// Where uploads wait to be imported. The system's temporary directory unless set.
+    spool_dir: Option<PathBuf>,
+    /// How long the report of a finished import can be fetched, in days
+    retention_days: u32,
+}
+
+impl Default for ImportPrefs {
+    fn default() -> Self {
+        ImportPrefs {
+            max_bytes: 20 * 1024 * 1024,
+            sync_max_bytes: 64 * 1024,
+            spool_dir: None,
+            retention_days: 7,
+        }
+    }
+}
+
+impl ImportPrefs {
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes
+    }
+    pub fn sync_max_bytes(&self) -> u64 {
+        self.sync_max_bytes
+    }
+    pub fn spool_dir(&self) -> PathBuf {
+        self.spool_dir.clone().unwrap_or_else(std::env::temp_dir)
+    }
+    pub fn retention(&self) -> Duration {
+        Duration::from_secs(u64::from(self.retention_days) * 60 * 60 * 24)
+    }
+    #[cfg(test)]
+    pub fn set_sync_max_bytes(&mut self, sync_max_bytes: u64) {
+        self.sync_max_bytes = sync_max_bytes;
+    }
+    #[cfg(test)]
+    pub fn set_max_bytes(&mut self, max_bytes: u64) {
+        self.max_bytes = max_bytes;
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Queued,
+    Running,
+    Finished,
+    Cancelled,
+    /// Stopped by a file that isn't CSV or an error of the database, see [Job::error]
+    Failed,
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Queued => "queued",
+            Status::Running => "running",
+            Status::Finished => "finished",
+            Status::Cancelled => "cancelled",
+            Status::Failed => "failed",
+        }
+    }
+    fn parse(value: &str) -> Self {
+        match value {
+            "running" => Status::Running,
+            "finished" => Status::Finished,
+            "cancelled" => Status::Cancelled,
+            "failed" => Status::Failed,
+            _ => Status::Queued,
+        }
+    }
+    /// Whether the import stopped, so it won't change anymore
+    pub fn is_done(&self) -> bool {
+        matches!(self, Status::Finished | Status::Cancelled | Status::Failed)
+    }
+}
+
+/// An import as its owner is shown it. `parsed` is None until the file has been read, and
+/// `processed` counts the rows gone through so far, each imported, skipped or invalid.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Job {
+    pub id: i64,
+    pub status: Status,
+    pub parsed: Option<i64>,
+    pub processed: i64,
+    pub imported: i64,
+    pub skipped: i64,
+    pub invalid: i64,
+    pub cancel_requested: bool,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+/// The row as stored, status being plain text
+struct JobRow {
+    id: i64,
+    status: String,
+    parsed: Option<i64>,
+    processed: i64,
+    imported: i64,
+    skipped: i64,
+    invalid: i64,
+    cancel_requested: bool,
+    error: Option<String>,
+    created_at: DateTime<Utc>,
+    finished_at: Option<DateTime<Utc>>,
+}
+
+impl From<JobRow> for Job {
+    fn from(row: JobRow) -> Self {
+        Job {
+            id: row.id,
+            status: Status::parse(&row.status),
+            parsed: row.parsed,
+            processed: row.processed,
+            imported: row.imported,
+            skipped: row.skipped,
+            invalid: row.invalid,
+            cancel_requested: row.cancel_requested,
+            error: row.error,
+            created_at: row.created_at,
+            finished_at: row.finished_at,
+        }
+    }
+}
+
+/// A row of an import that wasn't imported, with why
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RowError {
+    pub line: i64,
+    /// `skipped` or `invalid`
+    pub outcome: String,
+    pub reason: String,
+}
+
+impl RowError {
+    fn new(line: u64, outcome: &Outcome) -> Option<Self> {
+        let (kind, reason) = match outcome {
+            Outcome::Imported => return None,
+            Outcome::Skipped(reason) => ("skipped", reason),
+            Outcome::Invalid(reason) => ("invalid", reason),
+        };
+        Some(RowError {
+            line: i64::try_from(line).unwrap_or(i64::MAX),
+            outcome: String::from(kind),
+            reason: reason.clone(),
+        })
+    }
+}
+
+/// Writes `errors` as a `line,outcome,reason` CSV, for owners to fix the rows and upload them again
+pub fn errors_csv(errors: &[RowError]) -> Result<String, csv::Error> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(["line", "outcome", "reason"])?;
+    for error in errors {
+        writer.write_record([&error.line.to_string(), &error.outcome, &error.reason])?;
+    }
+    let bytes = writer.into_inner().map_err(|e| e.into_error())?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[derive(Debug)]
+pub enum SpoolError {
+    TooLarge,
+    /// The client stopped sending for longer than it's given between chunks
+    Stalled,
+    Body(axum::Error),
+    Io(std::io::Error),
+}
+
+/// An upload written to the spool directory
+#[derive(Debug)]
+pub struct Spooled {
+    pub path: PathBuf,
+    pub len: u64,
+}
+
+/// Streams `body` to a new file in the spool directory, refusing it once it's larger than
+/// [ImportPrefs::max_bytes] or stalls for `idle`. Nothing is left behind when it fails.
+pub async fn spool(body: Body, prefs: &ImportPrefs, idle: Duration) -> Result<Spooled, SpoolError> {
+    let path = prefs.spool_dir().join(format!(
+        "url_shortener_import_{:016x}.csv",
+        rand::random::<u64>()
+    ));
+    let written = write_body(body, &path, prefs.max_bytes(), idle).await;
+    if written.is_err() {
+        let _ = tokio::fs::remove_file(&path).await;
+    }
+    written.map(|len| Spooled { path, len })
+}
+
+async fn write_body(
+    body: Body,
+    path: &Path,
+    max_bytes: u64,
+    idle: Duration,
+) -> Result<u64, SpoolError> {
+    let mut file = tokio::fs::File::create(path)
+        .await
+        .map_err(SpoolError::Io)?;
+    let mut stream = body.into_data_stream();
+    let mut len = 0u64;
+    loop {
+        let chunk = match tokio::time::timeout(idle, stream.next()).await {
+            Err(_) => return Err(SpoolError::Stalled),
+            Ok(None) => break,
+            Ok(Some(chunk)) => chunk.map_err(SpoolError::Body)?,
+        };
+        len += chunk.len() as u64;
+        if len > max_bytes {
+            return Err(SpoolError::TooLarge);
+        }
+        file.write_all(&chunk).await.map_err(SpoolError::Io)?;
+    }
+    file.flush().await.map_err(SpoolError::Io)?;
+    Ok(len)
+}
+
+/// The rows of an uploaded file as `username` imports them. Every link is owned by the uploader, so
+/// a row made by someone else is invalid rather than imported for them.
+fn owned_by(row: &ImportRow, username: &str) -> Result<ImportRow, Outcome> {
+    match &row.created_by {
+        Some(created_by) if created_by != username => Err(Outcome::Invalid(String::from(
+            "Links can only be imported for yourself",
+        ))),
+        _ => Ok(ImportRow {
+            line: row.line,
+            long_url: row.long_url.clone(),
+            short_url: row.short_url.clone(),
+            created_by: Some(username.to_string()),
+        }),
+    }
+}
+
+/// Imports `rows` for `username`, counting each in `report` and keeping the ones that weren't
+/// imported in `errors`
+async fn import_rows(
+    rows: &[ImportRow],
+    username: &str,
+    importer: &mut Importer<'_>,
+    report: &mut Report,
+    errors: &mut Vec<RowError>,
+    conn: &mut PgConnection,
+) -> Result<(), sqlx::Error> {
+    for row in rows {
+        let outcome = match owned_by(row, username) {
+            Ok(row) => importer.import(&row, &mut *conn).await?,
+            Err(outcome) => outcome,
+        };
+        report.count(&outcome);
+        errors.extend(RowError::new(row.line, &outcome));
+    }
+    Ok(())
+}
+
+/// Imports a small upload while the request waits, for the uploader `username`
+pub async fn import_now(
+    contents: &str,
+    username: &str,
+    prefs: &Preferences,
+    pool: &PgPool,
+) -> Result<Result<(Report, Vec<RowError>), csv::Error>, sqlx::Error> {
+    let rows = match link_import::read_rows(contents) {
+        Ok(rows) => rows,
+        Err(e) => return Ok(Err(e)),
+    };
+    let mut conn = pool.acquire().await?;
+    let mut importer = Importer::new(prefs, false);
+    let (mut report, mut errors) = (Report::default(), Vec::new());
+    import_rows(
+        &rows,
+        username,
+        &mut importer,
+        &mut report,
+        &mut errors,
+        &mut conn,
+    )
+    .await?;
+    Ok(Ok((report, errors)))
+}
+
+/// Records a job importing the spooled file at `path` for `user_id`. Start it with [run].
+pub async fn enqueue(
+    user_id: i64,
+    path: &Path,
+    db: impl PgExecutor<'_>,
+) -> Result<i64, sqlx::Error> {
+    let path = path.to_string_lossy();
+    sqlx::query_scalar!(
+        "INSERT INTO import_jobs (user_id, path) VALUES ($1, $2) RETURNING id",
+        user_id,
+        path.as_ref(),
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// An import of `user_id`, None if there's no such import or it isn't theirs
+pub async fn job(
+    id: i64,
+    user_id: i64,
+    db: impl PgExecutor<'_>,
+) -> Result<Option<Job>, sqlx::Error> {
+    let row = sqlx::query_as!(
+        JobRow,
+        "SELECT id, status, parsed, processed, imported, skipped, invalid, cancel_requested, error,
+            created_at, finished_at
+        FROM import_jobs WHERE id = $1 AND user_id = $2",
+        id,
+        user_id,
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(row.map(Job::from))
+}
+
+/// The rows of an import that weren't imported, in file order
+pub async fn errors(job_id: i64, db: impl PgExecutor<'_>) -> Result<Vec<RowError>, sqlx::Error> {
+    sqlx::query_as!(
+        RowError,
+        "SELECT line, outcome, reason FROM import_job_errors WHERE job_id = $1 ORDER BY line",
+        job_id,
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Asks an import of `user_id` to stop at the end of the batch it's on. Returns the import as it
+/// is now, None if there's no such import, and the import unchanged if it already stopped.
+pub async fn cancel(id: i64, user_id: i64, db: &PgPool) -> Result<Option<Job>, sqlx::Error> {
+    sqlx::query!(
+        "UPDATE import_jobs SET cancel_requested = TRUE
+        WHERE id = $1 AND user_id = $2 AND status IN ('queued', 'running')",
+        id,
+        user_id,
+    )
+    .execute(db)
+    .await?;
+    job(id, user_id, db).await
+}
+
+/// How a batch left the import
+#[derive(Debug, PartialEq)]
+pub enum Progress {
+    More,
+    Done(Status),
+}
+
+/// An import being run, with its rows read from the spooled file
+pub struct Run<'a> {
+    id: i64,
+    path: PathBuf,
+    username: String,
+    rows: Vec<ImportRow>,
+    processed: usize,
+    importer: Importer<'a>,
+}
+
+impl<'a> Run<'a> {
+    /// Picks up import `id` where it was left, None if it already stopped. A file that can't be
+    /// read fails the import.
+    pub async fn load(
+        id: i64,
+        prefs: &'a Preferences,
+        pool: &PgPool,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        let Some(job) = sqlx::query!(
+            "UPDATE import_jobs SET status = 'running'
+            WHERE id = $1 AND status IN ('queued', 'running')
+            RETURNING user_id, path, processed",
+            id,
+        )
+        .fetch_optional(pool)
+        .await?
+        else {
+            return Ok(None);
+        };
+        let path = PathBuf::from(job.path);
+        let read = tokio::fs::read_to_string(&path)
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|contents| link_import::read_rows(&contents).map_err(|e| e.to_string()));
+        let rows = match read {
+            Ok(rows) => rows,
+            Err(problem) => {
+                finish(id, &path, Status::Failed, Some(&problem), pool).await?;
+                return Ok(None);
+            }
+        };
+        sqlx::query!(
+            "UPDATE import_jobs SET parsed = $1 WHERE id = $2",
+            rows.len() as i64,
+            id,
+        )
+        .execute(pool)
+        .await?;
+        let username = user::retrieve_user_by_id(job.user_id, pool)
+            .await?
+            .username()
+            .clone();
+        Ok(Some(Run {
+            id,
+            path,
+            username,
+            rows,
+            processed: usize::try_from(job.processed).unwrap_or(usize::MAX),
+            importer: Importer::new(prefs, false),
+        }))
+    }
+
+    /// Imports the next `batch` rows in one transaction with the counts, unless the import was
+    /// cancelled, so the counts always match what was stored
+    pub async fn next_batch(
+        &mut self,
+        batch: usize,
+        pool: &PgPool,
+    ) -> Result<Progress, sqlx::Error> {
+        let cancelled = sqlx::query_scalar!(
+            "SELECT cancel_requested FROM import_jobs WHERE id = $1",
+            self.id
+        )
+        .fetch_one(pool)
+        .await?;
+        if cancelled {
+            finish(self.id, &self.path, Status::Cancelled, None, pool).await?;
+            return Ok(Progress::Done(Status::Cancelled));
+        }
+        let rows = &self.rows[self.processed.min(self.rows.len())..];
+        let rows = &rows[..batch.min(rows.len())];
+        if rows.is_empty() {
+            finish(self.id, &self.path, Status::Finished, None, pool).await?;
+            return Ok(Progress::Done(Status::Finished));
+        }
+        let (mut report, mut errors) = (Report::default(), Vec::new());
+        let mut tx = pool.begin().await?;
+        import_rows(
+            rows,
+            &self.username,
+            &mut self.importer,
+            &mut report,
+            &mut errors,
+            &mut tx,
+        )
+        .await?;
+        sqlx::query!(
+            "UPDATE import_jobs SET processed = processed + $1, imported = imported + $2,
+                skipped = skipped + $3, invalid = invalid + $4
+            WHERE id = $5",
+            rows.len() as i64,
+            report.imported as i64,
+            report.skipped as i64,
+            report.invalid as i64,
+            self.id,
+        )
+        .execute(&mut *tx)
+        .await?;
+        for error in &errors {
+            sqlx::query!(
+                "INSERT INTO import_job_errors (job_id, line, outcome, reason) VALUES ($1, $2, $3, $4)",
+                self.id,
+                error.line,
+                error.outcome,
+                error.reason,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        self.processed += rows.len();
+        Ok(Progress::More)
+    }
+}
+
+/// Marks import `id` as stopped and deletes its spooled file, which isn't needed anymore
+async fn finish(
+    id: i64,
+    path: &Path,
+    status: Status,
+    error: Option<&str>,
+    pool: &PgPool,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE import_jobs SET status = $1, error = $2, finished_at = now() WHERE id = $3",
+        status.as_str(),
+        error,
+        id,
+    )
+    .execute(pool)
+    .await?;
+    if let Err(e) = tokio::fs::remove_file(path).await {
+        warn!("Couldn't delete the spooled import {}: {e}", path.display());
+    }
+    Ok(())
+}
+
+/// Runs import `id` to the end. Its own task, so it goes on when the upload's connection is gone.
+pub async fn run(id: i64, prefs: Preferences, pool: PgPool) {
+    if let Err(e) = run_batches(id, &prefs, &pool).await {
+        error!("Import {id} stopped: {e}");
+        let failed = sqlx::query!(
+            "UPDATE import_jobs SET status = 'failed', error = $1, finished_at = now() WHERE id = $2",
+            e.to_string(),
+            id,
+        )
+        .execute(&pool)
+        .await;
+        if let Err(e) = failed {
+            warn!("Couldn't mark import {id} as failed: {e}");
+        }
+    }
+}
+
+async fn run_batches(id: i64, prefs: &Preferences, pool: &PgPool) -> Result<(), sqlx::Error> {
+    let Some(mut run) = Run::load(id, prefs, pool).await? else {
+        return Ok(());
+    };
+    while run.next_batch(BATCH_ROWS, pool).await? == Progress::More {}
+    Ok(())
+}
+
+/// Starts the imports that were queued or running when the server last stopped, from the batch
+/// they were on
+pub async fn resume(prefs: Preferences, pool: PgPool) {
+    let unfinished = sqlx::query_scalar!(
+        "SELECT id FROM import_jobs WHERE status IN ('queued', 'running') ORDER BY id"
+    )
+    .fetch_all(&pool)
+    .await;
+    match unfinished {
+        Ok(ids) => {
+            for id in ids {
+                info!("Resuming import {id}");
+                run(id, prefs.clone(), pool.clone()).await;
+            }
+        }
+        Err(e) => warn!("Couldn't look for unfinished imports: {e}"),
+    }
+}
+
+/// Deletes imports that stopped at least `retention` ago, every [PRUNE_INTERVAL]
+pub async fn prune(pool: PgPool, retention: Duration) {
+    let retention_secs = i64::try_from(retention.as_secs()).unwrap_or(i64::MAX) as f64;
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let pruned = sqlx::query!(
+            "DELETE FROM import_jobs WHERE finished_at <= now() - make_interval(secs => $1)",
+            retention_secs
+        )
+        .execute(&pool)
+        .await;
+        match pruned {
+            Ok(done) if done.rows_affected() > 0 => {
+                info!(
+                    "Pruned {} import reports past retention",
+                    done.rows_affected()
+                )
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Couldn't prune import reports past retention: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_are_owned_by_the_uploader() {
+        let row = |created_by: Option<&str>| ImportRow {
+            line: 2,
+            long_url: String::from("https://example.com"),
+            short_url: None,
+            created_by: created_by.map(String::from),
+        };
+        assert_eq!(
+            owned_by(&row(None), "alice").unwrap().created_by.as_deref(),
+            Some("alice")
+        );
+        assert!(owned_by(&row(Some("alice")), "alice").is_ok());
+        assert!(matches!(
+            owned_by(&row(Some("bob")), "alice"),
+            Err(Outcome::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn errors_download_as_csv() {
+        let errors = [RowError {
+            line: 3,
+            outcome: String::from("invalid"),
+            reason: String::from("Not a url, at all"),
+        }];
+        assert_eq!(
+            errors_csv(&errors).unwrap(),
+            "line,outcome,reason\n3,invalid,\"Not a url, at all\"\n"
+        );
+    }
+}
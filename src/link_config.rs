@@ -0,0 +1,490 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    resolve::RedirectStatus,
+    tags,
+    url_db::{OwnerFilter, UrlRow},
+};
+
+/// A declarative description of links, keyed by alias. This is the format read by `apply` and
+/// written by `export`.
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+pub struct LinkFile {
+    #[serde(default)]
+    links: BTreeMap<String, LinkSpec>,
+}
+
+/// Desired state of a single link. Fields that are left out are left untouched on existing links,
+/// so an expiry or redirect status can't be taken off from a file.
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq, Clone)]
+pub struct LinkSpec {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination: Option<String>,
+    /// `[]` takes every tag off
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<Vec<String>>,
+    /// RFC 3339, like `2030-01-01T00:00:00Z`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+    /// 301, 302, 307 or 308
+    #[serde(skip_serializing_if = "Option::is_none")]
+    redirect_status: Option<RedirectStatus>,
+}
+
+/// A link as it should be made
+#[derive(Debug, PartialEq, Default)]
+pub struct NewSpec {
+    pub destination: String,
+    pub tags: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub redirect_status: Option<RedirectStatus>,
+}
+
+/// One field of an existing link that differs from the file, with its old and new value
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Destination {
+        old: String,
+        new: String,
+    },
+    Tags {
+        old: Vec<String>,
+        new: Vec<String>,
+    },
+    ExpiresAt {
+        old: Option<DateTime<Utc>>,
+        new: DateTime<Utc>,
+    },
+    RedirectStatus {
+        old: Option<RedirectStatus>,
+        new: RedirectStatus,
+    },
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let shown = |value: Option<String>| value.unwrap_or_else(|| String::from("none"));
+        match self {
+            Self::Destination { old, new } => write!(f, "{old} -> {new}"),
+            Self::Tags { old, new } => {
+                write!(f, "tags [{}] -> [{}]", old.join(", "), new.join(", "))
+            }
+            Self::ExpiresAt { old, new } => write!(
+                f,
+                "expires_at {} -> {}",
+                shown(old.map(|old| old.to_rfc3339())),
+                new.to_rfc3339()
+            ),
+            Self::RedirectStatus { old, new } => write!(
+                f,
+                "redirect_status {} -> {}",
+                shown(old.map(|old| u16::from(old).to_string())),
+                u16::from(*new)
+            ),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PlanItem {
+    Create {
+        alias: String,
+        spec: NewSpec,
+    },
+    Update {
+        id: i64,
+        alias: String,
+        changes: Vec<Change>,
+    },
+    /// The link is owned by the service user but no longer in the file, and isn't archived yet
+    Archive {
+        id: i64,
+        alias: String,
+    },
+    /// The alias is taken by a link the service user doesn't own
+    Conflict {
+        alias: String,
+    },
+    /// A new link was requested without a destination, so there is nothing to create, or a field
+    /// has a value no link can have
+    Invalid {
+        alias: String,
+        reason: String,
+    },
+}
+
+impl Display for PlanItem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Create { alias, spec } => {
+                write!(f, "+ create {alias} -> {}", spec.destination)?;
+                if !spec.tags.is_empty() {
+                    write!(f, ", tags [{}]", spec.tags.join(", "))?;
+                }
+                if let Some(expires_at) = spec.expires_at {
+                    write!(f, ", expires_at {}", expires_at.to_rfc3339())?;
+                }
+                if let Some(status) = spec.redirect_status {
+                    write!(f, ", redirect_status {}", u16::from(status))?;
+                }
+                Ok(())
+            }
+            Self::Update { alias, changes, .. } => {
+                let changes: Vec<String> = changes.iter().map(Change::to_string).collect();
+                write!(f, "~ update {alias}: {}", changes.join(", "))
+            }
+            Self::Archive { alias, .. } => write!(f, "- archive {alias}"),
+            Self::Conflict { alias } => {
+                write!(f, "! skip {alias}: owned by another user")
+            }
+            Self::Invalid { alias, reason } => write!(f, "! skip {alias}: {reason}"),
+        }
+    }
+}
+
+impl PlanItem {
+    /// Whether applying this item changes anything in the database
+    pub fn is_change(&self) -> bool {
+        matches!(
+            self,
+            Self::Create { .. } | Self::Update { .. } | Self::Archive { .. }
+        )
+    }
+}
+
+impl LinkFile {
+    pub fn from_toml(contents: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(contents)
+    }
+    pub fn to_toml(&self) -> Result<String, toml::ser::Error> {
+        toml::to_string(self)
+    }
+    /// Builds a file describing the given rows and their `tags`, by link id, exactly, so applying
+    /// it back is a no-op
+    pub fn from_rows(rows: &[UrlRow], tags: &HashMap<i64, Vec<String>>) -> Self {
+        let links = rows
+            .iter()
+            .map(|row| {
+                (
+                    row.short_url().clone(),
+                    LinkSpec {
+                        destination: Some(row.long_url().clone()),
+                        tags: tags.get(&row.id()).cloned(),
+                        expires_at: row.expires_at(),
+                        redirect_status: row.redirect_status(),
+                    },
+                )
+            })
+            .collect();
+        LinkFile { links }
+    }
+    pub fn links(&self) -> &BTreeMap<String, LinkSpec> {
+        &self.links
+    }
+}
+
+/// Diffs the desired links against the database. `owned` are all links currently owned by the
+/// service user and `existing` are the rows already using any of the desired aliases, whoever owns
+/// them, with `tags` the tags of those, by link id. Returned items are ordered by alias.
+pub fn plan(
+    desired: &LinkFile,
+    owned: &[UrlRow],
+    existing: &[UrlRow],
+    tags: &HashMap<i64, Vec<String>>,
+    owner: i64,
+) -> Vec<PlanItem> {
+    let mut items = Vec::new();
+
+    for (alias, spec) in desired.links() {
+        let current = existing.iter().find(|row| row.short_url() == alias);
+        let invalid = |reason: String| PlanItem::Invalid {
+            alias: alias.clone(),
+            reason,
+        };
+        // Stored the way tags typed into the site are, without letting one entry become two
+        let wanted_tags =
+            match spec
+                .tags
+                .as_ref()
+                .map(|list| match list.iter().any(|tag| tag.contains(',')) {
+                    true => Err(String::from(
+                        "Tags can only contain letters, digits, dashes and underscores",
+                    )),
+                    false => tags::parse(&list.join(",")),
+                }) {
+                None => None,
+                Some(Ok(parsed)) => Some(parsed),
+                Some(Err(reason)) => {
+                    items.push(invalid(reason));
+                    continue;
+                }
+            };
+        match (current, &spec.destination) {
+            (None, Some(destination)) => items.push(PlanItem::Create {
+                alias: alias.clone(),
+                spec: NewSpec {
+                    destination: destination.clone(),
+                    tags: wanted_tags.unwrap_or_default(),
+                    expires_at: spec.expires_at,
+                    redirect_status: spec.redirect_status,
+                },
+            }),
+            (None, None) => items.push(invalid(String::from("new links need a destination"))),
+            (Some(row), _) if !OwnerFilter::User(owner).matches(row.created_by()) => {
+                items.push(PlanItem::Conflict {
+                    alias: alias.clone(),
+                })
+            }
+            (Some(row), destination) => {
+                let changes = changes(row, spec, destination, wanted_tags, tags);
+                if !changes.is_empty() {
+                    items.push(PlanItem::Update {
+                        id: row.id(),
+                        alias: alias.clone(),
+                        changes,
+                    })
+                }
+            }
+        }
+    }
+
+    for row in owned.iter().filter(|row| row.deleted_at().is_none()) {
+        if !desired.links().contains_key(row.short_url()) {
+            items.push(PlanItem::Archive {
+                id: row.id(),
+                alias: row.clone_short_url(),
+            });
+        }
+    }
+
+    items.sort_by(|first, second| plan_alias(first).cmp(plan_alias(second)));
+    items
+}
+
+/// The fields `spec` sets that `row` has another value for. Tags are compared whatever their order.
+fn changes(
+    row: &UrlRow,
+    spec: &LinkSpec,
+    destination: &Option<String>,
+    wanted_tags: Option<Vec<String>>,
+    tags: &HashMap<i64, Vec<String>>,
+) -> Vec<Change> {
+    let mut changes = Vec::new();
+    if let Some(destination) = destination.as_ref().filter(|new| *new != row.long_url()) {
+        changes.push(Change::Destination {
+            old: row.long_url().clone(),
+            new: destination.clone(),
+        });
+    }
+    if let Some(new) = wanted_tags {
+        let old = tags.get(&row.id()).cloned().unwrap_or_default();
+        let (mut sorted_old, mut sorted_new) = (old.clone(), new.clone());
+        sorted_old.sort();
+        sorted_new.sort();
+        if sorted_old != sorted_new {
+            changes.push(Change::Tags { old, new });
+        }
+    }
+    if let Some(new) = spec.expires_at.filter(|new| Some(*new) != row.expires_at()) {
+        changes.push(Change::ExpiresAt {
+            old: row.expires_at(),
+            new,
+        });
+    }
+    if let Some(new) = spec
+        .redirect_status
+        .filter(|new| Some(*new) != row.redirect_status())
+    {
+        changes.push(Change::RedirectStatus {
+            old: row.redirect_status(),
+            new,
+        });
+    }
+    changes
+}
+
+fn plan_alias(item: &PlanItem) -> &str {
+    match item {
+        PlanItem::Create { alias, .. }
+        | PlanItem::Update { alias, .. }
+        | PlanItem::Archive { alias, .. }
+        | PlanItem::Conflict { alias }
+        | PlanItem::Invalid { alias, .. } => alias.as_str(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWNER: i64 = 7;
+
+    fn desired(contents: &str) -> LinkFile {
+        LinkFile::from_toml(contents).unwrap()
+    }
+
+    #[test]
+    fn plans_create_change_and_archive() {
+        let owned = vec![
+            UrlRow::test_row("blog", "https://old.example").with_owner(Some(OWNER)),
+            UrlRow::test_row("gone", "https://gone.example").with_owner(Some(OWNER)),
+            UrlRow::test_row("same", "https://same.example").with_owner(Some(OWNER)),
+        ];
+        let file = desired(
+            r#"
+            [links.blog]
+            destination = "https://new.example"
+
+            [links.docs]
+            destination = "https://docs.example"
+
+            [links.same]
+            destination = "https://same.example"
+            "#,
+        );
+        let items = plan(&file, &owned, &owned, &HashMap::new(), OWNER);
+
+        assert_eq!(
+            items,
+            vec![
+                PlanItem::Update {
+                    id: -1,
+                    alias: String::from("blog"),
+                    changes: vec![Change::Destination {
+                        old: String::from("https://old.example"),
+                        new: String::from("https://new.example"),
+                    }],
+                },
+                PlanItem::Create {
+                    alias: String::from("docs"),
+                    spec: NewSpec {
+                        destination: String::from("https://docs.example"),
+                        ..Default::default()
+                    },
+                },
+                PlanItem::Archive {
+                    id: -1,
+                    alias: String::from("gone"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn archived_links_are_archived_once() {
+        let owned = vec![UrlRow::test_row("gone", "https://gone.example")
+            .with_owner(Some(OWNER))
+            .with_deleted_at(chrono::Utc::now())];
+
+        assert!(plan(&LinkFile::default(), &owned, &owned, &HashMap::new(), OWNER).is_empty());
+    }
+
+    #[test]
+    fn unspecified_fields_are_untouched() {
+        let owned = vec![UrlRow::test_row("blog", "https://old.example").with_owner(Some(OWNER))];
+        let file = desired("[links.blog]\n");
+
+        assert!(plan(&file, &owned, &owned, &HashMap::new(), OWNER).is_empty());
+    }
+
+    #[test]
+    fn plans_tag_expiry_and_status_changes() {
+        let expires_at = DateTime::parse_from_rfc3339("2030-01-01T00:00:00Z")
+            .unwrap()
+            .to_utc();
+        let owned = vec![
+            UrlRow::test_row("blog", "https://blog.example")
+                .with_owner(Some(OWNER))
+                .with_redirect_status(RedirectStatus::Found),
+            UrlRow::test_row("docs", "https://docs.example").with_owner(Some(OWNER)),
+        ];
+        let tags = HashMap::from([(-1, vec![String::from("news"), String::from("old")])]);
+        let file = desired(
+            r#"
+            [links.blog]
+            tags = ["Old", "news"]
+            expires_at = "2030-01-01T00:00:00Z"
+            redirect_status = 308
+
+            [links.docs]
+            tags = ["a tag"]
+            "#,
+        );
+        let items = plan(&file, &owned, &owned, &tags, OWNER);
+
+        assert_eq!(
+            items[0],
+            PlanItem::Update {
+                id: -1,
+                alias: String::from("blog"),
+                changes: vec![
+                    Change::ExpiresAt {
+                        old: None,
+                        new: expires_at,
+                    },
+                    Change::RedirectStatus {
+                        old: Some(RedirectStatus::Found),
+                        new: RedirectStatus::PermanentRedirect,
+                    },
+                ],
+            }
+        );
+        assert!(matches!(&items[1], PlanItem::Invalid { alias, .. } if alias == "docs"));
+        assert!(LinkFile::from_toml("[links.blog]\nredirect_status = 200\n").is_err());
+    }
+
+    #[test]
+    fn skips_links_owned_by_others() {
+        let existing = vec![
+            UrlRow::test_row("theirs", "https://theirs.example").with_owner(Some(OWNER + 1)),
+            UrlRow::test_row("anon", "https://anon.example").with_owner(None),
+        ];
+        let file = desired(
+            r#"
+            [links.theirs]
+            destination = "https://mine.example"
+
+            [links.anon]
+            destination = "https://mine.example"
+            "#,
+        );
+        let items = plan(&file, &[], &existing, &HashMap::new(), OWNER);
+
+        assert_eq!(
+            items,
+            vec![
+                PlanItem::Conflict {
+                    alias: String::from("anon")
+                },
+                PlanItem::Conflict {
+                    alias: String::from("theirs")
+                },
+            ]
+        );
+        assert!(!items.iter().any(PlanItem::is_change));
+    }
+
+    #[test]
+    fn export_round_trips_as_noop() {
+        let owned = vec![
+            UrlRow::test_row("blog", "https://blog.example")
+                .with_owner(Some(OWNER))
+                .with_expiry(Utc::now())
+                .with_redirect_status(RedirectStatus::TemporaryRedirect),
+            UrlRow::test_row("quote\"d", "https://example.com/?a=1&b=\"2\"")
+                .with_owner(Some(OWNER)),
+        ];
+        let tags = HashMap::from([(-1, vec![String::from("news")])]);
+        let exported = LinkFile::from_rows(&owned, &tags).to_toml().unwrap();
+        let file = desired(&exported);
+
+        assert!(exported.contains("redirect_status = 307"));
+        assert!(plan(&file, &owned, &owned, &tags, OWNER).is_empty());
+    }
+}
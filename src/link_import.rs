@@ -0,0 +1,297 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+};
+
+use sqlx::PgConnection;
+
+use crate::{
+    alias_problem, assets, checked_destination,
+    preferences::Preferences,
+    url_db::{self, OwnerFilter, SlugCase, UrlCreateError, UrlOptions, UrlRow},
+    user,
+};
+
+/// One link of an import file, a CSV of `longurl[,shorturl][,created_by]` rows as exported by
+/// most shorteners. `created_by` is the username of a user of this instance.
+#[derive(Debug, PartialEq)]
+pub struct ImportRow {
+    /// Where the row is in the file, for reporting
+    pub line: u64,
+    pub long_url: String,
+    pub short_url: Option<String>,
+    pub created_by: Option<String>,
+}
+
+/// Reads the rows of an import file. Empty fields count as left out, and a first row starting
+/// with `longurl` is taken as a header.
+pub fn read_rows(contents: &str) -> Result<Vec<ImportRow>, csv::Error> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(contents.as_bytes());
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        // The reader's own line numbers leave out blank lines, so they're counted from where the
+        // record starts instead
+        let start = record.position().map_or(0, |at| at.byte() as usize);
+        let skipped = &contents[start..];
+        let start = start + skipped.len() - skipped.trim_start_matches(['\r', '\n']).len();
+        let line = contents[..start].matches('\n').count() as u64 + 1;
+        let field = |i| record.get(i).filter(|f| !f.is_empty()).map(String::from);
+        if line == 1
+            && record
+                .get(0)
+                .is_some_and(|f| f.eq_ignore_ascii_case("longurl"))
+        {
+            continue;
+        }
+        rows.push(ImportRow {
+            line,
+            long_url: field(0).unwrap_or_default(),
+            short_url: field(1),
+            created_by: field(2),
+        });
+    }
+    Ok(rows)
+}
+
+/// What became of a row
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Imported,
+    /// Left out since it's already there or can't be had, with why
+    Skipped(String),
+    /// Not a link this instance would make, with why
+    Invalid(String),
+}
+
+/// How many rows went each way
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+    pub imported: usize,
+    pub skipped: usize,
+    pub invalid: usize,
+}
+
+impl Report {
+    pub fn count(&mut self, outcome: &Outcome) {
+        match outcome {
+            Outcome::Imported => self.imported += 1,
+            Outcome::Skipped(_) => self.skipped += 1,
+            Outcome::Invalid(_) => self.invalid += 1,
+        }
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} imported, {} skipped, {} invalid",
+            self.imported, self.skipped, self.invalid
+        )
+    }
+}
+
+/// Imports rows one at a time with the same checks as links made through the site. Short urls
+/// that are taken and links without one whose owner already has one to the destination are
+/// skipped, so an import can be run again after fixing the invalid rows.
+pub struct Importer<'a> {
+    prefs: &'a Preferences,
+    /// Check every row but store none
+    dry_run: bool,
+    /// Ids of the users rows were made by, None for names that aren't users
+    users: HashMap<String, Option<i64>>,
+    /// Short urls and destinations earlier rows took, which a dry run doesn't store
+    codes: HashSet<String>,
+    destinations: HashSet<(Option<i64>, String)>,
+}
+
+impl<'a> Importer<'a> {
+    pub fn new(prefs: &'a Preferences, dry_run: bool) -> Self {
+        Importer {
+            prefs,
+            dry_run,
+            users: HashMap::new(),
+            codes: HashSet::new(),
+            destinations: HashSet::new(),
+        }
+    }
+
+    async fn user_id(
+        &mut self,
+        username: &str,
+        conn: &mut PgConnection,
+    ) -> Result<Option<i64>, sqlx::Error> {
+        if let Some(id) = self.users.get(username) {
+            return Ok(*id);
+        }
+        let id = match user::retrieve_user_by_name(username, conn).await {
+            Ok(user) => Some(*user.id()),
+            Err(sqlx::Error::RowNotFound) => None,
+            Err(e) => return Err(e),
+        };
+        self.users.insert(username.to_string(), id);
+        Ok(id)
+    }
+
+    pub async fn import(
+        &mut self,
+        row: &ImportRow,
+        conn: &mut PgConnection,
+    ) -> Result<Outcome, sqlx::Error> {
+        let (outcome, _) = self.import_with(row, UrlOptions::default(), conn).await?;
+        Ok(outcome)
+    }
+
+    /// [Importer::import] making the link with `options`, which also hands back the link made.
+    /// The submitted url in `options` is replaced by the one of the row.
+    pub async fn import_with(
+        &mut self,
+        row: &ImportRow,
+        options: UrlOptions,
+        conn: &mut PgConnection,
+    ) -> Result<(Outcome, Option<UrlRow>), sqlx::Error> {
+        let (destination, submitted_url) = match checked_destination(&row.long_url, self.prefs) {
+            Ok(checked) => checked,
+            Err(problem) => return Ok((Outcome::Invalid(problem.message().to_string()), None)),
+        };
+        let owner = match &row.created_by {
+            None => None,
+            Some(username) => match self.user_id(username, conn).await? {
+                Some(id) => Some(id),
+                None => {
+                    return Ok((
+                        Outcome::Invalid(format!("There is no user {username}")),
+                        None,
+                    ))
+                }
+            },
+        };
+        let case = self.prefs.slug_case();
+        match &row.short_url {
+            Some(code) => {
+                let reserved = self.prefs.reserved_codes();
+                if url_db::is_reserved(code, reserved) || assets::exists(code) {
+                    return Ok((Outcome::Skipped(format!("{code} is reserved")), None));
+                }
+                if let Some(problem) = alias_problem(code, reserved) {
+                    return Ok((Outcome::Invalid(problem), None));
+                }
+                let taken = url_db::is_code_taken(code, case, &mut *conn).await?;
+                let key = match case {
+                    SlugCase::Sensitive => code.clone(),
+                    SlugCase::Insensitive => code.to_ascii_lowercase(),
+                };
+                if taken || !self.codes.insert(key) {
+                    return Ok((Outcome::Skipped(format!("{code} is already taken")), None));
+                }
+            }
+            None => {
+                let filter = owner.map_or(OwnerFilter::Anonymous, OwnerFilter::User);
+                let existing = url_db::find_duplicate(&destination, filter, &mut *conn).await?;
+                if existing.is_some() || !self.destinations.insert((owner, destination.clone())) {
+                    return Ok((
+                        Outcome::Skipped(format!("{destination} is already shortened")),
+                        None,
+                    ));
+                }
+            }
+        }
+        if self.dry_run {
+            return Ok((Outcome::Imported, None));
+        }
+        let options = UrlOptions {
+            submitted_url,
+            ..options
+        };
+        let created = match &row.short_url {
+            Some(code) => url_db::create_url_with_alias(code, &destination, owner, conn, &options)
+                .await
+                .map_err(UrlCreateError::from),
+            None => {
+                url_db::create_url_on(&destination, owner, conn, self.prefs.code_rules(), &options)
+                    .await
+            }
+        };
+        match created {
+            Ok(created) => Ok((Outcome::Imported, Some(created))),
+            Err(UrlCreateError::Exhausted) => Ok((
+                Outcome::Skipped(String::from("Every short url tried was taken")),
+                None,
+            )),
+            Err(UrlCreateError::Database(e)) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_optional_fields() {
+        let file = "longurl,shorturl,created_by\n\
+            https://example.com/a\n\
+            https://example.com/b, docs ,\n\
+            \n\
+            \"https://example.com/c?x=1,2\",,alice\n\
+            ,empty\n";
+        let rows = read_rows(file).unwrap();
+        assert_eq!(
+            rows,
+            [
+                ImportRow {
+                    line: 2,
+                    long_url: String::from("https://example.com/a"),
+                    short_url: None,
+                    created_by: None,
+                },
+                ImportRow {
+                    line: 3,
+                    long_url: String::from("https://example.com/b"),
+                    short_url: Some(String::from("docs")),
+                    created_by: None,
+                },
+                ImportRow {
+                    line: 5,
+                    long_url: String::from("https://example.com/c?x=1,2"),
+                    short_url: None,
+                    created_by: Some(String::from("alice")),
+                },
+                ImportRow {
+                    line: 6,
+                    long_url: String::new(),
+                    short_url: Some(String::from("empty")),
+                    created_by: None,
+                },
+            ]
+        );
+        let lines: Vec<u64> = read_rows("a\r\n\r\nb\n\n\nc\r\n\"d\nd\"\ne")
+            .unwrap()
+            .iter()
+            .map(|row| row.line)
+            .collect();
+        assert_eq!(lines, [1, 3, 6, 7, 9]);
+        let headerless = read_rows("https://example.com,abc").unwrap();
+        assert_eq!(headerless[0].line, 1);
+        assert_eq!(headerless[0].short_url.as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn reports_count_each_outcome() {
+        let mut report = Report::default();
+        for outcome in [
+            Outcome::Imported,
+            Outcome::Imported,
+            Outcome::Skipped(String::new()),
+            Outcome::Invalid(String::new()),
+        ] {
+            report.count(&outcome);
+        }
+        assert_eq!(report.to_string(), "2 imported, 1 skipped, 1 invalid");
+    }
+}
@@ -0,0 +1,493 @@
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
+use sqlx::{FromRow, PgConnection, PgExecutor, PgPool};
+
+/// Longest value a template captures unless it sets its own limit
+pub const DEFAULT_MAX_VALUE_LEN: i32 = 64;
+/// Highest limit a template can set on its values
+pub const MAX_VALUE_LEN: i32 = 256;
+/// Keeps a value pattern from compiling into something that is slow to match on every visit
+const VALUE_PATTERN_SIZE_LIMIT: usize = 1 << 16;
+
+/// A family of codes like `inv-{customer}`, each redirecting to the template's destination with
+/// the captured value filled in, e.g. `https://billing.example/invoice?c={customer}`. Codes are
+/// `prefix`, then the value, then `suffix`. Existing codes always win over a template.
+#[derive(FromRow, Debug, Clone)]
+pub struct LinkTemplate {
+    id: i64,
+    owner: i64,
+    prefix: String,
+    placeholder: String,
+    suffix: String,
+    destination: String,
+    value_pattern: Option<String>,
+    max_value_len: i32,
+    clicks: i64,
+}
+
+impl LinkTemplate {
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+    pub fn owner(&self) -> i64 {
+        self.owner
+    }
+    /// The codes this template covers, as it was written, e.g. `inv-{customer}`
+    pub fn alias(&self) -> String {
+        format!("{}{{{}}}{}", self.prefix, self.placeholder, self.suffix)
+    }
+
+    /// The value in `code`, if `code` is one of this template's codes and the value meets its
+    /// constraints
+    pub fn capture<'a>(&self, code: &'a str) -> Option<&'a str> {
+        let value = code
+            .strip_prefix(self.prefix.as_str())?
+            .strip_suffix(self.suffix.as_str())?;
+        let short_enough = value.chars().count() <= usize::try_from(self.max_value_len).ok()?;
+        let allowed = match &self.value_pattern {
+            Some(pattern) => value_regex(pattern).is_ok_and(|regex| regex.is_match(value)),
+            None => true,
+        };
+        (!value.is_empty() && short_enough && allowed).then_some(value)
+    }
+
+    /// Where the code with `value` redirects. The value is percent encoded so it can't add query
+    /// parameters or path segments of its own.
+    pub fn destination_for(&self, value: &str) -> String {
+        let placeholder = format!("{{{}}}", self.placeholder);
+        self.destination
+            .replace(&placeholder, &percent_encode(value))
+    }
+}
+
+/// A template as the API describes it
+#[derive(Serialize, Debug, PartialEq)]
+pub struct TemplateView {
+    id: i64,
+    alias: String,
+    destination: String,
+    value_pattern: Option<String>,
+    max_value_len: i32,
+    clicks: i64,
+}
+
+impl From<&LinkTemplate> for TemplateView {
+    fn from(template: &LinkTemplate) -> Self {
+        TemplateView {
+            id: template.id,
+            alias: template.alias(),
+            destination: template.destination.clone(),
+            value_pattern: template.value_pattern.clone(),
+            max_value_len: template.max_value_len,
+            clicks: template.clicks,
+        }
+    }
+}
+
+/// A template someone asked for, checked by [NewTemplate::parse]
+#[derive(Debug, PartialEq)]
+pub struct NewTemplate<'a> {
+    prefix: &'a str,
+    placeholder: &'a str,
+    suffix: &'a str,
+    destination: &'a str,
+    value_pattern: Option<&'a str>,
+    max_value_len: i32,
+}
+
+impl<'a> NewTemplate<'a> {
+    /// Checks a template made of `alias`, a code with one `{placeholder}` such as `inv-{customer}`,
+    /// and a `destination` that uses the same placeholder. Errors are meant for people.
+    pub fn parse(
+        alias: &'a str,
+        destination: &'a str,
+        value_pattern: Option<&'a str>,
+        max_value_len: Option<i32>,
+    ) -> Result<Self, String> {
+        let (prefix, rest) = alias
+            .split_once('{')
+            .ok_or_else(|| format!("{alias} has no {{placeholder}}"))?;
+        let (placeholder, suffix) = rest
+            .split_once('}')
+            .ok_or_else(|| format!("{alias} has an unclosed {{"))?;
+        if suffix.contains(['{', '}']) || prefix.contains('}') {
+            return Err(String::from("Templates have exactly one placeholder"));
+        }
+        if placeholder.is_empty()
+            || !placeholder
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(String::from(
+                "Placeholder names can only contain letters, digits and underscores",
+            ));
+        }
+        let fixed = format!("{prefix}{suffix}");
+        if fixed.is_empty() {
+            return Err(String::from(
+                "Templates need some fixed text around the placeholder",
+            ));
+        }
+        if !fixed
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+        {
+            return Err(String::from(
+                "Templates can only contain letters, digits, dots, dashes and underscores",
+            ));
+        }
+        if prefix.starts_with('.') {
+            return Err(String::from("Templates can't start with a dot"));
+        }
+        if !destination.contains(&format!("{{{placeholder}}}")) {
+            return Err(format!("The destination doesn't use {{{placeholder}}}"));
+        }
+        let value_pattern = value_pattern.filter(|pattern| !pattern.is_empty());
+        if let Some(pattern) = value_pattern {
+            value_regex(pattern).map_err(|e| format!("The value pattern is invalid: {e}"))?;
+        }
+        let max_value_len = max_value_len.unwrap_or(DEFAULT_MAX_VALUE_LEN);
+        if !(1..=MAX_VALUE_LEN).contains(&max_value_len) {
+            return Err(format!(
+                "Values can be limited to between 1 and {MAX_VALUE_LEN} characters"
+            ));
+        }
+        Ok(NewTemplate {
+            prefix,
+            placeholder,
+            suffix,
+            destination,
+            value_pattern,
+            max_value_len,
+        })
+    }
+
+    /// The destination with a stand-in value, for checking it like any other destination
+    pub fn sample_destination(&self) -> String {
+        self.destination
+            .replace(&format!("{{{}}}", self.placeholder), "value")
+    }
+
+    /// Whether some code would match both this and a template with `prefix` and `suffix`. Values
+    /// can be as long as needed, so that is whenever one prefix starts the other and one suffix
+    /// ends the other. Value constraints are ignored, which errs on the side of refusing.
+    pub fn overlaps(&self, prefix: &str, suffix: &str) -> bool {
+        (self.prefix.starts_with(prefix) || prefix.starts_with(self.prefix))
+            && (self.suffix.ends_with(suffix) || suffix.ends_with(self.suffix))
+    }
+}
+
+/// Matches a whole value against a template's pattern
+fn value_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(&format!("^(?:{pattern})$"))
+        .size_limit(VALUE_PATTERN_SIZE_LIMIT)
+        .build()
+}
+
+/// Encodes everything but the characters RFC 3986 leaves unreserved
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(char::from(byte));
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+/// Why a template couldn't be stored
+#[derive(Debug)]
+pub enum CreateError {
+    /// Another template covers some of the same codes
+    OverlapsTemplate(String),
+    /// An existing code is one of the template's codes
+    OverlapsCode(String),
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for CreateError {
+    fn from(e: sqlx::Error) -> Self {
+        CreateError::Database(e)
+    }
+}
+
+/// Stores a template for `owner` unless it overlaps another template or an existing code. The
+/// checks and the insert hold a lock so two overlapping templates can't both get in.
+pub async fn create_template(
+    owner: i64,
+    template: &NewTemplate<'_>,
+    pool: &PgPool,
+) -> Result<LinkTemplate, CreateError> {
+    let mut tx = pool.begin().await?;
+    let created = create_template_on(owner, template, &mut tx).await?;
+    tx.commit().await?;
+    Ok(created)
+}
+
+/// [create_template] in a transaction of the caller's, which holds the lock until it ends
+pub async fn create_template_on(
+    owner: i64,
+    template: &NewTemplate<'_>,
+    tx: &mut PgConnection,
+) -> Result<LinkTemplate, CreateError> {
+    sqlx::query!("SELECT pg_advisory_xact_lock(hashtext('url_templates'))")
+        .execute(&mut *tx)
+        .await?;
+    let existing = sqlx::query_as!(
+        LinkTemplate,
+        "SELECT id, owner, prefix, placeholder, suffix, destination, value_pattern, max_value_len,
+            clicks
+        FROM url_templates"
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    if let Some(other) = existing
+        .iter()
+        .find(|other| template.overlaps(&other.prefix, &other.suffix))
+    {
+        return Err(CreateError::OverlapsTemplate(other.alias()));
+    }
+    let code = sqlx::query_scalar!(
+        "SELECT shorturl FROM urls
+        WHERE starts_with(shorturl, $1) AND right(shorturl, length($2)) = $2
+            AND length(shorturl) > length($1) + length($2)
+        LIMIT 1",
+        template.prefix,
+        template.suffix
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    if let Some(code) = code {
+        return Err(CreateError::OverlapsCode(code));
+    }
+    let created = sqlx::query_as!(
+        LinkTemplate,
+        "INSERT INTO url_templates (owner, prefix, placeholder, suffix, destination, value_pattern,
+            max_value_len)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING id, owner, prefix, placeholder, suffix, destination, value_pattern,
+            max_value_len, clicks",
+        owner,
+        template.prefix,
+        template.placeholder,
+        template.suffix,
+        template.destination,
+        template.value_pattern,
+        template.max_value_len
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    Ok(created)
+}
+
+/// The template whose codes `code` looks like, if any. Templates don't overlap, so there is at
+/// most one; whether its constraints accept the value is up to [LinkTemplate::capture].
+pub async fn find_for_code(
+    code: &str,
+    db: impl PgExecutor<'_>,
+) -> Result<Option<LinkTemplate>, sqlx::Error> {
+    sqlx::query_as!(
+        LinkTemplate,
+        "SELECT id, owner, prefix, placeholder, suffix, destination, value_pattern, max_value_len,
+            clicks
+        FROM url_templates
+        WHERE starts_with($1, prefix) AND right($1, length(suffix)) = suffix
+            AND length($1) > length(prefix) + length(suffix)
+        LIMIT 1",
+        code
+    )
+    .fetch_optional(db)
+    .await
+}
+
+pub async fn find_by_id(
+    id: i64,
+    db: impl PgExecutor<'_>,
+) -> Result<Option<LinkTemplate>, sqlx::Error> {
+    sqlx::query_as!(
+        LinkTemplate,
+        "SELECT id, owner, prefix, placeholder, suffix, destination, value_pattern, max_value_len,
+            clicks
+        FROM url_templates WHERE id = $1",
+        id
+    )
+    .fetch_optional(db)
+    .await
+}
+
+pub async fn list_for_owner(
+    owner: i64,
+    db: impl PgExecutor<'_>,
+) -> Result<Vec<LinkTemplate>, sqlx::Error> {
+    sqlx::query_as!(
+        LinkTemplate,
+        "SELECT id, owner, prefix, placeholder, suffix, destination, value_pattern, max_value_len,
+            clicks
+        FROM url_templates WHERE owner = $1 ORDER BY id",
+        owner
+    )
+    .fetch_all(db)
+    .await
+}
+
+pub async fn delete_template(id: i64, db: impl PgExecutor<'_>) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM url_templates WHERE id = $1", id)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Counts a click on the template and on the value it was opened with
+pub async fn record_click(
+    template: &LinkTemplate,
+    value: &str,
+    db: impl PgExecutor<'_>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "WITH counted AS (
+            UPDATE url_templates SET clicks = clicks + 1 WHERE id = $1 RETURNING id
+        )
+        INSERT INTO url_template_clicks (template_id, value, clicks)
+        SELECT id, $2, 1 FROM counted
+        ON CONFLICT (template_id, value)
+            DO UPDATE SET clicks = url_template_clicks.clicks + 1",
+        template.id,
+        value
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Clicks on a value of a template
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ValueClicks {
+    pub value: String,
+    pub clicks: i64,
+}
+
+/// Clicks per value of a template, most clicked first
+pub async fn value_clicks(
+    template_id: i64,
+    limit: i64,
+    db: impl PgExecutor<'_>,
+) -> Result<Vec<ValueClicks>, sqlx::Error> {
+    sqlx::query_as!(
+        ValueClicks,
+        "SELECT value, clicks FROM url_template_clicks WHERE template_id = $1
+        ORDER BY clicks DESC, value LIMIT $2",
+        template_id,
+        limit
+    )
+    .fetch_all(db)
+    .await
+}
+
+#[cfg(test)]
+impl LinkTemplate {
+    pub fn test_template(alias: &str, destination: &str, value_pattern: Option<&str>) -> Self {
+        let parsed = NewTemplate::parse(alias, destination, value_pattern, None).unwrap();
+        LinkTemplate {
+            id: 1,
+            owner: 1,
+            prefix: parsed.prefix.to_string(),
+            placeholder: parsed.placeholder.to_string(),
+            suffix: parsed.suffix.to_string(),
+            destination: destination.to_string(),
+            value_pattern: parsed.value_pattern.map(str::to_string),
+            max_value_len: parsed.max_value_len,
+            clicks: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INVOICE: &str = "https://billing.example/invoice?c={customer}";
+
+    #[test]
+    fn parses_aliases() {
+        let parsed = NewTemplate::parse("inv-{customer}.pdf", INVOICE, None, None).unwrap();
+        assert_eq!(parsed.prefix, "inv-");
+        assert_eq!(parsed.placeholder, "customer");
+        assert_eq!(parsed.suffix, ".pdf");
+        assert_eq!(parsed.max_value_len, DEFAULT_MAX_VALUE_LEN);
+        assert_eq!(
+            parsed.sample_destination(),
+            "https://billing.example/invoice?c=value"
+        );
+
+        for (alias, destination, pattern, max_len) in [
+            ("inv-", INVOICE, None, None),
+            ("inv-{customer", INVOICE, None, None),
+            ("{customer}", INVOICE, None, None),
+            ("inv-{customer}-{other}", INVOICE, None, None),
+            ("inv-{}", INVOICE, None, None),
+            ("in v-{customer}", INVOICE, None, None),
+            (".inv-{customer}", INVOICE, None, None),
+            ("inv-{customer}", "https://billing.example/", None, None),
+            ("inv-{customer}", INVOICE, Some("[0-9"), None),
+            ("inv-{customer}", INVOICE, None, Some(0)),
+            ("inv-{customer}", INVOICE, None, Some(MAX_VALUE_LEN + 1)),
+        ] {
+            assert!(
+                NewTemplate::parse(alias, destination, pattern, max_len).is_err(),
+                "{alias} {destination} {pattern:?} {max_len:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn captures_values_within_constraints() {
+        let digits = LinkTemplate::test_template("inv-{customer}", INVOICE, Some("[0-9]+"));
+        assert_eq!(digits.capture("inv-123"), Some("123"));
+        assert_eq!(digits.capture("inv-12a"), None);
+        assert_eq!(digits.capture("inv-"), None);
+        assert_eq!(digits.capture("invoice-123"), None);
+        // The pattern has to match the whole value
+        let partial = LinkTemplate::test_template("inv-{customer}", INVOICE, Some("[0-9]"));
+        assert_eq!(partial.capture("inv-12"), None);
+
+        let mut short = LinkTemplate::test_template("inv-{customer}.pdf", INVOICE, None);
+        short.max_value_len = 3;
+        assert_eq!(short.capture("inv-abc.pdf"), Some("abc"));
+        assert_eq!(short.capture("inv-abcd.pdf"), None);
+        assert_eq!(short.capture("inv-abc"), None);
+    }
+
+    #[test]
+    fn encodes_substituted_values() {
+        let template = LinkTemplate::test_template(
+            "inv-{customer}",
+            "https://billing.example/{customer}/invoice?c={customer}",
+            None,
+        );
+        assert_eq!(
+            template.destination_for("a-1"),
+            "https://billing.example/a-1/invoice?c=a-1"
+        );
+        assert_eq!(
+            template.destination_for("a b&x=1/é"),
+            "https://billing.example/a%20b%26x%3D1%2F%C3%A9/invoice?c=a%20b%26x%3D1%2F%C3%A9"
+        );
+    }
+
+    #[test]
+    fn detects_overlaps() {
+        let template = NewTemplate::parse("inv-{customer}", INVOICE, None, None).unwrap();
+        assert!(template.overlaps("inv-", ""));
+        // inv-2024-{x} codes are all inv-{customer} codes too
+        assert!(template.overlaps("inv-2024-", ""));
+        assert!(template.overlaps("in", ""));
+        assert!(template.overlaps("", ".pdf"));
+        assert!(!template.overlaps("bill-", ""));
+        assert!(!template.overlaps("bill-", ".pdf"));
+        let suffixed = NewTemplate::parse("inv-{customer}.pdf", INVOICE, None, None).unwrap();
+        assert!(suffixed.overlaps("inv-", "df"));
+        assert!(!suffixed.overlaps("inv-", ".png"));
+    }
+}
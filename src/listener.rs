@@ -0,0 +1,309 @@
+use std::{
+    future::{ready, Future, Ready},
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum_server::accept::Accept;
+use futures_util::future::Either;
+use hyper_util::{
+    rt::{TokioExecutor, TokioTimer},
+    server::conn::auto::Builder,
+};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{sleep, Instant, Sleep},
+};
+use tracing::warn;
+
+use crate::preferences::ListenerPrefs;
+
+/// Connections the listener turned away or cut off, so operators can tell when the limits bite
+#[derive(Default, Serialize, Debug)]
+pub struct ListenerStats {
+    /// Closed on arrival because `max_connections` were already open
+    shed: AtomicU64,
+    /// Closed after moving no bytes for the idle timeout
+    idle_closed: AtomicU64,
+    /// Closed without a single response byte written, e.g. when the request head never
+    /// finished arriving in time or the TLS handshake failed
+    unanswered: AtomicU64,
+}
+
+impl ListenerStats {
+    #[cfg(test)]
+    pub fn shed(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+    #[cfg(test)]
+    pub fn unanswered(&self) -> u64 {
+        self.unanswered.load(Ordering::Relaxed)
+    }
+}
+
+/// Applies the header size and header timeout limits to the HTTP server. Both listeners go
+/// through this so plain and TLS connections get the same limits.
+pub fn configure(builder: &mut Builder<TokioExecutor>, prefs: &ListenerPrefs) {
+    // Without a timer the header timeout is silently never enforced
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(prefs.header_read_timeout())
+        .max_buf_size(prefs.max_header_bytes());
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .max_header_list_size(u32::try_from(prefs.max_header_bytes()).unwrap_or(u32::MAX));
+}
+
+/// Acceptor enforcing the connection cap and idle timeout. It sits directly on the TCP stream, so
+/// a connection over the cap is dropped before any TLS or HTTP work is done for it.
+#[derive(Clone)]
+pub struct GuardAcceptor<A> {
+    inner: A,
+    permits: Arc<Semaphore>,
+    idle_timeout: Duration,
+    stats: Arc<ListenerStats>,
+}
+
+impl<A> GuardAcceptor<A> {
+    /// `max_connections` is the configured cap, or the one fitted to the open file limit
+    pub fn new(
+        inner: A,
+        prefs: &ListenerPrefs,
+        max_connections: usize,
+        stats: Arc<ListenerStats>,
+    ) -> Self {
+        GuardAcceptor {
+            inner,
+            permits: Arc::new(Semaphore::new(max_connections)),
+            idle_timeout: prefs.idle_timeout(),
+            stats,
+        }
+    }
+}
+
+impl<I, S, A> Accept<I, S> for GuardAcceptor<A>
+where
+    A: Accept<Guarded<I>, S>,
+{
+    type Stream = A::Stream;
+    type Service = A::Service;
+    type Future = Either<Ready<io::Result<(A::Stream, A::Service)>>, A::Future>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let Ok(permit) = self.permits.clone().try_acquire_owned() else {
+            let shed = self.stats.shed.fetch_add(1, Ordering::Relaxed) + 1;
+            // One line per power of two keeps a flood from flooding the log too
+            if shed.is_power_of_two() {
+                warn!("Connection limit reached, {shed} connections shed so far");
+            }
+            return Either::Left(ready(Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "connection limit reached",
+            ))));
+        };
+        let guarded = Guarded {
+            inner: stream,
+            _permit: permit,
+            idle_timeout: self.idle_timeout,
+            idle: Box::pin(sleep(self.idle_timeout)),
+            wrote: false,
+            stats: self.stats.clone(),
+        };
+        Either::Right(self.inner.accept(guarded, service))
+    }
+}
+
+/// A connection holding one of the permits. Any read or write pushes the idle deadline back; once
+/// it passes, the next read or write fails and the server drops the connection.
+pub struct Guarded<I> {
+    inner: I,
+    _permit: OwnedSemaphorePermit,
+    idle_timeout: Duration,
+    idle: Pin<Box<Sleep>>,
+    wrote: bool,
+    stats: Arc<ListenerStats>,
+}
+
+impl<I> Guarded<I> {
+    fn track<T>(&mut self, cx: &mut Context<'_>, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        if poll.is_ready() {
+            self.idle.as_mut().reset(Instant::now() + self.idle_timeout);
+            return poll;
+        }
+        match self.idle.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                self.stats.idle_closed.fetch_add(1, Ordering::Relaxed);
+                Poll::Ready(Err(io::ErrorKind::TimedOut.into()))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<I> Drop for Guarded<I> {
+    fn drop(&mut self) {
+        if !self.wrote {
+            self.stats.unanswered.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<I: AsyncRead + Unpin> AsyncRead for Guarded<I> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        this.track(cx, poll)
+    }
+}
+
+impl<I: AsyncWrite + Unpin> AsyncWrite for Guarded<I> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if matches!(poll, Poll::Ready(Ok(written)) if written > 0) {
+            this.wrote = true;
+        }
+        this.track(cx, poll)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{routing::get, Router};
+    use axum_server::{accept::DefaultAcceptor, Handle};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        time::timeout,
+    };
+
+    use super::*;
+
+    const HEADER_TIMEOUT: Duration = Duration::from_millis(200);
+
+    async fn slow() -> &'static str {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        "slow"
+    }
+
+    /// Starts a plain listener with these limits on a random port
+    async fn start(max_connections: usize) -> (SocketAddr, Arc<ListenerStats>) {
+        let prefs: ListenerPrefs = toml::from_str(&format!(
+            "header_read_timeout_ms = {}\nidle_timeout_ms = 1000",
+            HEADER_TIMEOUT.as_millis()
+        ))
+        .unwrap();
+        let stats = Arc::new(ListenerStats::default());
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .route("/slow", get(slow));
+
+        let handle = Handle::new();
+        let mut server = axum_server::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .acceptor(GuardAcceptor::new(
+                DefaultAcceptor,
+                &prefs,
+                max_connections,
+                stats.clone(),
+            ))
+            .handle(handle.clone());
+        configure(server.http_builder(), &prefs);
+        tokio::spawn(server.serve(app.into_make_service()));
+        (handle.listening().await.unwrap(), stats)
+    }
+
+    /// Everything the server sends until it closes the connection, or None if it never does
+    async fn read_until_closed(stream: &mut TcpStream, limit: Duration) -> Option<String> {
+        let mut received = Vec::new();
+        match timeout(limit, stream.read_to_end(&mut received)).await {
+            Ok(_) => Some(String::from_utf8_lossy(&received).into_owned()),
+            Err(_) => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn partial_headers_are_cut_off() {
+        let (addr, stats) = start(16).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: short.example\r\nX-Slow: ")
+            .await
+            .unwrap();
+
+        let received = read_until_closed(&mut stream, HEADER_TIMEOUT * 5).await;
+        assert!(received.is_some(), "connection was never closed");
+        assert!(!received.unwrap().contains("200 OK"));
+
+        // The connection task finishes shortly after the socket closes
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(stats.unanswered(), 1);
+    }
+
+    #[tokio::test]
+    async fn complete_requests_are_served() {
+        let (addr, _) = start(16).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: short.example\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        let received = read_until_closed(&mut stream, Duration::from_secs(2))
+            .await
+            .unwrap();
+        assert!(received.starts_with("HTTP/1.1 200 OK"));
+        assert!(received.ends_with("ok"));
+    }
+
+    #[tokio::test]
+    async fn connection_cap_sheds_new_connections() {
+        let (addr, stats) = start(1).await;
+        let mut established = TcpStream::connect(addr).await.unwrap();
+        established
+            .write_all(b"GET /slow HTTP/1.1\r\nHost: short.example\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        // Give the server a moment to accept it and start the handler
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut extra = TcpStream::connect(addr).await.unwrap();
+        let _ = extra
+            .write_all(b"GET / HTTP/1.1\r\nHost: short.example\r\n\r\n")
+            .await;
+        let shed = read_until_closed(&mut extra, Duration::from_millis(200)).await;
+        assert_eq!(shed.as_deref(), Some(""));
+        assert_eq!(stats.shed(), 1);
+
+        let served = read_until_closed(&mut established, Duration::from_secs(2))
+            .await
+            .unwrap();
+        assert!(served.starts_with("HTTP/1.1 200 OK"));
+        assert!(served.ends_with("slow"));
+    }
+}
@@ -0,0 +1,107 @@
+//! Locks for state shared between requests.
+//!
+//! Everything in [crate::PoolAndPrefs] falls in one of three groups:
+//!
+//! - Fixed at startup: plain fields, read without any synchronisation. Changing one means
+//!   restarting.
+//! - Counters and flags: atomics. Each one stands on its own, so nothing reads two of them
+//!   expecting them to agree.
+//! - Maps and caches: a [Locked] or [ReadMostly] inside the type that owns them, never exposed.
+//!
+//! Both lock types only hand their contents to a closure, and the closure can't be async, so no
+//! guard ever lives across an `.await` (`clippy::await_holding_lock` is denied on top of that).
+//! Nothing takes one lock while holding another, so there is no lock order to get wrong. Keep the
+//! closures short: clone what's needed out and do the slow part after.
+//!
+//! The one lock held across awaits on purpose is the tokio Mutex around a request's transaction in
+//! [crate::transaction], which only ever has one owner at a time.
+
+use std::sync::{Mutex, RwLock};
+
+/// A value behind a mutex
+#[derive(Default, Debug)]
+pub struct Locked<T>(Mutex<T>);
+
+impl<T> Locked<T> {
+    /// Runs `f` with the value locked. Panics if an earlier `f` panicked.
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.0.lock().expect("Lock poisoned by an earlier panic"))
+    }
+}
+
+/// A value read far more often than it is replaced, behind a read-write lock
+#[derive(Default, Debug)]
+pub struct ReadMostly<T>(RwLock<T>);
+
+impl<T> ReadMostly<T> {
+    pub fn new(value: T) -> Self {
+        ReadMostly(RwLock::new(value))
+    }
+
+    /// Runs `f` with the value shared with other readers
+    pub fn read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        f(&self.0.read().expect("Lock poisoned by an earlier panic"))
+    }
+
+    /// Runs `f` with the value locked for everyone else
+    pub fn write<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.0.write().expect("Lock poisoned by an earlier panic"))
+    }
+}
+
+/// Runs `work` on `threads` threads at once, each given its index, and fails if they haven't all
+/// finished in time, which is what a deadlock looks like from here
+#[cfg(test)]
+pub fn contend(threads: usize, work: impl Fn(usize) + Send + Sync + 'static) {
+    use std::{sync::Arc, thread, time::Duration};
+
+    let work = Arc::new(work);
+    let (done, finished) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let handles: Vec<_> = (0..threads)
+            .map(|index| {
+                let work = work.clone();
+                thread::spawn(move || work(index))
+            })
+            .collect();
+        let panicked = handles
+            .into_iter()
+            .map(|handle| handle.join())
+            .any(|joined| joined.is_err());
+        let _ = done.send(panicked);
+    });
+    match finished.recv_timeout(Duration::from_secs(60)) {
+        Ok(false) => {}
+        Ok(true) => panic!("A contending thread panicked"),
+        Err(_) => panic!("Contending threads didn't finish, likely a deadlock"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use super::*;
+
+    #[test]
+    fn counts_survive_contention() {
+        const THREADS: usize = 16;
+        const ROUNDS: usize = 10_000;
+        let counts: Arc<Locked<HashMap<usize, usize>>> = Arc::default();
+        let total = Arc::new(ReadMostly::new(0));
+        let (c, t) = (counts.clone(), total.clone());
+        contend(THREADS, move |thread| {
+            for round in 0..ROUNDS {
+                c.with(|counts| *counts.entry(round % 7).or_default() += 1);
+                if round % 10 == thread % 10 {
+                    t.write(|total| *total += 1);
+                } else {
+                    t.read(|total| assert!(*total <= THREADS * ROUNDS));
+                }
+            }
+        });
+        let counted: usize = counts.with(|counts| counts.values().sum());
+        assert_eq!(counted, THREADS * ROUNDS);
+        assert_eq!(total.read(|total| *total), THREADS * ROUNDS / 10);
+    }
+}
@@ -1,54 +1,267 @@
+#![deny(clippy::await_holding_lock)]
+
 use std::{
     collections::{BTreeMap, BTreeSet, HashMap},
-    fs,
+    env, fs,
     net::SocketAddr,
+    process,
     sync::Arc,
     time::{self, UNIX_EPOCH},
 };
 
+use announcement::{AnnouncementInput, Announcements};
+use api_error::ApiError;
+use appearance::Appearance;
 use askama::Template;
 use axum::{
     body::{Body, Bytes},
-    extract::{Path, State},
+    extract::{rejection::JsonRejection, ConnectInfo, Path, Query, State},
     http::{
-        header::{self, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, LOCATION, SET_COOKIE},
-        response, HeaderMap, HeaderName, StatusCode,
+        header::{
+            self, HeaderValue, CONTENT_TYPE, COOKIE, ETAG, IF_NONE_MATCH, LOCATION, SET_COOKIE,
+        },
+        HeaderMap, Method, StatusCode,
     },
+    middleware,
     response::{Html, IntoResponse, Response},
-    routing::{get, post},
-    Router,
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use axum_server::{
+    accept::DefaultAcceptor,
+    tls_rustls::{RustlsAcceptor, RustlsConfig},
+};
+use cache::{CacheClass, Caching};
+use chrono::{DateTime, NaiveDate, Utc};
+use client_id::Client;
+use display::filters;
+use dry_run::{Affected, Executor};
+use features::{Feature, FeatureSet};
+use firehose::{ClickEvent, Firehose};
+use geo::GeoLookup;
+use health::{Aggregate, Checked, HealthState};
+use host_policy::HostPolicy;
+use hot_links::ClickRates;
+use listener::{GuardAcceptor, ListenerStats};
+use missed::MissedLookups;
+use preferences::{Preferences, TimeoutPrefs};
+use preflight::Preflight;
+use rate_limit::{CreationLimits, Creator};
+use readiness::{Readiness, ReadyState};
+use recycle::Recycler;
+use repeat_clicks::RepeatClicks;
+use resolve::{Interstitial, Outcome, PendingLinks, RedirectStatus, RequestContext};
+use resources::{ResourceLimits, ResourceSource, ResourceUsage, Sizing};
+use safety::SafetyCheck;
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgPoolOptions, Acquire, PgConnection, PgPool};
+use theme::{Brand, Theme};
+use tracing::{debug, error, info, warn, Level};
+use url_db::{
+    ClickSource, DailyClicks, Destination, OwnerFilter, ReferrerClicks, SlugCase, UrlCreateError,
+    UrlOptions, UrlRow, UserRow, WeightedUrl,
+};
+use url_view::{UrlRowView, Viewer};
+use user::{
+    cookie::{self, FoundToken, LegacyMigrations, AUTH_COOKIE_NAME, LEGACY_AUTH_COOKIE_NAME},
+    jwt::{Jwt, JwtHeader, JwtPayload, SigAlgo},
+    session::{self, SessionState},
+    DeletedUsers,
 };
-use axum_server::tls_rustls::RustlsConfig;
-use preferences::Preferences;
-use regex::Regex;
-use serde::Deserialize;
-use sqlx::{postgres::PgPoolOptions, PgPool};
-use tracing::{debug, info, Level};
-use url_db::{UrlRow, UserRow};
-use user::jwt::{self, Jwt, JwtHeader, JwtPayload, SigAlgo};
+use user_agent::Device;
+use zeroize::Zeroizing;
 
+#[cfg(test)]
+mod accessibility;
+mod announcement;
+mod api_error;
+mod appearance;
+mod archive;
+mod assets;
+mod cache;
+mod cli;
+mod client_id;
+mod conversion;
+mod display;
+mod dry_run;
+mod features;
+mod firehose;
+mod form_verification;
+mod geo;
+mod health;
+mod history;
+mod host_policy;
+mod hot_links;
+mod https;
+mod import_job;
+mod link_config;
+mod link_import;
+mod link_template;
+mod listener;
+mod locked;
+mod missed;
+mod normalize;
+mod openapi;
 mod preferences;
+mod preflight;
+mod public_suffix;
+mod qr;
+mod rate_limit;
+mod readiness;
+mod recycle;
+mod referrer;
+mod repeat_clicks;
+mod resolve;
+mod resources;
+mod rollout;
+mod rollup;
+mod safety;
+mod smoke;
+mod tags;
+mod theme;
+mod timeout;
+mod transaction;
 mod url_db;
+mod url_view;
 mod user;
-
-const AUTH_COOKIE_NAME: &str = "Bearer";
+mod user_agent;
+mod user_archive;
 
 pub enum AuthenticationResponse {
-    Authenticated(UserRow),
+    /// The user, plus Set-Cookie values the response has to carry (see [FoundToken::Legacy])
+    Authenticated(UserRow, Vec<String>),
     Error(AuthError),
 }
 
 pub enum AuthError {
     NoCookieHeader,
     InvalidCookieHeader,
+    /// The token is malformed, expired, or its signature doesn't match
+    InvalidToken,
+    /// The token is valid but the user it was issued to no longer exists
+    UserNotFound,
+    /// The session went unused for longer than the idle timeout
+    SessionIdle,
+    /// The session is older than the absolute lifetime
+    SessionExpired,
+    DatabaseError,
+}
+
+impl IntoResponse for AuthError {
+    /// Tokens that can never authenticate again get their cookie cleared so the client stops
+    /// sending them.
+    fn into_response(self) -> Response {
+        match self {
+            AuthError::DatabaseError => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            AuthError::InvalidToken | AuthError::UserNotFound => Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header(SET_COOKIE, cookie::expired_cookie(AUTH_COOKIE_NAME))
+                .header(SET_COOKIE, cookie::expired_cookie(LEGACY_AUTH_COOKIE_NAME))
+                .body(Body::empty())
+                .unwrap_or(StatusCode::UNAUTHORIZED.into_response()),
+            AuthError::SessionIdle | AuthError::SessionExpired => {
+                let error = match self {
+                    AuthError::SessionIdle => ApiError::new(
+                        StatusCode::UNAUTHORIZED,
+                        "session_idle",
+                        "Signed out due to inactivity",
+                    ),
+                    _ => ApiError::new(
+                        StatusCode::UNAUTHORIZED,
+                        "session_expired",
+                        "Session expired, please sign in again",
+                    ),
+                };
+                let mut resp = error.into_response();
+                for name in [AUTH_COOKIE_NAME, LEGACY_AUTH_COOKIE_NAME] {
+                    if let Ok(value) = HeaderValue::from_str(&cookie::expired_cookie(name)) {
+                        resp.headers_mut().append(SET_COOKIE, value);
+                    }
+                }
+                resp
+            }
+            AuthError::NoCookieHeader | AuthError::InvalidCookieHeader => {
+                StatusCode::UNAUTHORIZED.into_response()
+            }
+        }
+    }
 }
 
+/// Everything handlers share. Which kind of synchronisation each field uses is explained in
+/// [locked]; nothing here hands out a lock guard.
 struct PoolAndPrefs {
+    // Fixed at startup
     pool: PgPool,
     prefs: Preferences,
+    theme: Theme,
+    geo: GeoLookup,
+    features: FeatureSet,
+    sizing: Sizing,
+    // Atomics
+    readiness: Arc<Readiness>,
+    legacy_migrations: LegacyMigrations,
+    listener: Arc<ListenerStats>,
+    caching: Arc<Caching>,
+    resources: Arc<ResourceUsage>,
+    // Locked inside
+    deleted_users: DeletedUsers,
+    missed: MissedLookups,
+    repeat_clicks: RepeatClicks,
+    click_rates: Arc<ClickRates>,
+    creation_limits: Arc<CreationLimits>,
+    health: HealthState,
+    announcements: Announcements,
+    firehose: Firehose,
+    recycler: Recycler,
+    safety: SafetyCheck,
 }
 
 impl PoolAndPrefs {
+    /// `recycler` has to be the one attached to `pool` when it was built. The in-memory state is
+    /// sized to `limits`.
+    fn new(
+        pool: PgPool,
+        recycler: Recycler,
+        prefs: Preferences,
+        theme: Theme,
+        geo: GeoLookup,
+        limits: ResourceLimits,
+    ) -> Self {
+        let sizing = Sizing::derive(&prefs, &limits);
+        let repeat_clicks =
+            RepeatClicks::new(prefs.repeat_click_window(), sizing.repeat_click_capacity);
+        let creation_limits = Arc::new(CreationLimits::new(
+            prefs.rate_limits(),
+            sizing.rate_limit_capacity,
+        ));
+        let readiness = Arc::new(Readiness::default());
+        let health = HealthState::default();
+        health.register(health::Database(pool.clone()));
+        health.register(health::WarmUp(readiness.clone()));
+        PoolAndPrefs {
+            recycler,
+            features: FeatureSet::from_prefs(prefs.features()),
+            caching: Arc::new(Caching::new(prefs.cache().clone())),
+            safety: SafetyCheck::from_prefs(prefs.safety()),
+            pool,
+            prefs,
+            theme,
+            geo,
+            deleted_users: DeletedUsers::with_capacity(sizing.deleted_user_capacity),
+            missed: MissedLookups::with_capacity(sizing.missed_capacity),
+            repeat_clicks,
+            click_rates: Arc::default(),
+            creation_limits,
+            readiness,
+            health,
+            announcements: Announcements::default(),
+            legacy_migrations: LegacyMigrations::default(),
+            listener: Arc::default(),
+            firehose: Firehose::with_buffer(sizing.click_stream_buffer),
+            resources: Arc::new(ResourceUsage::new(limits)),
+            sizing,
+        }
+    }
     fn pool(&self) -> &PgPool {
         &self.pool
     }
@@ -58,6 +271,105 @@ impl PoolAndPrefs {
     fn both(&self) -> (&PgPool, &Preferences) {
         (&self.pool, &self.prefs)
     }
+    fn theme(&self) -> &Theme {
+        &self.theme
+    }
+}
+
+#[derive(Template)]
+#[template(path = "countdown.html")]
+struct CountdownTemplate<'a> {
+    target: &'a str,
+    seconds: i16,
+    appearance: Appearance,
+    brand: Brand,
+}
+
+#[derive(Template)]
+#[template(path = "single-use.html")]
+struct SingleUseTemplate<'a> {
+    short_url: &'a str,
+    appearance: Appearance,
+    brand: Brand,
+}
+
+#[derive(Template)]
+#[template(path = "consumed.html")]
+struct ConsumedTemplate {
+    at: String,
+    appearance: Appearance,
+    brand: Brand,
+    /// Where the appearance toggle returns to
+    back: String,
+}
+
+#[derive(Template)]
+#[template(path = "passphrase.html")]
+struct PassphraseTemplate<'a> {
+    short_url: &'a str,
+    appearance: Appearance,
+    brand: Brand,
+    /// The last attempt was wrong
+    wrong: bool,
+}
+
+#[derive(Template)]
+#[template(path = "exhausted.html")]
+struct ExhaustedTemplate {
+    max_clicks: i64,
+    appearance: Appearance,
+    brand: Brand,
+    /// Where the appearance toggle returns to
+    back: String,
+}
+
+#[derive(Template)]
+#[template(path = "preview.html")]
+struct PreviewTemplate<'a> {
+    short_url: &'a str,
+    /// None for protected links, whose destination only the passphrase reveals
+    destination: Option<&'a str>,
+    clicks: i64,
+    appearance: Appearance,
+    brand: Brand,
+    /// Where the appearance toggle returns to
+    back: String,
+}
+
+#[derive(Template)]
+#[template(path = "announcement.html")]
+struct AnnouncementTemplate<'a> {
+    id: i64,
+    message: &'a str,
+    severity: &'static str,
+    dismissible: bool,
+}
+
+#[derive(Template)]
+#[template(path = "link-history.html")]
+struct LinkHistoryTemplate<'a> {
+    short_url: &'a str,
+    edits: &'a [history::EditView],
+}
+
+#[derive(Template)]
+#[template(path = "expired.html")]
+struct ExpiredTemplate {
+    at: String,
+    appearance: Appearance,
+    brand: Brand,
+    /// Where the appearance toggle returns to
+    back: String,
+}
+
+#[derive(Template)]
+#[template(path = "pending.html")]
+struct PendingTemplate {
+    at: String,
+    appearance: Appearance,
+    brand: Brand,
+    /// Where the appearance toggle returns to
+    back: String,
 }
 
 #[derive(Deserialize)]
@@ -77,7 +389,18 @@ impl<'a> LoginPayload<'a> {
 
 #[tokio::main]
 async fn main() -> Result<(), sqlx::Error> {
-    let prefs = Preferences::load_config("config.toml").expect("Error loading configuration.");
+    let started = time::Instant::now();
+    let prefs = Preferences::load_config("config.toml")
+        .unwrap_or_else(|e| panic!("Error loading configuration: {e}"));
+    let theme = Theme::from_prefs(prefs.theme())
+        .unwrap_or_else(|e| panic!("Invalid theme configuration: {e}"));
+    let geo = GeoLookup::from_prefs(prefs.geo())
+        .unwrap_or_else(|e| panic!("Invalid geo configuration: {e}"));
+    if let Some(path) = prefs.public_suffix_list() {
+        public_suffix::load_list(path)
+            .unwrap_or_else(|e| panic!("Invalid public suffix list: {e}"));
+    }
+    user_agent::load_bot_patterns(prefs.bot_patterns());
     let subscriber = tracing_subscriber::fmt()
         .with_max_level(Level::DEBUG)
         .compact()
@@ -86,11 +409,19 @@ async fn main() -> Result<(), sqlx::Error> {
         .finish();
     let _ = tracing::subscriber::set_global_default(subscriber)
         .map_err(|_err| eprintln!("Error setting subscriber!"));
+    for problem in theme::contrast_problems(prefs.theme()) {
+        warn!("Theme text may be hard to read: {problem}");
+    }
 
-    let cert = prefs.https_cert_path();
-    let key = prefs.https_key_path();
+    // Certificates load while the database connects and migrates rather than before it
+    let tls_task = match (prefs.https_cert_path(), prefs.https_key_path()) {
+        (Some(cert), Some(key)) => Some(tokio::spawn(RustlsConfig::from_pem_file(
+            cert.clone(),
+            key.clone(),
+        ))),
+        _ => None,
+    };
 
-    let router = Router::new();
     let url = format!(
         "postgres://{}:{}@{}/{}",
         prefs.db_user(),
@@ -98,52 +429,163 @@ async fn main() -> Result<(), sqlx::Error> {
         prefs.db_ip(),
         prefs.db_name()
     );
-    // This pool is to be used throughout
-    let pool_fut = PgPoolOptions::new()
+    // This pool is to be used throughout. Only one connection is opened before listening, the
+    // rest are opened by the warm-up task.
+    let recycler = Recycler::default();
+    let pool = recycler
+        .attach(PgPoolOptions::new())
         .max_connections(prefs.db_pool_size())
-        .connect(url.as_str());
-    let pool: Result<PgPool, sqlx::Error>;
-
-    let mut config: Option<RustlsConfig> = None;
-    if cert.is_some() && key.is_some() {
-        let temp = RustlsConfig::from_pem_file(cert.as_ref().unwrap(), key.as_ref().unwrap());
-        let conf_fut;
-        (conf_fut, pool) = tokio::join!(temp, pool_fut);
-        config = Some(conf_fut.unwrap());
-    } else {
-        pool = pool_fut.await;
-    }
+        .min_connections(1)
+        .connect(url.as_str())
+        .await;
 
-    let pool_and_prefs = PoolAndPrefs {
-        pool: pool.expect("Error creating connection pool. {}"),
-        prefs: prefs.clone(),
-    };
+    let resource_source: Arc<dyn ResourceSource> = Arc::new(resources::Procfs);
+    let pool_and_prefs = PoolAndPrefs::new(
+        pool.expect("Error creating connection pool."),
+        recycler,
+        prefs.clone(),
+        theme,
+        geo,
+        ResourceLimits::detect(resource_source.as_ref()),
+    );
 
     sqlx::migrate!("./migrations")
         .run(pool_and_prefs.pool())
         .await
         .unwrap_or_else(|_| debug!("Migration already exists, skipping"));
 
+    let args: Vec<String> = env::args().collect();
+    if let Some(code) = cli::run(&args, pool_and_prefs.pool(), pool_and_prefs.prefs()).await {
+        process::exit(code);
+    }
+
     let arc_pool_prefs: Arc<PoolAndPrefs> = Arc::new(pool_and_prefs);
+    let warming = arc_pool_prefs.clone();
+    tokio::spawn(async move {
+        let pool = warming.pool().clone();
+        let connections = warming.prefs().db_pool_size();
+        warming
+            .readiness
+            .warm_up_with(readiness::warm_pool(pool, connections))
+            .await;
+    });
+    let pool = arc_pool_prefs.pool().clone();
+    tokio::spawn(recycle::probe(
+        pool.clone(),
+        arc_pool_prefs.recycler.clone(),
+    ));
+    if arc_pool_prefs.features.enabled(Feature::Conversions) {
+        tokio::spawn(conversion::prune(
+            pool.clone(),
+            prefs.conversions().attribution_window(),
+        ));
+    }
+    if let Some(retention) = prefs.history().retention() {
+        tokio::spawn(history::prune(pool.clone(), retention));
+    }
+    if let Some(retention) = prefs.archive().retention() {
+        tokio::spawn(archive::purge(pool.clone(), retention));
+    }
+    if prefs.anonymous_link_ttl().is_some() {
+        tokio::spawn(archive::expire_anonymous(pool.clone()));
+    }
+    tokio::spawn(import_job::prune(pool.clone(), prefs.imports().retention()));
+    tokio::spawn(import_job::resume(prefs.clone(), pool.clone()));
+    tokio::spawn(rollup::resume(pool.clone()));
+    tokio::spawn(hot_links::compact(
+        pool.clone(),
+        prefs.hot_links().fold_interval(),
+    ));
+    tokio::spawn(hot_links::watch(
+        arc_pool_prefs.click_rates.clone(),
+        prefs.hot_links().clone(),
+        pool.clone(),
+    ));
+    tokio::spawn(rollout::finish(pool.clone()));
+    tokio::spawn(url_db::backfill_urls(pool.clone()));
+    tokio::spawn(rate_limit::sweep(arc_pool_prefs.creation_limits.clone()));
+    tokio::spawn(resources::watch(
+        resource_source,
+        arc_pool_prefs.resources.clone(),
+        prefs.resources().clone(),
+    ));
+    #[cfg(unix)]
+    tokio::spawn(recycle::on_signal(pool, arc_pool_prefs.recycler.clone()));
 
-    let app = router
-        .route("/", get(root))
-        .route("/:extra", get(subdir_handler))
-        .with_state(arc_pool_prefs.clone())
-        .route("/", post(post_new_url))
+    let config = match tls_task {
+        Some(task) => Some(
+            task.await
+                .expect("TLS loading task panicked.")
+                .expect("Error loading TLS certificate or key."),
+        ),
+        None => None,
+    };
+
+    let host_policy = Arc::new(HostPolicy::new(
+        prefs.unknown_host(),
+        prefs.domain_name(),
+        config.is_some(),
+    ));
+    host_policy.set_hosts(prefs.other_domains().iter().cloned());
+    let timeouts = prefs.timeouts();
+    let app = site_routes(timeouts)
+        .merge(api_routes(arc_pool_prefs.features, timeouts))
         .with_state(arc_pool_prefs.clone())
-        .route("/:extra/:extra", get(subdir_handler))
-        .with_state(arc_pool_prefs.clone());
+        .layer(middleware::from_fn_with_state(
+            host_policy,
+            host_policy::check,
+        ))
+        .layer(middleware::from_fn_with_state(
+            arc_pool_prefs.caching.clone(),
+            cache::apply,
+        ));
     let address = SocketAddr::from(([127, 0, 0, 1], u16::try_from(prefs.port()).unwrap()));
     info!(
-        "Listening on {}:{} for connections!",
+        "Listening on {}:{} for connections, {:?} after starting!",
         prefs.http_ip(),
-        prefs.port()
+        prefs.port(),
+        started.elapsed()
     );
 
-    if config.is_some() {
-        axum_server::bind_rustls(address, config.unwrap())
-            .serve(app.into_make_service())
+    let guard = GuardAcceptor::new(
+        DefaultAcceptor,
+        prefs.listener(),
+        arc_pool_prefs.sizing.max_connections,
+        arc_pool_prefs.listener.clone(),
+    );
+    if let Some(config) = config {
+        if let Some(port) = prefs.https().redirect_port() {
+            let redirect = Arc::new(https::Redirect::new(
+                prefs.https(),
+                prefs.domain_name(),
+                prefs.port(),
+            ));
+            let plain = app
+                .clone()
+                .layer(middleware::from_fn_with_state(redirect, https::to_https));
+            let listener = tokio::net::TcpListener::bind(format!("{}:{port}", prefs.http_ip()))
+                .await
+                .map_err(sqlx::Error::Io)?;
+            // The same connection cap and timeouts as the HTTPS listener, so port 80 can't be held
+            // open by slow or idle connections either
+            let mut server = axum_server::from_tcp(listener.into_std().map_err(sqlx::Error::Io)?)
+                .acceptor(guard.clone());
+            listener::configure(server.http_builder(), prefs.listener());
+            info!("Redirecting HTTP on port {port} to HTTPS");
+            tokio::spawn(server.serve(plain.into_make_service_with_connect_info::<SocketAddr>()));
+        }
+        let app = match prefs.https().hsts() {
+            Some(hsts) => app.layer(middleware::from_fn_with_state(
+                HeaderValue::from_str(&hsts).expect("HSTS values are always valid headers"),
+                https::add_hsts,
+            )),
+            None => app,
+        };
+        let mut server =
+            axum_server::bind(address).acceptor(RustlsAcceptor::new(config).acceptor(guard));
+        listener::configure(server.http_builder(), prefs.listener());
+        server
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
             .await
             .unwrap();
     } else {
@@ -151,7 +593,10 @@ async fn main() -> Result<(), sqlx::Error> {
             tokio::net::TcpListener::bind(format!("{}:{}", prefs.http_ip(), prefs.port()).as_str())
                 .await
                 .unwrap();
-        axum::serve(listener, app.into_make_service())
+        let mut server = axum_server::from_tcp(listener.into_std().unwrap()).acceptor(guard);
+        listener::configure(server.http_builder(), prefs.listener());
+        server
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
             .await
             .unwrap();
     }
@@ -159,230 +604,11051 @@ async fn main() -> Result<(), sqlx::Error> {
     Ok(())
 }
 
-async fn post_new_url(
-    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
-    body: Bytes,
-) -> Response<Body> {
-    let prefs = pool_and_prefs.prefs();
-    let longurl: HashMap<String, String> =
-        serde_html_form::from_bytes(&body).expect("Error deserializing form response");
-    let new_url = url_db::create_url(
-        &longurl["url"],
-        None,
-        pool_and_prefs.pool(),
-        pool_and_prefs
-            .prefs
-            .url_len()
-            .try_into()
-            .expect("Error converting url_len to usize. {}"),
+/// A link someone asked for, before any of it is checked. The form and the JSON API both fill one
+/// in and leave the rest to [create_link].
+#[derive(Default)]
+struct NewLink<'a> {
+    url: &'a str,
+    alias: Option<&'a str>,
+    /// The user the link is made for, None for anonymous links
+    owner: Option<i64>,
+    interstitial_seconds: Option<i16>,
+    single_use: bool,
+    max_clicks: Option<i64>,
+    expires_at: Option<DateTime<Utc>>,
+    /// The link only starts redirecting at this time, see [activation_problem]
+    active_from: Option<DateTime<Utc>>,
+    passphrase: Option<&'a str>,
+    redirect_status: Option<RedirectStatus>,
+    /// Comma separated, see [tags::parse]
+    tags: Option<&'a str>,
+    description: Option<&'a str>,
+    /// Destinations to rotate between instead of `url`
+    destinations: &'a [ApiDestination],
+}
+
+/// One of the destinations of a rotating link, as the API takes it
+#[derive(Deserialize, Serialize)]
+struct ApiDestination {
+    url: String,
+    /// Relative to the other destinations' weights
+    #[serde(default = "default_weight")]
+    weight: i32,
+}
+
+fn default_weight() -> i32 {
+    1
+}
+
+fn bad_interstitial() -> ApiError {
+    ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "invalid_interstitial",
+        format!(
+            "Redirect delay must be between 0 and {} seconds",
+            url_db::MAX_INTERSTITIAL_SECONDS
+        ),
     )
-    .await
-    .unwrap();
-    let rendered = new_url.render().unwrap();
-    let rendered = rendered.split_once(new_url.short_url()).unwrap();
+}
 
-    let replaced_second = rendered.1.replace(
-        new_url.short_url(),
-        format!("{}/{}", prefs.domain_name(), new_url.short_url()).as_str(),
-    );
-    format!("{}{}{}", rendered.0, new_url.short_url(), replaced_second).into_response()
+fn bad_redirect_status(problem: String) -> ApiError {
+    ApiError::new(StatusCode::BAD_REQUEST, "invalid_redirect_status", problem)
 }
 
-async fn root() -> Response {
-    let contents = fs::read("html/index.html").unwrap();
-    let html = Html::from(contents);
-    html.into_response()
-}
-
-#[forbid(unsafe_code)]
-async fn derivative(Path(extra): Path<String>) -> Response {
-    // TODO Seperate Html and CSS responses
-    let mut path = String::from("html/");
-    if extra.contains("..") {
-        return StatusCode::FORBIDDEN.into_response();
-    }
-    path.push_str(extra.as_str());
-    let contents = match fs::read(&path) {
-        Ok(content) => content,
-        Err(_) => return not_found_handler().await,
-    };
-
-    let file_ext_regex: Regex = Regex::new(r"\.\w+$").expect("Error creating Regex match");
-    let file_ext = match file_ext_regex.find(path.as_str()) {
-        Some(strtype) => strtype,
-        None => return StatusCode::NOT_FOUND.into_response(),
-    };
-    match file_ext.as_str() {
-        ".html" => Html::from(contents).into_response(),
-        ".css" => content_response(contents, HeaderValue::from_static("text/css")),
-        ".jpg" => image_load(path.as_str(), file_ext.as_str()),
-        ".webp" => image_load(path.as_str(), file_ext.as_str()),
-        // Icos don't have a content type that matches the file ext.
-        ".ico" => {
-            let image = match fs::read(path) {
-                Ok(img) => img,
-                Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-            };
-            response::Builder::new()
-                .status(StatusCode::OK)
-                .header(CONTENT_TYPE, "image/x-icon")
-                .header(CONTENT_LENGTH, image.len())
-                .body(image.into())
-                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+fn bad_click_limit() -> ApiError {
+    ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "invalid_max_clicks",
+        "Click limit must be a whole number above 0",
+    )
+}
+
+/// Why a destination can't be shortened, if it can't. Any URL with a host that could exist is
+/// accepted, with or without a scheme, see [form_verification::is_valid_url], unless its scheme
+/// isn't one of [Preferences::allowed_schemes] or its host is one of
+/// [Preferences::blocked_domains] or under one of them.
+fn destination_problem(url: &str, prefs: &Preferences) -> Option<ApiError> {
+    let invalid = |problem| ApiError::new(StatusCode::BAD_REQUEST, "invalid_url", problem);
+    if url.is_empty() {
+        return Some(invalid(String::from("Give a URL to shorten")));
+    }
+    if url.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Some(invalid(format!("{url} isn't a URL")));
+    }
+    // Which scheme these get depends on the page the link is on
+    if url.starts_with("//") {
+        return Some(invalid(format!("{url} needs a scheme, e.g. https:{url}")));
+    }
+    let target = resolve::redirect_target(url);
+    let allowed = prefs.allowed_schemes();
+    if !resolve::scheme_allowed(&target, allowed) {
+        let scheme = form_verification::given_scheme(url).unwrap_or_default();
+        return Some(invalid(format!(
+            "Links can only go to {} URLs, not {scheme}:",
+            allowed.join(" or ")
+        )));
+    }
+    let parsed = match form_verification::is_valid_url(url) {
+        Ok(parsed) => parsed,
+        Err(problem) => return Some(invalid(format!("{url} isn't a URL: {problem}"))),
+    };
+    let entry = form_verification::blocked_by(parsed.host_str()?, prefs.blocked_domains())?;
+    Some(ApiError::new(
+        StatusCode::FORBIDDEN,
+        "blocked_domain",
+        format!("Links to {entry} aren't allowed on this instance"),
+    ))
+}
+
+/// A description as it is stored: trimmed, with control characters dropped, or turned into spaces
+/// when they are whitespace like newlines. None when nothing is left.
+fn clean_description(text: &str) -> Result<Option<String>, ApiError> {
+    let cleaned: String = text
+        .chars()
+        .filter_map(|c| match c {
+            c if !c.is_control() => Some(c),
+            c if c.is_whitespace() => Some(' '),
+            _ => None,
+        })
+        .collect();
+    let cleaned = cleaned.trim();
+    if cleaned.chars().count() > url_db::MAX_DESCRIPTION_LEN {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_description",
+            format!(
+                "Descriptions can be at most {} characters",
+                url_db::MAX_DESCRIPTION_LEN
+            ),
+        ));
+    }
+    Ok((!cleaned.is_empty()).then(|| cleaned.to_string()))
+}
+
+/// The stored form of a destination someone typed, plus what they typed when normalizing it
+/// dropped something
+fn checked_destination(
+    url: &str,
+    prefs: &Preferences,
+) -> Result<(String, Option<String>), ApiError> {
+    let submitted = url.trim();
+    let max_len = prefs.max_long_url_len();
+    let too_long = || {
+        ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "url_too_long",
+            format!("URLs can be at most {max_len} characters long"),
+        )
+    };
+    // Checked before parsing so an oversized one is turned away cheaply
+    if submitted.chars().count() > max_len {
+        return Err(too_long());
+    }
+    if let Some(problem) = destination_problem(submitted, prefs) {
+        return Err(problem);
+    }
+    let destination = normalize::normalize(submitted, prefs.normalize());
+    // Percent-encoding can make it several times longer
+    if destination.canonical.chars().count() > max_len {
+        return Err(too_long());
+    }
+    if let Some(problem) = self_link_problem(&destination.canonical, prefs) {
+        return Err(problem);
+    }
+    Ok((
+        destination.canonical,
+        destination.lossy.then(|| submitted.to_string()),
+    ))
+}
+
+/// Refuses destinations on this instance's own domains, which would make links that chain or loop
+/// through it, unless [Preferences::allow_self_links] is set
+fn self_link_problem(url: &str, prefs: &Preferences) -> Option<ApiError> {
+    if prefs.allow_self_links() {
+        return None;
+    }
+    let parsed = form_verification::is_valid_url(url).ok()?;
+    let own_domains = std::iter::once(prefs.domain_name().as_str())
+        .chain(prefs.other_domains().iter().map(String::as_str));
+    host_policy::is_own_host(parsed.host_str()?, own_domains).then(|| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "self_link",
+            "Links can't point back at this site",
+        )
+    })
+}
+
+/// A [NewLink] that passed every check, ready for [store_link]
+struct CheckedLink<'a> {
+    destination: String,
+    alias: Option<&'a str>,
+    owner: Option<i64>,
+    options: UrlOptions,
+    tags: Vec<String>,
+    /// Empty unless the link rotates, the first being `destination`
+    destinations: Vec<WeightedUrl>,
+}
+
+/// The stored form of the destinations of a rotating link, each checked like any destination. A
+/// link rotates between at least two, and each one can only be listed once.
+async fn checked_destinations(
+    pool_and_prefs: &PoolAndPrefs,
+    destinations: &[ApiDestination],
+) -> Result<Vec<WeightedUrl>, ApiError> {
+    let invalid =
+        |problem: String| ApiError::new(StatusCode::BAD_REQUEST, "invalid_destinations", problem);
+    if !(2..=url_db::MAX_DESTINATIONS).contains(&destinations.len()) {
+        return Err(invalid(format!(
+            "A link rotates between 2 and {} destinations",
+            url_db::MAX_DESTINATIONS
+        )));
+    }
+    let mut checked: Vec<WeightedUrl> = Vec::with_capacity(destinations.len());
+    for destination in destinations {
+        if !(1..=url_db::MAX_WEIGHT).contains(&destination.weight) {
+            return Err(invalid(format!(
+                "Weights must be whole numbers from 1 to {}",
+                url_db::MAX_WEIGHT
+            )));
+        }
+        let (long_url, _) = checked_destination(&destination.url, pool_and_prefs.prefs())?;
+        if checked.iter().any(|earlier| earlier.long_url == long_url) {
+            return Err(invalid(format!("{long_url} is listed more than once")));
         }
-        _ => not_found_handler().await,
+        if let Some(problem) = pool_and_prefs.safety.problem(&long_url).await {
+            return Err(problem);
+        }
+        checked.push(WeightedUrl {
+            long_url,
+            weight: destination.weight,
+        });
     }
+    Ok(checked)
 }
 
-async fn consume_short_url(Path(url): Path<String>, State(pool): State<&PgPool>) -> Response {
-    let mut url_row: UrlRow = match url_db::retrieve_url_obj(url.as_str(), &pool).await {
-        Ok(row) => row,
-        Err(_) => return not_found_handler().await,
+/// The two destinations of `row` while it rolls `new` out to `percent` of its visitors, see
+/// [url_db::rollout_arms]. Without `new` the link has to be rolling one out already, and only the
+/// share changes.
+async fn checked_rollout(
+    pool: &PgPool,
+    row: &UrlRow,
+    new: Option<String>,
+    percent: i32,
+) -> Result<[WeightedUrl; 2], ApiError> {
+    let invalid =
+        |problem: String| ApiError::new(StatusCode::BAD_REQUEST, "invalid_rollout", problem);
+    if !(1..=url_db::MAX_ROLLOUT_PERCENT).contains(&percent) {
+        return Err(invalid(format!(
+            "A rollout sends from 1 to {}% of visitors to the new url, commit it to send them all",
+            url_db::MAX_ROLLOUT_PERCENT
+        )));
+    }
+    let current = match row.rollout_until() {
+        Some(_) => destinations_of(row, pool).await?,
+        None => Vec::new(),
+    };
+    let (old, new) = match (&current[..], new) {
+        ([old, _], Some(new)) => (old.long_url.clone(), new),
+        ([old, rolling_out], None) => (old.long_url.clone(), rolling_out.long_url.clone()),
+        (_, Some(new)) if !row.rotates() => (row.long_url().to_string(), new),
+        (_, Some(_)) => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "conflicting_options",
+                "Stop the link rotating before rolling out a url",
+            ))
+        }
+        (_, None) => return Err(invalid(String::from("Give the url to roll out"))),
     };
+    if old == new {
+        return Err(invalid(format!("The link goes to {new} already")));
+    }
+    Ok(url_db::rollout_arms(&old, &new, percent))
+}
+
+/// The stored form of a link's destinations by country, each checked like any destination and
+/// keyed on its country code in uppercase
+async fn checked_geo_targets(
+    pool_and_prefs: &PoolAndPrefs,
+    targets: &BTreeMap<String, String>,
+) -> Result<BTreeMap<String, String>, ApiError> {
+    let invalid =
+        |problem: String| ApiError::new(StatusCode::BAD_REQUEST, "invalid_geo_targets", problem);
+    if targets.len() > url_db::MAX_GEO_TARGETS {
+        return Err(invalid(format!(
+            "A link can send at most {} countries elsewhere",
+            url_db::MAX_GEO_TARGETS
+        )));
+    }
+    let mut checked = BTreeMap::new();
+    for (country, url) in targets {
+        let Some(code) = geo::parse_country(country) else {
+            return Err(invalid(format!(
+                "{country} isn't a two letter country code, like US or DE"
+            )));
+        };
+        let (long_url, _) = checked_destination(url, pool_and_prefs.prefs())?;
+        if let Some(problem) = pool_and_prefs.safety.problem(&long_url).await {
+            return Err(problem);
+        }
+        if checked.insert(code, long_url).is_some() {
+            return Err(invalid(format!("{country} is listed more than once")));
+        }
+    }
+    Ok(checked)
+}
 
-    url_db::incr_url_clicks(&mut url_row, pool).await;
+/// Checks a requested link and stores it, or hands back an existing anonymous link that goes to
+/// the same place. Errors carry a code for programs and a message for people.
+async fn create_link(pool_and_prefs: &PoolAndPrefs, link: NewLink<'_>) -> Result<UrlRow, ApiError> {
+    let link = check_link(pool_and_prefs, link).await?;
+    // Only taken once the checks passed, since the safety one can wait on another service
+    let mut conn = pool_and_prefs.pool().acquire().await.map_err(|e| {
+        error!("Couldn't create a link to {}: {e}", link.destination);
+        ApiError::unavailable("Couldn't create the link")
+    })?;
+    store_link(pool_and_prefs.prefs(), link, &mut conn).await
+}
 
-    let long = if url_row.long_url().starts_with("http") || url_row.long_url().starts_with("https")
+/// Everything about a requested link that can be checked without the database
+async fn check_link<'a>(
+    pool_and_prefs: &PoolAndPrefs,
+    link: NewLink<'a>,
+) -> Result<CheckedLink<'a>, ApiError> {
+    let prefs = pool_and_prefs.prefs();
+    if link
+        .interstitial_seconds
+        .is_some_and(|seconds| !(0..=url_db::MAX_INTERSTITIAL_SECONDS).contains(&seconds))
+    {
+        return Err(bad_interstitial());
+    }
+    if link.single_use && !pool_and_prefs.features.enabled(Feature::SingleUse) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "feature_disabled",
+            "Single use links are turned off on this instance",
+        ));
+    }
+    if link.max_clicks.is_some_and(|max_clicks| max_clicks <= 0) {
+        return Err(bad_click_limit());
+    }
+    if link.single_use && link.max_clicks.is_some() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "conflicting_options",
+            "Single use links already stop after one click",
+        ));
+    }
+    if let Some(problem) = link
+        .active_from
+        .and_then(|at| activation_problem(at, link.expires_at, Utc::now()))
     {
-        url_row.long_url()
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_activation",
+            problem,
+        ));
+    }
+    let conflicting =
+        |message| ApiError::new(StatusCode::BAD_REQUEST, "conflicting_options", message);
+    let (destination, submitted_url, destinations) = if link.destinations.is_empty() {
+        let (destination, submitted_url) = checked_destination(link.url, prefs)?;
+        if let Some(problem) = pool_and_prefs.safety.problem(&destination).await {
+            return Err(problem);
+        }
+        (destination, submitted_url, Vec::new())
+    } else if !link.url.trim().is_empty() {
+        return Err(conflicting(
+            "Give either a url or destinations to rotate between",
+        ));
+    } else if link.single_use {
+        return Err(conflicting(
+            "Single use links only ever go to one destination",
+        ));
     } else {
-        &format!("http://{}", url_row.long_url())
+        let destinations = checked_destinations(pool_and_prefs, link.destinations).await?;
+        (destinations[0].long_url.clone(), None, destinations)
     };
-
-    Response::builder()
-        .status(301) // Status 301: Moved permanently
-        .header(header::LOCATION, long)
-        .body(Body::empty())
-        .unwrap()
+    let alias = link
+        .alias
+        .map(|alias| alias.trim())
+        .filter(|alias| !alias.is_empty());
+    if let Some(problem) = alias.and_then(|alias| alias_problem(alias, prefs.reserved_codes())) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_alias",
+            problem,
+        ));
+    }
+    let tags = tags::parse(link.tags.unwrap_or_default())
+        .map_err(|problem| ApiError::new(StatusCode::BAD_REQUEST, "invalid_tags", problem))?;
+    let description = clean_description(link.description.unwrap_or_default())?;
+    let options = UrlOptions {
+        interstitial_seconds: link.interstitial_seconds,
+        single_use: link.single_use,
+        submitted_url,
+        expires_at: anonymous_expiry(prefs, &link, Utc::now()),
+        active_from: link.active_from,
+        max_clicks: link.max_clicks,
+        // Passwords aren't trimmed, but an empty one means no passphrase
+        passphrase_hash: link
+            .passphrase
+            .filter(|passphrase| !passphrase.is_empty())
+            .map(user::hash_password),
+        redirect_status: link.redirect_status,
+        description,
+    };
+    Ok(CheckedLink {
+        destination,
+        alias,
+        owner: link.owner,
+        options,
+        tags,
+        destinations,
+    })
 }
 
-/// This theoretically handles all of the incoming requests. If it matches a file extention (html
-/// and css at the moment) then it returns that from the server. Otherwise, it will assume it is a
-/// short url and send it to the handler.
-async fn subdir_handler(
-    Path(path): Path<String>,
-    State(pool): State<Arc<PoolAndPrefs>>,
-) -> Response {
-    const FILE_EXTENTIONS: [&str; 10] = [
-        "html",
-        "css",
-        "ico",
-        "png",
-        "jpg",
-        "webp",
-        "xml",
-        "csv",
-        "webmanifest",
-        "wasm",
-    ];
-    let split = match path.split('.').last() {
-        Some(ext) => ext,
-        None => return not_found_handler().await,
-    };
-    debug!("The file extention is {split}");
-    if FILE_EXTENTIONS.contains(&split) {
-        debug!("Loading file at {path}");
-        return derivative(Path(path)).await;
+/// Stores a checked link with its tags and destinations through `conn`, in a transaction of their own or a savepoint
+/// of the one `conn` is in.
+async fn store_link(
+    prefs: &Preferences,
+    link: CheckedLink<'_>,
+    conn: &mut PgConnection,
+) -> Result<UrlRow, ApiError> {
+    let CheckedLink {
+        destination,
+        alias,
+        owner,
+        options,
+        tags,
+        destinations,
+    } = link;
+    let unavailable = |e: sqlx::Error| {
+        error!("Couldn't create a link to {destination}: {e}");
+        ApiError::unavailable("Couldn't create the link")
+    };
+    let mut tx = conn.begin().await.map_err(unavailable)?;
+    // Links made while signed out are anonymous, so an existing anonymous link is reused unless
+    // the instance turned that off. Asking for an alias means asking for a link of its own.
+    let existing = if owner.is_none()
+        && alias.is_none()
+        && options.is_plain()
+        && tags.is_empty()
+        && destinations.is_empty()
+        && prefs.reuse_links()
+    {
+        url_db::find_duplicate(&destination, OwnerFilter::Anonymous, &mut *tx)
+            .await
+            .unwrap_or_else(|e| {
+                error!("Couldn't look for an existing link: {e}");
+                None
+            })
     } else {
-        debug!("Redirecting user based on db result for {path}");
-        return consume_short_url(Path(path), State(&pool.pool())).await;
+        None
+    };
+    let alias_taken = |alias: &str| {
+        ApiError::new(
+            StatusCode::CONFLICT,
+            "alias_taken",
+            format!("The alias {alias} is already taken"),
+        )
+    };
+    // The unique constraint only covers exact codes, so one differing in case is looked for first
+    if let (None, Some(alias), SlugCase::Insensitive) = (&existing, alias, prefs.slug_case()) {
+        match url_db::is_code_taken(alias, SlugCase::Insensitive, &mut *tx).await {
+            Ok(true) => return Err(alias_taken(alias)),
+            Ok(false) => {}
+            Err(e) => return Err(unavailable(e)),
+        }
+    }
+    let row = match (existing, alias) {
+        (Some(row), _) => Ok(row),
+        (None, Some(alias)) => {
+            url_db::create_url_with_alias(alias, &destination, owner, &mut *tx, &options)
+                .await
+                .map_err(|e| {
+                    if e.as_database_error()
+                        .is_some_and(|e| e.is_unique_violation())
+                    {
+                        alias_taken(alias)
+                    } else {
+                        unavailable(e)
+                    }
+                })
+        }
+        (None, None) => {
+            url_db::create_url_on(&destination, owner, &mut tx, prefs.code_rules(), &options)
+                .await
+                .map_err(|e| match e {
+                    UrlCreateError::Exhausted => {
+                        error!("Every code tried for a link to {destination} was taken");
+                        ApiError::new(
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "codes_exhausted",
+                            "No short url is free right now, try again later",
+                        )
+                    }
+                    UrlCreateError::Database(e) => unavailable(e),
+                })
+        }
+    }?;
+    if !tags.is_empty() {
+        tags::set(row.id(), &tags, &mut tx)
+            .await
+            .map_err(unavailable)?;
     }
+    let row = if destinations.is_empty() {
+        row
+    } else {
+        url_db::set_destinations(row.id(), &destinations, &mut tx)
+            .await
+            .map_err(unavailable)?
+    };
+    tx.commit().await.map_err(unavailable)?;
+    Ok(row)
 }
 
-fn content_response(contents: Vec<u8>, content_type: HeaderValue) -> Response {
-    let mut resp = Body::from(contents).into_response();
-    resp.headers_mut()
-        .insert(header::CONTENT_TYPE, content_type);
-    resp
+/// Shortens the url sent by the form on the home page, answering with the row for the table of
+/// links. Signed in users own the links they make, see [link_creator].
+async fn post_new_url(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    client: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response<Body> {
+    let (creator, set_cookies) = link_creator(&pool_and_prefs, &headers).await;
+    let client = client_address(pool_and_prefs.prefs(), client, &headers);
+    if let Some(resp) = over_creation_limit(&pool_and_prefs, creator, client, true) {
+        return resp;
+    }
+    let Ok(form) = serde_html_form::from_bytes::<HashMap<String, String>>(&body) else {
+        return (StatusCode::BAD_REQUEST, "The form couldn't be read").into_response();
+    };
+    let field = |name: &str| {
+        form.get(name)
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())
+    };
+    let interstitial_seconds = match field("interstitial_seconds").map(str::parse::<i16>) {
+        None => None,
+        Some(Ok(seconds)) => Some(seconds),
+        Some(Err(_)) => return bad_interstitial().into_plain_response(),
+    };
+    let max_clicks = match field("max_clicks").map(str::parse::<i64>) {
+        None => None,
+        Some(Ok(max_clicks)) => Some(max_clicks),
+        Some(Err(_)) => return bad_click_limit().into_plain_response(),
+    };
+    let redirect_status = match field("redirect_status").map(|status| {
+        status
+            .parse::<u16>()
+            .map_err(|_| format!("{status} isn't a redirect status"))
+            .and_then(RedirectStatus::try_from)
+    }) {
+        None => None,
+        Some(Ok(status)) => Some(status),
+        Some(Err(problem)) => return bad_redirect_status(problem).into_plain_response(),
+    };
+    let expires_at = match expiry_from_form(&form, Utc::now()) {
+        Ok(expires_at) => expires_at,
+        Err(problem) => {
+            return ApiError::new(StatusCode::BAD_REQUEST, "invalid_expiry", problem)
+                .into_plain_response()
+        }
+    };
+    let active_from = match field("active_from").map(form_time).transpose() {
+        Ok(active_from) => active_from,
+        Err(problem) => {
+            return ApiError::new(StatusCode::BAD_REQUEST, "invalid_activation", problem)
+                .into_plain_response()
+        }
+    };
+    let link = NewLink {
+        url: form.get("url").map_or("", String::as_str),
+        alias: form.get("alias").map(String::as_str),
+        owner: creator.owner(),
+        interstitial_seconds,
+        // Browsers send "on" for a ticked checkbox and leave unticked ones out
+        single_use: form.contains_key("single_use"),
+        max_clicks,
+        expires_at,
+        active_from,
+        passphrase: form.get("passphrase").map(String::as_str),
+        redirect_status,
+        tags: form.get("tags").map(String::as_str),
+        description: form.get("description").map(String::as_str),
+        destinations: &[],
+    };
+    let new_url = match create_link(&pool_and_prefs, link).await {
+        Ok(row) => row,
+        Err(e) => return e.into_plain_response(),
+    };
+    let view = UrlRowView::assemble(
+        &new_url,
+        pool_and_prefs.prefs().domain_name(),
+        Viewer::Owner,
+    );
+    let resp = match view.render() {
+        Ok(html) => Html::from(html).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    with_cookies(resp, set_cookies)
 }
 
-async fn not_found_handler() -> Response {
-    let content = fs::read("html/404.html").unwrap();
-    Response::builder()
-        .status(StatusCode::NOT_FOUND)
-        .body(Body::from(content))
-        .expect("Failed to build 404 response")
+#[derive(Deserialize, Serialize)]
+struct ApiNewUrl {
+    /// Left out for links with `destinations`
+    #[serde(default)]
+    url: Option<String>,
+    #[serde(default)]
+    alias: Option<String>,
+    /// Comma separated, like `newsletter,q3-campaign`
+    #[serde(default)]
+    tags: Option<String>,
+    #[serde(default)]
+    description: Option<String>,
+    /// Destinations to rotate between, `[{"url": "...", "weight": 3}, ...]`
+    #[serde(default)]
+    destinations: Vec<ApiDestination>,
+    /// RFC 3339 time the link starts redirecting at
+    #[serde(default)]
+    active_from: Option<DateTime<Utc>>,
+    /// Redirects once, then answers 410 Gone
+    #[serde(default)]
+    single_use: bool,
 }
 
-fn image_load(path: &str, ext: &str) -> Response {
-    let ext = ext.trim_start_matches('.');
-    let image = match fs::read(path) {
-        Ok(img) => img,
-        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-    };
-    response::Builder::new()
-        .status(StatusCode::OK)
-        .header(CONTENT_TYPE, format!("image/{ext}"))
-        .header(CONTENT_LENGTH, image.len())
-        .body(image.into())
-        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+impl ApiNewUrl {
+    /// The url to shorten, which only links rotating between `destinations` go without
+    fn url(&self) -> Result<&str, ApiError> {
+        match &self.url {
+            Some(url) => Ok(url),
+            None if !self.destinations.is_empty() => Ok(""),
+            None => Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_json",
+                "missing field `url`",
+            )),
+        }
+    }
 }
 
-async fn authenticate_request(
-    State(pools_and_prefs): State<Arc<PoolAndPrefs>>,
+/// A link as the JSON API describes it
+#[derive(Serialize)]
+struct ApiUrlBody<'a> {
+    short_url: &'a str,
+    long_url: &'a str,
+    id: i64,
+    full_url: String,
+    clicks: i64,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    tags: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<&'a str>,
+    /// Only on archived links
+    #[serde(skip_serializing_if = "Option::is_none")]
+    archived_at: Option<DateTime<Utc>>,
+    /// Only on links scheduled to start redirecting later
+    #[serde(skip_serializing_if = "Option::is_none")]
+    active_from: Option<DateTime<Utc>>,
+    /// Only on single use links, see [url_db::claim_single_use]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    single_use: bool,
+    /// Only on links that rotate, see [with_destinations](Self::with_destinations)
+    #[serde(skip_serializing_if = "<[Destination]>::is_empty")]
+    destinations: &'a [Destination],
+    /// Only on links rolling out the second of their destinations, when it gets every visitor
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rollout_until: Option<DateTime<Utc>>,
+    /// Destinations by country code, only on geo-targeted links
+    #[serde(skip_serializing_if = "Option::is_none")]
+    geo_targets: Option<&'a BTreeMap<String, String>>,
+}
+
+impl<'a> ApiUrlBody<'a> {
+    fn new(row: &'a UrlRow, prefs: &Preferences) -> Self {
+        ApiUrlBody {
+            short_url: row.short_url(),
+            long_url: row.long_url(),
+            id: row.id(),
+            full_url: prefs.full_url(row.short_url()),
+            clicks: row.clicks(),
+            tags: &[],
+            description: row.description(),
+            archived_at: row.deleted_at(),
+            active_from: row.active_from(),
+            single_use: row.single_use(),
+            destinations: &[],
+            rollout_until: row.rollout_until(),
+            geo_targets: None,
+        }
+    }
+    fn with_tags(mut self, tags: &'a [String]) -> Self {
+        self.tags = tags;
+        self
+    }
+    fn with_destinations(mut self, destinations: &'a [Destination]) -> Self {
+        self.destinations = destinations;
+        self
+    }
+    fn with_geo_targets(mut self, geo_targets: &'a BTreeMap<String, String>) -> Self {
+        self.geo_targets = (!geo_targets.is_empty()).then_some(geo_targets);
+        self
+    }
+}
+
+/// The destinations of `row` with their clicks, without a lookup for links that don't rotate
+async fn destinations_of(row: &UrlRow, pool: &PgPool) -> Result<Vec<Destination>, ApiError> {
+    if !row.rotates() {
+        return Ok(Vec::new());
+    }
+    url_db::destinations(row.id(), pool).await.map_err(|e| {
+        error!("Couldn't load the destinations of {}: {e}", row.short_url());
+        ApiError::unavailable("Couldn't load the link's destinations")
+    })
+}
+
+/// The destinations of `row` by country code, without a lookup for links that aren't geo-targeted
+async fn geo_targets_of(row: &UrlRow, pool: &PgPool) -> Result<BTreeMap<String, String>, ApiError> {
+    if !row.geo_targeted() {
+        return Ok(BTreeMap::new());
+    }
+    url_db::geo_targets(row.id(), pool).await.map_err(|e| {
+        error!("Couldn't load the geo targets of {}: {e}", row.short_url());
+        ApiError::unavailable("Couldn't load the link's geo targets")
+    })
+}
+
+/// Who is making a link where anyone can: a signed in user owns it and has an allowance of their
+/// own, see [CreationLimits]. Anyone else, including someone whose session has run out, makes an
+/// anonymous one. Also returns the cookies refreshing the session, for the response.
+async fn link_creator(
+    pool_and_prefs: &Arc<PoolAndPrefs>,
     headers: &HeaderMap,
-) -> AuthenticationResponse {
-    let prefs = pools_and_prefs.prefs();
-    let header_str = match headers.get(HeaderName::from_static("Cookie")) {
-        Some(val) => val.to_str().unwrap_or(""),
-        None => return AuthenticationResponse::Error(AuthError::NoCookieHeader),
+) -> (Creator, Vec<String>) {
+    match authenticate_request(State(pool_and_prefs.clone()), headers).await {
+        AuthenticationResponse::Authenticated(user, set_cookies) => {
+            (Creator::SignedIn(*user.id()), set_cookies)
+        }
+        AuthenticationResponse::Error(_) => (Creator::Anonymous, Vec::new()),
+    }
+}
+
+/// Takes one link from the client's allowance, see [CreationLimits], or answers 429 with
+/// Retry-After once it has run out. Plain for the form on the home page. Requests whose address
+/// isn't known, which only tests make, aren't limited.
+fn over_creation_limit(
+    pool_and_prefs: &PoolAndPrefs,
+    creator: Creator,
+    client: Option<Client>,
+    plain: bool,
+) -> Option<Response> {
+    let wait = pool_and_prefs
+        .creation_limits
+        .take(creator, client?.id)
+        .err()?;
+    let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    let error = ApiError::new(
+        StatusCode::TOO_MANY_REQUESTS,
+        "rate_limited",
+        format!("Too many links were made from this address, try again in {seconds} seconds"),
+    );
+    let mut resp = if plain {
+        error.into_plain_response()
+    } else {
+        error.into_response()
     };
+    resp.headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+    Some(resp)
+}
 
-    let cookie_map: BTreeMap<&str, &str> = BTreeMap::new();
-    let cookie_vec: Vec<&str> = header_str.split_terminator(';').collect();
-    for pair in cookie_vec {
-        let tup = match pair.trim().split_once('=') {
-            Some(val) => val,
-            None => return AuthenticationResponse::Error(AuthError::InvalidCookieHeader),
-        };
+/// Shortens `{"url": "...", "alias": null}` for scripts, with the same rules, ownership and rate
+/// limits as the form on the home page
+async fn api_create_url(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    client: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    request: Result<Json<ApiNewUrl>, JsonRejection>,
+) -> Response {
+    let (creator, set_cookies) = link_creator(&pool_and_prefs, &headers).await;
+    let client = client_address(pool_and_prefs.prefs(), client, &headers);
+    if let Some(resp) = over_creation_limit(&pool_and_prefs, creator, client, false) {
+        return resp;
     }
-
-    let token = match cookie_map.get(AUTH_COOKIE_NAME) {
-        Some(v) => v,
-        None => return AuthenticationResponse::Error(AuthError::InvalidCookieHeader),
+    let request = match request {
+        Ok(Json(request)) => request,
+        Err(rejection) => return ApiError::from(rejection).into_response(),
     };
-    let token = match Jwt::from_str_secret(token, prefs.jwt_secret()) {
-        Ok(v) => v,
-        Err(_) => return AuthenticationResponse::Error(AuthError::InvalidCookieHeader),
+    let url = match request.url() {
+        Ok(url) => url,
+        Err(e) => return e.into_response(),
+    };
+    let link = NewLink {
+        url,
+        alias: request.alias.as_deref(),
+        tags: request.tags.as_deref(),
+        description: request.description.as_deref(),
+        destinations: &request.destinations,
+        active_from: request.active_from,
+        single_use: request.single_use,
+        owner: creator.owner(),
+        ..NewLink::default()
+    };
+    let created = match create_link(&pool_and_prefs, link).await {
+        Ok(row) => destinations_of(&row, pool_and_prefs.pool())
+            .await
+            .map(|destinations| (row, destinations)),
+        Err(e) => Err(e),
+    };
+    let resp = match created {
+        Ok((row, destinations)) => {
+            Json(ApiUrlBody::new(&row, pool_and_prefs.prefs()).with_destinations(&destinations))
+                .into_response()
+        }
+        Err(e) => e.into_response(),
     };
+    with_cookies(resp, set_cookies)
+}
 
-    todo!()
+/// Most links one bulk request can make
+const MAX_BULK_URLS: usize = 500;
+
+/// What a bulk request does with the rest of its links once one of them fails
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum OnError {
+    /// Make the others anyway, reporting the failures next to them
+    #[default]
+    Continue,
+    /// Make none of them
+    Abort,
 }
 
-async fn attempt_login(State(pool_and_prefs): State<Arc<PoolAndPrefs>>, body: Bytes) -> Response {
-    let (pool, prefs) = pool_and_prefs.both();
-    let current_time = time::SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-    let login_data: LoginPayload = match serde_html_form::from_bytes(&body) {
-        Ok(parsed) => parsed,
-        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-    };
+#[derive(Deserialize)]
+struct BulkQuery {
+    #[serde(default)]
+    on_error: OnError,
+}
 
-    let user: UserRow = match user::retrieve_user_by_name(login_data.username(), pool).await {
-        Ok(user) => user,
-        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-    };
+/// What happened to one link of a bulk request
+enum BulkOutcome {
+    Created(UrlRow),
+    Failed(ApiError),
+    /// Made, then undone since a later link failed with `on_error=abort`
+    RolledBack(UrlRow),
+    /// Not tried since an earlier link failed with `on_error=abort`
+    Skipped,
+}
 
-    if user::verify_pw(login_data.password(), &user).await {
-        let token = Jwt::new(
-            JwtHeader::new(SigAlgo::HS256, String::from("JWT")),
-            JwtPayload::new(
-                *user.id(),
-                user.username().to_string(),
-                user.email().to_string(),
-                current_time,
-            ),
-        );
-        let token_str = format!("__Host-jwt={}; Secure", token.finalize(prefs.jwt_secret()));
-        return Response::builder()
-            .header(SET_COOKIE, token_str)
-            .status(StatusCode::TEMPORARY_REDIRECT)
-            .header(LOCATION, "/loggedin.html")
+#[derive(Serialize)]
+struct BulkItemBody<'a> {
+    /// Where the link was in the request
+    index: usize,
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link: Option<ApiUrlBody<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a ApiError>,
+}
+
+#[derive(Serialize)]
+struct BulkBody<'a> {
+    results: Vec<BulkItemBody<'a>>,
+}
+
+impl BulkOutcome {
+    fn body<'a>(&'a self, index: usize, prefs: &Preferences) -> BulkItemBody<'a> {
+        let (status, row, error) = match self {
+            BulkOutcome::Created(row) => ("created", Some(row), None),
+            BulkOutcome::Failed(e) => ("failed", None, Some(e)),
+            BulkOutcome::RolledBack(row) => ("rolled_back", Some(row), None),
+            BulkOutcome::Skipped => ("skipped", None, None),
+        };
+        BulkItemBody {
+            index,
+            status,
+            link: row.map(|row| ApiUrlBody::new(row, prefs)),
+            error,
+        }
+    }
+}
+
+/// Shortens a JSON array of up to [MAX_BULK_URLS] `{"url": "...", "alias": null}` for the signed
+/// in user, all in one transaction. Each link is checked like one made alone and answered for in
+/// `results`, in the order they were sent. With `?on_error=abort` the first failure undoes the
+/// links before it and answers 422, otherwise the failures are left out and the rest are kept.
+/// Each request counts once against the signed in rate limits, however many links it makes.
+async fn api_bulk_create_urls(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Query(query): Query<BulkQuery>,
+    client: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    request: Result<Json<Vec<ApiNewUrl>>, JsonRejection>,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let client = client_address(pool_and_prefs.prefs(), client, &headers);
+    if let Some(resp) = over_creation_limit(
+        &pool_and_prefs,
+        Creator::SignedIn(*user.id()),
+        client,
+        false,
+    ) {
+        return resp;
+    }
+    let requested = match request {
+        Ok(Json(requested)) => requested,
+        Err(rejection) => return ApiError::from(rejection).into_response(),
+    };
+    if requested.len() > MAX_BULK_URLS {
+        return ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "too_many_urls",
+            format!("At most {MAX_BULK_URLS} links can be made at once"),
+        )
+        .into_response();
+    }
+    let unavailable = |e: sqlx::Error| {
+        error!("Couldn't make the links of {}: {e}", user.username());
+        ApiError::unavailable("Couldn't create the links").into_response()
+    };
+    let mut tx = match pool_and_prefs.pool().begin().await {
+        Ok(tx) => tx,
+        Err(e) => return unavailable(e),
+    };
+    let mut outcomes = Vec::with_capacity(requested.len());
+    let mut aborted = false;
+    for new_url in &requested {
+        if aborted {
+            outcomes.push(BulkOutcome::Skipped);
+            continue;
+        }
+        // Each link gets a savepoint, so one failing leaves the transaction usable for the rest
+        let mut savepoint = match tx.begin().await {
+            Ok(savepoint) => savepoint,
+            Err(e) => return unavailable(e),
+        };
+        let checked = match new_url.url() {
+            Ok(url) => {
+                let link = NewLink {
+                    url,
+                    alias: new_url.alias.as_deref(),
+                    tags: new_url.tags.as_deref(),
+                    description: new_url.description.as_deref(),
+                    destinations: &new_url.destinations,
+                    active_from: new_url.active_from,
+                    single_use: new_url.single_use,
+                    owner: Some(*user.id()),
+                    ..NewLink::default()
+                };
+                check_link(&pool_and_prefs, link).await
+            }
+            Err(e) => Err(e),
+        };
+        let created = match checked {
+            Ok(link) => store_link(pool_and_prefs.prefs(), link, &mut savepoint).await,
+            Err(e) => Err(e),
+        };
+        let outcome = match created {
+            Ok(row) => savepoint.commit().await.map(|()| BulkOutcome::Created(row)),
+            Err(e) => {
+                aborted = query.on_error == OnError::Abort;
+                savepoint.rollback().await.map(|()| BulkOutcome::Failed(e))
+            }
+        };
+        match outcome {
+            Ok(outcome) => outcomes.push(outcome),
+            Err(e) => return unavailable(e),
+        }
+    }
+    let status = if aborted {
+        if let Err(e) = tx.rollback().await {
+            return unavailable(e);
+        }
+        outcomes = outcomes
+            .into_iter()
+            .map(|outcome| match outcome {
+                BulkOutcome::Created(row) => BulkOutcome::RolledBack(row),
+                outcome => outcome,
+            })
+            .collect();
+        StatusCode::UNPROCESSABLE_ENTITY
+    } else {
+        if let Err(e) = tx.commit().await {
+            return unavailable(e);
+        }
+        StatusCode::OK
+    };
+    let body = BulkBody {
+        results: outcomes
+            .iter()
+            .enumerate()
+            .map(|(index, outcome)| outcome.body(index, pool_and_prefs.prefs()))
+            .collect(),
+    };
+    let resp = (status, Json(body)).into_response();
+    with_cookies(resp, set_cookies)
+}
+
+#[derive(Serialize)]
+struct ImportReportBody {
+    imported: usize,
+    skipped: usize,
+    invalid: usize,
+    errors: Vec<import_job::RowError>,
+}
+
+/// Imports a `text/csv` upload of `longurl[,shorturl][,created_by]` rows for the signed in user,
+/// see [link_import::read_rows]. The body is spooled to disk as it arrives, so it's only held in
+/// memory when it is small. Uploads up to the `sync_max_bytes` of the imports config are imported
+/// while the request waits and answered with the report. Larger ones are answered 202 with the
+/// import, which goes on without the connection, and its progress is at `/api/imports/:id`. Each
+/// upload counts once against the signed in rate limits, like the bulk endpoint.
+async fn api_import_urls(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    client: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let resp = match import_upload(&pool_and_prefs, client, &headers, &user, body).await {
+        Ok(resp) => resp,
+        Err(e) => e.into_response(),
+    };
+    with_cookies(resp, set_cookies)
+}
+
+async fn import_upload(
+    pool_and_prefs: &Arc<PoolAndPrefs>,
+    client: Option<ConnectInfo<SocketAddr>>,
+    headers: &HeaderMap,
+    user: &UserRow,
+    body: Body,
+) -> Result<Response, ApiError> {
+    let prefs = pool_and_prefs.prefs();
+    let is_csv = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .split(';')
+                .next()
+                .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("text/csv"))
+        });
+    if !is_csv {
+        return Err(ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported_media_type",
+            "Imports have to be sent as text/csv",
+        ));
+    }
+    let client = client_address(prefs, client, headers);
+    if let Some(resp) =
+        over_creation_limit(pool_and_prefs, Creator::SignedIn(*user.id()), client, false)
+    {
+        return Ok(resp);
+    }
+    let failed = |e: &dyn std::fmt::Display| {
+        error!("Couldn't import the upload of {}: {e}", user.username());
+        ApiError::unavailable("Couldn't import the file")
+    };
+    let spooled = import_job::spool(body, prefs.imports(), prefs.timeouts().bulk_idle())
+        .await
+        .map_err(|e| match e {
+            import_job::SpoolError::TooLarge => ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "file_too_large",
+                format!(
+                    "Imports can be at most {} bytes",
+                    prefs.imports().max_bytes()
+                ),
+            ),
+            import_job::SpoolError::Stalled => ApiError::new(
+                StatusCode::REQUEST_TIMEOUT,
+                "upload_stalled",
+                "The upload stopped before it was finished",
+            ),
+            import_job::SpoolError::Body(e) => ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_body",
+                format!("Couldn't read the upload: {e}"),
+            ),
+            import_job::SpoolError::Io(e) => failed(&e),
+        })?;
+
+    if spooled.len > prefs.imports().sync_max_bytes() {
+        let id = import_job::enqueue(*user.id(), &spooled.path, pool_and_prefs.pool())
+            .await
+            .map_err(|e| failed(&e))?;
+        tokio::spawn(import_job::run(
+            id,
+            prefs.clone(),
+            pool_and_prefs.pool().clone(),
+        ));
+        let job = import_job::job(id, *user.id(), pool_and_prefs.pool())
+            .await
+            .map_err(|e| failed(&e))?
+            .ok_or_else(|| failed(&"the import is gone"))?;
+        let location = format!("/api/imports/{id}");
+        return Ok((StatusCode::ACCEPTED, [(LOCATION, location)], Json(job)).into_response());
+    }
+
+    let contents = tokio::fs::read(&spooled.path).await;
+    if let Err(e) = tokio::fs::remove_file(&spooled.path).await {
+        warn!(
+            "Couldn't delete the spooled import {}: {e}",
+            spooled.path.display()
+        );
+    }
+    let contents = String::from_utf8(contents.map_err(|e| failed(&e))?).map_err(|_| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_csv",
+            "Imports have to be UTF-8",
+        )
+    })?;
+    let imported = import_job::import_now(&contents, user.username(), prefs, pool_and_prefs.pool())
+        .await
+        .map_err(|e| failed(&e))?;
+    let (report, errors) = imported.map_err(|e| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_csv",
+            format!("The file isn't valid CSV: {e}"),
+        )
+    })?;
+    Ok(Json(ImportReportBody {
+        imported: report.imported,
+        skipped: report.skipped,
+        invalid: report.invalid,
+        errors,
+    })
+    .into_response())
+}
+
+/// One of the signed in user's imports, as an [ApiError] if it isn't theirs
+async fn user_import(
+    pool_and_prefs: &PoolAndPrefs,
+    id: i64,
+    user: &UserRow,
+) -> Result<import_job::Job, ApiError> {
+    import_job::job(id, *user.id(), pool_and_prefs.pool())
+        .await
+        .map_err(|e| {
+            error!("Couldn't load import {id}: {e}");
+            ApiError::unavailable("Couldn't load the import")
+        })?
+        .ok_or_else(|| {
+            ApiError::new(
+                StatusCode::NOT_FOUND,
+                "not_found",
+                format!("There is no import {id}"),
+            )
+        })
+}
+
+/// The progress of one of the signed in user's background imports. The counts only ever grow,
+/// and are updated after each batch of rows.
+async fn api_import_status(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let resp = match user_import(&pool_and_prefs, id, &user).await {
+        Ok(job) => Json(job).into_response(),
+        Err(e) => e.into_response(),
+    };
+    with_cookies(resp, set_cookies)
+}
+
+/// The rows of one of the signed in user's imports that weren't imported, as a
+/// `line,outcome,reason` CSV download
+async fn api_import_errors(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let csv = match user_import(&pool_and_prefs, id, &user).await {
+        Ok(job) => import_job::errors(job.id, pool_and_prefs.pool())
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|errors| import_job::errors_csv(&errors).map_err(|e| e.to_string()))
+            .map_err(|e| {
+                error!("Couldn't list the errors of import {id}: {e}");
+                ApiError::unavailable("Couldn't list the errors")
+            }),
+        Err(e) => Err(e),
+    };
+    let resp = match csv {
+        Ok(csv) => (
+            [
+                (CONTENT_TYPE, String::from("text/csv; charset=utf-8")),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"import-{id}-errors.csv\""),
+                ),
+            ],
+            csv,
+        )
+            .into_response(),
+        Err(e) => e.into_response(),
+    };
+    with_cookies(resp, set_cookies)
+}
+
+/// Stops one of the signed in user's background imports at the end of the batch it's on. The rows
+/// imported by then are kept. Answers 202 with the import, or 409 if it had already stopped.
+async fn api_cancel_import(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let cancelled = import_job::cancel(id, *user.id(), pool_and_prefs.pool())
+        .await
+        .map_err(|e| {
+            error!("Couldn't cancel import {id}: {e}");
+            ApiError::unavailable("Couldn't cancel the import")
+        });
+    let resp = match cancelled {
+        Ok(Some(job)) if job.status.is_done() => ApiError::new(
+            StatusCode::CONFLICT,
+            "already_stopped",
+            format!("Import {id} already stopped"),
+        )
+        .into_response(),
+        Ok(Some(job)) => (StatusCode::ACCEPTED, Json(job)).into_response(),
+        Ok(None) => ApiError::new(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            format!("There is no import {id}"),
+        )
+        .into_response(),
+        Err(e) => e.into_response(),
+    };
+    with_cookies(resp, set_cookies)
+}
+
+#[derive(Deserialize)]
+struct ReadyQuery {
+    /// Only report ready once every pool connection is open
+    #[serde(default)]
+    warm: bool,
+}
+
+#[derive(Serialize)]
+struct ReadyBody<'a> {
+    state: ReadyState,
+    degraded: bool,
+    #[serde(flatten)]
+    checked: Checked,
+    timeouts: &'a TimeoutPrefs,
+    connections: &'a ListenerStats,
+    resources: &'a ResourceUsage,
+}
+
+/// Readiness for load balancers. Answers 503 while any health contributor is unhealthy, and 200
+/// otherwise, flagging `degraded` when one of them is. With `?warm=true` it also answers 503 until
+/// the warm-up has finished. The body reports each contributor, the request time budgets and how
+/// many connections the listener limits have closed.
+async fn readyz(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Query(query): Query<ReadyQuery>,
+) -> Response {
+    let state = pool_and_prefs.readiness.state();
+    let (checked, _) = pool_and_prefs.health.check().await;
+    let status =
+        if checked.health == Aggregate::NotReady || (query.warm && state != ReadyState::Warm) {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else {
+            StatusCode::OK
+        };
+    let body = ReadyBody {
+        state,
+        degraded: checked.health == Aggregate::Degraded,
+        checked,
+        timeouts: pool_and_prefs.prefs().timeouts(),
+        connections: &pool_and_prefs.listener,
+        resources: &pool_and_prefs.resources,
+    };
+    (status, Json(body)).into_response()
+}
+
+/// Liveness: answers as long as the process can serve requests at all, whatever the database
+/// and the other health contributors are doing
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// The pages, files and short codes. Files from html/ live under /static so every other single
+/// segment path can be a short code, whatever characters it has.
+fn site_routes(timeouts: &TimeoutPrefs) -> Router<Arc<PoolAndPrefs>> {
+    let mut site = Router::new()
+        .route("/", get(root).post(post_new_url))
+        .route("/readyz", get(readyz))
+        .route("/healthz", get(healthz))
+        .route("/theme.css", get(theme_css))
+        .route("/logo/:file", get(theme_logo))
+        .route("/static/*file", get(assets::static_file))
+        .route("/appearance", post(appearance::set_appearance))
+        .route("/announcement", get(announcement_banner))
+        .route("/announcement/:id/dismiss", post(dismiss_announcement))
+        .route("/px/:file", get(conversion_pixel))
+        .route("/:extra", get(subdir_handler).post(unlock_short_url))
+        .route("/:dir/:file", get(legacy_nested_asset));
+    for file in assets::ROOT_FILES {
+        site = site.route(&format!("/{file}"), get(move || assets::serve(file)));
+    }
+    site.route_layer(middleware::from_fn_with_state(
+        timeouts.redirect(),
+        timeout::total,
+    ))
+}
+
+/// Everything under /api. Routes belonging to an optional feature are grouped behind a
+/// [features::guard] so turning the feature off hides all of them.
+fn api_routes(features: FeatureSet, timeouts: &TimeoutPrefs) -> Router<Arc<PoolAndPrefs>> {
+    let gate = |feature| middleware::from_fn_with_state((features, feature), features::guard);
+    let missed = Router::new()
+        .route("/api/admin/missed", get(admin_missed))
+        .route_layer(gate(Feature::MissedLookups));
+    let templates = Router::new()
+        .route(
+            "/api/templates",
+            get(api_list_templates).post(api_create_template),
+        )
+        .route("/api/templates/:id", delete(api_delete_template))
+        .route("/api/templates/:id/clicks", get(api_template_clicks))
+        .route_layer(gate(Feature::LinkTemplates));
+    let preflight = Router::new()
+        .route("/api/preflight/:code", get(api_preflight))
+        .route_layer(gate(Feature::Preflight));
+    let api = Router::new()
+        .route("/api/features", get(api_features))
+        .route("/api/openapi.json", get(api_openapi))
+        .route("/api/urls", get(api_list_urls).post(api_create_url))
+        .route("/api/urls/bulk", post(api_bulk_create_urls))
+        .route(
+            "/api/imports/:id",
+            get(api_import_status).delete(api_cancel_import),
+        )
+        .route("/api/imports/:id/errors", get(api_import_errors))
+        .route(
+            "/api/urls/:code",
+            delete(api_delete_url).patch(api_edit_url),
+        )
+        .route("/api/urls/:code/restore", post(api_restore_url))
+        .route("/api/urls/:code/transfer", post(api_transfer_url))
+        .route("/api/urls/:code/rollout/commit", post(api_commit_rollout))
+        .route("/api/urls/:code/stats", get(api_url_stats))
+        .route("/api/urls/:code/history", get(api_url_history))
+        .route("/api/urls/:code/qr", get(api_url_qr))
+        .route("/api/admin/db/recycle", post(admin_recycle_pool))
+        .route("/api/admin/cache/bump", post(admin_bump_cache))
+        .route("/api/admin/hsts", get(admin_hsts_readiness))
+        .route("/api/admin/rollups/rebuild", post(admin_rebuild_rollups))
+        .route("/api/admin/archive/purge", post(admin_purge_archived))
+        .route(
+            "/api/admin/urls/:code/hot",
+            post(admin_flag_hot).delete(admin_unflag_hot),
+        )
+        .route("/api/admin/rollups/rebuild/:id", get(admin_rollup_rebuild))
+        .route(
+            "/api/admin/announcements",
+            get(admin_list_announcements).post(admin_create_announcement),
+        )
+        .route(
+            "/api/admin/announcements/:id",
+            put(admin_update_announcement).delete(admin_delete_announcement),
+        )
+        .merge(missed)
+        .merge(preflight)
+        .merge(templates)
+        .route_layer(middleware::from_fn_with_state(
+            timeouts.api(),
+            timeout::total,
+        ));
+    // Long lived, so only the gaps between chunks are limited
+    let stream = Router::new()
+        .route("/api/stream/clicks", get(api_click_stream))
+        .route_layer(gate(Feature::ClickStream))
+        .route_layer(middleware::from_fn_with_state(
+            timeouts.bulk_idle(),
+            timeout::idle,
+        ));
+    // The upload limits the gaps between the chunks it reads itself, see [import_job::spool]
+    let uploads = Router::new().route("/api/imports", post(api_import_urls));
+    api.merge(stream).merge(uploads)
+}
+
+#[derive(Serialize)]
+struct FeaturesBody {
+    features: FeatureSet,
+    limits: Limits,
+}
+
+/// Limits clients should respect up front rather than learn about from a rejected request
+#[derive(Serialize)]
+struct Limits {
+    /// Length of generated short codes
+    code_length: usize,
+    max_interstitial_seconds: i16,
+    max_alias_length: usize,
+    max_click_streams: usize,
+}
+
+/// Lists the optional features enabled here and the limits that matter to clients. Nothing in it
+/// depends on who is asking, so it needs no session and can be cached.
+async fn api_features(State(pool_and_prefs): State<Arc<PoolAndPrefs>>) -> Response {
+    let body = FeaturesBody {
+        features: pool_and_prefs.features,
+        limits: Limits {
+            code_length: pool_and_prefs.prefs().url_len(),
+            max_interstitial_seconds: url_db::MAX_INTERSTITIAL_SECONDS,
+            max_alias_length: MAX_ALIAS_LEN,
+            max_click_streams: firehose::MAX_STREAMS_PER_USER,
+        },
+    };
+    (CacheClass::Discovery, Json(body)).into_response()
+}
+
+/// The OpenAPI document of everything under /api, see [openapi::document]
+async fn api_openapi() -> Response {
+    (CacheClass::Discovery, Json(openapi::document())).into_response()
+}
+
+async fn root() -> Response {
+    let contents = fs::read("html/index.html").unwrap();
+    let html = Html::from(contents);
+    (CacheClass::Static, html).into_response()
+}
+
+/// Serves the stylesheet generated from the theme section of the config. Clients revalidate on
+/// every load, which is cheap because the ETag is computed once at startup.
+async fn theme_css(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    headers: HeaderMap,
+) -> Response {
+    let theme = pool_and_prefs.theme();
+    let etag = HeaderValue::from_str(theme.etag()).expect("ETag is always a valid header value");
+    if headers.get(IF_NONE_MATCH) == Some(&etag) {
+        return (
+            StatusCode::NOT_MODIFIED,
+            CacheClass::Revalidate,
+            [(ETAG, etag)],
+        )
+            .into_response();
+    }
+    let resp = content_response(theme.css().into(), HeaderValue::from_static("text/css"));
+    (CacheClass::Revalidate, [(ETAG, etag)], resp).into_response()
+}
+
+/// Serves the configured logo. The file name is a hash of its contents, so anything else is a
+/// stale or made up name.
+async fn theme_logo(
+    Path(file): Path<String>,
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+) -> Response {
+    let logo = match pool_and_prefs.theme().logo() {
+        Some(logo) if logo.file_name() == file => logo,
+        _ => return not_found_handler().await,
+    };
+    let contents = match fs::read(logo.path()) {
+        Ok(contents) => contents,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    let resp = content_response(contents, HeaderValue::from_static(logo.content_type()));
+    (CacheClass::Fingerprinted, resp).into_response()
+}
+
+async fn consume_short_url(
+    Path(url): Path<String>,
+    State(pool_and_prefs): State<&PoolAndPrefs>,
+    headers: &HeaderMap,
+    method: &Method,
+    client: Option<Client>,
+) -> Response {
+    let url_row = url_db::retrieve_url_obj(
+        url.as_str(),
+        pool_and_prefs.prefs().slug_case(),
+        pool_and_prefs.pool(),
+    )
+    .await
+    .ok();
+    if url_row.is_none() && pool_and_prefs.features.enabled(Feature::LinkTemplates) {
+        if let Some(resp) = visit_template(&url, pool_and_prefs).await {
+            return resp;
+        }
+    }
+    visit_short_url(url, url_row, pool_and_prefs, headers, method, client, false).await
+}
+
+/// Redirects a code that isn't a link of its own but matches a link template. None when no
+/// template accepts the code, which leaves it not found.
+async fn visit_template(code: &str, pool_and_prefs: &PoolAndPrefs) -> Option<Response> {
+    let pool = pool_and_prefs.pool();
+    let template = match link_template::find_for_code(code, pool).await {
+        Ok(template) => template?,
+        Err(e) => {
+            error!("Couldn't look for a template matching {code}: {e}");
+            return None;
+        }
+    };
+    let value = template.capture(code)?;
+    let target = resolve::redirect_target(&template.destination_for(value));
+    if !resolve::scheme_allowed(&target, pool_and_prefs.prefs().allowed_schemes()) {
+        warn!(
+            "Template {} sends {code} to a scheme that isn't allowed",
+            template.id()
+        );
+        return None;
+    }
+    if let Err(e) = link_template::record_click(&template, value, pool).await {
+        error!("Couldn't count a click on template {}: {e}", template.id());
+        return Some(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    }
+    pool_and_prefs
+        .firehose
+        .publish(ClickEvent::from_template(&template, code, value));
+    // Never permanent, so every visit is counted against its value
+    Some(
+        Response::builder()
+            .status(StatusCode::FOUND)
+            .header(header::LOCATION, target)
             .body(Body::empty())
-            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR.into_response()),
+    )
+}
+
+#[derive(Deserialize)]
+struct PassphraseForm {
+    passphrase: String,
+}
+
+/// Stands in for a stored hash when there is no protected link to check against, so a code that
+/// doesn't exist takes as long to refuse as a wrong passphrase. No passphrase hashes to all zeros.
+const DECOY_PASSPHRASE_HASH: &str = "decoy#00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000";
+
+/// Takes the passphrase form of a protected link. The right passphrase carries on as if the link
+/// had been opened, counting the click. Anything else, whether a wrong passphrase, a link without
+/// one or no link at all, gets the same form back so the answer doesn't tell which it was.
+async fn unlock_short_url(
+    Path(url): Path<String>,
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    client: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let passphrase = serde_html_form::from_bytes::<PassphraseForm>(&body)
+        .map(|form| Zeroizing::new(form.passphrase))
+        .unwrap_or_default();
+    let url_row = url_db::retrieve_url_obj(
+        url.as_str(),
+        pool_and_prefs.prefs().slug_case(),
+        pool_and_prefs.pool(),
+    )
+    .await
+    .ok();
+    let stored = url_row
+        .as_ref()
+        .and_then(|row| row.passphrase_hash())
+        .unwrap_or(DECOY_PASSPHRASE_HASH);
+    if !user::password_matches(&passphrase, stored)
+        || !url_row.as_ref().is_some_and(UrlRow::is_protected)
+    {
+        let page = PassphraseTemplate {
+            short_url: &url,
+            appearance: Appearance::from_headers(&headers),
+            brand: pool_and_prefs.theme().brand().clone(),
+            wrong: true,
+        };
+        return no_store_page(StatusCode::FORBIDDEN, page);
+    }
+    let client = client_address(pool_and_prefs.prefs(), client, &headers);
+    visit_short_url(
+        url,
+        url_row,
+        &pool_and_prefs,
+        &headers,
+        &Method::POST,
+        client,
+        true,
+    )
+    .await
+}
+
+/// Serves a visit to the short url `url`, looked up as `url_row`. `client` is the visitor's
+/// address when the connection has one. `unlocked` is whether the visitor gave the link's
+/// passphrase. HEAD requests, from link checkers and unfurlers, get the same status and Location
+/// as a GET but aren't counted as clicks.
+async fn visit_short_url(
+    url: String,
+    mut url_row: Option<UrlRow>,
+    pool_and_prefs: &PoolAndPrefs,
+    headers: &HeaderMap,
+    method: &Method,
+    client: Option<Client>,
+    unlocked: bool,
+) -> Response {
+    let pool = pool_and_prefs.pool();
+    // Scheduled links go live by the database's clock, like single use links are claimed by it, so
+    // every instance agrees on when that is
+    let now = match url_row.as_ref().filter(|row| row.active_from().is_some()) {
+        Some(_) => url_db::database_now(pool).await.unwrap_or_else(|e| {
+            error!("Couldn't read the database clock for {url}: {e}");
+            Utc::now()
+        }),
+        None => Utc::now(),
+    };
+    let ctx = RequestContext::new(headers, method, now, unlocked, pool_and_prefs.prefs());
+    let appearance = Appearance::from_headers(headers);
+    let brand = pool_and_prefs.theme().brand();
+    let counted = *method != Method::HEAD;
+    let mut source = ClickSource::from_headers(headers);
+    if pool_and_prefs.features.enabled(Feature::Conversions) {
+        source.visitor = client.map(|client| {
+            let secret = pool_and_prefs.prefs().jwt_secret();
+            conversion::visitor_id(secret, ctx.now.date_naive(), client.id, headers)
+        });
+    }
+
+    // Only geo-targeted links need the visitor's country, and a country with a destination of its
+    // own isn't rotated
+    let mut targeted = false;
+    if let Some(row) = url_row.as_mut().filter(|row| row.geo_targeted()) {
+        let target = match pool_and_prefs
+            .geo
+            .country(headers, client.map(|client| client.address))
+        {
+            Some(country) => match url_db::geo_target(row.id(), &country, pool).await {
+                Ok(target) => target.map(|long_url| (country, long_url)),
+                Err(e) => {
+                    error!("Couldn't look up where {url} sends visitors from {country}: {e}");
+                    None
+                }
+            },
+            None => None,
+        };
+        let branch = match target {
+            Some((country, long_url)) => {
+                row.send_to(&long_url);
+                targeted = true;
+                country
+            }
+            None => String::from(geo::DEFAULT_BRANCH),
+        };
+        source.geo_branch = Some(branch);
+    }
+    // Only rotating links need their destinations looked up
+    if let Some(row) = url_row.as_mut().filter(|row| row.rotates() && !targeted) {
+        match url_db::destinations(row.id(), pool).await {
+            Ok(destinations) => {
+                let picked = url_db::pick_destination(&destinations, &mut rand::thread_rng());
+                if let Some(picked) = picked {
+                    row.send_to(&picked.long_url);
+                    source.destination = Some(picked.id);
+                }
+            }
+            // Its long url is the first destination, so the visit still goes somewhere it may
+            Err(e) => error!("Couldn't load the destinations of {url}: {e}"),
+        }
+    }
+
+    match resolve::resolve(url_row.as_ref(), &ctx) {
+        Outcome::Redirect(target, status) => {
+            if let Some(resp) = count_click(
+                url_row.as_mut().filter(|_| counted),
+                &source,
+                client,
+                pool_and_prefs,
+                appearance,
+            )
+            .await
+            {
+                return resp;
+            }
+            Response::builder()
+                .status(status)
+                .header(header::LOCATION, target)
+                .body(Body::empty())
+                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+        Outcome::Interstitial(Interstitial::Countdown { target, seconds }) => {
+            if let Some(resp) = count_click(
+                url_row.as_mut().filter(|_| counted),
+                &source,
+                client,
+                pool_and_prefs,
+                appearance,
+            )
+            .await
+            {
+                return resp;
+            }
+            let page = CountdownTemplate {
+                target: &target,
+                seconds,
+                appearance,
+                brand: brand.clone(),
+            };
+            match page.render() {
+                Ok(html) => Response::builder()
+                    .status(StatusCode::OK)
+                    .header(CONTENT_TYPE, "text/html; charset=utf-8")
+                    .header("Refresh", format!("{seconds};url={target}"))
+                    .body(Body::from(html))
+                    .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR.into_response()),
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            }
+        }
+        Outcome::Interstitial(Interstitial::SingleUse { short_url }) => no_store_page(
+            StatusCode::OK,
+            SingleUseTemplate {
+                short_url: &short_url,
+                appearance,
+                brand: brand.clone(),
+            },
+        ),
+        Outcome::Interstitial(Interstitial::Consumed { at }) => {
+            consumed_page(at, appearance, brand, &url)
+        }
+        Outcome::Interstitial(Interstitial::Expired { at }) => {
+            expired_page(at, appearance, brand, &url)
+        }
+        Outcome::Interstitial(Interstitial::Pending { at }) => {
+            match pool_and_prefs.prefs().pending_links() {
+                PendingLinks::Page => pending_page(at, appearance, brand, &url),
+                PendingLinks::NotFound => not_found_handler().await,
+            }
+        }
+        Outcome::Interstitial(Interstitial::Exhausted { max_clicks }) => {
+            exhausted_page(max_clicks, appearance, brand, &url)
+        }
+        Outcome::Interstitial(Interstitial::Passphrase { short_url }) => no_store_page(
+            StatusCode::OK,
+            PassphraseTemplate {
+                short_url: &short_url,
+                appearance,
+                brand: brand.clone(),
+                wrong: false,
+            },
+        ),
+        Outcome::Claim(id) => match url_db::claim_single_use(id, &source, pool).await {
+            Ok(Some(long_url)) => {
+                if let Some(row) = url_row.as_ref() {
+                    pool_and_prefs.firehose.publish(ClickEvent::new(row));
+                }
+                Response::builder()
+                    .status(StatusCode::FOUND)
+                    .header(header::LOCATION, resolve::redirect_target(&long_url))
+                    .body(Body::empty())
+                    .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+            }
+            // Someone else claimed it between the lookup and the update, or it just expired
+            Ok(None) => {
+                let row = url_db::retrieve_url_obj(&url, pool_and_prefs.prefs().slug_case(), pool)
+                    .await
+                    .ok();
+                match row
+                    .as_ref()
+                    .map(|row| (row.consumed_at(), row.expires_at()))
+                {
+                    Some((None, Some(expired_at))) => {
+                        expired_page(expired_at, appearance, brand, &url)
+                    }
+                    row => {
+                        let at = row.and_then(|(at, _)| at).unwrap_or_else(Utc::now);
+                        consumed_page(at, appearance, brand, &url)
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Couldn't claim single use link {url}: {e}");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        },
+        Outcome::NotFound => {
+            if pool_and_prefs.features.enabled(Feature::MissedLookups) {
+                pool_and_prefs.missed.record(&url);
+            }
+            not_found_handler().await
+        }
+    }
+}
+
+/// Tells the visitor a single use link is gone and when it was used
+fn consumed_page(at: DateTime<Utc>, appearance: Appearance, brand: &Brand, code: &str) -> Response {
+    let page = ConsumedTemplate {
+        at: at.format("%Y-%m-%d %H:%M UTC").to_string(),
+        appearance,
+        brand: brand.clone(),
+        back: format!("/{code}"),
+    };
+    no_store_page(StatusCode::GONE, page)
+}
+
+/// Counts a visit to a link that is about to redirect and publishes it to the firehose, unless
+/// `client` opened it moments ago, see [RepeatClicks]. Visits from bots are counted apart, see
+/// [url_db::incr_bot_clicks]. Returns the response to send instead when the click couldn't be
+/// counted, i.e. the link used up its last click since it was looked up.
+async fn count_click(
+    row: Option<&mut UrlRow>,
+    source: &ClickSource,
+    client: Option<Client>,
+    pool_and_prefs: &PoolAndPrefs,
+    appearance: Appearance,
+) -> Option<Response> {
+    let row = row?;
+    if client.is_some_and(|client| pool_and_prefs.repeat_clicks.is_repeat(row.id(), client.id)) {
+        return None;
+    }
+    let counted = if source.device == Some(Device::Bot) {
+        url_db::incr_bot_clicks(row.id(), pool_and_prefs.pool()).await
     } else {
-        return StatusCode::OK.into_response();
+        url_db::incr_url_clicks(row, source, pool_and_prefs.pool()).await
+    };
+    match counted {
+        Ok(true) if source.device == Some(Device::Bot) => None,
+        Ok(true) => {
+            if pool_and_prefs
+                .prefs()
+                .hot_links()
+                .auto_flag_per_second()
+                .is_some()
+            {
+                pool_and_prefs.click_rates.record(row.id());
+            }
+            pool_and_prefs.firehose.publish(ClickEvent::new(row));
+            None
+        }
+        Ok(false) => Some(exhausted_page(
+            row.max_clicks().unwrap_or_default(),
+            appearance,
+            pool_and_prefs.theme().brand(),
+            row.short_url(),
+        )),
+        Err(e) => {
+            error!("Couldn't count a click on {}: {e}", row.short_url());
+            Some(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+/// The conversion pixel, `/px/<code>.gif`, for the destination's thank you page to embed. Credits
+/// a conversion to the visitor's latest click on the link within the attribution window. Always
+/// answers with the image, whether or not anything was recorded, so it never breaks the page it
+/// is on or tells anyone which codes exist.
+async fn conversion_pixel(
+    Path(file): Path<String>,
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    client: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+) -> Response {
+    let code = file.strip_suffix(".gif").unwrap_or(&file);
+    let prefs = pool_and_prefs.prefs();
+    if let (true, Some(client)) = (
+        pool_and_prefs.features.enabled(Feature::Conversions),
+        client_address(prefs, client, &headers),
+    ) {
+        let visitor = conversion::visitor_id(
+            prefs.jwt_secret(),
+            Utc::now().date_naive(),
+            client.id,
+            &headers,
+        );
+        let window = prefs.conversions().attribution_window();
+        if let Err(e) = conversion::record(code, &visitor, window, pool_and_prefs.pool()).await {
+            error!("Couldn't record a conversion on {code}: {e}");
+        }
+    }
+    content_response(
+        conversion::PIXEL.to_vec(),
+        HeaderValue::from_static("image/gif"),
+    )
+}
+
+/// Tells the visitor a link has been opened as many times as it allows
+fn exhausted_page(max_clicks: i64, appearance: Appearance, brand: &Brand, code: &str) -> Response {
+    let page = ExhaustedTemplate {
+        max_clicks,
+        appearance,
+        brand: brand.clone(),
+        back: format!("/{code}"),
+    };
+    no_store_page(StatusCode::GONE, page)
+}
+
+/// Tells the visitor a link has expired and since when
+fn expired_page(at: DateTime<Utc>, appearance: Appearance, brand: &Brand, code: &str) -> Response {
+    let page = ExpiredTemplate {
+        at: at.format("%Y-%m-%d %H:%M UTC").to_string(),
+        appearance,
+        brand: brand.clone(),
+        back: format!("/{code}"),
+    };
+    no_store_page(StatusCode::GONE, page)
+}
+
+/// Tells the visitor a link isn't live yet and when it will be
+fn pending_page(at: DateTime<Utc>, appearance: Appearance, brand: &Brand, code: &str) -> Response {
+    let page = PendingTemplate {
+        at: at.format("%Y-%m-%d %H:%M UTC").to_string(),
+        appearance,
+        brand: brand.clone(),
+        back: format!("/{code}"),
+    };
+    no_store_page(StatusCode::NOT_FOUND, page)
+}
+
+/// Renders a page that browsers and proxies must not keep, since what the link does can change on
+/// the next request
+fn no_store_page(status: StatusCode, page: impl Template) -> Response {
+    match page.render() {
+        Ok(html) => (
+            status,
+            CacheClass::Private,
+            [(CONTENT_TYPE, "text/html; charset=utf-8")],
+            html,
+        )
+            .into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+const MAX_ALIAS_LEN: usize = 64;
+
+/// Why `alias` can't be used as a short code, if it can't. Aliases use the letters and digits
+/// generated codes are made of, plus dots, and must be reachable: nothing that routes to a file or
+/// another page, and not reserved by the instance in `reserved`.
+fn alias_problem(alias: &str, reserved: &[String]) -> Option<String> {
+    if alias.len() > MAX_ALIAS_LEN {
+        Some(format!("Aliases can be at most {MAX_ALIAS_LEN} characters"))
+    } else if !alias
+        .chars()
+        .all(|letter| letter.is_ascii_alphanumeric() || letter == '.')
+    {
+        Some(String::from(
+            "Aliases can only contain letters, digits and dots",
+        ))
+    } else if alias.starts_with('.') {
+        // `.` and `..` are resolved away by clients before the request is sent
+        Some(String::from("Aliases can't start with a dot"))
+    } else if url_db::is_reserved(alias, reserved) || assets::exists(alias) {
+        Some(format!("The alias {alias} is reserved"))
+    } else {
+        None
+    }
+}
+
+/// When a link being created should expire, from either `expires_in`, a duration like `90m`, `12h`
+/// or `7d`, or `expires_at`, a time in RFC 3339 or as sent by a `datetime-local` input (taken as
+/// UTC). Errors are meant for the person filling in the form, including an expiry that has
+/// already passed at `now`.
+fn expiry_from_form(
+    form: &HashMap<String, String>,
+    now: DateTime<Utc>,
+) -> Result<Option<DateTime<Utc>>, String> {
+    let field = |name: &str| {
+        form.get(name)
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())
+    };
+    let expires_at = match (field("expires_in"), field("expires_at")) {
+        (None, None) => return Ok(None),
+        (Some(_), Some(_)) => {
+            return Err(String::from(
+                "Give either an expiry time or how long until it, not both",
+            ))
+        }
+        (Some(expires_in), None) => {
+            let not_a_duration = || format!("{expires_in} isn't a duration like 90m, 12h or 7d");
+            let unit_at = expires_in.char_indices().last().map_or(0, |(at, _)| at);
+            let (amount, unit) = expires_in.split_at(unit_at);
+            let amount = amount.parse::<i64>().map_err(|_| not_a_duration())?;
+            let duration = match unit {
+                "m" => chrono::TimeDelta::try_minutes(amount),
+                "h" => chrono::TimeDelta::try_hours(amount),
+                "d" => chrono::TimeDelta::try_days(amount),
+                _ => None,
+            }
+            .ok_or_else(not_a_duration)?;
+            now.checked_add_signed(duration)
+                .ok_or_else(|| format!("{expires_in} is too far in the future"))?
+        }
+        (None, Some(expires_at)) => form_time(expires_at)?,
+    };
+    if expires_at <= now {
+        return Err(String::from("The expiry time has already passed"));
+    }
+    Ok(Some(expires_at))
+}
+
+/// When a new link stops working: when it was asked to, but no later than the instance's
+/// [anonymous_link_ttl](Preferences::anonymous_link_ttl) after it goes live if it has no owner
+fn anonymous_expiry(
+    prefs: &Preferences,
+    link: &NewLink,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    let ttl = prefs
+        .anonymous_link_ttl()
+        .filter(|_| link.owner.is_none())
+        .and_then(|ttl| chrono::TimeDelta::from_std(ttl).ok());
+    let Some(ttl) = ttl else {
+        return link.expires_at;
+    };
+    let latest = link
+        .active_from
+        .unwrap_or(now)
+        .max(now)
+        .checked_add_signed(ttl);
+    match (link.expires_at, latest) {
+        (Some(asked), Some(latest)) => Some(asked.min(latest)),
+        (asked, latest) => asked.or(latest),
+    }
+}
+
+/// A time from a form, in RFC 3339 or as sent by a `datetime-local` input (taken as UTC)
+fn form_time(value: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|at| at.with_timezone(&Utc))
+        .or_else(|_| {
+            chrono::NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M").map(|at| at.and_utc())
+        })
+        .map_err(|_| format!("{value} isn't a date and time"))
+}
+
+/// Furthest ahead a link can be scheduled to start redirecting, in months
+const MAX_ACTIVATION_MONTHS: u32 = 60;
+
+/// Why a link can't start redirecting at `active_from`, if it can't: more than
+/// [MAX_ACTIVATION_MONTHS] after `now`, or not before it expires. A time that has passed is fine
+/// and just means the link works straight away.
+fn activation_problem(
+    active_from: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> Option<String> {
+    let latest = now.checked_add_months(chrono::Months::new(MAX_ACTIVATION_MONTHS));
+    if latest.is_none_or(|latest| active_from > latest) {
+        return Some(format!(
+            "Links can be scheduled at most {} years ahead",
+            MAX_ACTIVATION_MONTHS / 12
+        ));
+    }
+    if expires_at.is_some_and(|expires_at| expires_at <= active_from) {
+        return Some(String::from(
+            "A link has to start working before it expires",
+        ));
+    }
+    None
+}
+
+/// Resolves a short code. The only other thing served here is a file from html/ requested at
+/// its old path, which never reaches the database.
+async fn subdir_handler(
+    Path(path): Path<String>,
+    State(pool): State<Arc<PoolAndPrefs>>,
+    client: Option<ConnectInfo<SocketAddr>>,
+    method: Method,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(resp) = assets::legacy(&path, pool.prefs().legacy_asset_paths()).await {
+        return resp;
+    }
+    if let Some(code) = path.strip_suffix('+') {
+        return preview_short_url(code, &pool, &headers).await;
+    }
+    debug!("Redirecting user based on db result for {path}");
+    let client = client_address(pool.prefs(), client, &headers);
+    consume_short_url(Path(path), State(&pool), &headers, &method, client).await
+}
+
+/// The visitor's address: the connection's, or with `trust_proxy` the last one in X-Forwarded-For,
+/// which is the one the proxy saw. Earlier entries are whatever the visitor sent. Everything that
+/// keeps track of clients takes the [Client::id] made here, which with `anonymize_clients` is a
+/// hash of the address, so no raw address gets past this point into memory, storage or logs.
+fn client_address(
+    prefs: &Preferences,
+    client: Option<ConnectInfo<SocketAddr>>,
+    headers: &HeaderMap,
+) -> Option<Client> {
+    let forwarded = || {
+        headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .last()?
+            .trim()
+            .parse()
+            .ok()
+    };
+    let address = prefs
+        .trust_proxy()
+        .then(forwarded)
+        .flatten()
+        .or(client.map(|ConnectInfo(address)| address.ip()))?;
+    let secret = prefs.anonymize_clients().then(|| prefs.jwt_secret());
+    Some(Client::new(address, Utc::now().date_naive(), secret))
+}
+
+/// `/<code>+`: where a link goes and how often it has been opened, with a button to carry on.
+/// Nothing is counted, and aliases can't contain `+` so no code is shadowed by this.
+async fn preview_short_url(
+    code: &str,
+    pool_and_prefs: &PoolAndPrefs,
+    headers: &HeaderMap,
+) -> Response {
+    let row = match url_db::retrieve_url_obj(
+        code,
+        pool_and_prefs.prefs().slug_case(),
+        pool_and_prefs.pool(),
+    )
+    .await
+    {
+        Ok(row) => row,
+        Err(sqlx::Error::RowNotFound) => return not_found_handler().await,
+        Err(e) => {
+            error!("Couldn't look up {code} to preview it: {e}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let page = PreviewTemplate {
+        short_url: code,
+        destination: (!row.is_protected()).then_some(row.long_url().as_str()),
+        clicks: row.clicks(),
+        appearance: Appearance::from_headers(headers),
+        brand: pool_and_prefs.theme().brand().clone(),
+        back: format!("/{code}+"),
+    };
+    no_store_page(StatusCode::OK, page)
+}
+
+/// Two segment paths used to be files in a directory of html/. Short codes have one segment, so
+/// these are only ever old asset paths.
+async fn legacy_nested_asset(
+    Path((dir, file)): Path<(String, String)>,
+    State(pool): State<Arc<PoolAndPrefs>>,
+) -> Response {
+    let path = format!("{dir}/{file}");
+    match assets::legacy(&path, pool.prefs().legacy_asset_paths()).await {
+        Some(resp) => resp,
+        None => not_found_handler().await,
+    }
+}
+
+#[derive(Deserialize)]
+struct MissedQuery {
+    limit: Option<usize>,
+}
+
+/// Lists short codes that were requested but don't exist, most requested first. Admins only.
+async fn admin_missed(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Query(query): Query<MissedQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let resp = if pool_and_prefs.prefs().is_admin(user.username()) {
+        let missed = pool_and_prefs.missed.top(query.limit.unwrap_or(100));
+        Json(missed).into_response()
+    } else {
+        StatusCode::FORBIDDEN.into_response()
+    };
+    with_cookies(resp, set_cookies)
+}
+
+/// Whether the configuration meets the HSTS preload list's requirements, and what's missing if
+/// not. Admins only.
+async fn admin_hsts_readiness(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    headers: HeaderMap,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let resp = if pool_and_prefs.prefs().is_admin(user.username()) {
+        Json(https::preload_readiness(pool_and_prefs.prefs())).into_response()
+    } else {
+        StatusCode::FORBIDDEN.into_response()
+    };
+    with_cookies(resp, set_cookies)
+}
+
+#[derive(Serialize)]
+struct RecycleBody {
+    closed_idle: usize,
+}
+
+/// Retires every pooled database connection, e.g. before or after a planned failover. Idle ones
+/// are closed right away and busy ones once their query finishes. Same as sending SIGUSR2.
+async fn admin_recycle_pool(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    headers: HeaderMap,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let resp = if pool_and_prefs.prefs().is_admin(user.username()) {
+        info!(
+            "{} asked for the connection pool to be recycled",
+            user.username()
+        );
+        let closed_idle = pool_and_prefs.recycler.recycle(pool_and_prefs.pool());
+        Json(RecycleBody { closed_idle }).into_response()
+    } else {
+        StatusCode::FORBIDDEN.into_response()
+    };
+    with_cookies(resp, set_cookies)
+}
+
+#[derive(Serialize)]
+struct BumpBody {
+    generation: u64,
+}
+
+/// Changes every ETag this instance hands out, so browsers and proxies fetch fresh copies of
+/// everything they revalidate. For after a theme change, without renaming anything.
+async fn admin_bump_cache(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    headers: HeaderMap,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let resp = if pool_and_prefs.prefs().is_admin(user.username()) {
+        let generation = pool_and_prefs.caching.bump();
+        info!(
+            "{} bumped the cache generation to {generation}",
+            user.username()
+        );
+        Json(BumpBody { generation }).into_response()
+    } else {
+        StatusCode::FORBIDDEN.into_response()
+    };
+    with_cookies(resp, set_cookies)
+}
+
+/// The banner for the announcement running right now, for pages to load into a placeholder.
+/// Answers 204 when there is nothing to show, including when the visitor dismissed it.
+async fn announcement_banner(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    headers: HeaderMap,
+) -> Response {
+    let (signed_in, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(_, set_cookies) => (true, set_cookies),
+            AuthenticationResponse::Error(_) => (false, Vec::new()),
+        };
+    let dismissed = announcement::dismissed_ids(&headers);
+    let current = pool_and_prefs
+        .announcements
+        .current(signed_in, &dismissed, pool_and_prefs.pool())
+        .await;
+    let resp = match current {
+        Ok(Some(current)) => {
+            let banner = AnnouncementTemplate {
+                id: current.id(),
+                message: current.message(),
+                severity: current.severity().as_str(),
+                dismissible: current.dismissible(),
+            };
+            match banner.render() {
+                Ok(html) => Html::from(html).into_response(),
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            }
+        }
+        Ok(None) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            // A missing banner shouldn't break the page it sits on
+            error!("Couldn't load announcements: {e}");
+            StatusCode::NO_CONTENT.into_response()
+        }
+    };
+    with_cookies(resp, set_cookies)
+}
+
+/// Remembers that the visitor closed an announcement. htmx swaps the banner for the empty body;
+/// without JavaScript the form posts here and the visitor is sent back home.
+async fn dismiss_announcement(Path(id): Path<i64>, headers: HeaderMap) -> Response {
+    let cookie = announcement::dismiss_cookie(announcement::dismissed_ids(&headers), id);
+    if headers.contains_key("HX-Request") {
+        ([(SET_COOKIE, cookie)], "").into_response()
+    } else {
+        (
+            StatusCode::SEE_OTHER,
+            [(SET_COOKIE, cookie), (LOCATION, String::from("/"))],
+        )
+            .into_response()
+    }
+}
+
+/// Every announcement, including ended and upcoming ones. Admins only.
+async fn admin_list_announcements(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    headers: HeaderMap,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let resp = if !pool_and_prefs.prefs().is_admin(user.username()) {
+        StatusCode::FORBIDDEN.into_response()
+    } else {
+        match announcement::list(pool_and_prefs.pool()).await {
+            Ok(announcements) => Json(announcements).into_response(),
+            Err(e) => {
+                error!("Couldn't list announcements: {e}");
+                ApiError::unavailable("Couldn't list the announcements").into_response()
+            }
+        }
+    };
+    with_cookies(resp, set_cookies)
+}
+
+/// Adds an announcement. Admins only.
+async fn admin_create_announcement(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    headers: HeaderMap,
+    request: Result<Json<AnnouncementInput>, JsonRejection>,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let resp = if !pool_and_prefs.prefs().is_admin(user.username()) {
+        StatusCode::FORBIDDEN.into_response()
+    } else {
+        match save_announcement(&pool_and_prefs, None, request).await {
+            Ok(created) => {
+                info!("{} added announcement {}", user.username(), created.id());
+                (StatusCode::CREATED, Json(created)).into_response()
+            }
+            Err(e) => e.into_response(),
+        }
+    };
+    with_cookies(resp, set_cookies)
+}
+
+/// Replaces an announcement. Admins only.
+async fn admin_update_announcement(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+    request: Result<Json<AnnouncementInput>, JsonRejection>,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let resp = if !pool_and_prefs.prefs().is_admin(user.username()) {
+        StatusCode::FORBIDDEN.into_response()
+    } else {
+        match save_announcement(&pool_and_prefs, Some(id), request).await {
+            Ok(updated) => {
+                info!("{} changed announcement {id}", user.username());
+                Json(updated).into_response()
+            }
+            Err(e) => e.into_response(),
+        }
+    };
+    with_cookies(resp, set_cookies)
+}
+
+/// Creates the announcement, or replaces announcement `id`, and drops the cached ones
+async fn save_announcement(
+    pool_and_prefs: &PoolAndPrefs,
+    id: Option<i64>,
+    request: Result<Json<AnnouncementInput>, JsonRejection>,
+) -> Result<announcement::Announcement, ApiError> {
+    let Json(input) = request?;
+    if let Some(problem) = input.problem(Utc::now()) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_announcement",
+            problem,
+        ));
+    }
+    let pool = pool_and_prefs.pool();
+    let saved = match id {
+        None => announcement::create(&input, pool).await.map(Some),
+        Some(id) => announcement::update(id, &input, pool).await,
+    };
+    match saved {
+        Ok(Some(saved)) => {
+            pool_and_prefs.announcements.invalidate();
+            Ok(saved)
+        }
+        Ok(None) => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            "There is no such announcement",
+        )),
+        Err(e) => {
+            error!("Couldn't save an announcement: {e}");
+            Err(ApiError::unavailable("Couldn't save the announcement"))
+        }
+    }
+}
+
+/// Removes an announcement. Admins only.
+async fn admin_delete_announcement(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Path(id): Path<i64>,
+    Query(query): Query<DryRunQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let resp = if !pool_and_prefs.prefs().is_admin(user.username()) {
+        StatusCode::FORBIDDEN.into_response()
+    } else {
+        let executor = dry_run::executor(query.dry_run);
+        match announcement::delete(id, executor, pool_and_prefs.pool()).await {
+            Ok(true) => {
+                executor.record(user.username(), &format!("removed announcement {id}"));
+                if executor.dry_run() {
+                    Json(Affected::new(executor, vec![id])).into_response()
+                } else {
+                    pool_and_prefs.announcements.invalidate();
+                    StatusCode::NO_CONTENT.into_response()
+                }
+            }
+            Ok(false) => ApiError::new(
+                StatusCode::NOT_FOUND,
+                "not_found",
+                "There is no such announcement",
+            )
+            .into_response(),
+            Err(e) => {
+                error!("Couldn't remove announcement {id}: {e}");
+                ApiError::unavailable("Couldn't remove the announcement").into_response()
+            }
+        }
+    };
+    with_cookies(resp, set_cookies)
+}
+
+/// What [admin_rebuild_rollups] rebuilds: the days from `from` to `to`, both included, of the link
+/// `url`, of every link of `user`, or of every link when neither is given
+#[derive(Deserialize)]
+struct RebuildRequest {
+    from: NaiveDate,
+    to: NaiveDate,
+    #[serde(default)]
+    url: Option<String>,
+    #[serde(default)]
+    user: Option<String>,
+}
+
+/// Recomputes the daily clicks of links from the clicks recorded for conversion tracking, after
+/// analytics were purged or clicks were reclassified. Answers 202 with the rebuild, which goes on
+/// in the background a day at a time, see [rollup::start]. The stats of the links mark the days
+/// it hasn't reached yet as `rebuilding`. With `?dry_run=true` nothing is started and the days it
+/// would change are answered instead.
+async fn admin_rebuild_rollups(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Query(query): Query<DryRunQuery>,
+    headers: HeaderMap,
+    request: Result<Json<RebuildRequest>, JsonRejection>,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let resp = if !pool_and_prefs.prefs().is_admin(user.username()) {
+        StatusCode::FORBIDDEN.into_response()
+    } else {
+        match request {
+            Ok(Json(request)) => {
+                let executor = dry_run::executor(query.dry_run);
+                match start_rebuild(&pool_and_prefs, &request, &user, executor).await {
+                    Ok(started) => {
+                        executor.record(
+                            user.username(),
+                            &format!(
+                                "started a rollup rebuild of {} to {}, changing {} days of links",
+                                request.from,
+                                request.to,
+                                started.changes.len()
+                            ),
+                        );
+                        match started.rebuild {
+                            Some(rebuild) => {
+                                tokio::spawn(rollup::run(
+                                    rebuild.id,
+                                    pool_and_prefs.pool().clone(),
+                                ));
+                                (StatusCode::ACCEPTED, Json(rebuild)).into_response()
+                            }
+                            None => Json(Affected::new(executor, started.changes)).into_response(),
+                        }
+                    }
+                    Err(e) => e.into_response(),
+                }
+            }
+            Err(rejection) => ApiError::from(rejection).into_response(),
+        }
+    };
+    with_cookies(resp, set_cookies)
+}
+
+async fn start_rebuild(
+    pool_and_prefs: &PoolAndPrefs,
+    request: &RebuildRequest,
+    admin: &UserRow,
+    executor: &dyn Executor,
+) -> Result<rollup::Started, ApiError> {
+    let pool = pool_and_prefs.pool();
+    let failed = |e: sqlx::Error| {
+        error!("Couldn't start a rollup rebuild: {e}");
+        ApiError::unavailable("Couldn't start the rebuild")
+    };
+    if !pool_and_prefs.features.enabled(Feature::Conversions) {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "not_tracked",
+            "Clicks are only recorded to rebuild from while conversion tracking is on",
+        ));
+    }
+    let scope = match (&request.url, &request.user) {
+        (Some(_), Some(_)) => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "conflicting_options",
+                "Give either a url or a user to rebuild, not both",
+            ))
+        }
+        (Some(code), None) => {
+            match url_db::retrieve_url_obj(code, pool_and_prefs.prefs().slug_case(), pool).await {
+                Ok(row) => rollup::Scope::Link(row.id()),
+                Err(sqlx::Error::RowNotFound) => {
+                    return Err(ApiError::new(
+                        StatusCode::NOT_FOUND,
+                        "not_found",
+                        format!("There is no link {code}"),
+                    ))
+                }
+                Err(e) => return Err(failed(e)),
+            }
+        }
+        (None, Some(username)) => match user::retrieve_user_by_name(username, pool).await {
+            Ok(owner) => rollup::Scope::User(*owner.id()),
+            Err(sqlx::Error::RowNotFound) => {
+                return Err(ApiError::new(
+                    StatusCode::NOT_FOUND,
+                    "not_found",
+                    format!("There is no user {username}"),
+                ))
+            }
+            Err(e) => return Err(failed(e)),
+        },
+        (None, None) => rollup::Scope::All,
+    };
+    rollup::start(
+        scope,
+        request.from,
+        request.to,
+        admin.username(),
+        executor,
+        pool,
+    )
+    .await
+    .map_err(|e| match e {
+        rollup::StartError::Overlaps(_) => {
+            ApiError::new(StatusCode::CONFLICT, "rebuild_overlaps", e.to_string())
+        }
+        rollup::StartError::Backwards => {
+            ApiError::new(StatusCode::BAD_REQUEST, "invalid_range", e.to_string())
+        }
+        rollup::StartError::NotCovered(_) => {
+            ApiError::new(StatusCode::BAD_REQUEST, "not_covered", e.to_string())
+        }
+        rollup::StartError::Database(e) => failed(e),
+    })
+}
+
+/// How far a rollup rebuild has got
+async fn admin_rollup_rebuild(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let resp = if !pool_and_prefs.prefs().is_admin(user.username()) {
+        StatusCode::FORBIDDEN.into_response()
+    } else {
+        match rollup::rebuild(id, pool_and_prefs.pool()).await {
+            Ok(Some(rebuild)) => Json(rebuild).into_response(),
+            Ok(None) => ApiError::new(
+                StatusCode::NOT_FOUND,
+                "not_found",
+                "There is no such rebuild",
+            )
+            .into_response(),
+            Err(e) => {
+                error!("Couldn't load rollup rebuild {id}: {e}");
+                ApiError::unavailable("Couldn't load the rebuild").into_response()
+            }
+        }
+    };
+    with_cookies(resp, set_cookies)
+}
+
+/// Loads a link for `user` to look at, change or delete. Anonymous links belong to nobody, so only admins
+/// can manage those.
+async fn managed_link(
+    pool_and_prefs: &PoolAndPrefs,
+    code: &str,
+    user: &UserRow,
+) -> Result<UrlRow, ApiError> {
+    let found = url_db::retrieve_url_obj(
+        code,
+        pool_and_prefs.prefs().slug_case(),
+        pool_and_prefs.pool(),
+    )
+    .await;
+    manageable(pool_and_prefs, code, user, found)
+}
+
+/// [managed_link] for archived links, which are only ever restored
+async fn archived_link(
+    pool_and_prefs: &PoolAndPrefs,
+    code: &str,
+    user: &UserRow,
+) -> Result<UrlRow, ApiError> {
+    let found = url_db::retrieve_archived_url_obj(
+        code,
+        pool_and_prefs.prefs().slug_case(),
+        pool_and_prefs.pool(),
+    )
+    .await;
+    manageable(pool_and_prefs, code, user, found)
+}
+
+/// The link looked up for `code` if `user` may manage it
+fn manageable(
+    pool_and_prefs: &PoolAndPrefs,
+    code: &str,
+    user: &UserRow,
+    found: Result<UrlRow, sqlx::Error>,
+) -> Result<UrlRow, ApiError> {
+    let row = match found {
+        Ok(row) => row,
+        Err(sqlx::Error::RowNotFound) => {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "not_found",
+                format!("There is no link {code}"),
+            ))
+        }
+        Err(e) => {
+            error!("Couldn't look up {code} to manage it: {e}");
+            return Err(ApiError::unavailable("Couldn't look up the link"));
+        }
+    };
+    let allowed = match row.created_by() {
+        Some(owner) => owner == *user.id(),
+        None => pool_and_prefs.prefs().is_admin(user.username()),
+    };
+    if allowed {
+        Ok(row)
+    } else {
+        Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "forbidden",
+            "Only the owner of a link can manage it",
+        ))
+    }
+}
+
+#[derive(Deserialize)]
+struct UrlPageQuery {
+    page: Option<i64>,
+    per_page: Option<i64>,
+    /// Only links with this tag
+    tag: Option<String>,
+    /// The archived links instead of the live ones
+    #[serde(default)]
+    archived: bool,
+}
+
+#[derive(Serialize)]
+struct UrlPageBody<'a> {
+    items: Vec<ApiUrlBody<'a>>,
+    total: i64,
+    page: i64,
+    per_page: i64,
+}
+
+/// The signed in user's links, newest first, a page at a time. `?tag=` narrows them to the links
+/// with that tag, and `?archived=true` lists the archived ones instead.
+async fn api_list_urls(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Query(query): Query<UrlPageQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(20).clamp(1, url_db::MAX_PAGE_SIZE);
+    // Compared the way tags are stored
+    let tag = query
+        .tag
+        .map(|tag| tag.trim().to_lowercase())
+        .filter(|tag| !tag.is_empty());
+    let pool = pool_and_prefs.pool();
+    let listed = tokio::try_join!(
+        url_db::list_urls_for_user(
+            *user.id(),
+            tag.as_deref(),
+            query.archived,
+            page,
+            per_page,
+            pool
+        ),
+        url_db::count_urls(
+            OwnerFilter::User(*user.id()),
+            tag.as_deref(),
+            query.archived,
+            pool
+        ),
+    );
+    let listed = match listed {
+        Ok((rows, total)) => {
+            let ids: Vec<i64> = rows.iter().map(UrlRow::id).collect();
+            tags::for_links(&ids, pool)
+                .await
+                .map(|tags| (rows, total, tags))
+        }
+        Err(e) => Err(e),
+    };
+    let resp = match listed {
+        Ok((rows, total, tags)) => Json(UrlPageBody {
+            items: rows
+                .iter()
+                .map(|row| {
+                    let row_tags = tags.get(&row.id()).map_or(&[][..], Vec::as_slice);
+                    ApiUrlBody::new(row, pool_and_prefs.prefs()).with_tags(row_tags)
+                })
+                .collect(),
+            total,
+            page,
+            per_page,
+        })
+        .into_response(),
+        Err(e) => {
+            error!("Couldn't list the links of {}: {e}", user.username());
+            ApiError::unavailable("Couldn't list your links").into_response()
+        }
+    };
+    with_cookies(resp, set_cookies)
+}
+
+#[derive(Deserialize)]
+struct DeleteQuery {
+    /// Delete the link for good instead of archiving it. Admins only.
+    #[serde(default)]
+    purge: bool,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Archives a link belonging to the signed in user, see [managed_link]. It stops redirecting but
+/// keeps its clicks and its code, and can be restored. Admins can delete any link for good,
+/// archived or not, with `?purge=true`. With `?dry_run=true` the link is only looked up, and
+/// answered as what would have been deleted.
+async fn api_delete_url(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Path(code): Path<String>,
+    Query(query): Query<DeleteQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let executor = dry_run::executor(query.dry_run);
+    let done = if query.purge {
+        purge_link(&pool_and_prefs, &code, &user, executor).await
+    } else {
+        archive_link(&pool_and_prefs, &code, &user, executor).await
+    };
+    let resp = match done {
+        Ok(code) if executor.dry_run() => Json(Affected::new(executor, vec![code])).into_response(),
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => e.into_response(),
+    };
+    with_cookies(resp, set_cookies)
+}
+
+/// Returns the code of the link archived
+async fn archive_link(
+    pool_and_prefs: &PoolAndPrefs,
+    code: &str,
+    user: &UserRow,
+    executor: &dyn Executor,
+) -> Result<String, ApiError> {
+    let row = managed_link(pool_and_prefs, code, user).await?;
+    let archive = async {
+        url_db::archive_url(row.id(), pool_and_prefs.pool())
+            .await
+            .map(|_| 1)
+    };
+    match executor.act(Box::pin(archive)).await {
+        Ok(_) => {
+            executor.record(
+                user.username(),
+                &format!("archived the link {}", row.short_url()),
+            );
+            Ok(row.short_url().to_string())
+        }
+        // Archived or purged since it was looked up
+        Err(sqlx::Error::RowNotFound) => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            format!("There is no link {code}"),
+        )),
+        Err(e) => {
+            error!("Couldn't archive {code}: {e}");
+            Err(ApiError::unavailable("Couldn't delete the link"))
+        }
+    }
+}
+
+/// Returns the code of the link purged
+async fn purge_link(
+    pool_and_prefs: &PoolAndPrefs,
+    code: &str,
+    user: &UserRow,
+    executor: &dyn Executor,
+) -> Result<String, ApiError> {
+    if !pool_and_prefs.prefs().is_admin(user.username()) {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "forbidden",
+            "Only admins can purge links",
+        ));
+    }
+    let case = pool_and_prefs.prefs().slug_case();
+    let pool = pool_and_prefs.pool();
+    let found = match url_db::retrieve_url_obj(code, case, pool).await {
+        Err(sqlx::Error::RowNotFound) => url_db::retrieve_archived_url_obj(code, case, pool).await,
+        found => found,
+    };
+    let failed = |e: sqlx::Error| {
+        error!("Couldn't purge {code}: {e}");
+        ApiError::unavailable("Couldn't delete the link")
+    };
+    let row = match found {
+        Ok(row) => row,
+        Err(sqlx::Error::RowNotFound) => {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "not_found",
+                format!("There is no link {code}"),
+            ))
+        }
+        Err(e) => return Err(failed(e)),
+    };
+    let delete = async {
+        let deleted = url_db::delete_url(row.id(), pool).await?;
+        Ok(deleted.rows_affected())
+    };
+    executor.act(Box::pin(delete)).await.map_err(failed)?;
+    executor.record(
+        user.username(),
+        &format!("purged the link {}", row.short_url()),
+    );
+    Ok(row.short_url().to_string())
+}
+
+/// `?dry_run=true` on a destructive admin operation answers what it would change without changing
+/// anything, see [dry_run::Executor]
+#[derive(Deserialize)]
+struct DryRunQuery {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Deserialize)]
+struct PurgeArchivedQuery {
+    /// Defaults to the configured retention
+    #[serde(default)]
+    older_than_days: Option<u32>,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Deletes the links archived at least `?older_than_days` ago for good, as the background purge
+/// does with the configured retention, and answers with their codes
+async fn admin_purge_archived(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Query(query): Query<PurgeArchivedQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let retention = query
+        .older_than_days
+        .map(|days| time::Duration::from_secs(u64::from(days) * 60 * 60 * 24))
+        .or_else(|| pool_and_prefs.prefs().archive().retention());
+    let resp = if !pool_and_prefs.prefs().is_admin(user.username()) {
+        StatusCode::FORBIDDEN.into_response()
+    } else if let Some(retention) = retention {
+        let executor = dry_run::executor(query.dry_run);
+        match archive::purge_archived(retention, executor, pool_and_prefs.pool()).await {
+            Ok(codes) => {
+                executor.record(
+                    user.username(),
+                    &format!("purged {} archived links", codes.len()),
+                );
+                Json(Affected::new(executor, codes)).into_response()
+            }
+            Err(e) => {
+                error!("Couldn't purge archived links: {e}");
+                ApiError::unavailable("Couldn't purge the archived links").into_response()
+            }
+        }
+    } else {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "missing_retention",
+            "Give older_than_days, no retention is configured",
+        )
+        .into_response()
+    };
+    with_cookies(resp, set_cookies)
+}
+
+/// Makes a link hot, counting its clicks over several rows, see [hot_links]. Links with a click
+/// limit can't be.
+async fn admin_flag_hot(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Path(code): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let resp = match hot_link(&pool_and_prefs, &code, &user).await {
+        Ok(row) => {
+            let shards = pool_and_prefs.prefs().hot_links().shards();
+            match hot_links::flag(row.id(), shards, pool_and_prefs.pool()).await {
+                Ok(hot_links::Flagged::Now) => {
+                    info!("{} made the link {code} hot", user.username());
+                    StatusCode::NO_CONTENT.into_response()
+                }
+                Ok(hot_links::Flagged::Already) => StatusCode::NO_CONTENT.into_response(),
+                Ok(hot_links::Flagged::Limited) => ApiError::new(
+                    StatusCode::CONFLICT,
+                    "click_limited",
+                    "Links with a click limit or single use can't be hot",
+                )
+                .into_response(),
+                Err(e) => {
+                    error!("Couldn't make {code} hot: {e}");
+                    ApiError::unavailable("Couldn't make the link hot").into_response()
+                }
+            }
+        }
+        Err(e) => e.into_response(),
+    };
+    with_cookies(resp, set_cookies)
+}
+
+/// Counts a hot link's clicks in its own row again, folding its shards into it first
+async fn admin_unflag_hot(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Path(code): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let resp = match hot_link(&pool_and_prefs, &code, &user).await {
+        Ok(row) => match hot_links::unflag(row.id(), pool_and_prefs.pool()).await {
+            Ok(folded) => {
+                info!(
+                    "{} stopped counting the link {code} as hot, folding {folded} clicks",
+                    user.username()
+                );
+                StatusCode::NO_CONTENT.into_response()
+            }
+            Err(e) => {
+                error!("Couldn't stop counting {code} as hot: {e}");
+                ApiError::unavailable("Couldn't change the link").into_response()
+            }
+        },
+        Err(e) => e.into_response(),
+    };
+    with_cookies(resp, set_cookies)
+}
+
+/// The live link `code`, for admins
+async fn hot_link(
+    pool_and_prefs: &PoolAndPrefs,
+    code: &str,
+    user: &UserRow,
+) -> Result<UrlRow, ApiError> {
+    if !pool_and_prefs.prefs().is_admin(user.username()) {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "forbidden",
+            "Only admins can change how links are counted",
+        ));
+    }
+    let case = pool_and_prefs.prefs().slug_case();
+    match url_db::retrieve_url_obj(code, case, pool_and_prefs.pool()).await {
+        Ok(row) => Ok(row),
+        Err(sqlx::Error::RowNotFound) => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            format!("There is no link {code}"),
+        )),
+        Err(e) => {
+            error!("Couldn't look up {code}: {e}");
+            Err(ApiError::unavailable("Couldn't look up the link"))
+        }
+    }
+}
+
+/// Brings back one of the signed in user's archived links, with the clicks it had
+async fn api_restore_url(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Path(code): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let restored = match archived_link(&pool_and_prefs, &code, &user).await {
+        Ok(row) => url_db::restore_url(row.id(), pool_and_prefs.pool())
+            .await
+            .map_err(|e| match e {
+                // Restored or purged since it was looked up
+                sqlx::Error::RowNotFound => ApiError::new(
+                    StatusCode::NOT_FOUND,
+                    "not_found",
+                    format!("There is no archived link {code}"),
+                ),
+                e => {
+                    error!("Couldn't restore {code}: {e}");
+                    ApiError::unavailable("Couldn't restore the link")
+                }
+            }),
+        Err(e) => Err(e),
+    };
+    let resp = match restored {
+        Ok(row) => {
+            info!("{} restored the link {code}", user.username());
+            Json(ApiUrlBody::new(&row, pool_and_prefs.prefs())).into_response()
+        }
+        Err(e) => e.into_response(),
+    };
+    with_cookies(resp, set_cookies)
+}
+
+/// What the owner of a link can see about it. Kept apart from [ApiUrlBody] so it can grow without
+/// changing what creating a link returns.
+#[derive(Serialize)]
+struct ApiUrlStats<'a> {
+    short_url: &'a str,
+    long_url: &'a str,
+    full_url: String,
+    clicks: i64,
+    /// Visits from crawlers, link previews and scripts, which aren't in `clicks`
+    bot_clicks: i64,
+    /// Null for links made before creation times were recorded
+    created_at: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+    /// Null for links that worked as soon as they were made
+    active_from: Option<DateTime<Utc>>,
+    max_clicks: Option<i64>,
+    interstitial_seconds: i16,
+    single_use: bool,
+    consumed_at: Option<DateTime<Utc>>,
+    protected: bool,
+    /// Null when the link redirects with the instance's status
+    redirect_status: Option<RedirectStatus>,
+    /// Every day of the requested range, see [StatsQuery]. Days a rollup rebuild hasn't reached
+    /// yet are marked `rebuilding`.
+    daily: Vec<DailyClicks>,
+    /// The origins that sent the most clicks, over the link's whole life
+    top_referrers: Vec<ReferrerClicks>,
+    /// Clicks per kind of client, e.g. `{"bot": 1, "desktop": 4, "mobile": 7, "other": 0}`. Clicks
+    /// from before devices were recorded aren't in it.
+    devices: BTreeMap<&'static str, i64>,
+    /// Clicks followed by the conversion pixel, see [conversion_pixel]
+    conversions: i64,
+    /// conversions / clicks, null while there are no clicks. Clicks from before conversions were
+    /// tracked count too.
+    conversion_rate: Option<f64>,
+    /// The destinations of a rotating link with the clicks each was picked for
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    destinations: Vec<Destination>,
+    /// When a link rolling out the second of its destinations sends every visitor there
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rollout_until: Option<DateTime<Utc>>,
+    /// Destinations by country code, see [with_geo](Self::with_geo)
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    geo_targets: BTreeMap<String, String>,
+    /// Clicks per branch a geo-targeted link sent them down, e.g. `{"US": 4, "default": 9}`.
+    /// Clicks from before it was targeted aren't in it.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    geo_clicks: BTreeMap<String, i64>,
+}
+
+impl<'a> ApiUrlStats<'a> {
+    fn new(
+        row: &'a UrlRow,
+        prefs: &Preferences,
+        daily: Vec<DailyClicks>,
+        top_referrers: Vec<ReferrerClicks>,
+        devices: BTreeMap<&'static str, i64>,
+        conversions: i64,
+        destinations: Vec<Destination>,
+    ) -> Self {
+        let conversion_rate = (row.clicks() > 0).then(|| conversions as f64 / row.clicks() as f64);
+        ApiUrlStats {
+            short_url: row.short_url(),
+            long_url: row.long_url(),
+            full_url: prefs.full_url(row.short_url()),
+            clicks: row.clicks(),
+            bot_clicks: row.bot_clicks(),
+            created_at: row.created_at(),
+            expires_at: row.expires_at(),
+            active_from: row.active_from(),
+            max_clicks: row.max_clicks(),
+            interstitial_seconds: row.interstitial_seconds(),
+            single_use: row.single_use(),
+            consumed_at: row.consumed_at(),
+            protected: row.is_protected(),
+            redirect_status: row.redirect_status(),
+            daily,
+            top_referrers,
+            devices,
+            conversions,
+            conversion_rate,
+            destinations,
+            rollout_until: row.rollout_until(),
+            geo_targets: BTreeMap::new(),
+            geo_clicks: BTreeMap::new(),
+        }
+    }
+    fn with_geo(
+        mut self,
+        geo_targets: BTreeMap<String, String>,
+        geo_clicks: BTreeMap<String, i64>,
+    ) -> Self {
+        self.geo_targets = geo_targets;
+        self.geo_clicks = geo_clicks;
+        self
+    }
+}
+
+/// Most days of click history one stats request can cover
+const MAX_STATS_DAYS: i64 = 366;
+/// How many referrers the stats list
+const TOP_REFERRERS: i64 = 10;
+
+/// Days of click history to include, both ends included and in UTC. Defaults to the last 30 days.
+#[derive(Deserialize)]
+struct StatsQuery {
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+}
+
+impl StatsQuery {
+    fn range(&self, today: NaiveDate) -> Result<(NaiveDate, NaiveDate), ApiError> {
+        let to = self.to.unwrap_or(today);
+        let from = self.from.unwrap_or(to - chrono::Days::new(29));
+        let days = (to - from).num_days() + 1;
+        if !(1..=MAX_STATS_DAYS).contains(&days) {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_range",
+                format!("from has to be on or before to, and at most {MAX_STATS_DAYS} days apart"),
+            ));
+        }
+        Ok((from, to))
+    }
+}
+
+/// Clicks and settings of one of the signed in user's links, see [managed_link]
+async fn api_url_stats(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Path(code): Path<String>,
+    Query(query): Query<StatsQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let resp = match url_stats(&pool_and_prefs, &code, &user, &query).await {
+        Ok(resp) => resp,
+        Err(e) => e.into_response(),
+    };
+    with_cookies(resp, set_cookies)
+}
+
+/// A QR code for a link's short url, as an SVG unless `format=png` is asked for. Anyone can get
+/// one, but only for links that exist. The image only depends on the short url, so it is cached
+/// for as long as fingerprinted files are.
+async fn api_url_qr(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Path(code): Path<String>,
+    Query(query): Query<qr::QrQuery>,
+) -> Response {
+    let Some(module_size) = query.module_size() else {
+        return ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_size",
+            qr::QrQuery::bounds_message(),
+        )
+        .into_response();
+    };
+    let row = match url_db::retrieve_url_obj(
+        &code,
+        pool_and_prefs.prefs().slug_case(),
+        pool_and_prefs.pool(),
+    )
+    .await
+    {
+        Ok(row) => row,
+        Err(sqlx::Error::RowNotFound) => {
+            return ApiError::new(
+                StatusCode::NOT_FOUND,
+                "not_found",
+                format!("There is no link {code}"),
+            )
+            .into_response()
+        }
+        Err(e) => {
+            error!("Couldn't look up {code} for a QR code: {e}");
+            return ApiError::unavailable("Couldn't look up the link").into_response();
+        }
+    };
+    let url = pool_and_prefs.prefs().full_url(row.short_url());
+    match qr::render(&url, query.format, module_size) {
+        Ok(image) => {
+            let content_type = HeaderValue::from_static(query.format.content_type());
+            (
+                CacheClass::Fingerprinted,
+                content_response(image, content_type),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            error!("Couldn't make a QR code for {code}: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn url_stats(
+    pool_and_prefs: &PoolAndPrefs,
+    code: &str,
+    user: &UserRow,
+    query: &StatsQuery,
+) -> Result<Response, ApiError> {
+    let (from, to) = query.range(Utc::now().date_naive())?;
+    let row = managed_link(pool_and_prefs, code, user).await?;
+    let pool = pool_and_prefs.pool();
+    let (daily, top_referrers, devices, conversions, geo_clicks) = tokio::try_join!(
+        url_db::clicks_by_day(row.id(), from, to, pool),
+        url_db::top_referrers(row.id(), TOP_REFERRERS, pool),
+        url_db::clicks_by_device(row.id(), pool),
+        conversion::count(row.id(), pool),
+        url_db::clicks_by_geo_branch(row.id(), pool),
+    )
+    .map_err(|e| {
+        error!("Couldn't load the click history of {code}: {e}");
+        ApiError::unavailable("Couldn't load the click history")
+    })?;
+    let destinations = destinations_of(&row, pool).await?;
+    let geo_targets = geo_targets_of(&row, pool).await?;
+    let stats = ApiUrlStats::new(
+        &row,
+        pool_and_prefs.prefs(),
+        daily,
+        top_referrers,
+        devices,
+        conversions,
+        destinations,
+    )
+    .with_geo(geo_targets, geo_clicks);
+    Ok(Json(stats).into_response())
+}
+
+/// Changes to one link. Fields left out stay as they are, and an empty description removes it.
+/// A url points a rotating link at just that, and an empty list of destinations stops it
+/// rotating.
+#[derive(Deserialize)]
+struct ApiEditUrl {
+    #[serde(default)]
+    url: Option<String>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    destinations: Option<Vec<ApiDestination>>,
+    /// Rolls the url out to this percent of visitors instead, the rest still going where the link
+    /// goes now, see [url_db::start_rollout]. Without a url it changes the share of the url being
+    /// rolled out. It gets every visitor once the rollout is committed, or after the days set in
+    /// [rollout::RolloutPrefs].
+    #[serde(default)]
+    rollout_percent: Option<i32>,
+    /// Where to send visitors from some countries instead, `{"US": "https://...", ...}`. They
+    /// replace the link's current ones, and `{}` sends everyone to the url again. A visitor whose
+    /// country is listed isn't rotated.
+    #[serde(default)]
+    geo_targets: Option<BTreeMap<String, String>>,
+}
+
+/// Points one of the signed in user's links somewhere else, changes the destinations it rotates
+/// between or by country, or changes its description. The code and its clicks stay.
+async fn api_edit_url(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Path(code): Path<String>,
+    headers: HeaderMap,
+    request: Result<Json<ApiEditUrl>, JsonRejection>,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let edited = match edit_link(&pool_and_prefs, &code, &user, request).await {
+        Ok(row) => {
+            let pool = pool_and_prefs.pool();
+            tokio::try_join!(destinations_of(&row, pool), geo_targets_of(&row, pool))
+                .map(|(destinations, geo_targets)| (row, destinations, geo_targets))
+        }
+        Err(e) => Err(e),
+    };
+    let resp = match edited {
+        Ok((row, destinations, geo_targets)) => Json(
+            ApiUrlBody::new(&row, pool_and_prefs.prefs())
+                .with_destinations(&destinations)
+                .with_geo_targets(&geo_targets),
+        )
+        .into_response(),
+        Err(e) => e.into_response(),
+    };
+    with_cookies(resp, set_cookies)
+}
+
+/// Geo targets the way a link's history shows them, None for none
+fn describe_geo_targets(targets: &BTreeMap<String, String>) -> Option<String> {
+    let described: Vec<String> = targets
+        .iter()
+        .map(|(country, long_url)| format!("{country}: {long_url}"))
+        .collect();
+    (!described.is_empty()).then(|| described.join(", "))
+}
+
+/// Destinations the way a link's history shows them, None for none
+fn describe_destinations<'a>(
+    destinations: impl IntoIterator<Item = (&'a str, i32)>,
+) -> Option<String> {
+    let described: Vec<String> = destinations
+        .into_iter()
+        .map(|(long_url, weight)| format!("{long_url} (weight {weight})"))
+        .collect();
+    (!described.is_empty()).then(|| described.join(", "))
+}
+
+async fn edit_link(
+    pool_and_prefs: &PoolAndPrefs,
+    code: &str,
+    user: &UserRow,
+    request: Result<Json<ApiEditUrl>, JsonRejection>,
+) -> Result<UrlRow, ApiError> {
+    let Json(request) = request?;
+    if request.url.is_none()
+        && request.description.is_none()
+        && request.destinations.is_none()
+        && request.geo_targets.is_none()
+        && request.rollout_percent.is_none()
+    {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "nothing_to_change",
+            "Give a url, destinations, geo targets, a rollout percent or a description to change",
+        ));
+    }
+    let rotating = request.destinations.as_ref().is_some_and(|d| !d.is_empty());
+    if request.url.is_some() && rotating {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "conflicting_options",
+            "Give either a url or destinations to rotate between",
+        ));
+    }
+    let rolling_out = request.rollout_percent.is_some();
+    if rolling_out && request.destinations.is_some() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "conflicting_options",
+            "Give either destinations or a rollout percent",
+        ));
+    }
+    let targeting = request.geo_targets.as_ref().is_some_and(|t| !t.is_empty());
+    let row = managed_link(pool_and_prefs, code, user).await?;
+    if row.single_use() && (rotating || targeting || rolling_out) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "conflicting_options",
+            "Single use links only ever go to one destination",
+        ));
+    }
+    let mut destination = request
+        .url
+        .as_deref()
+        .map(|url| checked_destination(url, pool_and_prefs.prefs()))
+        .transpose()?;
+    let rollout = match request.rollout_percent {
+        Some(percent) => {
+            let new = destination.take().map(|(destination, _)| destination);
+            Some(checked_rollout(pool_and_prefs.pool(), &row, new, percent).await?)
+        }
+        None => None,
+    };
+    // A new url is all a rotating link goes to from then on, unless it is rolled out
+    let destinations = match (request.destinations.as_deref(), &rollout) {
+        (_, Some(arms)) => Some(arms.to_vec()),
+        (Some(list), None) if !list.is_empty() => {
+            Some(checked_destinations(pool_and_prefs, list).await?)
+        }
+        (Some(_), None) => Some(Vec::new()),
+        (None, None) if destination.is_some() && row.rotates() => Some(Vec::new()),
+        (None, None) => None,
+    };
+    // Changing the share doesn't put off when the rollout finishes
+    let rollout_until = rollout.is_some().then(|| {
+        row.rollout_until()
+            .unwrap_or_else(|| pool_and_prefs.prefs().rollout().deadline(Utc::now()))
+    });
+    let geo_targets = match &request.geo_targets {
+        Some(targets) => Some(checked_geo_targets(pool_and_prefs, targets).await?),
+        None => None,
+    };
+    let description = request
+        .description
+        .as_deref()
+        .map(clean_description)
+        .transpose()?;
+    let failed = |e: sqlx::Error| {
+        error!("Couldn't change {code}: {e}");
+        ApiError::unavailable("Couldn't change the link")
+    };
+    let gone = |e: sqlx::Error| match e {
+        // Deleted since it was looked up
+        sqlx::Error::RowNotFound => ApiError::new(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            format!("There is no link {code}"),
+        ),
+        e => failed(e),
+    };
+    // Edits are recorded with the change, so the history can't miss one that went through
+    let mut tx = pool_and_prefs.pool().begin().await.map_err(failed)?;
+    let mut updated = row.clone();
+    if let Some((destination, submitted_url)) = &destination {
+        updated = url_db::update_url_destination(
+            row.id(),
+            destination,
+            submitted_url.as_deref(),
+            &mut *tx,
+        )
+        .await
+        .map_err(gone)?;
+        history::record(
+            row.id(),
+            *user.id(),
+            history::DESTINATION,
+            Some(row.long_url()),
+            Some(destination),
+            &mut *tx,
+        )
+        .await
+        .map_err(failed)?;
+    }
+    if let Some(destinations) = &destinations {
+        let old = if row.rotates() {
+            url_db::destinations(row.id(), &mut *tx)
+                .await
+                .map_err(failed)?
+        } else {
+            Vec::new()
+        };
+        updated = match rollout_until {
+            Some(until) => url_db::start_rollout(row.id(), destinations, until, &mut tx).await,
+            None => url_db::set_destinations(row.id(), destinations, &mut tx).await,
+        }
+        .map_err(gone)?;
+        let old = describe_destinations(old.iter().map(|d| (d.long_url.as_str(), d.weight)));
+        let new =
+            describe_destinations(destinations.iter().map(|d| (d.long_url.as_str(), d.weight)));
+        if old != new {
+            history::record(
+                row.id(),
+                *user.id(),
+                history::DESTINATIONS,
+                old.as_deref(),
+                new.as_deref(),
+                &mut *tx,
+            )
+            .await
+            .map_err(failed)?;
+        }
+    }
+    if let Some(geo_targets) = &geo_targets {
+        let old = if row.geo_targeted() {
+            url_db::geo_targets(row.id(), &mut *tx)
+                .await
+                .map_err(failed)?
+        } else {
+            BTreeMap::new()
+        };
+        updated = url_db::set_geo_targets(row.id(), geo_targets, &mut tx)
+            .await
+            .map_err(gone)?;
+        if &old != geo_targets {
+            history::record(
+                row.id(),
+                *user.id(),
+                history::GEO_TARGETS,
+                describe_geo_targets(&old).as_deref(),
+                describe_geo_targets(geo_targets).as_deref(),
+                &mut *tx,
+            )
+            .await
+            .map_err(failed)?;
+        }
+    }
+    if let Some(description) = &description {
+        updated = url_db::update_url_description(row.id(), description.as_deref(), &mut *tx)
+            .await
+            .map_err(gone)?;
+        history::record(
+            row.id(),
+            *user.id(),
+            history::DESCRIPTION,
+            row.description(),
+            description.as_deref(),
+            &mut *tx,
+        )
+        .await
+        .map_err(failed)?;
+    }
+    tx.commit().await.map_err(failed)?;
+    if let Some((destination, _)) = &destination {
+        info!("{} pointed {code} at {destination}", user.username());
+    }
+    match (destinations.as_ref().map(Vec::len), &rollout) {
+        (_, Some([_, new])) => info!(
+            "{} is rolling {} out to {}% of the visitors of {code}",
+            user.username(),
+            new.long_url,
+            new.weight
+        ),
+        (Some(0), None) if row.rotates() => info!("{} stopped {code} rotating", user.username()),
+        (Some(0) | None, None) => {}
+        (Some(count), None) => info!(
+            "{} set {code} to rotate between {count} destinations",
+            user.username()
+        ),
+    }
+    match geo_targets.as_ref().map(BTreeMap::len) {
+        Some(0) if row.geo_targeted() => info!("{} stopped geo-targeting {code}", user.username()),
+        Some(0) | None => {}
+        Some(count) => info!(
+            "{} set {code} to send {count} countries elsewhere",
+            user.username()
+        ),
+    }
+    if description.is_some() {
+        info!("{} changed the description of {code}", user.username());
+    }
+    Ok(updated)
+}
+
+/// Sends every visitor to the url one of the signed in user's links is rolling out, see
+/// [url_db::finish_rollout]. The link keeps its clicks but not how they were split, as when a
+/// link stops rotating.
+async fn api_commit_rollout(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Path(code): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let committed = match commit_rollout(&pool_and_prefs, &code, &user).await {
+        Ok(row) => geo_targets_of(&row, pool_and_prefs.pool())
+            .await
+            .map(|geo_targets| (row, geo_targets)),
+        Err(e) => Err(e),
+    };
+    let resp = match committed {
+        Ok((row, geo_targets)) => {
+            Json(ApiUrlBody::new(&row, pool_and_prefs.prefs()).with_geo_targets(&geo_targets))
+                .into_response()
+        }
+        Err(e) => e.into_response(),
+    };
+    with_cookies(resp, set_cookies)
+}
+
+async fn commit_rollout(
+    pool_and_prefs: &PoolAndPrefs,
+    code: &str,
+    user: &UserRow,
+) -> Result<UrlRow, ApiError> {
+    let not_rolling_out = || {
+        ApiError::new(
+            StatusCode::CONFLICT,
+            "not_rolling_out",
+            format!("{code} isn't rolling out a url"),
+        )
+    };
+    let row = managed_link(pool_and_prefs, code, user).await?;
+    if row.rollout_until().is_none() {
+        return Err(not_rolling_out());
+    }
+    let failed = |e: sqlx::Error| {
+        error!("Couldn't commit the rollout of {code}: {e}");
+        ApiError::unavailable("Couldn't commit the rollout")
+    };
+    let mut tx = pool_and_prefs.pool().begin().await.map_err(failed)?;
+    let old = url_db::destinations(row.id(), &mut *tx)
+        .await
+        .map_err(failed)?;
+    let committed = url_db::finish_rollout(row.id(), &mut tx)
+        .await
+        .map_err(|e| match e {
+            // Committed, finished or archived since it was looked up
+            sqlx::Error::RowNotFound => not_rolling_out(),
+            e => failed(e),
+        })?;
+    history::record(
+        row.id(),
+        *user.id(),
+        history::DESTINATIONS,
+        describe_destinations(old.iter().map(|d| (d.long_url.as_str(), d.weight))).as_deref(),
+        None,
+        &mut *tx,
+    )
+    .await
+    .map_err(failed)?;
+    history::record(
+        row.id(),
+        *user.id(),
+        history::DESTINATION,
+        Some(row.long_url()),
+        Some(committed.long_url()),
+        &mut *tx,
+    )
+    .await
+    .map_err(failed)?;
+    tx.commit().await.map_err(failed)?;
+    info!(
+        "{} committed the rollout of {} on {code}",
+        user.username(),
+        committed.long_url()
+    );
+    Ok(committed)
+}
+
+/// Who a link is handed to
+#[derive(Deserialize)]
+struct ApiTransfer {
+    username: String,
+}
+
+/// Hands one of the signed in user's links to another user, see [managed_link]. The code, its
+/// clicks and its history stay with the link, and only the new owner can manage it afterwards.
+async fn api_transfer_url(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Path(code): Path<String>,
+    headers: HeaderMap,
+    request: Result<Json<ApiTransfer>, JsonRejection>,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let resp = match transfer_link(&pool_and_prefs, &code, &user, request).await {
+        Ok(row) => Json(ApiUrlBody::new(&row, pool_and_prefs.prefs())).into_response(),
+        Err(e) => e.into_response(),
+    };
+    with_cookies(resp, set_cookies)
+}
+
+async fn transfer_link(
+    pool_and_prefs: &PoolAndPrefs,
+    code: &str,
+    user: &UserRow,
+    request: Result<Json<ApiTransfer>, JsonRejection>,
+) -> Result<UrlRow, ApiError> {
+    let Json(request) = request?;
+    let row = managed_link(pool_and_prefs, code, user).await?;
+    let pool = pool_and_prefs.pool();
+    let target = match user::retrieve_user_by_name(request.username.trim(), pool).await {
+        Ok(target) => target,
+        Err(sqlx::Error::RowNotFound) => {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "unknown_user",
+                format!("There is no user {}", request.username.trim()),
+            ))
+        }
+        Err(e) => {
+            error!("Couldn't look up who to transfer {code} to: {e}");
+            return Err(ApiError::unavailable("Couldn't look up the user"));
+        }
+    };
+    if row.created_by() == Some(*target.id()) {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "already_owner",
+            format!("{} already owns {code}", target.username()),
+        ));
+    }
+    let failed = |e: sqlx::Error| {
+        error!("Couldn't transfer {code}: {e}");
+        ApiError::unavailable("Couldn't transfer the link")
+    };
+    // Only the owner or, for anonymous links, an admin gets this far, so the old owner is the user
+    let previous = row.created_by().map(|_| user.username().as_str());
+    let mut tx = pool.begin().await.map_err(failed)?;
+    let updated = url_db::update_url_owner(row.id(), *target.id(), row.created_by(), &mut *tx)
+        .await
+        .map_err(|e| match e {
+            // Transferred, archived or deleted since it was looked up
+            sqlx::Error::RowNotFound => ApiError::new(
+                StatusCode::CONFLICT,
+                "transfer_conflict",
+                format!("{code} changed while it was being transferred, try again"),
+            ),
+            e => failed(e),
+        })?;
+    history::record(
+        row.id(),
+        *user.id(),
+        history::OWNER,
+        previous,
+        Some(target.username()),
+        &mut *tx,
+    )
+    .await
+    .map_err(failed)?;
+    tx.commit().await.map_err(failed)?;
+    info!(
+        "{} transferred {code} to {}",
+        user.username(),
+        target.username()
+    );
+    Ok(updated)
+}
+
+#[derive(Serialize)]
+struct ApiUrlHistory<'a> {
+    short_url: &'a str,
+    edits: Vec<history::EditView>,
+}
+
+/// The changes made to one of the signed in user's links, oldest first, see [managed_link]. htmx
+/// requests get the history panel instead of JSON.
+async fn api_url_history(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Path(code): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let resp = match url_history(&pool_and_prefs, &code, &user).await {
+        Ok((row, edits)) if headers.contains_key("HX-Request") => {
+            let panel = LinkHistoryTemplate {
+                short_url: row.short_url(),
+                edits: &edits,
+            };
+            match panel.render() {
+                Ok(html) => Html::from(html).into_response(),
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            }
+        }
+        Ok((row, edits)) => Json(ApiUrlHistory {
+            short_url: row.short_url(),
+            edits,
+        })
+        .into_response(),
+        Err(e) => e.into_response(),
+    };
+    with_cookies(resp, set_cookies)
+}
+
+async fn url_history(
+    pool_and_prefs: &PoolAndPrefs,
+    code: &str,
+    user: &UserRow,
+) -> Result<(UrlRow, Vec<history::EditView>), ApiError> {
+    let row = managed_link(pool_and_prefs, code, user).await?;
+    let edits = history::for_link(row.id(), pool_and_prefs.pool())
+        .await
+        .map_err(|e| {
+            error!("Couldn't load the history of {code}: {e}");
+            ApiError::unavailable("Couldn't load the history")
+        })?;
+    Ok((
+        row,
+        edits.into_iter().map(history::EditView::from).collect(),
+    ))
+}
+
+#[derive(Deserialize)]
+struct ApiNewTemplate {
+    alias: String,
+    destination: String,
+    #[serde(default)]
+    value_pattern: Option<String>,
+    #[serde(default)]
+    max_value_len: Option<i32>,
+}
+
+/// Creates a link template for the signed in user, e.g.
+/// `{"alias": "inv-{customer}", "destination": "https://billing.example.com/invoice?c={customer}"}`
+async fn api_create_template(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    headers: HeaderMap,
+    request: Result<Json<ApiNewTemplate>, JsonRejection>,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let resp = match create_template(&pool_and_prefs, &user, request).await {
+        Ok(template) => (
+            StatusCode::CREATED,
+            Json(link_template::TemplateView::from(&template)),
+        )
+            .into_response(),
+        Err(e) => e.into_response(),
+    };
+    with_cookies(resp, set_cookies)
+}
+
+async fn create_template(
+    pool_and_prefs: &PoolAndPrefs,
+    user: &UserRow,
+    request: Result<Json<ApiNewTemplate>, JsonRejection>,
+) -> Result<link_template::LinkTemplate, ApiError> {
+    let Json(request) = request?;
+    let template = link_template::NewTemplate::parse(
+        request.alias.trim(),
+        request.destination.trim(),
+        request.value_pattern.as_deref(),
+        request.max_value_len,
+    )
+    .map_err(|problem| ApiError::new(StatusCode::BAD_REQUEST, "invalid_template", problem))?;
+    let prefs = pool_and_prefs.prefs();
+    let sample = template.sample_destination();
+    if let Some(problem) =
+        destination_problem(&sample, prefs).or_else(|| self_link_problem(&sample, prefs))
+    {
+        return Err(problem);
+    }
+    let created = link_template::create_template(*user.id(), &template, pool_and_prefs.pool())
+        .await
+        .map_err(|e| match e {
+            link_template::CreateError::OverlapsTemplate(other) => ApiError::new(
+                StatusCode::CONFLICT,
+                "template_overlap",
+                format!("Some codes would match both this template and {other}"),
+            ),
+            link_template::CreateError::OverlapsCode(code) => ApiError::new(
+                StatusCode::CONFLICT,
+                "code_overlap",
+                format!("The existing code {code} matches this template"),
+            ),
+            link_template::CreateError::Database(e) => {
+                error!("Couldn't create a link template: {e}");
+                ApiError::unavailable("Couldn't create the template")
+            }
+        })?;
+    info!(
+        "{} created the link template {}",
+        user.username(),
+        created.alias()
+    );
+    Ok(created)
+}
+
+/// Lists the signed in user's link templates
+async fn api_list_templates(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    headers: HeaderMap,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let resp = match link_template::list_for_owner(*user.id(), pool_and_prefs.pool()).await {
+        Ok(templates) => {
+            let views: Vec<_> = templates
+                .iter()
+                .map(link_template::TemplateView::from)
+                .collect();
+            Json(views).into_response()
+        }
+        Err(e) => {
+            error!("Couldn't list link templates: {e}");
+            ApiError::unavailable("Couldn't list the templates").into_response()
+        }
+    };
+    with_cookies(resp, set_cookies)
+}
+
+/// Loads a link template for its owner, the only one who can see or change it
+async fn owned_template(
+    pool_and_prefs: &PoolAndPrefs,
+    id: i64,
+    user: &UserRow,
+) -> Result<link_template::LinkTemplate, ApiError> {
+    match link_template::find_by_id(id, pool_and_prefs.pool()).await {
+        Ok(Some(template)) if template.owner() == *user.id() => Ok(template),
+        Ok(Some(_)) => Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "forbidden",
+            "Only the owner of a template can manage it",
+        )),
+        Ok(None) => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            format!("There is no template {id}"),
+        )),
+        Err(e) => {
+            error!("Couldn't look up template {id}: {e}");
+            Err(ApiError::unavailable("Couldn't look up the template"))
+        }
+    }
+}
+
+/// Deletes one of the signed in user's link templates along with its click counts
+async fn api_delete_template(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let resp = match owned_template(&pool_and_prefs, id, &user).await {
+        Ok(template) => {
+            match link_template::delete_template(template.id(), pool_and_prefs.pool()).await {
+                Ok(()) => StatusCode::NO_CONTENT.into_response(),
+                Err(e) => {
+                    error!("Couldn't delete template {id}: {e}");
+                    ApiError::unavailable("Couldn't delete the template").into_response()
+                }
+            }
+        }
+        Err(e) => e.into_response(),
+    };
+    with_cookies(resp, set_cookies)
+}
+
+#[derive(Deserialize)]
+struct TemplateClicksQuery {
+    limit: Option<i64>,
+}
+
+/// Clicks on each value of one of the signed in user's templates, most clicked first
+async fn api_template_clicks(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Path(id): Path<i64>,
+    Query(query): Query<TemplateClicksQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+    let resp = match owned_template(&pool_and_prefs, id, &user).await {
+        Ok(template) => {
+            match link_template::value_clicks(template.id(), limit, pool_and_prefs.pool()).await {
+                Ok(clicks) => Json(clicks).into_response(),
+                Err(e) => {
+                    error!("Couldn't count clicks on template {id}: {e}");
+                    ApiError::unavailable("Couldn't count the clicks").into_response()
+                }
+            }
+        }
+        Err(e) => e.into_response(),
+    };
+    with_cookies(resp, set_cookies)
+}
+
+/// Describes a short link without visiting it: whether it exists, where it goes and what gate a
+/// visitor would hit. Never counts a click or uses up a single use link. Only the link's owner and
+/// admins see the full destination; everyone else gets its host.
+async fn api_preflight(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Path(code): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let row = match url_db::retrieve_url_obj(
+        &code,
+        pool_and_prefs.prefs().slug_case(),
+        pool_and_prefs.pool(),
+    )
+    .await
+    {
+        Ok(row) => Some(row),
+        Err(sqlx::Error::RowNotFound) => None,
+        Err(e) => {
+            error!("Couldn't look up {code} for a preflight: {e}");
+            return ApiError::unavailable("Couldn't look up the link").into_response();
+        }
+    };
+    let full_destination = pool_and_prefs.prefs().is_admin(user.username())
+        || row
+            .as_ref()
+            .is_some_and(|row| OwnerFilter::User(*user.id()).matches(row.created_by()));
+    // Where scheduled links 404 until they go live, others can't tell them from unused codes
+    let hidden = !full_destination
+        && pool_and_prefs.prefs().pending_links() == PendingLinks::NotFound
+        && row.as_ref().is_some_and(|row| row.is_pending(Utc::now()));
+    let row = row.filter(|_| !hidden);
+    let preflight = Preflight::describe(
+        &code,
+        row.as_ref(),
+        full_destination,
+        pool_and_prefs.prefs(),
+    );
+    with_cookies(Json(preflight).into_response(), set_cookies)
+}
+
+#[derive(Deserialize)]
+struct ClickStreamQuery {
+    format: Option<firehose::Format>,
+    /// Comma separated short codes to limit the stream to
+    codes: Option<String>,
+}
+
+/// Streams clicks as they are counted, as NDJSON or with `?format=sse` as server-sent events.
+/// Users get clicks on their own links and admins get every click.
+async fn api_click_stream(
+    State(pool_and_prefs): State<Arc<PoolAndPrefs>>,
+    Query(query): Query<ClickStreamQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let (user, set_cookies) =
+        match authenticate_request(State(pool_and_prefs.clone()), &headers).await {
+            AuthenticationResponse::Authenticated(user, set_cookies) => (user, set_cookies),
+            AuthenticationResponse::Error(err) => return err.into_response(),
+        };
+    let scope = firehose::Scope {
+        owner: (!pool_and_prefs.prefs().is_admin(user.username())).then_some(*user.id()),
+        codes: query.codes.map(|codes| {
+            codes
+                .split(',')
+                .map(str::trim)
+                .filter(|code| !code.is_empty())
+                .map(String::from)
+                .collect()
+        }),
+    };
+    let format = query.format.unwrap_or_default();
+    let resp = match pool_and_prefs.firehose.subscribe(*user.id(), scope, format) {
+        Some(stream) => Response::builder()
+            .header(CONTENT_TYPE, format.content_type())
+            .body(Body::from_stream(stream))
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR.into_response()),
+        None => ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "too_many_streams",
+            format!(
+                "At most {} click streams can be open at once",
+                firehose::MAX_STREAMS_PER_USER
+            ),
+        )
+        .into_response(),
+    };
+    with_cookies(resp, set_cookies)
+}
+
+/// Adds Set-Cookie headers, e.g. the ones returned with [AuthenticationResponse::Authenticated]
+fn with_cookies(mut resp: Response, cookies: Vec<String>) -> Response {
+    for cookie in cookies {
+        if let Ok(value) = HeaderValue::from_str(&cookie) {
+            resp.headers_mut().append(SET_COOKIE, value);
+        }
+    }
+    resp
+}
+
+fn content_response(contents: Vec<u8>, content_type: HeaderValue) -> Response {
+    let mut resp = Body::from(contents).into_response();
+    resp.headers_mut()
+        .insert(header::CONTENT_TYPE, content_type);
+    resp
+}
+
+async fn not_found_handler() -> Response {
+    let content = fs::read("html/404.html").unwrap();
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::from(content))
+        .expect("Failed to build 404 response")
+}
+
+/// Checks the auth cookie on a request and loads the user it belongs to. A token for a user that
+/// has since been deleted is treated like any other invalid token rather than an error.
+async fn authenticate_request(
+    State(pools_and_prefs): State<Arc<PoolAndPrefs>>,
+    headers: &HeaderMap,
+) -> AuthenticationResponse {
+    let prefs = pools_and_prefs.prefs();
+    let header_str = match headers.get(COOKIE) {
+        Some(val) => val.to_str().unwrap_or(""),
+        None => return AuthenticationResponse::Error(AuthError::NoCookieHeader),
+    };
+
+    let mut cookie_map: BTreeMap<&str, &str> = BTreeMap::new();
+    let cookie_vec: Vec<&str> = header_str.split_terminator(';').collect();
+    for pair in cookie_vec {
+        let (name, value) = match pair.trim().split_once('=') {
+            Some(val) => val,
+            None => return AuthenticationResponse::Error(AuthError::InvalidCookieHeader),
+        };
+        cookie_map.insert(name, value);
+    }
+
+    let legacy_allowed =
+        cookie::legacy_window_open(prefs.legacy_cookie_until(), time::SystemTime::now());
+    let (raw_token, legacy) = match cookie::find_token(&cookie_map, legacy_allowed) {
+        Some(FoundToken::Current(token)) => (token, false),
+        Some(FoundToken::Legacy(token)) => (token, true),
+        None => return AuthenticationResponse::Error(AuthError::InvalidCookieHeader),
+    };
+    let token = match Jwt::from_str_verified(raw_token, prefs.jwt_secret()) {
+        Ok(v) => v,
+        Err(_) => return AuthenticationResponse::Error(AuthError::InvalidToken),
+    };
+    let now = time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default();
+    let mut set_cookies = Vec::new();
+    match session::check(token.payload(), prefs.sessions(), now) {
+        SessionState::Active if legacy => set_cookies.push(cookie::session_cookie(raw_token)),
+        SessionState::Active => {}
+        SessionState::Refresh => {
+            let refreshed = Jwt::new(token.header().clone(), token.payload().refreshed(now));
+            set_cookies.push(cookie::session_cookie(
+                &refreshed.finalize(prefs.jwt_secret()),
+            ));
+        }
+        SessionState::Idle => return AuthenticationResponse::Error(AuthError::SessionIdle),
+        SessionState::Expired => return AuthenticationResponse::Error(AuthError::SessionExpired),
+    }
+    if legacy {
+        set_cookies.push(cookie::expired_cookie(LEGACY_AUTH_COOKIE_NAME));
+    }
+
+    let user_id = token.payload().sub();
+    if pools_and_prefs.deleted_users.contains(user_id) {
+        return AuthenticationResponse::Error(AuthError::UserNotFound);
+    }
+    match user::retrieve_user_by_id(user_id, pools_and_prefs.pool()).await {
+        Ok(user) => {
+            if legacy {
+                let migrated = pools_and_prefs.legacy_migrations.record();
+                debug!("Moved session of user {user_id} off the legacy cookie ({migrated} so far)");
+            }
+            AuthenticationResponse::Authenticated(user, set_cookies)
+        }
+        Err(sqlx::Error::RowNotFound) => {
+            debug!("Token presented for deleted user {user_id}");
+            pools_and_prefs.deleted_users.insert(user_id);
+            AuthenticationResponse::Error(AuthError::UserNotFound)
+        }
+        Err(err) => {
+            error!("Error loading user {user_id} for authentication: {err}");
+            AuthenticationResponse::Error(AuthError::DatabaseError)
+        }
+    }
+}
+
+async fn attempt_login(State(pool_and_prefs): State<Arc<PoolAndPrefs>>, body: Bytes) -> Response {
+    let (pool, prefs) = pool_and_prefs.both();
+    let current_time = time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let login_data: LoginPayload = match serde_html_form::from_bytes(&body) {
+        Ok(parsed) => parsed,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    let user: UserRow = match user::retrieve_user_by_name(login_data.username(), pool).await {
+        Ok(user) => user,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    if user::verify_pw(login_data.password(), &user).await {
+        let token = Jwt::new(
+            JwtHeader::new(SigAlgo::HS256, String::from("JWT")),
+            JwtPayload::new(
+                *user.id(),
+                user.username().to_string(),
+                user.email().to_string(),
+                current_time,
+            ),
+        );
+        let token_str = cookie::session_cookie(&token.finalize(prefs.jwt_secret()));
+        return Response::builder()
+            .header(SET_COOKIE, token_str)
+            .status(StatusCode::TEMPORARY_REDIRECT)
+            .header(LOCATION, "/loggedin.html")
+            .body(Body::empty())
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    } else {
+        return StatusCode::OK.into_response();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header::CACHE_CONTROL, HeaderValue};
+    use sqlx::postgres::PgPoolOptions;
+
+    use super::*;
+    use url_db::CodeRules;
+
+    async fn state_init() -> Arc<PoolAndPrefs> {
+        let prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        state_with(prefs).await
+    }
+
+    async fn state_with(prefs: Preferences) -> Arc<PoolAndPrefs> {
+        let conn_url = format!(
+            "postgres://{}:{}@172.17.0.2/{}",
+            prefs.db_user(),
+            prefs.db_pass(),
+            prefs.db_name(),
+        );
+        let recycler = Recycler::default();
+        let pool = recycler
+            .attach(PgPoolOptions::new())
+            .max_connections(prefs.db_pool_size())
+            .connect(&conn_url)
+            .await
+            .expect("Couldn't create connection pool. Are your credentials correct?");
+
+        sqlx::migrate!("./migrations")
+            .run(&pool)
+            .await
+            .unwrap_or_else(|_| debug!("Migration already exists, skipping"));
+
+        let theme = Theme::from_prefs(prefs.theme()).expect("Invalid theme configuration.");
+        let geo = GeoLookup::from_prefs(prefs.geo()).expect("Invalid geo configuration.");
+        Arc::new(PoolAndPrefs::new(
+            pool,
+            recycler,
+            prefs,
+            theme,
+            geo,
+            ResourceLimits::default(),
+        ))
+    }
+
+    fn auth_headers(user: &UserRow, secret: &str) -> HeaderMap {
+        auth_headers_named(user, secret, AUTH_COOKIE_NAME)
+    }
+
+    fn auth_headers_named(user: &UserRow, secret: &str, cookie_name: &str) -> HeaderMap {
+        let payload = JwtPayload::new(
+            *user.id(),
+            user.username().to_string(),
+            user.email().to_string(),
+            unix_now(),
+        );
+        auth_headers_with(payload, secret, cookie_name)
+    }
+
+    fn unix_now() -> u64 {
+        time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn auth_headers_with(payload: JwtPayload, secret: &str, cookie_name: &str) -> HeaderMap {
+        let token = Jwt::new(JwtHeader::defaults(), payload);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            COOKIE,
+            HeaderValue::from_str(&format!(
+                "other=1; {cookie_name}={}",
+                token.finalize(secret)
+            ))
+            .unwrap(),
+        );
+        headers
+    }
+
+    #[sqlx::test]
+    async fn sessions_end_for_idleness_or_age() {
+        const HOUR: u64 = 60 * 60;
+        let state = state_init().await;
+        let secret = state.prefs().jwt_secret();
+        let name = format!("session-{}", rand::random::<u32>());
+        let user = user::new_user(
+            name,
+            String::from("pw"),
+            String::from("email"),
+            state.pool(),
+        )
+        .await
+        .unwrap();
+        let now = unix_now();
+        let payload = |iat| {
+            JwtPayload::new(
+                *user.id(),
+                user.username().to_string(),
+                user.email().to_string(),
+                iat,
+            )
+        };
+
+        // Used a while ago, so the request is recorded with a new token from the same sign-in
+        let headers = auth_headers_with(payload(now - 2 * HOUR), secret, AUTH_COOKIE_NAME);
+        let AuthenticationResponse::Authenticated(_, set_cookies) =
+            authenticate_request(State(state.clone()), &headers).await
+        else {
+            panic!("Recently used session was rejected");
+        };
+        assert_eq!(set_cookies.len(), 1);
+        let token = set_cookies[0]
+            .strip_prefix(&format!("{AUTH_COOKIE_NAME}="))
+            .and_then(|rest| rest.split(';').next())
+            .unwrap();
+        let reissued = Jwt::from_str_verified(token, secret).unwrap();
+        assert!(reissued.payload().iat() >= now);
+        assert_eq!(reissued.payload().auth_time(), now - 2 * HOUR);
+
+        let idle = auth_headers_with(payload(now - 48 * HOUR), secret, AUTH_COOKIE_NAME);
+        let active_but_old = auth_headers_with(
+            payload(now - 31 * 24 * HOUR).refreshed(now),
+            secret,
+            AUTH_COOKIE_NAME,
+        );
+        for (headers, code) in [(idle, "session_idle"), (active_but_old, "session_expired")] {
+            let AuthenticationResponse::Error(err) =
+                authenticate_request(State(state.clone()), &headers).await
+            else {
+                panic!("{code} session was accepted");
+            };
+            let resp = err.into_response();
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(resp.headers().get_all(SET_COOKIE).iter().count(), 2);
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["error"]["code"], code);
+        }
+    }
+
+    #[sqlx::test]
+    async fn deleted_user_session_is_unauthorized() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let name = format!("deleted-{}", rand::random::<u32>());
+        let user = user::new_user(name, String::from("pw"), String::from("email"), pool)
+            .await
+            .unwrap();
+        let link = url_db::create_url(
+            "https://example.com",
+            Some(*user.id()),
+            pool,
+            CodeRules::of_len(8),
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let headers = auth_headers(&user, state.prefs().jwt_secret());
+
+        let before = authenticate_request(State(state.clone()), &headers).await;
+        assert!(matches!(before, AuthenticationResponse::Authenticated(..)));
+
+        user::delete_user_from_db(*user.id(), pool).await.unwrap();
+
+        let after = authenticate_request(State(state.clone()), &headers).await;
+        let AuthenticationResponse::Error(err) = after else {
+            panic!("Deleted user was authenticated");
+        };
+        assert!(matches!(err, AuthError::UserNotFound));
+        assert!(state.deleted_users.contains(*user.id()));
+
+        let resp = err.into_response();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let cookie = resp.headers().get(SET_COOKIE).unwrap().to_str().unwrap();
+        assert!(cookie.starts_with(&format!("{AUTH_COOKIE_NAME}=;")));
+        assert!(cookie.contains("Max-Age=0"));
+
+        let orphaned = url_db::retrieve_url_obj(link.short_url(), SlugCase::Sensitive, pool)
+            .await
+            .unwrap();
+        assert_eq!(orphaned.created_by(), None);
+    }
+
+    #[sqlx::test]
+    async fn forged_token_is_unauthorized() {
+        let state = state_init().await;
+        let user = UserRow::new(1, String::from("a"), String::new(), String::new());
+        let headers = auth_headers(&user, "not the real secret");
+
+        let resp = authenticate_request(State(state), &headers).await;
+        assert!(matches!(
+            resp,
+            AuthenticationResponse::Error(AuthError::InvalidToken)
+        ));
+    }
+
+    #[sqlx::test]
+    async fn interstitials_follow_the_appearance_cookie() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let countdown = UrlOptions {
+            interstitial_seconds: Some(3),
+            ..UrlOptions::default()
+        };
+        let single_use = UrlOptions {
+            single_use: true,
+            ..UrlOptions::default()
+        };
+        let mut codes = Vec::new();
+        for options in [&countdown, &single_use] {
+            let link = url_db::create_url(
+                "https://example.com",
+                None,
+                pool,
+                CodeRules::of_len(8),
+                options,
+            )
+            .await
+            .unwrap();
+            codes.push(link.clone_short_url());
+        }
+        // Use up the single use link so the rest get the consumed page
+        consume_short_url(
+            Path(codes[1].clone()),
+            State(&state),
+            &HeaderMap::new(),
+            &Method::GET,
+            None,
+        )
+        .await;
+
+        for (cookie, attribute, scheme) in [
+            (None, "auto", "light dark"),
+            (Some("appearance=light"), "light", "light"),
+            (Some("appearance=dark"), "dark", "dark"),
+        ] {
+            let mut headers = HeaderMap::new();
+            if let Some(cookie) = cookie {
+                headers.insert(COOKIE, HeaderValue::from_static(cookie));
+            }
+            for code in &codes {
+                let resp = consume_short_url(
+                    Path(code.clone()),
+                    State(&state),
+                    &headers,
+                    &Method::GET,
+                    None,
+                )
+                .await;
+                let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                let body = String::from_utf8(body.to_vec()).unwrap();
+                assert!(
+                    body.contains(&format!(
+                        r#"<html lang="en" data-appearance="{attribute}">"#
+                    )),
+                    "{body}"
+                );
+                assert!(body.contains(&format!(r#"<meta name="color-scheme" content="{scheme}">"#)));
+            }
+        }
+        let resp = consume_short_url(
+            Path(codes[1].clone()),
+            State(&state),
+            &HeaderMap::new(),
+            &Method::GET,
+            None,
+        )
+        .await;
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(r#"action="/appearance""#));
+        assert!(body.contains(&format!(r#"name="back" value="/{}""#, codes[1])));
+    }
+
+    #[sqlx::test]
+    async fn qr_codes_only_for_existing_links() {
+        use tower::ServiceExt;
+
+        let state = state_init().await;
+        let app = Router::new()
+            .route("/api/urls/:code/qr", get(api_url_qr))
+            .with_state(state.clone())
+            .layer(middleware::from_fn_with_state(
+                state.caching.clone(),
+                cache::apply,
+            ));
+        let fetch = |path: String| {
+            let req = axum::http::Request::get(path).body(Body::empty()).unwrap();
+            app.clone().oneshot(req)
+        };
+        let link = url_db::create_url(
+            "https://example.com/qr",
+            None,
+            state.pool(),
+            CodeRules::of_len(8),
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let path = format!("/api/urls/{}/qr", link.short_url());
+
+        let resp = fetch(path.clone()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "image/svg+xml");
+        assert_eq!(
+            resp.headers()[CACHE_CONTROL],
+            "public, max-age=31536000, immutable"
+        );
+        let resp = fetch(format!("{path}?format=png&size=2")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "image/png");
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(body.starts_with(b"\x89PNG"));
+
+        for (path, status) in [
+            (format!("{path}?size=0"), StatusCode::BAD_REQUEST),
+            (format!("{path}?size=1000"), StatusCode::BAD_REQUEST),
+            (format!("{path}?format=gif"), StatusCode::BAD_REQUEST),
+            (
+                format!("/api/urls/missing{}/qr", rand::random::<u32>()),
+                StatusCode::NOT_FOUND,
+            ),
+        ] {
+            let resp = fetch(path.clone()).await.unwrap();
+            assert_eq!(resp.status(), status, "{path}");
+            assert_eq!(resp.headers()[CACHE_CONTROL], "no-store", "{path}");
+        }
+    }
+
+    #[sqlx::test]
+    async fn routes_declare_their_cache_class() {
+        use tower::ServiceExt;
+
+        let state = state_init().await;
+        let app = Router::new()
+            .route("/", get(root))
+            .route("/theme.css", get(theme_css))
+            .route("/:extra", get(subdir_handler))
+            .with_state(state.clone())
+            .layer(middleware::from_fn_with_state(
+                state.caching.clone(),
+                cache::apply,
+            ));
+        let fetch = |path: String, etag: Option<HeaderValue>| {
+            let mut req = axum::http::Request::get(path);
+            if let Some(etag) = etag {
+                req = req.header(IF_NONE_MATCH, etag);
+            }
+            app.clone().oneshot(req.body(Body::empty()).unwrap())
+        };
+        let link = url_db::create_url(
+            "https://example.com",
+            None,
+            state.pool(),
+            CodeRules::of_len(8),
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        for (path, cache_control) in [
+            (String::from("/"), "public, max-age=300"),
+            (String::from("/404.html"), "public, max-age=300"),
+            (String::from("/missing.html"), "no-store"),
+            (format!("/{}", link.clone_short_url()), "no-store"),
+            (String::from("/theme.css"), "public, no-cache"),
+        ] {
+            let resp = fetch(path.clone(), None).await.unwrap();
+            assert_eq!(resp.headers()[CACHE_CONTROL], cache_control, "{path}");
+        }
+
+        let resp = fetch(String::from("/theme.css"), None).await.unwrap();
+        let first = resp.headers()[ETAG].clone();
+        let resp = fetch(String::from("/theme.css"), Some(first.clone()))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+        // Only admins can bump the generation
+        let name = format!("not-admin-{}", rand::random::<u32>());
+        let user = user::new_user(
+            name,
+            String::from("pw"),
+            String::from("email"),
+            state.pool(),
+        )
+        .await
+        .unwrap();
+        let headers = auth_headers(&user, state.prefs().jwt_secret());
+        let resp = admin_bump_cache(State(state.clone()), headers).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(state.caching.generation(), 0);
+
+        state.caching.bump();
+        let resp = fetch(String::from("/theme.css"), Some(first.clone()))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let second = resp.headers()[ETAG].clone();
+        assert_ne!(first, second);
+        let resp = fetch(String::from("/theme.css"), Some(second))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        user::delete_user_from_db(*user.id(), state.pool())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn reads_expiry_from_the_form() {
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let form = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<HashMap<_, _>>()
+        };
+        let expiry = |pairs: &[(&str, &str)]| expiry_from_form(&form(pairs), now);
+
+        assert_eq!(expiry(&[]), Ok(None));
+        assert_eq!(expiry(&[("expires_in", " ")]), Ok(None));
+        assert_eq!(
+            expiry(&[("expires_in", "90m")]),
+            Ok(Some(now + chrono::TimeDelta::minutes(90)))
+        );
+        assert_eq!(
+            expiry(&[("expires_in", "7d")]),
+            Ok(Some(now + chrono::TimeDelta::days(7)))
+        );
+        let at = DateTime::from_timestamp(1_700_003_600, 0).unwrap();
+        assert_eq!(
+            expiry(&[("expires_at", "2023-11-14T23:13:20Z")]),
+            Ok(Some(at))
+        );
+        assert_eq!(
+            expiry(&[("expires_at", "2023-11-15T01:13:20+02:00")]),
+            Ok(Some(at))
+        );
+        assert_eq!(
+            expiry(&[("expires_at", "2023-11-14T23:13")]),
+            Ok(Some(DateTime::from_timestamp(1_700_003_580, 0).unwrap()))
+        );
+
+        for bad in [
+            &[("expires_in", "0m")][..],
+            &[("expires_in", "-1d")],
+            &[("expires_at", "2023-11-14T22:13:20Z")],
+            &[("expires_in", "7")],
+            &[("expires_in", "7ü")],
+            &[("expires_in", "1w")],
+            &[("expires_in", "999999999999d")],
+            &[("expires_at", "next tuesday")],
+            &[("expires_in", "1h"), ("expires_at", "2030-01-01T00:00")],
+        ] {
+            assert!(expiry(bad).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn anonymous_links_expire_after_the_ttl() {
+        let mut prefs = Preferences::load_config("./config.toml").unwrap();
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let later = |days| now + chrono::TimeDelta::days(days);
+        let link = |owner, expires_at, active_from| NewLink {
+            owner,
+            expires_at,
+            active_from,
+            ..NewLink::default()
+        };
+
+        assert_eq!(anonymous_expiry(&prefs, &link(None, None, None), now), None);
+        prefs.set_anonymous_link_ttl_days(30);
+        assert_eq!(
+            anonymous_expiry(&prefs, &link(None, None, None), now),
+            Some(later(30))
+        );
+        assert_eq!(
+            anonymous_expiry(&prefs, &link(None, Some(later(1)), None), now),
+            Some(later(1))
+        );
+        assert_eq!(
+            anonymous_expiry(&prefs, &link(None, Some(later(90)), None), now),
+            Some(later(30))
+        );
+        assert_eq!(
+            anonymous_expiry(&prefs, &link(None, None, Some(later(10))), now),
+            Some(later(40))
+        );
+        assert_eq!(
+            anonymous_expiry(&prefs, &link(Some(7), None, None), now),
+            None
+        );
+        assert_eq!(
+            anonymous_expiry(&prefs, &link(Some(7), Some(later(90)), None), now),
+            Some(later(90))
+        );
+    }
+
+    #[sqlx::test]
+    async fn expired_links_stop_redirecting() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let create = |expires_at| {
+            let options = UrlOptions {
+                expires_at: Some(expires_at),
+                ..UrlOptions::default()
+            };
+            async move {
+                url_db::create_url(
+                    "https://example.com/expiry",
+                    None,
+                    pool,
+                    CodeRules::of_len(8),
+                    &options,
+                )
+                .await
+                .unwrap()
+            }
+        };
+        let shared: &PoolAndPrefs = &state;
+        let visit = |code: String| async move {
+            consume_short_url(
+                Path(code),
+                State(shared),
+                &HeaderMap::new(),
+                &Method::GET,
+                None,
+            )
+            .await
+        };
+
+        let expiring = create(Utc::now() + chrono::TimeDelta::hours(1)).await;
+        let resp = visit(expiring.clone_short_url()).await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        assert_eq!(resp.headers()[LOCATION], "https://example.com/expiry");
+
+        let expired = create(Utc::now() - chrono::TimeDelta::hours(1)).await;
+        let resp = visit(expired.clone_short_url()).await;
+        assert_eq!(resp.status(), StatusCode::GONE);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(String::from_utf8(body.to_vec())
+            .unwrap()
+            .contains("This link expired on"));
+        // Still there for its owner, and the visit wasn't counted
+        let row = url_db::retrieve_url_obj(expired.short_url(), SlugCase::Sensitive, pool)
+            .await
+            .unwrap();
+        assert_eq!(row.clicks(), 0);
+        assert!(row.is_expired(Utc::now()));
+
+        // Single use links can't be claimed once expired either
+        let options = UrlOptions {
+            single_use: true,
+            expires_at: Some(Utc::now() - chrono::TimeDelta::hours(1)),
+            ..UrlOptions::default()
+        };
+        let single_use = url_db::create_url(
+            "https://example.com/expiry",
+            None,
+            pool,
+            CodeRules::of_len(8),
+            &options,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            url_db::claim_single_use(single_use.id(), &ClickSource::default(), pool)
+                .await
+                .unwrap(),
+            None
+        );
+        let resp = visit(single_use.clone_short_url()).await;
+        assert_eq!(resp.status(), StatusCode::GONE);
+    }
+
+    #[sqlx::test]
+    async fn rejects_expiry_in_the_past() {
+        let state = state_init().await;
+        let resp = post_new_url(
+            State(state.clone()),
+            None,
+            HeaderMap::new(),
+            Bytes::from("url=https%3A%2F%2Fexample.com&expires_at=2000-01-01T00%3A00"),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // Links with an expiry are never reused for a later request without one
+        let page = rand::random::<u32>();
+        let create = |form: String| {
+            let state = state.clone();
+            async move {
+                let resp =
+                    post_new_url(State(state), None, HeaderMap::new(), Bytes::from(form)).await;
+                assert_eq!(resp.status(), StatusCode::OK);
+                let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                String::from_utf8(body.to_vec()).unwrap()
+            }
+        };
+        let expiring = create(format!(
+            "url=https%3A%2F%2Fexample.com%2F{page}&expires_in=1d"
+        ))
+        .await;
+        let plain = create(format!("url=https%3A%2F%2Fexample.com%2F{page}")).await;
+        assert_ne!(expiring, plain);
+    }
+
+    #[test]
+    fn schedules_at_most_five_years_ahead() {
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let later = |days| now + chrono::TimeDelta::days(days);
+
+        assert_eq!(activation_problem(later(-1), None, now), None);
+        assert_eq!(activation_problem(later(365 * 5), None, now), None);
+        assert!(activation_problem(later(365 * 5 + 7), None, now).is_some());
+        assert_eq!(activation_problem(later(1), Some(later(2)), now), None);
+        assert!(activation_problem(later(2), Some(later(2)), now).is_some());
+        assert!(activation_problem(later(3), Some(later(2)), now).is_some());
+    }
+
+    #[sqlx::test]
+    async fn scheduled_links_wait_for_their_time() {
+        let mut prefs = Preferences::load_config("./config.toml").unwrap();
+        let state = state_init().await;
+        prefs.set_pending_links(PendingLinks::NotFound);
+        let hiding = state_with(prefs).await;
+        let pool = state.pool();
+        let create = |active_from| {
+            let state = state.clone();
+            async move {
+                api_create_url(
+                    State(state),
+                    None,
+                    HeaderMap::new(),
+                    Ok(Json(ApiNewUrl {
+                        url: Some(String::from("https://example.com/launch")),
+                        alias: None,
+                        tags: None,
+                        description: None,
+                        destinations: Vec::new(),
+                        active_from: Some(active_from),
+                        single_use: false,
+                    })),
+                )
+                .await
+            }
+        };
+        let visit = |state: &Arc<PoolAndPrefs>, code: &str| {
+            let (state, code) = (state.clone(), code.to_string());
+            async move {
+                consume_short_url(
+                    Path(code),
+                    State(state.as_ref()),
+                    &HeaderMap::new(),
+                    &Method::GET,
+                    None,
+                )
+                .await
+            }
+        };
+
+        let resp = create(Utc::now() + chrono::TimeDelta::days(365 * 6)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = create(Utc::now() + chrono::TimeDelta::days(1)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let created: serde_json::Value = serde_json::from_slice(
+            &axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+        assert!(created["active_from"].is_string());
+        let code = created["short_url"].as_str().unwrap().to_string();
+
+        let resp = visit(&state, &code).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(String::from_utf8(body.to_vec())
+            .unwrap()
+            .contains("It starts working on"));
+        let resp = visit(&hiding, &code).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(!String::from_utf8(body.to_vec())
+            .unwrap()
+            .contains("It starts working on"));
+        let row = url_db::retrieve_url_obj(&code, SlugCase::Sensitive, pool)
+            .await
+            .unwrap();
+        assert_eq!(row.clicks(), 0);
+
+        // Goes live by the database's clock
+        sqlx::query!(
+            "UPDATE urls SET active_from = now() - interval '1 minute' WHERE id = $1",
+            row.id()
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+        for state in [&state, &hiding] {
+            let resp = visit(state, &code).await;
+            assert!(resp.status().is_redirection());
+            assert_eq!(resp.headers()[LOCATION], "https://example.com/launch");
+        }
+        url_db::delete_url(row.id(), pool).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn reloads_count_once() {
+        let mut prefs = Preferences::load_config("./config.toml").unwrap();
+        let state = state_init().await;
+        prefs.set_trust_proxy(true);
+        let proxied = state_with(prefs).await;
+        let pool = state.pool();
+        let link = url_db::create_url(
+            "https://example.com/reloaded",
+            None,
+            pool,
+            CodeRules::of_len(8),
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let visit = |state: &Arc<PoolAndPrefs>, from: &str, forwarded: Option<&'static str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(forwarded) = forwarded {
+                headers.insert("x-forwarded-for", HeaderValue::from_static(forwarded));
+            }
+            subdir_handler(
+                Path(link.clone_short_url()),
+                State(state.clone()),
+                Some(ConnectInfo(from.parse().unwrap())),
+                Method::GET,
+                headers,
+            )
+        };
+        let clicks = || async {
+            url_db::retrieve_url_obj(link.short_url(), SlugCase::Sensitive, pool)
+                .await
+                .unwrap()
+                .clicks()
+        };
+
+        for _ in 0..3 {
+            let resp = visit(&state, "203.0.113.7:50000", None).await;
+            assert!(resp.status().is_redirection());
+        }
+        assert_eq!(clicks().await, 1);
+        // Another address, and the header means nothing unless the proxy is trusted
+        visit(&state, "203.0.113.8:50000", Some("198.51.100.1")).await;
+        visit(&state, "203.0.113.8:50000", Some("198.51.100.2")).await;
+        assert_eq!(clicks().await, 2);
+
+        // Behind the proxy, only the entry it added says who the visitor is
+        visit(&proxied, "10.0.0.1:50000", Some("198.51.100.1")).await;
+        visit(&proxied, "10.0.0.1:50000", Some("192.0.2.9, 198.51.100.1")).await;
+        visit(&proxied, "10.0.0.1:50000", Some("198.51.100.2")).await;
+        assert_eq!(clicks().await, 4);
+        url_db::delete_url(link.id(), pool).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn head_requests_are_not_clicks() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let link = url_db::create_url(
+            "https://example.com/checked",
+            None,
+            pool,
+            CodeRules::of_len(8),
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let visit = |method| {
+            subdir_handler(
+                Path(link.clone_short_url()),
+                State(state.clone()),
+                None,
+                method,
+                HeaderMap::new(),
+            )
+        };
+
+        let head = visit(Method::HEAD).await;
+        let get = visit(Method::GET).await;
+        assert!(head.status().is_redirection());
+        assert_eq!(head.status(), get.status());
+        assert_eq!(head.headers()[LOCATION], "https://example.com/checked");
+        assert_eq!(head.headers()[LOCATION], get.headers()[LOCATION]);
+        let row = url_db::retrieve_url_obj(link.short_url(), SlugCase::Sensitive, pool)
+            .await
+            .unwrap();
+        assert_eq!(row.clicks(), 1);
+        url_db::delete_url(link.id(), pool).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn bot_visits_are_counted_apart() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let link = url_db::create_url(
+            "https://example.com/unfurled",
+            None,
+            pool,
+            CodeRules::of_len(8),
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let visit = |agent| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::USER_AGENT, HeaderValue::from_static(agent));
+            subdir_handler(
+                Path(link.clone_short_url()),
+                State(state.clone()),
+                None,
+                Method::GET,
+                headers,
+            )
+        };
+
+        let bot = visit("Slackbot-LinkExpanding 1.0 (+https://api.slack.com/robots)").await;
+        assert!(bot.status().is_redirection());
+        assert_eq!(bot.headers()[LOCATION], "https://example.com/unfurled");
+        visit("Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0").await;
+        let row = url_db::retrieve_url_obj(link.short_url(), SlugCase::Sensitive, pool)
+            .await
+            .unwrap();
+        assert_eq!(row.clicks(), 1);
+        assert_eq!(row.bot_clicks(), 1);
+        let devices = url_db::clicks_by_device(link.id(), pool).await.unwrap();
+        assert_eq!(devices["bot"], 1);
+        assert_eq!(devices["desktop"], 1);
+        url_db::delete_url(link.id(), pool).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn bots_are_turned_away_once_the_click_limit_is_used_up() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let options = UrlOptions {
+            max_clicks: Some(1),
+            ..UrlOptions::default()
+        };
+        let link = url_db::create_url(
+            "https://example.com/limited",
+            None,
+            pool,
+            CodeRules::of_len(8),
+            &options,
+        )
+        .await
+        .unwrap();
+        // Looked up before a human visit uses the last click
+        let mut stale = url_db::retrieve_url_obj(link.short_url(), SlugCase::Sensitive, pool)
+            .await
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::USER_AGENT,
+            HeaderValue::from_static("Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Firefox/131.0"),
+        );
+        let human = subdir_handler(
+            Path(link.clone_short_url()),
+            State(state.clone()),
+            None,
+            Method::GET,
+            headers,
+        )
+        .await;
+        assert!(human.status().is_redirection());
+
+        let bot = ClickSource {
+            device: Some(Device::Bot),
+            ..ClickSource::from_headers(&HeaderMap::new())
+        };
+        let refused = count_click(
+            Some(&mut stale),
+            &bot,
+            None,
+            &state,
+            Appearance::from_headers(&HeaderMap::new()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(refused.status(), StatusCode::GONE);
+        let row = url_db::retrieve_url_obj(link.short_url(), SlugCase::Sensitive, pool)
+            .await
+            .unwrap();
+        assert_eq!(row.clicks(), 1);
+        assert_eq!(row.bot_clicks(), 0);
+        url_db::delete_url(link.id(), pool).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn click_limit_holds_under_concurrent_visits() {
+        let state = state_init().await;
+        let options = UrlOptions {
+            max_clicks: Some(3),
+            ..UrlOptions::default()
+        };
+        let link = url_db::create_url(
+            "https://example.com/limited",
+            None,
+            state.pool(),
+            CodeRules::of_len(8),
+            &options,
+        )
+        .await
+        .unwrap();
+
+        let visits = (0..10).map(|_| {
+            let state = state.clone();
+            let code = link.clone_short_url();
+            tokio::spawn(async move {
+                consume_short_url(
+                    Path(code),
+                    State(&state),
+                    &HeaderMap::new(),
+                    &Method::GET,
+                    None,
+                )
+                .await
+                .status()
+            })
+        });
+        let mut statuses = Vec::new();
+        for visit in visits.collect::<Vec<_>>() {
+            statuses.push(visit.await.unwrap());
+        }
+        assert_eq!(
+            statuses
+                .iter()
+                .filter(|status| **status == StatusCode::FOUND)
+                .count(),
+            3,
+            "{statuses:?}"
+        );
+        assert!(statuses
+            .iter()
+            .all(|status| [StatusCode::FOUND, StatusCode::GONE].contains(status)));
+        let row = url_db::retrieve_url_obj(link.short_url(), SlugCase::Sensitive, state.pool())
+            .await
+            .unwrap();
+        assert_eq!(row.clicks(), 3);
+        assert!(row.is_exhausted());
+    }
+
+    #[sqlx::test]
+    async fn shortening_again_reuses_the_link() {
+        let state = state_init().await;
+        let page = rand::random::<u32>();
+        let create = |state: Arc<PoolAndPrefs>, url: String| async move {
+            let link = NewLink {
+                url: &url,
+                ..NewLink::default()
+            };
+            create_link(&state, link).await.unwrap()
+        };
+        let first = create(state.clone(), format!("https://example.com/{page}")).await;
+        url_db::incr_url_clicks(&mut first.clone(), &ClickSource::default(), state.pool())
+            .await
+            .unwrap();
+        // Only differs in ways normalizing undoes
+        let again = create(state.clone(), format!("  HTTPS://Example.COM/{page}/ ")).await;
+        assert_eq!(again.id(), first.id());
+        assert_eq!(again.short_url(), first.short_url());
+        assert_eq!(again.clicks(), 1);
+
+        let mut prefs = state.prefs().clone();
+        prefs.set_reuse_links(false);
+        let separate = state_with(prefs).await;
+        let another = create(separate, format!("https://example.com/{page}")).await;
+        assert_ne!(another.id(), first.id());
+        assert_eq!(another.clicks(), 0);
+    }
+
+    #[sqlx::test]
+    async fn link_creation_is_rate_limited_per_address() {
+        let mut prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        prefs.set_rate_limits(toml::from_str("anonymous_per_minute = 2").unwrap());
+        prefs.set_trust_proxy(true);
+        let state = state_with(prefs).await;
+        let client = Some(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))));
+        let from = |address: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-forwarded-for", HeaderValue::from_static(address));
+            headers
+        };
+        let form = |headers| {
+            post_new_url(
+                State(state.clone()),
+                client,
+                headers,
+                Bytes::from(format!(
+                    "url=https%3A%2F%2Fexample.com%2Flimited-{}",
+                    rand::random::<u32>()
+                )),
+            )
+        };
+
+        assert_eq!(form(from("203.0.113.7")).await.status(), StatusCode::OK);
+        let request = Ok(Json(
+            serde_json::from_str::<ApiNewUrl>(r#"{"url": "https://example.com/limited"}"#).unwrap(),
+        ));
+        let resp = api_create_url(State(state.clone()), client, from("203.0.113.7"), request).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = form(from("203.0.113.7")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers()[api_error::ERROR_CODE], "rate_limited");
+        let retry_after: u64 = resp.headers()[header::RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=30).contains(&retry_after), "{retry_after}");
+        // Another address has an allowance of its own
+        assert_eq!(form(from("203.0.113.8")).await.status(), StatusCode::OK);
+
+        // Signed in, the form, the API and bulk requests take from the user's allowance instead
+        // of the address's
+        let name = format!("limited-{}", rand::random::<u32>());
+        let user = user::new_user(
+            name,
+            String::from("pw"),
+            String::from("email"),
+            state.pool(),
+        )
+        .await
+        .unwrap();
+        let signed_in = || {
+            let mut headers = auth_headers(&user, state.prefs().jwt_secret());
+            headers.extend(from("203.0.113.7"));
+            headers
+        };
+        let resp = form(signed_in()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let request = Ok(Json(
+            serde_json::from_str::<ApiNewUrl>(r#"{"url": "https://example.com/limited/own"}"#)
+                .unwrap(),
+        ));
+        let resp = api_create_url(State(state.clone()), client, signed_in(), request).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let owned = url_db::retrieve_urls_by_owner(OwnerFilter::User(*user.id()), state.pool())
+            .await
+            .unwrap();
+        assert_eq!(owned.len(), 2);
+        assert!(owned
+            .iter()
+            .any(|row| row.long_url() == "https://example.com/limited/own"));
+        let request = serde_json::from_str::<ApiNewUrl>(r#"{"url": "https://example.com/bulk"}"#);
+        let resp = api_bulk_create_urls(
+            State(state.clone()),
+            Query(BulkQuery {
+                on_error: OnError::Abort,
+            }),
+            client,
+            signed_in(),
+            Ok(Json(vec![request.unwrap()])),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let owned = url_db::retrieve_urls_by_owner(OwnerFilter::User(*user.id()), state.pool())
+            .await
+            .unwrap();
+        for row in owned {
+            url_db::delete_url(row.id(), state.pool()).await.unwrap();
+        }
+        user::delete_user_from_db(*user.id(), state.pool())
+            .await
+            .unwrap();
+    }
+
+    #[sqlx::test]
+    async fn only_allowed_schemes_are_shortened() {
+        let shorten = |state: Arc<PoolAndPrefs>, url: &str| {
+            post_new_url(
+                State(state),
+                None,
+                HeaderMap::new(),
+                Bytes::from(format!(
+                    "url={}",
+                    url.replace('%', "%25")
+                        .replace('/', "%2F")
+                        .replace(':', "%3A")
+                        .replace(',', "%2C")
+                )),
+            )
+        };
+        let mut prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        let state = state_with(prefs.clone()).await;
+        for url in [
+            "javascript:alert(1)",
+            "JAVASCRIPT://example.com/%0Aalert(1)",
+            "data:text/html,hi",
+            "//evil.com/page",
+            "ftp://files.example.com/pub",
+        ] {
+            let resp = shorten(state.clone(), url).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{url}");
+            assert_eq!(resp.headers()[api_error::ERROR_CODE], "invalid_url");
+        }
+        let resp = shorten(state, "HTTPS://example.com/schemed").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        prefs.set_allowed_schemes(vec![String::from("https"), String::from("ftp")]);
+        let state = state_with(prefs).await;
+        let resp = shorten(state.clone(), "ftp://files.example.com/pub").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        // Without a scheme is http, which is now off the list
+        let resp = shorten(state, "example.com/plain").await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[sqlx::test]
+    async fn links_back_to_the_site_are_refused() {
+        let shorten = |state: Arc<PoolAndPrefs>, url: &str| {
+            post_new_url(
+                State(state),
+                None,
+                HeaderMap::new(),
+                Bytes::from(format!(
+                    "url={}",
+                    url.replace('/', "%2F").replace(':', "%3A")
+                )),
+            )
+        };
+        let mut prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        prefs.set_other_domains(vec![String::from("https://short.link")]);
+        let state = state_with(prefs.clone()).await;
+        for url in [
+            "https://short.link/abc",
+            "HTTPS://SHORT.LINK./abc",
+            "http://www.short.link:8080/abc",
+            "short.link/abc",
+        ] {
+            let resp = shorten(state.clone(), url).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{url}");
+            assert_eq!(resp.headers()[api_error::ERROR_CODE], "self_link");
+        }
+        let resp = shorten(state, "https://sub.short.link/abc").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        prefs.set_allow_self_links(true);
+        let allowed = state_with(prefs).await;
+        let resp = shorten(allowed, "https://short.link/abc").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[sqlx::test]
+    async fn blocked_domains_are_refused() {
+        let mut prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        prefs.set_blocked_domains(vec![String::from("evil.com")]);
+        let state = state_with(prefs).await;
+        let pool = state.pool();
+        for url in ["https://evil.com/a", "a.EVIL.com", "https://b.a.evil.com./"] {
+            let resp = post_new_url(
+                State(state.clone()),
+                None,
+                HeaderMap::new(),
+                Bytes::from(format!(
+                    "url={}",
+                    url.replace('/', "%2F").replace(':', "%3A")
+                )),
+            )
+            .await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN, "{url}");
+            assert_eq!(resp.headers()[api_error::ERROR_CODE], "blocked_domain");
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(body, "Links to evil.com aren't allowed on this instance");
+        }
+        let resp = post_new_url(
+            State(state.clone()),
+            None,
+            HeaderMap::new(),
+            Bytes::from("url=https%3A%2F%2Fnotevil.com"),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let owner = user::new_user(
+            format!("blocked-{}", rand::random::<u32>()),
+            String::from("pw"),
+            String::from("email"),
+            pool,
+        )
+        .await
+        .unwrap();
+        let link = url_db::create_url(
+            "https://example.com/fine",
+            Some(*owner.id()),
+            pool,
+            CodeRules::of_len(8),
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let request = Ok(Json(ApiEditUrl {
+            url: Some(String::from("https://www.evil.com")),
+            description: None,
+            destinations: None,
+            geo_targets: None,
+            rollout_percent: None,
+        }));
+        let resp = api_edit_url(
+            State(state.clone()),
+            Path(link.short_url().clone()),
+            auth_headers(&owner, state.prefs().jwt_secret()),
+            request,
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let row = url_db::retrieve_url_obj(link.short_url(), SlugCase::Sensitive, pool)
+            .await
+            .unwrap();
+        assert_eq!(row.long_url(), "https://example.com/fine");
+
+        url_db::delete_url(link.id(), pool).await.unwrap();
+        user::delete_user_from_db(*owner.id(), pool).await.unwrap();
+    }
+
+    /// Lists every destination containing "phish"
+    struct PhishList;
+
+    impl safety::UrlSafetyChecker for PhishList {
+        fn check<'a>(
+            &'a self,
+            url: &'a str,
+        ) -> futures_util::future::BoxFuture<'a, safety::SafetyVerdict> {
+            Box::pin(async move {
+                if url.contains("phish") {
+                    safety::SafetyVerdict::Unsafe(String::from("SOCIAL_ENGINEERING"))
+                } else {
+                    safety::SafetyVerdict::Safe
+                }
+            })
+        }
+    }
+
+    #[sqlx::test]
+    async fn unsafe_destinations_are_refused() {
+        let mut state = Arc::into_inner(state_init().await).unwrap();
+        state.safety = SafetyCheck::with(PhishList, safety::FailurePolicy::Open);
+        let state = Arc::new(state);
+        let page = rand::random::<u32>();
+        let resp = post_new_url(
+            State(state.clone()),
+            None,
+            HeaderMap::new(),
+            Bytes::from(format!("url=https%3A%2F%2Fphish.example.com%2F{page}")),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(resp.headers()[api_error::ERROR_CODE], "unsafe_url");
+        let stored = url_db::find_duplicate(
+            &format!("https://phish.example.com/{page}"),
+            OwnerFilter::Anonymous,
+            state.pool(),
+        )
+        .await
+        .unwrap();
+        assert!(stored.is_none());
+        let resp = post_new_url(
+            State(state.clone()),
+            None,
+            HeaderMap::new(),
+            Bytes::from(format!("url=https%3A%2F%2Fexample.com%2F{page}")),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[sqlx::test]
+    async fn international_destinations_are_stored_ascii() {
+        let state = state_init().await;
+        let page = rand::random::<u32>();
+        let url = format!("https://München.de/straße/{page}?q=größe");
+        let link = NewLink {
+            url: &url,
+            ..NewLink::default()
+        };
+        let row = create_link(&state, link).await.unwrap();
+        assert_eq!(
+            row.long_url(),
+            &format!("https://xn--mnchen-3ya.de/stra%C3%9Fe/{page}?q=gr%C3%B6%C3%9Fe")
+        );
+        assert!(HeaderValue::from_str(row.long_url()).is_ok());
+    }
+
+    #[sqlx::test]
+    async fn long_destinations_are_refused() {
+        let mut prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        prefs.set_max_long_url_len(40);
+        let state = state_with(prefs).await;
+        for (url, status) in [
+            ("https://example.com/12345678901234567890", StatusCode::OK),
+            (
+                "https://example.com/123456789012345678901",
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ),
+            // Short enough as typed, too long once percent-encoded
+            (
+                "https://example.com/ääääääääääää",
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ),
+        ] {
+            let link = NewLink {
+                url,
+                ..NewLink::default()
+            };
+            let result = create_link(&state, link).await;
+            let got = result.map_or_else(|e| e.into_response().status(), |_| StatusCode::OK);
+            assert_eq!(got, status, "{url}");
+        }
+    }
+
+    #[sqlx::test]
+    async fn bulk_shortening_reports_each_link() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let user = user::new_user(
+            format!("bulk-{}", rand::random::<u32>()),
+            String::from("pw"),
+            String::from("email"),
+            pool,
+        )
+        .await
+        .unwrap();
+        let headers = auth_headers(&user, state.prefs().jwt_secret());
+        let alias = format!("bulk{}", rand::random::<u32>());
+        let requested = |url: &str, alias: Option<&String>| ApiNewUrl {
+            url: Some(url.to_string()),
+            alias: alias.cloned(),
+            tags: None,
+            description: None,
+            destinations: Vec::new(),
+            active_from: None,
+            single_use: false,
+        };
+        let batch = || {
+            vec![
+                requested("https://example.com/bulk/a", None),
+                requested("not a url", None),
+                requested("https://example.com/bulk/b", Some(&alias)),
+                // Taken by the one before it
+                requested("https://example.com/bulk/c", Some(&alias)),
+            ]
+        };
+        let bulk = |on_error, headers, requested| {
+            api_bulk_create_urls(
+                State(state.clone()),
+                Query(BulkQuery { on_error }),
+                None,
+                headers,
+                Ok(Json(requested)),
+            )
+        };
+        let results = |resp: Response| async move {
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            body["results"].as_array().unwrap().clone()
+        };
+        let statuses = |results: &[serde_json::Value]| -> Vec<String> {
+            results
+                .iter()
+                .map(|result| result["status"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        let resp = bulk(OnError::Abort, headers.clone(), batch()).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let aborted = results(resp).await;
+        assert_eq!(
+            statuses(&aborted),
+            ["rolled_back", "failed", "skipped", "skipped"]
+        );
+        assert_eq!(aborted[1]["error"]["code"], "invalid_url");
+        let undone = aborted[0]["link"]["short_url"].as_str().unwrap();
+        assert!(matches!(
+            url_db::retrieve_url(undone, SlugCase::Sensitive, pool).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+
+        let resp = bulk(OnError::Continue, headers.clone(), batch()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let kept = results(resp).await;
+        assert_eq!(statuses(&kept), ["created", "failed", "created", "failed"]);
+        assert_eq!(kept[2]["link"]["short_url"], alias.as_str());
+        assert_eq!(kept[3]["index"], 3);
+        assert_eq!(kept[3]["error"]["code"], "alias_taken");
+        let owned = url_db::retrieve_urls_by_owner(OwnerFilter::User(*user.id()), pool)
+            .await
+            .unwrap();
+        assert_eq!(owned.len(), 2);
+
+        let resp = bulk(OnError::Continue, HeaderMap::new(), batch()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let too_many = (0..=MAX_BULK_URLS)
+            .map(|i| requested(&format!("https://example.com/{i}"), None))
+            .collect();
+        let resp = bulk(OnError::Continue, headers, too_many).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        for row in owned {
+            url_db::delete_url(row.id(), pool).await.unwrap();
+        }
+        user::delete_user_from_db(*user.id(), pool).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn links_can_be_listed_by_tag() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let user = user::new_user(
+            format!("tagger-{}", rand::random::<u32>()),
+            String::from("pw"),
+            String::from("email"),
+            pool,
+        )
+        .await
+        .unwrap();
+        let headers = auth_headers(&user, state.prefs().jwt_secret());
+        let tagged = |url: &str, tags: &str| ApiNewUrl {
+            url: Some(url.to_string()),
+            alias: None,
+            tags: Some(tags.to_string()),
+            description: None,
+            destinations: Vec::new(),
+            active_from: None,
+            single_use: false,
+        };
+        let resp = api_bulk_create_urls(
+            State(state.clone()),
+            Query(BulkQuery {
+                on_error: OnError::Abort,
+            }),
+            None,
+            headers.clone(),
+            Ok(Json(vec![
+                tagged("https://example.com/tags/a", " Newsletter, q3-campaign"),
+                tagged("https://example.com/tags/b", "newsletter"),
+                tagged("https://example.com/tags/c", ""),
+            ])),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let bad = ApiNewUrl {
+            url: Some(String::from("https://example.com/tags/d")),
+            alias: None,
+            tags: Some(String::from("two words")),
+            description: None,
+            destinations: Vec::new(),
+            active_from: None,
+            single_use: false,
+        };
+        let resp =
+            api_create_url(State(state.clone()), None, HeaderMap::new(), Ok(Json(bad))).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let list = |tag: Option<&str>| {
+            let query = UrlPageQuery {
+                page: None,
+                per_page: None,
+                tag: tag.map(String::from),
+                archived: false,
+            };
+            let headers = headers.clone();
+            let state = state.clone();
+            async move {
+                let resp = api_list_urls(State(state), Query(query), headers).await;
+                assert_eq!(resp.status(), StatusCode::OK);
+                let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+            }
+        };
+        let newsletter = list(Some(" NEWSLETTER ")).await;
+        assert_eq!(newsletter["total"], 2);
+        let items = newsletter["items"].as_array().unwrap();
+        assert_eq!(items[0]["long_url"], "https://example.com/tags/b");
+        assert_eq!(
+            items[1]["tags"],
+            serde_json::json!(["newsletter", "q3-campaign"])
+        );
+        assert_eq!(list(Some("q3-campaign")).await["total"], 1);
+        assert_eq!(list(Some("other")).await["total"], 0);
+        let all = list(None).await;
+        assert_eq!(all["total"], 3);
+        assert!(all["items"][0].get("tags").is_none());
+
+        let owned = url_db::retrieve_urls_by_owner(OwnerFilter::User(*user.id()), pool)
+            .await
+            .unwrap();
+        let ids: Vec<i64> = owned.iter().map(UrlRow::id).collect();
+        for id in &ids {
+            url_db::delete_url(*id, pool).await.unwrap();
+        }
+        assert!(tags::for_links(&ids, pool).await.unwrap().is_empty());
+        user::delete_user_from_db(*user.id(), pool).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn importing_checks_every_row() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let suffix = rand::random::<u32>();
+        let username = format!("importer-{suffix}");
+        let user = user::new_user(
+            username.clone(),
+            String::from("pw"),
+            String::from("email"),
+            pool,
+        )
+        .await
+        .unwrap();
+        let file = format!(
+            "longurl,shorturl,created_by\n\
+            https://example.com/import/{suffix},imp{suffix},{username}\n\
+            https://example.com/import/{suffix}/b,,{username}\n\
+            https://example.com/import/{suffix}/c,imp{suffix},{username}\n\
+            https://example.com/import/{suffix}/b,,{username}\n\
+            https://example.com/import/{suffix}/d,api\n\
+            not a url\n\
+            https://example.com/import/{suffix}/e,bad/alias\n\
+            https://example.com/import/{suffix}/f,,nobody-{suffix}\n"
+        );
+        let rows = link_import::read_rows(&file).unwrap();
+        let (rows, prefs) = (&rows, state.prefs());
+        let import = |dry_run| async move {
+            let mut conn = pool.acquire().await.unwrap();
+            let mut importer = link_import::Importer::new(prefs, dry_run);
+            let mut outcomes = Vec::new();
+            for row in rows {
+                outcomes.push(importer.import(row, &mut conn).await.unwrap());
+            }
+            outcomes
+        };
+        let kinds = |outcomes: &[link_import::Outcome]| -> Vec<&str> {
+            outcomes
+                .iter()
+                .map(|outcome| match outcome {
+                    link_import::Outcome::Imported => "imported",
+                    link_import::Outcome::Skipped(_) => "skipped",
+                    link_import::Outcome::Invalid(_) => "invalid",
+                })
+                .collect()
+        };
+        let expected = [
+            "imported", "imported", "skipped", "skipped", "skipped", "invalid", "invalid",
+            "invalid",
+        ];
+
+        let dry = import(true).await;
+        assert_eq!(kinds(&dry), expected);
+        let owner = OwnerFilter::User(*user.id());
+        let owned = url_db::retrieve_urls_by_owner(owner, pool).await.unwrap();
+        assert!(owned.is_empty());
+
+        let imported = import(false).await;
+        assert_eq!(kinds(&imported), expected);
+        let owned = url_db::retrieve_urls_by_owner(owner, pool).await.unwrap();
+        assert_eq!(owned.len(), 2);
+        assert!(owned
+            .iter()
+            .any(|row| row.short_url() == &format!("imp{suffix}")));
+        // Everything imported the first time is skipped the second
+        let again = import(false).await;
+        assert_eq!(&kinds(&again)[..2], ["skipped", "skipped"]);
+
+        for row in owned {
+            url_db::delete_url(row.id(), pool).await.unwrap();
+        }
+        user::delete_user_from_db(*user.id(), pool).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn rollup_rebuilds_recompute_daily_clicks() {
+        let suffix = rand::random::<u32>();
+        let admin_name = format!("rebuilder-{suffix}");
+        let mut prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        prefs.set_admins(vec![admin_name.clone()]);
+        prefs.set_features(toml::from_str("conversions = true").unwrap());
+        let state = state_with(prefs).await;
+        let pool = state.pool();
+        let admin = user::new_user(admin_name, String::from("pw"), String::from("email"), pool)
+            .await
+            .unwrap();
+        let link = url_db::create_url_with_alias(
+            &format!("rollup{suffix}"),
+            "https://example.com/rollup",
+            Some(*admin.id()),
+            pool,
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let today = url_db::database_now(pool).await.unwrap().date_naive();
+        let days: Vec<NaiveDate> = (0..3)
+            .rev()
+            .map(|ago| today - chrono::Days::new(ago))
+            .collect();
+        // One click before the rebuilt days, so every click of those is still recorded, then 3
+        // yesterday and 2 today
+        for (ago, clicks) in [(3, 1), (1, 3), (0, 2)] {
+            for _ in 0..clicks {
+                sqlx::query!(
+                    "INSERT INTO url_click_visitors (url_id, visitor, clicked_at)
+                    VALUES ($1, 'visitor',
+                        (date_trunc('day', now() AT TIME ZONE 'UTC') AT TIME ZONE 'UTC')
+                            - make_interval(days => $2))",
+                    link.id(),
+                    ago,
+                )
+                .execute(pool)
+                .await
+                .unwrap();
+            }
+        }
+        // Counts that don't match the clicks, as left by a purge or a misclassification
+        for (day, count) in days.iter().zip([5, 999, 7]) {
+            sqlx::query!(
+                "INSERT INTO url_clicks_daily (url_id, day, count) VALUES ($1, $2, $3)",
+                link.id(),
+                day,
+                count,
+            )
+            .execute(pool)
+            .await
+            .unwrap();
+        }
+        let counts = || async {
+            url_db::clicks_by_day(link.id(), days[0], days[2], pool)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|day| (day.count, day.rebuilding))
+                .collect::<Vec<_>>()
+        };
+
+        let headers = auth_headers(&admin, state.prefs().jwt_secret());
+        let request = |from, to| {
+            Ok(Json(RebuildRequest {
+                from,
+                to,
+                url: Some(format!("rollup{suffix}")),
+                user: None,
+            }))
+        };
+        let long_ago = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap();
+        let resp = admin_rebuild_rollups(
+            State(state.clone()),
+            Query(DryRunQuery { dry_run: false }),
+            headers.clone(),
+            request(long_ago, days[2]),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let rebuild = rollup::start(
+            rollup::Scope::Link(link.id()),
+            days[0],
+            days[2],
+            "test",
+            &dry_run::Apply,
+            pool,
+        )
+        .await
+        .unwrap()
+        .rebuild
+        .unwrap();
+        assert_eq!(
+            rollup::next_day(rebuild.id, pool).await.unwrap(),
+            rollup::Progress::More
+        );
+        // Interrupted after the first day, the rest are marked as not rebuilt yet
+        assert_eq!(counts().await, [(0, false), (999, true), (7, true)]);
+        // Overlapping days can't be rebuilt meanwhile, whatever links they cover
+        let overlapping = rollup::start(
+            rollup::Scope::All,
+            days[2],
+            days[2],
+            "test",
+            &dry_run::Apply,
+            pool,
+        )
+        .await;
+        assert!(matches!(
+            overlapping,
+            Err(rollup::StartError::Overlaps(id)) if id == rebuild.id
+        ));
+        let resp = admin_rebuild_rollups(
+            State(state.clone()),
+            Query(DryRunQuery { dry_run: false }),
+            headers.clone(),
+            request(days[1], days[1]),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        rollup::resume(pool.clone()).await;
+        let from_scratch: Vec<(i64, bool)> = sqlx::query_scalar!(
+            r#"SELECT count(v.id) AS "count!" FROM generate_series($2::date, $3::date, interval '1 day') AS d
+            LEFT JOIN url_click_visitors v ON v.url_id = $1
+                AND (v.clicked_at AT TIME ZONE 'UTC')::date = d::date
+            GROUP BY d ORDER BY d"#,
+            link.id(),
+            days[0],
+            days[2],
+        )
+        .fetch_all(pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|count| (count, false))
+        .collect();
+        assert_eq!(counts().await, from_scratch);
+        assert_eq!(counts().await, [(0, false), (3, false), (2, false)]);
+        let finished = rollup::rebuild(rebuild.id, pool).await.unwrap().unwrap();
+        assert!(finished.finished_at.is_some());
+
+        // Started through the API, it runs in the background
+        let resp = admin_rebuild_rollups(
+            State(state.clone()),
+            Query(DryRunQuery { dry_run: false }),
+            headers.clone(),
+            request(days[1], days[2]),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let started: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        // Deleting the link while the rebuild is on a day of it would deadlock with it
+        let id = started["id"].as_i64().unwrap();
+        while rollup::rebuild(id, pool)
+            .await
+            .unwrap()
+            .is_some_and(|rebuild| rebuild.finished_at.is_none())
+        {
+            tokio::time::sleep(time::Duration::from_millis(20)).await;
+        }
+
+        url_db::delete_url(link.id(), pool).await.unwrap();
+        user::delete_user_from_db(*admin.id(), pool).await.unwrap();
+    }
+    #[sqlx::test]
+    async fn dry_runs_report_what_real_runs_change_and_change_nothing() {
+        let suffix = rand::random::<u32>();
+        let admin_name = format!("dry-runner-{suffix}");
+        let mut prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        prefs.set_admins(vec![admin_name.clone()]);
+        prefs.set_features(toml::from_str("conversions = true").unwrap());
+        let state = state_with(prefs).await;
+        let pool = state.pool();
+        let admin = user::new_user(
+            admin_name.clone(),
+            String::from("pw"),
+            String::from("email"),
+            pool,
+        )
+        .await
+        .unwrap();
+        let mut links = Vec::new();
+        for name in ["purged", "archived", "old"] {
+            let link = url_db::create_url_with_alias(
+                &format!("{name}{suffix}"),
+                "https://example.com/dry",
+                Some(*admin.id()),
+                pool,
+                &UrlOptions::default(),
+            )
+            .await
+            .unwrap();
+            links.push(link);
+        }
+        let [purged, archived, old] = &links[..] else {
+            unreachable!()
+        };
+        url_db::archive_url(old.id(), pool).await.unwrap();
+        // Archived long before any link another test archives
+        sqlx::query!(
+            "UPDATE urls SET deleted_at = '2000-01-01T00:00:00Z' WHERE id = $1",
+            old.id()
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+        let input: AnnouncementInput = serde_json::from_value(serde_json::json!({
+            "message": format!("Dry run {suffix}"),
+            "severity": "info",
+        }))
+        .unwrap();
+        let announcement = announcement::create(&input, pool).await.unwrap().id();
+        // A click before the rebuilt days so they are covered, then daily clicks that are off on
+        // the first two days
+        let today = url_db::database_now(pool).await.unwrap().date_naive();
+        let days: Vec<NaiveDate> = (0..3)
+            .rev()
+            .map(|ago| today - chrono::Days::new(ago))
+            .collect();
+        for (ago, clicks) in [(3, 1), (1, 2), (0, 1)] {
+            for _ in 0..clicks {
+                sqlx::query!(
+                    "INSERT INTO url_click_visitors (url_id, visitor, clicked_at)
+                    VALUES ($1, 'visitor',
+                        (date_trunc('day', now() AT TIME ZONE 'UTC') AT TIME ZONE 'UTC')
+                            - make_interval(days => $2))",
+                    purged.id(),
+                    ago,
+                )
+                .execute(pool)
+                .await
+                .unwrap();
+            }
+        }
+        for (day, count) in days.iter().zip([4, 5, 1]) {
+            sqlx::query!(
+                "INSERT INTO url_clicks_daily (url_id, day, count) VALUES ($1, $2, $3)",
+                purged.id(),
+                day,
+                count,
+            )
+            .execute(pool)
+            .await
+            .unwrap();
+        }
+
+        // The row count and a checksum of the rows of this test in every table these touch
+        let owner = *admin.id();
+        let snapshot = || async {
+            let mut tables = Vec::new();
+            for rows in [
+                format!("SELECT u.* FROM urls u WHERE created_by = {owner}"),
+                format!(
+                    "SELECT d.* FROM url_clicks_daily d JOIN urls u ON u.id = d.url_id
+                    WHERE u.created_by = {owner}"
+                ),
+                format!("SELECT r.* FROM rollup_rebuilds r WHERE started_by = '{admin_name}'"),
+                format!("SELECT a.* FROM announcements a WHERE id = {announcement}"),
+            ] {
+                let table: (i64, String) = sqlx::query_as(&format!(
+                    "SELECT count(*), coalesce(md5(string_agg(t::text, ',' ORDER BY t::text)), '')
+                    FROM ({rows}) t"
+                ))
+                .fetch_one(pool)
+                .await
+                .unwrap();
+                tables.push(table);
+            }
+            tables
+        };
+        let daily = || async {
+            let mut counts = Vec::new();
+            for day in &days {
+                let count = sqlx::query_scalar!(
+                    "SELECT count FROM url_clicks_daily WHERE url_id = $1 AND day = $2",
+                    purged.id(),
+                    day,
+                )
+                .fetch_optional(pool)
+                .await
+                .unwrap();
+                counts.push(count.unwrap_or(0));
+            }
+            counts
+        };
+        let body = |resp: Response| async move {
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+        let headers = auth_headers(&admin, state.prefs().jwt_secret());
+        let delete = |code: &str, purge: bool, dry_run: bool| {
+            api_delete_url(
+                State(state.clone()),
+                Path(code.to_string()),
+                Query(DeleteQuery { purge, dry_run }),
+                headers.clone(),
+            )
+        };
+        let delete_announcement = |dry_run: bool| {
+            admin_delete_announcement(
+                State(state.clone()),
+                Path(announcement),
+                Query(DryRunQuery { dry_run }),
+                headers.clone(),
+            )
+        };
+        let older_than_days = (today - NaiveDate::from_ymd_opt(2000, 1, 2).unwrap()).num_days();
+        let purge_archived = |dry_run: bool| {
+            admin_purge_archived(
+                State(state.clone()),
+                Query(PurgeArchivedQuery {
+                    older_than_days: Some(older_than_days as u32),
+                    dry_run,
+                }),
+                headers.clone(),
+            )
+        };
+        let (state, headers, days) = (&state, &headers, &days);
+        let rebuild = |dry_run: bool| async move {
+            loop {
+                let resp = admin_rebuild_rollups(
+                    State(state.clone()),
+                    Query(DryRunQuery { dry_run }),
+                    headers.clone(),
+                    Ok(Json(RebuildRequest {
+                        from: days[0],
+                        to: days[2],
+                        url: Some(purged.short_url().clone()),
+                        user: None,
+                    })),
+                )
+                .await;
+                // Until the rebuilds of other tests, of any links, are done with these days
+                if resp.status() != StatusCode::CONFLICT {
+                    return resp;
+                }
+                tokio::time::sleep(time::Duration::from_millis(50)).await;
+            }
+        };
+
+        let before = snapshot().await;
+        let mut reported = Vec::new();
+        for resp in [
+            delete(purged.short_url(), true, true).await,
+            delete(archived.short_url(), false, true).await,
+            delete_announcement(true).await,
+            purge_archived(true).await,
+            rebuild(true).await,
+        ] {
+            assert_eq!(resp.status(), StatusCode::OK);
+            let report = body(resp).await;
+            assert_eq!(report["dry_run"], true);
+            assert_eq!(
+                report["count"],
+                report["affected"].as_array().unwrap().len()
+            );
+            reported.push(report["affected"].clone());
+        }
+        assert_eq!(snapshot().await, before);
+
+        // What the dry runs picked is what the real runs then change
+        let daily_before = daily().await;
+        let resp = rebuild(false).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        rollup::run(body(resp).await["id"].as_i64().unwrap(), pool.clone()).await;
+        let daily_after = daily().await;
+        let changed: Vec<serde_json::Value> = days
+            .iter()
+            .zip(daily_before.iter().zip(&daily_after))
+            .filter(|(_, (before, after))| before != after)
+            .map(|(day, (before, after))| {
+                serde_json::json!({
+                    "url_id": purged.id(),
+                    "day": day,
+                    "recorded": before,
+                    "rebuilt": after,
+                })
+            })
+            .collect();
+        assert_eq!(changed.len(), 2);
+        assert_eq!(reported[4], serde_json::Value::from(changed));
+
+        let resp = purge_archived(false).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let report = body(resp).await;
+        assert_eq!(report["dry_run"], false);
+        assert_eq!(report["affected"], reported[3]);
+        assert!(reported[3]
+            .as_array()
+            .unwrap()
+            .contains(&serde_json::Value::from(old.short_url().as_str())));
+        for code in reported[3].as_array().unwrap() {
+            assert!(
+                !url_db::is_code_taken(code.as_str().unwrap(), SlugCase::Sensitive, pool)
+                    .await
+                    .unwrap()
+            );
+        }
+
+        assert_eq!(
+            delete(purged.short_url(), true, false).await.status(),
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(reported[0], serde_json::json!([purged.short_url()]));
+        assert!(
+            !url_db::is_code_taken(purged.short_url(), SlugCase::Sensitive, pool)
+                .await
+                .unwrap()
+        );
+
+        assert_eq!(
+            delete(archived.short_url(), false, false).await.status(),
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(reported[1], serde_json::json!([archived.short_url()]));
+        let found =
+            url_db::retrieve_archived_url_obj(archived.short_url(), SlugCase::Sensitive, pool)
+                .await
+                .unwrap();
+        assert!(found.deleted_at().is_some());
+
+        assert_eq!(
+            delete_announcement(false).await.status(),
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(reported[2], serde_json::json!([announcement]));
+        assert_eq!(
+            delete_announcement(false).await.status(),
+            StatusCode::NOT_FOUND
+        );
+
+        url_db::delete_url(archived.id(), pool).await.unwrap();
+        user::delete_user_from_db(*admin.id(), pool).await.unwrap();
+    }
+
+    /// The clicks of link `id` as read, and as they are split between urls and its shards
+    async fn clicks_of(id: i64, pool: &PgPool) -> (i64, i64, Option<i64>) {
+        let row = url_db::retrieve_urls_by_owner(OwnerFilter::Any, pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|row| row.id() == id)
+            .unwrap();
+        let split = sqlx::query!(
+            r#"SELECT clicks, (SELECT SUM(clicks)::BIGINT FROM url_click_shards WHERE url_id = $1)
+                AS shards
+            FROM urls WHERE id = $1"#,
+            id
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        (row.clicks(), split.clicks, split.shards)
+    }
+
+    #[sqlx::test]
+    async fn hot_links_lose_no_clicks_while_folded() {
+        use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+        const TASKS: u64 = 40;
+        const CLICKS_PER_TASK: u64 = 25;
+        let state = state_init().await;
+        let pool = state.pool().clone();
+        let link = url_db::create_url_with_alias(
+            &format!("hot{}", rand::random::<u32>()),
+            "https://example.com/hot",
+            None,
+            &pool,
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let id = link.id();
+        assert_eq!(
+            hot_links::flag(id, 8, &pool).await.unwrap(),
+            hot_links::Flagged::Now
+        );
+        assert_eq!(
+            hot_links::flag(id, 8, &pool).await.unwrap(),
+            hot_links::Flagged::Already
+        );
+
+        // Clicks counted so far, and whether they are all in
+        let counted = Arc::new(AtomicU64::new(0));
+        let finished = Arc::new(AtomicBool::new(false));
+        let clickers: Vec<_> = (0..TASKS)
+            .map(|_| {
+                let (mut row, pool, counted) = (link.clone(), pool.clone(), counted.clone());
+                tokio::spawn(async move {
+                    for _ in 0..CLICKS_PER_TASK {
+                        let source = ClickSource::default();
+                        assert!(url_db::incr_url_clicks(&mut row, &source, &pool)
+                            .await
+                            .unwrap());
+                        counted.fetch_add(1, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect();
+        let folder = {
+            let (pool, finished) = (pool.clone(), finished.clone());
+            tokio::spawn(async move {
+                let mut folded = 0;
+                while !finished.load(Ordering::SeqCst) {
+                    folded += hot_links::fold(id, &pool).await.unwrap();
+                }
+                folded
+            })
+        };
+        // Mid-fold, reads never miss a click that was counted nor see one twice
+        let reader = {
+            let (pool, counted, finished) = (pool.clone(), counted.clone(), finished.clone());
+            tokio::spawn(async move {
+                let mut last = 0;
+                while !finished.load(Ordering::SeqCst) {
+                    let at_least = counted.load(Ordering::SeqCst) as i64;
+                    let (read, _, _) = clicks_of(id, &pool).await;
+                    assert!(read >= at_least.max(last), "{read} clicks read");
+                    assert!(
+                        read <= (TASKS * CLICKS_PER_TASK) as i64,
+                        "{read} clicks read"
+                    );
+                    last = read;
+                }
+            })
+        };
+        for clicker in clickers {
+            clicker.await.unwrap();
+        }
+        finished.store(true, Ordering::SeqCst);
+        reader.await.unwrap();
+        let folded = folder.await.unwrap();
+
+        let total = (TASKS * CLICKS_PER_TASK) as i64;
+        let (read, base, shards) = clicks_of(id, &pool).await;
+        assert_eq!(read, total);
+        assert_eq!(base + shards.unwrap(), total);
+        assert_eq!(base, folded);
+        hot_links::fold(id, &pool).await.unwrap();
+        assert_eq!(clicks_of(id, &pool).await, (total, total, Some(0)));
+
+        // Unflagged, its clicks go to urls again
+        hot_links::unflag(id, &pool).await.unwrap();
+        let mut row = link.clone();
+        url_db::incr_url_clicks(&mut row, &ClickSource::default(), &pool)
+            .await
+            .unwrap();
+        assert_eq!(clicks_of(id, &pool).await, (total + 1, total + 1, None));
+        url_db::delete_url(id, &pool).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn busy_links_are_made_hot_unless_limited() {
+        let suffix = rand::random::<u32>();
+        let admin_name = format!("hot-admin-{suffix}");
+        let mut prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        prefs.set_admins(vec![admin_name.clone()]);
+        let mut hot_prefs = hot_links::HotLinkPrefs::default();
+        hot_prefs.set_auto_flag_per_second(Some(5));
+        prefs.set_hot_links(hot_prefs);
+        let state = state_with(prefs).await;
+        let pool = state.pool();
+        let admin = user::new_user(admin_name, String::from("pw"), String::from("email"), pool)
+            .await
+            .unwrap();
+        let (busy_code, limited_code) = (format!("busy{suffix}"), format!("limited{suffix}"));
+        let busy = url_db::create_url_with_alias(
+            &busy_code,
+            "https://example.com/busy",
+            None,
+            pool,
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let limited = url_db::create_url_with_alias(
+            &limited_code,
+            "https://example.com/limited",
+            None,
+            pool,
+            &UrlOptions {
+                max_clicks: Some(1000),
+                ..UrlOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+        let visit = |code: &str| {
+            subdir_handler(
+                Path(code.to_string()),
+                State(state.clone()),
+                None,
+                Method::GET,
+                HeaderMap::new(),
+            )
+        };
+        let shards = |id: i64| {
+            sqlx::query_scalar!(
+                r#"SELECT count(*) AS "count!" FROM url_click_shards WHERE url_id = $1"#,
+                id
+            )
+            .fetch_one(pool)
+        };
+
+        for _ in 0..50 {
+            assert!(visit(&busy_code).await.status().is_redirection());
+            assert!(visit(&limited_code).await.status().is_redirection());
+        }
+        // Both were clicked often enough, but the limit is checked against a single count
+        let flagged = hot_links::flag_busy(
+            &state.click_rates,
+            5,
+            time::Duration::from_secs(10),
+            4,
+            pool,
+        )
+        .await
+        .unwrap();
+        assert_eq!(flagged, [busy.id()]);
+        assert_eq!(shards(busy.id()).await.unwrap(), 4);
+        assert_eq!(shards(limited.id()).await.unwrap(), 0);
+        for _ in 0..10 {
+            assert!(visit(&busy_code).await.status().is_redirection());
+        }
+        assert_eq!(clicks_of(busy.id(), pool).await.0, 60);
+        assert!(hot_links::flag_busy(
+            &state.click_rates,
+            5,
+            time::Duration::from_secs(10),
+            4,
+            pool
+        )
+        .await
+        .unwrap()
+        .is_empty());
+
+        // Given a limit once hot, its clicks are counted in urls, against the shards too
+        sqlx::query!("UPDATE urls SET max_clicks = 62 WHERE id = $1", busy.id())
+            .execute(pool)
+            .await
+            .unwrap();
+        let mut row = busy.clone();
+        for counted in [true, true, false] {
+            assert_eq!(
+                url_db::incr_url_clicks(&mut row, &ClickSource::default(), pool)
+                    .await
+                    .unwrap(),
+                counted
+            );
+        }
+        assert_eq!(clicks_of(busy.id(), pool).await.0, 62);
+
+        // By hand
+        let headers = auth_headers(&admin, state.prefs().jwt_secret());
+        let flag = |code: &str| {
+            admin_flag_hot(
+                State(state.clone()),
+                Path(code.to_string()),
+                headers.clone(),
+            )
+        };
+        let unflag = |code: &str| {
+            admin_unflag_hot(
+                State(state.clone()),
+                Path(code.to_string()),
+                headers.clone(),
+            )
+        };
+        assert_eq!(flag(&limited_code).await.status(), StatusCode::CONFLICT);
+        assert_eq!(unflag(&busy_code).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(clicks_of(busy.id(), pool).await, (62, 62, None));
+        sqlx::query!("UPDATE urls SET max_clicks = NULL WHERE id = $1", busy.id())
+            .execute(pool)
+            .await
+            .unwrap();
+        assert_eq!(flag(&busy_code).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(shards(busy.id()).await.unwrap(), 16);
+        assert_eq!(
+            flag(&format!("missing{suffix}")).await.status(),
+            StatusCode::NOT_FOUND
+        );
+        let reader = user::new_user(
+            format!("hot-reader-{suffix}"),
+            String::from("pw"),
+            String::from("email"),
+            pool,
+        )
+        .await
+        .unwrap();
+        let resp = admin_unflag_hot(
+            State(state.clone()),
+            Path(busy_code.clone()),
+            auth_headers(&reader, state.prefs().jwt_secret()),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        for link in [&busy, &limited] {
+            url_db::delete_url(link.id(), pool).await.unwrap();
+        }
+        for user in [&admin, &reader] {
+            user::delete_user_from_db(*user.id(), pool).await.unwrap();
+        }
+    }
+
+    /// A signed in user to import for, with the cookie they upload with
+    async fn importer(state: &Arc<PoolAndPrefs>, name: &str) -> (UserRow, HeaderMap) {
+        let user = user::new_user(
+            format!("{name}-{}", rand::random::<u32>()),
+            String::from("pw"),
+            String::from("email"),
+            state.pool(),
+        )
+        .await
+        .unwrap();
+        let mut headers = auth_headers(&user, state.prefs().jwt_secret());
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/csv"));
+        (user, headers)
+    }
+
+    async fn delete_importer(user: &UserRow, pool: &PgPool) {
+        for row in url_db::retrieve_urls_by_owner(OwnerFilter::User(*user.id()), pool)
+            .await
+            .unwrap()
+        {
+            url_db::delete_url(row.id(), pool).await.unwrap();
+        }
+        user::delete_user_from_db(*user.id(), pool).await.unwrap();
+    }
+
+    async fn import_status(
+        state: &Arc<PoolAndPrefs>,
+        id: i64,
+        headers: &HeaderMap,
+    ) -> import_job::Job {
+        import_job::job(id, user_id_of(state, headers).await, state.pool())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn user_id_of(state: &Arc<PoolAndPrefs>, headers: &HeaderMap) -> i64 {
+        match authenticate_request(State(state.clone()), headers).await {
+            AuthenticationResponse::Authenticated(user, _) => *user.id(),
+            AuthenticationResponse::Error(_) => panic!("not signed in"),
+        }
+    }
+
+    #[sqlx::test]
+    async fn small_imports_are_answered_with_the_report() {
+        let mut prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        let mut imports = prefs.imports().clone();
+        imports.set_max_bytes(1024);
+        prefs.set_imports(imports);
+        let state = state_with(prefs).await;
+        let (user, headers) = importer(&state, "small-importer").await;
+        let suffix = rand::random::<u32>();
+        let upload = |headers: HeaderMap, body: String| {
+            api_import_urls(State(state.clone()), None, headers, Body::from(body))
+        };
+
+        let csv = format!(
+            "longurl,shorturl,created_by\n\
+            https://example.com/import/{suffix}\n\
+            not a url\n\
+            https://example.com/import/{suffix}/theirs,,someone-else\n"
+        );
+        let resp = upload(headers.clone(), csv.clone()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(report["imported"], 1);
+        assert_eq!(report["invalid"], 2);
+        assert_eq!(report["errors"][0]["line"], 3);
+        assert_eq!(report["errors"][1]["line"], 4);
+        let owned = url_db::retrieve_urls_by_owner(OwnerFilter::User(*user.id()), state.pool())
+            .await
+            .unwrap();
+        assert_eq!(owned.len(), 1);
+
+        let mut json = headers.clone();
+        json.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        let resp = upload(json, csv).await;
+        assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let resp = upload(headers.clone(), "x".repeat(1025)).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        delete_importer(&user, state.pool()).await;
+    }
+
+    #[sqlx::test]
+    async fn large_imports_go_on_without_the_upload() {
+        let mut prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        let mut imports = prefs.imports().clone();
+        imports.set_sync_max_bytes(0);
+        prefs.set_imports(imports);
+        let state = state_with(prefs).await;
+        let (user, headers) = importer(&state, "large-importer").await;
+        let suffix = rand::random::<u32>();
+        let csv: String = (0..5)
+            .map(|i| format!("https://example.com/import/{suffix}/{i}\n"))
+            .chain([String::from("not a url\n")])
+            .collect();
+
+        let resp =
+            api_import_urls(State(state.clone()), None, headers.clone(), Body::from(csv)).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let location = resp.headers()[LOCATION].to_str().unwrap().to_string();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let job: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let id = job["id"].as_i64().unwrap();
+        assert_eq!(location, format!("/api/imports/{id}"));
+        // Nothing waits on the request anymore, the import goes on by itself
+
+        let mut done = None;
+        for _ in 0..200 {
+            let job = import_status(&state, id, &headers).await;
+            if job.status.is_done() {
+                done = Some(job);
+                break;
+            }
+            tokio::time::sleep(time::Duration::from_millis(50)).await;
+        }
+        let job = done.expect("The import never finished");
+        assert_eq!(job.status, import_job::Status::Finished);
+        assert_eq!(
+            (job.parsed, job.processed, job.imported, job.invalid),
+            (Some(6), 6, 5, 1)
+        );
+
+        let resp = api_import_errors(State(state.clone()), Path(id), headers.clone()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(String::from_utf8_lossy(&body).starts_with("line,outcome,reason\n6,invalid,"));
+        let resp = api_cancel_import(State(state.clone()), Path(id), headers.clone()).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        // Imports are only shown to whoever uploaded them
+        let (other, other_headers) = importer(&state, "other-importer").await;
+        let resp = api_import_status(State(state.clone()), Path(id), other_headers).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        delete_importer(&other, state.pool()).await;
+        delete_importer(&user, state.pool()).await;
+    }
+
+    /// Spools `csv` and queues an import of it for `user`, without starting it
+    async fn queued_import(
+        state: &Arc<PoolAndPrefs>,
+        user: &UserRow,
+        csv: String,
+    ) -> (i64, std::path::PathBuf) {
+        let spooled = import_job::spool(
+            Body::from(csv),
+            state.prefs().imports(),
+            time::Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+        let id = import_job::enqueue(*user.id(), &spooled.path, state.pool())
+            .await
+            .unwrap();
+        (id, spooled.path)
+    }
+
+    #[sqlx::test]
+    async fn imports_report_progress_and_stop_when_cancelled() {
+        let state = state_init().await;
+        let (user, headers) = importer(&state, "cancelled-importer").await;
+        let suffix = rand::random::<u32>();
+        let csv: String = (0..10)
+            .map(|i| format!("https://example.com/import/{suffix}/{i}\n"))
+            .collect();
+        let (id, path) = queued_import(&state, &user, csv).await;
+        let mut run = import_job::Run::load(id, state.prefs(), state.pool())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let mut processed = Vec::new();
+        for _ in 0..2 {
+            let progress = run.next_batch(2, state.pool()).await.unwrap();
+            assert_eq!(progress, import_job::Progress::More);
+            let job = import_status(&state, id, &headers).await;
+            assert_eq!(job.status, import_job::Status::Running);
+            assert_eq!(job.imported + job.skipped + job.invalid, job.processed);
+            processed.push(job.processed);
+        }
+        assert_eq!(processed, [2, 4]);
+
+        let resp = api_cancel_import(State(state.clone()), Path(id), headers.clone()).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let progress = run.next_batch(2, state.pool()).await.unwrap();
+        assert_eq!(
+            progress,
+            import_job::Progress::Done(import_job::Status::Cancelled)
+        );
+        let job = import_status(&state, id, &headers).await;
+        assert_eq!(
+            (job.status, job.processed),
+            (import_job::Status::Cancelled, 4)
+        );
+        let owned = url_db::retrieve_urls_by_owner(OwnerFilter::User(*user.id()), state.pool())
+            .await
+            .unwrap();
+        assert_eq!(owned.len(), 4);
+        assert!(!path.exists());
+
+        delete_importer(&user, state.pool()).await;
+    }
+
+    #[sqlx::test]
+    async fn unfinished_imports_resume_where_they_stopped() {
+        let state = state_init().await;
+        let (user, headers) = importer(&state, "resumed-importer").await;
+        let suffix = rand::random::<u32>();
+        let csv: String = (0..6)
+            .map(|i| format!("https://example.com/import/{suffix}/{i}\n"))
+            .collect();
+        let (id, _) = queued_import(&state, &user, csv).await;
+        // As if the server stopped after the first batch
+        let mut run = import_job::Run::load(id, state.prefs(), state.pool())
+            .await
+            .unwrap()
+            .unwrap();
+        run.next_batch(4, state.pool()).await.unwrap();
+        drop(run);
+
+        import_job::resume(state.prefs().clone(), state.pool().clone()).await;
+        let job = import_status(&state, id, &headers).await;
+        assert_eq!(job.status, import_job::Status::Finished);
+        assert_eq!((job.processed, job.imported, job.skipped), (6, 6, 0));
+
+        delete_importer(&user, state.pool()).await;
+    }
+
+    #[sqlx::test]
+    async fn applying_link_files_checks_every_link() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let suffix = rand::random::<u32>();
+        let username = format!("applier-{suffix}");
+        let user = user::new_user(
+            username.clone(),
+            String::from("pw"),
+            String::from("email"),
+            pool,
+        )
+        .await
+        .unwrap();
+        let path = env::temp_dir().join(format!("url_shortener_apply_{suffix}.toml"));
+        let prefs = state.prefs();
+        let apply = |contents: String| {
+            let (path, username) = (path.clone(), username.clone());
+            async move {
+                fs::write(&path, contents).unwrap();
+                let args = [
+                    "url_shortner",
+                    "apply",
+                    &path.to_string_lossy(),
+                    "--user",
+                    &username,
+                    "--yes",
+                ]
+                .map(String::from);
+                cli::run(&args, pool, prefs).await.unwrap()
+            }
+        };
+        let owner = OwnerFilter::User(*user.id());
+
+        // The reserved alias fails the plan, so the good link isn't made either
+        let code = apply(format!(
+            "[links.app{suffix}]\ndestination = \"https://example.com/apply/{suffix}\"\n\
+            [links.api]\ndestination = \"https://example.com/apply/{suffix}/api\"\n"
+        ))
+        .await;
+        assert_eq!(code, 1);
+        assert!(url_db::retrieve_urls_by_owner(owner, pool)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let code = apply(format!(
+            "[links.app{suffix}]\ndestination = \"https://example.com/apply/{suffix}\"\n"
+        ))
+        .await;
+        assert_eq!(code, 0);
+        let owned = url_db::retrieve_urls_by_owner(owner, pool).await.unwrap();
+        assert_eq!(owned.len(), 1);
+
+        // Updates are checked too, and recorded in the link's history
+        let self_link = format!("https://{}/elsewhere", prefs.domain_name());
+        let code = apply(format!(
+            "[links.app{suffix}]\ndestination = \"{self_link}\"\n"
+        ))
+        .await;
+        assert_eq!(code, 1);
+        let code = apply(format!(
+            "[links.app{suffix}]\ndestination = \"https://example.com/apply/{suffix}/new\"\n"
+        ))
+        .await;
+        assert_eq!(code, 0);
+        let edits = history::for_link(owned[0].id(), pool).await.unwrap();
+        assert_eq!(edits.len(), 1);
+        assert_eq!(
+            edits[0].new_value.as_deref(),
+            Some(format!("https://example.com/apply/{suffix}/new").as_str())
+        );
+
+        // Tags, expiry and redirect status are set on new links and changed on existing ones
+        let code = apply(format!(
+            "[links.app{suffix}]\n\
+            [links.tagged{suffix}]\ndestination = \"https://example.com/apply/{suffix}/tagged\"\n\
+            tags = [\"Docs\"]\nexpires_at = \"2099-01-01T00:00:00Z\"\nredirect_status = 307\n"
+        ))
+        .await;
+        assert_eq!(code, 0);
+        let tagged = url_db::retrieve_url_obj(&format!("tagged{suffix}"), prefs.slug_case(), pool)
+            .await
+            .unwrap();
+        let tagged_tags = tags::for_links(&[tagged.id()], pool).await.unwrap();
+        assert_eq!(tagged_tags[&tagged.id()], [String::from("docs")]);
+        assert!(tagged.expires_at().is_some());
+        assert_eq!(
+            tagged.redirect_status(),
+            Some(RedirectStatus::TemporaryRedirect)
+        );
+        let past = apply(format!(
+            "[links.app{suffix}]\nexpires_at = \"2000-01-01T00:00:00Z\"\n\
+            [links.tagged{suffix}]\n"
+        ))
+        .await;
+        assert_eq!(past, 1);
+        let code = apply(format!(
+            "[links.app{suffix}]\ntags = [\"kept\"]\nredirect_status = 308\n\
+            [links.tagged{suffix}]\ntags = []\n"
+        ))
+        .await;
+        assert_eq!(code, 0);
+        let changed = url_db::retrieve_url_obj(&format!("app{suffix}"), prefs.slug_case(), pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            changed.redirect_status(),
+            Some(RedirectStatus::PermanentRedirect)
+        );
+        assert!(changed.expires_at().is_none());
+        let all_tags = tags::for_links(&[changed.id(), tagged.id()], pool)
+            .await
+            .unwrap();
+        assert_eq!(all_tags[&changed.id()], [String::from("kept")]);
+        assert!(!all_tags.contains_key(&tagged.id()));
+
+        // Links left out of the file are archived, once
+        assert_eq!(apply(String::new()).await, 0);
+        let archived = url_db::retrieve_urls_by_owner(owner, pool).await.unwrap();
+        assert!(archived[0].deleted_at().is_some());
+        assert_eq!(apply(String::new()).await, 0);
+
+        fs::remove_file(&path).unwrap();
+        url_db::delete_url(owned[0].id(), pool).await.unwrap();
+        url_db::delete_url(tagged.id(), pool).await.unwrap();
+        user::delete_user_from_db(*user.id(), pool).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn applied_links_only_use_allowed_schemes() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let suffix = rand::random::<u32>();
+        let username = format!("scheme-applier-{suffix}");
+        let user = user::new_user(
+            username.clone(),
+            String::from("pw"),
+            String::from("email"),
+            pool,
+        )
+        .await
+        .unwrap();
+        let mut prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        prefs.set_allowed_schemes(vec![String::from("https")]);
+        let path = env::temp_dir().join(format!("url_shortener_apply_{suffix}.toml"));
+        let args = [
+            "url_shortner",
+            "apply",
+            &path.to_string_lossy(),
+            "--user",
+            &username,
+            "--yes",
+        ]
+        .map(String::from);
+
+        for destination in [
+            "javascript:alert(1)",
+            "data:text/html,hi",
+            "http://example.com/apply",
+            "//evil.com",
+        ] {
+            fs::write(
+                &path,
+                format!("[links.sch{suffix}]\ndestination = \"{destination}\"\n"),
+            )
+            .unwrap();
+            assert_eq!(
+                cli::run(&args, pool, &prefs).await,
+                Some(1),
+                "{destination}"
+            );
+        }
+        let owner = OwnerFilter::User(*user.id());
+        assert!(url_db::retrieve_urls_by_owner(owner, pool)
+            .await
+            .unwrap()
+            .is_empty());
+
+        fs::remove_file(&path).unwrap();
+        user::delete_user_from_db(*user.id(), pool).await.unwrap();
+    }
+
+    /// The links by the code each had before `recoded` gave it a new one, which it is put back to
+    fn links_by_old_code(
+        links: &[user_archive::ArchivedLink],
+        recoded: &[(String, String)],
+    ) -> BTreeMap<String, user_archive::ArchivedLink> {
+        links
+            .iter()
+            .map(|link| {
+                let old = recoded
+                    .iter()
+                    .find(|(_, new)| *new == link.code)
+                    .map_or(link.code.clone(), |(old, _)| old.clone());
+                let mut link = link.clone();
+                link.code.clone_from(&old);
+                (old, link)
+            })
+            .collect()
+    }
+
+    #[sqlx::test]
+    async fn users_move_between_instances_with_their_links_and_clicks() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let prefs = state.prefs();
+        let suffix = rand::random::<u32>();
+        let username = format!("mover-{suffix}");
+        let user = user::new_user(
+            username.clone(),
+            String::from("pw"),
+            format!("mover-{suffix}@example.com"),
+            pool,
+        )
+        .await
+        .unwrap();
+        let owner = Some(*user.id());
+
+        // A tagged link with settings and clicks, a rotating one and an archived geo-targeted one
+        let options = UrlOptions {
+            description: Some(String::from("Docs")),
+            expires_at: Some(Utc::now() + chrono::Duration::days(30)),
+            redirect_status: Some(RedirectStatus::TemporaryRedirect),
+            ..UrlOptions::default()
+        };
+        let plain = url_db::create_url_with_alias(
+            &format!("mva{suffix}"),
+            "https://example.com/move/a",
+            owner,
+            pool,
+            &options,
+        )
+        .await
+        .unwrap();
+        let mut conn = pool.acquire().await.unwrap();
+        tags::set(
+            plain.id(),
+            &[String::from("docs"), String::from("team")],
+            &mut conn,
+        )
+        .await
+        .unwrap();
+        sqlx::query("UPDATE urls SET clicks = 40, bot_clicks = 3 WHERE id = $1")
+            .bind(plain.id())
+            .execute(pool)
+            .await
+            .unwrap();
+        sqlx::query(
+            "INSERT INTO url_clicks_daily (url_id, day, count)
+            VALUES ($1, '2026-01-01', 15), ($1, '2026-01-02', 25)",
+        )
+        .bind(plain.id())
+        .execute(pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO url_referrers (url_id, referrer, count) VALUES ($1, 'news.example', 12)",
+        )
+        .bind(plain.id())
+        .execute(pool)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO url_devices (url_id, device, count) VALUES ($1, 'mobile', 30), ($1, 'desktop', 10)")
+            .bind(plain.id())
+            .execute(pool)
+            .await
+            .unwrap();
+        let rotating = url_db::create_url_with_alias(
+            &format!("mvb{suffix}"),
+            "https://example.com/move/b",
+            owner,
+            pool,
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        url_db::set_destinations(
+            rotating.id(),
+            &[
+                url_db::WeightedUrl {
+                    long_url: String::from("https://example.com/move/b1"),
+                    weight: 3,
+                },
+                url_db::WeightedUrl {
+                    long_url: String::from("https://example.com/move/b2"),
+                    weight: 1,
+                },
+            ],
+            &mut conn,
+        )
+        .await
+        .unwrap();
+        sqlx::query("UPDATE url_destinations SET clicks = weight * 7 WHERE url_id = $1")
+            .bind(rotating.id())
+            .execute(pool)
+            .await
+            .unwrap();
+        let archived = url_db::create_url_with_alias(
+            &format!("mvc{suffix}"),
+            "https://example.com/move/c",
+            owner,
+            pool,
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        url_db::set_geo_targets(
+            archived.id(),
+            &BTreeMap::from([(
+                String::from("DE"),
+                String::from("https://example.de/move/c"),
+            )]),
+            &mut conn,
+        )
+        .await
+        .unwrap();
+        sqlx::query("UPDATE urls SET deleted_at = now() WHERE id = $1")
+            .bind(archived.id())
+            .execute(pool)
+            .await
+            .unwrap();
+        let alias = format!("mvt{suffix}-{{customer}}");
+        let template = link_template::NewTemplate::parse(
+            &alias,
+            "https://example.com/customers/{customer}",
+            None,
+            None,
+        )
+        .unwrap();
+        let template = link_template::create_template(*user.id(), &template, pool)
+            .await
+            .unwrap();
+        for value in ["acme", "acme", "globex"] {
+            link_template::record_click(&template, value, pool)
+                .await
+                .unwrap();
+        }
+        drop(conn);
+        let original = user_archive::export(&username, pool).await.unwrap();
+        assert_eq!(original.links.len(), 3);
+        assert_eq!(original.templates[0].clicks, 3);
+
+        let path = env::temp_dir().join(format!("url_shortener_user_{suffix}.archive"));
+        let passphrase_path = env::temp_dir().join(format!("url_shortener_user_{suffix}.pass"));
+        fs::write(&passphrase_path, "correct horse\n").unwrap();
+        let (path_arg, passphrase_arg) = (
+            path.to_string_lossy().to_string(),
+            passphrase_path.to_string_lossy().to_string(),
+        );
+        let args = [
+            "url_shortner",
+            "export-user",
+            &username,
+            &path_arg,
+            "--passphrase-file",
+            &passphrase_arg,
+        ]
+        .map(String::from);
+        assert_eq!(cli::run(&args, pool, prefs).await, Some(0));
+        let sealed = fs::read(&path).unwrap();
+        assert!(!String::from_utf8_lossy(&sealed).contains(&username));
+        let archive = user_archive::open(&sealed, "correct horse").unwrap();
+        assert_eq!(archive.links, original.links);
+
+        // The user is still here, so the import is refused until told what to do
+        let args = [
+            "url_shortner",
+            "import-user",
+            &path_arg,
+            "--passphrase-file",
+            &passphrase_arg,
+        ]
+        .map(String::from);
+        assert_eq!(cli::run(&args, pool, prefs).await, Some(1));
+
+        // Renamed, every code is taken by the original so each link is recoded, and the template
+        // overlaps the original's
+        let renamed = format!("moved-{suffix}");
+        let moved = user_archive::import(
+            &archive,
+            &user_archive::OnConflict::Rename(renamed.clone()),
+            false,
+            prefs,
+            pool,
+        )
+        .await
+        .unwrap();
+        assert!(moved.created);
+        assert_eq!(moved.username, renamed);
+        assert_eq!(moved.password, None);
+        let old_codes: BTreeSet<&str> = moved.recoded.iter().map(|(old, _)| old.as_str()).collect();
+        assert_eq!(
+            old_codes,
+            BTreeSet::from([
+                format!("mva{suffix}").as_str(),
+                format!("mvb{suffix}").as_str(),
+                format!("mvc{suffix}").as_str(),
+            ])
+        );
+        assert!(moved.recoded.iter().all(|(old, new)| old != new));
+        assert_eq!(moved.templates, 0);
+        assert_eq!(moved.skipped_templates.len(), 1);
+        let copy = user_archive::export(&renamed, pool).await.unwrap();
+        assert_eq!(copy.email, original.email);
+        assert_eq!(copy.hashed_pw, original.hashed_pw);
+        assert_eq!(
+            links_by_old_code(&copy.links, &moved.recoded),
+            links_by_old_code(&original.links, &[])
+        );
+
+        // Once the original and its copy are gone, the codes, template and email are free and
+        // kept as they were
+        for id in [*user.id(), moved.user_id] {
+            for row in url_db::retrieve_urls_by_owner(OwnerFilter::User(id), pool)
+                .await
+                .unwrap()
+            {
+                url_db::delete_url(row.id(), pool).await.unwrap();
+            }
+            user::delete_user_from_db(id, pool).await.unwrap();
+        }
+        let back = user_archive::import(
+            &archive,
+            &user_archive::OnConflict::Refuse,
+            true,
+            prefs,
+            pool,
+        )
+        .await
+        .unwrap();
+        assert!(back.recoded.is_empty());
+        assert_eq!(back.templates, 1);
+        let restored = user_archive::export(&username, pool).await.unwrap();
+        assert_eq!(restored.links, original.links);
+        assert_eq!(restored.templates, original.templates);
+        let password = back.password.unwrap();
+        let restored_user = user::retrieve_user_by_name(&username, pool).await.unwrap();
+        assert!(user::verify_pw(&password, &restored_user).await);
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&passphrase_path).unwrap();
+        for row in url_db::retrieve_urls_by_owner(OwnerFilter::User(back.user_id), pool)
+            .await
+            .unwrap()
+        {
+            url_db::delete_url(row.id(), pool).await.unwrap();
+        }
+        user::delete_user_from_db(back.user_id, pool).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn rejects_bad_click_limits() {
+        let state = state_init().await;
+        for form in [
+            "max_clicks=0",
+            "max_clicks=-2",
+            "max_clicks=lots",
+            "max_clicks=5&single_use=on",
+        ] {
+            let resp = post_new_url(
+                State(state.clone()),
+                None,
+                HeaderMap::new(),
+                Bytes::from(format!("url=https%3A%2F%2Fexample.com&{form}")),
+            )
+            .await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{form}");
+        }
+        let resp = post_new_url(
+            State(state.clone()),
+            None,
+            HeaderMap::new(),
+            Bytes::from("url=https%3A%2F%2Fexample.com&max_clicks=5"),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[sqlx::test]
+    async fn links_can_choose_their_redirect_status() {
+        let state = state_init().await;
+        for status in ["303", "200", "soon"] {
+            let resp = post_new_url(
+                State(state.clone()),
+                None,
+                HeaderMap::new(),
+                Bytes::from(format!(
+                    "url=https%3A%2F%2Fexample.com&redirect_status={status}"
+                )),
+            )
+            .await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{status}");
+        }
+
+        let page = rand::random::<u32>();
+        let destination = format!("https://example.com/{page}");
+        for form in ["", "&redirect_status=307"] {
+            let resp = post_new_url(
+                State(state.clone()),
+                None,
+                HeaderMap::new(),
+                Bytes::from(format!("url=https%3A%2F%2Fexample.com%2F{page}{form}")),
+            )
+            .await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        // The one asking for a status of its own isn't folded into the plain link
+        let rows: Vec<_> = url_db::retrieve_urls_by_owner(OwnerFilter::Anonymous, state.pool())
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|row| row.long_url() == &destination)
+            .collect();
+        assert_eq!(rows.len(), 2);
+        for row in rows {
+            let resp = subdir_handler(
+                Path(row.clone_short_url()),
+                State(state.clone()),
+                None,
+                Method::GET,
+                HeaderMap::new(),
+            )
+            .await;
+            let expected = match row.redirect_status() {
+                Some(RedirectStatus::TemporaryRedirect) => StatusCode::TEMPORARY_REDIRECT,
+                None => StatusCode::MOVED_PERMANENTLY,
+                other => panic!("{other:?}"),
+            };
+            assert_eq!(resp.status(), expected);
+        }
+    }
+
+    #[sqlx::test]
+    async fn previews_show_the_destination_without_counting() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let plain = url_db::create_url(
+            "https://example.com/preview",
+            None,
+            pool,
+            CodeRules::of_len(8),
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let protected = UrlOptions {
+            passphrase_hash: Some(user::hash_password("open sesame")),
+            ..UrlOptions::default()
+        };
+        let protected = url_db::create_url(
+            "https://example.com/secret",
+            None,
+            pool,
+            CodeRules::of_len(8),
+            &protected,
+        )
+        .await
+        .unwrap();
+        let visit = |path: String| {
+            let state = state.clone();
+            async move {
+                let resp = subdir_handler(
+                    Path(path),
+                    State(state),
+                    None,
+                    Method::GET,
+                    HeaderMap::new(),
+                )
+                .await;
+                let status = resp.status();
+                let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                (status, String::from_utf8(body.to_vec()).unwrap())
+            }
+        };
+
+        let code = plain.short_url();
+        for _ in 0..2 {
+            let (status, page) = visit(format!("{code}+")).await;
+            assert_eq!(status, StatusCode::OK);
+            assert!(page.contains("https://example.com/preview"), "{page}");
+            assert!(page.contains("opened 0 times"), "{page}");
+            assert!(page.contains(&format!(r#"href="/{code}""#)), "{page}");
+        }
+        let clicks = url_db::retrieve_url_obj(code, SlugCase::Sensitive, pool)
+            .await
+            .unwrap()
+            .clicks();
+        assert_eq!(clicks, 0);
+        // Without the + it is the usual redirect
+        let (status, _) = visit(code.clone()).await;
+        assert!(status.is_redirection());
+        let (_, page) = visit(format!("{code}+")).await;
+        assert!(page.contains("opened once"), "{page}");
+
+        let (status, page) = visit(format!("{}+", protected.short_url())).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!page.contains("example.com"), "{page}");
+        assert!(page.contains("passphrase"), "{page}");
+
+        let (status, _) = visit(String::from("nosuchcode+")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[sqlx::test]
+    async fn passphrase_unlocks_protected_links() {
+        let state = state_init().await;
+        let page = rand::random::<u32>();
+        let resp = post_new_url(
+            State(state.clone()),
+            None,
+            HeaderMap::new(),
+            Bytes::from(format!(
+                "url=https%3A%2F%2Fexample.com%2F{page}&passphrase=open+sesame"
+            )),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let row = url_db::retrieve_urls_by_owner(OwnerFilter::Anonymous, state.pool())
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|row| row.long_url() == &format!("https://example.com/{page}"))
+            .unwrap();
+        assert!(row.is_protected());
+        assert_ne!(row.passphrase_hash(), Some("open sesame"));
+        let code = row.clone_short_url();
+
+        async fn body_of(resp: Response) -> String {
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        }
+        let unlock = |code: String, passphrase: &'static str| {
+            let state = state.clone();
+            async move {
+                unlock_short_url(
+                    Path(code),
+                    State(state),
+                    None,
+                    HeaderMap::new(),
+                    Bytes::from(format!("passphrase={passphrase}")),
+                )
+                .await
+            }
+        };
+
+        // Opening it only asks for the passphrase
+        let resp = consume_short_url(
+            Path(code.clone()),
+            State(&state),
+            &HeaderMap::new(),
+            &Method::GET,
+            None,
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get(LOCATION).is_none());
+        assert!(body_of(resp).await.contains(r#"name="passphrase""#));
+
+        // A wrong passphrase and a made up code get the same answer
+        let wrong = unlock(code.clone(), "open+says+me").await;
+        assert_eq!(wrong.status(), StatusCode::FORBIDDEN);
+        let wrong = body_of(wrong).await.replace(&code, "CODE");
+        let missing = unlock(String::from("nosuchcode"), "open+sesame").await;
+        assert_eq!(missing.status(), StatusCode::FORBIDDEN);
+        let missing = body_of(missing).await.replace("nosuchcode", "CODE");
+        assert_eq!(wrong, missing);
+        assert!(wrong.contains("That passphrase isn't right."));
+
+        let resp = unlock(code.clone(), "open+sesame").await;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            resp.headers()[LOCATION],
+            format!("https://example.com/{page}")
+        );
+        let row = url_db::retrieve_url_obj(&code, SlugCase::Sensitive, state.pool())
+            .await
+            .unwrap();
+        assert_eq!(row.clicks(), 1);
+
+        // Links without a passphrase can't be told apart by posting to them either
+        let plain = url_db::create_url(
+            "https://example.com",
+            None,
+            state.pool(),
+            CodeRules::of_len(8),
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let resp = unlock(plain.clone_short_url(), "").await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[sqlx::test]
+    async fn countdown_counts_one_click() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let options = UrlOptions {
+            interstitial_seconds: Some(3),
+            ..UrlOptions::default()
+        };
+        let link = url_db::create_url(
+            "https://example.com/?a=1&b=2",
+            None,
+            pool,
+            CodeRules::of_len(8),
+            &options,
+        )
+        .await
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::USER_AGENT,
+            HeaderValue::from_static("Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Firefox/131.0"),
+        );
+
+        let resp = consume_short_url(
+            Path(link.clone_short_url()),
+            State(&state),
+            &headers,
+            &Method::GET,
+            None,
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("Refresh").unwrap(),
+            "3;url=https://example.com/?a=1&b=2"
+        );
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(r#"content="3;url=https://example.com/?a=1&amp;b=2""#));
+        assert!(body.contains(r#"<a href="https://example.com/?a=1&amp;b=2">Continue now</a>"#));
+
+        let clicks: i64 = sqlx::query_scalar("SELECT clicks FROM urls WHERE id = $1")
+            .bind(link.id())
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        assert_eq!(clicks, 1);
+
+        headers.insert(
+            header::USER_AGENT,
+            HeaderValue::from_static("Twitterbot/1.0"),
+        );
+        let resp = consume_short_url(
+            Path(link.clone_short_url()),
+            State(&state),
+            &headers,
+            &Method::GET,
+            None,
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+    }
+
+    #[sqlx::test]
+    async fn single_use_redirects_exactly_once() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let options = UrlOptions {
+            single_use: true,
+            ..UrlOptions::default()
+        };
+        let link = url_db::create_url(
+            "https://example.com/secret",
+            None,
+            pool,
+            CodeRules::of_len(8),
+            &options,
+        )
+        .await
+        .unwrap();
+        let code = link.clone_short_url();
+        let mut browser = HeaderMap::new();
+        browser.insert(
+            header::USER_AGENT,
+            HeaderValue::from_static("Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Firefox/131.0"),
+        );
+        let mut unfurler = HeaderMap::new();
+        unfurler.insert(
+            header::USER_AGENT,
+            HeaderValue::from_static("Slackbot-LinkExpanding 1.0 (+https://api.slack.com/robots)"),
+        );
+
+        // Neither previews nor HEAD requests get the destination or use the link up
+        for (headers, method) in [(&unfurler, Method::GET), (&browser, Method::HEAD)] {
+            let resp =
+                consume_short_url(Path(code.clone()), State(&state), headers, &method, None).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert!(resp.headers().get(LOCATION).is_none());
+        }
+        let row = url_db::retrieve_url_obj(&code, SlugCase::Sensitive, pool)
+            .await
+            .unwrap();
+        assert_eq!(row.consumed_at(), None);
+        assert_eq!(
+            UrlRowView::assemble(&row, "sho.rt", Viewer::Owner)
+                .status_badge()
+                .as_deref(),
+            Some("Single use")
+        );
+
+        let statuses = futures_util::future::join_all((0..8).map(|_| {
+            consume_short_url(
+                Path(code.clone()),
+                State(&state),
+                &browser,
+                &Method::GET,
+                None,
+            )
+        }))
+        .await
+        .into_iter()
+        .map(|resp| resp.status())
+        .collect::<Vec<_>>();
+        assert_eq!(
+            statuses.iter().filter(|s| **s == StatusCode::FOUND).count(),
+            1
+        );
+        assert_eq!(
+            statuses.iter().filter(|s| **s == StatusCode::GONE).count(),
+            7
+        );
+
+        let row = url_db::retrieve_url_obj(&code, SlugCase::Sensitive, pool)
+            .await
+            .unwrap();
+        assert_eq!(row.clicks(), 1);
+        let used_at = row.consumed_at().unwrap();
+        assert!(UrlRowView::assemble(&row, "sho.rt", Viewer::Owner)
+            .status_badge()
+            .unwrap()
+            .starts_with("Used "));
+
+        let resp = consume_short_url(Path(code), State(&state), &browser, &Method::GET, None).await;
+        assert_eq!(resp.status(), StatusCode::GONE);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(&used_at.format("%Y-%m-%d %H:%M UTC").to_string()));
+        assert!(!body.contains("example.com/secret"));
+    }
+
+    #[sqlx::test]
+    async fn api_creates_single_use_links() {
+        let state = state_init().await;
+        let resp = api_create_url(
+            State(state.clone()),
+            None,
+            HeaderMap::new(),
+            Ok(Json(ApiNewUrl {
+                url: Some(String::from("https://example.com/api-secret")),
+                alias: None,
+                tags: None,
+                description: None,
+                destinations: Vec::new(),
+                active_from: None,
+                single_use: true,
+            })),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(
+            &axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(body["single_use"], true);
+        let code = body["short_url"].as_str().unwrap().to_string();
+
+        let headers = HeaderMap::new();
+        let visit = || {
+            consume_short_url(
+                Path(code.clone()),
+                State(&state),
+                &headers,
+                &Method::GET,
+                None,
+            )
+        };
+        assert_eq!(visit().await.status(), StatusCode::FOUND);
+        assert_eq!(visit().await.status(), StatusCode::GONE);
+        let row = url_db::retrieve_url_obj(&code, SlugCase::Sensitive, state.pool())
+            .await
+            .unwrap();
+        assert!(row.consumed_at().is_some());
+        url_db::delete_url(row.id(), state.pool()).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn preflight_changes_nothing() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let mut users = Vec::new();
+        for role in ["owner", "partner"] {
+            let name = format!("preflight-{role}-{}", rand::random::<u32>());
+            let user = user::new_user(name, String::from("pw"), String::from("email"), pool)
+                .await
+                .unwrap();
+            users.push(user);
+        }
+        let options = UrlOptions {
+            single_use: true,
+            ..UrlOptions::default()
+        };
+        let link = url_db::create_url(
+            "https://example.com/secret",
+            Some(*users[0].id()),
+            pool,
+            CodeRules::of_len(8),
+            &options,
+        )
+        .await
+        .unwrap();
+        let code = link.clone_short_url();
+
+        for (user, destination) in [
+            (&users[0], Some("https://example.com/secret")),
+            (&users[1], None),
+        ] {
+            for _ in 0..3 {
+                let headers = auth_headers(user, state.prefs().jwt_secret());
+                let resp = api_preflight(State(state.clone()), Path(code.clone()), headers).await;
+                assert_eq!(resp.status(), StatusCode::OK);
+                let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(body["gate"], "single_use");
+                assert_eq!(body["destination_host"], "example.com");
+                assert_eq!(body["destination"].as_str(), destination);
+            }
+        }
+
+        let row = url_db::retrieve_url_obj(&code, SlugCase::Sensitive, pool)
+            .await
+            .unwrap();
+        assert_eq!(row.clicks(), 0);
+        assert_eq!(row.consumed_at(), None);
+
+        let resp = api_preflight(State(state.clone()), Path(code), HeaderMap::new()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[sqlx::test]
+    async fn preflight_agrees_with_a_visit() {
+        let mut prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        prefs.set_allowed_schemes(vec![String::from("https"), String::from("ftp")]);
+        let state = state_with(prefs).await;
+        let pool = state.pool();
+        let name = format!("preflight-schemes-{}", rand::random::<u32>());
+        let user = user::new_user(name, String::from("pw"), String::from("email"), pool)
+            .await
+            .unwrap();
+        let countdown = UrlOptions {
+            interstitial_seconds: Some(5),
+            ..UrlOptions::default()
+        };
+        let mut links = Vec::new();
+        // Stored straight away, as if the allowlist had changed since they were made
+        for (url, options) in [
+            ("ftp://files.example.com/pub", &countdown),
+            ("http://example.com/plain", &UrlOptions::default()),
+        ] {
+            let link =
+                url_db::create_url(url, Some(*user.id()), pool, CodeRules::of_len(8), options)
+                    .await
+                    .unwrap();
+            links.push(link);
+        }
+
+        for (link, gate, visited) in [
+            (&links[0], "countdown", StatusCode::OK),
+            (&links[1], "unreachable", StatusCode::NOT_FOUND),
+        ] {
+            let headers = auth_headers(&user, state.prefs().jwt_secret());
+            let resp =
+                api_preflight(State(state.clone()), Path(link.clone_short_url()), headers).await;
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["gate"], gate, "{}", link.long_url());
+            let resp = subdir_handler(
+                Path(link.clone_short_url()),
+                State(state.clone()),
+                None,
+                Method::GET,
+                HeaderMap::new(),
+            )
+            .await;
+            assert_eq!(resp.status(), visited, "{}", link.long_url());
+        }
+
+        for link in &links {
+            url_db::delete_url(link.id(), pool).await.unwrap();
+        }
+        user::delete_user_from_db(*user.id(), pool).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn click_stream_sees_counted_clicks() {
+        use futures_util::StreamExt;
+
+        let state = state_init().await;
+        let pool = state.pool();
+        let name = format!("streamer-{}", rand::random::<u32>());
+        let user = user::new_user(name, String::from("pw"), String::from("email"), pool)
+            .await
+            .unwrap();
+        let mine = url_db::create_url(
+            "https://example.com/live",
+            Some(*user.id()),
+            pool,
+            CodeRules::of_len(8),
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap()
+        .clone_short_url();
+        let theirs = url_db::create_url(
+            "https://example.com",
+            None,
+            pool,
+            CodeRules::of_len(8),
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap()
+        .clone_short_url();
+
+        let query = ClickStreamQuery {
+            format: None,
+            codes: None,
+        };
+        let headers = auth_headers(&user, state.prefs().jwt_secret());
+        let resp = api_click_stream(State(state.clone()), Query(query), headers).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/x-ndjson");
+        let mut stream = resp.into_body().into_data_stream();
+
+        for code in [&theirs, &mine] {
+            let resp = consume_short_url(
+                Path(code.clone()),
+                State(&state),
+                &HeaderMap::new(),
+                &Method::GET,
+                None,
+            )
+            .await;
+            assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+        }
+        let chunk = tokio::time::timeout(std::time::Duration::from_secs(1), stream.next())
+            .await
+            .expect("no click arrived")
+            .unwrap()
+            .unwrap();
+        let line: serde_json::Value = serde_json::from_slice(&chunk).unwrap();
+        assert_eq!(line["type"], "click");
+        assert_eq!(line["code"], mine.as_str());
+
+        // The first stream is still open
+        let mut statuses = Vec::new();
+        let mut open = Vec::new();
+        for _ in 0..firehose::MAX_STREAMS_PER_USER {
+            let query = ClickStreamQuery {
+                format: Some(firehose::Format::Sse),
+                codes: Some(mine.clone()),
+            };
+            let headers = auth_headers(&user, state.prefs().jwt_secret());
+            let resp = api_click_stream(State(state.clone()), Query(query), headers).await;
+            statuses.push(resp.status());
+            open.push(resp);
+        }
+        assert_eq!(statuses.pop(), Some(StatusCode::TOO_MANY_REQUESTS));
+        assert!(statuses.iter().all(|status| *status == StatusCode::OK));
+        assert_eq!(open[0].headers()[CONTENT_TYPE], "text/event-stream");
+    }
+
+    #[sqlx::test]
+    async fn equivalent_destinations_share_a_link() {
+        let state = state_init().await;
+        let page = format!("dedupe-{}", rand::random::<u32>());
+        let create = |form: String| {
+            let state = state.clone();
+            async move {
+                let resp =
+                    post_new_url(State(state), None, HeaderMap::new(), Bytes::from(form)).await;
+                let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                let body = String::from_utf8(body.to_vec()).unwrap();
+                let code = body.split("<a href=\"").nth(1).unwrap();
+                code[..code.find('"').unwrap()].to_string()
+            }
+        };
+
+        let first = create(format!("url=https%3A%2F%2Fexample.com%2F{page}")).await;
+        for variant in [
+            format!("url=https%3A%2F%2Fexample.com%2F{page}%2F"),
+            format!("url=HTTPS%3A%2F%2FExample.com%3A443%2F{page}"),
+            format!("url=https%3A%2F%2Fexample.com%2F{page}&interstitial_seconds=0"),
+        ] {
+            assert_eq!(create(variant).await, first);
+        }
+        // Links with a gate are never shared
+        for gated in [
+            format!("url=https%3A%2F%2Fexample.com%2F{page}&single_use=on"),
+            format!("url=https%3A%2F%2Fexample.com%2F{page}&interstitial_seconds=3"),
+        ] {
+            assert_ne!(create(gated).await, first);
+        }
+
+        let row = url_db::retrieve_url_obj(&first, SlugCase::Sensitive, state.pool())
+            .await
+            .unwrap();
+        assert_eq!(row.long_url(), &format!("https://example.com/{page}"));
+        assert_eq!(row.submitted_url(), None);
+    }
+
+    #[sqlx::test]
+    async fn disabled_features_are_hidden_everywhere() {
+        use tower::ServiceExt;
+
+        async fn get_json(state: &Arc<PoolAndPrefs>, path: &str) -> Response {
+            api_routes(state.features, state.prefs().timeouts())
+                .with_state(state.clone())
+                .layer(middleware::from_fn_with_state(
+                    state.caching.clone(),
+                    cache::apply,
+                ))
+                .oneshot(axum::http::Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+        }
+        let gated = [
+            "/api/admin/missed",
+            "/api/preflight/anything",
+            "/api/stream/clicks",
+            "/api/templates",
+        ];
+
+        let on = state_init().await;
+        let resp = get_json(&on, "/api/features").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CACHE_CONTROL], "public, max-age=300");
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["features"]["single_use"], true);
+        assert_eq!(body["features"]["click_stream"], true);
+        assert_eq!(body["limits"]["code_length"], on.prefs().url_len());
+        assert_eq!(
+            body["limits"]["max_interstitial_seconds"],
+            url_db::MAX_INTERSTITIAL_SECONDS
+        );
+        // Present, so they only turn the caller away for not being signed in
+        for path in gated {
+            assert_eq!(
+                get_json(&on, path).await.status(),
+                StatusCode::UNAUTHORIZED,
+                "{path}"
+            );
+        }
+
+        let mut prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        prefs.set_features(
+            toml::from_str(
+                "single_use = false\npreflight = false\nclick_stream = false\nmissed_lookups = false
+                link_templates = false",
+            )
+            .unwrap(),
+        );
+        let off = state_with(prefs).await;
+        let body = axum::body::to_bytes(
+            get_json(&off, "/api/features").await.into_body(),
+            usize::MAX,
+        )
+        .await
+        .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body["features"],
+            serde_json::json!({
+                "single_use": false,
+                "preflight": false,
+                "click_stream": false,
+                "missed_lookups": false,
+                "link_templates": false,
+                "conversions": false,
+            })
+        );
+        for path in gated.into_iter().chain(["/api/nonexistent"]) {
+            assert_eq!(
+                get_json(&off, path).await.status(),
+                StatusCode::NOT_FOUND,
+                "{path}"
+            );
+        }
+
+        let form = Bytes::from("url=https%3A%2F%2Fexample.com&single_use=on");
+        let resp = post_new_url(State(off.clone()), None, HeaderMap::new(), form).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let code = format!("missing{}", rand::random::<u32>());
+        consume_short_url(
+            Path(code),
+            State(&off),
+            &HeaderMap::new(),
+            &Method::GET,
+            None,
+        )
+        .await;
+        assert!(off.missed.top(usize::MAX).is_empty());
+    }
+
+    /// The (path, method) pairs `router` answers, found from the paths axum prints for it and the
+    /// `Allow` header of the 405 each path answers a method nothing is routed for with
+    async fn routed_operations(
+        router: Router<Arc<PoolAndPrefs>>,
+        state: &Arc<PoolAndPrefs>,
+    ) -> BTreeSet<(String, String)> {
+        use tower::ServiceExt;
+
+        let paths: BTreeSet<String> = format!("{router:?}")
+            .split('"')
+            .skip(1)
+            .step_by(2)
+            .filter(|quoted| quoted.starts_with("/api"))
+            .map(String::from)
+            .collect();
+        let app = router.with_state(state.clone());
+        let mut routed = BTreeSet::new();
+        for path in paths {
+            let probe: Vec<&str> = path
+                .split('/')
+                .map(|segment| {
+                    if segment.starts_with(':') {
+                        "1"
+                    } else {
+                        segment
+                    }
+                })
+                .collect();
+            let resp = app
+                .clone()
+                .oneshot(
+                    axum::http::Request::builder()
+                        .method(Method::TRACE)
+                        .uri(probe.join("/"))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED, "{path}");
+            let allowed = resp.headers()[header::ALLOW].to_str().unwrap();
+            for method in allowed.split(',').filter(|method| *method != "HEAD") {
+                routed.insert((path.clone(), method.to_string()));
+            }
+        }
+        routed
+    }
+
+    #[sqlx::test]
+    async fn every_route_is_in_the_document() {
+        let state = state_init().await;
+        let routed =
+            routed_operations(api_routes(state.features, state.prefs().timeouts()), &state).await;
+        let documented: BTreeSet<(String, String)> = openapi::operations()
+            .iter()
+            .map(|operation| (operation.path.to_string(), operation.method.to_string()))
+            .collect();
+
+        let undocumented: Vec<_> = routed.difference(&documented).collect();
+        assert!(
+            undocumented.is_empty(),
+            "Routed but missing from the OpenAPI operations: {undocumented:?}"
+        );
+        let unrouted: Vec<_> = documented.difference(&routed).collect();
+        assert!(
+            unrouted.is_empty(),
+            "In the OpenAPI operations but not routed: {unrouted:?}"
+        );
+    }
+
+    /// What an example of the contract test is sent about, seeded afresh for each so examples
+    /// that change things don't change what the next one sees
+    struct Seeded {
+        code: String,
+        archived: String,
+        rolling: String,
+        import: i64,
+        template: i64,
+        announcement: i64,
+        rebuild: i64,
+    }
+
+    async fn seed_contract(owner: i64, n: usize, pool: &PgPool) -> Seeded {
+        let suffix = rand::random::<u32>();
+        let link = |prefix: &str| format!("{prefix}{suffix}x{n}");
+        let (code, archived, rolling) = (link("c"), link("a"), link("r"));
+        let create = |code: String| async move {
+            url_db::create_url_with_alias(
+                &code,
+                "https://example.com/contract",
+                Some(owner),
+                pool,
+                &UrlOptions::default(),
+            )
+            .await
+            .unwrap()
+        };
+        let live = create(code.clone()).await;
+        url_db::archive_url(create(archived.clone()).await.id(), pool)
+            .await
+            .unwrap();
+        let arms = url_db::rollout_arms(
+            "https://example.com/contract",
+            "https://example.com/contract/new",
+            25,
+        );
+        let mut conn = pool.acquire().await.unwrap();
+        url_db::start_rollout(
+            create(rolling.clone()).await.id(),
+            &arms,
+            Utc::now() + chrono::Duration::days(1),
+            &mut conn,
+        )
+        .await
+        .unwrap();
+        drop(conn);
+
+        // Recorded long enough ago that a dry run rebuild of the example's days is allowed
+        sqlx::query(
+            "INSERT INTO url_click_visitors (url_id, visitor, clicked_at)
+            VALUES ($1, 'visitor', '2024-01-01T12:00:00Z')",
+        )
+        .bind(live.id())
+        .execute(pool)
+        .await
+        .unwrap();
+        let import: i64 = sqlx::query_scalar(
+            "INSERT INTO import_jobs (user_id, path, status, parsed, processed, skipped, finished_at)
+            VALUES ($1, '', 'finished', 1, 1, 1, now()) RETURNING id",
+        )
+        .bind(owner)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO import_job_errors (job_id, line, outcome, reason)
+            VALUES ($1, 1, 'skipped', 'Already shortened')",
+        )
+        .bind(import)
+        .execute(pool)
+        .await
+        .unwrap();
+        let alias = format!("t{code}-{{value}}");
+        let template = link_template::NewTemplate::parse(
+            &alias,
+            "https://example.com/contract?v={value}",
+            None,
+            None,
+        )
+        .unwrap();
+        let template = link_template::create_template(owner, &template, pool)
+            .await
+            .unwrap()
+            .id();
+        let announcement: i64 = sqlx::query_scalar(
+            "INSERT INTO announcements (message, severity, starts_at)
+            VALUES ('Contract test', 'info', '2099-01-01T00:00:00Z') RETURNING id",
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        let rebuild: i64 = sqlx::query_scalar(
+            "INSERT INTO rollup_rebuilds (url_id, from_day, to_day, next_day, started_by,
+                finished_at)
+            VALUES ($1, '2024-03-01', '2024-03-02', '2024-03-03', 'contract', now())
+            RETURNING id",
+        )
+        .bind(live.id())
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        Seeded {
+            code,
+            archived,
+            rolling,
+            import,
+            template,
+            announcement,
+            rebuild,
+        }
+    }
+
+    /// Sends every example of the OpenAPI document and checks the response is one the document
+    /// declares: the status, the media type, and for JSON every field, with none left out or added
+    #[sqlx::test]
+    async fn endpoints_answer_as_documented() {
+        use openapi::{Caller, EXAMPLE_CODE, EXAMPLE_USER};
+        use tower::ServiceExt;
+
+        let mut prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        prefs.set_features(toml::from_str("conversions = true").unwrap());
+        let suffix = rand::random::<u32>();
+        let names = ["other", "owner", "admin"].map(|role| format!("contract-{role}-{suffix}"));
+        prefs.set_admins(vec![names[2].clone()]);
+        let state = state_with(prefs).await;
+        let pool = state.pool();
+        let mut users = Vec::new();
+        for name in &names {
+            let user = user::new_user(
+                name.clone(),
+                String::from("pw"),
+                String::from("email"),
+                pool,
+            )
+            .await
+            .unwrap();
+            users.push(user);
+        }
+        let headers = |caller| match caller {
+            Caller::Anonymous => HeaderMap::new(),
+            Caller::SignedIn => auth_headers(&users[0], state.prefs().jwt_secret()),
+            Caller::Owner => auth_headers(&users[1], state.prefs().jwt_secret()),
+            Caller::Admin => auth_headers(&users[2], state.prefs().jwt_secret()),
+        };
+
+        let document = openapi::document();
+        let (mut anonymous_links, mut announcements) = (Vec::new(), Vec::new());
+        let mut n = 0;
+        for operation in openapi::operations() {
+            let documented = &document["paths"][operation.document_path()]
+                [operation.method.as_str().to_lowercase()];
+            for example in &operation.examples {
+                n += 1;
+                let name = format!(
+                    "{} {} as {:?}, expecting {}",
+                    operation.method, operation.path, example.caller, example.status
+                );
+                let seeded = seed_contract(*users[1].id(), n, pool).await;
+                announcements.push(seeded.announcement);
+                let path: Vec<String> = operation
+                    .path
+                    .split('/')
+                    .map(|segment| match segment {
+                        ":code" if operation.path.ends_with("/restore") => seeded.archived.clone(),
+                        ":code" if operation.path.ends_with("/commit") => seeded.rolling.clone(),
+                        ":code" => seeded.code.clone(),
+                        ":id" if operation.path.starts_with("/api/imports") => {
+                            seeded.import.to_string()
+                        }
+                        ":id" if operation.path.starts_with("/api/templates") => {
+                            seeded.template.to_string()
+                        }
+                        ":id" if operation.path.starts_with("/api/admin/announcements") => {
+                            seeded.announcement.to_string()
+                        }
+                        ":id" => seeded.rebuild.to_string(),
+                        segment => segment.to_string(),
+                    })
+                    .collect();
+                let mut uri = path.join("/");
+                if let Some(query) = example.query {
+                    uri = format!("{uri}?{}", query.replace(EXAMPLE_CODE, &seeded.code));
+                }
+                let mut request = axum::http::Request::builder()
+                    .method(operation.method.clone())
+                    .uri(uri);
+                for (header, value) in &headers(example.caller) {
+                    request = request.header(header, value);
+                }
+                let request = match &example.body {
+                    Some(serde_json::Value::String(csv)) => request
+                        .header(CONTENT_TYPE, "text/csv")
+                        .body(Body::from(csv.clone())),
+                    Some(json) => {
+                        request
+                            .header(CONTENT_TYPE, "application/json")
+                            .body(Body::from(
+                                json.to_string()
+                                    .replace(
+                                        &format!("\"{EXAMPLE_USER}\""),
+                                        &format!("\"{}\"", names[0]),
+                                    )
+                                    .replace(EXAMPLE_CODE, &seeded.code),
+                            ))
+                    }
+                    None => request.body(Body::empty()),
+                };
+                let resp = api_routes(state.features, state.prefs().timeouts())
+                    .with_state(state.clone())
+                    .oneshot(request.unwrap())
+                    .await
+                    .unwrap();
+
+                let status = resp.status().as_u16();
+                let content_type = resp
+                    .headers()
+                    .get(CONTENT_TYPE)
+                    .map(|value| value.to_str().unwrap().to_string());
+                // Streams stay open, so only what they are is checked
+                let streamed = content_type.as_deref().is_some_and(|content_type| {
+                    ["application/x-ndjson", "text/event-stream"]
+                        .iter()
+                        .any(|media_type| content_type.starts_with(media_type))
+                });
+                let body = if streamed {
+                    Bytes::new()
+                } else {
+                    axum::body::to_bytes(resp.into_body(), usize::MAX)
+                        .await
+                        .unwrap()
+                };
+                assert_eq!(
+                    status,
+                    example.status,
+                    "{name}: {}",
+                    String::from_utf8_lossy(&body)
+                );
+
+                let response = &documented["responses"][status.to_string()];
+                assert!(response.is_object(), "{name}: {status} isn't documented");
+                let Some(content) = response.get("content").and_then(|c| c.as_object()) else {
+                    assert!(
+                        body.is_empty(),
+                        "{name}: answered a body the document doesn't have"
+                    );
+                    continue;
+                };
+                let content_type = content_type.unwrap_or_default();
+                let (media_type, media) = content
+                    .iter()
+                    .find(|(media_type, _)| content_type.starts_with(media_type.as_str()))
+                    .unwrap_or_else(|| panic!("{name}: {content_type} isn't documented"));
+                if media_type != "application/json" {
+                    continue;
+                }
+                let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                if let Err(e) = openapi::validate(&document, &media["schema"], &value) {
+                    panic!("{name}: {e} in {value}");
+                }
+                if operation.method == Method::POST && example.caller == Caller::Anonymous {
+                    if operation.path == "/api/urls" {
+                        anonymous_links.push(value["id"].as_i64().unwrap());
+                    }
+                } else if operation.method == Method::POST
+                    && operation.path == "/api/admin/announcements"
+                {
+                    announcements.push(value["id"].as_i64().unwrap());
+                }
+            }
+        }
+
+        let user_ids: Vec<i64> = users.iter().map(|user| *user.id()).collect();
+        let owned: Vec<i64> = sqlx::query_scalar("SELECT id FROM urls WHERE created_by = ANY($1)")
+            .bind(&user_ids)
+            .fetch_all(pool)
+            .await
+            .unwrap();
+        for id in owned.into_iter().chain(anonymous_links) {
+            url_db::delete_url(id, pool).await.unwrap();
+        }
+        sqlx::query("DELETE FROM announcements WHERE id = ANY($1)")
+            .bind(&announcements)
+            .execute(pool)
+            .await
+            .unwrap();
+        for id in user_ids {
+            user::delete_user_from_db(id, pool).await.unwrap();
+        }
+    }
+
+    #[sqlx::test]
+    async fn aliases_are_checked_before_use() {
+        let state = state_init().await;
+        let alias = format!("alias{}", rand::random::<u32>());
+        let create = |alias: &str| {
+            let form = format!("url=https%3A%2F%2Fexample.com%2Faliased&alias={alias}");
+            post_new_url(
+                State(state.clone()),
+                None,
+                HeaderMap::new(),
+                Bytes::from(form),
+            )
+        };
+
+        let resp = create(&alias).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let row = url_db::retrieve_url_obj(&alias, SlugCase::Sensitive, state.pool())
+            .await
+            .unwrap();
+        assert_eq!(row.long_url(), "https://example.com/aliased");
+        // The same destination with another alias is a separate link, not the one above
+        let second = format!("{alias}b");
+        assert_eq!(create(&second).await.status(), StatusCode::OK);
+        let second = url_db::retrieve_url_obj(&second, SlugCase::Sensitive, state.pool())
+            .await
+            .unwrap();
+        assert_ne!(second.id(), row.id());
+
+        let resp = create(&alias).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        assert_eq!(resp.headers()[api_error::ERROR_CODE], "alias_taken");
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, format!("The alias {alias} is already taken"));
+
+        for invalid in [
+            "my%20blog",
+            "index.css",
+            "favicon.ico",
+            ".hidden",
+            "static",
+            "readyz",
+            "healthz",
+            "API",
+            "login",
+            "theme.css",
+            "a%2Fb",
+            &"a".repeat(65),
+        ] {
+            assert_eq!(
+                create(invalid).await.status(),
+                StatusCode::BAD_REQUEST,
+                "{invalid}"
+            );
+        }
+
+        let mut prefs = state.prefs().clone();
+        prefs.set_reserved_codes(vec![String::from("pricing")]);
+        let reserving = state_with(prefs).await;
+        let form = "url=https%3A%2F%2Fexample.com%2Faliased&alias=Pricing";
+        let resp = post_new_url(State(reserving), None, HeaderMap::new(), Bytes::from(form)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[sqlx::test]
+    async fn slugs_can_ignore_case() {
+        let mut prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        prefs.set_case_insensitive_slugs(true);
+        let state = state_with(prefs).await;
+        let alias = format!("CaseAlias{}", rand::random::<u32>());
+        let create = |alias: String| {
+            let form = format!("url=https%3A%2F%2Fexample.com%2Fcased&alias={alias}");
+            post_new_url(
+                State(state.clone()),
+                None,
+                HeaderMap::new(),
+                Bytes::from(form),
+            )
+        };
+        let visit = |code: String| async {
+            consume_short_url(
+                Path(code),
+                State(&state),
+                &HeaderMap::new(),
+                &Method::GET,
+                None,
+            )
+            .await
+        };
+
+        assert_eq!(create(alias.clone()).await.status(), StatusCode::OK);
+        for typed in [alias.clone(), alias.to_lowercase(), alias.to_uppercase()] {
+            let resp = visit(typed.clone()).await;
+            assert!(resp.status().is_redirection(), "{typed}");
+            assert_eq!(resp.headers()[LOCATION], "https://example.com/cased");
+        }
+        let resp = create(alias.to_lowercase()).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let generated = url_db::create_url(
+            "https://example.com/cased",
+            None,
+            state.pool(),
+            CodeRules {
+                case: SlugCase::Insensitive,
+                ..CodeRules::of_len(8)
+            },
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let code = generated.clone_short_url();
+        assert_eq!(code, code.to_lowercase());
+        assert!(visit(code.to_uppercase()).await.status().is_redirection());
+
+        // Off by default, where only the exact code is found
+        let sensitive = state_init().await;
+        let resp = consume_short_url(
+            Path(alias.to_lowercase()),
+            State(&sensitive),
+            &HeaderMap::new(),
+            &Method::GET,
+            None,
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[sqlx::test]
+    async fn api_creates_links_like_the_form() {
+        use tower::ServiceExt;
+
+        let state = state_init().await;
+        let create = |body: String| {
+            let req = axum::http::Request::post("/api/urls")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .unwrap();
+            let app =
+                api_routes(state.features, state.prefs().timeouts()).with_state(state.clone());
+            async move {
+                let resp = app.oneshot(req).await.unwrap();
+                let status = resp.status();
+                let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                (status, body)
+            }
+        };
+        let page = format!("api-{}", rand::random::<u32>());
+
+        let (status, body) = create(format!(
+            r#"{{"url": "HTTPS://Example.com/{page}/", "alias": null}}"#
+        ))
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let code = body["short_url"].as_str().unwrap();
+        assert_eq!(body["long_url"], format!("https://example.com/{page}"));
+        assert_eq!(body["full_url"], state.prefs().full_url(code));
+        let row = url_db::retrieve_url_obj(code, SlugCase::Sensitive, state.pool())
+            .await
+            .unwrap();
+        assert_eq!(body["id"], row.id());
+        // Deduplicated against links made from the form
+        let form = format!("url=https%3A%2F%2Fexample.com%2F{page}");
+        let html = post_new_url(
+            State(state.clone()),
+            None,
+            HeaderMap::new(),
+            Bytes::from(form),
+        )
+        .await;
+        let html = axum::body::to_bytes(html.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(String::from_utf8(html.to_vec())
+            .unwrap()
+            .contains(&format!("<a href=\"{code}\"")));
+
+        let alias = format!("api{}", rand::random::<u32>());
+        let with_alias = format!(r#"{{"url": "https://example.com/{page}", "alias": "{alias}"}}"#);
+        let (status, body) = create(with_alias.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["short_url"], alias.as_str());
+        let (status, body) = create(with_alias).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["error"]["code"], "alias_taken");
+
+        for (invalid, code) in [
+            ("{\"url\": ", "invalid_json"),
+            ("{\"alias\": \"x\"}", "invalid_json"),
+            ("{\"url\": \"\"}", "invalid_url"),
+            ("{\"url\": \"not a url\"}", "invalid_url"),
+            ("{\"url\": \"https://\"}", "invalid_url"),
+            ("{\"url\": \"https://example.invalidtld\"}", "invalid_url"),
+            ("{\"url\": \"mailto:someone@example.com\"}", "invalid_url"),
+            (
+                "{\"url\": \"example.com\", \"alias\": \"a/b\"}",
+                "invalid_alias",
+            ),
+        ] {
+            let (status, body) = create(invalid.to_string()).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{invalid}");
+            assert_eq!(body["error"]["code"], code, "{invalid}");
+        }
+    }
+
+    #[sqlx::test]
+    async fn malformed_forms_are_rejected() {
+        let state = state_init().await;
+        for form in ["alias=abc", "url=+", "url=https%3A%2F%2F"] {
+            let resp = post_new_url(
+                State(state.clone()),
+                None,
+                HeaderMap::new(),
+                Bytes::from(form),
+            )
+            .await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{form}");
+        }
+    }
+
+    #[sqlx::test]
+    async fn links_are_deleted_by_their_owner() {
+        let suffix = rand::random::<u32>();
+        let admin_name = format!("admin-{suffix}");
+        let mut prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        prefs.set_admins(vec![admin_name.clone()]);
+        let state = state_with(prefs).await;
+        let pool = state.pool();
+        let secret = state.prefs().jwt_secret();
+        let mut users = Vec::new();
+        for name in [
+            format!("owner-{suffix}"),
+            format!("other-{suffix}"),
+            admin_name,
+        ] {
+            let user = user::new_user(name, String::from("pw"), String::from("email"), pool)
+                .await
+                .unwrap();
+            users.push(user);
+        }
+        let [owner, other, admin] = &users[..] else {
+            unreachable!()
+        };
+        let options = UrlOptions::default();
+        let owned = url_db::create_url(
+            "https://example.com/owned",
+            Some(*owner.id()),
+            pool,
+            CodeRules::of_len(8),
+            &options,
+        )
+        .await
+        .unwrap();
+        let anonymous = url_db::create_url(
+            "https://example.com/anonymous",
+            None,
+            pool,
+            CodeRules::of_len(8),
+            &options,
+        )
+        .await
+        .unwrap();
+        let delete = |code: &str, headers: HeaderMap| {
+            api_delete_url(
+                State(state.clone()),
+                Path(code.to_string()),
+                Query(DeleteQuery {
+                    purge: false,
+                    dry_run: false,
+                }),
+                headers,
+            )
+        };
+
+        let resp = delete(owned.short_url(), HeaderMap::new()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        for user in [other, admin] {
+            let resp = delete(owned.short_url(), auth_headers(user, secret)).await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        }
+        let resp = delete(anonymous.short_url(), auth_headers(owner, secret)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = delete(owned.short_url(), auth_headers(owner, secret)).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(matches!(
+            url_db::retrieve_url_obj(owned.short_url(), SlugCase::Sensitive, pool).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+        let resp = delete(owned.short_url(), auth_headers(owner, secret)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = delete(anonymous.short_url(), auth_headers(admin, secret)).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        url_db::delete_url(owned.id(), pool).await.unwrap();
+        url_db::delete_url(anonymous.id(), pool).await.unwrap();
+        for user in &users {
+            user::delete_user_from_db(*user.id(), pool).await.unwrap();
+        }
+    }
+
+    #[sqlx::test]
+    async fn archived_links_can_be_restored_or_purged() {
+        let suffix = rand::random::<u32>();
+        let admin_name = format!("purger-{suffix}");
+        let mut prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        prefs.set_admins(vec![admin_name.clone()]);
+        let state = state_with(prefs).await;
+        let pool = state.pool();
+        let secret = state.prefs().jwt_secret();
+        let mut users = Vec::new();
+        for name in [format!("archivist-{suffix}"), admin_name] {
+            let user = user::new_user(name, String::from("pw"), String::from("email"), pool)
+                .await
+                .unwrap();
+            users.push(user);
+        }
+        let [owner, admin] = &users[..] else {
+            unreachable!()
+        };
+        let code = format!("arch{suffix}");
+        let link = url_db::create_url_with_alias(
+            &code,
+            "https://example.com/archived",
+            Some(*owner.id()),
+            pool,
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let delete = |user: &UserRow, purge: bool| {
+            api_delete_url(
+                State(state.clone()),
+                Path(code.clone()),
+                Query(DeleteQuery {
+                    purge,
+                    dry_run: false,
+                }),
+                auth_headers(user, secret),
+            )
+        };
+        let restore = |user: &UserRow| {
+            api_restore_url(
+                State(state.clone()),
+                Path(code.clone()),
+                auth_headers(user, secret),
+            )
+        };
+        let list = |archived: bool| {
+            api_list_urls(
+                State(state.clone()),
+                Query(UrlPageQuery {
+                    page: None,
+                    per_page: None,
+                    tag: None,
+                    archived,
+                }),
+                auth_headers(owner, secret),
+            )
+        };
+        let body = |resp: Response| async move {
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+        let visit = || {
+            subdir_handler(
+                Path(code.clone()),
+                State(state.clone()),
+                None,
+                Method::GET,
+                HeaderMap::new(),
+            )
+        };
+        sqlx::query("UPDATE urls SET clicks = 3 WHERE id = $1")
+            .bind(link.id())
+            .execute(pool)
+            .await
+            .unwrap();
+
+        assert_eq!(restore(owner).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(delete(owner, false).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(visit().await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(body(list(false).await).await["total"], 0);
+        let archived = body(list(true).await).await;
+        assert_eq!(archived["total"], 1);
+        assert_eq!(archived["items"][0]["short_url"], code.as_str());
+        assert!(archived["items"][0]["archived_at"].is_string());
+
+        // The code stays taken while the link is archived
+        let request = ApiNewUrl {
+            url: Some(String::from("https://example.com/squatter")),
+            alias: Some(code.clone()),
+            tags: None,
+            description: None,
+            destinations: Vec::new(),
+            active_from: None,
+            single_use: false,
+        };
+        let resp = api_create_url(
+            State(state.clone()),
+            None,
+            HeaderMap::new(),
+            Ok(Json(request)),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        assert_eq!(restore(admin).await.status(), StatusCode::FORBIDDEN);
+        let resp = restore(owner).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let restored = body(resp).await;
+        assert_eq!(restored["clicks"], 3);
+        assert!(restored.get("archived_at").is_none());
+        assert!(visit().await.status().is_redirection());
+
+        assert_eq!(delete(owner, true).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(delete(owner, false).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(delete(admin, true).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(restore(owner).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(delete(admin, true).await.status(), StatusCode::NOT_FOUND);
+        assert!(!url_db::is_code_taken(&code, SlugCase::Sensitive, pool)
+            .await
+            .unwrap());
+
+        for user in &users {
+            user::delete_user_from_db(*user.id(), pool).await.unwrap();
+        }
+    }
+
+    #[sqlx::test]
+    async fn geo_targeted_links_send_countries_elsewhere() {
+        let mut prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        prefs.set_geo(geo::GeoPrefs::with_country_header("X-Country"));
+        let state = state_with(prefs).await;
+        let pool = state.pool();
+        let secret = state.prefs().jwt_secret();
+        let suffix = rand::random::<u32>();
+        let owner = user::new_user(
+            format!("targeter-{suffix}"),
+            String::from("pw"),
+            String::from("email"),
+            pool,
+        )
+        .await
+        .unwrap();
+        let code = format!("geo{suffix}");
+        let link = url_db::create_url_with_alias(
+            &code,
+            "https://example.com/geo/global",
+            Some(*owner.id()),
+            pool,
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let body = |resp: Response| async move {
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+        let edit = |targets: &[(&str, &str)]| {
+            let geo_targets = targets
+                .iter()
+                .map(|(country, url)| (country.to_string(), url.to_string()))
+                .collect();
+            api_edit_url(
+                State(state.clone()),
+                Path(code.clone()),
+                auth_headers(&owner, secret),
+                Ok(Json(ApiEditUrl {
+                    url: None,
+                    description: None,
+                    destinations: None,
+                    geo_targets: Some(geo_targets),
+                    rollout_percent: None,
+                })),
+            )
+        };
+        let visit = |country: Option<&'static str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(country) = country {
+                headers.insert("x-country", HeaderValue::from_static(country));
+            }
+            subdir_handler(
+                Path(code.clone()),
+                State(state.clone()),
+                None,
+                Method::GET,
+                headers,
+            )
+        };
+        let location = |resp: Response| {
+            assert!(resp.status().is_redirection());
+            resp.headers()[header::LOCATION]
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+
+        for bad in [
+            ("USA", "https://example.com/geo/us"),
+            ("XX", "https://example.com/geo/us"),
+        ] {
+            let resp = edit(&[bad]).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(body(resp).await["error"]["code"], "invalid_geo_targets");
+        }
+        let resp = edit(&[
+            ("us", "https://example.com/geo/us"),
+            ("US", "https://example.com/geo/other"),
+        ])
+        .await;
+        assert_eq!(body(resp).await["error"]["code"], "invalid_geo_targets");
+
+        let resp = edit(&[
+            ("us", "https://example.com/geo/us"),
+            ("DE", "https://example.com/geo/de"),
+        ])
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let edited = body(resp).await;
+        assert_eq!(edited["long_url"], "https://example.com/geo/global");
+        assert_eq!(edited["geo_targets"]["US"], "https://example.com/geo/us");
+
+        let resp = visit(Some("US")).await;
+        // Never kept by the browser, which may be somewhere else next time
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        assert_eq!(location(resp), "https://example.com/geo/us");
+        assert_eq!(
+            location(visit(Some("de")).await),
+            "https://example.com/geo/de"
+        );
+        assert_eq!(
+            location(visit(Some("FR")).await),
+            "https://example.com/geo/global"
+        );
+        assert_eq!(
+            location(visit(None).await),
+            "https://example.com/geo/global"
+        );
+
+        let resp = api_url_stats(
+            State(state.clone()),
+            Path(code.clone()),
+            Query(StatsQuery {
+                from: None,
+                to: None,
+            }),
+            auth_headers(&owner, secret),
+        )
+        .await;
+        let stats = body(resp).await;
+        assert_eq!(stats["clicks"], 4);
+        assert_eq!(stats["geo_targets"]["DE"], "https://example.com/geo/de");
+        assert_eq!(
+            stats["geo_clicks"],
+            serde_json::json!({"DE": 1, "US": 1, "default": 2})
+        );
+
+        let resp = edit(&[]).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(body(resp).await.get("geo_targets").is_none());
+        assert_eq!(
+            location(visit(Some("US")).await),
+            "https://example.com/geo/global"
+        );
+        let edits = history::for_link(link.id(), pool).await.unwrap();
+        let targeted: Vec<_> = edits
+            .iter()
+            .filter(|edit| edit.field == history::GEO_TARGETS)
+            .collect();
+        assert_eq!(targeted.len(), 2);
+        assert_eq!(
+            targeted[0].new_value.as_deref(),
+            Some("DE: https://example.com/geo/de, US: https://example.com/geo/us")
+        );
+
+        url_db::delete_url(link.id(), pool).await.unwrap();
+        user::delete_user_from_db(*owner.id(), pool).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn rotating_links_split_their_visits() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let secret = state.prefs().jwt_secret();
+        let suffix = rand::random::<u32>();
+        let owner = user::new_user(
+            format!("rotator-{suffix}"),
+            String::from("pw"),
+            String::from("email"),
+            pool,
+        )
+        .await
+        .unwrap();
+        let weighted = |urls: &[(&str, i32)]| -> Vec<ApiDestination> {
+            urls.iter()
+                .map(|(url, weight)| ApiDestination {
+                    url: url.to_string(),
+                    weight: *weight,
+                })
+                .collect()
+        };
+        let body = |resp: Response| async move {
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        let create = |url: Option<&str>, destinations: Vec<ApiDestination>| {
+            api_create_url(
+                State(state.clone()),
+                None,
+                HeaderMap::new(),
+                Ok(Json(ApiNewUrl {
+                    url: url.map(String::from),
+                    alias: None,
+                    tags: None,
+                    description: None,
+                    destinations,
+                    active_from: None,
+                    single_use: false,
+                })),
+            )
+        };
+        let one = weighted(&[("https://example.com/rotate/only", 1)]);
+        let resp = create(None, one).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body(resp).await["error"]["code"], "invalid_destinations");
+        let twice = weighted(&[
+            ("https://example.com/rotate/a", 1),
+            ("https://example.com/rotate/a", 2),
+        ]);
+        let resp = create(None, twice).await;
+        assert_eq!(body(resp).await["error"]["code"], "invalid_destinations");
+        let heavy = weighted(&[
+            ("https://example.com/rotate/a", 1),
+            ("https://example.com/rotate/b", url_db::MAX_WEIGHT + 1),
+        ]);
+        let resp = create(None, heavy).await;
+        assert_eq!(body(resp).await["error"]["code"], "invalid_destinations");
+        let both = weighted(&[
+            ("https://example.com/rotate/a", 1),
+            ("https://example.com/rotate/b", 1),
+        ]);
+        let resp = create(Some("https://example.com/rotate/a"), both).await;
+        assert_eq!(body(resp).await["error"]["code"], "conflicting_options");
+
+        let resp = create(
+            None,
+            weighted(&[
+                ("https://example.com/rotate/a", 3),
+                ("https://example.com/rotate/b", 1),
+            ]),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let created = body(resp).await;
+        let anonymous = created["short_url"].as_str().unwrap().to_string();
+        assert_eq!(created["destinations"].as_array().unwrap().len(), 2);
+        assert_eq!(created["destinations"][0]["weight"], 3);
+
+        let code = format!("rot{suffix}");
+        let link = url_db::create_url_with_alias(
+            &code,
+            "https://example.com/rotate/single",
+            Some(*owner.id()),
+            pool,
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let edit = |url: Option<&str>, destinations: Option<Vec<ApiDestination>>| {
+            api_edit_url(
+                State(state.clone()),
+                Path(code.clone()),
+                auth_headers(&owner, secret),
+                Ok(Json(ApiEditUrl {
+                    url: url.map(String::from),
+                    description: None,
+                    destinations,
+                    geo_targets: None,
+                    rollout_percent: None,
+                })),
+            )
+        };
+        let split = weighted(&[
+            ("https://example.com/rotate/c", 1),
+            ("https://example.com/rotate/d", 1),
+        ]);
+        let resp = edit(Some("https://example.com/rotate/e"), Some(split)).await;
+        assert_eq!(body(resp).await["error"]["code"], "conflicting_options");
+        let split = weighted(&[
+            ("https://example.com/rotate/c", 1),
+            ("https://example.com/rotate/d", 1),
+        ]);
+        let resp = edit(None, Some(split)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            body(resp).await["destinations"][1]["long_url"],
+            "https://example.com/rotate/d"
+        );
+
+        let visits = 20;
+        for _ in 0..visits {
+            let resp = subdir_handler(
+                Path(code.clone()),
+                State(state.clone()),
+                None,
+                Method::GET,
+                HeaderMap::new(),
+            )
+            .await;
+            assert_eq!(resp.status(), StatusCode::FOUND);
+            let location = resp.headers()[header::LOCATION].to_str().unwrap();
+            assert!(
+                [
+                    "https://example.com/rotate/c",
+                    "https://example.com/rotate/d"
+                ]
+                .contains(&location),
+                "{location}"
+            );
+        }
+        let resp = api_url_stats(
+            State(state.clone()),
+            Path(code.clone()),
+            Query(StatsQuery {
+                from: None,
+                to: None,
+            }),
+            auth_headers(&owner, secret),
+        )
+        .await;
+        let stats = body(resp).await;
+        assert_eq!(stats["clicks"], visits);
+        let split: i64 = stats["destinations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|destination| destination["clicks"].as_i64().unwrap())
+            .sum();
+        assert_eq!(split, visits);
+
+        // A single destination again stops the rotation
+        let resp = edit(Some("https://example.com/rotate/e"), None).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(body(resp).await.get("destinations").is_none());
+        assert!(url_db::destinations(link.id(), pool)
+            .await
+            .unwrap()
+            .is_empty());
+        let edits = history::for_link(link.id(), pool).await.unwrap();
+        assert!(edits.iter().any(|edit| edit.field == history::DESTINATIONS));
+
+        let anonymous = url_db::retrieve_url_obj(&anonymous, SlugCase::Sensitive, pool)
+            .await
+            .unwrap();
+        for id in [link.id(), anonymous.id()] {
+            url_db::delete_url(id, pool).await.unwrap();
+        }
+        user::delete_user_from_db(*owner.id(), pool).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn new_urls_roll_out_to_part_of_a_links_visitors() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let secret = state.prefs().jwt_secret();
+        let suffix = rand::random::<u32>();
+        let owner = user::new_user(
+            format!("roller-{suffix}"),
+            String::from("pw"),
+            String::from("email"),
+            pool,
+        )
+        .await
+        .unwrap();
+        let code = format!("roll{suffix}");
+        let link = url_db::create_url_with_alias(
+            &code,
+            "https://example.com/rollout/old",
+            Some(*owner.id()),
+            pool,
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let body = |resp: Response| async move {
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+        let edit = |url: Option<&str>, rollout_percent: Option<i32>| {
+            api_edit_url(
+                State(state.clone()),
+                Path(code.clone()),
+                auth_headers(&owner, secret),
+                Ok(Json(ApiEditUrl {
+                    url: url.map(String::from),
+                    description: None,
+                    destinations: None,
+                    geo_targets: None,
+                    rollout_percent,
+                })),
+            )
+        };
+        let commit = || {
+            api_commit_rollout(
+                State(state.clone()),
+                Path(code.clone()),
+                auth_headers(&owner, secret),
+            )
+        };
+        let weights = |body: &serde_json::Value| -> Vec<(String, i64)> {
+            body["destinations"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|d| {
+                    let long_url = d["long_url"].as_str().unwrap().to_string();
+                    (long_url, d["weight"].as_i64().unwrap())
+                })
+                .collect()
+        };
+
+        let resp = edit(None, Some(10)).await;
+        assert_eq!(body(resp).await["error"]["code"], "invalid_rollout");
+        for percent in [0, 100] {
+            let resp = edit(Some("https://example.com/rollout/new"), Some(percent)).await;
+            assert_eq!(body(resp).await["error"]["code"], "invalid_rollout");
+        }
+        let resp = commit().await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let resp = edit(Some("https://example.com/rollout/new"), Some(10)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let started = body(resp).await;
+        // Most visitors still go where the link went
+        assert_eq!(started["long_url"], "https://example.com/rollout/old");
+        assert_eq!(
+            weights(&started),
+            [
+                (String::from("https://example.com/rollout/old"), 90),
+                (String::from("https://example.com/rollout/new"), 10)
+            ]
+        );
+        let until = started["rollout_until"].clone();
+        let deadline: DateTime<Utc> = serde_json::from_value(until.clone()).unwrap();
+        let expected = state.prefs().rollout().deadline(Utc::now());
+        assert!((expected - deadline).num_seconds().abs() < 60, "{deadline}");
+
+        let resp = subdir_handler(
+            Path(code.clone()),
+            State(state.clone()),
+            None,
+            Method::GET,
+            HeaderMap::new(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+
+        // Changing the share keeps each arm's clicks and when the rollout finishes
+        let resp = edit(None, Some(40)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let adjusted = body(resp).await;
+        assert_eq!(
+            weights(&adjusted),
+            [
+                (String::from("https://example.com/rollout/old"), 60),
+                (String::from("https://example.com/rollout/new"), 40)
+            ]
+        );
+        assert_eq!(adjusted["rollout_until"], until);
+        let resp = api_url_stats(
+            State(state.clone()),
+            Path(code.clone()),
+            Query(StatsQuery {
+                from: None,
+                to: None,
+            }),
+            auth_headers(&owner, secret),
+        )
+        .await;
+        let stats = body(resp).await;
+        assert_eq!(stats["rollout_until"], until);
+        let split: i64 = stats["destinations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|destination| destination["clicks"].as_i64().unwrap())
+            .sum();
+        assert_eq!(split, 1);
+
+        let resp = commit().await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let committed = body(resp).await;
+        assert_eq!(committed["long_url"], "https://example.com/rollout/new");
+        assert!(committed.get("destinations").is_none());
+        assert!(committed.get("rollout_until").is_none());
+        assert!(url_db::destinations(link.id(), pool)
+            .await
+            .unwrap()
+            .is_empty());
+        let resp = commit().await;
+        assert_eq!(body(resp).await["error"]["code"], "not_rolling_out");
+        let edits = history::for_link(link.id(), pool).await.unwrap();
+        let last = edits.last().unwrap();
+        assert_eq!(last.field, history::DESTINATION);
+        assert_eq!(
+            last.new_value.as_deref(),
+            Some("https://example.com/rollout/new")
+        );
+
+        url_db::delete_url(link.id(), pool).await.unwrap();
+        user::delete_user_from_db(*owner.id(), pool).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn rollouts_that_are_due_finish_on_their_own() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let suffix = rand::random::<u32>();
+        let now = Utc::now();
+        let mut links = Vec::new();
+        for (name, until) in [
+            ("due", now - chrono::TimeDelta::hours(1)),
+            ("later", now + chrono::TimeDelta::days(1)),
+        ] {
+            let link = url_db::create_url_with_alias(
+                &format!("{name}{suffix}"),
+                &format!("https://example.com/{name}/old"),
+                None,
+                pool,
+                &UrlOptions::default(),
+            )
+            .await
+            .unwrap();
+            let arms = url_db::rollout_arms(
+                link.long_url(),
+                &format!("https://example.com/{name}/new"),
+                25,
+            );
+            let mut conn = pool.acquire().await.unwrap();
+            url_db::start_rollout(link.id(), &arms, until, &mut conn)
+                .await
+                .unwrap();
+            links.push(link);
+        }
+
+        assert!(rollout::finish_due(now, pool).await.unwrap() >= 1);
+        let due = url_db::retrieve_url_obj(links[0].short_url(), SlugCase::Sensitive, pool)
+            .await
+            .unwrap();
+        assert_eq!(due.long_url(), "https://example.com/due/new");
+        assert!(!due.rotates());
+        assert_eq!(due.rollout_until(), None);
+        let later = url_db::retrieve_url_obj(links[1].short_url(), SlugCase::Sensitive, pool)
+            .await
+            .unwrap();
+        assert_eq!(later.long_url(), "https://example.com/later/old");
+        assert!(later.rotates());
+        assert!(later.rollout_until().is_some());
+
+        for link in links {
+            url_db::delete_url(link.id(), pool).await.unwrap();
+        }
+    }
+
+    #[sqlx::test]
+    async fn smoke_runs_pass_against_a_running_app() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let secret = state.prefs().jwt_secret();
+        let owner = user::new_user(
+            format!("smoker-{}", rand::random::<u32>()),
+            String::from("pw"),
+            String::from("email"),
+            pool,
+        )
+        .await
+        .unwrap();
+        let app = site_routes(state.prefs().timeouts())
+            .merge(api_routes(state.features, state.prefs().timeouts()))
+            .with_state(state.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+        });
+        let payload = JwtPayload::new(
+            *owner.id(),
+            owner.username().to_string(),
+            owner.email().to_string(),
+            unix_now(),
+        );
+        let token = Jwt::new(JwtHeader::defaults(), payload).finalize(secret);
+
+        let steps = smoke::run(&base_url, &token).await;
+        assert_eq!(steps.len(), 7);
+        assert!(steps.iter().all(smoke::Step::passed), "{steps:#?}");
+        // The run's link is archived, as the user isn't an admin
+        let live = url_db::retrieve_urls_by_owner(OwnerFilter::User(*owner.id()), pool)
+            .await
+            .unwrap();
+        assert!(live.iter().all(|row| row.deleted_at().is_some()));
+
+        let steps = smoke::run(&base_url, "not-a-token").await;
+        let failed: Vec<String> = steps
+            .iter()
+            .filter(|step| !step.passed())
+            .map(|step| step.to_string())
+            .collect();
+        assert_eq!(failed.len(), 4, "{failed:#?}");
+        assert!(failed[0].starts_with("FAIL create"), "{failed:#?}");
+
+        let made: Vec<i64> = sqlx::query_scalar("SELECT id FROM urls WHERE created_by = $1")
+            .bind(*owner.id())
+            .fetch_all(pool)
+            .await
+            .unwrap();
+        assert_eq!(made.len(), 1);
+        for id in made {
+            url_db::delete_url(id, pool).await.unwrap();
+        }
+        user::delete_user_from_db(*owner.id(), pool).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn users_list_their_links_a_page_at_a_time() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let secret = state.prefs().jwt_secret();
+        let name = format!("lister-{}", rand::random::<u32>());
+        let user = user::new_user(name, String::from("pw"), String::from("email"), pool)
+            .await
+            .unwrap();
+        let mut codes = Vec::new();
+        for n in 0..3 {
+            let row = url_db::create_url(
+                &format!("https://example.com/{n}"),
+                Some(*user.id()),
+                pool,
+                CodeRules::of_len(8),
+                &UrlOptions::default(),
+            )
+            .await
+            .unwrap();
+            codes.push(row.clone_short_url());
+        }
+        codes.reverse();
+        let list = |page, per_page, headers| {
+            api_list_urls(
+                State(state.clone()),
+                Query(UrlPageQuery {
+                    page,
+                    per_page,
+                    tag: None,
+                    archived: false,
+                }),
+                headers,
+            )
+        };
+        let body = |resp: Response| async move {
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+        let codes_in = |body: &serde_json::Value| -> Vec<String> {
+            body["items"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|item| item["short_url"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        let resp = list(None, None, HeaderMap::new()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let first = body(list(Some(1), Some(2), auth_headers(&user, secret)).await).await;
+        assert_eq!(codes_in(&first), codes[..2]);
+        assert_eq!(first["total"], 3);
+        let second = body(list(Some(2), Some(2), auth_headers(&user, secret)).await).await;
+        assert_eq!(codes_in(&second), codes[2..]);
+        assert_eq!(second["items"][0]["long_url"], "https://example.com/0");
+
+        let capped = body(list(None, Some(1000), auth_headers(&user, secret)).await).await;
+        assert_eq!(capped["per_page"], url_db::MAX_PAGE_SIZE);
+        assert_eq!(capped["page"], 1);
+        assert_eq!(codes_in(&capped), codes);
+
+        user::delete_user_from_db(*user.id(), pool).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn owners_see_link_stats() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let secret = state.prefs().jwt_secret();
+        let suffix = rand::random::<u32>();
+        let mut users = Vec::new();
+        for name in [format!("stats-{suffix}"), format!("nosy-{suffix}")] {
+            let user = user::new_user(name, String::from("pw"), String::from("email"), pool)
+                .await
+                .unwrap();
+            users.push(user);
+        }
+        let [owner, other] = &users[..] else {
+            unreachable!()
+        };
+        let mut link = url_db::create_url(
+            "https://example.com/stats",
+            Some(*owner.id()),
+            pool,
+            CodeRules::of_len(8),
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert!(link.created_at().is_some());
+        url_db::incr_url_clicks(&mut link, &ClickSource::default(), pool)
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                header::REFERER,
+                HeaderValue::from_static("https://news.example.com/item?id=42"),
+            );
+            headers.insert(
+                header::USER_AGENT,
+                HeaderValue::from_static("Mozilla/5.0 (iPhone; CPU iPhone OS 18_0 like Mac OS X)"),
+            );
+            let resp = consume_short_url(
+                Path(link.clone_short_url()),
+                State(&state),
+                &headers,
+                &Method::GET,
+                None,
+            )
+            .await;
+            assert!(resp.status().is_redirection());
+        }
+        let stats = |code: &str, headers: HeaderMap| {
+            api_url_stats(
+                State(state.clone()),
+                Path(code.to_string()),
+                Query(StatsQuery {
+                    from: None,
+                    to: None,
+                }),
+                headers,
+            )
+        };
+
+        let resp = stats(link.short_url(), auth_headers(owner, secret)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["clicks"], 3);
+        assert_eq!(body["long_url"], "https://example.com/stats");
+        assert_eq!(body["protected"], false);
+        let created_at: DateTime<Utc> = body["created_at"].as_str().unwrap().parse().unwrap();
+        assert_eq!(Some(created_at), link.created_at());
+        let daily = body["daily"].as_array().unwrap();
+        assert_eq!(daily.len(), 30);
+        assert_eq!(daily[29]["day"], Utc::now().date_naive().to_string());
+        assert_eq!(daily[29]["count"], 3);
+        assert_eq!(daily[0]["count"], 0);
+        assert_eq!(
+            body["top_referrers"],
+            serde_json::json!([
+                {"referrer": "https://news.example.com", "count": 2},
+                {"referrer": "direct", "count": 1},
+            ])
+        );
+        // The click counted straight through the database has no device
+        assert_eq!(
+            body["devices"],
+            serde_json::json!({"bot": 0, "desktop": 0, "mobile": 2, "other": 0})
+        );
+        assert_eq!(body["conversions"], 0);
+        assert_eq!(body["conversion_rate"], 0.0);
+
+        let resp = stats(link.short_url(), auth_headers(other, secret)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = stats(link.short_url(), HeaderMap::new()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = stats("no-such-code", auth_headers(owner, secret)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        for user in &users {
+            user::delete_user_from_db(*user.id(), pool).await.unwrap();
+        }
+    }
+
+    #[sqlx::test]
+    async fn conversion_pixel_credits_recent_clicks() {
+        let mut prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        prefs.set_features(toml::from_str("conversions = true").unwrap());
+        prefs.set_conversions(conversion::ConversionPrefs::with_window(60 * 60));
+        let state = state_with(prefs).await;
+        let pool = state.pool();
+        let link = url_db::create_url(
+            "https://example.com/shop",
+            None,
+            pool,
+            CodeRules::of_len(8),
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let mut browser = HeaderMap::new();
+        browser.insert(header::USER_AGENT, HeaderValue::from_static("Browser"));
+        let visitor: SocketAddr = "203.0.113.7:50000".parse().unwrap();
+        let click = || {
+            consume_short_url(
+                Path(link.clone_short_url()),
+                State(&state),
+                &browser,
+                &Method::GET,
+                client_address(state.prefs(), Some(ConnectInfo(visitor)), &browser),
+            )
+        };
+        let pixel = |file: String, from: SocketAddr| {
+            conversion_pixel(
+                Path(file),
+                State(state.clone()),
+                Some(ConnectInfo(from)),
+                browser.clone(),
+            )
+        };
+        let file = format!("{}.gif", link.short_url());
+        let conversions = || conversion::count(link.id(), pool);
+
+        assert!(click().await.status().is_redirection());
+        let resp = pixel(file.clone(), visitor).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "image/gif");
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, conversion::PIXEL[..]);
+        assert_eq!(conversions().await.unwrap(), 1);
+        // A click converts once, and only for whoever made it
+        pixel(file.clone(), visitor).await;
+        let elsewhere: SocketAddr = "203.0.113.8:50000".parse().unwrap();
+        pixel(file.clone(), elsewhere).await;
+        assert_eq!(conversions().await.unwrap(), 1);
+
+        // Outside the window
+        assert!(click().await.status().is_redirection());
+        sqlx::query!(
+            "UPDATE url_click_visitors SET clicked_at = now() - interval '2 hours' WHERE url_id = $1",
+            link.id()
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+        pixel(file.clone(), visitor).await;
+        assert_eq!(conversions().await.unwrap(), 1);
+
+        let resp = pixel(format!("missing{}.gif", rand::random::<u32>()), visitor).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "image/gif");
+    }
+
+    #[sqlx::test]
+    async fn anonymized_clients_are_still_told_apart() {
+        let mut prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        prefs.set_features(toml::from_str("conversions = true").unwrap());
+        prefs.set_conversions(conversion::ConversionPrefs::with_window(60 * 60));
+        prefs.set_rate_limits(toml::from_str("anonymous_per_minute = 2").unwrap());
+        prefs.set_trust_proxy(true);
+        prefs.set_anonymize_clients(true);
+        let state = state_with(prefs).await;
+        let pool = state.pool();
+        let from = |address: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::USER_AGENT, HeaderValue::from_static("Browser"));
+            headers.insert("x-forwarded-for", HeaderValue::from_static(address));
+            headers
+        };
+        let link = url_db::create_url(
+            "https://example.com/private",
+            None,
+            pool,
+            CodeRules::of_len(8),
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let visit = |headers| {
+            subdir_handler(
+                Path(link.clone_short_url()),
+                State(state.clone()),
+                None,
+                Method::GET,
+                headers,
+            )
+        };
+        let pixel = |headers| {
+            conversion_pixel(
+                Path(format!("{}.gif", link.short_url())),
+                State(state.clone()),
+                None,
+                headers,
+            )
+        };
+
+        // A reload is still a repeat, and the pixel still finds the click it follows
+        assert!(visit(from("203.0.113.7")).await.status().is_redirection());
+        assert!(visit(from("203.0.113.7")).await.status().is_redirection());
+        pixel(from("203.0.113.8")).await;
+        assert_eq!(conversion::count(link.id(), pool).await.unwrap(), 0);
+        pixel(from("203.0.113.7")).await;
+        assert_eq!(conversion::count(link.id(), pool).await.unwrap(), 1);
+        let row = url_db::retrieve_url_obj(link.short_url(), SlugCase::Sensitive, pool)
+            .await
+            .unwrap();
+        assert_eq!(row.clicks(), 1);
+
+        let stored: Vec<String> =
+            sqlx::query_scalar("SELECT visitor FROM url_click_visitors WHERE url_id = $1")
+                .bind(link.id())
+                .fetch_all(pool)
+                .await
+                .unwrap();
+        let unhashed = conversion::visitor_id(
+            state.prefs().jwt_secret(),
+            Utc::now().date_naive(),
+            client_id::ClientId::Address("203.0.113.7".parse().unwrap()),
+            &from("203.0.113.7"),
+        );
+        assert_eq!(stored.len(), 1);
+        assert_ne!(stored[0], unhashed);
+        assert!(!stored[0].contains("203.0.113.7"));
+
+        let shorten = |headers| {
+            post_new_url(
+                State(state.clone()),
+                None,
+                headers,
+                Bytes::from(format!(
+                    "url=https%3A%2F%2Fexample.com%2Fanonymous-{}",
+                    rand::random::<u32>()
+                )),
+            )
+        };
+        assert_eq!(shorten(from("203.0.113.7")).await.status(), StatusCode::OK);
+        assert_eq!(shorten(from("203.0.113.7")).await.status(), StatusCode::OK);
+        let resp = shorten(from("203.0.113.7")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(shorten(from("203.0.113.8")).await.status(), StatusCode::OK);
+        url_db::delete_url(link.id(), pool).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn conversions_are_off_by_default() {
+        let state = state_init().await;
+        assert!(!state.features.enabled(Feature::Conversions));
+        let pool = state.pool();
+        let link = url_db::create_url(
+            "https://example.com/private",
+            None,
+            pool,
+            CodeRules::of_len(8),
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let visitor: SocketAddr = "203.0.113.7:50000".parse().unwrap();
+        let resp = consume_short_url(
+            Path(link.clone_short_url()),
+            State(&state),
+            &HeaderMap::new(),
+            &Method::GET,
+            client_address(state.prefs(), Some(ConnectInfo(visitor)), &HeaderMap::new()),
+        )
+        .await;
+        assert!(resp.status().is_redirection());
+        let resp = conversion_pixel(
+            Path(format!("{}.gif", link.short_url())),
+            State(state.clone()),
+            Some(ConnectInfo(visitor)),
+            HeaderMap::new(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "image/gif");
+
+        let remembered = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!" FROM url_click_visitors WHERE url_id = $1"#,
+            link.id()
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        assert_eq!(remembered, 0);
+        assert_eq!(conversion::count(link.id(), pool).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn stats_ranges_are_bounded() {
+        let today: NaiveDate = "2024-03-10".parse().unwrap();
+        let range = |from: Option<&str>, to: Option<&str>| {
+            StatsQuery {
+                from: from.map(|d| d.parse().unwrap()),
+                to: to.map(|d| d.parse().unwrap()),
+            }
+            .range(today)
+            .map_err(|e| e.into_response().status())
+        };
+        let date = |d: &str| d.parse::<NaiveDate>().unwrap();
+        assert_eq!(
+            range(None, None),
+            Ok((date("2024-02-10"), date("2024-03-10")))
+        );
+        assert_eq!(
+            range(None, Some("2024-01-31")),
+            Ok((date("2024-01-02"), date("2024-01-31")))
+        );
+        assert_eq!(range(Some("2024-03-10"), None), Ok((today, today)));
+        assert_eq!(
+            range(Some("2024-03-11"), None),
+            Err(StatusCode::BAD_REQUEST)
+        );
+        assert_eq!(
+            range(Some("2023-01-01"), Some("2024-03-10")),
+            Err(StatusCode::BAD_REQUEST)
+        );
+    }
+
+    #[sqlx::test]
+    async fn admins_manage_the_announcement_banner() {
+        use tower::ServiceExt;
+
+        let suffix = rand::random::<u32>();
+        let admin_name = format!("announcer-{suffix}");
+        let mut prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        prefs.set_admins(vec![admin_name.clone()]);
+        let state = state_with(prefs).await;
+        let pool = state.pool();
+        let secret = state.prefs().jwt_secret();
+        let mut users = Vec::new();
+        for name in [admin_name, format!("reader-{suffix}")] {
+            let user = user::new_user(name, String::from("pw"), String::from("email"), pool)
+                .await
+                .unwrap();
+            users.push(user);
+        }
+        let [admin, reader] = &users[..] else {
+            unreachable!()
+        };
+        let input = |body: serde_json::Value| Ok(Json(serde_json::from_value(body).unwrap()));
+        let banner = |headers: HeaderMap| {
+            let state = state.clone();
+            async move {
+                let resp = announcement_banner(State(state), headers).await;
+                let status = resp.status();
+                let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                (status, String::from_utf8(body.to_vec()).unwrap())
+            }
+        };
+        let message = format!("Maintenance tonight {suffix}");
+
+        let resp = admin_create_announcement(
+            State(state.clone()),
+            auth_headers(reader, secret),
+            input(serde_json::json!({"message": "nope", "severity": "info"})),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = admin_create_announcement(
+            State(state.clone()),
+            auth_headers(admin, secret),
+            input(serde_json::json!({"message": " ", "severity": "info"})),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // Cached before anything is announced, the new announcement has to replace that
+        assert_eq!(banner(HeaderMap::new()).await.0, StatusCode::NO_CONTENT);
+        let resp = admin_create_announcement(
+            State(state.clone()),
+            auth_headers(admin, secret),
+            input(serde_json::json!({
+                "message": message,
+                "severity": "warning",
+                "public": true,
+            })),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let id = created["id"].as_i64().unwrap();
+        let (status, html) = banner(HeaderMap::new()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(html.contains(&message));
+        assert!(html.contains("announcement-warning"));
+
+        // Edits show up right away
+        let edited = format!("Maintenance moved {suffix}");
+        let resp = admin_update_announcement(
+            State(state.clone()),
+            Path(id),
+            auth_headers(admin, secret),
+            input(serde_json::json!({
+                "message": edited,
+                "severity": "critical",
+                "public": false,
+            })),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(banner(HeaderMap::new()).await.0, StatusCode::NO_CONTENT);
+        let (status, html) = banner(auth_headers(reader, secret)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(html.contains(&edited));
+        assert!(html.contains("announcement-critical"));
+
+        // Dismissing it keeps it away for that visitor
+        let mut headers = auth_headers(reader, secret);
+        headers.insert("HX-Request", HeaderValue::from_static("true"));
+        let resp = dismiss_announcement(Path(id), headers).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let cookie = resp.headers()[SET_COOKIE].to_str().unwrap();
+        let dismissed = cookie.split(';').next().unwrap();
+        let mut headers = auth_headers(reader, secret);
+        let cookies = format!("{}; {dismissed}", headers[COOKIE].to_str().unwrap());
+        headers.insert(COOKIE, HeaderValue::from_str(&cookies).unwrap());
+        assert_eq!(banner(headers).await.0, StatusCode::NO_CONTENT);
+        let resp = dismiss_announcement(Path(id), HeaderMap::new()).await;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+
+        // JSON routes are left alone
+        let resp = site_routes(state.prefs().timeouts())
+            .merge(api_routes(state.features, state.prefs().timeouts()))
+            .with_state(state.clone())
+            .oneshot(
+                axum::http::Request::get("/api/features")
+                    .header(COOKIE, auth_headers(reader, secret)[COOKIE].clone())
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(serde_json::from_slice::<serde_json::Value>(&body).is_ok());
+        assert!(!String::from_utf8_lossy(&body).contains("announcement"));
+
+        let resp = admin_delete_announcement(
+            State(state.clone()),
+            Path(id),
+            Query(DryRunQuery { dry_run: false }),
+            auth_headers(admin, secret),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            banner(auth_headers(reader, secret)).await.0,
+            StatusCode::NO_CONTENT
+        );
+        let resp = admin_delete_announcement(
+            State(state.clone()),
+            Path(id),
+            Query(DryRunQuery { dry_run: false }),
+            auth_headers(admin, secret),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        for user in &users {
+            user::delete_user_from_db(*user.id(), pool).await.unwrap();
+        }
+    }
+
+    #[sqlx::test]
+    async fn http_listener_answers_only_exempt_paths() {
+        use tower::ServiceExt;
+
+        let state = state_init().await;
+        let over_http = |prefs: https::HttpsPrefs, path: &str| {
+            let redirect = Arc::new(https::Redirect::new(&prefs, "Short.Example", 443));
+            let app = site_routes(state.prefs().timeouts())
+                .merge(api_routes(state.features, state.prefs().timeouts()))
+                .with_state(state.clone())
+                .layer(middleware::from_fn_with_state(redirect, https::to_https));
+            app.oneshot(axum::http::Request::get(path).body(Body::empty()).unwrap())
+        };
+
+        for path in ["/healthz", "/readyz"] {
+            let resp = over_http(https::HttpsPrefs::default(), path).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK, "{path}");
+            assert!(resp.headers().get(LOCATION).is_none(), "{path}");
+        }
+        for (path, location) in [
+            ("/abc123", "https://short.example/abc123"),
+            (
+                "/abc123?utm_source=qr&x=1",
+                "https://short.example/abc123?utm_source=qr&x=1",
+            ),
+            ("/", "https://short.example/"),
+            // Only the exact path is exempt, not lookalikes of it
+            ("/healthz-fake", "https://short.example/healthz-fake"),
+            (
+                "/.well-known/acme-challenge/abc-123",
+                "https://short.example/.well-known/acme-challenge/abc-123",
+            ),
+        ] {
+            let resp = over_http(https::HttpsPrefs::default(), path).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT, "{path}");
+            assert_eq!(resp.headers()[LOCATION], location, "{path}");
+        }
+
+        // Ones ending in a slash cover everything under them
+        let mut prefs = https::HttpsPrefs::default();
+        prefs.set_redirect_exempt(vec![String::from("/static/")]);
+        let resp = over_http(prefs.clone(), "/static/404.css").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = over_http(prefs, "/healthz").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+    }
+
+    #[sqlx::test]
+    async fn static_files_are_routed_apart_from_codes() {
+        use tower::ServiceExt;
+
+        let fetch = |state: &Arc<PoolAndPrefs>, path: String| {
+            let app = site_routes(state.prefs().timeouts()).with_state(state.clone());
+            app.oneshot(axum::http::Request::get(path).body(Body::empty()).unwrap())
+        };
+        let state = state_init().await;
+        let alias = format!("docs.v{}", rand::random::<u32>());
+        assert_eq!(alias_problem(&alias, &[]), None);
+        url_db::create_url_with_alias(
+            &alias,
+            "https://example.com/dotted",
+            None,
+            state.pool(),
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let resp = fetch(&state, format!("/{alias}")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(resp.headers()[LOCATION], "https://example.com/dotted");
+
+        // Any extension is served under /static, known or not
+        let unknown = format!("routing-{}.unknownext", rand::random::<u32>());
+        fs::write(format!("html/{unknown}"), "contents").unwrap();
+        let resp = fetch(&state, format!("/static/{unknown}")).await;
+        fs::remove_file(format!("html/{unknown}")).unwrap();
+        let resp = resp.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/octet-stream");
+        let resp = fetch(&state, String::from("/static/../config.toml"))
+            .await
+            .unwrap();
+        assert_ne!(resp.status(), StatusCode::OK);
+
+        // Old paths keep working, by default by serving the file
+        let resp = fetch(&state, String::from("/index.css")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "text/css");
+        let mut prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        prefs.set_legacy_asset_paths(assets::LegacyAssetPaths::Redirect);
+        let redirecting = state_with(prefs.clone()).await;
+        let resp = fetch(&redirecting, String::from("/index.css"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(resp.headers()[LOCATION], "/static/index.css");
+        prefs.set_legacy_asset_paths(assets::LegacyAssetPaths::Off);
+        let off = state_with(prefs).await;
+        let resp = fetch(&off, String::from("/index.css")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // Nothing but short codes needs the database
+        state.pool().close().await;
+        for path in [
+            "/static/index.css",
+            "/favicon.ico",
+            "/robots.txt",
+            "/404.html",
+        ] {
+            let resp = fetch(&state, String::from(path)).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK, "{path}");
+        }
+        let resp = fetch(&state, format!("/{alias}")).await.unwrap();
+        assert_ne!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+    }
+
+    #[sqlx::test]
+    async fn owners_can_change_where_links_go() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let secret = state.prefs().jwt_secret();
+        let suffix = rand::random::<u32>();
+        let mut users = Vec::new();
+        for name in [format!("editor-{suffix}"), format!("bystander-{suffix}")] {
+            let user = user::new_user(name, String::from("pw"), String::from("email"), pool)
+                .await
+                .unwrap();
+            users.push(user);
+        }
+        let [owner, other] = &users[..] else {
+            unreachable!()
+        };
+        let options = UrlOptions::default();
+        let mut link = url_db::create_url(
+            "https://example.com/before",
+            Some(*owner.id()),
+            pool,
+            CodeRules::of_len(8),
+            &options,
+        )
+        .await
+        .unwrap();
+        let anonymous = url_db::create_url(
+            "https://example.com/anonymous",
+            None,
+            pool,
+            CodeRules::of_len(8),
+            &options,
+        )
+        .await
+        .unwrap();
+        url_db::incr_url_clicks(&mut link, &ClickSource::default(), pool)
+            .await
+            .unwrap();
+        let edit = |code: &str, user: &UserRow, url: &str| {
+            let request = Ok(Json(ApiEditUrl {
+                url: Some(url.to_string()),
+                description: None,
+                destinations: None,
+                geo_targets: None,
+                rollout_percent: None,
+            }));
+            api_edit_url(
+                State(state.clone()),
+                Path(code.to_string()),
+                auth_headers(user, secret),
+                request,
+            )
+        };
+
+        let resp = edit(link.short_url(), owner, " HTTPS://Example.com/after/ ").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["short_url"], link.short_url().as_str());
+        assert_eq!(body["long_url"], "https://example.com/after");
+        assert_eq!(body["clicks"], 1);
+        let row = url_db::retrieve_url_obj(link.short_url(), SlugCase::Sensitive, pool)
+            .await
+            .unwrap();
+        assert_eq!(row.long_url(), "https://example.com/after");
+        assert_eq!(row.clicks(), 1);
+
+        for (code, user, url, status) in [
+            (
+                link.short_url().as_str(),
+                owner,
+                "not a url",
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                link.short_url(),
+                other,
+                "https://example.com/mine",
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                anonymous.short_url(),
+                owner,
+                "https://example.com/mine",
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                "nosuchcode.x",
+                owner,
+                "https://example.com/mine",
+                StatusCode::NOT_FOUND,
+            ),
+        ] {
+            assert_eq!(edit(code, user, url).await.status(), status, "{code} {url}");
+        }
+        let row = url_db::retrieve_url_obj(link.short_url(), SlugCase::Sensitive, pool)
+            .await
+            .unwrap();
+        assert_eq!(row.long_url(), "https://example.com/after");
+
+        url_db::delete_url(link.id(), pool).await.unwrap();
+        url_db::delete_url(anonymous.id(), pool).await.unwrap();
+        for user in &users {
+            user::delete_user_from_db(*user.id(), pool).await.unwrap();
+        }
+    }
+
+    #[sqlx::test]
+    async fn link_history_lists_each_change() {
+        let suffix = rand::random::<u32>();
+        let names = ["first", "second", "outsider"].map(|name| format!("{name}-{suffix}"));
+        let mut prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        prefs.set_admins(names[..2].to_vec());
+        let state = state_with(prefs).await;
+        let pool = state.pool();
+        let secret = state.prefs().jwt_secret();
+        let mut users = Vec::new();
+        for name in &names {
+            let user = user::new_user(
+                name.clone(),
+                String::from("pw"),
+                String::from("email"),
+                pool,
+            )
+            .await
+            .unwrap();
+            users.push(user);
+        }
+        let [first, second, outsider] = &users[..] else {
+            unreachable!()
+        };
+        // Anonymous, so both admins manage it
+        let link = url_db::create_url(
+            "https://example.com/one",
+            None,
+            pool,
+            CodeRules::of_len(8),
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let long = format!("https://example.com/{}", "x".repeat(200));
+        for (user, url) in [(first, "https://example.com/two"), (second, long.as_str())] {
+            let request = Ok(Json(ApiEditUrl {
+                url: Some(url.to_string()),
+                description: None,
+                destinations: None,
+                geo_targets: None,
+                rollout_percent: None,
+            }));
+            let resp = api_edit_url(
+                State(state.clone()),
+                Path(link.short_url().clone()),
+                auth_headers(user, secret),
+                request,
+            )
+            .await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let history = |user: &UserRow, htmx: bool| {
+            let mut headers = auth_headers(user, secret);
+            if htmx {
+                headers.insert("HX-Request", HeaderValue::from_static("true"));
+            }
+            api_url_history(
+                State(state.clone()),
+                Path(link.short_url().clone()),
+                headers,
+            )
+        };
+
+        let resp = history(first, false).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["short_url"], link.short_url().as_str());
+        let edits = body["edits"].as_array().unwrap();
+        assert_eq!(edits.len(), 2);
+        assert_eq!(edits[0]["edited_by"], names[0].as_str());
+        assert_eq!(edits[0]["field"], "destination");
+        assert_eq!(edits[0]["old_value"], "https://example.com/one");
+        assert_eq!(edits[0]["new_value"], "https://example.com/two");
+        assert_eq!(edits[1]["edited_by"], names[1].as_str());
+        assert_eq!(edits[1]["old_value"], "https://example.com/two");
+        let shown = edits[1]["new_value"].as_str().unwrap();
+        assert_eq!(shown.chars().count(), history::DISPLAY_LEN);
+        assert!(shown.ends_with('…'), "{shown}");
+        assert!(long.starts_with(shown.trim_end_matches('…')));
+        assert!(edits[0]["edited_at"].as_str() <= edits[1]["edited_at"].as_str());
+
+        let resp = history(second, true).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let html = String::from_utf8(body.to_vec()).unwrap();
+        assert!(html.contains(&format!("Changes to {}", link.short_url())));
+        assert!(html.contains(&names[1]));
+
+        assert_eq!(
+            history(outsider, false).await.status(),
+            StatusCode::FORBIDDEN
+        );
+
+        url_db::delete_url(link.id(), pool).await.unwrap();
+        for user in &users {
+            user::delete_user_from_db(*user.id(), pool).await.unwrap();
+        }
+    }
+
+    #[sqlx::test]
+    async fn links_can_be_handed_to_another_user() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let secret = state.prefs().jwt_secret();
+        let suffix = rand::random::<u32>();
+        let mut users = Vec::new();
+        for role in ["leaver", "successor"] {
+            let name = format!("{role}-{suffix}");
+            let user = user::new_user(name, String::from("pw"), String::from("email"), pool)
+                .await
+                .unwrap();
+            users.push(user);
+        }
+        let [leaver, successor] = &users[..] else {
+            unreachable!()
+        };
+        let link = url_db::create_url(
+            "https://example.com/handover",
+            Some(*leaver.id()),
+            pool,
+            CodeRules::of_len(8),
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let transfer = |user: &UserRow, username: &str| {
+            api_transfer_url(
+                State(state.clone()),
+                Path(link.short_url().clone()),
+                auth_headers(user, secret),
+                Ok(Json(ApiTransfer {
+                    username: username.to_string(),
+                })),
+            )
+        };
+        let describe = |user: &UserRow| {
+            api_edit_url(
+                State(state.clone()),
+                Path(link.short_url().clone()),
+                auth_headers(user, secret),
+                Ok(Json(ApiEditUrl {
+                    url: None,
+                    description: Some(String::from("handed over")),
+                    destinations: None,
+                    geo_targets: None,
+                    rollout_percent: None,
+                })),
+            )
+        };
+
+        let resp = transfer(leaver, &format!("nobody-{suffix}")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = transfer(successor, successor.username()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = transfer(leaver, successor.username()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let row = url_db::retrieve_url_obj(link.short_url(), SlugCase::Sensitive, pool)
+            .await
+            .unwrap();
+        assert_eq!(row.created_by(), Some(*successor.id()));
+
+        // The previous owner can't manage it any more, and the new one can't take it twice
+        assert_eq!(describe(leaver).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            transfer(leaver, leaver.username()).await.status(),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(describe(successor).await.status(), StatusCode::OK);
+        assert_eq!(
+            transfer(successor, successor.username()).await.status(),
+            StatusCode::CONFLICT
+        );
+
+        let edits = history::for_link(link.id(), pool).await.unwrap();
+        assert_eq!(edits[0].field, history::OWNER);
+        assert_eq!(edits[0].old_value.as_ref(), Some(leaver.username()));
+        assert_eq!(edits[0].new_value.as_ref(), Some(successor.username()));
+        assert_eq!(edits[0].edited_by.as_ref(), Some(leaver.username()));
+
+        url_db::delete_url(link.id(), pool).await.unwrap();
+        for user in &users {
+            user::delete_user_from_db(*user.id(), pool).await.unwrap();
+        }
+    }
+
+    #[sqlx::test]
+    async fn concurrent_transfers_hand_a_link_over_once() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let secret = state.prefs().jwt_secret();
+        let suffix = rand::random::<u32>();
+        let mut users = Vec::new();
+        for role in ["giver", "taker-a", "taker-b"] {
+            let name = format!("{role}-{suffix}");
+            let user = user::new_user(name, String::from("pw"), String::from("email"), pool)
+                .await
+                .unwrap();
+            users.push(user);
+        }
+        let link = url_db::create_url(
+            "https://example.com/contested",
+            Some(*users[0].id()),
+            pool,
+            CodeRules::of_len(8),
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let transfer = |to: &UserRow| {
+            let state = state.clone();
+            let code = link.clone_short_url();
+            let headers = auth_headers(&users[0], secret);
+            let username = to.username().clone();
+            tokio::spawn(async move {
+                api_transfer_url(
+                    State(state),
+                    Path(code),
+                    headers,
+                    Ok(Json(ApiTransfer { username })),
+                )
+                .await
+                .status()
+            })
+        };
+
+        let (a, b) = tokio::join!(transfer(&users[1]), transfer(&users[2]));
+        let statuses = [a.unwrap(), b.unwrap()];
+        // The one that lost finds the link gone from under it, or already someone else's
+        assert_eq!(
+            statuses.iter().filter(|s| **s == StatusCode::OK).count(),
+            1,
+            "{statuses:?}"
+        );
+        assert!(statuses.iter().all(|s| [
+            StatusCode::OK,
+            StatusCode::CONFLICT,
+            StatusCode::FORBIDDEN
+        ]
+        .contains(s)));
+        let winner = if statuses[0] == StatusCode::OK { 1 } else { 2 };
+        let row = url_db::retrieve_url_obj(link.short_url(), SlugCase::Sensitive, pool)
+            .await
+            .unwrap();
+        assert_eq!(row.created_by(), Some(*users[winner].id()));
+        let edits = history::for_link(link.id(), pool).await.unwrap();
+        assert_eq!(edits.len(), 1);
+
+        url_db::delete_url(link.id(), pool).await.unwrap();
+        for user in &users {
+            user::delete_user_from_db(*user.id(), pool).await.unwrap();
+        }
+    }
+
+    #[sqlx::test]
+    async fn descriptions_are_cleaned_and_editable() {
+        let state = state_init().await;
+        let pool = state.pool();
+        let secret = state.prefs().jwt_secret();
+        let name = format!("describer-{}", rand::random::<u32>());
+        let user = user::new_user(name, String::from("pw"), String::from("email"), pool)
+            .await
+            .unwrap();
+        let body = |resp: Response| async move {
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        let request = |description: &str| {
+            Ok(Json(ApiNewUrl {
+                url: Some(format!("https://example.com/{}", rand::random::<u32>())),
+                alias: None,
+                tags: None,
+                description: Some(description.to_string()),
+                destinations: Vec::new(),
+                active_from: None,
+                single_use: false,
+            }))
+        };
+        let resp = api_create_url(
+            State(state.clone()),
+            None,
+            HeaderMap::new(),
+            request("  Spring\tcampaign\r\nfor the\u{7}\u{1b} newsletter  "),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let created = body(resp).await;
+        assert_eq!(
+            created["description"],
+            "Spring campaign  for the newsletter"
+        );
+        let anonymous = created["id"].as_i64().unwrap();
+        let too_long = "x".repeat(url_db::MAX_DESCRIPTION_LEN + 1);
+        let resp = api_create_url(
+            State(state.clone()),
+            None,
+            HeaderMap::new(),
+            request(&too_long),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body(resp).await["error"]["code"], "invalid_description");
+
+        let link = url_db::create_url(
+            "https://example.com/described",
+            Some(*user.id()),
+            pool,
+            CodeRules::of_len(8),
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let edit = |url: Option<&str>, description: Option<&str>| {
+            let request = Ok(Json(ApiEditUrl {
+                url: url.map(String::from),
+                description: description.map(String::from),
+                destinations: None,
+                geo_targets: None,
+                rollout_percent: None,
+            }));
+            api_edit_url(
+                State(state.clone()),
+                Path(link.short_url().clone()),
+                auth_headers(&user, secret),
+                request,
+            )
+        };
+        let resp = edit(None, Some("Q3\nreport")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body(resp).await["description"], "Q3 report");
+        // Changing only the destination keeps the description
+        let resp = edit(Some("https://example.com/moved"), None).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let edited = body(resp).await;
+        assert_eq!(edited["long_url"], "https://example.com/moved");
+        assert_eq!(edited["description"], "Q3 report");
+
+        let resp = api_list_urls(
+            State(state.clone()),
+            Query(UrlPageQuery {
+                page: None,
+                per_page: None,
+                tag: None,
+                archived: false,
+            }),
+            auth_headers(&user, secret),
+        )
+        .await;
+        assert_eq!(body(resp).await["items"][0]["description"], "Q3 report");
+
+        // Visitors are only sent on
+        let resp = subdir_handler(
+            Path(link.clone_short_url()),
+            State(state.clone()),
+            None,
+            Method::GET,
+            HeaderMap::new(),
+        )
+        .await;
+        assert!(resp.status().is_redirection());
+        assert!(resp
+            .headers()
+            .values()
+            .all(|value| !value.to_str().unwrap_or_default().contains("Q3")));
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(!String::from_utf8_lossy(&bytes).contains("Q3 report"));
+
+        let resp = edit(None, Some(" \n ")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(body(resp).await.get("description").is_none());
+        let edits = history::for_link(link.id(), pool).await.unwrap();
+        let fields: Vec<_> = edits.iter().map(|edit| edit.field.as_str()).collect();
+        assert_eq!(fields, ["description", "destination", "description"]);
+        assert_eq!(edits[2].old_value.as_deref(), Some("Q3 report"));
+        assert_eq!(edits[2].new_value, None);
+
+        let resp = edit(None, Some(&too_long)).await;
+        assert_eq!(body(resp).await["error"]["code"], "invalid_description");
+        let resp = edit(None, None).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body(resp).await["error"]["code"], "nothing_to_change");
+
+        url_db::delete_url(anonymous, pool).await.unwrap();
+        url_db::delete_url(link.id(), pool).await.unwrap();
+        user::delete_user_from_db(*user.id(), pool).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn link_templates_resolve_unmatched_codes() {
+        use futures_util::StreamExt;
+
+        let state = state_init().await;
+        let pool = state.pool();
+        let secret = state.prefs().jwt_secret();
+        let suffix = rand::random::<u32>();
+        let mut users = Vec::new();
+        for name in [format!("templater-{suffix}"), format!("peeker-{suffix}")] {
+            let user = user::new_user(name, String::from("pw"), String::from("email"), pool)
+                .await
+                .unwrap();
+            users.push(user);
+        }
+        let [owner, other] = &users[..] else {
+            unreachable!()
+        };
+        let prefix = format!("inv{suffix}-");
+        let create = |alias: String, pattern: Option<&str>| {
+            let request = Ok(Json(ApiNewTemplate {
+                alias,
+                destination: String::from("https://billing.example.com/invoice?c={customer}"),
+                value_pattern: pattern.map(str::to_string),
+                max_value_len: None,
+            }));
+            api_create_template(State(state.clone()), auth_headers(owner, secret), request)
+        };
+        let resp = create(format!("{prefix}{{customer}}"), Some("[0-9a-z ]+")).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let id = body["id"].as_i64().unwrap();
+
+        // Templates can't cover the same codes as another template or an existing code
+        for (alias, code) in [
+            (format!("{prefix}{{customer}}.pdf"), "template_overlap"),
+            (format!("inv{suffix}{{customer}}"), "template_overlap"),
+        ] {
+            let resp = create(alias.clone(), None).await;
+            assert_eq!(resp.status(), StatusCode::CONFLICT, "{alias}");
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["error"]["code"], code, "{alias}");
+        }
+        let taken = format!("bill{suffix}-7");
+        url_db::create_url_with_alias(
+            &taken,
+            "https://example.com/taken",
+            None,
+            pool,
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let resp = create(format!("bill{suffix}-{{customer}}"), None).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        // Exact codes made after the template still win over it
+        let exact = format!("{prefix}7");
+        url_db::create_url_with_alias(
+            &exact,
+            "https://example.com/exact",
+            None,
+            pool,
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let scope = firehose::Scope {
+            owner: Some(*owner.id()),
+            codes: None,
+        };
+        let mut clicks = Box::pin(
+            state
+                .firehose
+                .subscribe(*owner.id(), scope, firehose::Format::Ndjson)
+                .unwrap(),
+        );
+        let visit = |code: String| {
+            let state = state.clone();
+            async move {
+                subdir_handler(
+                    Path(code),
+                    State(state),
+                    None,
+                    Method::GET,
+                    HeaderMap::new(),
+                )
+                .await
+            }
+        };
+        let resp = visit(exact).await;
+        assert_eq!(resp.headers()[LOCATION], "https://example.com/exact");
+        for (value, times) in [("42", 2), ("a b", 1)] {
+            for _ in 0..times {
+                let resp = visit(format!("{prefix}{value}")).await;
+                assert_eq!(resp.status(), StatusCode::FOUND);
+                assert_eq!(
+                    resp.headers()[LOCATION],
+                    format!(
+                        "https://billing.example.com/invoice?c={}",
+                        value.replace(' ', "%20")
+                    )
+                );
+            }
+        }
+        let line = clicks.next().await.unwrap().unwrap();
+        let line = String::from_utf8(line.to_vec()).unwrap();
+        assert!(line.contains(r#""template_value":"42""#), "{line}");
+        for rejected in [format!("{prefix}ABC"), prefix.clone()] {
+            let resp = visit(rejected.clone()).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{rejected}");
+        }
+
+        let per_value = link_template::value_clicks(id, 10, pool).await.unwrap();
+        assert_eq!(
+            per_value
+                .iter()
+                .map(|clicks| (clicks.value.as_str(), clicks.clicks))
+                .collect::<Vec<_>>(),
+            [("42", 2), ("a b", 1)]
+        );
+        let resp = api_template_clicks(
+            State(state.clone()),
+            Path(id),
+            Query(TemplateClicksQuery { limit: None }),
+            auth_headers(other, secret),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp =
+            api_delete_template(State(state.clone()), Path(id), auth_headers(owner, secret)).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let resp = visit(format!("{prefix}42")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        for user in &users {
+            user::delete_user_from_db(*user.id(), pool).await.unwrap();
+        }
+    }
+
+    #[sqlx::test]
+    async fn missed_lookups_are_admin_only() {
+        let state = state_init().await;
+        let code = format!("missing{}", rand::random::<u32>());
+        let resp = consume_short_url(
+            Path(code.clone()),
+            State(&state),
+            &HeaderMap::new(),
+            &Method::GET,
+            None,
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(state
+            .missed
+            .top(usize::MAX)
+            .iter()
+            .any(|missed| missed.code == code));
+
+        let name = format!("not-admin-{}", rand::random::<u32>());
+        let user = user::new_user(
+            name,
+            String::from("pw"),
+            String::from("email"),
+            state.pool(),
+        )
+        .await
+        .unwrap();
+        let headers = auth_headers(&user, state.prefs().jwt_secret());
+        let resp = admin_missed(
+            State(state.clone()),
+            Query(MissedQuery { limit: None }),
+            headers,
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = admin_missed(
+            State(state.clone()),
+            Query(MissedQuery { limit: None }),
+            HeaderMap::new(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        user::delete_user_from_db(*user.id(), state.pool())
+            .await
+            .unwrap();
+    }
+
+    async fn ready_state(state: &Arc<PoolAndPrefs>, warm: bool) -> (StatusCode, String) {
+        let resp = readyz(State(state.clone()), Query(ReadyQuery { warm })).await;
+        let status = resp.status();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        (status, body["state"].as_str().unwrap().to_string())
+    }
+
+    #[sqlx::test]
+    async fn serves_redirects_while_warming() {
+        let state = state_init().await;
+        let link = url_db::create_url(
+            "https://example.com",
+            None,
+            state.pool(),
+            CodeRules::of_len(8),
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let (finish, finished) = tokio::sync::oneshot::channel::<()>();
+        let warming = state.clone();
+        let warm_up = tokio::spawn(async move {
+            warming
+                .readiness
+                .warm_up_with(async {
+                    let _ = finished.await;
+                })
+                .await
+        });
+
+        assert_eq!(
+            ready_state(&state, false).await,
+            (StatusCode::OK, String::from("minimal"))
+        );
+        assert_eq!(
+            ready_state(&state, true).await,
+            (StatusCode::SERVICE_UNAVAILABLE, String::from("minimal"))
+        );
+        let resp = consume_short_url(
+            Path(link.clone_short_url()),
+            State(&state),
+            &HeaderMap::new(),
+            &Method::GET,
+            None,
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+
+        finish.send(()).unwrap();
+        warm_up.await.unwrap();
+        assert_eq!(
+            ready_state(&state, true).await,
+            (StatusCode::OK, String::from("warm"))
+        );
+    }
+
+    /// A contributor whose report the test sets
+    struct Switch(Arc<std::sync::Mutex<health::Report>>);
+
+    impl health::Contributor for Switch {
+        fn name(&self) -> &'static str {
+            "switch"
+        }
+        fn check(&self) -> futures_util::future::BoxFuture<'_, health::Report> {
+            let report = self.0.lock().unwrap().clone();
+            Box::pin(async move { report })
+        }
+    }
+
+    #[sqlx::test]
+    async fn readyz_aggregates_contributors() {
+        use tower::ServiceExt;
+
+        let state = state_init().await;
+        state.readiness.warm_up_with(async {}).await;
+        let report = Arc::new(std::sync::Mutex::new(health::Report::healthy()));
+        state.health.register(Switch(report.clone()));
+
+        for (set, status, degraded) in [
+            (health::Report::healthy(), StatusCode::OK, false),
+            (health::Report::degraded("slow"), StatusCode::OK, true),
+            (
+                health::Report::unhealthy("down"),
+                StatusCode::SERVICE_UNAVAILABLE,
+                false,
+            ),
+            (health::Report::healthy(), StatusCode::OK, false),
+        ] {
+            *report.lock().unwrap() = set;
+            let resp = readyz(State(state.clone()), Query(ReadyQuery { warm: false })).await;
+            assert_eq!(resp.status(), status);
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["degraded"], degraded);
+            let components = body["components"].as_array().unwrap();
+            let names: Vec<_> = components.iter().map(|c| c["name"].clone()).collect();
+            assert_eq!(names, ["database", "warm_up", "switch"]);
+            assert_eq!(components[0]["status"], "healthy");
+        }
+
+        // Only the switch changed since the last check
+        *report.lock().unwrap() = health::Report::unhealthy("down");
+        let (checked, transitions) = state.health.check().await;
+        assert_eq!(checked.health, Aggregate::NotReady);
+        assert_eq!(
+            transitions,
+            [health::Transition {
+                component: "switch",
+                from: Some(health::Health::Healthy),
+                to: health::Health::Unhealthy,
+            }]
+        );
+
+        let resp = site_routes(state.prefs().timeouts())
+            .with_state(state.clone())
+            .oneshot(
+                axum::http::Request::get("/healthz")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[sqlx::test]
+    async fn legacy_cookie_is_migrated_during_window() {
+        let mut prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        prefs.set_legacy_cookie_until(Some("9999-12-31".parse().unwrap()));
+        let open = state_with(prefs.clone()).await;
+        prefs.set_legacy_cookie_until(Some("2000-01-01".parse().unwrap()));
+        let closed = state_with(prefs).await;
+
+        let name = format!("legacy-{}", rand::random::<u32>());
+        let user = user::new_user(name, String::from("pw"), String::from("email"), open.pool())
+            .await
+            .unwrap();
+        let secret = open.prefs().jwt_secret();
+        let legacy = auth_headers_named(&user, secret, LEGACY_AUTH_COOKIE_NAME);
+
+        let AuthenticationResponse::Authenticated(_, set_cookies) =
+            authenticate_request(State(open.clone()), &legacy).await
+        else {
+            panic!("Legacy cookie was rejected inside the window");
+        };
+        assert_eq!(set_cookies.len(), 2);
+        assert!(set_cookies[0].starts_with(&format!("{AUTH_COOKIE_NAME}=ey")));
+        assert!(set_cookies[1].starts_with(&format!("{LEGACY_AUTH_COOKIE_NAME}=;")));
+
+        let current = auth_headers(&user, secret);
+        let AuthenticationResponse::Authenticated(_, set_cookies) =
+            authenticate_request(State(open.clone()), &current).await
+        else {
+            panic!("Current cookie was rejected");
+        };
+        assert!(set_cookies.is_empty());
+
+        let after = authenticate_request(State(closed.clone()), &legacy).await;
+        assert!(matches!(
+            after,
+            AuthenticationResponse::Error(AuthError::InvalidCookieHeader)
+        ));
+        user::delete_user_from_db(*user.id(), open.pool())
+            .await
+            .unwrap();
     }
 }
@@ -0,0 +1,299 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::locked::Locked;
+
+/// Codes longer than this are cut down before being stored
+const MAX_CODE_LEN: usize = 64;
+/// Most codes tracked at once unless sized otherwise, see [crate::resources]. Past the capacity,
+/// the least requested code makes room for the new one.
+pub const MISSED_CAPACITY: usize = 1000;
+/// Repeat misses of the same code inside this window only count once
+const RECORD_INTERVAL: Duration = Duration::from_secs(1);
+/// Codes nobody has asked for in this long are dropped
+const MISSED_RETENTION: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+
+/// Path fragments that only show up in requests from vulnerability scanners
+const SCANNER_PATTERNS: [&str; 14] = [
+    "wp-",
+    "wordpress",
+    "phpmyadmin",
+    "cgi-bin",
+    "xmlrpc",
+    "admin",
+    "config",
+    "backup",
+    "shell",
+    "eval",
+    "passwd",
+    "actuator",
+    "owa",
+    "boaform",
+];
+
+/// Short codes that were looked up but don't exist, with how often and how recently. A code that
+/// keeps getting requested is usually a typo on something that was printed or shared.
+pub struct MissedLookups {
+    entries: Locked<HashMap<String, MissEntry>>,
+    capacity: usize,
+}
+
+impl Default for MissedLookups {
+    fn default() -> Self {
+        MissedLookups::with_capacity(MISSED_CAPACITY)
+    }
+}
+
+struct MissEntry {
+    count: u64,
+    last_counted: Instant,
+    last_seen: SystemTime,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct MissedLookup {
+    pub code: String,
+    pub count: u64,
+    /// Unix timestamp in seconds
+    pub last_seen: u64,
+}
+
+impl MissedLookups {
+    pub fn with_capacity(capacity: usize) -> Self {
+        MissedLookups {
+            entries: Locked::default(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    pub fn record(&self, code: &str) {
+        self.record_at(code, Instant::now(), SystemTime::now());
+    }
+
+    fn record_at(&self, code: &str, now: Instant, wall_now: SystemTime) {
+        let code = match normalize(code) {
+            Some(code) => code,
+            None => return,
+        };
+        self.entries
+            .with(|entries| record_into(entries, self.capacity, code, now, wall_now));
+    }
+
+    /// The most missed codes, most requested first
+    pub fn top(&self, limit: usize) -> Vec<MissedLookup> {
+        let mut top: Vec<MissedLookup> = self.entries.with(|entries| {
+            sweep(entries, SystemTime::now());
+            entries
+                .iter()
+                .map(|(code, entry)| MissedLookup {
+                    code: code.clone(),
+                    count: entry.count,
+                    last_seen: entry
+                        .last_seen
+                        .duration_since(UNIX_EPOCH)
+                        .map(|since| since.as_secs())
+                        .unwrap_or(0),
+                })
+                .collect()
+        });
+        top.sort_by(|first, second| {
+            second
+                .count
+                .cmp(&first.count)
+                .then(second.last_seen.cmp(&first.last_seen))
+                .then(first.code.cmp(&second.code))
+        });
+        top.truncate(limit);
+        top
+    }
+}
+
+/// Counts a miss of `code`, making room for it when the map holds `capacity` codes
+fn record_into(
+    entries: &mut HashMap<String, MissEntry>,
+    capacity: usize,
+    code: String,
+    now: Instant,
+    wall_now: SystemTime,
+) {
+    if let Some(entry) = entries.get_mut(&code) {
+        entry.last_seen = wall_now;
+        if now.duration_since(entry.last_counted) >= RECORD_INTERVAL {
+            entry.count += 1;
+            entry.last_counted = now;
+        }
+        return;
+    }
+
+    if entries.len() >= capacity {
+        sweep(entries, wall_now);
+    }
+    if entries.len() >= capacity {
+        let least = entries
+            .iter()
+            .min_by_key(|(_, entry)| (entry.count, entry.last_seen))
+            .map(|(code, _)| code.clone());
+        if let Some(least) = least {
+            entries.remove(&least);
+        }
+    }
+    entries.insert(
+        code,
+        MissEntry {
+            count: 1,
+            last_counted: now,
+            last_seen: wall_now,
+        },
+    );
+}
+
+fn sweep(entries: &mut HashMap<String, MissEntry>, wall_now: SystemTime) {
+    entries.retain(|_, entry| {
+        wall_now
+            .duration_since(entry.last_seen)
+            .map_or(true, |age| age < MISSED_RETENTION)
+    });
+}
+
+/// Trims and truncates a requested code, or returns None if the request looks like a probe for a
+/// file or a known vulnerable path rather than a short url.
+fn normalize(code: &str) -> Option<String> {
+    let code = code.trim().trim_matches('/');
+    if code.is_empty() || code.contains(['.', '/', '\\', '%']) || code.chars().any(char::is_control)
+    {
+        return None;
+    }
+    let lowercase = code.to_lowercase();
+    if SCANNER_PATTERNS
+        .iter()
+        .any(|pattern| lowercase.contains(pattern))
+    {
+        return None;
+    }
+    Some(code.chars().take(MAX_CODE_LEN).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::locked::contend;
+
+    #[test]
+    fn top_codes_are_ordered_by_count() {
+        let missed = MissedLookups::default();
+        let start = Instant::now();
+        let wall = SystemTime::now();
+
+        for second in 0..5 {
+            let now = start + Duration::from_secs(second);
+            missed.record_at("spring-sael", now, wall);
+            if second < 3 {
+                missed.record_at("promo24", now, wall);
+            }
+        }
+        missed.record_at("x7Yq2", start, wall);
+        for probe in [
+            "wp-login.php",
+            ".env",
+            "phpMyAdmin",
+            "favicon.ico",
+            "cgi-bin",
+            "robots.txt",
+            "../etc/passwd",
+        ] {
+            missed.record_at(probe, start, wall);
+        }
+
+        let top = missed.top(10);
+        let codes: Vec<(&str, u64)> = top
+            .iter()
+            .map(|missed| (missed.code.as_str(), missed.count))
+            .collect();
+        assert_eq!(
+            codes,
+            vec![("spring-sael", 5), ("promo24", 3), ("x7Yq2", 1)]
+        );
+        assert_eq!(missed.top(1).len(), 1);
+    }
+
+    #[test]
+    fn repeats_are_rate_limited() {
+        let missed = MissedLookups::default();
+        let start = Instant::now();
+        let wall = SystemTime::now();
+
+        for _ in 0..100 {
+            missed.record_at("typo", start, wall);
+        }
+        missed.record_at("typo", start + RECORD_INTERVAL, wall);
+
+        assert_eq!(missed.top(1)[0].count, 2);
+    }
+
+    #[test]
+    fn cardinality_is_bounded() {
+        let missed = MissedLookups::default();
+        let start = Instant::now();
+        let wall = SystemTime::now();
+
+        for second in 0..3 {
+            missed.record_at("popular", start + Duration::from_secs(second), wall);
+        }
+        for i in 0..MISSED_CAPACITY * 3 {
+            missed.record_at(&format!("junk{i}"), start, wall);
+        }
+        let long_code = "a".repeat(MAX_CODE_LEN * 2);
+        missed.record_at(&long_code, start, wall);
+
+        let top = missed.top(usize::MAX);
+        assert_eq!(top.len(), MISSED_CAPACITY);
+        assert_eq!(top[0].code, "popular");
+        assert!(top.iter().all(|missed| missed.code.len() <= MAX_CODE_LEN));
+    }
+
+    #[test]
+    fn old_codes_are_swept() {
+        let missed = MissedLookups::default();
+        let start = Instant::now();
+        let old = SystemTime::now() - MISSED_RETENTION - Duration::from_secs(1);
+
+        missed.record_at("forgotten", start, old);
+        missed.record_at("recent", start, SystemTime::now());
+
+        let top = missed.top(10);
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].code, "recent");
+    }
+
+    #[test]
+    fn concurrent_misses_are_all_counted() {
+        const THREADS: usize = 16;
+        const CODES: usize = 50;
+        let missed = Arc::new(MissedLookups::default());
+        let start = Instant::now();
+        let wall = SystemTime::now();
+        let shared = missed.clone();
+        contend(THREADS, move |thread| {
+            for round in 0..CODES {
+                let second = Duration::from_secs(round as u64);
+                shared.record_at(&format!("thread{thread}"), start + second, wall);
+                shared.record_at(&format!("thread{thread}-{round}"), start, wall);
+                shared.top(5);
+            }
+        });
+
+        let top = missed.top(usize::MAX);
+        assert_eq!(top.len(), THREADS * (CODES + 1));
+        for thread in 0..THREADS {
+            let code = format!("thread{thread}");
+            let own = top.iter().find(|missed| missed.code == code).unwrap();
+            assert_eq!(own.count, CODES as u64, "{code}");
+        }
+    }
+}
@@ -0,0 +1,418 @@
+use percent_encoding::{utf8_percent_encode, CONTROLS};
+use serde::{Deserialize, Serialize};
+
+/// Query parameters that only exist to track where a click came from, besides every `utm_*` one
+const TRACKING_PARAMS: [&str; 4] = ["fbclid", "gclid", "dclid", "msclkid"];
+
+/// Which rewrites are applied to destinations before they are stored and compared for duplicates.
+/// Every rule can be turned off on its own.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct NormalizeRules {
+    /// `example.com/page/` becomes `example.com/page`, unless the last segment looks like a file
+    pub trailing_slash: bool,
+    /// `:80` on http and `:443` on https are dropped
+    pub default_port: bool,
+    /// The scheme and host are lowercased. Paths are case sensitive and are left alone.
+    pub lowercase_host: bool,
+    /// `utm_*`, `fbclid` and similar parameters are removed, whatever their case. Off by default
+    /// since some users need them to reach the destination.
+    pub strip_tracking: bool,
+    /// Parameters removed along with the built in ones when strip_tracking is on, e.g. `mc_eid`
+    pub extra_tracking_params: Vec<String>,
+    /// Hosts outside ASCII become punycode and every other non-ASCII character is percent-encoded,
+    /// so the destination can go in a Location header as is
+    pub ascii: bool,
+}
+
+impl Default for NormalizeRules {
+    fn default() -> Self {
+        NormalizeRules {
+            trailing_slash: true,
+            default_port: true,
+            lowercase_host: true,
+            strip_tracking: false,
+            extra_tracking_params: Vec::new(),
+            ascii: true,
+        }
+    }
+}
+
+/// A destination after normalization
+#[derive(Debug, PartialEq)]
+pub struct Normalized {
+    pub canonical: String,
+    /// Parameters were dropped, so the canonical form no longer carries everything that was
+    /// submitted. The other rules only ever produce an equivalent address.
+    pub lossy: bool,
+}
+
+/// Rewrites a destination into the form it is stored and deduplicated in. Anything that doesn't
+/// parse as `[scheme://][userinfo@]host[:port][/path][?query][#fragment]` is passed through as is.
+pub fn normalize(url: &str, rules: &NormalizeRules) -> Normalized {
+    let url = url.trim();
+    let (scheme, rest) = match url.split_once("://") {
+        Some((scheme, rest)) if is_scheme(scheme) => (Some(scheme), rest),
+        _ => (None, url),
+    };
+    let (rest, fragment) = split_off(rest, '#');
+    let (rest, query) = split_off(rest, '?');
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let (userinfo, host_port) = match authority.rsplit_once('@') {
+        Some((userinfo, host_port)) => (Some(userinfo), host_port),
+        None => (None, authority),
+    };
+    let (host, port) = split_port(host_port);
+
+    let scheme = scheme.map(|scheme| {
+        if rules.lowercase_host {
+            scheme.to_ascii_lowercase()
+        } else {
+            scheme.to_string()
+        }
+    });
+    let host = if rules.ascii && !host.is_ascii() {
+        idna::domain_to_ascii(host).unwrap_or_else(|_| host.to_string())
+    } else if rules.lowercase_host {
+        host.to_ascii_lowercase()
+    } else {
+        host.to_string()
+    };
+    let ascii = |text: &str| {
+        if rules.ascii {
+            utf8_percent_encode(text, CONTROLS).to_string()
+        } else {
+            text.to_string()
+        }
+    };
+    // Destinations without a scheme are sent over http, so :80 is their default too
+    let default_port = match scheme.as_deref().map(str::to_ascii_lowercase).as_deref() {
+        None | Some("http") => "80",
+        Some("https") => "443",
+        Some(_) => "",
+    };
+    let port = port.filter(|port| !(rules.default_port && *port == default_port));
+    let path = if rules.trailing_slash {
+        trim_trailing_slash(path)
+    } else {
+        path
+    };
+    let (query, lossy) = match query {
+        Some(query) if rules.strip_tracking => {
+            let kept: Vec<&str> = query
+                .split('&')
+                .filter(|param| !is_tracking(param, &rules.extra_tracking_params))
+                .collect();
+            let lossy = kept.len() != query.split('&').count();
+            ((!kept.is_empty()).then(|| kept.join("&")), lossy)
+        }
+        query => (query.map(str::to_string), false),
+    };
+
+    let mut canonical = String::with_capacity(url.len());
+    if let Some(scheme) = scheme {
+        canonical.push_str(&scheme);
+        canonical.push_str("://");
+    }
+    if let Some(userinfo) = userinfo {
+        canonical.push_str(&ascii(userinfo));
+        canonical.push('@');
+    }
+    canonical.push_str(&host);
+    if let Some(port) = port {
+        canonical.push(':');
+        canonical.push_str(port);
+    }
+    canonical.push_str(&ascii(path));
+    if let Some(query) = query {
+        canonical.push('?');
+        canonical.push_str(&ascii(&query));
+    }
+    if let Some(fragment) = fragment {
+        canonical.push('#');
+        canonical.push_str(&ascii(fragment));
+    }
+    Normalized { canonical, lossy }
+}
+
+fn is_scheme(scheme: &str) -> bool {
+    scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+}
+
+fn split_off(text: &str, separator: char) -> (&str, Option<&str>) {
+    match text.split_once(separator) {
+        Some((before, after)) => (before, Some(after)),
+        None => (text, None),
+    }
+}
+
+/// Splits `host:port`, leaving the colons inside a bracketed IPv6 address alone
+fn split_port(host_port: &str) -> (&str, Option<&str>) {
+    let host_end = match host_port.find(']') {
+        Some(bracket) if host_port.starts_with('[') => bracket + 1,
+        _ => 0,
+    };
+    match host_port[host_end..].rfind(':') {
+        Some(colon) => {
+            let colon = host_end + colon;
+            let port = &host_port[colon + 1..];
+            if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) {
+                (&host_port[..colon], Some(port))
+            } else {
+                (host_port, None)
+            }
+        }
+        None => (host_port, None),
+    }
+}
+
+/// Drops one trailing slash. Left alone when it could mean something: after a segment that looks
+/// like a file (`/script.php/`, where it usually starts extra path info) or when there are
+/// several (`/a//`).
+fn trim_trailing_slash(path: &str) -> &str {
+    let Some(trimmed) = path.strip_suffix('/') else {
+        return path;
+    };
+    if trimmed.ends_with('/') {
+        return path;
+    }
+    let last_segment = trimmed.rsplit('/').next().unwrap_or_default();
+    if last_segment.contains('.') {
+        return path;
+    }
+    trimmed
+}
+
+fn is_tracking(param: &str, extra: &[String]) -> bool {
+    let name = param
+        .split('=')
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    name.strip_prefix("utm_")
+        .is_some_and(|rest| !rest.is_empty())
+        || TRACKING_PARAMS.contains(&name.as_str())
+        || extra.iter().any(|extra| extra.eq_ignore_ascii_case(&name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_rules() -> NormalizeRules {
+        NormalizeRules {
+            strip_tracking: true,
+            ..NormalizeRules::default()
+        }
+    }
+
+    #[test]
+    fn rewrites_equivalent_forms() {
+        let cases = [
+            ("https://example.com/page/", "https://example.com/page"),
+            ("https://example.com/", "https://example.com"),
+            ("example.com/page/", "example.com/page"),
+            ("HTTPS://Example.COM/Page", "https://example.com/Page"),
+            ("http://example.com:80/a", "http://example.com/a"),
+            ("https://example.com:443/a", "https://example.com/a"),
+            ("example.com:80/a/", "example.com/a"),
+            ("https://[2001:DB8::1]:443/", "https://[2001:db8::1]"),
+            (
+                "https://User:Pw@Example.com/a/",
+                "https://User:Pw@example.com/a",
+            ),
+            (
+                "https://example.com/a/?q=1#top",
+                "https://example.com/a?q=1#top",
+            ),
+            (
+                "https://example.com/a?utm_source=x&id=7&fbclid=abc",
+                "https://example.com/a?id=7",
+            ),
+            (
+                "https://example.com/?utm_medium=email",
+                "https://example.com",
+            ),
+            ("  https://example.com/a  ", "https://example.com/a"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(
+                normalize(input, &all_rules()).canonical,
+                expected,
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn leaves_meaningful_differences() {
+        let cases = [
+            // Trailing slashes that may matter
+            "https://example.com/index.php/",
+            "https://example.com/a//",
+            // Ports that aren't the default for the scheme
+            "https://example.com:80/a",
+            "http://example.com:443/a",
+            "https://example.com:8443/a",
+            "ftp://example.com:21/a",
+            // Parameters that only look like tracking ones
+            "https://example.com/a?utm_=1",
+            "https://example.com/a?utm=1",
+            "https://example.com/a?xutm_source=1",
+            "https://example.com/a?fbclid2=1",
+            "https://example.com/a?ref=utm_source",
+            // Case in the path, query and fragment
+            "https://example.com/Page?Q=A#Top",
+            // Slashes inside the query or fragment aren't the path's
+            "https://example.com?next=/a/",
+            "https://example.com/a#/b/",
+            // Not something this understands
+            "mailto:someone@example.com",
+            "",
+        ];
+        for input in cases {
+            assert_eq!(normalize(input, &all_rules()).canonical, input, "{input}");
+        }
+    }
+
+    #[test]
+    fn rules_toggle_independently() {
+        let input = "HTTPS://Example.com:443/a/?utm_source=x";
+        let with = |rules: NormalizeRules| normalize(input, &rules).canonical;
+        let none = NormalizeRules {
+            trailing_slash: false,
+            default_port: false,
+            lowercase_host: false,
+            strip_tracking: false,
+            extra_tracking_params: Vec::new(),
+            ascii: false,
+        };
+
+        assert_eq!(with(none.clone()), input);
+        assert_eq!(
+            with(NormalizeRules {
+                trailing_slash: true,
+                ..none.clone()
+            }),
+            "HTTPS://Example.com:443/a?utm_source=x"
+        );
+        assert_eq!(
+            with(NormalizeRules {
+                default_port: true,
+                ..none.clone()
+            }),
+            "HTTPS://Example.com/a/?utm_source=x"
+        );
+        assert_eq!(
+            with(NormalizeRules {
+                lowercase_host: true,
+                ..none.clone()
+            }),
+            "https://example.com:443/a/?utm_source=x"
+        );
+        assert_eq!(
+            with(NormalizeRules {
+                strip_tracking: true,
+                ..none
+            }),
+            "HTTPS://Example.com:443/a/"
+        );
+        assert_eq!(
+            with(NormalizeRules::default()),
+            "https://example.com/a?utm_source=x"
+        );
+    }
+
+    #[test]
+    fn strips_tracking_whatever_the_case() {
+        let rules = NormalizeRules {
+            extra_tracking_params: vec![String::from("mc_eid"), String::from("Ref_Src")],
+            ..all_rules()
+        };
+        let cases = [
+            (
+                "https://example.com/a?UTM_SOURCE=x&Id=7&Utm_Medium=y#Top",
+                "https://example.com/a?Id=7#Top",
+            ),
+            (
+                "https://example.com/a?FBCLID=1&q=a%26b&GClid=2",
+                "https://example.com/a?q=a%26b",
+            ),
+            // Repeated keys, tracking or not
+            (
+                "https://example.com/a?tag=x&utm_source=a&tag=y&utm_source=b",
+                "https://example.com/a?tag=x&tag=y",
+            ),
+            // Nothing left but the fragment
+            (
+                "https://example.com/a?utm_source=x&utm_source=y#part",
+                "https://example.com/a#part",
+            ),
+            // Extra parameters from the config
+            (
+                "https://example.com/a?MC_EID=1&ref_src=twsrc&keep=1",
+                "https://example.com/a?keep=1",
+            ),
+            ("https://example.com/a?mc_eid", "https://example.com/a"),
+        ];
+        for (input, expected) in cases {
+            let normalized = normalize(input, &rules);
+            assert_eq!(normalized.canonical, expected, "{input}");
+            assert!(normalized.lossy, "{input}");
+        }
+        assert_eq!(
+            normalize("https://example.com/a?mc_eid=1", &all_rules()).canonical,
+            "https://example.com/a?mc_eid=1"
+        );
+    }
+
+    #[test]
+    fn makes_international_destinations_ascii() {
+        let cases = [
+            (
+                "https://München.de/straße?q=größe#kapitel-ü",
+                "https://xn--mnchen-3ya.de/stra%C3%9Fe?q=gr%C3%B6%C3%9Fe#kapitel-%C3%BC",
+            ),
+            ("http://☕.ws/☕", "http://xn--53h.ws/%E2%98%95"),
+            ("https://i❤️.ws", "https://xn--i-7iq.ws"),
+            // Mixed scripts: the first a is Cyrillic
+            (
+                "https://pаypal.com/login",
+                "https://xn--pypal-4ve.com/login",
+            ),
+            (
+                "https://例え.jp/パス",
+                "https://xn--r8jz45g.jp/%E3%83%91%E3%82%B9",
+            ),
+            ("bücher.de/ä", "xn--bcher-kva.de/%C3%A4"),
+            // Already ASCII, escapes included
+            (
+                "https://xn--mnchen-3ya.de/stra%C3%9Fe",
+                "https://xn--mnchen-3ya.de/stra%C3%9Fe",
+            ),
+        ];
+        for (input, expected) in cases {
+            let normalized = normalize(input, &NormalizeRules::default());
+            assert_eq!(normalized.canonical, expected, "{input}");
+            assert!(!normalized.lossy, "{input}");
+        }
+        let kept = NormalizeRules {
+            ascii: false,
+            ..NormalizeRules::default()
+        };
+        assert_eq!(
+            normalize("https://münchen.de/straße", &kept).canonical,
+            "https://münchen.de/straße"
+        );
+    }
+
+    #[test]
+    fn only_dropped_parameters_are_lossy() {
+        assert!(!normalize("HTTPS://Example.com:443/a/", &all_rules()).lossy);
+        assert!(normalize("https://example.com/a?gclid=1", &all_rules()).lossy);
+        assert!(!normalize("https://example.com/a?gclid=1", &NormalizeRules::default()).lossy);
+    }
+}
@@ -0,0 +1,1503 @@
+use axum::http::Method;
+use serde_json::{json, Map, Value};
+
+use crate::user::cookie::AUTH_COOKIE_NAME;
+
+const JSON: &str = "application/json";
+
+/// The schema of a body in each media type it can be sent in
+type Content = Vec<(&'static str, Value)>;
+
+/// The code of the link examples are about. The contract tests seed a link for each example and
+/// send its code wherever this is.
+pub const EXAMPLE_CODE: &str = "launch";
+/// The user examples hand links to, swapped for a seeded user the same way
+pub const EXAMPLE_USER: &str = "bob";
+/// The id in the paths of examples about an import, template, announcement or rebuild
+const EXAMPLE_ID: i64 = 1;
+
+/// Who an example sends its request as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Caller {
+    Anonymous,
+    /// Signed in, but neither the owner of what the request is about nor an admin. Sessions have
+    /// no scopes, so this is what a token that isn't allowed the operation looks like.
+    SignedIn,
+    /// Signed in as the owner of the link, import or template the request is about
+    Owner,
+    Admin,
+}
+
+impl Caller {
+    pub const ALL: [Caller; 4] = [
+        Caller::Anonymous,
+        Caller::SignedIn,
+        Caller::Owner,
+        Caller::Admin,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Caller::Anonymous => "anonymous",
+            Caller::SignedIn => "signed_in",
+            Caller::Owner => "owner",
+            Caller::Admin => "admin",
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Caller::Anonymous => "someone signed out",
+            Caller::SignedIn => "someone else signed in",
+            Caller::Owner => "the owner",
+            Caller::Admin => "an admin",
+        }
+    }
+}
+
+/// A request to an operation and the status it is answered with. The contract tests send every
+/// one and check the response against the document, which shows them as examples.
+pub struct Example {
+    pub caller: Caller,
+    pub status: u16,
+    /// Without the `?`
+    pub query: Option<&'static str>,
+    /// JSON, or a string sent as is for other media types
+    pub body: Option<Value>,
+}
+
+/// One method on one path of the API
+pub struct Operation {
+    pub method: Method,
+    /// As routed, e.g. `/api/urls/:code`
+    pub path: &'static str,
+    summary: &'static str,
+    signed_in: bool,
+    params: Vec<(&'static str, Value, &'static str)>,
+    request: Option<(&'static str, Value)>,
+    responses: Vec<(u16, &'static str, Content)>,
+    pub examples: Vec<Example>,
+}
+
+impl Operation {
+    fn new(method: Method, path: &'static str, summary: &'static str) -> Self {
+        Operation {
+            method,
+            path,
+            summary,
+            signed_in: false,
+            params: Vec::new(),
+            request: None,
+            responses: Vec::new(),
+            examples: Vec::new(),
+        }
+        .error(503, "Took too long, or the database couldn't be reached")
+    }
+
+    /// Needs a session, and answers 401 with no body without one
+    fn signed_in(mut self) -> Self {
+        self.signed_in = true;
+        self.empty(401, "Not signed in, or the session ended")
+    }
+
+    /// Needs an admin's session, and answers 403 with no body to anyone else
+    fn admins_only(self) -> Self {
+        self.signed_in().empty(403, "Not an admin")
+    }
+
+    fn query(mut self, name: &'static str, schema: Value, description: &'static str) -> Self {
+        self.params.push((name, schema, description));
+        self
+    }
+
+    fn body(mut self, media_type: &'static str, schema: Value) -> Self {
+        self.request = Some((media_type, schema));
+        self
+    }
+
+    fn json_body(self, schema: Value) -> Self {
+        self.body(JSON, schema)
+    }
+
+    fn responds(mut self, status: u16, description: &'static str, content: Content) -> Self {
+        self.responses
+            .retain(|(documented, _, _)| *documented != status);
+        self.responses.push((status, description, content));
+        self
+    }
+
+    fn json(self, status: u16, description: &'static str, schema: Value) -> Self {
+        self.responds(status, description, vec![(JSON, schema)])
+    }
+
+    fn error(self, status: u16, description: &'static str) -> Self {
+        self.json(status, description, reference("Error"))
+    }
+
+    fn empty(self, status: u16, description: &'static str) -> Self {
+        self.responds(status, description, Vec::new())
+    }
+
+    /// The same request sent as every [Caller], answered with the statuses in their order
+    fn examples(
+        mut self,
+        query: Option<&'static str>,
+        body: Option<Value>,
+        statuses: [u16; 4],
+    ) -> Self {
+        for (caller, status) in Caller::ALL.into_iter().zip(statuses) {
+            self.examples.push(Example {
+                caller,
+                status,
+                query,
+                body: body.clone(),
+            });
+        }
+        self
+    }
+
+    fn example(
+        mut self,
+        caller: Caller,
+        status: u16,
+        query: Option<&'static str>,
+        body: Option<Value>,
+    ) -> Self {
+        self.examples.push(Example {
+            caller,
+            status,
+            query,
+            body,
+        });
+        self
+    }
+
+    /// The path as the document has it, e.g. `/api/urls/{code}`
+    pub fn document_path(&self) -> String {
+        self.path
+            .split('/')
+            .map(|segment| match segment.strip_prefix(':') {
+                Some(param) => format!("{{{param}}}"),
+                None => segment.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    fn path_params(&self) -> impl Iterator<Item = &'static str> {
+        self.path
+            .split('/')
+            .filter_map(|segment| segment.strip_prefix(':'))
+    }
+
+    fn describe(&self) -> Value {
+        let named = |example: &Example| {
+            (
+                format!("{}_{}", example.caller.name(), example.status),
+                format!(
+                    "Sent by {}, answered {}",
+                    example.caller.describe(),
+                    example.status
+                ),
+            )
+        };
+        let mut parameters: Vec<Value> = self
+            .path_params()
+            .map(|name| {
+                let (schema, example) = match name {
+                    "code" => (string(), json!(EXAMPLE_CODE)),
+                    _ => (integer(), json!(EXAMPLE_ID)),
+                };
+                json!({
+                    "name": name,
+                    "in": "path",
+                    "required": true,
+                    "schema": schema,
+                    "example": example,
+                })
+            })
+            .collect();
+        for (name, schema, description) in &self.params {
+            let mut examples = Map::new();
+            for example in &self.examples {
+                let value = example
+                    .query
+                    .into_iter()
+                    .flat_map(|query| query.split('&'))
+                    .filter_map(|pair| pair.split_once('='))
+                    .find_map(|(key, value)| (key == *name).then_some(value));
+                if let Some(value) = value {
+                    let (key, summary) = named(example);
+                    examples.insert(key, json!({"summary": summary, "value": value}));
+                }
+            }
+            let mut parameter = json!({
+                "name": name,
+                "in": "query",
+                "required": false,
+                "description": description,
+                "schema": schema,
+            });
+            if !examples.is_empty() {
+                parameter["examples"] = Value::Object(examples);
+            }
+            parameters.push(parameter);
+        }
+
+        let mut responses = Map::new();
+        let mut sorted: Vec<_> = self.responses.iter().collect();
+        sorted.sort_by_key(|(status, _, _)| *status);
+        for (status, description, content) in sorted {
+            let mut response = json!({ "description": description });
+            if !content.is_empty() {
+                let content: Map<String, Value> = content
+                    .iter()
+                    .map(|(media_type, schema)| {
+                        (media_type.to_string(), json!({ "schema": schema }))
+                    })
+                    .collect();
+                response["content"] = Value::Object(content);
+            }
+            responses.insert(status.to_string(), response);
+        }
+
+        let mut operation = json!({
+            "summary": self.summary,
+            "responses": responses,
+        });
+        if !parameters.is_empty() {
+            operation["parameters"] = Value::Array(parameters);
+        }
+        if let Some((media_type, schema)) = &self.request {
+            let mut examples = Map::new();
+            for example in &self.examples {
+                if let Some(body) = &example.body {
+                    let (key, summary) = named(example);
+                    examples.insert(key, json!({"summary": summary, "value": body}));
+                }
+            }
+            operation["requestBody"] = json!({
+                "required": true,
+                "content": {
+                    *media_type: { "schema": schema, "examples": examples },
+                },
+            });
+        }
+        if self.signed_in {
+            operation["security"] = json!([{ "session": [] }]);
+        }
+        operation
+    }
+}
+
+fn string() -> Value {
+    json!({ "type": "string" })
+}
+
+fn integer() -> Value {
+    json!({ "type": "integer" })
+}
+
+fn number() -> Value {
+    json!({ "type": "number" })
+}
+
+fn boolean() -> Value {
+    json!({ "type": "boolean" })
+}
+
+fn date() -> Value {
+    json!({ "type": "string", "format": "date" })
+}
+
+fn date_time() -> Value {
+    json!({ "type": "string", "format": "date-time" })
+}
+
+/// A string that is one of `values`
+fn one_of(values: &[&str]) -> Value {
+    json!({ "type": "string", "enum": values })
+}
+
+fn nullable(schema: Value) -> Value {
+    json!({ "anyOf": [schema, { "type": "null" }] })
+}
+
+fn array(items: Value) -> Value {
+    json!({ "type": "array", "items": items })
+}
+
+/// An object with any keys, each holding a `values`
+fn map(values: Value) -> Value {
+    json!({ "type": "object", "additionalProperties": values })
+}
+
+fn reference(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{name}") })
+}
+
+/// An object with exactly these properties, of which the `optional` ones may be left out. Any
+/// other property doesn't match, so a field a handler adds has to be documented first.
+fn object<const N: usize>(properties: [(&str, Value); N], optional: &[&str]) -> Value {
+    let required: Vec<&str> = properties
+        .iter()
+        .map(|(name, _)| *name)
+        .filter(|name| !optional.contains(name))
+        .collect();
+    let properties: Map<String, Value> = properties
+        .into_iter()
+        .map(|(name, schema)| (name.to_string(), schema))
+        .collect();
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+        "additionalProperties": false,
+    })
+}
+
+/// `{"dry_run": ..., "count": ..., "affected": [...]}`, see [crate::dry_run::Affected]
+fn affected(items: Value) -> Value {
+    object(
+        [
+            ("dry_run", boolean()),
+            ("count", integer()),
+            ("affected", array(items)),
+        ],
+        &[],
+    )
+}
+
+fn schemas() -> Value {
+    json!({
+        "Error": object([("error", reference("ErrorDetail"))], &[]),
+        "ErrorDetail": object([("code", string()), ("message", string())], &[]),
+        "Features": object(
+            [
+                (
+                    "features",
+                    object(
+                        [
+                            ("single_use", boolean()),
+                            ("preflight", boolean()),
+                            ("click_stream", boolean()),
+                            ("missed_lookups", boolean()),
+                            ("link_templates", boolean()),
+                            ("conversions", boolean()),
+                        ],
+                        &[],
+                    ),
+                ),
+                (
+                    "limits",
+                    object(
+                        [
+                            ("code_length", integer()),
+                            ("max_interstitial_seconds", integer()),
+                            ("max_alias_length", integer()),
+                            ("max_click_streams", integer()),
+                        ],
+                        &[],
+                    ),
+                ),
+            ],
+            &[],
+        ),
+        "NewLink": object(
+            [
+                ("url", string()),
+                ("alias", string()),
+                ("tags", string()),
+                ("description", string()),
+                ("destinations", array(reference("NewDestination"))),
+                ("active_from", date_time()),
+                ("single_use", boolean()),
+            ],
+            &[
+                "url",
+                "alias",
+                "tags",
+                "description",
+                "destinations",
+                "active_from",
+                "single_use",
+            ],
+        ),
+        "NewDestination": object([("url", string()), ("weight", integer())], &["weight"]),
+        "Destination": object(
+            [
+                ("long_url", string()),
+                ("weight", integer()),
+                ("clicks", integer()),
+            ],
+            &[],
+        ),
+        "Link": object(
+            [
+                ("short_url", string()),
+                ("long_url", string()),
+                ("id", integer()),
+                ("full_url", string()),
+                ("clicks", integer()),
+                ("tags", array(string())),
+                ("description", string()),
+                ("archived_at", date_time()),
+                ("active_from", date_time()),
+                ("single_use", boolean()),
+                ("destinations", array(reference("Destination"))),
+                ("rollout_until", date_time()),
+                ("geo_targets", map(string())),
+            ],
+            &[
+                "tags",
+                "description",
+                "archived_at",
+                "active_from",
+                "single_use",
+                "destinations",
+                "rollout_until",
+                "geo_targets",
+            ],
+        ),
+        "LinkPage": object(
+            [
+                ("items", array(reference("Link"))),
+                ("total", integer()),
+                ("page", integer()),
+                ("per_page", integer()),
+            ],
+            &[],
+        ),
+        "BulkResults": object(
+            [(
+                "results",
+                array(object(
+                    [
+                        ("index", integer()),
+                        ("status", one_of(&["created", "failed", "rolled_back", "skipped"])),
+                        ("link", reference("Link")),
+                        ("error", reference("ErrorDetail")),
+                    ],
+                    &["link", "error"],
+                )),
+            )],
+            &[],
+        ),
+        "ImportReport": object(
+            [
+                ("imported", integer()),
+                ("skipped", integer()),
+                ("invalid", integer()),
+                (
+                    "errors",
+                    array(object(
+                        [
+                            ("line", integer()),
+                            ("outcome", one_of(&["skipped", "invalid"])),
+                            ("reason", string()),
+                        ],
+                        &[],
+                    )),
+                ),
+            ],
+            &[],
+        ),
+        "Import": object(
+            [
+                ("id", integer()),
+                (
+                    "status",
+                    one_of(&["queued", "running", "finished", "cancelled", "failed"]),
+                ),
+                ("parsed", nullable(integer())),
+                ("processed", integer()),
+                ("imported", integer()),
+                ("skipped", integer()),
+                ("invalid", integer()),
+                ("cancel_requested", boolean()),
+                ("error", nullable(string())),
+                ("created_at", date_time()),
+                ("finished_at", nullable(date_time())),
+            ],
+            &[],
+        ),
+        "EditLink": object(
+            [
+                ("url", string()),
+                ("description", string()),
+                ("destinations", array(reference("NewDestination"))),
+                ("rollout_percent", integer()),
+                ("geo_targets", map(string())),
+            ],
+            &[
+                "url",
+                "description",
+                "destinations",
+                "rollout_percent",
+                "geo_targets",
+            ],
+        ),
+        "LinkStats": object(
+            [
+                ("short_url", string()),
+                ("long_url", string()),
+                ("full_url", string()),
+                ("clicks", integer()),
+                ("bot_clicks", integer()),
+                ("created_at", nullable(date_time())),
+                ("expires_at", nullable(date_time())),
+                ("active_from", nullable(date_time())),
+                ("max_clicks", nullable(integer())),
+                ("interstitial_seconds", integer()),
+                ("single_use", boolean()),
+                ("consumed_at", nullable(date_time())),
+                ("protected", boolean()),
+                (
+                    "redirect_status",
+                    nullable(json!({ "type": "integer", "enum": [301, 302, 307, 308] })),
+                ),
+                (
+                    "daily",
+                    array(object(
+                        [
+                            ("day", date()),
+                            ("count", integer()),
+                            ("rebuilding", boolean()),
+                        ],
+                        &["rebuilding"],
+                    )),
+                ),
+                (
+                    "top_referrers",
+                    array(object(
+                        [("referrer", string()), ("count", integer())],
+                        &[],
+                    )),
+                ),
+                ("devices", map(integer())),
+                ("conversions", integer()),
+                ("conversion_rate", nullable(number())),
+                ("destinations", array(reference("Destination"))),
+                ("rollout_until", date_time()),
+                ("geo_targets", map(string())),
+                ("geo_clicks", map(integer())),
+            ],
+            &["destinations", "rollout_until", "geo_targets", "geo_clicks"],
+        ),
+        "LinkHistory": object(
+            [
+                ("short_url", string()),
+                (
+                    "edits",
+                    array(object(
+                        [
+                            ("field", string()),
+                            ("old_value", nullable(string())),
+                            ("new_value", nullable(string())),
+                            ("edited_by", nullable(string())),
+                            ("edited_at", date_time()),
+                        ],
+                        &[],
+                    )),
+                ),
+            ],
+            &[],
+        ),
+        "NewTemplate": object(
+            [
+                ("alias", string()),
+                ("destination", string()),
+                ("value_pattern", string()),
+                ("max_value_len", integer()),
+            ],
+            &["value_pattern", "max_value_len"],
+        ),
+        "Template": object(
+            [
+                ("id", integer()),
+                ("alias", string()),
+                ("destination", string()),
+                ("value_pattern", nullable(string())),
+                ("max_value_len", integer()),
+                ("clicks", integer()),
+            ],
+            &[],
+        ),
+        "Preflight": object(
+            [
+                ("code", string()),
+                ("exists", boolean()),
+                ("destination_host", string()),
+                ("destination", string()),
+                (
+                    "gate",
+                    one_of(&[
+                        "none",
+                        "countdown",
+                        "single_use",
+                        "pending",
+                        "expired",
+                        "exhausted",
+                        "passphrase",
+                        "unreachable",
+                    ]),
+                ),
+                ("countdown_seconds", integer()),
+                ("consumed_at", date_time()),
+                ("expires_at", date_time()),
+                ("active_from", date_time()),
+            ],
+            &[
+                "destination_host",
+                "destination",
+                "gate",
+                "countdown_seconds",
+                "consumed_at",
+                "expires_at",
+                "active_from",
+            ],
+        ),
+        "NewAnnouncement": object(
+            [
+                ("message", string()),
+                ("severity", reference("Severity")),
+                ("starts_at", date_time()),
+                ("ends_at", date_time()),
+                ("dismissible", boolean()),
+                ("public", boolean()),
+            ],
+            &["starts_at", "ends_at", "dismissible", "public"],
+        ),
+        "Announcement": object(
+            [
+                ("id", integer()),
+                ("message", string()),
+                ("severity", reference("Severity")),
+                ("starts_at", date_time()),
+                ("ends_at", nullable(date_time())),
+                ("dismissible", boolean()),
+                ("public", boolean()),
+            ],
+            &[],
+        ),
+        "Severity": one_of(&["info", "warning", "critical"]),
+        "RebuildRequest": object(
+            [
+                ("from", date()),
+                ("to", date()),
+                ("url", string()),
+                ("user", string()),
+            ],
+            &["url", "user"],
+        ),
+        "Rebuild": object(
+            [
+                ("id", integer()),
+                ("url_id", nullable(integer())),
+                ("user_id", nullable(integer())),
+                ("from_day", date()),
+                ("to_day", date()),
+                ("next_day", date()),
+                ("started_by", string()),
+                ("started_at", date_time()),
+                ("finished_at", nullable(date_time())),
+            ],
+            &[],
+        ),
+        "DayChange": object(
+            [
+                ("url_id", integer()),
+                ("day", date()),
+                ("recorded", integer()),
+                ("rebuilt", integer()),
+            ],
+            &[],
+        ),
+    })
+}
+
+/// Every operation of the API, with the examples the contract tests send
+pub fn operations() -> Vec<Operation> {
+    use Caller::*;
+    let link = json!({
+        "url": "https://example.com/spring-sale",
+        "tags": "newsletter,spring",
+        "description": "Spring sale landing page",
+    });
+    let announcement = json!({
+        "message": "Maintenance on Sunday from 02:00 UTC",
+        "severity": "warning",
+        "starts_at": "2099-06-07T02:00:00Z",
+        "ends_at": "2099-06-07T04:00:00Z",
+    });
+    vec![
+        Operation::new(Method::GET, "/api/openapi.json", "This document")
+            .json(200, "The OpenAPI document", json!({ "type": "object" }))
+            .examples(None, None, [200, 200, 200, 200]),
+        Operation::new(
+            Method::GET,
+            "/api/features",
+            "The optional features enabled here and the limits clients should respect",
+        )
+        .json(200, "Features and limits", reference("Features"))
+        .examples(None, None, [200, 200, 200, 200]),
+        Operation::new(
+            Method::GET,
+            "/api/urls",
+            "The signed in user's links, newest first",
+        )
+        .signed_in()
+        .query("page", integer(), "From 1")
+        .query("per_page", integer(), "Links per page")
+        .query("tag", string(), "Only links with this tag")
+        .query(
+            "archived",
+            boolean(),
+            "The archived links instead of the live ones",
+        )
+        .json(200, "A page of links", reference("LinkPage"))
+        .examples(Some("page=1&per_page=20"), None, [401, 200, 200, 200])
+        .example(Owner, 200, Some("tag=newsletter&archived=true"), None),
+        Operation::new(
+            Method::POST,
+            "/api/urls",
+            "Shortens a url, for the signed in user or anonymously",
+        )
+        .json_body(reference("NewLink"))
+        .json(200, "The link made", reference("Link"))
+        .error(400, "Not a link that can be made")
+        .error(403, "The destination's domain is blocked")
+        .error(409, "The alias is taken")
+        .error(413, "The url is too long")
+        .error(429, "Too many links were made from this address")
+        .examples(None, Some(link.clone()), [200, 200, 200, 200])
+        .example(Owner, 400, None, Some(json!({ "url": "not a link" }))),
+        Operation::new(
+            Method::POST,
+            "/api/urls/bulk",
+            "Shortens up to 500 urls for the signed in user in one transaction",
+        )
+        .signed_in()
+        .query(
+            "on_error",
+            one_of(&["continue", "abort"]),
+            "Whether a failed link undoes the others",
+        )
+        .json_body(array(reference("NewLink")))
+        .json(200, "What happened to each link", reference("BulkResults"))
+        .json(
+            422,
+            "A link failed with on_error=abort, so none were made",
+            reference("BulkResults"),
+        )
+        .error(400, "Not a list of links")
+        .error(413, "More than 500 links")
+        .error(429, "Too many links were made from this address")
+        .examples(
+            Some("on_error=continue"),
+            Some(json!([link, { "url": "https://example.com/autumn-sale" }])),
+            [401, 200, 200, 200],
+        )
+        .example(
+            Owner,
+            422,
+            Some("on_error=abort"),
+            Some(json!([{ "url": "https://example.com/ok" }, { "url": "not a link" }])),
+        ),
+        Operation::new(
+            Method::POST,
+            "/api/imports",
+            "Imports a CSV of `longurl[,shorturl][,created_by]` rows for the signed in user",
+        )
+        .signed_in()
+        .body("text/csv", string())
+        .json(
+            200,
+            "Imported while the upload waited",
+            reference("ImportReport"),
+        )
+        .json(
+            202,
+            "Too large to import while the upload waits, so it goes on in the background",
+            reference("Import"),
+        )
+        .error(400, "Not valid CSV")
+        .error(408, "The upload stopped before it was finished")
+        .error(413, "The file is too large")
+        .error(415, "Not sent as text/csv")
+        .error(429, "Too many links were made from this address")
+        .examples(
+            None,
+            Some(json!(
+                "https://example.com/imported\nhttps://example.com/also-imported\n"
+            )),
+            [401, 200, 200, 200],
+        ),
+        Operation::new(
+            Method::GET,
+            "/api/imports/:id",
+            "The progress of one of the signed in user's imports",
+        )
+        .signed_in()
+        .json(200, "The import", reference("Import"))
+        .error(404, "The user has no such import")
+        .examples(None, None, [401, 404, 200, 404]),
+        Operation::new(
+            Method::DELETE,
+            "/api/imports/:id",
+            "Stops one of the signed in user's imports at the end of the batch it's on",
+        )
+        .signed_in()
+        .json(202, "The import, stopping", reference("Import"))
+        .error(404, "The user has no such import")
+        .error(409, "The import already stopped")
+        .examples(None, None, [401, 404, 409, 404]),
+        Operation::new(
+            Method::GET,
+            "/api/imports/:id/errors",
+            "The rows of one of the signed in user's imports that weren't imported",
+        )
+        .signed_in()
+        .responds(
+            200,
+            "A `line,outcome,reason` CSV",
+            vec![("text/csv", string())],
+        )
+        .error(404, "The user has no such import")
+        .examples(None, None, [401, 404, 200, 404]),
+        Operation::new(
+            Method::DELETE,
+            "/api/urls/:code",
+            "Archives one of the signed in user's links, or deletes it for good",
+        )
+        .signed_in()
+        .query("purge", boolean(), "Delete the link for good. Admins only.")
+        .query(
+            "dry_run",
+            boolean(),
+            "Only answer with what would be deleted",
+        )
+        .json(200, "What a dry run would delete", affected(string()))
+        .empty(204, "Deleted")
+        .error(
+            403,
+            "Not the owner's link, or a purge by someone not an admin",
+        )
+        .error(404, "There is no such link")
+        .examples(None, None, [401, 403, 204, 403])
+        .example(Owner, 200, Some("dry_run=true"), None),
+        Operation::new(
+            Method::PATCH,
+            "/api/urls/:code",
+            "Changes where one of the signed in user's links goes, or its description",
+        )
+        .signed_in()
+        .json_body(reference("EditLink"))
+        .json(200, "The link changed", reference("Link"))
+        .error(400, "Nothing or something invalid to change")
+        .error(403, "Not the owner's link")
+        .error(404, "There is no such link")
+        .examples(
+            None,
+            Some(json!({ "description": "Spring sale, extended" })),
+            [401, 403, 200, 403],
+        ),
+        Operation::new(
+            Method::POST,
+            "/api/urls/:code/restore",
+            "Brings back one of the signed in user's archived links",
+        )
+        .signed_in()
+        .json(200, "The link restored", reference("Link"))
+        .error(403, "Not the owner's link")
+        .error(404, "There is no such archived link")
+        .examples(None, None, [401, 403, 200, 403]),
+        Operation::new(
+            Method::POST,
+            "/api/urls/:code/transfer",
+            "Hands one of the signed in user's links to another user",
+        )
+        .signed_in()
+        .json_body(object([("username", string())], &[]))
+        .json(200, "The link, now the other user's", reference("Link"))
+        .error(400, "No username")
+        .error(403, "Not the owner's link")
+        .error(404, "There is no such link or user")
+        .error(
+            409,
+            "The user already owns the link, or it changed meanwhile",
+        )
+        .examples(
+            None,
+            Some(json!({ "username": EXAMPLE_USER })),
+            [401, 403, 200, 403],
+        ),
+        Operation::new(
+            Method::POST,
+            "/api/urls/:code/rollout/commit",
+            "Sends every visitor to the url one of the signed in user's links is rolling out",
+        )
+        .signed_in()
+        .json(200, "The link, no longer rolling out", reference("Link"))
+        .error(403, "Not the owner's link")
+        .error(404, "There is no such link")
+        .error(409, "The link isn't rolling out a url")
+        .examples(None, None, [401, 403, 200, 403]),
+        Operation::new(
+            Method::GET,
+            "/api/urls/:code/stats",
+            "Clicks and settings of one of the signed in user's links",
+        )
+        .signed_in()
+        .query(
+            "from",
+            date(),
+            "First day of click history, 29 days before `to` by default",
+        )
+        .query("to", date(), "Last day of click history, today by default")
+        .json(200, "The link's stats", reference("LinkStats"))
+        .error(400, "The days are backwards or over a year apart")
+        .error(403, "Not the owner's link")
+        .error(404, "There is no such link")
+        .examples(None, None, [401, 403, 200, 403])
+        .example(Owner, 400, Some("from=2024-03-31&to=2024-03-01"), None),
+        Operation::new(
+            Method::GET,
+            "/api/urls/:code/history",
+            "The changes made to one of the signed in user's links, oldest first",
+        )
+        .signed_in()
+        .responds(
+            200,
+            "The changes, or the history panel for htmx requests",
+            vec![(JSON, reference("LinkHistory")), ("text/html", string())],
+        )
+        .error(403, "Not the owner's link")
+        .error(404, "There is no such link")
+        .examples(None, None, [401, 403, 200, 403]),
+        Operation::new(
+            Method::GET,
+            "/api/urls/:code/qr",
+            "A QR code for a link's short url",
+        )
+        .query("format", one_of(&["svg", "png"]), "svg by default")
+        .query("size", integer(), "Pixels per module, from 1 to 64")
+        .responds(
+            200,
+            "The QR code",
+            vec![("image/svg+xml", string()), ("image/png", string())],
+        )
+        .error(400, "The size is out of bounds")
+        .error(404, "There is no such link")
+        .examples(None, None, [200, 200, 200, 200])
+        .example(Anonymous, 200, Some("format=png&size=4"), None)
+        .example(Anonymous, 400, Some("size=65"), None),
+        Operation::new(
+            Method::GET,
+            "/api/preflight/:code",
+            "Where a link goes and what a visitor would hit, without visiting it",
+        )
+        .signed_in()
+        .json(200, "The link described", reference("Preflight"))
+        .examples(None, None, [401, 200, 200, 200]),
+        Operation::new(
+            Method::GET,
+            "/api/templates",
+            "The signed in user's link templates",
+        )
+        .signed_in()
+        .json(200, "The templates", array(reference("Template")))
+        .examples(None, None, [401, 200, 200, 200]),
+        Operation::new(
+            Method::POST,
+            "/api/templates",
+            "Makes a link template for the signed in user",
+        )
+        .signed_in()
+        .json_body(reference("NewTemplate"))
+        .json(201, "The template made", reference("Template"))
+        .error(400, "Not a valid template")
+        .error(
+            409,
+            "Some codes would match this template and an existing one",
+        )
+        .examples(
+            None,
+            Some(json!({
+                "alias": format!("{EXAMPLE_CODE}-{{variant}}"),
+                "destination": "https://example.com/spring-sale?variant={variant}",
+            })),
+            [401, 201, 201, 201],
+        ),
+        Operation::new(
+            Method::DELETE,
+            "/api/templates/:id",
+            "Deletes one of the signed in user's link templates",
+        )
+        .signed_in()
+        .empty(204, "Deleted")
+        .error(403, "Not the owner's template")
+        .error(404, "There is no such template")
+        .examples(None, None, [401, 403, 204, 403]),
+        Operation::new(
+            Method::GET,
+            "/api/templates/:id/clicks",
+            "Clicks on each value of one of the signed in user's templates, most first",
+        )
+        .signed_in()
+        .query("limit", integer(), "From 1 to 1000, 100 by default")
+        .json(
+            200,
+            "Clicks per value",
+            array(object([("value", string()), ("clicks", integer())], &[])),
+        )
+        .error(403, "Not the owner's template")
+        .error(404, "There is no such template")
+        .examples(Some("limit=10"), None, [401, 403, 200, 403]),
+        Operation::new(
+            Method::GET,
+            "/api/stream/clicks",
+            "Clicks as they are counted: the signed in user's, or every click for admins",
+        )
+        .signed_in()
+        .query(
+            "format",
+            one_of(&["ndjson", "sse"]),
+            "Newline delimited JSON by default, or server-sent events",
+        )
+        .query(
+            "codes",
+            string(),
+            "Comma separated codes to limit the stream to",
+        )
+        .responds(
+            200,
+            "The stream, which stays open",
+            vec![
+                ("application/x-ndjson", string()),
+                ("text/event-stream", string()),
+            ],
+        )
+        .error(429, "Too many streams are open")
+        .examples(None, None, [401, 200, 200, 200])
+        .example(Owner, 200, Some("format=sse&codes=launch"), None),
+        Operation::new(
+            Method::GET,
+            "/api/admin/missed",
+            "Codes requested that don't exist, most requested first",
+        )
+        .admins_only()
+        .query("limit", integer(), "100 by default")
+        .json(
+            200,
+            "The codes",
+            array(object(
+                [
+                    ("code", string()),
+                    ("count", integer()),
+                    ("last_seen", integer()),
+                ],
+                &[],
+            )),
+        )
+        .examples(Some("limit=10"), None, [401, 403, 403, 200]),
+        Operation::new(
+            Method::GET,
+            "/api/admin/hsts",
+            "Whether the configuration meets the HSTS preload list's requirements",
+        )
+        .admins_only()
+        .json(
+            200,
+            "Readiness, and what's missing",
+            object(
+                [
+                    ("ready", boolean()),
+                    ("header", nullable(string())),
+                    ("failures", array(string())),
+                ],
+                &[],
+            ),
+        )
+        .examples(None, None, [401, 403, 403, 200]),
+        Operation::new(
+            Method::POST,
+            "/api/admin/db/recycle",
+            "Retires every pooled database connection",
+        )
+        .admins_only()
+        .json(
+            200,
+            "How many idle connections were closed",
+            object([("closed_idle", integer())], &[]),
+        )
+        .examples(None, None, [401, 403, 403, 200]),
+        Operation::new(
+            Method::POST,
+            "/api/admin/cache/bump",
+            "Changes every ETag this instance hands out",
+        )
+        .admins_only()
+        .json(
+            200,
+            "The new cache generation",
+            object([("generation", integer())], &[]),
+        )
+        .examples(None, None, [401, 403, 403, 200]),
+        Operation::new(
+            Method::POST,
+            "/api/admin/rollups/rebuild",
+            "Recomputes the daily clicks of links from the clicks recorded",
+        )
+        .admins_only()
+        .query(
+            "dry_run",
+            boolean(),
+            "Only answer with the days that would change",
+        )
+        .json_body(reference("RebuildRequest"))
+        .json(
+            200,
+            "The days a dry run would change",
+            affected(reference("DayChange")),
+        )
+        .json(202, "The rebuild, running", reference("Rebuild"))
+        .error(400, "The days are backwards or not all recorded")
+        .error(404, "There is no such link or user")
+        .error(
+            409,
+            "Clicks aren't recorded, or another rebuild covers some of the days",
+        )
+        .examples(
+            Some("dry_run=true"),
+            Some(json!({ "from": "2024-03-01", "to": "2024-03-31", "url": EXAMPLE_CODE })),
+            [401, 403, 403, 200],
+        )
+        .example(
+            Admin,
+            400,
+            Some("dry_run=true"),
+            Some(json!({ "from": "2024-03-31", "to": "2024-03-01" })),
+        ),
+        Operation::new(
+            Method::GET,
+            "/api/admin/rollups/rebuild/:id",
+            "How far a rollup rebuild has got",
+        )
+        .admins_only()
+        .json(200, "The rebuild", reference("Rebuild"))
+        .error(404, "There is no such rebuild")
+        .examples(None, None, [401, 403, 403, 200]),
+        Operation::new(
+            Method::POST,
+            "/api/admin/archive/purge",
+            "Deletes links archived a while ago for good",
+        )
+        .admins_only()
+        .query(
+            "older_than_days",
+            integer(),
+            "Defaults to the configured retention",
+        )
+        .query(
+            "dry_run",
+            boolean(),
+            "Only answer with what would be deleted",
+        )
+        .json(200, "The codes of the links deleted", affected(string()))
+        .error(400, "No retention is given or configured")
+        .examples(
+            Some("older_than_days=3650&dry_run=true"),
+            None,
+            [401, 403, 403, 200],
+        ),
+        Operation::new(
+            Method::POST,
+            "/api/admin/urls/:code/hot",
+            "Counts a busy link's clicks over several rows",
+        )
+        .signed_in()
+        .empty(204, "The link is hot")
+        .error(403, "Not an admin")
+        .error(404, "There is no such link")
+        .error(409, "The link has a click limit or is single use")
+        .examples(None, None, [401, 403, 403, 204]),
+        Operation::new(
+            Method::DELETE,
+            "/api/admin/urls/:code/hot",
+            "Counts a hot link's clicks in its own row again",
+        )
+        .signed_in()
+        .empty(204, "The link isn't hot")
+        .error(403, "Not an admin")
+        .error(404, "There is no such link")
+        .examples(None, None, [401, 403, 403, 204]),
+        Operation::new(
+            Method::GET,
+            "/api/admin/announcements",
+            "Every announcement, including ended and upcoming ones",
+        )
+        .admins_only()
+        .json(200, "The announcements", array(reference("Announcement")))
+        .examples(None, None, [401, 403, 403, 200]),
+        Operation::new(
+            Method::POST,
+            "/api/admin/announcements",
+            "Adds an announcement",
+        )
+        .admins_only()
+        .json_body(reference("NewAnnouncement"))
+        .json(201, "The announcement added", reference("Announcement"))
+        .error(400, "Not a valid announcement")
+        .examples(None, Some(announcement.clone()), [401, 403, 403, 201]),
+        Operation::new(
+            Method::PUT,
+            "/api/admin/announcements/:id",
+            "Replaces an announcement",
+        )
+        .admins_only()
+        .json_body(reference("NewAnnouncement"))
+        .json(
+            200,
+            "The announcement as replaced",
+            reference("Announcement"),
+        )
+        .error(400, "Not a valid announcement")
+        .error(404, "There is no such announcement")
+        .examples(None, Some(announcement), [401, 403, 403, 200]),
+        Operation::new(
+            Method::DELETE,
+            "/api/admin/announcements/:id",
+            "Removes an announcement",
+        )
+        .admins_only()
+        .query(
+            "dry_run",
+            boolean(),
+            "Only answer with what would be removed",
+        )
+        .json(200, "What a dry run would remove", affected(integer()))
+        .empty(204, "Removed")
+        .error(404, "There is no such announcement")
+        .examples(None, None, [401, 403, 403, 204])
+        .example(Admin, 200, Some("dry_run=true"), None),
+    ]
+}
+
+/// The OpenAPI document of the API, built from [operations]
+pub fn document() -> Value {
+    let mut paths = Map::new();
+    for operation in operations() {
+        let path = paths
+            .entry(operation.document_path())
+            .or_insert_with(|| json!({}));
+        path[operation.method.as_str().to_lowercase()] = operation.describe();
+    }
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "URL shortener",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas(),
+            "securitySchemes": {
+                "session": { "type": "apiKey", "in": "cookie", "name": AUTH_COOKIE_NAME },
+            },
+        },
+    })
+}
+
+/// Checks `value` against `schema`, resolving references in `document`, for the parts of JSON
+/// Schema the document uses. Returns where the first mismatch is and what it is.
+#[cfg(test)]
+pub fn validate(document: &Value, schema: &Value, value: &Value) -> Result<(), String> {
+    validate_at(document, schema, value, "$")
+}
+
+#[cfg(test)]
+fn validate_at(document: &Value, schema: &Value, value: &Value, at: &str) -> Result<(), String> {
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        let name = reference
+            .strip_prefix("#/components/schemas/")
+            .ok_or_else(|| format!("{at}: unknown reference {reference}"))?;
+        let resolved = &document["components"]["schemas"][name];
+        if resolved.is_null() {
+            return Err(format!("{at}: there is no schema {name}"));
+        }
+        return validate_at(document, resolved, value, at);
+    }
+    if let Some(any_of) = schema.get("anyOf").and_then(Value::as_array) {
+        return if any_of
+            .iter()
+            .any(|schema| validate_at(document, schema, value, at).is_ok())
+        {
+            Ok(())
+        } else {
+            Err(format!("{at}: {value} matches none of {schema}"))
+        };
+    }
+    if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+        if !values.contains(value) {
+            return Err(format!("{at}: {value} isn't one of {values:?}"));
+        }
+    }
+    let typed = match schema.get("type").and_then(Value::as_str) {
+        None => true,
+        Some("null") => value.is_null(),
+        Some("boolean") => value.is_boolean(),
+        Some("integer") => value.is_i64() || value.is_u64(),
+        Some("number") => value.is_number(),
+        Some("string") => value.is_string(),
+        Some("array") => value.is_array(),
+        Some("object") => value.is_object(),
+        Some(other) => return Err(format!("{at}: unknown type {other}")),
+    };
+    if !typed {
+        return Err(format!("{at}: {value} isn't a {}", schema["type"]));
+    }
+    if let (Some(format), Some(text)) =
+        (schema.get("format").and_then(Value::as_str), value.as_str())
+    {
+        let parsed = match format {
+            "date" => chrono::NaiveDate::parse_from_str(text, "%Y-%m-%d").is_ok(),
+            "date-time" => chrono::DateTime::parse_from_rfc3339(text).is_ok(),
+            _ => true,
+        };
+        if !parsed {
+            return Err(format!("{at}: {text} isn't a {format}"));
+        }
+    }
+    if let (Some(items), Some(values)) = (schema.get("items"), value.as_array()) {
+        for (index, item) in values.iter().enumerate() {
+            validate_at(document, items, item, &format!("{at}[{index}]"))?;
+        }
+    }
+    if let Some(fields) = value.as_object() {
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for required in schema
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+        {
+            if !fields.contains_key(required) {
+                return Err(format!("{at}: {required} is missing"));
+            }
+        }
+        for (name, field) in fields {
+            let at = format!("{at}.{name}");
+            match (
+                properties.and_then(|properties| properties.get(name)),
+                schema.get("additionalProperties"),
+            ) {
+                (Some(property), _) => validate_at(document, property, field, &at)?,
+                (None, Some(Value::Bool(false))) => {
+                    return Err(format!("{at} isn't in the document"))
+                }
+                (None, Some(additional)) if additional.is_object() => {
+                    validate_at(document, additional, field, &at)?
+                }
+                (None, _) => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn undocumented_or_missing_fields_dont_match() {
+        let document = document();
+        let link = reference("Link");
+        let mut value = json!({
+            "short_url": "launch",
+            "long_url": "https://example.com/",
+            "id": 1,
+            "full_url": "https://sho.rt/launch",
+            "clicks": 0,
+            "archived_at": "2024-03-01T10:00:00Z",
+        });
+        assert_eq!(validate(&document, &link, &value), Ok(()));
+
+        value["secret"] = json!("leaked");
+        assert_eq!(
+            validate(&document, &link, &value),
+            Err(String::from("$.secret isn't in the document"))
+        );
+        value.as_object_mut().unwrap().remove("secret");
+        value["archived_at"] = json!("yesterday");
+        assert!(validate(&document, &link, &value).is_err());
+        value.as_object_mut().unwrap().remove("archived_at");
+        value.as_object_mut().unwrap().remove("clicks");
+        assert_eq!(
+            validate(&document, &link, &value),
+            Err(String::from("$: clicks is missing"))
+        );
+
+        let import = reference("Import");
+        let job = |finished_at: Value| {
+            json!({
+                "id": 1, "status": "finished", "parsed": null, "processed": 0, "imported": 0,
+                "skipped": 0, "invalid": 0, "cancel_requested": false, "error": null,
+                "created_at": "2024-03-01T10:00:00Z", "finished_at": finished_at,
+            })
+        };
+        assert_eq!(validate(&document, &import, &job(Value::Null)), Ok(()));
+        assert!(validate(&document, &import, &job(json!(3))).is_err());
+    }
+
+    #[test]
+    fn every_operation_has_an_example_for_every_caller() {
+        let document = document();
+        for operation in operations() {
+            let name = format!("{} {}", operation.method, operation.path);
+            for caller in Caller::ALL {
+                assert!(
+                    operation.examples.iter().any(|e| e.caller == caller),
+                    "{name} has no example sent by {caller:?}"
+                );
+            }
+            let documented = &document["paths"][operation.document_path()]
+                [operation.method.as_str().to_lowercase()];
+            for example in &operation.examples {
+                let status = example.status.to_string();
+                assert!(
+                    documented["responses"].get(&status).is_some(),
+                    "{name} doesn't document the {status} of its {:?} example",
+                    example.caller
+                );
+                for (key, _) in example
+                    .query
+                    .into_iter()
+                    .flat_map(|query| query.split('&'))
+                    .filter_map(|pair| pair.split_once('='))
+                {
+                    assert!(
+                        operation.params.iter().any(|(param, _, _)| *param == key),
+                        "{name} doesn't document its {key} parameter"
+                    );
+                }
+                match (&operation.request, &example.body) {
+                    (Some((JSON, schema)), Some(body)) => {
+                        if let Err(e) = validate(&document, schema, body) {
+                            panic!(
+                                "The {:?} example of {name} isn't valid: {e}",
+                                example.caller
+                            );
+                        }
+                    }
+                    (Some(_), Some(_)) => {}
+                    (None, None) => {}
+                    (Some(_), None) => panic!("{name} takes a body its examples don't send"),
+                    (None, Some(_)) => panic!("{name} doesn't document the body its examples send"),
+                }
+            }
+        }
+    }
+}
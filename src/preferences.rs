@@ -1,12 +1,44 @@
-use std::fs;
+use std::{fs, time::Duration};
 
 use serde::{Deserialize, Serialize};
 use tracing::error;
 
+use crate::{
+    archive::ArchivePrefs,
+    assets::LegacyAssetPaths,
+    cache::CachePrefs,
+    conversion::ConversionPrefs,
+    features::FeaturePrefs,
+    geo::GeoPrefs,
+    history::HistoryPrefs,
+    host_policy::UnknownHostPolicy,
+    hot_links::HotLinkPrefs,
+    https::HttpsPrefs,
+    import_job::ImportPrefs,
+    normalize::NormalizeRules,
+    rate_limit::RateLimitPrefs,
+    resolve::{PendingLinks, RedirectStatus},
+    resources::ResourcePrefs,
+    rollout::RolloutPrefs,
+    safety::SafetyPrefs,
+    url_db::{self, CodeRules, SlugCase, SlugStrategy},
+};
+
 #[derive(Debug)]
 pub enum PrefError {
     IoError(std::io::Error),
     TomlError(toml::de::Error),
+    InvalidValue(String),
+}
+
+impl std::fmt::Display for PrefError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PrefError::IoError(e) => write!(f, "couldn't read the file: {e}"),
+            PrefError::TomlError(e) => write!(f, "it isn't valid TOML: {e}"),
+            PrefError::InvalidValue(problem) => f.write_str(problem),
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -24,13 +56,321 @@ pub struct Preferences {
     https_cert_path: Option<String>,
     https_key_path: Option<String>,
     jwt_secret: String,
+    /// Status short links redirect with unless they have their own: 301, 302, 307 or 308.
+    /// Browsers keep 301 and 308 redirects, so visitors who have been before don't see a link
+    /// edited or deleted.
+    #[serde(default)]
+    redirect_status: RedirectStatus,
+    /// What visitors to a link scheduled for later get: `page`, saying when it starts working, or
+    /// `not_found`, as if there were no link
+    #[serde(default)]
+    pending_links: PendingLinks,
+    #[serde(default)]
+    theme: ThemePrefs,
+    /// Newer copy of the public suffix list to use instead of the one built in
+    #[serde(default)]
+    public_suffix_list: Option<String>,
+    #[serde(default)]
+    timeouts: TimeoutPrefs,
+    #[serde(default)]
+    listener: ListenerPrefs,
+    /// Redirecting plain HTTP and the Strict-Transport-Security header
+    #[serde(default)]
+    https: HttpsPrefs,
+    #[serde(default)]
+    sessions: SessionPrefs,
+    /// How destinations are rewritten before they are stored and checked for duplicates
+    #[serde(default)]
+    normalize: NormalizeRules,
+    /// Shortening a destination that already has a plain anonymous link hands back that link
+    /// instead of making another. On unless turned off.
+    #[serde(default = "reuse_links_by_default")]
+    reuse_links: bool,
+    /// Links made while signed out expire this many days after they are made, or go live, and are
+    /// archived a while later. Such links are never reused, see `reuse_links`. 0, the default,
+    /// keeps them until they are deleted.
+    #[serde(default)]
+    anonymous_link_ttl_days: u32,
+    /// Visits to a link from the same address within this many seconds count as one click. 0
+    /// counts every visit.
+    #[serde(default = "repeat_click_window_secs_by_default")]
+    repeat_click_window_secs: u64,
+    /// Take visitors' addresses from the last entry of X-Forwarded-For rather than the
+    /// connection. Only set it behind a reverse proxy that adds that entry itself, as anyone can
+    /// send the header.
+    #[serde(default)]
+    trust_proxy: bool,
+    /// Never keep visitors' addresses, not even in memory: the rate limits, repeated clicks and
+    /// conversions go by a keyed hash of each address that changes every day instead, see
+    /// [crate::client_id::ClientId]. The address is still used on the spot to look up the
+    /// visitor's country for geo-targeted links.
+    #[serde(default)]
+    anonymize_clients: bool,
+    /// User agent fragments counted as bots on top of the built-in ones, e.g. a monitoring
+    /// service's. Visits from bots redirect as usual but are counted apart from clicks.
+    #[serde(default)]
+    bot_patterns: Vec<String>,
+    /// Codes that can't be generated or taken as an alias, on top of the ones that always are
+    #[serde(default)]
+    reserved_codes: Vec<String>,
+    /// Longest destination that can be shortened, in characters. Capped at
+    /// [url_db::MAX_LONG_URL_LEN].
+    #[serde(default = "max_long_url_len_by_default")]
+    max_long_url_len: usize,
+    /// Other domains that reach this instance, e.g. one it moved from. Requests for them are
+    /// served whatever unknown_host says, and links can't point at them, as with domain_name.
+    #[serde(default)]
+    other_domains: Vec<String>,
+    /// Lets links point at this instance itself, e.g. as shortcuts to other links. Off, as such
+    /// links can chain or loop.
+    #[serde(default)]
+    allow_self_links: bool,
+    /// Schemes links can send visitors to. Destinations without one are read as http. Only
+    /// schemes with a host, like ftp, can be added; visits to a stored link whose scheme was taken
+    /// off the list are answered as if it didn't exist.
+    #[serde(default = "allowed_schemes_by_default")]
+    allowed_schemes: Vec<String>,
+    /// Domains links can't point at, each blocking its subdomains too
+    #[serde(default)]
+    blocked_domains: Vec<String>,
+    /// Looking destinations up in a malicious url list before shortening them
+    #[serde(default)]
+    safety: SafetyPrefs,
+    /// Short urls are found whatever the case they are typed in, and generated in lowercase. Off
+    /// unless turned on.
+    #[serde(default)]
+    case_insensitive_slugs: bool,
+    /// How codes are generated: window, from the destination, or sequential
+    #[serde(default)]
+    slug_strategy: SlugStrategy,
+    /// Random codes tried for a new link before it fails as unavailable, 16 unless set
+    #[serde(default = "code_attempts_by_default")]
+    code_attempts: u32,
+    /// Optional features, on unless turned off here except for conversion tracking
+    #[serde(default)]
+    features: FeaturePrefs,
+    /// The attribution window, used when the conversions feature is on
+    #[serde(default)]
+    conversions: ConversionPrefs,
+    /// How long the changes made to links are kept
+    #[serde(default)]
+    history: HistoryPrefs,
+    /// How long archived links are kept before they are purged
+    #[serde(default)]
+    archive: ArchivePrefs,
+    /// Limits on CSV uploads to /api/imports, and which are imported in the background
+    #[serde(default)]
+    imports: ImportPrefs,
+    /// When links are clicked so often their clicks are counted over several rows
+    #[serde(default)]
+    hot_links: HotLinkPrefs,
+    /// How long a new destination is rolled out to part of a link's visitors before it gets them all
+    #[serde(default)]
+    rollout: RolloutPrefs,
+    /// How many links each address can make a minute and a day
+    #[serde(default)]
+    rate_limits: RateLimitPrefs,
+    /// Where visitors' countries come from, for links that send some countries elsewhere
+    #[serde(default)]
+    geo: GeoPrefs,
+    /// max-age of the cacheable response classes
+    #[serde(default)]
+    cache: CachePrefs,
+    /// Sizes of the in-memory state and when to warn about memory and open files
+    #[serde(default)]
+    resources: ResourcePrefs,
+    /// What to do with requests whose Host isn't domain_name: pass, misdirected or redirect
+    #[serde(default)]
+    unknown_host: UnknownHostPolicy,
+    /// Until when sessions stored in the old `Bearer` cookie are still accepted (and moved to the
+    /// new cookie), e.g. `2026-12-31`. Unset means they aren't.
+    #[serde(default)]
+    legacy_cookie_until: Option<toml::value::Datetime>,
+    /// What to do with requests for files at their old paths outside /static: serve, redirect
+    /// or off
+    #[serde(default)]
+    legacy_asset_paths: LegacyAssetPaths,
+    /// Usernames allowed to use the /api/admin endpoints
+    #[serde(default)]
+    admins: Vec<String>,
     // TODO: Log verbosity
 }
 
+/// Branding for the instance. Everything here is optional in the config file so existing configs
+/// keep loading; the defaults match the stock look of the site. The config is only read at
+/// startup, so a changed theme takes effect on the next restart.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct ThemePrefs {
+    primary_color: String,
+    brand_name: String,
+    footer_text: String,
+    logo_path: Option<String>,
+}
+
+impl Default for ThemePrefs {
+    fn default() -> Self {
+        ThemePrefs {
+            primary_color: String::from("#99E1D9"),
+            brand_name: String::from("RURLS"),
+            footer_text: String::new(),
+            logo_path: None,
+        }
+    }
+}
+
+impl ThemePrefs {
+    #[cfg(test)]
+    pub fn new(
+        primary_color: String,
+        brand_name: String,
+        footer_text: String,
+        logo_path: Option<String>,
+    ) -> Self {
+        ThemePrefs {
+            primary_color,
+            brand_name,
+            footer_text,
+            logo_path,
+        }
+    }
+    pub fn primary_color(&self) -> &str {
+        self.primary_color.as_str()
+    }
+    pub fn brand_name(&self) -> &str {
+        self.brand_name.as_str()
+    }
+    pub fn footer_text(&self) -> &str {
+        self.footer_text.as_str()
+    }
+    pub fn logo_path(&self) -> &Option<String> {
+        &self.logo_path
+    }
+}
+
+/// How long each class of route may take before the request is abandoned, in milliseconds.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct TimeoutPrefs {
+    /// Short url redirects and the static pages
+    redirect_ms: u64,
+    /// JSON endpoints under /api
+    api_ms: u64,
+    /// Exports and imports. These stream, so this is the longest gap allowed between chunks
+    /// rather than a limit on the whole request.
+    bulk_idle_ms: u64,
+}
+
+impl Default for TimeoutPrefs {
+    fn default() -> Self {
+        TimeoutPrefs {
+            redirect_ms: 2_000,
+            api_ms: 10_000,
+            bulk_idle_ms: 30_000,
+        }
+    }
+}
+
+impl TimeoutPrefs {
+    pub fn redirect(&self) -> Duration {
+        Duration::from_millis(self.redirect_ms)
+    }
+    pub fn api(&self) -> Duration {
+        Duration::from_millis(self.api_ms)
+    }
+    pub fn bulk_idle(&self) -> Duration {
+        Duration::from_millis(self.bulk_idle_ms)
+    }
+}
+
+/// Limits on the connections themselves, applied before a request reaches any handler. The
+/// defaults leave plenty of room for slow clients and only stop connections that are clearly
+/// stuck or abusive.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct ListenerPrefs {
+    /// Largest request head (request line plus headers) read before the request is refused
+    max_header_bytes: usize,
+    /// How long a client gets to send a complete request head
+    header_read_timeout_ms: u64,
+    /// Connections that move no bytes either way for this long are closed
+    idle_timeout_ms: u64,
+    /// Connections open at once. New ones beyond this are closed straight after accepting them.
+    /// Unset fits it to the open file limit, see [crate::resources].
+    max_connections: Option<usize>,
+}
+
+impl Default for ListenerPrefs {
+    fn default() -> Self {
+        ListenerPrefs {
+            max_header_bytes: 64 * 1024,
+            header_read_timeout_ms: 30_000,
+            idle_timeout_ms: 300_000,
+            max_connections: None,
+        }
+    }
+}
+
+impl ListenerPrefs {
+    /// Never below 8 KiB, the smallest read buffer the HTTP implementation accepts
+    pub fn max_header_bytes(&self) -> usize {
+        self.max_header_bytes.max(8 * 1024)
+    }
+    pub fn header_read_timeout(&self) -> Duration {
+        Duration::from_millis(self.header_read_timeout_ms)
+    }
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_millis(self.idle_timeout_ms)
+    }
+    pub fn max_connections(&self) -> Option<usize> {
+        self.max_connections
+    }
+}
+
+/// How long a sign-in lasts
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct SessionPrefs {
+    /// Sessions with no requests for this long are signed out
+    idle_timeout_mins: u64,
+    /// Sessions are signed out this long after signing in, however active they are
+    absolute_lifetime_mins: u64,
+}
+
+impl Default for SessionPrefs {
+    fn default() -> Self {
+        SessionPrefs {
+            idle_timeout_mins: 60 * 24,
+            absolute_lifetime_mins: 60 * 24 * 30,
+        }
+    }
+}
+
+impl SessionPrefs {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_mins * 60)
+    }
+    pub fn absolute_lifetime(&self) -> Duration {
+        Duration::from_secs(self.absolute_lifetime_mins * 60)
+    }
+}
+
 impl Preferences {
     pub fn domain_name(&self) -> &String {
         &self.domain_name
     }
+    /// Absolute address of a short code, over https when a certificate is configured. A
+    /// domain_name that already carries a scheme is used as is.
+    pub fn full_url(&self, code: &str) -> String {
+        let domain = self.domain_name.trim_end_matches('/');
+        if domain.contains("://") {
+            format!("{domain}/{code}")
+        } else if self.https_cert_path.is_some() {
+            format!("https://{domain}/{code}")
+        } else {
+            format!("http://{domain}/{code}")
+        }
+    }
     pub fn port(&self) -> u32 {
         self.port
     }
@@ -103,6 +443,245 @@ impl Preferences {
     pub fn jwt_secret(&self) -> &str {
         self.jwt_secret.as_str()
     }
+    pub fn redirect_status(&self) -> RedirectStatus {
+        self.redirect_status
+    }
+    pub fn pending_links(&self) -> PendingLinks {
+        self.pending_links
+    }
+    pub fn theme(&self) -> &ThemePrefs {
+        &self.theme
+    }
+    pub fn public_suffix_list(&self) -> &Option<String> {
+        &self.public_suffix_list
+    }
+    pub fn timeouts(&self) -> &TimeoutPrefs {
+        &self.timeouts
+    }
+    pub fn normalize(&self) -> &NormalizeRules {
+        &self.normalize
+    }
+    pub fn reuse_links(&self) -> bool {
+        self.reuse_links
+    }
+    pub fn repeat_click_window(&self) -> Duration {
+        Duration::from_secs(self.repeat_click_window_secs)
+    }
+    pub fn trust_proxy(&self) -> bool {
+        self.trust_proxy
+    }
+    pub fn anonymize_clients(&self) -> bool {
+        self.anonymize_clients
+    }
+    pub fn bot_patterns(&self) -> &[String] {
+        &self.bot_patterns
+    }
+    /// How long links made while signed out last, None while they last until deleted
+    pub fn anonymous_link_ttl(&self) -> Option<Duration> {
+        (self.anonymous_link_ttl_days > 0)
+            .then(|| Duration::from_secs(u64::from(self.anonymous_link_ttl_days) * 60 * 60 * 24))
+    }
+    pub fn reserved_codes(&self) -> &[String] {
+        &self.reserved_codes
+    }
+    pub fn max_long_url_len(&self) -> usize {
+        self.max_long_url_len.min(url_db::MAX_LONG_URL_LEN)
+    }
+    pub fn other_domains(&self) -> &[String] {
+        &self.other_domains
+    }
+    pub fn allow_self_links(&self) -> bool {
+        self.allow_self_links
+    }
+    pub fn allowed_schemes(&self) -> &[String] {
+        &self.allowed_schemes
+    }
+    pub fn blocked_domains(&self) -> &[String] {
+        &self.blocked_domains
+    }
+    pub fn safety(&self) -> &SafetyPrefs {
+        &self.safety
+    }
+    pub fn slug_case(&self) -> SlugCase {
+        if self.case_insensitive_slugs {
+            SlugCase::Insensitive
+        } else {
+            SlugCase::Sensitive
+        }
+    }
+    pub fn code_rules(&self) -> CodeRules<'_> {
+        CodeRules {
+            len: self.url_len,
+            reserved: &self.reserved_codes,
+            case: self.slug_case(),
+            strategy: self.slug_strategy,
+            attempts: self.code_attempts,
+        }
+    }
+    pub fn https(&self) -> &HttpsPrefs {
+        &self.https
+    }
+    pub fn listener(&self) -> &ListenerPrefs {
+        &self.listener
+    }
+    pub fn sessions(&self) -> &SessionPrefs {
+        &self.sessions
+    }
+    pub fn features(&self) -> &FeaturePrefs {
+        &self.features
+    }
+    pub fn conversions(&self) -> &ConversionPrefs {
+        &self.conversions
+    }
+    pub fn history(&self) -> &HistoryPrefs {
+        &self.history
+    }
+    pub fn archive(&self) -> &ArchivePrefs {
+        &self.archive
+    }
+    pub fn imports(&self) -> &ImportPrefs {
+        &self.imports
+    }
+    pub fn hot_links(&self) -> &HotLinkPrefs {
+        &self.hot_links
+    }
+    pub fn rollout(&self) -> &RolloutPrefs {
+        &self.rollout
+    }
+    pub fn rate_limits(&self) -> &RateLimitPrefs {
+        &self.rate_limits
+    }
+    pub fn geo(&self) -> &GeoPrefs {
+        &self.geo
+    }
+    pub fn cache(&self) -> &CachePrefs {
+        &self.cache
+    }
+    pub fn resources(&self) -> &ResourcePrefs {
+        &self.resources
+    }
+    pub fn unknown_host(&self) -> UnknownHostPolicy {
+        self.unknown_host
+    }
+    pub fn legacy_cookie_until(&self) -> Option<&toml::value::Datetime> {
+        self.legacy_cookie_until.as_ref()
+    }
+    pub fn legacy_asset_paths(&self) -> LegacyAssetPaths {
+        self.legacy_asset_paths
+    }
+    #[cfg(test)]
+    pub fn set_legacy_cookie_until(&mut self, until: Option<toml::value::Datetime>) {
+        self.legacy_cookie_until = until;
+    }
+    #[cfg(test)]
+    pub fn set_reuse_links(&mut self, reuse_links: bool) {
+        self.reuse_links = reuse_links;
+    }
+    #[cfg(test)]
+    pub fn set_anonymous_link_ttl_days(&mut self, days: u32) {
+        self.anonymous_link_ttl_days = days;
+    }
+    #[cfg(test)]
+    pub fn set_trust_proxy(&mut self, trust_proxy: bool) {
+        self.trust_proxy = trust_proxy;
+    }
+    #[cfg(test)]
+    pub fn set_anonymize_clients(&mut self, anonymize_clients: bool) {
+        self.anonymize_clients = anonymize_clients;
+    }
+    #[cfg(test)]
+    pub fn set_reserved_codes(&mut self, reserved_codes: Vec<String>) {
+        self.reserved_codes = reserved_codes;
+    }
+    #[cfg(test)]
+    pub fn set_max_long_url_len(&mut self, max_long_url_len: usize) {
+        self.max_long_url_len = max_long_url_len;
+    }
+    #[cfg(test)]
+    pub fn set_other_domains(&mut self, other_domains: Vec<String>) {
+        self.other_domains = other_domains;
+    }
+    #[cfg(test)]
+    pub fn set_allow_self_links(&mut self, allow_self_links: bool) {
+        self.allow_self_links = allow_self_links;
+    }
+    #[cfg(test)]
+    pub fn set_allowed_schemes(&mut self, allowed_schemes: Vec<String>) {
+        self.allowed_schemes = allowed_schemes;
+    }
+    #[cfg(test)]
+    pub fn set_blocked_domains(&mut self, blocked_domains: Vec<String>) {
+        self.blocked_domains = blocked_domains;
+    }
+    #[cfg(test)]
+    pub fn set_case_insensitive_slugs(&mut self, case_insensitive_slugs: bool) {
+        self.case_insensitive_slugs = case_insensitive_slugs;
+    }
+    #[cfg(test)]
+    pub fn set_imports(&mut self, imports: ImportPrefs) {
+        self.imports = imports;
+    }
+    #[cfg(test)]
+    pub fn set_hot_links(&mut self, hot_links: HotLinkPrefs) {
+        self.hot_links = hot_links;
+    }
+    #[cfg(test)]
+    pub fn set_features(&mut self, features: FeaturePrefs) {
+        self.features = features;
+    }
+    #[cfg(test)]
+    pub fn set_conversions(&mut self, conversions: ConversionPrefs) {
+        self.conversions = conversions;
+    }
+    #[cfg(test)]
+    pub fn set_legacy_asset_paths(&mut self, policy: LegacyAssetPaths) {
+        self.legacy_asset_paths = policy;
+    }
+    #[cfg(test)]
+    pub fn set_admins(&mut self, admins: Vec<String>) {
+        self.admins = admins;
+    }
+    #[cfg(test)]
+    pub fn set_redirect_status(&mut self, redirect_status: RedirectStatus) {
+        self.redirect_status = redirect_status;
+    }
+    #[cfg(test)]
+    pub fn set_pending_links(&mut self, pending_links: PendingLinks) {
+        self.pending_links = pending_links;
+    }
+    #[cfg(test)]
+    pub fn set_rate_limits(&mut self, rate_limits: RateLimitPrefs) {
+        self.rate_limits = rate_limits;
+    }
+    #[cfg(test)]
+    pub fn set_geo(&mut self, geo: GeoPrefs) {
+        self.geo = geo;
+    }
+    pub fn is_admin(&self, username: &str) -> bool {
+        self.admins.iter().any(|admin| admin == username)
+    }
+}
+
+fn reuse_links_by_default() -> bool {
+    true
+}
+
+fn repeat_click_window_secs_by_default() -> u64 {
+    30
+}
+
+fn code_attempts_by_default() -> u32 {
+    16
+}
+
+/// The only list of schemes allowed out of the box. Everything else, the redirect gate included,
+/// is handed [Preferences::allowed_schemes] rather than keeping a copy.
+pub(crate) fn allowed_schemes_by_default() -> Vec<String> {
+    vec![String::from("http"), String::from("https")]
+}
+
+fn max_long_url_len_by_default() -> usize {
+    4096
 }
 
 fn create_default_config(path: &str) -> Result<Preferences, std::io::Error> {
@@ -120,6 +699,46 @@ fn create_default_config(path: &str) -> Result<Preferences, std::io::Error> {
         https_cert_path: None,
         https_key_path: None,
         jwt_secret: String::from("THISISALSOVERYBAD CHANGE!!"),
+        redirect_status: RedirectStatus::default(),
+        pending_links: PendingLinks::default(),
+        theme: ThemePrefs::default(),
+        public_suffix_list: None,
+        timeouts: TimeoutPrefs::default(),
+        listener: ListenerPrefs::default(),
+        https: HttpsPrefs::default(),
+        sessions: SessionPrefs::default(),
+        normalize: NormalizeRules::default(),
+        reuse_links: true,
+        anonymous_link_ttl_days: 0,
+        repeat_click_window_secs: repeat_click_window_secs_by_default(),
+        trust_proxy: false,
+        anonymize_clients: false,
+        bot_patterns: Vec::new(),
+        reserved_codes: Vec::new(),
+        max_long_url_len: max_long_url_len_by_default(),
+        other_domains: Vec::new(),
+        allow_self_links: false,
+        allowed_schemes: allowed_schemes_by_default(),
+        blocked_domains: Vec::new(),
+        safety: SafetyPrefs::default(),
+        case_insensitive_slugs: false,
+        slug_strategy: SlugStrategy::default(),
+        code_attempts: code_attempts_by_default(),
+        features: FeaturePrefs::default(),
+        conversions: ConversionPrefs::default(),
+        history: HistoryPrefs::default(),
+        archive: ArchivePrefs::default(),
+        imports: ImportPrefs::default(),
+        hot_links: HotLinkPrefs::default(),
+        rollout: RolloutPrefs::default(),
+        rate_limits: RateLimitPrefs::default(),
+        geo: GeoPrefs::default(),
+        cache: CachePrefs::default(),
+        resources: ResourcePrefs::default(),
+        unknown_host: UnknownHostPolicy::default(),
+        legacy_cookie_until: None,
+        legacy_asset_paths: LegacyAssetPaths::default(),
+        admins: Vec::new(),
     };
     eprintln!("Using default passwords. \x1b[1mTHIS MUST BE CHANGED!!!\x1b[0m");
     error!("Using default passwords. \x1b[1mTHIS MUST BE CHANGED!!!\x1b[0m");
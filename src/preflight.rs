@@ -0,0 +1,220 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{
+    preferences::Preferences,
+    resolve::{self, Interstitial, Outcome},
+    url_db::UrlRow,
+};
+
+/// What stands between opening a link and reaching its destination
+#[derive(Serialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Gate {
+    None,
+    Countdown,
+    SingleUse,
+    /// Scheduled to start working later. Opening it only shows when.
+    Pending,
+    /// Past its expiry. Opening it only shows that it is gone.
+    Expired,
+    /// Used up its click limit. Opening it only shows that it is gone.
+    Exhausted,
+    /// Asks for a passphrase before going anywhere
+    Passphrase,
+    /// Goes somewhere the instance no longer redirects to, see
+    /// [crate::preferences::Preferences::allowed_schemes]. Opening it finds nothing.
+    Unreachable,
+}
+
+/// Description of a short link for clients that want to unfurl it safely, built from a dry run of
+/// the resolution pipeline so it never counts a click or uses up a link.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Preflight {
+    code: String,
+    exists: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination_host: Option<String>,
+    /// Only filled in for callers allowed to see where the link goes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gate: Option<Gate>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    countdown_seconds: Option<i16>,
+    /// When a single use link was used. Opening it now only shows that it is gone.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    consumed_at: Option<DateTime<Utc>>,
+    /// When the link stops, or stopped, redirecting
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+    /// When a link scheduled ahead of time starts, or started, redirecting
+    #[serde(skip_serializing_if = "Option::is_none")]
+    active_from: Option<DateTime<Utc>>,
+}
+
+impl Preflight {
+    /// Describes the link `code` looked up as `row`, as it behaves on an instance set up with
+    /// `prefs`. `full_destination` decides whether the whole destination is included or just its
+    /// host.
+    pub fn describe(
+        code: &str,
+        row: Option<&UrlRow>,
+        full_destination: bool,
+        prefs: &Preferences,
+    ) -> Self {
+        let mut preflight = Preflight {
+            code: code.to_string(),
+            exists: row.is_some(),
+            destination_host: None,
+            destination: None,
+            gate: None,
+            countdown_seconds: None,
+            consumed_at: None,
+            expires_at: None,
+            active_from: None,
+        };
+        let Some(row) = row else {
+            return preflight;
+        };
+        let target = resolve::redirect_target(row.long_url());
+        // Whoever protected the link didn't want even the site known without the passphrase
+        preflight.destination_host =
+            destination_host(&target).filter(|_| full_destination || !row.is_protected());
+        preflight.destination = full_destination.then_some(target);
+        preflight.expires_at = row.expires_at();
+        preflight.active_from = row.active_from();
+
+        preflight.gate = Some(match resolve::dry_run(Some(row), prefs) {
+            Outcome::Redirect(..) => Gate::None,
+            Outcome::NotFound => Gate::Unreachable,
+            Outcome::Interstitial(Interstitial::Countdown { seconds, .. }) => {
+                preflight.countdown_seconds = Some(seconds);
+                Gate::Countdown
+            }
+            Outcome::Claim(_) | Outcome::Interstitial(Interstitial::SingleUse { .. }) => {
+                Gate::SingleUse
+            }
+            Outcome::Interstitial(Interstitial::Consumed { at }) => {
+                preflight.consumed_at = Some(at);
+                Gate::SingleUse
+            }
+            Outcome::Interstitial(Interstitial::Pending { .. }) => Gate::Pending,
+            Outcome::Interstitial(Interstitial::Expired { .. }) => Gate::Expired,
+            Outcome::Interstitial(Interstitial::Exhausted { .. }) => Gate::Exhausted,
+            Outcome::Interstitial(Interstitial::Passphrase { .. }) => Gate::Passphrase,
+        });
+        preflight
+    }
+}
+
+/// Lowercased host of a destination, without credentials or port
+pub fn destination_host(target: &str) -> Option<String> {
+    let after_scheme = target.split_once("://").map_or(target, |(_, rest)| rest);
+    let authority = after_scheme
+        .split(['/', '?', '#'])
+        .next()
+        .unwrap_or_default();
+    let host_port = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    let host = match host_port.strip_prefix('[') {
+        Some(v6) => v6.split(']').next().unwrap_or_default(),
+        None => host_port.split(':').next().unwrap_or_default(),
+    };
+    (!host.is_empty()).then(|| host.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefs() -> Preferences {
+        Preferences::load_config("./config.toml").expect("Error loading config from TOML")
+    }
+
+    fn describe(row: &UrlRow, full: bool) -> Preflight {
+        Preflight::describe(row.short_url(), Some(row), full, &prefs())
+    }
+
+    #[test]
+    fn reports_each_gate() {
+        let plain = UrlRow::test_row("abc", "https://example.com/a");
+        let countdown = plain.clone().with_interstitial(5);
+        let single_use = plain.clone().with_single_use(None);
+        let at = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let consumed = plain.clone().with_single_use(Some(at));
+
+        assert_eq!(describe(&plain, false).gate, Some(Gate::None));
+        let described = describe(&countdown, false);
+        assert_eq!(described.gate, Some(Gate::Countdown));
+        assert_eq!(described.countdown_seconds, Some(5));
+        let described = describe(&single_use, false);
+        assert_eq!(described.gate, Some(Gate::SingleUse));
+        assert_eq!(described.consumed_at, None);
+        let described = describe(&consumed, false);
+        assert_eq!(described.gate, Some(Gate::SingleUse));
+        assert_eq!(described.consumed_at, Some(at));
+        let described = describe(&plain.clone().with_expiry(at), false);
+        assert_eq!(described.gate, Some(Gate::Expired));
+        assert_eq!(described.expires_at, Some(at));
+        let exhausted = plain.clone().with_max_clicks(3).with_clicks(3);
+        assert_eq!(describe(&exhausted, false).gate, Some(Gate::Exhausted));
+        let limited = plain.clone().with_max_clicks(3).with_clicks(2);
+        assert_eq!(describe(&limited, false).gate, Some(Gate::None));
+        let launch = Utc::now() + chrono::TimeDelta::days(1);
+        let described = describe(&plain.clone().with_active_from(launch), false);
+        assert_eq!(described.gate, Some(Gate::Pending));
+        assert_eq!(described.active_from, Some(launch));
+        let ftp = UrlRow::test_row("abc", "ftp://files.example.com/pub");
+        assert_eq!(describe(&ftp, false).gate, Some(Gate::Unreachable));
+    }
+
+    #[test]
+    fn missing_link_only_reports_that() {
+        assert_eq!(
+            serde_json::to_value(Preflight::describe("nope", None, true, &prefs())).unwrap(),
+            serde_json::json!({"code": "nope", "exists": false})
+        );
+    }
+
+    #[test]
+    fn redacts_destination_to_host() {
+        let row = UrlRow::test_row("abc", "https://user:pw@Docs.Example.com:8443/private?t=1");
+
+        let redacted = serde_json::to_value(describe(&row, false)).unwrap();
+        assert_eq!(redacted["destination_host"], "docs.example.com");
+        assert!(redacted.get("destination").is_none());
+
+        let full = describe(&row, true);
+        assert_eq!(
+            full.destination.as_deref(),
+            Some("https://user:pw@Docs.Example.com:8443/private?t=1")
+        );
+    }
+
+    #[test]
+    fn protected_links_hide_their_host() {
+        let row = UrlRow::test_row("abc", "https://example.com/a").with_passphrase("pw");
+
+        let redacted = describe(&row, false);
+        assert_eq!(redacted.gate, Some(Gate::Passphrase));
+        assert_eq!(redacted.destination_host, None);
+        let full = describe(&row, true);
+        assert_eq!(full.destination_host.as_deref(), Some("example.com"));
+        assert_eq!(full.destination.as_deref(), Some("https://example.com/a"));
+    }
+
+    #[test]
+    fn finds_hosts() {
+        assert_eq!(
+            destination_host("example.com/a").as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(
+            destination_host("http://[::1]:8080/").as_deref(),
+            Some("::1")
+        );
+        assert_eq!(destination_host("https://?q").as_deref(), None);
+    }
+}
@@ -0,0 +1,142 @@
+use std::{fmt::Display, fs, net::IpAddr, str::FromStr, sync::OnceLock};
+
+use publicsuffix::{List, Psl};
+
+use crate::preferences::PrefError;
+
+/// Snapshot of https://publicsuffix.org/list/public_suffix_list.dat used when the config doesn't
+/// point at a newer copy.
+const BUNDLED_LIST: &str = include_str!("../data/public_suffix_list.dat");
+
+static LIST: OnceLock<List> = OnceLock::new();
+
+/// The part of a host that one party controls, e.g. `example.co.uk` for `blog.example.co.uk`.
+/// Hosts that are reported or matched together (referrers, destinations, block list entries,
+/// rate limits) should be compared by this rather than by raw host.
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RegistrableDomain(String);
+
+#[allow(dead_code)]
+impl RegistrableDomain {
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl Display for RegistrableDomain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Replaces the bundled list with the one at `path`, so the list can be updated without a rebuild.
+/// Has to be called before the first lookup; later calls are ignored.
+pub fn load_list(path: &str) -> Result<(), PrefError> {
+    let contents = fs::read_to_string(path).map_err(PrefError::IoError)?;
+    let list = parse_list(&contents).map_err(|err| {
+        PrefError::InvalidValue(format!(
+            "public_suffix_list `{path}` couldn't be parsed: {err}"
+        ))
+    })?;
+    let _ = LIST.set(list);
+    Ok(())
+}
+
+fn parse_list(contents: &str) -> Result<List, publicsuffix::Error> {
+    List::from_str(contents)
+}
+
+fn list() -> &'static List {
+    LIST.get_or_init(|| parse_list(BUNDLED_LIST).expect("Bundled public suffix list is valid"))
+}
+
+/// Groups a host by its registrable domain (eTLD+1). Hosts may carry a port and may be given in
+/// unicode or punycode; both spellings end up in the same group. IP literals and hosts that are
+/// themselves a public suffix are their own group.
+#[allow(dead_code)]
+pub fn host_group(host: &str) -> RegistrableDomain {
+    group_with(list(), host)
+}
+
+fn group_with(list: &List, host: &str) -> RegistrableDomain {
+    let host = strip_port(host.trim()).trim_end_matches('.');
+    if let Ok(ip) = IpAddr::from_str(host) {
+        return RegistrableDomain(ip.to_string());
+    }
+    let host = idna::domain_to_ascii(host).unwrap_or_else(|_| host.to_ascii_lowercase());
+    match list.domain(host.as_bytes()) {
+        Some(domain) => RegistrableDomain(String::from_utf8_lossy(domain.as_bytes()).into_owned()),
+        None => RegistrableDomain(host),
+    }
+}
+
+/// Removes a trailing `:port` and the brackets around IPv6 literals
+fn strip_port(host: &str) -> &str {
+    if let Some(rest) = host.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    match host.rsplit_once(':') {
+        Some((name, port)) if !name.contains(':') && port.bytes().all(|b| b.is_ascii_digit()) => {
+            name
+        }
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(host: &str) -> String {
+        host_group(host).to_string()
+    }
+
+    #[test]
+    fn multi_label_suffixes() {
+        assert_eq!(group("blog.example.co.uk"), "example.co.uk");
+        assert_eq!(group("shop.example.co.uk"), "example.co.uk");
+        assert_eq!(group("www.example.com.au"), "example.com.au");
+        assert_eq!(group("WWW.Example.COM:8080"), "example.com");
+        assert_eq!(group("example.com."), "example.com");
+    }
+
+    #[test]
+    fn public_suffix_hosts() {
+        assert_eq!(group("co.uk"), "co.uk");
+        assert_eq!(group("blogspot.com"), "blogspot.com");
+        assert_ne!(group("a.blogspot.com"), group("b.blogspot.com"));
+        assert_eq!(group("www.a.blogspot.com"), "a.blogspot.com");
+    }
+
+    #[test]
+    fn ip_literals() {
+        assert_eq!(group("192.168.1.20"), "192.168.1.20");
+        assert_eq!(group("192.168.1.20:8080"), "192.168.1.20");
+        assert_eq!(group("[2001:db8::1]:443"), "2001:db8::1");
+        assert_eq!(group("2001:DB8::1"), "2001:db8::1");
+    }
+
+    #[test]
+    fn punycode_hosts() {
+        assert_eq!(group("www.bücher.de"), "xn--bcher-kva.de");
+        assert_eq!(group("www.xn--bcher-kva.de"), "xn--bcher-kva.de");
+        assert_eq!(
+            group("shop.例え.公司.cn"),
+            group("shop.xn--r8jz45g.xn--55qx5d.cn")
+        );
+        assert_eq!(group("公司.cn"), "xn--55qx5d.cn");
+    }
+
+    #[test]
+    fn configured_list_is_used() {
+        let list =
+            parse_list("// ===BEGIN PRIVATE DOMAINS===\nexample.com\n// ===END PRIVATE DOMAINS===")
+                .unwrap();
+        assert_eq!(
+            group_with(&list, "a.b.example.com").to_string(),
+            "b.example.com"
+        );
+        assert!(parse_list("// just a comment\n").is_err());
+    }
+}
@@ -0,0 +1,125 @@
+use std::io::Cursor;
+
+use image::{ImageFormat, Luma};
+use qrcode::{render::svg, QrCode};
+use serde::Deserialize;
+
+/// Pixels per module when the request doesn't say
+const DEFAULT_MODULE_SIZE: u32 = 8;
+/// Smallest and largest module size accepted. A full length link makes a code of about 40
+/// modules a side, so the largest PNG stays under 3000 pixels across.
+const MODULE_SIZES: std::ops::RangeInclusive<u32> = 1..=64;
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum QrFormat {
+    /// Scales to any size, so it is what you get unless you ask for a PNG
+    #[default]
+    Svg,
+    Png,
+}
+
+impl QrFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            QrFormat::Svg => "image/svg+xml",
+            QrFormat::Png => "image/png",
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub struct QrQuery {
+    #[serde(default)]
+    pub format: QrFormat,
+    /// Pixels per module, [DEFAULT_MODULE_SIZE] by default. For SVGs it only sets the size the
+    /// image asks to be shown at.
+    pub size: Option<u32>,
+}
+
+impl QrQuery {
+    /// The module size asked for, or None when it is out of bounds
+    pub fn module_size(&self) -> Option<u32> {
+        let size = self.size.unwrap_or(DEFAULT_MODULE_SIZE);
+        MODULE_SIZES.contains(&size).then_some(size)
+    }
+
+    pub fn bounds_message() -> String {
+        format!(
+            "size has to be between {} and {} pixels per module",
+            MODULE_SIZES.start(),
+            MODULE_SIZES.end()
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum QrError {
+    /// Too much data for the largest QR code, which no short url comes near
+    Encode(qrcode::types::QrError),
+    Image(image::ImageError),
+}
+
+impl std::fmt::Display for QrError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QrError::Encode(e) => write!(f, "couldn't encode the QR code: {e}"),
+            QrError::Image(e) => write!(f, "couldn't write the PNG: {e}"),
+        }
+    }
+}
+
+/// A QR code for `url`, `module_size` pixels per module and with the usual quiet zone around it
+pub fn render(url: &str, format: QrFormat, module_size: u32) -> Result<Vec<u8>, QrError> {
+    let code = QrCode::new(url.as_bytes()).map_err(QrError::Encode)?;
+    match format {
+        QrFormat::Svg => Ok(code
+            .render::<svg::Color>()
+            .module_dimensions(module_size, module_size)
+            .build()
+            .into_bytes()),
+        QrFormat::Png => {
+            let image = code
+                .render::<Luma<u8>>()
+                .module_dimensions(module_size, module_size)
+                .build();
+            let mut png = Vec::new();
+            image
+                .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+                .map_err(QrError::Image)?;
+            Ok(png)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_both_formats() {
+        let url = "https://short.example/abc123";
+        let svg = String::from_utf8(render(url, QrFormat::Svg, 4).unwrap()).unwrap();
+        assert!(svg.contains("<svg"));
+
+        let small = render(url, QrFormat::Png, 1).unwrap();
+        assert!(small.starts_with(b"\x89PNG"));
+        let large = image::load_from_memory(&render(url, QrFormat::Png, 10).unwrap()).unwrap();
+        let small = image::load_from_memory(&small).unwrap();
+        assert_eq!(large.width(), small.width() * 10);
+        assert_eq!(large.width(), large.height());
+    }
+
+    #[test]
+    fn module_size_is_bounded() {
+        let query = |size| QrQuery {
+            format: QrFormat::Png,
+            size,
+        };
+        assert_eq!(query(None).module_size(), Some(DEFAULT_MODULE_SIZE));
+        assert_eq!(query(Some(1)).module_size(), Some(1));
+        assert_eq!(query(Some(64)).module_size(), Some(64));
+        assert_eq!(query(Some(0)).module_size(), None);
+        assert_eq!(query(Some(65)).module_size(), None);
+    }
+}
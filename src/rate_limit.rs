@@ -0,0 +1,324 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{client_id::ClientId, locked::Locked};
+
+/// Most addresses and users tracked at once unless sized otherwise, see [crate::resources]. Once
+/// that many are, ones not among them are refused until a sweep makes room, rather than pushing
+/// out one that is, which would hand back its allowance to whoever can rotate through enough of
+/// them.
+pub const RATE_LIMIT_CAPACITY: usize = 10_000;
+/// How often allowances that have filled back up are forgotten
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const MINUTE: Duration = Duration::from_secs(60);
+const DAY: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// How many links can be made. 0 turns that limit off. Signed in users have an allowance of their
+/// own, whatever address they come from, and own the links they make; everyone else shares the
+/// allowance of their address.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct RateLimitPrefs {
+    /// Links made without signing in, from the form on the home page or `POST /api/urls`
+    anonymous_per_minute: u32,
+    anonymous_per_day: u32,
+    /// Links made signed in the same ways, and bulk requests, each counting once however many
+    /// links it makes
+    signed_in_per_minute: u32,
+    signed_in_per_day: u32,
+}
+
+impl Default for RateLimitPrefs {
+    fn default() -> Self {
+        RateLimitPrefs {
+            anonymous_per_minute: 10,
+            anonymous_per_day: 100,
+            signed_in_per_minute: 60,
+            signed_in_per_day: 1_000,
+        }
+    }
+}
+
+impl RateLimitPrefs {
+    fn limits(&self, key: &LimitKey) -> [(u32, Duration); 2] {
+        match key {
+            LimitKey::Address(_) => [
+                (self.anonymous_per_minute, MINUTE),
+                (self.anonymous_per_day, DAY),
+            ],
+            LimitKey::User(_) => [
+                (self.signed_in_per_minute, MINUTE),
+                (self.signed_in_per_day, DAY),
+            ],
+        }
+    }
+}
+
+/// Who is making links, as each has limits of its own
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Creator {
+    Anonymous,
+    /// The id of the signed in user
+    SignedIn(i64),
+}
+
+impl Creator {
+    /// Who the links made are owned by
+    pub fn owner(self) -> Option<i64> {
+        match self {
+            Creator::Anonymous => None,
+            Creator::SignedIn(id) => Some(id),
+        }
+    }
+}
+
+/// What is left of one address's allowance: a token bucket per limit, each holding as many
+/// tokens as the limit and filling back up over its period
+#[derive(Debug, Clone)]
+struct Buckets {
+    tokens: [f64; 2],
+    updated: Instant,
+}
+
+/// Whose allowance a link is taken from
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+enum LimitKey {
+    /// The network of someone who isn't signed in
+    Address(ClientId),
+    User(i64),
+}
+
+/// How many links each address and user made lately, so one can't fill the database in minutes. Kept in
+/// memory only, so limits start over on a restart and every instance keeps its own.
+pub struct CreationLimits {
+    entries: Locked<HashMap<LimitKey, Buckets>>,
+    capacity: usize,
+    prefs: RateLimitPrefs,
+}
+
+impl CreationLimits {
+    pub fn new(prefs: &RateLimitPrefs, capacity: usize) -> Self {
+        CreationLimits {
+            entries: Locked::default(),
+            capacity: capacity.max(1),
+            prefs: prefs.clone(),
+        }
+    }
+
+    /// Takes one link from the allowance of `creator`, or of `client` when they aren't signed in.
+    /// When it has run out, or it is new and the capacity is already tracked, nothing is taken
+    /// and the error says how long until there is one again.
+    pub fn take(&self, creator: Creator, client: ClientId) -> Result<(), Duration> {
+        let key = match creator {
+            Creator::Anonymous => LimitKey::Address(client.network()),
+            Creator::SignedIn(id) => LimitKey::User(id),
+        };
+        let limits = self.prefs.limits(&key);
+        self.entries
+            .with(|entries| take_token(entries, self.capacity, limits, key, Instant::now()))
+    }
+
+    /// Forgets the allowances that have filled back up by `now`, as they are no different from
+    /// ones never touched
+    fn sweep(&self, now: Instant) {
+        self.entries.with(|entries| {
+            entries.retain(|key, buckets| !is_full(buckets, self.prefs.limits(key), now))
+        });
+    }
+}
+
+/// Runs [CreationLimits::sweep] every [SWEEP_INTERVAL]
+pub async fn sweep(limits: Arc<CreationLimits>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        limits.sweep(Instant::now());
+    }
+}
+
+/// `buckets` as they are at `now`, having filled up since they were last updated
+fn refill(buckets: &mut Buckets, limits: [(u32, Duration); 2], now: Instant) {
+    let elapsed = now.duration_since(buckets.updated).as_secs_f64();
+    for (tokens, (limit, period)) in buckets.tokens.iter_mut().zip(limits) {
+        let limit = f64::from(limit);
+        *tokens = (*tokens + elapsed * limit / period.as_secs_f64()).min(limit);
+    }
+    buckets.updated = now;
+}
+
+fn is_full(buckets: &Buckets, limits: [(u32, Duration); 2], now: Instant) -> bool {
+    let mut buckets = buckets.clone();
+    refill(&mut buckets, limits, now);
+    buckets
+        .tokens
+        .iter()
+        .zip(limits)
+        .all(|(tokens, (limit, _))| *tokens >= f64::from(limit))
+}
+
+/// Takes a token from each of `key`'s buckets, or none if any of them is empty. A new key is
+/// refused while the map holds `capacity` of them, until the next sweep.
+fn take_token(
+    entries: &mut HashMap<LimitKey, Buckets>,
+    capacity: usize,
+    limits: [(u32, Duration); 2],
+    key: LimitKey,
+    now: Instant,
+) -> Result<(), Duration> {
+    if !entries.contains_key(&key) && entries.len() >= capacity {
+        return Err(SWEEP_INTERVAL);
+    }
+    let buckets = entries.entry(key).or_insert_with(|| Buckets {
+        tokens: limits.map(|(limit, _)| f64::from(limit)),
+        updated: now,
+    });
+    refill(buckets, limits, now);
+    let mut wait = Duration::ZERO;
+    for (tokens, (limit, period)) in buckets.tokens.iter().zip(limits) {
+        if limit > 0 && *tokens < 1.0 {
+            let missing = (1.0 - tokens) * period.as_secs_f64() / f64::from(limit);
+            wait = wait.max(Duration::from_secs_f64(missing));
+        }
+    }
+    if !wait.is_zero() {
+        return Err(wait);
+    }
+    for (tokens, (limit, _)) in buckets.tokens.iter_mut().zip(limits) {
+        if limit > 0 {
+            *tokens -= 1.0;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+    use crate::client_id::Client;
+
+    const LIMITS: [(u32, Duration); 2] = [(2, MINUTE), (3, DAY)];
+
+    fn key(address: &str) -> LimitKey {
+        LimitKey::Address(ClientId::Address(address.parse().unwrap()))
+    }
+
+    #[test]
+    fn refuses_past_the_limit_until_it_refills() {
+        let mut entries = HashMap::new();
+        let start = Instant::now();
+        let take = |entries: &mut HashMap<_, _>, seconds| {
+            take_token(
+                entries,
+                10,
+                LIMITS,
+                key("203.0.113.7"),
+                start + Duration::from_secs(seconds),
+            )
+        };
+
+        assert_eq!(take(&mut entries, 0), Ok(()));
+        assert_eq!(take(&mut entries, 0), Ok(()));
+        // A token comes back every 30 seconds
+        let wait = take(&mut entries, 10).unwrap_err();
+        assert!((wait.as_secs_f64() - 20.0).abs() < 0.01, "{wait:?}");
+        assert_eq!(take(&mut entries, 30), Ok(()));
+        // The day's three are used up, and one comes back every 8 hours
+        let wait = take(&mut entries, 90).unwrap_err();
+        assert!(wait > Duration::from_secs(7 * 60 * 60), "{wait:?}");
+        assert_eq!(
+            take_token(&mut entries, 10, LIMITS, key("203.0.113.8"), start),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn zero_turns_a_limit_off() {
+        let mut entries = HashMap::new();
+        let now = Instant::now();
+        let per_day_only = [(0, MINUTE), (3, DAY)];
+        for _ in 0..3 {
+            assert_eq!(
+                take_token(&mut entries, 10, per_day_only, key("203.0.113.7"), now),
+                Ok(())
+            );
+        }
+        assert!(take_token(&mut entries, 10, per_day_only, key("203.0.113.7"), now).is_err());
+        for _ in 0..100 {
+            assert_eq!(
+                take_token(&mut entries, 10, [(0, MINUTE); 2], key("203.0.113.8"), now),
+                Ok(())
+            );
+        }
+    }
+
+    #[test]
+    fn ipv6_addresses_share_their_64() {
+        let day: NaiveDate = "2024-03-04".parse().unwrap();
+        for secret in [None, Some("secret")] {
+            let limits = CreationLimits::new(&RateLimitPrefs::default(), 10);
+            let client = |address: &str| Client::new(address.parse().unwrap(), day, secret).id;
+            for last in 0..10 {
+                let client = client(&format!("2001:db8::{last}"));
+                assert_eq!(limits.take(Creator::Anonymous, client), Ok(()));
+            }
+            let client = client("2001:db8::ff");
+            assert!(limits.take(Creator::Anonymous, client).is_err());
+            // Signed in users have an allowance of their own
+            assert_eq!(limits.take(Creator::SignedIn(1), client), Ok(()));
+        }
+    }
+
+    #[test]
+    fn anonymous_clients_are_limited_without_their_address() {
+        let day: NaiveDate = "2024-03-04".parse().unwrap();
+        let limits = CreationLimits::new(&RateLimitPrefs::default(), 10);
+        let client = Client::new("203.0.113.7".parse().unwrap(), day, Some("secret"));
+        for _ in 0..10 {
+            assert_eq!(limits.take(Creator::Anonymous, client.id), Ok(()));
+        }
+        assert!(limits.take(Creator::Anonymous, client.id).is_err());
+        let tracked: Vec<LimitKey> = limits
+            .entries
+            .with(|entries| entries.keys().copied().collect());
+        assert!(tracked
+            .iter()
+            .all(|key| matches!(key, LimitKey::Address(ClientId::Anonymous(_)))));
+    }
+
+    #[test]
+    fn signed_in_users_are_limited_wherever_they_are() {
+        let limits = CreationLimits::new(&RateLimitPrefs::default(), 10);
+        let address = |last: u8| ClientId::Address(format!("203.0.113.{last}").parse().unwrap());
+        for last in 0..60 {
+            assert_eq!(limits.take(Creator::SignedIn(1), address(last)), Ok(()));
+        }
+        assert!(limits.take(Creator::SignedIn(1), address(60)).is_err());
+        // Another user behind the same address has an allowance of their own
+        assert_eq!(limits.take(Creator::SignedIn(2), address(0)), Ok(()));
+    }
+
+    #[test]
+    fn full_allowances_are_forgotten() {
+        let limits = CreationLimits::new(&RateLimitPrefs::default(), 1);
+        let first = ClientId::Address("203.0.113.7".parse().unwrap());
+        let second = ClientId::Address("203.0.113.8".parse().unwrap());
+        limits.take(Creator::Anonymous, first).unwrap();
+        // At capacity a new address waits for room instead of pushing out the one tracked
+        assert_eq!(limits.take(Creator::Anonymous, second), Err(SWEEP_INTERVAL));
+        assert_eq!(limits.take(Creator::Anonymous, first), Ok(()));
+        assert_eq!(limits.entries.with(|entries| entries.len()), 1);
+
+        limits.sweep(Instant::now());
+        assert_eq!(limits.entries.with(|entries| entries.len()), 1);
+        limits.sweep(Instant::now() + DAY);
+        assert_eq!(limits.entries.with(|entries| entries.len()), 0);
+        assert_eq!(limits.take(Creator::Anonymous, second), Ok(()));
+    }
+}
@@ -0,0 +1,52 @@
+use std::{
+    future::Future,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Instant,
+};
+
+use futures_util::future::join_all;
+use serde::Serialize;
+use sqlx::PgPool;
+use tracing::{info, warn};
+
+/// How ready the server is to take traffic. It listens as soon as one database connection works
+/// and the migrations ran ([ReadyState::Minimal]); the rest of the pool is opened in the
+/// background after that ([ReadyState::Warm]).
+#[derive(Default)]
+pub struct Readiness {
+    warm: AtomicBool,
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadyState {
+    Minimal,
+    Warm,
+}
+
+impl Readiness {
+    pub fn state(&self) -> ReadyState {
+        if self.warm.load(Ordering::Acquire) {
+            ReadyState::Warm
+        } else {
+            ReadyState::Minimal
+        }
+    }
+    /// Runs the warm-up work and marks the server warm once it is done
+    pub async fn warm_up_with(&self, work: impl Future<Output = ()>) {
+        let started = Instant::now();
+        work.await;
+        self.warm.store(true, Ordering::Release);
+        info!("Warm-up finished in {:?}", started.elapsed());
+    }
+}
+
+/// Opens connections until the pool holds `connections` of them. They are all checked out at once
+/// so the pool has to open new ones instead of handing back the same connection.
+pub async fn warm_pool(pool: PgPool, connections: u32) {
+    let acquired = join_all((0..connections).map(|_| pool.acquire())).await;
+    let failed = acquired.iter().filter(|conn| conn.is_err()).count();
+    if failed > 0 {
+        warn!("{failed} of {connections} connections couldn't be opened during warm-up");
+    }
+}
@@ -0,0 +1,264 @@
+use std::{
+    fmt::Display,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use sqlx::{postgres::PgPoolOptions, PgPool};
+use tracing::{info, warn};
+
+use crate::locked::Locked;
+
+/// How often the probe checks the database
+const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+/// A probe taking longer than this counts as failed
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Failed probes in a row before the pool is recycled. One failure is usually just a blip.
+const FAILURES_BEFORE_RECYCLE: u32 = 3;
+
+/// Retires pooled connections after a database failover. Each connection resolves the database
+/// host when it is opened, so once the old ones are gone new ones reach whatever the name points to
+/// now. Connections busy with a query are left to finish and are closed when they are released.
+#[derive(Clone, Default)]
+pub struct Recycler {
+    inner: Arc<RecyclerState>,
+}
+
+#[derive(Default)]
+struct RecyclerState {
+    /// Connections opened before this are stale
+    recycled_at: Locked<Option<Instant>>,
+    recycles: AtomicU64,
+}
+
+impl Recycler {
+    /// Adds the hooks that keep stale connections from being used or returned to the pool
+    pub fn attach(&self, options: PgPoolOptions) -> PgPoolOptions {
+        let on_acquire = self.clone();
+        let on_release = self.clone();
+        options
+            .before_acquire(move |_, meta| {
+                let fresh = on_acquire.is_fresh(meta.age);
+                Box::pin(async move { Ok(fresh) })
+            })
+            .after_release(move |_, meta| {
+                let fresh = on_release.is_fresh(meta.age);
+                Box::pin(async move { Ok(fresh) })
+            })
+    }
+
+    /// Whether a connection opened `age` ago was opened after the last recycle
+    fn is_fresh(&self, age: Duration) -> bool {
+        self.inner
+            .recycled_at
+            .with(|recycled_at| recycled_at.is_none_or(|at| age < at.elapsed()))
+    }
+
+    /// Marks every open connection stale and closes the idle ones straight away. Returns how many
+    /// idle connections were closed.
+    pub fn recycle(&self, pool: &PgPool) -> usize {
+        self.inner
+            .recycled_at
+            .with(|recycled_at| *recycled_at = Some(Instant::now()));
+        let recycles = self.inner.recycles.fetch_add(1, Ordering::Relaxed) + 1;
+        // try_acquire only hands out idle connections and never opens new ones
+        let mut closed = 0;
+        for _ in 0..pool.num_idle() {
+            let Some(mut conn) = pool.try_acquire() else {
+                break;
+            };
+            conn.close_on_drop();
+            closed += 1;
+        }
+        info!("Recycled the connection pool ({recycles} so far), closed {closed} idle connections");
+        closed
+    }
+
+    #[cfg(test)]
+    pub fn recycles(&self) -> u64 {
+        self.inner.recycles.load(Ordering::Relaxed)
+    }
+}
+
+/// Checks the database every few seconds and recycles the pool after repeated failures. Being
+/// connected to a standby counts as a failure: after a failover the old primary often keeps
+/// accepting connections but refuses every write.
+pub async fn probe(pool: PgPool, recycler: Recycler) {
+    let mut failures = 0;
+    let mut interval = tokio::time::interval(PROBE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let probed = tokio::time::timeout(
+            PROBE_TIMEOUT,
+            sqlx::query_scalar!("SELECT pg_is_in_recovery()").fetch_one(&pool),
+        )
+        .await;
+        match probed {
+            Ok(Ok(Some(false))) => {
+                failures = 0;
+                continue;
+            }
+            Ok(Ok(_)) => warn!("Database probe reached a standby"),
+            Ok(Err(e)) => warn!("Database probe failed ({}): {e}", Failure::classify(&e)),
+            Err(_) => warn!("Database probe timed out after {PROBE_TIMEOUT:?}"),
+        }
+        failures += 1;
+        if failures >= FAILURES_BEFORE_RECYCLE {
+            recycler.recycle(&pool);
+            failures = 0;
+        }
+    }
+}
+
+/// Recycles the pool whenever the process gets SIGUSR2, for planned failovers
+#[cfg(unix)]
+pub async fn on_signal(pool: PgPool, recycler: Recycler) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::user_defined2()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            warn!(
+                "Couldn't listen for SIGUSR2, pool recycling is only available over the API: {e}"
+            );
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        recycler.recycle(&pool);
+    }
+}
+
+/// Why talking to the database failed, for logs. A DNS failure points at the failover still
+/// propagating, an auth failure at the credentials, and neither is fixed by the other's remedy.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Failure {
+    Dns,
+    Auth,
+    Refused,
+    Timeout,
+    Other,
+}
+
+impl Failure {
+    pub fn classify(err: &sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::Io(e) if e.kind() == io::ErrorKind::ConnectionRefused => Failure::Refused,
+            sqlx::Error::Io(e) if e.kind() == io::ErrorKind::TimedOut => Failure::Timeout,
+            // getaddrinfo failures have no kind of their own, only this message
+            sqlx::Error::Io(e) if e.to_string().contains("failed to lookup address") => {
+                Failure::Dns
+            }
+            // invalid_authorization_specification and invalid_password
+            sqlx::Error::Database(e) if matches!(e.code().as_deref(), Some("28000" | "28P01")) => {
+                Failure::Auth
+            }
+            sqlx::Error::PoolTimedOut => Failure::Timeout,
+            _ => Failure::Other,
+        }
+    }
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Failure::Dns => "DNS lookup failed",
+            Failure::Auth => "authentication failed",
+            Failure::Refused => "connection refused",
+            Failure::Timeout => "timed out",
+            Failure::Other => "other error",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::Connection;
+
+    use super::*;
+    use crate::preferences::Preferences;
+
+    fn conn_url(user_pass: Option<&str>, host: &str) -> String {
+        let prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        let credentials = user_pass
+            .map(String::from)
+            .unwrap_or_else(|| format!("{}:{}", prefs.db_user(), prefs.db_pass()));
+        format!("postgres://{credentials}@{host}/{}", prefs.db_name())
+    }
+
+    async fn backend_pid(pool: &PgPool) -> i32 {
+        sqlx::query_scalar!("SELECT pg_backend_pid()")
+            .fetch_one(pool)
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn recycling_replaces_connections_without_cutting_queries() {
+        let recycler = Recycler::default();
+        let pool = recycler
+            .attach(PgPoolOptions::new().max_connections(4))
+            .connect(&conn_url(None, "172.17.0.2"))
+            .await
+            .unwrap();
+        // Open two connections, one to stay busy and one to sit idle
+        let (busy, idle) = tokio::join!(pool.acquire(), pool.acquire());
+        let mut busy = busy.unwrap();
+        drop(idle);
+        // Released connections go back to the pool in the background
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(pool.num_idle(), 1);
+        let old = backend_pid(&pool).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let in_flight = tokio::spawn(async move {
+            let pid = sqlx::query_scalar!("SELECT pg_backend_pid() FROM pg_sleep(0.3)")
+                .fetch_one(&mut *busy)
+                .await;
+            (pid, busy)
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(recycler.recycle(&pool), 1);
+        assert_eq!(recycler.recycles(), 1);
+
+        let after = backend_pid(&pool).await;
+        assert_ne!(after, old);
+        let (pid, busy) = in_flight.await.unwrap();
+        let busy_pid = pid.expect("the in-flight query was cut off").unwrap();
+        assert_ne!(busy_pid, after);
+        drop(busy);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // The connection that was busy isn't handed out again once it is released
+        for _ in 0..4 {
+            let pid = backend_pid(&pool).await;
+            assert!(
+                pid != old && pid != busy_pid,
+                "stale connection {pid} reused"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn classifies_connection_failures() {
+        let dns = sqlx::PgConnection::connect(&conn_url(None, "no-such-host.invalid"))
+            .await
+            .unwrap_err();
+        assert_eq!(Failure::classify(&dns), Failure::Dns, "{dns}");
+
+        let auth = sqlx::PgConnection::connect(&conn_url(Some("no_such_role:pw"), "172.17.0.2"))
+            .await
+            .unwrap_err();
+        assert_eq!(Failure::classify(&auth), Failure::Auth, "{auth}");
+
+        let refused = sqlx::PgConnection::connect(&conn_url(None, "127.0.0.1:1"))
+            .await
+            .unwrap_err();
+        assert_eq!(Failure::classify(&refused), Failure::Refused, "{refused}");
+    }
+}
@@ -0,0 +1,92 @@
+use axum::http::{header::REFERER, HeaderMap};
+
+/// What visits without a usable Referer are counted under
+pub const DIRECT: &str = "direct";
+/// Longer origins are counted as direct rather than stored
+const MAX_ORIGIN_LEN: usize = 255;
+
+/// The origin (scheme and host) of the page that linked to us, or [DIRECT]. Paths and queries are
+/// dropped so the number of distinct referrers per link stays small and nothing private in them
+/// gets stored.
+pub fn origin(headers: &HeaderMap) -> String {
+    headers
+        .get(REFERER)
+        .and_then(|referer| referer.to_str().ok())
+        .and_then(parse_origin)
+        .unwrap_or_else(|| String::from(DIRECT))
+}
+
+fn parse_origin(referer: &str) -> Option<String> {
+    let (scheme, rest) = referer.trim().split_once("://")?;
+    let scheme_ok = scheme
+        .chars()
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic())
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+    if !scheme_ok {
+        return None;
+    }
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    // Credentials are never part of the origin
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    if host.is_empty() || host.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return None;
+    }
+    let origin = format!(
+        "{}://{}",
+        scheme.to_ascii_lowercase(),
+        host.to_ascii_lowercase()
+    );
+    (origin.len() <= MAX_ORIGIN_LEN).then_some(origin)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn referred_by(referer: &str) -> String {
+        let mut headers = HeaderMap::new();
+        headers.insert(REFERER, HeaderValue::from_str(referer).unwrap());
+        origin(&headers)
+    }
+
+    #[test]
+    fn keeps_only_the_origin() {
+        for (referer, expected) in [
+            (
+                "https://news.example.com/item?id=42",
+                "https://news.example.com",
+            ),
+            ("HTTPS://Example.COM", "https://example.com"),
+            ("http://localhost:8080/page#top", "http://localhost:8080"),
+            ("https://user:pw@example.com/", "https://example.com"),
+            (
+                "android-app://com.google.android.gm/",
+                "android-app://com.google.android.gm",
+            ),
+        ] {
+            assert_eq!(referred_by(referer), expected, "{referer}");
+        }
+    }
+
+    #[test]
+    fn unusable_referers_are_direct() {
+        assert_eq!(origin(&HeaderMap::new()), DIRECT);
+        for referer in [
+            "",
+            "example.com/page",
+            "://example.com",
+            "https://",
+            "1http://example.com",
+            &format!("https://{}.com", "a".repeat(300)),
+        ] {
+            assert_eq!(referred_by(referer), DIRECT, "{referer}");
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use hmac::Mac;
+
+use crate::{client_id::ClientId, locked::Locked, user::jwt::HmacSha256};
+
+/// Most visitors remembered at once unless sized otherwise, see [crate::resources]. Past the
+/// capacity, the visitor seen longest ago makes room for the new one.
+pub const REPEAT_CLICK_CAPACITY: usize = 10_000;
+
+/// A visitor to one link: its id and a keyed hash of their address
+type VisitKey = (i64, [u8; 16]);
+
+/// Who opened which link recently, so reloading a link doesn't count as another visit. Addresses
+/// are hashed with a key made at startup and never kept, so nothing here says who the visitors
+/// were, not even across restarts.
+pub struct RepeatClicks {
+    entries: Locked<Visits>,
+    capacity: usize,
+    /// Visits to the same link from the same address closer together than this count once. Zero
+    /// counts every visit.
+    window: Duration,
+    key: [u8; 32],
+}
+
+impl RepeatClicks {
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        RepeatClicks {
+            entries: Locked::default(),
+            capacity: capacity.max(1),
+            window,
+            key: rand::random(),
+        }
+    }
+
+    /// Whether `client` opened link `url_id` within the window, remembering this visit either
+    /// way. A repeat doesn't move the window on, so someone reloading all day is counted once a
+    /// window.
+    pub fn is_repeat(&self, url_id: i64, client: ClientId) -> bool {
+        if self.window.is_zero() {
+            return false;
+        }
+        let key = (url_id, self.hash(client));
+        self.entries
+            .with(|entries| seen_within(entries, self.capacity, self.window, key, Instant::now()))
+    }
+
+    fn hash(&self, client: ClientId) -> [u8; 16] {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC takes keys of any size");
+        mac.update(client.to_string().as_bytes());
+        let mut hashed = [0; 16];
+        hashed.copy_from_slice(&mac.finalize().into_bytes()[..16]);
+        hashed
+    }
+}
+
+/// The visitors remembered, with when each was counted
+#[derive(Default)]
+struct Visits {
+    counted: HashMap<VisitKey, Instant>,
+    /// The keys of `counted`, oldest first, so the oldest can be let go without a scan
+    order: VecDeque<(Instant, VisitKey)>,
+}
+
+impl Visits {
+    fn forget_oldest(&mut self) {
+        if let Some((_, key)) = self.order.pop_front() {
+            self.counted.remove(&key);
+        }
+    }
+}
+
+/// Checks `key` against `visits` and records it when it isn't a repeat, making room for it when
+/// `capacity` visitors are remembered
+fn seen_within(
+    visits: &mut Visits,
+    capacity: usize,
+    window: Duration,
+    key: VisitKey,
+    now: Instant,
+) -> bool {
+    // Visitors are only ever counted at the back, so those past the window are all at the front
+    while visits
+        .order
+        .front()
+        .is_some_and(|(counted, _)| now.duration_since(*counted) >= window)
+    {
+        visits.forget_oldest();
+    }
+    if visits.counted.contains_key(&key) {
+        return true;
+    }
+    if visits.counted.len() >= capacity {
+        visits.forget_oldest();
+    }
+    visits.counted.insert(key, now);
+    visits.order.push_back((now, key));
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeats_count_once_a_window() {
+        let window = Duration::from_secs(30);
+        let mut entries = Visits::default();
+        let start = Instant::now();
+        let visit = |entries: &mut Visits, key, seconds| {
+            seen_within(
+                entries,
+                10,
+                window,
+                key,
+                start + Duration::from_secs(seconds),
+            )
+        };
+
+        assert!(!visit(&mut entries, (1, [0; 16]), 0));
+        assert!(visit(&mut entries, (1, [0; 16]), 10));
+        assert!(visit(&mut entries, (1, [0; 16]), 29));
+        // Another visitor, or the same one on another link
+        assert!(!visit(&mut entries, (1, [1; 16]), 10));
+        assert!(!visit(&mut entries, (2, [0; 16]), 10));
+        assert!(!visit(&mut entries, (1, [0; 16]), 30));
+        assert!(visit(&mut entries, (1, [0; 16]), 45));
+    }
+
+    #[test]
+    fn the_oldest_visitor_makes_room() {
+        let window = Duration::from_secs(30);
+        let mut entries = Visits::default();
+        let start = Instant::now();
+        for (visitor, seconds) in [(0, 0), (1, 1), (2, 2)] {
+            seen_within(
+                &mut entries,
+                2,
+                window,
+                (1, [visitor; 16]),
+                start + Duration::from_secs(seconds),
+            );
+        }
+
+        assert_eq!(entries.counted.len(), 2);
+        assert_eq!(entries.order.len(), 2);
+        assert!(!entries.counted.contains_key(&(1, [0; 16])));
+        // Visitors past the window are let go whether or not room is needed
+        seen_within(
+            &mut entries,
+            2,
+            window,
+            (2, [0; 16]),
+            start + Duration::from_secs(60),
+        );
+        assert_eq!(entries.counted.len(), 1);
+        assert_eq!(entries.order.len(), 1);
+    }
+
+    #[test]
+    fn addresses_are_only_kept_hashed() {
+        let clicks = RepeatClicks::new(Duration::from_secs(30), 10);
+        let client = ClientId::Address("203.0.113.7".parse().unwrap());
+
+        assert!(!clicks.is_repeat(1, client));
+        assert!(clicks.is_repeat(1, client));
+        let elsewhere = ClientId::Address("203.0.113.8".parse().unwrap());
+        assert!(!clicks.is_repeat(1, elsewhere));
+        let stored = clicks
+            .entries
+            .with(|entries| entries.counted.keys().copied().collect::<Vec<_>>());
+        assert!(stored.contains(&(1, clicks.hash(client))));
+        assert_ne!(clicks.hash(client)[..4], [203, 0, 113, 7]);
+        // Another key, as after a restart, hashes the same address differently
+        assert_ne!(
+            RepeatClicks::new(Duration::ZERO, 1).hash(client),
+            clicks.hash(client)
+        );
+
+        let off = RepeatClicks::new(Duration::ZERO, 10);
+        assert!(!off.is_repeat(1, client));
+        assert!(!off.is_repeat(1, client));
+    }
+}
@@ -0,0 +1,712 @@
+use axum::http::{HeaderMap, Method, StatusCode};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{form_verification, preferences::Preferences, url_db::UrlRow, user_agent};
+
+/// What the visitor's request looks like, as far as the gates are concerned.
+pub struct RequestContext<'a> {
+    pub headers: &'a HeaderMap,
+    pub method: &'a Method,
+    pub now: DateTime<Utc>,
+    /// The visitor gave the right passphrase for this link
+    pub unlocked: bool,
+    /// How links without a status of their own redirect, see [crate::preferences::Preferences]
+    pub redirect_status: RedirectStatus,
+    /// The schemes a destination may have to be redirected to, lowercase
+    pub allowed_schemes: &'a [String],
+}
+
+impl<'a> RequestContext<'a> {
+    /// A request on an instance set up with `prefs`, which decide how links redirect and where to
+    pub fn new(
+        headers: &'a HeaderMap,
+        method: &'a Method,
+        now: DateTime<Utc>,
+        unlocked: bool,
+        prefs: &'a Preferences,
+    ) -> Self {
+        RequestContext {
+            headers,
+            method,
+            now,
+            unlocked,
+            redirect_status: prefs.redirect_status(),
+            allowed_schemes: prefs.allowed_schemes(),
+        }
+    }
+}
+
+/// The statuses a link may redirect with. Anything else in a config file or a new link is
+/// rejected, and a stored value outside these is ignored.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(try_from = "u16", into = "u16")]
+pub enum RedirectStatus {
+    /// Cached by browsers with no expiry, so edits and deletions never reach people who have
+    /// been before
+    #[default]
+    MovedPermanently,
+    Found,
+    TemporaryRedirect,
+    PermanentRedirect,
+}
+
+impl RedirectStatus {
+    pub fn status_code(self) -> StatusCode {
+        match self {
+            RedirectStatus::MovedPermanently => StatusCode::MOVED_PERMANENTLY,
+            RedirectStatus::Found => StatusCode::FOUND,
+            RedirectStatus::TemporaryRedirect => StatusCode::TEMPORARY_REDIRECT,
+            RedirectStatus::PermanentRedirect => StatusCode::PERMANENT_REDIRECT,
+        }
+    }
+
+    /// The status that means the same but isn't cached, for links that can stop working
+    pub fn temporary(self) -> RedirectStatus {
+        match self {
+            RedirectStatus::MovedPermanently => RedirectStatus::Found,
+            RedirectStatus::PermanentRedirect => RedirectStatus::TemporaryRedirect,
+            temporary => temporary,
+        }
+    }
+}
+
+impl TryFrom<u16> for RedirectStatus {
+    type Error = String;
+
+    fn try_from(status: u16) -> Result<Self, Self::Error> {
+        match status {
+            301 => Ok(RedirectStatus::MovedPermanently),
+            302 => Ok(RedirectStatus::Found),
+            307 => Ok(RedirectStatus::TemporaryRedirect),
+            308 => Ok(RedirectStatus::PermanentRedirect),
+            other => Err(format!(
+                "Redirect status must be 301, 302, 307 or 308, not {other}"
+            )),
+        }
+    }
+}
+
+impl From<RedirectStatus> for u16 {
+    fn from(status: RedirectStatus) -> u16 {
+        status.status_code().as_u16()
+    }
+}
+
+/// What visitors to a link that isn't active yet get
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PendingLinks {
+    /// A page saying when the link starts working
+    #[default]
+    Page,
+    /// The same as for a link that doesn't exist, so a launch isn't given away early
+    NotFound,
+}
+
+/// The result of resolving a short url. Handlers turn this into a response with a single match and
+/// never make gating decisions themselves.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    /// Send the visitor to the destination with the given status
+    Redirect(String, StatusCode),
+    /// Show a page instead of redirecting straight away
+    Interstitial(Interstitial),
+    /// Try to claim the single use link with this id. Whoever claims it gets redirected.
+    Claim(i64),
+    NotFound,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Interstitial {
+    /// A branded "redirecting in N seconds" page. Serving it counts as the click.
+    Countdown { target: String, seconds: i16 },
+    /// Stands in for an unclaimed single use link without revealing or consuming it
+    SingleUse { short_url: String },
+    /// The single use link was claimed at this time. Served as 410 Gone.
+    Consumed { at: DateTime<Utc> },
+    /// The link only starts working at this time. Served as a 404, with a page saying when unless
+    /// the instance hides such links, see [PendingLinks].
+    Pending { at: DateTime<Utc> },
+    /// The link stopped working at this time. Served as 410 Gone.
+    Expired { at: DateTime<Utc> },
+    /// The link was opened as many times as it allows. Served as 410 Gone.
+    Exhausted { max_clicks: i64 },
+    /// Asks for the link's passphrase. Posting it back resolves the link again, unlocked.
+    Passphrase { short_url: String },
+}
+
+/// Runs a looked up row through every gate and decides what the visitor gets. Gates are checked in
+/// the order below and the first one that applies wins:
+///
+/// 1. The row doesn't exist, or its destination's scheme isn't allowed, say because it was stored
+///    before the allowlist or taken off it since: [Outcome::NotFound]. Nothing outside the
+///    allowlist is ever put in a Location header, whatever got stored.
+/// 2. The link is scheduled to start working later: [Interstitial::Pending], whatever else it is.
+///    `ctx.now` should come from the database's clock for these, see
+///    [crate::url_db::database_now].
+/// 3. The link has expired: [Interstitial::Expired]
+/// 4. The link has used up its click limit: [Interstitial::Exhausted]. The handler checks the
+///    limit again when it counts the click, since others may be visiting at the same time.
+/// 5. The link is single use and already claimed: [Interstitial::Consumed]
+/// 6. The link has a passphrase the visitor hasn't given: [Interstitial::Passphrase], bots
+///    included
+/// 7. The link is single use and the visitor is a bot or only asked for the headers:
+///    [Interstitial::SingleUse], so unfurlers and link checkers can't burn it
+/// 8. The link is single use: [Outcome::Claim]. A countdown is skipped since the page would give
+///    the destination away before the claim.
+/// 9. The link has a countdown and the visitor isn't a bot: [Interstitial::Countdown]. Bots skip
+///    it so link previews still see the destination.
+/// 10. Otherwise the visitor is redirected to the destination: [Outcome::Redirect], with the
+///     link's own status or the instance's. Links that can stop working, by expiry or click
+///     limit, get the [RedirectStatus::temporary] of that, since browsers would keep following a
+///     301 or 308 without asking. So do rotating and geo-targeted links, which a browser would
+///     otherwise send to the same destination every time, wherever it is. An unlocked link
+///     answers the passphrase form, so it gets a 303 for the browser to follow with a GET.
+///
+/// New gates belong in this function (and in this list), not in the handler.
+pub fn resolve(row: Option<&UrlRow>, ctx: &RequestContext) -> Outcome {
+    let row = match row {
+        Some(row) => row,
+        None => return Outcome::NotFound,
+    };
+    if !scheme_allowed(&redirect_target(row.long_url()), ctx.allowed_schemes) {
+        return Outcome::NotFound;
+    }
+    if let Some(at) = row.active_from().filter(|_| row.is_pending(ctx.now)) {
+        return Outcome::Interstitial(Interstitial::Pending { at });
+    }
+    if let Some(at) = row.expires_at().filter(|at| *at <= ctx.now) {
+        return Outcome::Interstitial(Interstitial::Expired { at });
+    }
+    if let Some(max_clicks) = row.max_clicks().filter(|_| row.is_exhausted()) {
+        return Outcome::Interstitial(Interstitial::Exhausted { max_clicks });
+    }
+    if let Some(at) = row.consumed_at().filter(|_| row.single_use()) {
+        return Outcome::Interstitial(Interstitial::Consumed { at });
+    }
+    if row.is_protected() && !ctx.unlocked {
+        return Outcome::Interstitial(Interstitial::Passphrase {
+            short_url: row.clone_short_url(),
+        });
+    }
+    if row.single_use() {
+        if user_agent::is_bot(ctx.headers) || ctx.method == Method::HEAD {
+            return Outcome::Interstitial(Interstitial::SingleUse {
+                short_url: row.clone_short_url(),
+            });
+        }
+        return Outcome::Claim(row.id());
+    }
+
+    let target = redirect_target(row.long_url());
+    let seconds = row.interstitial_seconds();
+    if seconds > 0 && !user_agent::is_bot(ctx.headers) {
+        return Outcome::Interstitial(Interstitial::Countdown { target, seconds });
+    }
+
+    if ctx.unlocked {
+        return Outcome::Redirect(target, StatusCode::SEE_OTHER);
+    }
+    let status = row.redirect_status().unwrap_or(ctx.redirect_status);
+    let varies = row.rotates() || row.geo_targeted();
+    let status = if row.expires_at().is_some() || row.max_clicks().is_some() || varies {
+        status.temporary()
+    } else {
+        status
+    };
+    Outcome::Redirect(target, status.status_code())
+}
+
+/// What a person opening the link in a browser on an instance set up with `prefs` would get, for
+/// describing a link without visiting it. Like [resolve] this changes nothing; it is the caller
+/// that counts clicks and claims links.
+pub fn dry_run(row: Option<&UrlRow>, prefs: &Preferences) -> Outcome {
+    let headers = HeaderMap::new();
+    resolve(
+        row,
+        &RequestContext::new(&headers, &Method::GET, Utc::now(), false, prefs),
+    )
+}
+
+/// Destinations stored without a scheme are sent over plain http. Those with one keep it, allowed
+/// or not, see [scheme_allowed].
+pub fn redirect_target(long_url: &str) -> String {
+    if form_verification::given_scheme(long_url).is_some() {
+        long_url.to_string()
+    } else {
+        format!("http://{}", long_url)
+    }
+}
+
+/// Whether a [redirect_target] has one of the `allowed` schemes
+pub fn scheme_allowed(target: &str, allowed: &[String]) -> bool {
+    form_verification::given_scheme(target).is_some_and(|scheme| {
+        allowed
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(&scheme))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header::USER_AGENT, HeaderValue};
+
+    use super::*;
+
+    const BROWSER: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0";
+    const CRAWLER: &str = "Slackbot-LinkExpanding 1.0 (+https://api.slack.com/robots)";
+
+    /// Owns what a [RequestContext] borrows, so each test only spells out what it changes
+    struct Visit {
+        headers: HeaderMap,
+        method: Method,
+        now: DateTime<Utc>,
+        unlocked: bool,
+        redirect_status: RedirectStatus,
+        allowed_schemes: Vec<String>,
+    }
+
+    /// A plain GET from a visitor without a user agent, on an instance with the defaults
+    fn ctx() -> Visit {
+        Visit {
+            headers: HeaderMap::new(),
+            method: Method::GET,
+            now: Utc::now(),
+            unlocked: false,
+            redirect_status: RedirectStatus::default(),
+            allowed_schemes: crate::preferences::allowed_schemes_by_default(),
+        }
+    }
+
+    impl Visit {
+        fn agent(mut self, agent: &'static str) -> Self {
+            self.headers
+                .insert(USER_AGENT, HeaderValue::from_static(agent));
+            self
+        }
+
+        fn method(mut self, method: Method) -> Self {
+            self.method = method;
+            self
+        }
+
+        fn at(mut self, now: DateTime<Utc>) -> Self {
+            self.now = now;
+            self
+        }
+
+        fn unlocked(mut self, unlocked: bool) -> Self {
+            self.unlocked = unlocked;
+            self
+        }
+
+        fn redirect_status(mut self, redirect_status: RedirectStatus) -> Self {
+            self.redirect_status = redirect_status;
+            self
+        }
+
+        fn schemes(mut self, schemes: &[&str]) -> Self {
+            self.allowed_schemes = schemes.iter().map(|scheme| scheme.to_string()).collect();
+            self
+        }
+
+        fn resolve(&self, row: Option<&UrlRow>) -> Outcome {
+            let ctx = RequestContext {
+                headers: &self.headers,
+                method: &self.method,
+                now: self.now,
+                unlocked: self.unlocked,
+                redirect_status: self.redirect_status,
+                allowed_schemes: &self.allowed_schemes,
+            };
+            resolve(row, &ctx)
+        }
+    }
+
+    #[test]
+    fn missing_row_is_not_found() {
+        assert_eq!(ctx().resolve(None), Outcome::NotFound);
+    }
+
+    #[test]
+    fn redirects_to_destination() {
+        let with_scheme = UrlRow::test_row("abc", "https://example.com");
+        let without_scheme = UrlRow::test_row("abc", "example.com/page");
+
+        assert_eq!(
+            ctx().resolve(Some(&with_scheme)),
+            Outcome::Redirect(
+                String::from("https://example.com"),
+                StatusCode::MOVED_PERMANENTLY
+            )
+        );
+        assert_eq!(
+            ctx().resolve(Some(&without_scheme)),
+            Outcome::Redirect(
+                String::from("http://example.com/page"),
+                StatusCode::MOVED_PERMANENTLY
+            )
+        );
+    }
+
+    #[test]
+    fn countdown_unless_bot() {
+        let row = UrlRow::test_row("abc", "https://example.com").with_interstitial(3);
+
+        assert_eq!(
+            ctx().agent(BROWSER).resolve(Some(&row)),
+            Outcome::Interstitial(Interstitial::Countdown {
+                target: String::from("https://example.com"),
+                seconds: 3
+            })
+        );
+        assert_eq!(
+            ctx().agent(CRAWLER).resolve(Some(&row)),
+            Outcome::Redirect(
+                String::from("https://example.com"),
+                StatusCode::MOVED_PERMANENTLY
+            )
+        );
+    }
+
+    #[test]
+    fn zero_seconds_redirects() {
+        let row = UrlRow::test_row("abc", "https://example.com").with_interstitial(0);
+
+        assert_eq!(
+            ctx().agent(BROWSER).resolve(Some(&row)),
+            Outcome::Redirect(
+                String::from("https://example.com"),
+                StatusCode::MOVED_PERMANENTLY
+            )
+        );
+    }
+
+    #[test]
+    fn single_use_is_claimed_by_people_only() {
+        let row = UrlRow::test_row("abc", "https://example.com")
+            .with_interstitial(3)
+            .with_single_use(None);
+        let unclaimed = Outcome::Interstitial(Interstitial::SingleUse {
+            short_url: String::from("abc"),
+        });
+
+        assert_eq!(
+            ctx().agent(BROWSER).resolve(Some(&row)),
+            Outcome::Claim(row.id())
+        );
+        assert_eq!(ctx().agent(CRAWLER).resolve(Some(&row)), unclaimed);
+        assert_eq!(
+            ctx()
+                .agent(BROWSER)
+                .method(Method::HEAD)
+                .resolve(Some(&row)),
+            unclaimed
+        );
+    }
+
+    #[test]
+    fn consumed_single_use_is_gone() {
+        let at = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let row = UrlRow::test_row("abc", "https://example.com").with_single_use(Some(at));
+
+        for visit in [ctx(), ctx().agent(CRAWLER).method(Method::HEAD)] {
+            assert_eq!(
+                visit.resolve(Some(&row)),
+                Outcome::Interstitial(Interstitial::Consumed { at })
+            );
+        }
+    }
+
+    #[test]
+    fn expired_links_are_gone() {
+        let at = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let plain = UrlRow::test_row("abc", "https://example.com").with_expiry(at);
+        let single_use = plain.clone().with_single_use(None);
+        let countdown = plain.clone().with_interstitial(3);
+
+        for row in [&plain, &single_use, &countdown] {
+            for visit in [ctx().at(at), ctx().agent(CRAWLER).at(at)] {
+                assert_eq!(
+                    visit.resolve(Some(row)),
+                    Outcome::Interstitial(Interstitial::Expired { at })
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pending_links_wait_for_their_time() {
+        let at = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let plain = UrlRow::test_row("abc", "https://example.com").with_active_from(at);
+        let protected = plain.clone().with_passphrase("hunter2");
+        let single_use = plain.clone().with_single_use(None);
+
+        let before = ctx().at(at - chrono::TimeDelta::seconds(1));
+        for row in [&plain, &protected, &single_use] {
+            assert_eq!(
+                before.resolve(Some(row)),
+                Outcome::Interstitial(Interstitial::Pending { at })
+            );
+        }
+        assert_eq!(
+            ctx().at(at).resolve(Some(&plain)),
+            Outcome::Redirect(
+                String::from("https://example.com"),
+                StatusCode::MOVED_PERMANENTLY
+            )
+        );
+        assert_eq!(ctx().at(at).resolve(Some(&single_use)), Outcome::Claim(-1));
+    }
+
+    #[test]
+    fn expiring_links_redirect_temporarily() {
+        let at = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let row = UrlRow::test_row("abc", "https://example.com").with_expiry(at);
+        assert_eq!(
+            ctx()
+                .at(at - chrono::Duration::seconds(1))
+                .resolve(Some(&row)),
+            Outcome::Redirect(String::from("https://example.com"), StatusCode::FOUND)
+        );
+    }
+
+    #[test]
+    fn rotating_and_geo_targeted_links_redirect_temporarily() {
+        for row in [
+            UrlRow::test_row("abc", "https://example.com").with_rotation(),
+            UrlRow::test_row("abc", "https://example.com").with_geo_targets(),
+        ] {
+            for (instance, expected) in [
+                (RedirectStatus::MovedPermanently, StatusCode::FOUND),
+                (
+                    RedirectStatus::PermanentRedirect,
+                    StatusCode::TEMPORARY_REDIRECT,
+                ),
+            ] {
+                assert_eq!(
+                    ctx().redirect_status(instance).resolve(Some(&row)),
+                    Outcome::Redirect(String::from("https://example.com"), expected)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn click_limit_ends_redirects() {
+        let row = UrlRow::test_row("abc", "https://example.com").with_max_clicks(2);
+
+        for clicks in [0, 1] {
+            assert_eq!(
+                ctx().resolve(Some(&row.clone().with_clicks(clicks))),
+                Outcome::Redirect(String::from("https://example.com"), StatusCode::FOUND)
+            );
+        }
+        assert_eq!(
+            ctx().resolve(Some(&row.clone().with_clicks(2))),
+            Outcome::Interstitial(Interstitial::Exhausted { max_clicks: 2 })
+        );
+    }
+
+    #[test]
+    fn passphrase_comes_before_everything_but_gone_links() {
+        let plain = UrlRow::test_row("abc", "https://example.com").with_passphrase("pw");
+        let single_use = plain.clone().with_single_use(None);
+        let countdown = plain.clone().with_interstitial(3);
+        let ask = Outcome::Interstitial(Interstitial::Passphrase {
+            short_url: String::from("abc"),
+        });
+
+        for row in [&plain, &single_use, &countdown] {
+            assert_eq!(ctx().resolve(Some(row)), ask);
+            assert_eq!(ctx().agent(CRAWLER).resolve(Some(row)), ask);
+        }
+        assert_eq!(
+            ctx().unlocked(true).resolve(Some(&plain)),
+            Outcome::Redirect(String::from("https://example.com"), StatusCode::SEE_OTHER)
+        );
+        assert_eq!(
+            ctx().unlocked(true).resolve(Some(&single_use)),
+            Outcome::Claim(single_use.id())
+        );
+        let at = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        assert_eq!(
+            ctx().resolve(Some(&plain.clone().with_expiry(at))),
+            Outcome::Interstitial(Interstitial::Expired { at })
+        );
+    }
+
+    #[test]
+    fn only_allowed_schemes_are_redirected_to() {
+        for stored in [
+            "javascript:alert(1)",
+            "JavaScript:alert(1)",
+            "data:text/html,<script>alert(1)</script>",
+            "ftp://files.example.com/pub",
+        ] {
+            let row = UrlRow::test_row("abc", stored);
+            assert_eq!(ctx().resolve(Some(&row)), Outcome::NotFound, "{stored}");
+        }
+        let row = UrlRow::test_row("abc", "HTTPS://example.com");
+        assert!(matches!(ctx().resolve(Some(&row)), Outcome::Redirect(..)));
+
+        let row = UrlRow::test_row("abc", "ftp://files.example.com/pub");
+        let outcome = ctx().schemes(&["ftp"]).resolve(Some(&row));
+        assert!(matches!(outcome, Outcome::Redirect(..)));
+    }
+
+    #[test]
+    fn the_first_gate_that_applies_wins() {
+        let past = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let launch = Utc::now() + chrono::TimeDelta::days(1);
+        // Every combination of what a link and its visitor can be, each checked against the
+        // precedence in the documentation of [resolve]
+        for conditions in 0..1 << 7 {
+            let has = |bit: u32| conditions & (1 << bit) != 0;
+            let (off_list, pending, expired, exhausted, protected, countdown, bot) =
+                (has(0), has(1), has(2), has(3), has(4), has(5), has(6));
+            for single_use in [None, Some(None), Some(Some(past))] {
+                let target = if off_list {
+                    "ftp://example.com"
+                } else {
+                    "https://example.com"
+                };
+                let mut row = UrlRow::test_row("abc", target);
+                if pending {
+                    row = row.with_active_from(launch);
+                }
+                if expired {
+                    row = row.with_expiry(past);
+                }
+                if exhausted {
+                    row = row.with_max_clicks(2).with_clicks(2);
+                }
+                if protected {
+                    row = row.with_passphrase("pw");
+                }
+                if countdown {
+                    row = row.with_interstitial(3);
+                }
+                if let Some(consumed_at) = single_use {
+                    row = row.with_single_use(consumed_at);
+                }
+                let visit = ctx().agent(if bot { CRAWLER } else { BROWSER });
+
+                let expected = if off_list {
+                    Outcome::NotFound
+                } else if pending {
+                    Outcome::Interstitial(Interstitial::Pending { at: launch })
+                } else if expired {
+                    Outcome::Interstitial(Interstitial::Expired { at: past })
+                } else if exhausted {
+                    Outcome::Interstitial(Interstitial::Exhausted { max_clicks: 2 })
+                } else if let Some(Some(at)) = single_use {
+                    Outcome::Interstitial(Interstitial::Consumed { at })
+                } else if protected {
+                    Outcome::Interstitial(Interstitial::Passphrase {
+                        short_url: String::from("abc"),
+                    })
+                } else if single_use.is_some() && bot {
+                    Outcome::Interstitial(Interstitial::SingleUse {
+                        short_url: String::from("abc"),
+                    })
+                } else if single_use.is_some() {
+                    Outcome::Claim(row.id())
+                } else if countdown && !bot {
+                    Outcome::Interstitial(Interstitial::Countdown {
+                        target: String::from(target),
+                        seconds: 3,
+                    })
+                } else {
+                    let redirected = visit.resolve(Some(&row));
+                    assert!(
+                        matches!(redirected, Outcome::Redirect(..)),
+                        "{conditions:07b} {single_use:?}: {redirected:?}"
+                    );
+                    redirected
+                };
+                assert_eq!(
+                    visit.resolve(Some(&row)),
+                    expected,
+                    "{conditions:07b} {single_use:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn dry_run_follows_the_instance() {
+        let mut prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        prefs.set_redirect_status(RedirectStatus::Found);
+        prefs.set_allowed_schemes(vec![String::from("ftp")]);
+        let ftp = UrlRow::test_row("abc", "ftp://files.example.com/pub");
+        let https = UrlRow::test_row("abc", "https://example.com");
+
+        assert_eq!(
+            dry_run(Some(&ftp), &prefs),
+            Outcome::Redirect(
+                String::from("ftp://files.example.com/pub"),
+                StatusCode::FOUND
+            )
+        );
+        assert_eq!(dry_run(Some(&https), &prefs), Outcome::NotFound);
+    }
+
+    #[test]
+    fn redirect_status_comes_from_the_link_then_the_instance() {
+        let status_of =
+            |row: &UrlRow, instance| match ctx().redirect_status(instance).resolve(Some(row)) {
+                Outcome::Redirect(_, status) => status,
+                other => panic!("{other:?}"),
+            };
+        let plain = UrlRow::test_row("abc", "https://example.com");
+        let own = plain
+            .clone()
+            .with_redirect_status(RedirectStatus::PermanentRedirect);
+        let expiring = own
+            .clone()
+            .with_expiry(Utc::now() + chrono::TimeDelta::days(1));
+
+        assert_eq!(
+            status_of(&plain, RedirectStatus::TemporaryRedirect),
+            StatusCode::TEMPORARY_REDIRECT
+        );
+        assert_eq!(
+            status_of(&own, RedirectStatus::Found),
+            StatusCode::PERMANENT_REDIRECT
+        );
+        // Permanent redirects would outlive the link
+        assert_eq!(
+            status_of(&expiring, RedirectStatus::Found),
+            StatusCode::TEMPORARY_REDIRECT
+        );
+        assert_eq!(
+            status_of(&plain.with_max_clicks(5), RedirectStatus::MovedPermanently),
+            StatusCode::FOUND
+        );
+    }
+
+    #[test]
+    fn only_redirect_statuses_are_accepted() {
+        #[derive(Deserialize)]
+        struct Config {
+            redirect_status: RedirectStatus,
+        }
+        for (status, expected) in [
+            (301, RedirectStatus::MovedPermanently),
+            (302, RedirectStatus::Found),
+            (307, RedirectStatus::TemporaryRedirect),
+            (308, RedirectStatus::PermanentRedirect),
+        ] {
+            let config: Config = toml::from_str(&format!("redirect_status = {status}")).unwrap();
+            assert_eq!(config.redirect_status, expected);
+            assert_eq!(u16::from(expected), status);
+        }
+        for status in ["200", "303", "404", "-1", "\"301\""] {
+            let config = toml::from_str::<Config>(&format!("redirect_status = {status}"));
+            assert!(config.is_err(), "{status}");
+        }
+    }
+}
@@ -0,0 +1,491 @@
+//! Sizing the in-memory state to the container it runs in.
+//!
+//! At startup the memory limit of the process's cgroup and its open file limit are read, logged and
+//! turned into a [Sizing]: how many entries each in-memory map may hold, how many clicks the click
+//! streams buffer and how many connections the listener takes. A value set in the config always
+//! wins; without a detected limit (bare metal, cgroup v1) the static defaults are used. ResourceLimits only
+//! ever shrink the defaults, never grow them.
+//!
+//! After that, [watch] records memory and file descriptor use every so often and warns once each
+//! time one of them crosses its threshold.
+
+use std::{
+    fs,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{firehose, missed, preferences::Preferences, rate_limit, repeat_clicks, user};
+
+/// Rough bytes each entry takes, strings at their usual length and map overhead included
+const MISSED_ENTRY_BYTES: u64 = 160;
+const DELETED_USER_ENTRY_BYTES: u64 = 48;
+const CLICK_EVENT_BYTES: u64 = 192;
+const REPEAT_CLICK_ENTRY_BYTES: u64 = 64;
+const RATE_LIMIT_ENTRY_BYTES: u64 = 80;
+/// Smallest derived capacity, so a tiny limit leaves the features working rather than useless
+const MIN_CAPACITY: usize = 16;
+/// Open files kept back for the database pool, the log, certificates and assets before the rest
+/// is offered to connections
+const RESERVED_FILES: u64 = 64;
+/// Connections taken at once when the open file limit doesn't say otherwise
+pub const DEFAULT_MAX_CONNECTIONS: usize = 4_096;
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct ResourcePrefs {
+    /// Share of the detected memory limit the in-memory maps and buffers may take together
+    cache_memory_fraction: f64,
+    /// Codes tracked by the missed lookups report. Unset derives it from the memory limit.
+    missed_capacity: Option<usize>,
+    /// Deleted users remembered so their old tokens are refused cheaply. Unset derives it.
+    deleted_user_capacity: Option<usize>,
+    /// Clicks buffered for click streams that fall behind. Unset derives it.
+    click_stream_buffer: Option<usize>,
+    /// Visitors remembered so reloading a link isn't counted again. Unset derives it.
+    repeat_click_capacity: Option<usize>,
+    /// Addresses whose links made lately are counted against the rate limits. Unset derives it.
+    rate_limit_capacity: Option<usize>,
+    /// How often memory and file descriptor use are recorded, in seconds
+    check_interval_secs: u64,
+    /// Warn when memory use passes this share of the limit
+    memory_warn_fraction: f64,
+    /// Warn when open files pass this share of the limit
+    open_files_warn_fraction: f64,
+}
+
+impl Default for ResourcePrefs {
+    fn default() -> Self {
+        ResourcePrefs {
+            cache_memory_fraction: 0.01,
+            missed_capacity: None,
+            deleted_user_capacity: None,
+            click_stream_buffer: None,
+            repeat_click_capacity: None,
+            rate_limit_capacity: None,
+            check_interval_secs: 60,
+            memory_warn_fraction: 0.9,
+            open_files_warn_fraction: 0.8,
+        }
+    }
+}
+
+impl ResourcePrefs {
+    pub fn check_interval(&self) -> Duration {
+        Duration::from_secs(self.check_interval_secs.max(1))
+    }
+}
+
+/// Where limits and use are read from: [Procfs] when running, stubs in tests
+pub trait ResourceSource: Send + Sync {
+    /// Bytes the process may use before it is killed, None when unlimited or unknown
+    fn memory_limit(&self) -> Option<u64>;
+    /// Soft limit on open file descriptors, None when unlimited or unknown
+    fn open_files_limit(&self) -> Option<u64>;
+    /// Resident set size in bytes
+    fn memory_used(&self) -> Option<u64>;
+    fn open_files(&self) -> Option<u64>;
+}
+
+/// Reads cgroup v2 and /proc. Only the cgroup mounted at /sys/fs/cgroup is looked at, which inside
+/// a container is the container's own.
+pub struct Procfs;
+
+impl ResourceSource for Procfs {
+    fn memory_limit(&self) -> Option<u64> {
+        parse_memory_max(&fs::read_to_string("/sys/fs/cgroup/memory.max").ok()?)
+    }
+    fn open_files_limit(&self) -> Option<u64> {
+        parse_open_files_limit(&fs::read_to_string("/proc/self/limits").ok()?)
+    }
+    fn memory_used(&self) -> Option<u64> {
+        parse_vm_rss(&fs::read_to_string("/proc/self/status").ok()?)
+    }
+    fn open_files(&self) -> Option<u64> {
+        Some(fs::read_dir("/proc/self/fd").ok()?.count() as u64)
+    }
+}
+
+/// `memory.max` holds a byte count, or `max` for no limit
+fn parse_memory_max(contents: &str) -> Option<u64> {
+    contents.trim().parse().ok()
+}
+
+/// The soft limit from the `Max open files` line of /proc/self/limits
+fn parse_open_files_limit(contents: &str) -> Option<u64> {
+    let line = contents
+        .lines()
+        .find(|line| line.starts_with("Max open files"))?;
+    line.trim_start_matches("Max open files")
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// The `VmRSS:   1234 kB` line of /proc/self/status, in bytes
+fn parse_vm_rss(contents: &str) -> Option<u64> {
+    let line = contents.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// The limits found at startup
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct ResourceLimits {
+    pub memory_bytes: Option<u64>,
+    pub open_files: Option<u64>,
+}
+
+impl ResourceLimits {
+    pub fn detect(source: &dyn ResourceSource) -> Self {
+        let limits = ResourceLimits {
+            memory_bytes: source.memory_limit(),
+            open_files: source.open_files_limit(),
+        };
+        match limits.memory_bytes {
+            Some(bytes) => info!("Memory limit is {} MiB", bytes / (1024 * 1024)),
+            None => info!("No memory limit detected, using the default sizes"),
+        }
+        match limits.open_files {
+            Some(files) => info!("Open file limit is {files}"),
+            None => info!("No open file limit detected"),
+        }
+        limits
+    }
+}
+
+/// How big each piece of in-memory state may get
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sizing {
+    pub missed_capacity: usize,
+    pub deleted_user_capacity: usize,
+    pub click_stream_buffer: usize,
+    pub repeat_click_capacity: usize,
+    pub rate_limit_capacity: usize,
+    pub max_connections: usize,
+}
+
+impl Sizing {
+    /// Explicit values from `prefs` first, then what fits in `limits`, then the static defaults
+    pub fn derive(prefs: &Preferences, limits: &ResourceLimits) -> Self {
+        let resources = prefs.resources();
+        // Split evenly between the five; none of them is expected to dominate
+        let budget = limits
+            .memory_bytes
+            .map(|bytes| (bytes as f64 * resources.cache_memory_fraction / 5.0) as u64);
+        let fit = |explicit: Option<usize>, default: usize, entry_bytes: u64| {
+            explicit.unwrap_or_else(|| match budget {
+                Some(budget) => usize::try_from(budget / entry_bytes)
+                    .unwrap_or(usize::MAX)
+                    .clamp(MIN_CAPACITY, default.max(MIN_CAPACITY)),
+                None => default,
+            })
+        };
+        let max_connections =
+            prefs
+                .listener()
+                .max_connections()
+                .unwrap_or_else(|| match limits.open_files {
+                    Some(files) => {
+                        let reserved = RESERVED_FILES + u64::from(prefs.db_pool_size());
+                        usize::try_from(files.saturating_sub(reserved))
+                            .unwrap_or(usize::MAX)
+                            .clamp(1, DEFAULT_MAX_CONNECTIONS)
+                    }
+                    None => DEFAULT_MAX_CONNECTIONS,
+                });
+        Sizing {
+            missed_capacity: fit(
+                resources.missed_capacity,
+                missed::MISSED_CAPACITY,
+                MISSED_ENTRY_BYTES,
+            ),
+            deleted_user_capacity: fit(
+                resources.deleted_user_capacity,
+                user::DELETED_USER_CAPACITY,
+                DELETED_USER_ENTRY_BYTES,
+            ),
+            click_stream_buffer: fit(
+                resources.click_stream_buffer,
+                firehose::BUFFERED_EVENTS,
+                CLICK_EVENT_BYTES,
+            ),
+            repeat_click_capacity: fit(
+                resources.repeat_click_capacity,
+                repeat_clicks::REPEAT_CLICK_CAPACITY,
+                REPEAT_CLICK_ENTRY_BYTES,
+            ),
+            rate_limit_capacity: fit(
+                resources.rate_limit_capacity,
+                rate_limit::RATE_LIMIT_CAPACITY,
+                RATE_LIMIT_ENTRY_BYTES,
+            ),
+            max_connections: max_connections.max(1),
+        }
+    }
+}
+
+/// What the last check found, reported by /readyz
+#[derive(Serialize, Debug, Default)]
+pub struct ResourceUsage {
+    limits: ResourceLimits,
+    /// 0 until the first check, or when it can't be read
+    memory_bytes: AtomicU64,
+    open_files: AtomicU64,
+    #[serde(skip)]
+    memory_high: AtomicBool,
+    #[serde(skip)]
+    open_files_high: AtomicBool,
+}
+
+/// A threshold crossed since the previous check
+#[derive(Debug, PartialEq)]
+pub enum Crossing {
+    Memory { used: u64, limit: u64 },
+    OpenFiles { used: u64, limit: u64 },
+}
+
+impl ResourceUsage {
+    pub fn new(limits: ResourceLimits) -> Self {
+        ResourceUsage {
+            limits,
+            ..ResourceUsage::default()
+        }
+    }
+
+    /// Records current use and returns the thresholds crossed since the last check, each warned
+    /// about once. A threshold is warned about again only after use drops back below it.
+    pub fn check(&self, source: &dyn ResourceSource, prefs: &ResourcePrefs) -> Vec<Crossing> {
+        let mut crossed = Vec::new();
+        let memory = source.memory_used();
+        self.memory_bytes
+            .store(memory.unwrap_or_default(), Ordering::Relaxed);
+        if let (Some(used), Some(limit)) = (memory, self.limits.memory_bytes) {
+            let high = used as f64 >= limit as f64 * prefs.memory_warn_fraction;
+            let was_high = self.memory_high.swap(high, Ordering::Relaxed);
+            if high && !was_high {
+                warn!(
+                    "Memory use is {} MiB of a {} MiB limit",
+                    used / (1024 * 1024),
+                    limit / (1024 * 1024)
+                );
+                crossed.push(Crossing::Memory { used, limit });
+            }
+        }
+        let files = source.open_files();
+        self.open_files
+            .store(files.unwrap_or_default(), Ordering::Relaxed);
+        if let (Some(used), Some(limit)) = (files, self.limits.open_files) {
+            let high = used as f64 >= limit as f64 * prefs.open_files_warn_fraction;
+            let was_high = self.open_files_high.swap(high, Ordering::Relaxed);
+            if high && !was_high {
+                warn!("{used} files are open of a limit of {limit}");
+                crossed.push(Crossing::OpenFiles { used, limit });
+            }
+        }
+        crossed
+    }
+}
+
+/// Checks use every [ResourcePrefs::check_interval], for as long as the process runs
+pub async fn watch(
+    source: Arc<dyn ResourceSource>,
+    usage: Arc<ResourceUsage>,
+    prefs: ResourcePrefs,
+) {
+    let mut interval = tokio::time::interval(prefs.check_interval());
+    loop {
+        interval.tick().await;
+        usage.check(source.as_ref(), &prefs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    /// Reports whatever the test sets
+    #[derive(Default)]
+    struct Stub {
+        limits: ResourceLimits,
+        used: Mutex<(Option<u64>, Option<u64>)>,
+    }
+
+    impl ResourceSource for Stub {
+        fn memory_limit(&self) -> Option<u64> {
+            self.limits.memory_bytes
+        }
+        fn open_files_limit(&self) -> Option<u64> {
+            self.limits.open_files
+        }
+        fn memory_used(&self) -> Option<u64> {
+            self.used.lock().unwrap().0
+        }
+        fn open_files(&self) -> Option<u64> {
+            self.used.lock().unwrap().1
+        }
+    }
+
+    fn prefs(extra: &str) -> Preferences {
+        toml::from_str(&format!(
+            r#"
+            url_len = 6
+            domain_name = "localhost"
+            http_ip = "127.0.0.1"
+            port = 8080
+            db_ip = "127.0.0.1"
+            db_name = "shortener"
+            db_user = "postgres"
+            db_pass = "pw"
+            db_port = 5432
+            db_pool_size = 10
+            jwt_secret = "secret"
+            {extra}
+            "#
+        ))
+        .unwrap()
+    }
+
+    fn sizing(extra: &str, memory_bytes: Option<u64>, open_files: Option<u64>) -> Sizing {
+        let stub = Stub {
+            limits: ResourceLimits {
+                memory_bytes,
+                open_files,
+            },
+            ..Stub::default()
+        };
+        Sizing::derive(&prefs(extra), &ResourceLimits::detect(&stub))
+    }
+
+    #[test]
+    fn no_limits_means_static_defaults() {
+        assert_eq!(
+            sizing("", None, None),
+            Sizing {
+                missed_capacity: missed::MISSED_CAPACITY,
+                deleted_user_capacity: user::DELETED_USER_CAPACITY,
+                click_stream_buffer: firehose::BUFFERED_EVENTS,
+                repeat_click_capacity: repeat_clicks::REPEAT_CLICK_CAPACITY,
+                rate_limit_capacity: rate_limit::RATE_LIMIT_CAPACITY,
+                max_connections: DEFAULT_MAX_CONNECTIONS,
+            }
+        );
+        // A generous limit doesn't grow anything past the defaults
+        assert_eq!(
+            sizing("", Some(64 * 1024 * MIB), Some(1 << 20)),
+            sizing("", None, None)
+        );
+    }
+
+    #[test]
+    fn tight_limits_shrink_the_defaults() {
+        // 1% of 16 MiB split five ways is about 33 KiB each
+        let small = sizing("", Some(16 * MIB), Some(1024));
+        assert_eq!(small.missed_capacity, 209);
+        assert_eq!(small.deleted_user_capacity, 699);
+        assert_eq!(small.click_stream_buffer, 174);
+        assert_eq!(small.repeat_click_capacity, 524);
+        assert_eq!(small.rate_limit_capacity, 419);
+        // Less the database pool and the reserve
+        assert_eq!(small.max_connections, 1024 - 64 - 10);
+
+        let tiny = sizing("", Some(MIB / 4), Some(16));
+        assert_eq!(tiny.missed_capacity, MIN_CAPACITY);
+        assert_eq!(tiny.click_stream_buffer, MIN_CAPACITY);
+        assert_eq!(tiny.max_connections, 1);
+
+        let generous = sizing(
+            "[resources]\ncache_memory_fraction = 0.1",
+            Some(16 * MIB),
+            None,
+        );
+        assert_eq!(generous.missed_capacity, missed::MISSED_CAPACITY);
+    }
+
+    #[test]
+    fn explicit_values_win() {
+        let config = "[resources]\nmissed_capacity = 5000\nclick_stream_buffer = 8\n\
+            [listener]\nmax_connections = 10000";
+        for (memory, files) in [(None, None), (Some(MIB), Some(16))] {
+            let sized = sizing(config, memory, files);
+            assert_eq!(sized.missed_capacity, 5000);
+            assert_eq!(sized.click_stream_buffer, 8);
+            assert_eq!(sized.max_connections, 10000);
+        }
+    }
+
+    #[test]
+    fn warns_once_per_crossing() {
+        let stub = Stub {
+            limits: ResourceLimits {
+                memory_bytes: Some(100 * MIB),
+                open_files: Some(100),
+            },
+            ..Stub::default()
+        };
+        let usage = ResourceUsage::new(ResourceLimits::detect(&stub));
+        let prefs = ResourcePrefs::default();
+        let check = |memory_mib: u64, files: u64| {
+            *stub.used.lock().unwrap() = (Some(memory_mib * MIB), Some(files));
+            usage.check(&stub, &prefs)
+        };
+
+        assert_eq!(check(50, 10), []);
+        assert_eq!(
+            check(95, 10),
+            [Crossing::Memory {
+                used: 95 * MIB,
+                limit: 100 * MIB
+            }]
+        );
+        // Still high, already warned
+        assert_eq!(
+            check(96, 85),
+            [Crossing::OpenFiles {
+                used: 85,
+                limit: 100
+            }]
+        );
+        assert_eq!(check(97, 90), []);
+        // Back under, then over again
+        assert_eq!(check(40, 90), []);
+        assert_eq!(check(91, 90).len(), 1);
+        assert_eq!(usage.memory_bytes.load(Ordering::Relaxed), 91 * MIB);
+        assert_eq!(usage.open_files.load(Ordering::Relaxed), 90);
+
+        // Nothing to compare against without limits, but use is still recorded
+        let unlimited = ResourceUsage::new(ResourceLimits::default());
+        assert_eq!(unlimited.check(&stub, &prefs), []);
+        assert_eq!(unlimited.open_files.load(Ordering::Relaxed), 90);
+    }
+
+    #[test]
+    fn reads_proc_formats() {
+        assert_eq!(parse_memory_max("536870912\n"), Some(512 * MIB));
+        assert_eq!(parse_memory_max("max\n"), None);
+        let limits = "Limit                     Soft Limit           Hard Limit           Units\n\
+            Max processes             63704                63704                processes\n\
+            Max open files            1024                 524288               files\n";
+        assert_eq!(parse_open_files_limit(limits), Some(1024));
+        assert_eq!(
+            parse_open_files_limit(
+                "Max open files            unlimited            unlimited            files"
+            ),
+            None
+        );
+        let status = "Name:\turl_shortner\nVmPeak:\t  20000 kB\nVmRSS:\t   12345 kB\n";
+        assert_eq!(parse_vm_rss(status), Some(12345 * 1024));
+        assert_eq!(parse_vm_rss("Name:\tx\n"), None);
+    }
+}
@@ -0,0 +1,81 @@
+use std::time::Duration;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tracing::{info, warn};
+
+use crate::url_db;
+
+/// How often rollouts that are due are finished
+const FINISH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long a link rolls a new destination out to part of its visitors, see
+/// [url_db::start_rollout], before everyone is sent there
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct RolloutPrefs {
+    /// Rollouts not committed by then are finished this many days after they started. At least 1.
+    finish_after_days: u32,
+}
+
+impl Default for RolloutPrefs {
+    fn default() -> Self {
+        RolloutPrefs {
+            finish_after_days: 7,
+        }
+    }
+}
+
+impl RolloutPrefs {
+    /// When a rollout started at `now` is finished
+    pub fn deadline(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        now + TimeDelta::days(i64::from(self.finish_after_days.max(1)))
+    }
+}
+
+/// Finishes the rollouts that are due by `now`, each in a transaction of its own. Returns how many
+/// were.
+pub async fn finish_due(now: DateTime<Utc>, pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let mut finished = 0;
+    for id in url_db::due_rollouts(now, pool).await? {
+        let mut tx = pool.begin().await?;
+        match url_db::finish_rollout(id, &mut tx).await {
+            Ok(_) => finished += 1,
+            // Committed since it was looked up
+            Err(sqlx::Error::RowNotFound) => continue,
+            Err(e) => return Err(e),
+        }
+        tx.commit().await?;
+    }
+    Ok(finished)
+}
+
+/// Runs [finish_due] every [FINISH_INTERVAL]
+pub async fn finish(pool: PgPool) {
+    let mut interval = tokio::time::interval(FINISH_INTERVAL);
+    loop {
+        interval.tick().await;
+        match finish_due(Utc::now(), &pool).await {
+            Ok(0) => {}
+            Ok(finished) => info!("Finished {finished} rollouts that were due"),
+            Err(e) => warn!("Couldn't finish the rollouts that were due: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rollouts_last_at_least_a_day() {
+        let now = Utc::now();
+        assert_eq!(
+            RolloutPrefs::default().deadline(now),
+            now + TimeDelta::days(7)
+        );
+        let prefs: RolloutPrefs = toml::from_str("finish_after_days = 0").unwrap();
+        assert_eq!(prefs.deadline(now), now + TimeDelta::days(1));
+    }
+}
@@ -0,0 +1,328 @@
+use std::fmt::Display;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
+use sqlx::{PgExecutor, PgPool};
+use tracing::{error, info, warn};
+
+use crate::dry_run::Executor;
+
+/// Which links a rebuild recomputes the daily clicks of
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scope {
+    Link(i64),
+    /// Every link of a user
+    User(i64),
+    All,
+}
+
+impl Scope {
+    fn url_id(&self) -> Option<i64> {
+        match self {
+            Scope::Link(id) => Some(*id),
+            _ => None,
+        }
+    }
+    fn user_id(&self) -> Option<i64> {
+        match self {
+            Scope::User(id) => Some(*id),
+            _ => None,
+        }
+    }
+}
+
+/// A rebuild of the daily clicks of some links, from `from_day` to `to_day`. Days from `next_day`
+/// on haven't been rebuilt yet.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Rebuild {
+    pub id: i64,
+    pub url_id: Option<i64>,
+    pub user_id: Option<i64>,
+    pub from_day: NaiveDate,
+    pub to_day: NaiveDate,
+    pub next_day: NaiveDate,
+    pub started_by: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+pub enum StartError {
+    /// `to` is before `from`
+    Backwards,
+    /// Some of the days are being rebuilt already, by the rebuild with this id
+    Overlaps(i64),
+    /// Not every click of some of the days is still recorded, so rebuilding them would lose clicks.
+    /// None when no clicks are recorded at all.
+    NotCovered(Option<(NaiveDate, NaiveDate)>),
+    Database(sqlx::Error),
+}
+
+impl Display for StartError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StartError::Backwards => f.write_str("The last day to rebuild is before the first"),
+            StartError::Overlaps(id) => write!(f, "Rebuild {id} is rebuilding some of those days"),
+            StartError::NotCovered(Some((first, last))) => write!(
+                f,
+                "Only the days from {first} to {last} can be rebuilt, earlier clicks aren't recorded anymore"
+            ),
+            StartError::NotCovered(None) => f.write_str("No clicks are recorded to rebuild from"),
+            StartError::Database(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<sqlx::Error> for StartError {
+    fn from(e: sqlx::Error) -> Self {
+        StartError::Database(e)
+    }
+}
+
+/// A day of a link whose daily clicks don't match the clicks recorded that day, so a rebuild
+/// changes it
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DayChange {
+    pub url_id: i64,
+    pub day: NaiveDate,
+    /// The daily clicks now
+    pub recorded: i64,
+    /// The daily clicks once rebuilt
+    pub rebuilt: i64,
+}
+
+/// A rebuild that was started, or only checked on a dry run
+#[derive(Debug)]
+pub struct Started {
+    /// None on a dry run
+    pub rebuild: Option<Rebuild>,
+    /// What the rebuild changes, as things stand
+    pub changes: Vec<DayChange>,
+}
+
+/// Records a rebuild of the daily clicks of `scope` from `from` to `to`, both included, through
+/// `executor`. Start it with [run]. The clicks of a day are only all in url_click_visitors from the
+/// day after the oldest one still there, as earlier ones were pruned or made before conversion
+/// tracking was on, up to today, so only those days can be rebuilt. Rebuilds of overlapping days,
+/// whatever links they cover, can't run at once; the check and the insert are serialized by an
+/// advisory lock.
+pub async fn start(
+    scope: Scope,
+    from: NaiveDate,
+    to: NaiveDate,
+    started_by: &str,
+    executor: &dyn Executor,
+    pool: &PgPool,
+) -> Result<Started, StartError> {
+    if to < from {
+        return Err(StartError::Backwards);
+    }
+    let mut tx = pool.begin().await?;
+    sqlx::query!("SELECT pg_advisory_xact_lock(hashtext('rollup_rebuilds'))")
+        .execute(&mut *tx)
+        .await?;
+    let covered = sqlx::query!(
+        r#"SELECT ((min(clicked_at) AT TIME ZONE 'UTC')::date + 1) AS first,
+            (now() AT TIME ZONE 'UTC')::date AS "last!"
+        FROM url_click_visitors"#
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    match covered.first {
+        Some(first) if first <= from && to <= covered.last => {}
+        Some(first) => return Err(StartError::NotCovered(Some((first, covered.last)))),
+        None => return Err(StartError::NotCovered(None)),
+    }
+    let overlapping = sqlx::query_scalar!(
+        "SELECT id FROM rollup_rebuilds
+        WHERE finished_at IS NULL AND from_day <= $2 AND to_day >= $1
+        ORDER BY id LIMIT 1",
+        from,
+        to,
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    if let Some(id) = overlapping {
+        return Err(StartError::Overlaps(id));
+    }
+    let changes = changes(scope, from, to, &mut *tx).await?;
+    let mut rebuild = None;
+    executor
+        .act(Box::pin(async {
+            rebuild = Some(
+                sqlx::query_as!(
+                    Rebuild,
+                    "INSERT INTO rollup_rebuilds (url_id, user_id, from_day, to_day, next_day,
+                        started_by)
+                    VALUES ($1, $2, $3, $4, $3, $5)
+                    RETURNING id, url_id, user_id, from_day, to_day, next_day, started_by,
+                        started_at, finished_at",
+                    scope.url_id(),
+                    scope.user_id(),
+                    from,
+                    to,
+                    started_by,
+                )
+                .fetch_one(&mut *tx)
+                .await?,
+            );
+            Ok(1)
+        }))
+        .await?;
+    tx.commit().await?;
+    Ok(Started { rebuild, changes })
+}
+
+/// The days of `scope` from `from` to `to` whose daily clicks differ from the clicks recorded in
+/// url_click_visitors, which is what [next_day] rebuilds them from
+pub async fn changes(
+    scope: Scope,
+    from: NaiveDate,
+    to: NaiveDate,
+    db: impl PgExecutor<'_>,
+) -> Result<Vec<DayChange>, sqlx::Error> {
+    sqlx::query_as!(
+        DayChange,
+        r#"WITH recorded AS (
+            SELECT url_id, day, count FROM url_clicks_daily
+            WHERE day BETWEEN $1 AND $2
+                AND ($3::bigint IS NULL OR url_id = $3)
+                AND ($4::bigint IS NULL OR url_id IN (SELECT id FROM urls WHERE created_by = $4))
+        ), rebuilt AS (
+            SELECT url_id, (clicked_at AT TIME ZONE 'UTC')::date AS day, count(*) AS count
+            FROM url_click_visitors
+            WHERE clicked_at >= $1::date::timestamp AT TIME ZONE 'UTC'
+                AND clicked_at < ($2::date + 1)::timestamp AT TIME ZONE 'UTC'
+                AND ($3::bigint IS NULL OR url_id = $3)
+                AND ($4::bigint IS NULL OR url_id IN (SELECT id FROM urls WHERE created_by = $4))
+            GROUP BY 1, 2
+        )
+        SELECT url_id AS "url_id!", day AS "day!", coalesce(recorded.count, 0) AS "recorded!",
+            coalesce(rebuilt.count, 0) AS "rebuilt!"
+        FROM recorded FULL JOIN rebuilt USING (url_id, day)
+        WHERE recorded.count IS DISTINCT FROM rebuilt.count
+        ORDER BY url_id, day"#,
+        from,
+        to,
+        scope.url_id(),
+        scope.user_id(),
+    )
+    .fetch_all(db)
+    .await
+}
+
+pub async fn rebuild(id: i64, db: impl PgExecutor<'_>) -> Result<Option<Rebuild>, sqlx::Error> {
+    sqlx::query_as!(
+        Rebuild,
+        "SELECT id, url_id, user_id, from_day, to_day, next_day, started_by, started_at,
+            finished_at
+        FROM rollup_rebuilds WHERE id = $1",
+        id,
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// How a day left the rebuild
+#[derive(Debug, PartialEq)]
+pub enum Progress {
+    More,
+    Done,
+}
+
+/// Rebuilds the next day of rebuild `id` in one transaction with its progress, so a day is either
+/// rebuilt and recorded as such or not at all. The rebuild's row is locked meanwhile, so two
+/// servers resuming it take turns rather than rebuilding a day twice.
+pub async fn next_day(id: i64, pool: &PgPool) -> Result<Progress, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let Some(rebuild) = sqlx::query!(
+        "SELECT url_id, user_id, to_day, next_day, finished_at
+        FROM rollup_rebuilds WHERE id = $1 FOR UPDATE",
+        id,
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Ok(Progress::Done);
+    };
+    if rebuild.finished_at.is_some() || rebuild.next_day > rebuild.to_day {
+        return Ok(Progress::Done);
+    }
+    let day = rebuild.next_day;
+    sqlx::query!(
+        "DELETE FROM url_clicks_daily WHERE day = $1
+            AND ($2::bigint IS NULL OR url_id = $2)
+            AND ($3::bigint IS NULL OR url_id IN (SELECT id FROM urls WHERE created_by = $3))",
+        day,
+        rebuild.url_id,
+        rebuild.user_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO url_clicks_daily (url_id, day, count)
+        SELECT url_id, $1, count(*) FROM url_click_visitors
+        WHERE clicked_at >= $1::date::timestamp AT TIME ZONE 'UTC'
+            AND clicked_at < ($1::date + 1)::timestamp AT TIME ZONE 'UTC'
+            AND ($2::bigint IS NULL OR url_id = $2)
+            AND ($3::bigint IS NULL OR url_id IN (SELECT id FROM urls WHERE created_by = $3))
+        GROUP BY url_id",
+        day,
+        rebuild.url_id,
+        rebuild.user_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+    let finished = day >= rebuild.to_day;
+    sqlx::query!(
+        "UPDATE rollup_rebuilds SET next_day = next_day + 1,
+            finished_at = CASE WHEN $2 THEN now() END
+        WHERE id = $1",
+        id,
+        finished,
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(if finished {
+        Progress::Done
+    } else {
+        Progress::More
+    })
+}
+
+/// Rebuilds the days of rebuild `id` that are left, one at a time
+pub async fn run(id: i64, pool: PgPool) {
+    loop {
+        match next_day(id, &pool).await {
+            Ok(Progress::More) => {}
+            Ok(Progress::Done) => {
+                info!("Finished rollup rebuild {id}");
+                return;
+            }
+            Err(e) => {
+                // Left unfinished, to be resumed by the next start
+                error!("Rollup rebuild {id} stopped: {e}");
+                return;
+            }
+        }
+    }
+}
+
+/// Goes on with the rebuilds that were running when the server last stopped, from the day they
+/// were on
+pub async fn resume(pool: PgPool) {
+    let unfinished =
+        sqlx::query_scalar!("SELECT id FROM rollup_rebuilds WHERE finished_at IS NULL ORDER BY id")
+            .fetch_all(&pool)
+            .await;
+    match unfinished {
+        Ok(ids) => {
+            for id in ids {
+                info!("Resuming rollup rebuild {id}");
+                run(id, pool.clone()).await;
+            }
+        }
+        Err(e) => warn!("Couldn't look for unfinished rollup rebuilds: {e}"),
+    }
+}
@@ -0,0 +1,276 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::http::StatusCode;
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::warn;
+
+use crate::api_error::ApiError;
+
+/// The Safe Browsing v4 lookup endpoint, https://developers.google.com/safe-browsing/v4/lookup-api
+const SAFE_BROWSING_ENDPOINT: &str = "https://safebrowsing.googleapis.com/v4/threatMatches:find";
+/// How long a lookup gets before the service counts as unavailable
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What to do with a destination when the checker can't say whether it is safe
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    /// Shorten it anyway, so an outage of the service doesn't stop links being made
+    #[default]
+    Open,
+    /// Refuse it until the service answers
+    Closed,
+}
+
+/// Checking destinations for phishing and malware before they are shortened. Off unless an API
+/// key is set.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Default)]
+#[serde(default)]
+pub struct SafetyPrefs {
+    /// Google Safe Browsing API key
+    google_api_key: Option<String>,
+    on_failure: FailurePolicy,
+}
+
+/// What a checker found out about a destination
+#[derive(Debug, Clone, PartialEq)]
+pub enum SafetyVerdict {
+    Safe,
+    /// Listed as a threat, e.g. `MALWARE` or `SOCIAL_ENGINEERING`
+    Unsafe(String),
+    /// The checker couldn't tell, with why
+    Unavailable(String),
+}
+
+/// Something that can tell whether a destination is known to be malicious
+pub trait UrlSafetyChecker: Send + Sync {
+    fn check<'a>(&'a self, url: &'a str) -> BoxFuture<'a, SafetyVerdict>;
+}
+
+/// The checker used when none is configured: everything is safe
+pub struct NoCheck;
+
+impl UrlSafetyChecker for NoCheck {
+    fn check<'a>(&'a self, _url: &'a str) -> BoxFuture<'a, SafetyVerdict> {
+        Box::pin(async { SafetyVerdict::Safe })
+    }
+}
+
+/// Looks destinations up with the Google Safe Browsing v4 API
+pub struct SafeBrowsing {
+    client: reqwest::Client,
+    endpoint: String,
+    api_key: String,
+}
+
+impl SafeBrowsing {
+    pub fn new(api_key: &str) -> Self {
+        SafeBrowsing {
+            client: reqwest::Client::builder()
+                .timeout(LOOKUP_TIMEOUT)
+                .build()
+                .expect("The HTTP client's TLS backend is available"),
+            endpoint: String::from(SAFE_BROWSING_ENDPOINT),
+            api_key: api_key.to_string(),
+        }
+    }
+    #[cfg(test)]
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.to_string();
+        self
+    }
+}
+
+#[derive(Deserialize)]
+struct ThreatMatches {
+    #[serde(default)]
+    matches: Vec<ThreatMatch>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ThreatMatch {
+    threat_type: String,
+}
+
+/// The body of a lookup for `url`
+fn lookup_body(url: &str) -> serde_json::Value {
+    json!({
+        "client": {
+            "clientId": env!("CARGO_PKG_NAME"),
+            "clientVersion": env!("CARGO_PKG_VERSION"),
+        },
+        "threatInfo": {
+            "threatTypes": [
+                "MALWARE",
+                "SOCIAL_ENGINEERING",
+                "UNWANTED_SOFTWARE",
+                "POTENTIALLY_HARMFUL_APPLICATION",
+            ],
+            "platformTypes": ["ANY_PLATFORM"],
+            "threatEntryTypes": ["URL"],
+            "threatEntries": [{ "url": url }],
+        },
+    })
+}
+
+impl ThreatMatches {
+    /// No matches, the empty object the API answers with, means the url isn't listed
+    fn verdict(self) -> SafetyVerdict {
+        match self.matches.into_iter().next() {
+            Some(found) => SafetyVerdict::Unsafe(found.threat_type),
+            None => SafetyVerdict::Safe,
+        }
+    }
+}
+
+impl UrlSafetyChecker for SafeBrowsing {
+    fn check<'a>(&'a self, url: &'a str) -> BoxFuture<'a, SafetyVerdict> {
+        Box::pin(async move {
+            let sent = self
+                .client
+                .post(&self.endpoint)
+                .query(&[("key", &self.api_key)])
+                .json(&lookup_body(url))
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status);
+            let matches = match sent {
+                Ok(resp) => resp.json::<ThreatMatches>().await,
+                Err(e) => Err(e),
+            };
+            match matches {
+                Ok(matches) => matches.verdict(),
+                // The key is in the query string, so it's left out of what gets logged
+                Err(e) => SafetyVerdict::Unavailable(e.without_url().to_string()),
+            }
+        })
+    }
+}
+
+/// The configured checker and what to do when it fails
+pub struct SafetyCheck {
+    checker: Arc<dyn UrlSafetyChecker>,
+    on_failure: FailurePolicy,
+}
+
+impl SafetyCheck {
+    pub fn from_prefs(prefs: &SafetyPrefs) -> Self {
+        let checker: Arc<dyn UrlSafetyChecker> = match &prefs.google_api_key {
+            Some(key) => Arc::new(SafeBrowsing::new(key)),
+            None => Arc::new(NoCheck),
+        };
+        SafetyCheck {
+            checker,
+            on_failure: prefs.on_failure,
+        }
+    }
+    #[cfg(test)]
+    pub fn with(checker: impl UrlSafetyChecker + 'static, on_failure: FailurePolicy) -> Self {
+        SafetyCheck {
+            checker: Arc::new(checker),
+            on_failure,
+        }
+    }
+
+    /// Why `url` can't be shortened according to the checker, if it can't
+    pub async fn problem(&self, url: &str) -> Option<ApiError> {
+        match self.checker.check(url).await {
+            SafetyVerdict::Safe => None,
+            SafetyVerdict::Unsafe(threat) => {
+                warn!("Refused to shorten {url}, listed as {threat}");
+                Some(ApiError::new(
+                    StatusCode::FORBIDDEN,
+                    "unsafe_url",
+                    format!("{url} is listed as unsafe ({threat})"),
+                ))
+            }
+            SafetyVerdict::Unavailable(reason) => {
+                warn!("Couldn't check whether {url} is safe: {reason}");
+                match self.on_failure {
+                    FailurePolicy::Open => None,
+                    FailurePolicy::Closed => Some(ApiError::unavailable(
+                        "Couldn't check that the link is safe, try again later",
+                    )),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{response::IntoResponse, routing::post, Json, Router};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// A stand-in for the Safe Browsing API that lists every url containing "phish"
+    async fn fake_service() -> String {
+        let app = Router::new().route(
+            "/lookup",
+            post(|Json(body): Json<serde_json::Value>| async move {
+                let url = body["threatInfo"]["threatEntries"][0]["url"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string();
+                if url.contains("phish") {
+                    Json(json!({ "matches": [{
+                        "threatType": "SOCIAL_ENGINEERING",
+                        "platformType": "ANY_PLATFORM",
+                        "threat": { "url": url },
+                    }]}))
+                } else {
+                    Json(json!({}))
+                }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{addr}/lookup")
+    }
+
+    #[tokio::test]
+    async fn safe_browsing_reports_matches() {
+        let endpoint = fake_service().await;
+        let checker = SafeBrowsing::new("key").with_endpoint(&endpoint);
+        assert_eq!(
+            checker.check("https://example.com").await,
+            SafetyVerdict::Safe
+        );
+        assert_eq!(
+            checker.check("https://phish.example.com").await,
+            SafetyVerdict::Unsafe(String::from("SOCIAL_ENGINEERING"))
+        );
+        let down = SafeBrowsing::new("key").with_endpoint(&endpoint.replace("/lookup", "/gone"));
+        assert!(matches!(
+            down.check("https://example.com").await,
+            SafetyVerdict::Unavailable(_)
+        ));
+    }
+
+    struct Down;
+
+    impl UrlSafetyChecker for Down {
+        fn check<'a>(&'a self, _url: &'a str) -> BoxFuture<'a, SafetyVerdict> {
+            Box::pin(async { SafetyVerdict::Unavailable(String::from("timed out")) })
+        }
+    }
+
+    #[tokio::test]
+    async fn failures_follow_the_policy() {
+        let open = SafetyCheck::with(Down, FailurePolicy::Open);
+        assert!(open.problem("https://example.com").await.is_none());
+        let closed = SafetyCheck::with(Down, FailurePolicy::Closed);
+        let problem = closed.problem("https://example.com").await.unwrap();
+        assert_eq!(
+            problem.into_response().status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        let unconfigured = SafetyCheck::from_prefs(&SafetyPrefs::default());
+        assert!(unconfigured.problem("https://example.com").await.is_none());
+    }
+}
@@ -0,0 +1,299 @@
+use std::{
+    fmt::{self, Display},
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use reqwest::{header, redirect, Client, Method, RequestBuilder, Response, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::{user::cookie::AUTH_COOKIE_NAME, ApiNewUrl};
+
+/// Longest a smoke run waits for any one request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Where the link a smoke run makes points, followed by a marker unique to the run. The run only
+/// reads the redirect, so it is never visited. It is on a domain kept for examples rather than the
+/// instance's own, as links to the instance are refused unless
+/// [crate::preferences::Preferences::allow_self_links] is set.
+const MARKER_BASE: &str = "https://example.com/url-shortner-smoke/";
+
+/// How one step of a smoke run went
+#[derive(Debug)]
+pub struct Step {
+    name: &'static str,
+    /// What was seen when it passed, what went wrong when it didn't
+    outcome: Result<String, String>,
+    took: Duration,
+}
+
+impl Step {
+    pub fn passed(&self) -> bool {
+        self.outcome.is_ok()
+    }
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (verdict, detail) = match &self.outcome {
+            Ok(detail) => ("PASS", detail),
+            Err(problem) => ("FAIL", problem),
+        };
+        write!(
+            f,
+            "{verdict} {:<9} {:>6} ms  {detail}",
+            self.name,
+            self.took.as_millis()
+        )
+    }
+}
+
+/// The parts of a link the run needs from what creating it answers
+#[derive(Deserialize)]
+struct Link {
+    short_url: String,
+    long_url: String,
+}
+
+#[derive(Deserialize)]
+struct Bulk {
+    results: Vec<BulkItem>,
+}
+
+#[derive(Deserialize)]
+struct BulkItem {
+    link: Option<Link>,
+    error: Option<ErrorBody>,
+}
+
+#[derive(Deserialize)]
+struct Stats {
+    clicks: i64,
+    bot_clicks: i64,
+}
+
+/// See [crate::api_error::ApiError]
+#[derive(Deserialize)]
+struct ErrorEnvelope {
+    error: ErrorBody,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    code: String,
+    message: String,
+}
+
+/// Checks an instance at `base_url` end to end over HTTP, as the user whose session `token` is: it
+/// makes a link, follows it without leaving the instance, checks the visit was counted, asks for
+/// a code that doesn't exist and deletes the link again. The link is deleted whichever steps
+/// failed, purged for admins and archived for everyone else.
+pub async fn run(base_url: &str, token: &str) -> Vec<Step> {
+    let mut steps = Vec::new();
+    let api = match Api::new(base_url, token) {
+        Ok(api) => api,
+        Err(problem) => {
+            steps.push(Step {
+                name: "setup",
+                outcome: Err(problem),
+                took: Duration::ZERO,
+            });
+            return steps;
+        }
+    };
+    let marker = format!("{:016x}", rand::random::<u64>());
+    timed(&mut steps, "healthz", api.expect_ok("/healthz")).await;
+    timed(&mut steps, "readyz", api.expect_ok("/readyz")).await;
+    let link = timed(&mut steps, "create", api.create(&marker)).await;
+    let missing = format!("/smoke-missing-{marker}");
+    timed(&mut steps, "not found", api.expect_missing(&missing)).await;
+    let Some(link) = link else {
+        for name in ["resolve", "stats", "delete"] {
+            steps.push(Step {
+                name,
+                outcome: Err(String::from("skipped, no link was made")),
+                took: Duration::ZERO,
+            });
+        }
+        return steps;
+    };
+    timed(&mut steps, "resolve", api.resolve(&link)).await;
+    timed(&mut steps, "stats", api.stats(&link)).await;
+    timed(&mut steps, "delete", api.delete(&link)).await;
+    steps
+}
+
+/// Runs `check`, recording how it went and how long it took as the step `name`
+async fn timed<T>(
+    steps: &mut Vec<Step>,
+    name: &'static str,
+    check: impl Future<Output = Result<(T, String), String>>,
+) -> Option<T> {
+    let started = Instant::now();
+    let checked = check.await;
+    let took = started.elapsed();
+    let (value, outcome) = match checked {
+        Ok((value, detail)) => (Some(value), Ok(detail)),
+        Err(problem) => (None, Err(problem)),
+    };
+    steps.push(Step {
+        name,
+        outcome,
+        took,
+    });
+    value
+}
+
+struct Api {
+    client: Client,
+    base_url: String,
+    cookie: String,
+}
+
+impl Api {
+    fn new(base_url: &str, token: &str) -> Result<Self, String> {
+        let client = Client::builder()
+            .redirect(redirect::Policy::none())
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(|e| format!("couldn't make an HTTP client: {e}"))?;
+        Ok(Api {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            cookie: format!("{AUTH_COOKIE_NAME}={token}"),
+        })
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.client
+            .request(method, format!("{}{path}", self.base_url))
+            .header(header::COOKIE, &self.cookie)
+    }
+
+    async fn send(request: RequestBuilder) -> Result<Response, String> {
+        request
+            .send()
+            .await
+            .map_err(|e| format!("request failed: {e}"))
+    }
+
+    async fn expect_ok(&self, path: &str) -> Result<((), String), String> {
+        let resp = Self::send(self.request(Method::GET, path)).await?;
+        match resp.status() {
+            StatusCode::OK => Ok(((), String::from("200"))),
+            _ => Err(unexpected(resp).await),
+        }
+    }
+
+    async fn expect_missing(&self, path: &str) -> Result<((), String), String> {
+        let resp = Self::send(self.request(Method::GET, path)).await?;
+        match resp.status() {
+            StatusCode::NOT_FOUND => Ok(((), String::from("404"))),
+            _ => Err(unexpected(resp).await),
+        }
+    }
+
+    /// Makes the run's link, owned by the signed in user so it can be read and deleted
+    async fn create(&self, marker: &str) -> Result<(Link, String), String> {
+        let new_url = ApiNewUrl {
+            url: Some(format!("{MARKER_BASE}{marker}")),
+            alias: None,
+            tags: None,
+            description: Some(String::from("Made by a smoke test, which deletes it")),
+            destinations: Vec::new(),
+            active_from: None,
+            single_use: false,
+        };
+        let request = self
+            .request(Method::POST, "/api/urls/bulk")
+            .json(&[new_url]);
+        let resp = Self::send(request).await?;
+        if resp.status() != StatusCode::OK {
+            return Err(unexpected(resp).await);
+        }
+        let mut bulk: Bulk = parsed(resp).await?;
+        match bulk.results.pop() {
+            Some(BulkItem {
+                link: Some(link), ..
+            }) => {
+                let made = format!("made {}", link.short_url);
+                Ok((link, made))
+            }
+            Some(BulkItem {
+                error: Some(error), ..
+            }) => Err(format!("{}: {}", error.code, error.message)),
+            _ => Err(String::from("no link was made")),
+        }
+    }
+
+    async fn resolve(&self, link: &Link) -> Result<((), String), String> {
+        let path = format!("/{}", link.short_url);
+        let resp = Self::send(self.request(Method::GET, &path)).await?;
+        if !resp.status().is_redirection() {
+            return Err(unexpected(resp).await);
+        }
+        let location = resp
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|location| location.to_str().ok());
+        match location {
+            Some(location) if location == link.long_url => {
+                Ok(((), format!("{} to {location}", resp.status().as_u16())))
+            }
+            location => Err(format!(
+                "expected a redirect to {}, got one to {}",
+                link.long_url,
+                location.unwrap_or("nowhere")
+            )),
+        }
+    }
+
+    /// The visit made by [resolve](Self::resolve) is the only one the link has had. It counts
+    /// whether or not the instance takes the run for a bot.
+    async fn stats(&self, link: &Link) -> Result<((), String), String> {
+        let path = format!("/api/urls/{}/stats", link.short_url);
+        let resp = Self::send(self.request(Method::GET, &path)).await?;
+        if resp.status() != StatusCode::OK {
+            return Err(unexpected(resp).await);
+        }
+        let stats: Stats = parsed(resp).await?;
+        match stats.clicks + stats.bot_clicks {
+            1 => Ok(((), String::from("1 visit counted"))),
+            counted => Err(format!("expected 1 visit counted, got {counted}")),
+        }
+    }
+
+    async fn delete(&self, link: &Link) -> Result<((), String), String> {
+        let path = format!("/api/urls/{}", link.short_url);
+        let purge = self.request(Method::DELETE, &format!("{path}?purge=true"));
+        let resp = Self::send(purge).await?;
+        let (resp, done) = match resp.status() {
+            // Only admins can purge
+            StatusCode::FORBIDDEN => (
+                Self::send(self.request(Method::DELETE, &path)).await?,
+                "archived",
+            ),
+            _ => (resp, "purged"),
+        };
+        match resp.status() {
+            StatusCode::NO_CONTENT => Ok(((), format!("{done} {}", link.short_url))),
+            _ => Err(unexpected(resp).await),
+        }
+    }
+}
+
+async fn parsed<T: DeserializeOwned>(resp: Response) -> Result<T, String> {
+    resp.json()
+        .await
+        .map_err(|e| format!("couldn't read the response: {e}"))
+}
+
+/// What to say about a response with a status the step didn't expect
+async fn unexpected(resp: Response) -> String {
+    let status = resp.status();
+    let body = resp.text().await.unwrap_or_default();
+    match serde_json::from_str::<ErrorEnvelope>(&body) {
+        Ok(ErrorEnvelope { error }) => format!("{status}, {}: {}", error.code, error.message),
+        Err(_) if body.is_empty() => format!("{status}"),
+        Err(_) => format!("{status}, {}", body.chars().take(200).collect::<String>()),
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+
+use sqlx::{PgConnection, PgExecutor};
+
+/// Longest tag, in characters
+pub const MAX_TAG_LEN: usize = 32;
+/// Most tags one link can have
+pub const MAX_TAGS: usize = 10;
+
+/// The tags of a comma separated list as they are stored: trimmed, lowercased and each once, in
+/// the order given. Empty entries are dropped, so `""` is no tags. Errors are meant for the person
+/// who typed the list.
+pub fn parse(list: &str) -> Result<Vec<String>, String> {
+    let mut tags: Vec<String> = Vec::new();
+    for tag in list.split(',').map(str::trim).filter(|tag| !tag.is_empty()) {
+        let tag = tag.to_lowercase();
+        if tag.chars().count() > MAX_TAG_LEN {
+            return Err(format!("Tags can be at most {MAX_TAG_LEN} characters"));
+        }
+        if !tag
+            .chars()
+            .all(|letter| letter.is_alphanumeric() || letter == '-' || letter == '_')
+        {
+            return Err(String::from(
+                "Tags can only contain letters, digits, dashes and underscores",
+            ));
+        }
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    if tags.len() > MAX_TAGS {
+        return Err(format!("A link can have at most {MAX_TAGS} tags"));
+    }
+    Ok(tags)
+}
+
+/// Replaces the tags of a link with `tags`, which should come from [parse]. Run it in the
+/// transaction making the link, so it can't be left with only some of them.
+pub async fn set(url_id: i64, tags: &[String], conn: &mut PgConnection) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM url_tags WHERE url_id = $1", url_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query!(
+        "INSERT INTO url_tags (url_id, tag) SELECT $1, UNNEST($2::text[])",
+        url_id,
+        tags
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// The tags of each of `url_ids` that has any, alphabetically
+pub async fn for_links(
+    url_ids: &[i64],
+    db: impl PgExecutor<'_>,
+) -> Result<HashMap<i64, Vec<String>>, sqlx::Error> {
+    let rows = sqlx::query!(
+        "SELECT url_id, tag FROM url_tags WHERE url_id = ANY($1) ORDER BY url_id, tag",
+        url_ids
+    )
+    .fetch_all(db)
+    .await?;
+    let mut tags: HashMap<i64, Vec<String>> = HashMap::new();
+    for row in rows {
+        tags.entry(row.url_id).or_default().push(row.tag);
+    }
+    Ok(tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_tags() {
+        assert_eq!(
+            parse(" Newsletter, q3-campaign,,newsletter , Über_Uns").unwrap(),
+            ["newsletter", "q3-campaign", "über_uns"]
+        );
+        assert_eq!(parse("").unwrap(), Vec::<String>::new());
+        assert_eq!(parse(" , ").unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn limits_tags() {
+        assert!(parse(&"a".repeat(MAX_TAG_LEN)).is_ok());
+        assert!(parse(&"a".repeat(MAX_TAG_LEN + 1)).is_err());
+        let most: Vec<String> = (0..MAX_TAGS).map(|i| format!("t{i}")).collect();
+        assert!(parse(&most.join(",")).is_ok());
+        assert!(parse(&format!("{},another", most.join(","))).is_err());
+        // Repeats only count once
+        assert!(parse(&format!("{},t0", most.join(","))).is_ok());
+        for bad in ["two words", "semi;colon", "slash/ed"] {
+            assert!(parse(bad).is_err(), "{bad}");
+        }
+    }
+}
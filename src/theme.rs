@@ -0,0 +1,412 @@
+use std::{fs, path::Path};
+
+use regex::Regex;
+use sha2::{Digest, Sha256};
+
+use crate::preferences::{PrefError, ThemePrefs};
+
+const LOGO_EXTENTIONS: [(&str, &str); 6] = [
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("webp", "image/webp"),
+    ("gif", "image/gif"),
+    ("svg", "image/svg+xml"),
+];
+
+/// Colors that differ between the light and dark palettes, as (name, light, dark)
+const PALETTE: [(&str, &str, &str); 7] = [
+    ("background-color", "#F0F7F4", "#121416"),
+    ("surface-color", "#FFFFFF", "#1E2225"),
+    ("text-color", "#1B1B1B", "#E4E6E8"),
+    ("muted-text-color", "#5A5F63", "#A0A6AB"),
+    ("announcement-info-color", "#DDEBF7", "#1C3246"),
+    ("announcement-warning-color", "#FCEFC7", "#4A3B10"),
+    ("announcement-critical-color", "#F9D6D5", "#4F1A1A"),
+];
+/// Announcement severities, each with a banner class using its color from [PALETTE]
+const ANNOUNCEMENT_SEVERITIES: [&str; 3] = ["info", "warning", "critical"];
+/// Lowest contrast ratio WCAG AA allows for normal sized text
+const MIN_CONTRAST: f64 = 4.5;
+/// Text colors from [PALETTE] and the backgrounds from it they are drawn on, as (text, background)
+const TEXT_ON: [(&str, &str); 7] = [
+    ("text-color", "background-color"),
+    ("text-color", "surface-color"),
+    ("muted-text-color", "background-color"),
+    ("muted-text-color", "surface-color"),
+    ("text-color", "announcement-info-color"),
+    ("text-color", "announcement-warning-color"),
+    ("text-color", "announcement-critical-color"),
+];
+
+/// The instance theme as it is served. Everything is computed once from [ThemePrefs] so serving
+/// `/theme.css` never has to touch the filesystem or rebuild the stylesheet.
+#[derive(Debug, Clone)]
+pub struct Theme {
+    css: String,
+    etag: String,
+    logo: Option<Logo>,
+    brand: Brand,
+}
+
+/// The text every page shows around its content, rendered by the base template. Pages served as is
+/// from html/ only get the logo, through the stylesheet.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Brand {
+    name: String,
+    footer: String,
+}
+
+impl Brand {
+    #[cfg(test)]
+    pub fn new(name: &str, footer: &str) -> Self {
+        Brand {
+            name: name.to_string(),
+            footer: footer.to_string(),
+        }
+    }
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+    pub fn footer(&self) -> &str {
+        self.footer.as_str()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Logo {
+    path: String,
+    file_name: String,
+    content_type: &'static str,
+}
+
+impl Theme {
+    /// Validates the theme section of the config and builds the stylesheet. Fails if the primary
+    /// color isn't a CSS color or if the configured logo can't be read.
+    pub fn from_prefs(prefs: &ThemePrefs) -> Result<Self, PrefError> {
+        if !is_css_color(prefs.primary_color()) {
+            return Err(PrefError::InvalidValue(format!(
+                "theme.primary_color `{}` is not a valid color",
+                prefs.primary_color()
+            )));
+        }
+        let logo = match prefs.logo_path() {
+            Some(path) => Some(Logo::load(path)?),
+            None => None,
+        };
+        let css = build_css(prefs, logo.as_ref());
+        let etag = format!("\"{}\"", short_hash(css.as_bytes()));
+        let brand = Brand {
+            name: prefs.brand_name().to_string(),
+            footer: prefs.footer_text().to_string(),
+        };
+
+        Ok(Theme {
+            css,
+            etag,
+            logo,
+            brand,
+        })
+    }
+    pub fn css(&self) -> &str {
+        self.css.as_str()
+    }
+    /// Quoted ETag for the generated stylesheet. Since the stylesheet contains every theme
+    /// setting (and the logo's content hash), this changes whenever any of them do.
+    pub fn etag(&self) -> &str {
+        self.etag.as_str()
+    }
+    pub fn logo(&self) -> &Option<Logo> {
+        &self.logo
+    }
+    pub fn brand(&self) -> &Brand {
+        &self.brand
+    }
+}
+
+impl Logo {
+    fn load(path: &str) -> Result<Self, PrefError> {
+        let ext = Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase())
+            .unwrap_or_default();
+        let content_type = match LOGO_EXTENTIONS.iter().find(|(known, _)| *known == ext) {
+            Some((_, content_type)) => *content_type,
+            None => {
+                return Err(PrefError::InvalidValue(format!(
+                    "theme.logo_path `{path}` is not a supported image type"
+                )))
+            }
+        };
+        let contents = fs::read(path).map_err(PrefError::IoError)?;
+        let file_name = format!("{}.{ext}", short_hash(&contents));
+
+        Ok(Logo {
+            path: path.to_string(),
+            file_name,
+            content_type,
+        })
+    }
+    pub fn path(&self) -> &str {
+        self.path.as_str()
+    }
+    /// Content-hashed file name the logo is served under, so it can be cached forever.
+    pub fn file_name(&self) -> &str {
+        self.file_name.as_str()
+    }
+    pub fn url(&self) -> String {
+        format!("/logo/{}", self.file_name)
+    }
+    pub fn content_type(&self) -> &'static str {
+        self.content_type
+    }
+}
+
+/// Colors of the theme that text sits on or is drawn in without enough contrast, described for the
+/// startup log. Both palettes are checked, and the primary color against the light palette's text
+/// since buttons keep dark text on it. Only hex colors can be checked; others are assumed fine.
+pub fn contrast_problems(prefs: &ThemePrefs) -> Vec<String> {
+    let color = |name: &str, dark: bool| {
+        PALETTE
+            .iter()
+            .find(|(known, _, _)| *known == name)
+            .map(|(_, light_color, dark_color)| if dark { *dark_color } else { *light_color })
+            .expect("TEXT_ON only names colors in PALETTE")
+    };
+    let mut pairs: Vec<(String, &str, &str)> = [("light", false), ("dark", true)]
+        .into_iter()
+        .flat_map(|(palette, dark)| {
+            TEXT_ON.iter().map(move |(text, background)| {
+                (
+                    format!("{text} on {background} in the {palette} palette"),
+                    color(text, dark),
+                    color(background, dark),
+                )
+            })
+        })
+        .collect();
+    pairs.push((
+        String::from("text-color on theme.primary_color"),
+        color("text-color", false),
+        prefs.primary_color().trim(),
+    ));
+    pairs
+        .into_iter()
+        .filter_map(|(what, text, background)| {
+            let ratio = contrast_ratio(text, background)?;
+            (ratio < MIN_CONTRAST)
+                .then(|| format!("{what} has a contrast of {ratio:.2}:1, under {MIN_CONTRAST}:1"))
+        })
+        .collect()
+}
+
+/// WCAG contrast ratio of two hex colors, from 1 (the same) to 21 (black and white). Alpha is
+/// ignored. None if either isn't a hex color.
+fn contrast_ratio(first: &str, second: &str) -> Option<f64> {
+    let first = relative_luminance(first)?;
+    let second = relative_luminance(second)?;
+    let (lighter, darker) = if first > second {
+        (first, second)
+    } else {
+        (second, first)
+    };
+    Some((lighter + 0.05) / (darker + 0.05))
+}
+
+/// Relative luminance of a `#rgb`, `#rgba`, `#rrggbb` or `#rrggbbaa` color
+fn relative_luminance(color: &str) -> Option<f64> {
+    let hex = color.strip_prefix('#')?;
+    let channels: Vec<u8> = match hex.len() {
+        3 | 4 => hex
+            .chars()
+            .take(3)
+            .map(|digit| u8::from_str_radix(&digit.to_string().repeat(2), 16).ok())
+            .collect::<Option<_>>()?,
+        6 | 8 => (0..3)
+            .map(|at| u8::from_str_radix(hex.get(at * 2..at * 2 + 2)?, 16).ok())
+            .collect::<Option<_>>()?,
+        _ => return None,
+    };
+    let linear = |channel: u8| {
+        let channel = f64::from(channel) / 255.0;
+        if channel <= 0.04045 {
+            channel / 12.92
+        } else {
+            ((channel + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    Some(0.2126 * linear(channels[0]) + 0.7152 * linear(channels[1]) + 0.0722 * linear(channels[2]))
+}
+
+fn is_css_color(color: &str) -> bool {
+    let color_regex = Regex::new(
+        r"^(#([0-9a-fA-F]{3,4}|[0-9a-fA-F]{6}|[0-9a-fA-F]{8})|(rgb|rgba|hsl|hsla)\(\s*[0-9.%,/\s]+\))$",
+    )
+    .expect("Error creating Regex match");
+    color_regex.is_match(color.trim())
+}
+
+fn build_css(prefs: &ThemePrefs, logo: Option<&Logo>) -> String {
+    let logo_rule = match logo {
+        Some(logo) => format!("url(\"{}\")", logo.url()),
+        None => String::from("none"),
+    };
+    // light-dark() picks from each pair by the used color scheme: the browser preference while
+    // both are allowed, or whatever data-appearance forces. Each palette is only written once.
+    let palette: String = PALETTE
+        .iter()
+        .map(|(name, light, dark)| format!("\t--{name}: light-dark({light}, {dark});\n"))
+        .collect();
+    let announcements: String = ANNOUNCEMENT_SEVERITIES
+        .iter()
+        .map(|severity| {
+            format!(
+                "\n.announcement-{severity} {{\n\tbackground-color: \
+                 var(--announcement-{severity}-color);\n}}\n"
+            )
+        })
+        .collect();
+    format!(
+        ":root {{\n\t--primary-color: {};\n\t--brand-logo: {logo_rule};\n\tcolor-scheme: light dark;\n\
+         {palette}}}\n\n\
+         :root[data-appearance=\"light\"] {{\n\tcolor-scheme: light;\n}}\n\n\
+         :root[data-appearance=\"dark\"] {{\n\tcolor-scheme: dark;\n}}\n\n\
+         body {{\n\tbackground-color: var(--background-color);\n\tcolor: var(--text-color);\n}}\n\n\
+         .visually-hidden, .skip-link:not(:focus) {{\n\tposition: absolute;\n\twidth: 1px;\n\
+         \theight: 1px;\n\toverflow: hidden;\n\tclip-path: inset(50%);\n\twhite-space: nowrap;\n}}\n\
+         {announcements}",
+        prefs.primary_color().trim(),
+    )
+}
+
+fn short_hash(bytes: &[u8]) -> String {
+    let digest = Sha256::digest(bytes);
+    hex::encode(&digest[..8])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use askama::Template;
+
+    use super::*;
+
+    fn theme_with(color: &str, brand: &str, logo: Option<String>) -> ThemePrefs {
+        ThemePrefs::new(
+            color.to_string(),
+            brand.to_string(),
+            String::from("Footer"),
+            logo,
+        )
+    }
+
+    #[test]
+    fn renders_configured_values() {
+        let first = Theme::from_prefs(&theme_with("#123456", "Acme", None)).unwrap();
+        let second =
+            Theme::from_prefs(&theme_with("rgb(1, 2, 3)", "Globex \"Corp\"", None)).unwrap();
+
+        assert!(first.css().contains("--primary-color: #123456;"));
+        assert!(first.css().contains("--brand-logo: none;"));
+        assert!(first
+            .css()
+            .contains("background-color: var(--announcement-critical-color);"));
+        assert!(second.css().contains("--primary-color: rgb(1, 2, 3);"));
+        assert!(!second.css().contains("Globex"));
+
+        // The brand is rendered into the pages, escaped like any other text
+        for (theme, shown) in [(first, "Acme"), (second, "Globex &quot;Corp&quot;")] {
+            let page = crate::ExpiredTemplate {
+                at: String::from("2026-01-01 00:00 UTC"),
+                appearance: Default::default(),
+                brand: theme.brand().clone(),
+                back: String::from("/abc"),
+            }
+            .render()
+            .unwrap();
+            assert!(
+                page.contains(&format!("<h1 class=\"brand-name\">{shown}</h1>")),
+                "{page}"
+            );
+            assert!(page.contains("Footer\n\t</footer>"), "{page}");
+        }
+    }
+
+    #[test]
+    fn has_both_palettes_once() {
+        let theme = Theme::from_prefs(&theme_with("#123456", "Acme", None)).unwrap();
+        let css = theme.css();
+        for (name, light, dark) in PALETTE {
+            assert_eq!(css.matches(&format!("--{name}:")).count(), 1, "{name}");
+            assert_eq!(css.matches(light).count(), 1, "{light}");
+            assert_eq!(css.matches(dark).count(), 1, "{dark}");
+        }
+        assert!(css.contains(":root[data-appearance=\"dark\"] {\n\tcolor-scheme: dark;\n}"));
+        assert!(css.contains(":root[data-appearance=\"light\"] {\n\tcolor-scheme: light;\n}"));
+    }
+
+    #[test]
+    fn contrast_follows_wcag() {
+        assert_eq!(contrast_ratio("#000", "#FFFFFF"), Some(21.0));
+        assert_eq!(contrast_ratio("#777777", "#777"), Some(1.0));
+        let ratio = contrast_ratio("#767676", "#ffffff").unwrap();
+        assert!((ratio - 4.54).abs() < 0.01, "{ratio}");
+        assert_eq!(contrast_ratio("rgb(0, 0, 0)", "#fff"), None);
+    }
+
+    #[test]
+    fn palettes_are_readable() {
+        assert_eq!(
+            contrast_problems(&theme_with("#99E1D9", "Acme", None)),
+            Vec::<String>::new()
+        );
+        let problems = contrast_problems(&theme_with("#333333", "Acme", None));
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("text-color on theme.primary_color"));
+        // Can't be checked, so not reported
+        assert!(contrast_problems(&theme_with("rgb(0, 0, 0)", "Acme", None)).is_empty());
+    }
+
+    #[test]
+    fn etag_follows_color() {
+        let first = Theme::from_prefs(&theme_with("#123456", "Acme", None)).unwrap();
+        let same = Theme::from_prefs(&theme_with("#123456", "Acme", None)).unwrap();
+        let changed = Theme::from_prefs(&theme_with("#654321", "Acme", None)).unwrap();
+
+        assert_eq!(first.etag(), same.etag());
+        assert_ne!(first.etag(), changed.etag());
+    }
+
+    #[test]
+    fn rejects_invalid_colors() {
+        for color in ["red; } body { display: none", "#12345", "12345z", ""] {
+            assert!(Theme::from_prefs(&theme_with(color, "Acme", None)).is_err());
+        }
+        // The message says which value was wrong
+        let Err(e) = Theme::from_prefs(&theme_with("#12345", "Acme", None)) else {
+            panic!("#12345 was accepted");
+        };
+        assert_eq!(
+            e.to_string(),
+            "theme.primary_color `#12345` is not a valid color"
+        );
+    }
+
+    #[test]
+    fn logo_is_content_hashed() {
+        let missing = theme_with("#fff", "Acme", Some(String::from("html/no-such-logo.png")));
+        assert!(Theme::from_prefs(&missing).is_err());
+
+        let path = env::temp_dir().join("url_shortener_test_logo.png");
+        fs::write(&path, b"not really a png").unwrap();
+        let prefs = theme_with("#fff", "Acme", Some(path.to_string_lossy().to_string()));
+        let theme = Theme::from_prefs(&prefs).unwrap();
+        let logo = theme.logo().as_ref().unwrap();
+
+        assert!(logo.file_name().ends_with(".png"));
+        assert_eq!(logo.content_type(), "image/png");
+        assert!(theme.css().contains(logo.url().as_str()));
+    }
+}
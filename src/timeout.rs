@@ -0,0 +1,158 @@
+use std::{error::Error, fmt::Display, time::Duration};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures_util::{stream, StreamExt};
+use tracing::error;
+
+use crate::api_error::ApiError;
+
+/// Middleware that abandons a request once it has taken longer than the budget in its state.
+/// Use with `middleware::from_fn_with_state(budget, timeout::total)`.
+pub async fn total(State(budget): State<Duration>, req: Request, next: Next) -> Response {
+    let method = req.method().clone();
+    let uri = req.uri().clone();
+    match tokio::time::timeout(budget, next.run(req)).await {
+        Ok(resp) => resp,
+        Err(_) => {
+            error!("{method} {uri} timed out after {budget:?}");
+            ApiError::unavailable("The request took too long").into_response()
+        }
+    }
+}
+
+/// Like [total] but for handlers that stream their response. The budget applies to producing the
+/// response head and then to every gap between body chunks, so a long export that keeps making
+/// progress is never cut off. Once the body has started the status can't change anymore, so a
+/// stalled stream is ended with an error, which drops the connection.
+pub async fn idle(State(budget): State<Duration>, req: Request, next: Next) -> Response {
+    let method = req.method().clone();
+    let uri = req.uri().clone();
+    let resp = match tokio::time::timeout(budget, next.run(req)).await {
+        Ok(resp) => resp,
+        Err(_) => {
+            error!("{method} {uri} timed out after {budget:?}");
+            return ApiError::unavailable("The request took too long").into_response();
+        }
+    };
+
+    let (parts, body) = resp.into_parts();
+    let request = format!("{method} {uri}");
+    let chunks = stream::unfold(Some(body.into_data_stream()), move |state| {
+        let request = request.clone();
+        async move {
+            let mut chunks = state?;
+            match tokio::time::timeout(budget, chunks.next()).await {
+                Ok(Some(chunk)) => Some((chunk.map_err(axum::Error::new), Some(chunks))),
+                Ok(None) => None,
+                Err(_) => {
+                    error!("{request} stalled for {budget:?} while streaming");
+                    Some((Err(axum::Error::new(Stalled(budget))), None))
+                }
+            }
+        }
+    });
+    Response::from_parts(parts, Body::from_stream(chunks))
+}
+
+#[derive(Debug)]
+struct Stalled(Duration);
+
+impl Display for Stalled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no data was produced for {:?}", self.0)
+    }
+}
+
+impl Error for Stalled {}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Bytes},
+        http::StatusCode,
+        middleware,
+        routing::get,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    const BUDGET: Duration = Duration::from_millis(100);
+
+    async fn fast() -> &'static str {
+        "done"
+    }
+
+    async fn slow() -> &'static str {
+        tokio::time::sleep(BUDGET * 3).await;
+        "too late"
+    }
+
+    /// Sends a chunk every `gap`, `count` times
+    async fn chunked(gap: Duration, count: usize) -> Body {
+        let chunks = stream::iter(0..count).then(move |i| async move {
+            tokio::time::sleep(gap).await;
+            Ok::<_, std::io::Error>(Bytes::from(format!("{i},")))
+        });
+        Body::from_stream(chunks)
+    }
+
+    fn app() -> Router {
+        let total_routes = Router::new()
+            .route("/fast", get(fast))
+            .route("/slow", get(slow))
+            .layer(middleware::from_fn_with_state(BUDGET, total));
+        let idle_routes = Router::new()
+            .route("/export", get(|| chunked(BUDGET / 2, 6)))
+            .route("/stalled", get(|| chunked(BUDGET * 3, 2)))
+            .layer(middleware::from_fn_with_state(BUDGET, idle));
+        total_routes.merge(idle_routes)
+    }
+
+    async fn get_path(path: &str) -> Response {
+        let req = Request::builder().uri(path).body(Body::empty()).unwrap();
+        app().oneshot(req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn fast_request_is_unaffected() {
+        let resp = get_path("/fast").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            to_bytes(resp.into_body(), usize::MAX).await.unwrap(),
+            "done"
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_request_gets_envelope() {
+        let resp = get_path("/slow").await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["code"], "unavailable");
+        assert!(body["error"]["message"].is_string());
+    }
+
+    #[tokio::test]
+    async fn long_stream_that_progresses_completes() {
+        // Takes three times the budget in total, but never waits longer than half of it
+        let resp = get_path("/export").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "0,1,2,3,4,5,");
+    }
+
+    #[tokio::test]
+    async fn stalled_stream_is_cut_off() {
+        let resp = get_path("/stalled").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(to_bytes(resp.into_body(), usize::MAX).await.is_err());
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{request::Parts, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sqlx::{PgConnection, PgPool, Postgres, Transaction};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tracing::error;
+
+use crate::api_error::ApiError;
+
+type Slot = Arc<Mutex<Option<Transaction<'static, Postgres>>>>;
+
+/// Middleware giving each request on the routes it wraps its own transaction, which handlers take
+/// with the [Tx] extractor. It commits when the response is a success or a redirect and rolls back
+/// otherwise. A panicking handler drops the transaction, which rolls it back as well.
+///
+/// Only add it to routes that write more than once; everything else should keep using the pool
+/// directly. Use with `middleware::from_fn_with_state(pool, transaction::scoped)`.
+#[allow(dead_code)]
+pub async fn scoped(State(pool): State<PgPool>, mut req: Request, next: Next) -> Response {
+    let tx = match pool.begin().await {
+        Ok(tx) => tx,
+        Err(e) => {
+            error!("Couldn't start a transaction: {e}");
+            return ApiError::unavailable("The database is unavailable").into_response();
+        }
+    };
+    let slot: Slot = Arc::new(Mutex::new(Some(tx)));
+    req.extensions_mut().insert(slot.clone());
+
+    let resp = next.run(req).await;
+
+    // The handler has returned, so nothing else holds the lock anymore
+    let Some(tx) = slot.lock().await.take() else {
+        return resp;
+    };
+    let status = resp.status();
+    if status.is_success() || status.is_redirection() {
+        if let Err(e) = tx.commit().await {
+            error!("Couldn't commit the transaction: {e}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    } else if let Err(e) = tx.rollback().await {
+        error!("Couldn't roll back the transaction: {e}");
+    }
+    resp
+}
+
+/// The request's transaction, from [scoped]. Derefs to a connection, so `&mut *tx` can be passed to
+/// any database function taking an executor.
+#[allow(dead_code)]
+pub struct Tx(OwnedMutexGuard<Option<Transaction<'static, Postgres>>>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Tx {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(slot) = parts.extensions.get::<Slot>().cloned() else {
+            error!("{} uses Tx but has no transaction layer", parts.uri.path());
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        };
+        let guard = slot.lock_owned().await;
+        if guard.is_none() {
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+        Ok(Tx(guard))
+    }
+}
+
+impl Deref for Tx {
+    type Target = PgConnection;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref().expect("Transaction checked when extracted")
+    }
+}
+
+impl DerefMut for Tx {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0.as_mut().expect("Transaction checked when extracted")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header::LOCATION, Request},
+        middleware,
+        routing::post,
+        Router,
+    };
+    use sqlx::postgres::PgPoolOptions;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        preferences::Preferences,
+        url_db::{self, UrlOptions},
+    };
+
+    async fn pool_init() -> PgPool {
+        let prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        let conn_url = format!(
+            "postgres://{}:{}@172.17.0.2/{}",
+            prefs.db_user(),
+            prefs.db_pass(),
+            prefs.db_name(),
+        );
+        PgPoolOptions::new()
+            .max_connections(prefs.db_pool_size())
+            .connect(&conn_url)
+            .await
+            .expect("Couldn't create connection pool. Are your credentials correct?")
+    }
+
+    /// Creates two links in the request's transaction, then answers with `status`, or panics
+    async fn two_writes(mut tx: Tx, alias: String, status: Option<StatusCode>) -> Response {
+        for suffix in ["a", "b"] {
+            url_db::create_url_with_alias(
+                &format!("{alias}{suffix}"),
+                "https://example.com",
+                None,
+                &mut *tx,
+                &UrlOptions::default(),
+            )
+            .await
+            .unwrap();
+        }
+        match status {
+            Some(status) => (status, [(LOCATION, "/")]).into_response(),
+            None => panic!("handler failed after writing"),
+        }
+    }
+
+    fn app(pool: &PgPool, alias: &str, status: Option<StatusCode>) -> Router {
+        let alias = alias.to_string();
+        Router::new()
+            .route(
+                "/",
+                post(move |tx: Tx| two_writes(tx, alias.clone(), status)),
+            )
+            .layer(middleware::from_fn_with_state(pool.clone(), scoped))
+    }
+
+    async fn send(app: Router) -> Result<StatusCode, tokio::task::JoinError> {
+        let req = Request::post("/").body(Body::empty()).unwrap();
+        // Run in its own task so a panicking handler can be observed
+        tokio::spawn(async move { app.oneshot(req).await.unwrap().status() }).await
+    }
+
+    async fn saved(pool: &PgPool, alias: &str) -> usize {
+        let codes = [format!("{alias}a"), format!("{alias}b")];
+        url_db::retrieve_url_objs(&codes, pool).await.unwrap().len()
+    }
+
+    #[sqlx::test]
+    async fn commits_on_success() {
+        let pool = pool_init().await;
+        for status in [StatusCode::OK, StatusCode::SEE_OTHER] {
+            let alias = format!("txok{}", rand::random::<u32>());
+            let resp = send(app(&pool, &alias, Some(status))).await.unwrap();
+            assert_eq!(resp, status);
+            assert_eq!(saved(&pool, &alias).await, 2);
+        }
+    }
+
+    #[sqlx::test]
+    async fn rolls_back_on_error_response() {
+        let pool = pool_init().await;
+        let alias = format!("txerr{}", rand::random::<u32>());
+        let resp = send(app(&pool, &alias, Some(StatusCode::UNPROCESSABLE_ENTITY)))
+            .await
+            .unwrap();
+        assert_eq!(resp, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(saved(&pool, &alias).await, 0);
+    }
+
+    #[sqlx::test]
+    async fn rolls_back_on_panic() {
+        let pool = pool_init().await;
+        let alias = format!("txpanic{}", rand::random::<u32>());
+        assert!(send(app(&pool, &alias, None)).await.unwrap_err().is_panic());
+        assert_eq!(saved(&pool, &alias).await, 0);
+    }
+
+    #[tokio::test]
+    async fn routes_without_the_layer_cant_take_a_transaction() {
+        let app = Router::new().route("/", post(|_: Tx| async { StatusCode::OK }));
+        assert_eq!(send(app).await.unwrap(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
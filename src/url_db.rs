@@ -1,14 +1,20 @@
-use askama::Template;
+use axum::http::HeaderMap;
 use base64::{engine::general_purpose, prelude::*};
+use chrono::{DateTime, NaiveDate, Utc};
 use rand::{
-    distributions::{Alphanumeric, DistString},
+    distributions::{Alphanumeric, DistString, WeightedIndex},
     prelude::*,
 };
-use sqlx::{postgres::PgQueryResult, FromRow};
-use std::{result::Result, str};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    postgres::PgQueryResult, Acquire, FromRow, PgConnection, PgExecutor, Postgres, QueryBuilder,
+};
+use std::{collections::BTreeMap, result::Result, str, time::Duration};
+use tracing::{info, warn};
+
+use crate::{referrer, resolve::RedirectStatus, user_agent::Device};
 
-#[derive(FromRow, Debug, Clone, Template)]
-#[template(path = "url-table-row.html")]
+#[derive(FromRow, Debug, Clone)]
 #[allow(dead_code)]
 pub struct UrlRow {
     // If fields are updated, update UrlRowIterator
@@ -17,15 +23,223 @@ pub struct UrlRow {
     longurl: String,
     created_by: Option<i64>,
     clicks: i64,
+    interstitial_seconds: Option<i16>,
+    single_use: Option<bool>,
+    consumed_at: Option<DateTime<Utc>>,
+    submitted_url: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+    max_clicks: Option<i64>,
+    passphrase_hash: Option<String>,
+    created_at: Option<DateTime<Utc>>,
+    redirect_status: Option<i16>,
+    description: Option<String>,
+    deleted_at: Option<DateTime<Utc>>,
+    rotates: Option<bool>,
+    geo_targeted: Option<bool>,
+    active_from: Option<DateTime<Utc>>,
+    bot_clicks: Option<i64>,
+    rollout_until: Option<DateTime<Utc>>,
+}
+
+/// Longest countdown page a link can ask for before redirecting
+pub const MAX_INTERSTITIAL_SECONDS: i16 = 10;
+/// Longest description a link can have, in characters. Enforced by a CHECK constraint too.
+pub const MAX_DESCRIPTION_LEN: usize = 500;
+/// Most links [list_urls_for_user] returns at once
+pub const MAX_PAGE_SIZE: i64 = 100;
+/// Longest destination, or submitted url, the table takes whatever the config allows. Enforced by
+/// a CHECK constraint on both columns.
+pub const MAX_LONG_URL_LEN: usize = 32_768;
+/// Windows over the encoded destination tried before falling back to random codes, so a long
+/// destination doesn't cost a lookup per character
+const MAX_WINDOWS: usize = 32;
+/// Codes with a route or page of their own, or that one is likely to get, so a link there would
+/// never be reached
+pub const RESERVED_CODES: [&str; 14] = [
+    "admin",
+    "announcement",
+    "api",
+    "appearance",
+    "healthz",
+    "index.html",
+    "login",
+    "logo",
+    "logout",
+    "px",
+    "readyz",
+    "signup",
+    "static",
+    "theme.css",
+];
+
+/// How a short url someone typed is matched against the stored ones
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SlugCase {
+    /// Only the short url exactly as stored
+    #[default]
+    Sensitive,
+    /// Any short url differing only in letter case, the exact one first if there are several.
+    /// Codes are generated in lowercase and can't be created next to one differing only in case.
+    Insensitive,
+}
+
+impl SlugCase {
+    /// A generated code as it should be stored
+    fn generated(self, code: String) -> String {
+        match self {
+            SlugCase::Sensitive => code,
+            SlugCase::Insensitive => code.to_ascii_lowercase(),
+        }
+    }
+}
+
+/// Where generated codes come from
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SlugStrategy {
+    /// A window over the base64 of the destination that isn't taken, or a random code if none is
+    /// free. Windows with characters codes can't have are skipped.
+    #[default]
+    Window,
+    /// The next value of a database sequence in base 62, left padded with zeros, so no lookups are
+    /// needed. Random codes take over once the sequence needs more than the code length.
+    Sequential,
+}
+
+/// How codes for new links are made. Built from the preferences, see
+/// [crate::preferences::Preferences::code_rules].
+#[derive(Debug, Clone, Copy)]
+pub struct CodeRules<'a> {
+    pub len: usize,
+    /// Codes that aren't generated on top of [RESERVED_CODES], see [is_reserved]
+    pub reserved: &'a [String],
+    pub case: SlugCase,
+    pub strategy: SlugStrategy,
+    /// Random codes tried before giving up with [UrlCreateError::Exhausted]
+    pub attempts: u32,
+}
+
+impl CodeRules<'_> {
+    /// Codes of `len` characters with the default rules
+    #[cfg(test)]
+    pub fn of_len(len: usize) -> Self {
+        CodeRules {
+            len,
+            reserved: &[],
+            case: SlugCase::default(),
+            strategy: SlugStrategy::default(),
+            attempts: 16,
+        }
+    }
+}
+
+/// Why a link with a generated code couldn't be made
+#[derive(Debug)]
+pub enum UrlCreateError {
+    /// Every random code tried was taken, so codes of this length are running out
+    Exhausted,
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for UrlCreateError {
+    fn from(e: sqlx::Error) -> Self {
+        UrlCreateError::Database(e)
+    }
+}
+
+/// Whether `code` is one of [RESERVED_CODES], a file browsers look for at the root or one of the
+/// instance's own `extra` reserved codes. Case is ignored, so `API` is as reserved as `api`.
+pub fn is_reserved(code: &str, extra: &[String]) -> bool {
+    RESERVED_CODES
+        .iter()
+        .chain(crate::assets::ROOT_FILES.iter())
+        .copied()
+        .chain(extra.iter().map(String::as_str))
+        .any(|reserved| reserved.eq_ignore_ascii_case(code))
+}
+
+/// Optional per link settings chosen at creation time. The defaults give a plain redirect.
+#[derive(Debug, Default, Clone)]
+pub struct UrlOptions {
+    pub interstitial_seconds: Option<i16>,
+    /// The destination is only revealed to the first visitor
+    pub single_use: bool,
+    /// The destination as it was typed, when normalizing it dropped something
+    pub submitted_url: Option<String>,
+    /// The link stops redirecting at this time
+    pub expires_at: Option<DateTime<Utc>>,
+    /// The link only starts redirecting at this time
+    pub active_from: Option<DateTime<Utc>>,
+    /// The link stops redirecting after this many clicks
+    pub max_clicks: Option<i64>,
+    /// Visitors have to give the passphrase this was hashed from, see [crate::user::hash_password]
+    pub passphrase_hash: Option<String>,
+    /// Redirect with this instead of the instance's status
+    pub redirect_status: Option<RedirectStatus>,
+    /// The owner's notes on the link, never shown to visitors
+    pub description: Option<String>,
+}
+
+impl UrlOptions {
+    /// Whether links made with these options just redirect, so any of them can stand in for another
+    pub fn is_plain(&self) -> bool {
+        self.interstitial_seconds.unwrap_or(0) == 0
+            && !self.single_use
+            && self.expires_at.is_none()
+            && self.active_from.is_none()
+            && self.max_clicks.is_none()
+            && self.passphrase_hash.is_none()
+            && self.redirect_status.is_none()
+            && self.description.is_none()
+    }
+}
+
+/// Where a click came from, counted along with it
+#[derive(Debug, Clone)]
+pub struct ClickSource {
+    /// See [crate::referrer::origin]
+    pub referrer: String,
+    /// See [crate::conversion::visitor_id]. Only set while conversion tracking is on.
+    pub visitor: Option<String>,
+    /// Left out of [clicks_by_device] when None
+    pub device: Option<Device>,
+    /// The [Destination] a rotating link sent the click to
+    pub destination: Option<i64>,
+    /// The branch a geo-targeted link sent the click down, see [crate::geo::DEFAULT_BRANCH]
+    pub geo_branch: Option<String>,
+}
+
+impl ClickSource {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        ClickSource {
+            referrer: referrer::origin(headers),
+            visitor: None,
+            device: Some(Device::classify(headers)),
+            destination: None,
+            geo_branch: None,
+        }
+    }
+}
+
+impl Default for ClickSource {
+    fn default() -> Self {
+        ClickSource {
+            referrer: String::from(referrer::DIRECT),
+            visitor: None,
+            device: None,
+            destination: None,
+            geo_branch: None,
+        }
+    }
 }
 
 #[derive(FromRow, Debug)]
 #[allow(dead_code)]
 pub struct UserRow {
-    id: i64,
-    username: String,
-    hashed_pw: String,
-    email: String,
+    pub(crate) id: i64,
+    pub(crate) username: String,
+    pub(crate) hashed_pw: String,
+    pub(crate) email: String,
 }
 
 #[allow(dead_code)]
@@ -80,198 +294,2502 @@ impl UrlRow {
     pub fn clone_short_url(&self) -> String {
         self.shorturl.clone()
     }
+    pub fn created_by(&self) -> Option<i64> {
+        self.created_by
+    }
+    pub fn clicks(&self) -> i64 {
+        self.clicks
+    }
+    /// Visits from bots, see [incr_bot_clicks]. Not in [clicks](Self::clicks).
+    pub fn bot_clicks(&self) -> i64 {
+        self.bot_clicks.unwrap_or(0)
+    }
+    /// Seconds to show the countdown page before redirecting. 0 means redirect immediately.
+    pub fn interstitial_seconds(&self) -> i16 {
+        self.interstitial_seconds.unwrap_or(0)
+    }
+    pub fn single_use(&self) -> bool {
+        self.single_use.unwrap_or(false)
+    }
+    /// The destination as it was typed, if normalizing it dropped something. Only for display.
+    pub fn submitted_url(&self) -> Option<&str> {
+        self.submitted_url.as_deref()
+    }
+    /// When a single use link was claimed. None until then, and always for other links.
+    pub fn consumed_at(&self) -> Option<DateTime<Utc>> {
+        self.consumed_at
+    }
+    /// When the link stops redirecting, if it ever does
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.expires_at
+    }
+    /// When a link scheduled ahead of time starts redirecting
+    pub fn active_from(&self) -> Option<DateTime<Utc>> {
+        self.active_from
+    }
+    /// Whether the link is scheduled to start redirecting after `now`
+    pub fn is_pending(&self, now: DateTime<Utc>) -> bool {
+        self.active_from.is_some_and(|at| at > now)
+    }
+    /// Clicks after which the link stops redirecting, if it has a limit
+    pub fn max_clicks(&self) -> Option<i64> {
+        self.max_clicks
+    }
+    /// Whether the link has used up its clicks
+    pub fn is_exhausted(&self) -> bool {
+        self.max_clicks.is_some_and(|max| self.clicks >= max)
+    }
+    /// Hash of the passphrase visitors have to give, for links that have one
+    pub fn passphrase_hash(&self) -> Option<&str> {
+        self.passphrase_hash.as_deref()
+    }
+    /// None for links made before creation times were recorded
+    pub fn created_at(&self) -> Option<DateTime<Utc>> {
+        self.created_at
+    }
+    /// The link's own redirect status, if it has one
+    pub fn redirect_status(&self) -> Option<RedirectStatus> {
+        self.redirect_status
+            .and_then(|status| u16::try_from(status).ok())
+            .and_then(|status| RedirectStatus::try_from(status).ok())
+    }
+    /// The owner's notes on the link. Only for the owner, never on the redirect path.
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+    /// When the link was archived, None while it is live
+    pub fn deleted_at(&self) -> Option<DateTime<Utc>> {
+        self.deleted_at
+    }
+    /// Whether visitors are sent to one of the link's [destinations] instead of its long url
+    pub fn rotates(&self) -> bool {
+        self.rotates.unwrap_or(false)
+    }
+    /// Whether visitors from some countries are sent somewhere other than the long url, see
+    /// [crate::geo]
+    pub fn geo_targeted(&self) -> bool {
+        self.geo_targeted.unwrap_or(false)
+    }
+    /// When a link rolling out a new destination sends everyone there, see [start_rollout]. None
+    /// for links that aren't rolling one out.
+    pub fn rollout_until(&self) -> Option<DateTime<Utc>> {
+        self.rollout_until
+    }
+    /// Sends this visit to `long_url`, one of the link's own destinations. Nothing is stored.
+    pub fn send_to(&mut self, long_url: &str) {
+        long_url.clone_into(&mut self.longurl);
+    }
+    pub fn is_protected(&self) -> bool {
+        self.passphrase_hash.is_some()
+    }
+    /// Whether the link has expired by `now`
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
     pub fn incr_click(&mut self) -> &Self {
         self.clicks += 1;
         self
     }
+    #[cfg(test)]
+    pub fn test_row(shorturl: &str, longurl: &str) -> UrlRow {
+        UrlRow {
+            id: -1,
+            shorturl: shorturl.to_string(),
+            longurl: longurl.to_string(),
+            created_by: None,
+            clicks: 0,
+            interstitial_seconds: None,
+            single_use: None,
+            consumed_at: None,
+            submitted_url: None,
+            expires_at: None,
+            max_clicks: None,
+            passphrase_hash: None,
+            created_at: None,
+            redirect_status: None,
+            description: None,
+            deleted_at: None,
+            rotates: None,
+            geo_targeted: None,
+            active_from: None,
+            bot_clicks: None,
+            rollout_until: None,
+        }
+    }
+    #[cfg(test)]
+    pub fn with_interstitial(mut self, seconds: i16) -> UrlRow {
+        self.interstitial_seconds = Some(seconds);
+        self
+    }
+    #[cfg(test)]
+    pub fn with_owner(mut self, created_by: Option<i64>) -> UrlRow {
+        self.created_by = created_by;
+        self
+    }
+    #[cfg(test)]
+    pub fn with_single_use(mut self, consumed_at: Option<DateTime<Utc>>) -> UrlRow {
+        self.single_use = Some(true);
+        self.consumed_at = consumed_at;
+        self
+    }
+    #[cfg(test)]
+    pub fn with_submitted_url(mut self, submitted_url: &str) -> UrlRow {
+        self.submitted_url = Some(submitted_url.to_string());
+        self
+    }
+    #[cfg(test)]
+    pub fn with_expiry(mut self, expires_at: DateTime<Utc>) -> UrlRow {
+        self.expires_at = Some(expires_at);
+        self
+    }
+    #[cfg(test)]
+    pub fn with_deleted_at(mut self, deleted_at: DateTime<Utc>) -> UrlRow {
+        self.deleted_at = Some(deleted_at);
+        self
+    }
+    #[cfg(test)]
+    pub fn with_active_from(mut self, active_from: DateTime<Utc>) -> UrlRow {
+        self.active_from = Some(active_from);
+        self
+    }
+    #[cfg(test)]
+    pub fn with_max_clicks(mut self, max_clicks: i64) -> UrlRow {
+        self.max_clicks = Some(max_clicks);
+        self
+    }
+    #[cfg(test)]
+    pub fn with_passphrase(mut self, passphrase: &str) -> UrlRow {
+        self.passphrase_hash = Some(crate::user::hash_password(passphrase));
+        self
+    }
+    #[cfg(test)]
+    pub fn with_redirect_status(mut self, status: RedirectStatus) -> UrlRow {
+        self.redirect_status = Some(u16::from(status) as i16);
+        self
+    }
+    #[cfg(test)]
+    pub fn with_description(mut self, description: &str) -> UrlRow {
+        self.description = Some(description.to_string());
+        self
+    }
+    #[cfg(test)]
+    pub fn with_rotation(mut self) -> UrlRow {
+        self.rotates = Some(true);
+        self
+    }
+    #[cfg(test)]
+    pub fn with_geo_targets(mut self) -> UrlRow {
+        self.geo_targeted = Some(true);
+        self
+    }
+    #[cfg(test)]
+    pub fn with_clicks(mut self, clicks: i64) -> UrlRow {
+        self.clicks = clicks;
+        self
+    }
 }
 
-/// Creates a UrlRow, inserts it into the PostgreSQL databse, and returns the created UrlRow object
+/// [create_url_on] with a connection from `connection_pool`, for tests that only need the link
+#[cfg(test)]
 pub async fn create_url(
     long_url: &str,
     user_id: Option<i64>,
     connection_pool: &sqlx::PgPool,
-    url_len: usize,
-) -> Result<UrlRow, sqlx::Error> {
-    let temp_long = gen_url_longword(long_url);
-    let mut short_url = String::new();
+    rules: CodeRules<'_>,
+    options: &UrlOptions,
+) -> Result<UrlRow, UrlCreateError> {
+    let mut conn = connection_pool.acquire().await?;
+    create_url_on(long_url, user_id, &mut conn, rules, options).await
+}
 
-    // Cycle through intil there is a window that is unused
-    for keyword in temp_long.windows(url_len) {
-        let keyword_str =
-            str::from_utf8(keyword).expect("Error parsing str. This shouldn't be possible!");
-        match retrieve_url(keyword_str, connection_pool).await {
-            Ok(response) => {
-                if response.is_empty() {
-                    short_url = String::from_utf8(Vec::from(keyword))
-                        .expect("Error iterpreting short url set")
+/// Creates a UrlRow, inserts it into the PostgreSQL databse through `conn`, and returns the created
+/// UrlRow object. `conn` may be in a transaction: a sequential code found taken on insert is undone
+/// to a savepoint rather than aborting it.
+/// Codes that are reserved, see [is_reserved], are never generated, nor are codes the rules' case
+/// would match to an existing one. Gives up with [UrlCreateError::Exhausted] once the rules'
+/// attempts at a random code all collide.
+pub async fn create_url_on(
+    long_url: &str,
+    user_id: Option<i64>,
+    conn: &mut PgConnection,
+    rules: CodeRules<'_>,
+    options: &UrlOptions,
+) -> Result<UrlRow, UrlCreateError> {
+    let CodeRules {
+        len: url_len,
+        reserved,
+        case,
+        strategy,
+        attempts,
+    } = rules;
+    let mut new_row = UrlRow {
+        id: -1,
+        shorturl: String::new(),
+        longurl: long_url.to_string(),
+        created_by: user_id,
+        clicks: 0,
+        interstitial_seconds: options.interstitial_seconds,
+        single_use: Some(options.single_use),
+        consumed_at: None,
+        submitted_url: options.submitted_url.clone(),
+        expires_at: options.expires_at,
+        max_clicks: options.max_clicks,
+        passphrase_hash: options.passphrase_hash.clone(),
+        created_at: None,
+        redirect_status: options
+            .redirect_status
+            .map(|status| u16::from(status) as i16),
+        description: options.description.clone(),
+        deleted_at: None,
+        rotates: None,
+        geo_targeted: None,
+        active_from: options.active_from,
+        bot_clicks: Some(0),
+        rollout_until: None,
+    };
+
+    if strategy == SlugStrategy::Sequential {
+        while let Some(code) = next_sequential_code(url_len, case, &mut *conn).await? {
+            if is_reserved(&code, reserved) {
+                continue;
+            }
+            new_row.shorturl = code;
+            // An alias can take a code before the sequence gets to it, which only the insert sees
+            let mut attempt = conn.begin().await?;
+            match url_db_create(&mut new_row, &mut *attempt).await {
+                Err(e)
+                    if e.as_database_error()
+                        .is_some_and(|e| e.is_unique_violation()) =>
+                {
+                    attempt.rollback().await?
+                }
+                result => {
+                    result?;
+                    attempt.commit().await?;
+                    return Ok(new_row);
                 }
             }
-            Err(_) => break,
         }
-        if !short_url.is_empty() {
+    }
+
+    // A sequence that got here has outgrown the length, so it goes straight to random codes
+    let temp_long = match strategy {
+        SlugStrategy::Window => gen_url_longword(long_url),
+        SlugStrategy::Sequential => Vec::new(),
+    };
+    let mut short_url = String::new();
+
+    // Cycle through intil there is a window that is unused
+    for keyword in temp_long.windows(url_len).take(MAX_WINDOWS) {
+        let keyword_str = case.generated(
+            String::from_utf8(Vec::from(keyword))
+                .expect("Error parsing str. This shouldn't be possible!"),
+        );
+        // Base64 has `+` and `/` too, which can't be in a code
+        if !keyword_str.bytes().all(|byte| byte.is_ascii_alphanumeric())
+            || is_reserved(&keyword_str, reserved)
+        {
+            continue;
+        }
+        if !is_code_taken(&keyword_str, case, &mut *conn).await? {
+            short_url = keyword_str;
             break;
         }
     }
 
     // Checking if there is a successful URL generated from uuid and generating random if there are
-    // collisions
-    if short_url.is_empty() {
-        loop {
-            short_url = Alphanumeric.sample_string(&mut thread_rng(), url_len);
-            let req_result = retrieve_url(&short_url, connection_pool).await;
-            // If there is a response that is empty (no long url) or error (there is no applicable
-            // row) then break from the loop (new url found isn't being used)
-            if req_result.as_ref().is_ok_and(|res_str| res_str.is_empty()) || req_result.is_err() {
-                break;
-            }
+    // collisions. The tries are bounded, since they collide more and more as the codes run out.
+    let mut tries = 0;
+    while short_url.is_empty() {
+        if tries == attempts {
+            return Err(UrlCreateError::Exhausted);
+        }
+        tries += 1;
+        let candidate = case.generated(Alphanumeric.sample_string(&mut thread_rng(), url_len));
+        if is_reserved(&candidate, reserved) {
+            continue;
+        }
+        // An error checking it counts as free, and the insert has the final say
+        if !matches!(is_code_taken(&candidate, case, &mut *conn).await, Ok(true)) {
+            short_url = candidate;
+        }
+    }
+
+    new_row.shorturl = short_url;
+    url_db_create(&mut new_row, &mut *conn).await?;
+
+    Ok(new_row)
+}
+
+/// Digits of sequential codes. Only the first 36, digits and lowercase letters, are used when
+/// case is ignored, since codes differing in case would be the same code.
+const CODE_DIGITS: &[u8; 62] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+/// `value` written with [CODE_DIGITS] and left padded with zeros to `len`, or None if it needs more
+/// than `len` digits
+fn sequential_code(value: u64, len: usize, case: SlugCase) -> Option<String> {
+    let base = match case {
+        SlugCase::Sensitive => 62,
+        SlugCase::Insensitive => 36,
+    };
+    let mut digits = Vec::new();
+    let mut rest = value;
+    loop {
+        digits.push(CODE_DIGITS[(rest % base) as usize]);
+        rest /= base;
+        if rest == 0 {
+            break;
         }
     }
+    if digits.len() > len {
+        return None;
+    }
+    digits.resize(len, b'0');
+    digits.reverse();
+    Some(String::from_utf8(digits).expect("CODE_DIGITS are ASCII"))
+}
 
+/// The code for the next value of the code sequence, or None once it needs more than `len` digits
+async fn next_sequential_code(
+    len: usize,
+    case: SlugCase,
+    db: impl PgExecutor<'_>,
+) -> Result<Option<String>, sqlx::Error> {
+    let value = sqlx::query_scalar!(r#"SELECT nextval('urls_code_seq') AS "value!""#)
+        .fetch_one(db)
+        .await?;
+    Ok(sequential_code(value as u64, len, case))
+}
+
+/// Creates a UrlRow with a caller chosen short url instead of a generated one. The short url is
+/// assumed to be unused; the unique constraint on the table rejects it otherwise.
+pub async fn create_url_with_alias(
+    alias: &str,
+    long_url: &str,
+    user_id: Option<i64>,
+    db: impl PgExecutor<'_>,
+    options: &UrlOptions,
+) -> Result<UrlRow, sqlx::Error> {
     let mut new_row = UrlRow {
         id: -1,
-        shorturl: short_url.clone(),
+        shorturl: alias.to_string(),
         longurl: long_url.to_string(),
         created_by: user_id,
         clicks: 0,
+        interstitial_seconds: options.interstitial_seconds,
+        single_use: Some(options.single_use),
+        consumed_at: None,
+        submitted_url: options.submitted_url.clone(),
+        expires_at: options.expires_at,
+        max_clicks: options.max_clicks,
+        passphrase_hash: options.passphrase_hash.clone(),
+        created_at: None,
+        redirect_status: options
+            .redirect_status
+            .map(|status| u16::from(status) as i16),
+        description: options.description.clone(),
+        deleted_at: None,
+        rotates: None,
+        geo_targeted: None,
+        active_from: options.active_from,
+        bot_clicks: Some(0),
+        rollout_until: None,
     };
 
-    new_row.id = url_db_create(&new_row, connection_pool).await?;
+    url_db_create(&mut new_row, db).await?;
 
     Ok(new_row)
 }
 
-/// Retrieves a Long Url from the database from a Short Url. This is a more efficient function than
-/// retriving the object because the filtering is done on the PostgreSQL server.
-pub async fn retrieve_url(
-    url: &str,
-    pool: &sqlx::PgPool,
-) -> Result<std::string::String, sqlx::Error> {
-    let response = sqlx::query_scalar("SELECT longurl FROM urls WHERE shorturl = $1")
-        .bind(url)
-        .fetch_one(pool)
-        .await?;
-    Ok(response)
+/// Points an existing row at a new long url. Clicks and the short url are kept. `submitted_url`
+/// replaces the old one, see [UrlOptions::submitted_url]. Returns the updated row, or
+/// sqlx::Error::RowNotFound if there is no row with `id`.
+pub async fn update_url_destination(
+    id: i64,
+    new_long_url: &str,
+    submitted_url: Option<&str>,
+    db: impl PgExecutor<'_>,
+) -> Result<UrlRow, sqlx::Error> {
+    sqlx::query_as!(
+        UrlRow,
+        r#"UPDATE urls SET longurl = $1, submitted_url = $2 WHERE id = $3
+        RETURNING id, shorturl, longurl, created_by,
+            urls.clicks + (SELECT COALESCE(SUM(clicks), 0) FROM url_click_shards
+                WHERE url_id = urls.id)::BIGINT AS "clicks!",
+            interstitial_seconds, single_use, consumed_at, submitted_url, expires_at,
+            max_clicks, passphrase_hash, created_at, redirect_status, description,
+            deleted_at, rotates, geo_targeted, active_from, bot_clicks, rollout_until"#,
+        new_long_url,
+        submitted_url,
+        id
+    )
+    .fetch_one(db)
+    .await
 }
 
-pub async fn incr_url_clicks(row: &mut UrlRow, pool: &sqlx::PgPool) {
-    row.incr_click();
-    sqlx::query(
-        "UPDATE urls
-        SET clicks = clicks + 1
-        WHERE id = $1",
+/// Replaces the description of a link, None removing it. Returns the updated row, or
+/// sqlx::Error::RowNotFound if there is no row with `id`.
+pub async fn update_url_description(
+    id: i64,
+    description: Option<&str>,
+    db: impl PgExecutor<'_>,
+) -> Result<UrlRow, sqlx::Error> {
+    sqlx::query_as!(
+        UrlRow,
+        r#"UPDATE urls SET description = $1 WHERE id = $2
+        RETURNING id, shorturl, longurl, created_by,
+            urls.clicks + (SELECT COALESCE(SUM(clicks), 0) FROM url_click_shards
+                WHERE url_id = urls.id)::BIGINT AS "clicks!",
+            interstitial_seconds, single_use, consumed_at, submitted_url, expires_at,
+            max_clicks, passphrase_hash, created_at, redirect_status, description,
+            deleted_at, rotates, geo_targeted, active_from, bot_clicks, rollout_until"#,
+        description,
+        id
     )
-    .bind(row.id())
-    .execute(pool)
+    .fetch_one(db)
     .await
-    .unwrap();
 }
 
-/// Deletes a url entry in the databse by id. Returns a sqlx::PgQueryResult on success and
-/// sqlx::Error on failure
-pub async fn delete_url(id: i64, pool: &sqlx::PgPool) -> Result<PgQueryResult, sqlx::Error> {
-    sqlx::query("DELETE FROM urls WHERE id = $1")
-        .bind(id)
-        .execute(pool)
-        .await
+/// Sets when a link stops redirecting. Returns the updated row, or sqlx::Error::RowNotFound if
+/// there is no row with `id`.
+pub async fn update_url_expiry(
+    id: i64,
+    expires_at: DateTime<Utc>,
+    db: impl PgExecutor<'_>,
+) -> Result<UrlRow, sqlx::Error> {
+    sqlx::query_as!(
+        UrlRow,
+        r#"UPDATE urls SET expires_at = $1 WHERE id = $2
+        RETURNING id, shorturl, longurl, created_by,
+            urls.clicks + (SELECT COALESCE(SUM(clicks), 0) FROM url_click_shards
+                WHERE url_id = urls.id)::BIGINT AS "clicks!",
+            interstitial_seconds, single_use, consumed_at, submitted_url, expires_at,
+            max_clicks, passphrase_hash, created_at, redirect_status, description,
+            deleted_at, rotates, geo_targeted, active_from, bot_clicks, rollout_until"#,
+        expires_at,
+        id
+    )
+    .fetch_one(db)
+    .await
 }
 
-/// Retrieve a UrlRow object WHERE shorturl = $url
-/// This will return a UrlRow, or a sqlx::Error upon failure
-pub async fn retrieve_url_obj(url: &str, pool: &sqlx::PgPool) -> Result<UrlRow, sqlx::Error> {
-    let response: UrlRow = sqlx::query_as("SELECT * FROM urls WHERE shorturl = $1")
-        .bind(url)
-        .fetch_one(pool)
-        .await?;
-    Ok(response)
+/// Sets the status a link redirects with instead of the instance's. Returns the updated row, or
+/// sqlx::Error::RowNotFound if there is no row with `id`.
+pub async fn update_url_redirect_status(
+    id: i64,
+    status: RedirectStatus,
+    db: impl PgExecutor<'_>,
+) -> Result<UrlRow, sqlx::Error> {
+    sqlx::query_as!(
+        UrlRow,
+        r#"UPDATE urls SET redirect_status = $1 WHERE id = $2
+        RETURNING id, shorturl, longurl, created_by,
+            urls.clicks + (SELECT COALESCE(SUM(clicks), 0) FROM url_click_shards
+                WHERE url_id = urls.id)::BIGINT AS "clicks!",
+            interstitial_seconds, single_use, consumed_at, submitted_url, expires_at,
+            max_clicks, passphrase_hash, created_at, redirect_status, description,
+            deleted_at, rotates, geo_targeted, active_from, bot_clicks, rollout_until"#,
+        u16::from(status) as i16,
+        id
+    )
+    .fetch_one(db)
+    .await
 }
 
-/// Creates a long string from which we can use to create a short url
-fn gen_url_longword(long_url: &str) -> Vec<u8> {
-    let long_word = general_purpose::STANDARD_NO_PAD.encode(long_url.as_bytes());
-    return Vec::from(long_word.as_bytes());
+/// Hands a link to `owner`, who can manage it from then on instead of `previous`, its owner when
+/// it was looked up. Fails with [sqlx::Error::RowNotFound] if it has changed hands or been archived
+/// or deleted since, so of concurrent transfers only the first goes through.
+pub async fn update_url_owner(
+    id: i64,
+    owner: i64,
+    previous: Option<i64>,
+    db: impl PgExecutor<'_>,
+) -> Result<UrlRow, sqlx::Error> {
+    sqlx::query_as!(
+        UrlRow,
+        r#"UPDATE urls SET created_by = $1
+        WHERE id = $2 AND created_by IS NOT DISTINCT FROM $3 AND deleted_at IS NULL
+        RETURNING id, shorturl, longurl, created_by,
+            urls.clicks + (SELECT COALESCE(SUM(clicks), 0) FROM url_click_shards
+                WHERE url_id = urls.id)::BIGINT AS "clicks!",
+            interstitial_seconds, single_use, consumed_at, submitted_url, expires_at,
+            max_clicks, passphrase_hash, created_at, redirect_status, description,
+            deleted_at, rotates, geo_targeted, active_from, bot_clicks, rollout_until"#,
+        owner,
+        id,
+        previous
+    )
+    .fetch_one(db)
+    .await
 }
 
-/// Creates the UrlRow object in the PostgreSQL database and returns the id of the newly created
-/// row
-async fn url_db_create(new_row: &UrlRow, pool: &sqlx::PgPool) -> Result<i64, sqlx::Error> {
-    sqlx::query("INSERT INTO urls (shorturl, longurl, created_by, clicks) VALUES ($1, $2, $3, 0)")
-        .bind(new_row.shorturl.clone())
-        .bind(new_row.longurl.clone())
-        .bind(new_row.created_by)
-        .execute(pool)
-        .await?;
+/// Most destinations one link can rotate between
+pub const MAX_DESTINATIONS: usize = 10;
+/// Largest weight a destination can have. Weights are relative, so this only bounds the ratios.
+pub const MAX_WEIGHT: i32 = 1000;
 
-    let new_id = retrieve_url_obj(new_row.shorturl.as_str(), pool).await?.id;
-    Ok(new_id)
+/// One of the destinations a rotating link sends visitors to, picked in proportion to its weight
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Destination {
+    #[serde(skip)]
+    pub id: i64,
+    pub long_url: String,
+    pub weight: i32,
+    /// Clicks the link sent here
+    pub clicks: i64,
 }
 
-#[cfg(test)]
-mod tests {
-    use std::env;
+/// A destination as it is given for [set_destinations]
+#[derive(Debug, Clone, PartialEq)]
+pub struct WeightedUrl {
+    pub long_url: String,
+    pub weight: i32,
+}
 
-    use sqlx::{postgres::PgPoolOptions, PgPool};
-    use tracing::debug;
+/// Makes `destinations` the ones the link rotates between, in that order, and points its long url
+/// at the first. Destinations already there keep their clicks. An empty list stops the link
+/// rotating and leaves its long url as it is. Either way a rollout the link was in ends, see
+/// [start_rollout]. Run it in the transaction making the link. Returns
+/// the updated row, or sqlx::Error::RowNotFound if there is no row with `id`.
+pub async fn set_destinations(
+    id: i64,
+    destinations: &[WeightedUrl],
+    conn: &mut PgConnection,
+) -> Result<UrlRow, sqlx::Error> {
+    let urls: Vec<&str> = destinations.iter().map(|d| d.long_url.as_str()).collect();
+    let weights: Vec<i32> = destinations.iter().map(|d| d.weight).collect();
+    sqlx::query!(
+        "DELETE FROM url_destinations WHERE url_id = $1 AND NOT (longurl = ANY($2))",
+        id,
+        &urls as &[&str]
+    )
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query!(
+        "UPDATE url_destinations SET position = given.position, weight = given.weight
+        FROM UNNEST($2::text[], $3::int[]) WITH ORDINALITY AS given(longurl, weight, position)
+        WHERE url_id = $1 AND url_destinations.longurl = given.longurl",
+        id,
+        &urls as &[&str],
+        &weights
+    )
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO url_destinations (url_id, position, longurl, weight)
+        SELECT $1, given.position, given.longurl, given.weight
+        FROM UNNEST($2::text[], $3::int[]) WITH ORDINALITY AS given(longurl, weight, position)
+        WHERE NOT EXISTS (
+            SELECT 1 FROM url_destinations WHERE url_id = $1 AND longurl = given.longurl
+        )",
+        id,
+        &urls as &[&str],
+        &weights
+    )
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query_as!(
+        UrlRow,
+        r#"UPDATE urls SET rotates = $2, longurl = COALESCE($3, longurl), rollout_until = NULL
+        WHERE id = $1
+        RETURNING id, shorturl, longurl, created_by,
+            urls.clicks + (SELECT COALESCE(SUM(clicks), 0) FROM url_click_shards
+                WHERE url_id = urls.id)::BIGINT AS "clicks!",
+            interstitial_seconds, single_use, consumed_at, submitted_url, expires_at,
+            max_clicks, passphrase_hash, created_at, redirect_status, description,
+            deleted_at, rotates, geo_targeted, active_from, bot_clicks, rollout_until"#,
+        id,
+        (!destinations.is_empty()).then_some(true),
+        urls.first().copied()
+    )
+    .fetch_one(&mut *conn)
+    .await
+}
 
-    use crate::preferences::Preferences;
+/// The destinations a link rotates between, in order. Empty for links that don't.
+pub async fn destinations(
+    url_id: i64,
+    db: impl PgExecutor<'_>,
+) -> Result<Vec<Destination>, sqlx::Error> {
+    sqlx::query_as!(
+        Destination,
+        "SELECT id, longurl AS long_url, weight, clicks FROM url_destinations
+        WHERE url_id = $1
+        ORDER BY position",
+        url_id
+    )
+    .fetch_all(db)
+    .await
+}
 
-    use super::*;
+/// Picks one of `destinations` at random, each in proportion to its weight. None when there are
+/// none to pick from.
+pub fn pick_destination<'a>(
+    destinations: &'a [Destination],
+    rng: &mut impl Rng,
+) -> Option<&'a Destination> {
+    let weights = WeightedIndex::new(destinations.iter().map(|d| d.weight.max(1))).ok()?;
+    destinations.get(weights.sample(rng))
+}
 
-    async fn pool_init() -> (PgPool, Preferences) {
-        eprintln!("Current dir: {:#?}", env::current_dir().unwrap());
-        let prefs =
-            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
-        let conn_url = format!(
-            "postgres://{}:{}@172.17.0.2/{}",
-            prefs.db_user(),
-            prefs.db_pass(),
-            prefs.db_name(),
-        );
-        let pool = PgPoolOptions::new()
-            .max_connections(prefs.db_pool_size())
-            .connect(&conn_url)
-            .await
-            .expect("Couldn't create connection pool. Are your credentials correct?");
+/// Largest share of visitors, in percent, a rollout sends to the new destination. Sending all of
+/// them there is what [finish_rollout] does.
+pub const MAX_ROLLOUT_PERCENT: i32 = 99;
 
-        sqlx::migrate!("./migrations")
-            .run(&pool)
-            .await
-            .unwrap_or_else(|_| debug!("Migration already exists, skipping"));
+/// The two destinations of a link rolling `new` out to `percent` of its visitors, with `old`
+/// getting the rest. `old` comes first, so it stays the link's long url until the rollout is
+/// finished.
+pub fn rollout_arms(old: &str, new: &str, percent: i32) -> [WeightedUrl; 2] {
+    [
+        WeightedUrl {
+            long_url: old.to_string(),
+            weight: 100 - percent,
+        },
+        WeightedUrl {
+            long_url: new.to_string(),
+            weight: percent,
+        },
+    ]
+}
 
-        return (pool, prefs);
-    }
+/// Splits a link's visitors between the two `arms` from [rollout_arms] until `until`, when
+/// [finish_rollout] sends them all to the second. Starting one on a link already rolling one out
+/// changes the share, or what it rolls out, and each destination kept keeps its clicks. Run it in
+/// the transaction making the change. Returns the updated row, or sqlx::Error::RowNotFound if
+/// there is no row with `id`.
+pub async fn start_rollout(
+    id: i64,
+    arms: &[WeightedUrl],
+    until: DateTime<Utc>,
+    conn: &mut PgConnection,
+) -> Result<UrlRow, sqlx::Error> {
+    set_destinations(id, arms, conn).await?;
+    sqlx::query_as!(
+        UrlRow,
+        r#"UPDATE urls SET rollout_until = $2 WHERE id = $1
+        RETURNING id, shorturl, longurl, created_by,
+            urls.clicks + (SELECT COALESCE(SUM(clicks), 0) FROM url_click_shards
+                WHERE url_id = urls.id)::BIGINT AS "clicks!",
+            interstitial_seconds, single_use, consumed_at, submitted_url, expires_at,
+            max_clicks, passphrase_hash, created_at, redirect_status, description,
+            deleted_at, rotates, geo_targeted, active_from, bot_clicks, rollout_until"#,
+        id,
+        until
+    )
+    .fetch_one(&mut *conn)
+    .await
+}
 
-    async fn test_make_url() -> UrlRow {
-        let (pool, prefs) = pool_init().await;
-        let short_row: UrlRow = create_url("https://example.com", None, &pool, prefs.url_len())
-            .await
-            .unwrap();
+/// Sends everyone to the destination a link is rolling out, making it the long url, and stops
+/// the link rotating. Run it in the transaction making the change. Returns the updated row, or
+/// sqlx::Error::RowNotFound if there is no row with `id` rolling one out.
+pub async fn finish_rollout(id: i64, conn: &mut PgConnection) -> Result<UrlRow, sqlx::Error> {
+    let finished = sqlx::query_as!(
+        UrlRow,
+        r#"UPDATE urls SET rotates = NULL, rollout_until = NULL, submitted_url = NULL,
+            longurl = COALESCE((
+                SELECT d.longurl FROM url_destinations d WHERE d.url_id = $1
+                ORDER BY d.position DESC LIMIT 1
+            ), longurl)
+        WHERE id = $1 AND rollout_until IS NOT NULL
+        RETURNING id, shorturl, longurl, created_by,
+            urls.clicks + (SELECT COALESCE(SUM(clicks), 0) FROM url_click_shards
+                WHERE url_id = urls.id)::BIGINT AS "clicks!",
+            interstitial_seconds, single_use, consumed_at, submitted_url, expires_at,
+            max_clicks, passphrase_hash, created_at, redirect_status, description,
+            deleted_at, rotates, geo_targeted, active_from, bot_clicks, rollout_until"#,
+        id
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    sqlx::query!("DELETE FROM url_destinations WHERE url_id = $1", id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(finished)
+}
 
-        println!("{:#?}", short_row);
+/// The links whose rollout should have finished by `now`
+pub async fn due_rollouts(
+    now: DateTime<Utc>,
+    db: impl PgExecutor<'_>,
+) -> Result<Vec<i64>, sqlx::Error> {
+    sqlx::query_scalar!(
+        "SELECT id FROM urls WHERE rollout_until <= $1 AND deleted_at IS NULL ORDER BY id",
+        now
+    )
+    .fetch_all(db)
+    .await
+}
 
-        assert_eq!(short_row.longurl, "https://example.com");
-        assert_eq!(short_row.created_by, None);
-        assert_eq!(short_row.clicks, 0);
-        return short_row;
-    }
+/// Most countries one link can send somewhere other than its long url
+pub const MAX_GEO_TARGETS: usize = 50;
 
-    async fn test_retrieve_url(test_short: UrlRow) {
-        let (pool, _) = pool_init().await;
+/// Makes `targets`, from country code to destination, the countries whose visitors the link sends
+/// somewhere other than its long url, replacing any it had. No targets sends every visitor to the
+/// long url again. Run it in the transaction making the change. Returns the updated row, or
+/// sqlx::Error::RowNotFound if there is no row with `id`.
+pub async fn set_geo_targets(
+    id: i64,
+    targets: &BTreeMap<String, String>,
+    conn: &mut PgConnection,
+) -> Result<UrlRow, sqlx::Error> {
+    let countries: Vec<&str> = targets.keys().map(String::as_str).collect();
+    let urls: Vec<&str> = targets.values().map(String::as_str).collect();
+    sqlx::query!("DELETE FROM url_geo_targets WHERE url_id = $1", id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query!(
+        "INSERT INTO url_geo_targets (url_id, country, longurl)
+        SELECT $1, given.country, given.longurl
+        FROM UNNEST($2::text[], $3::text[]) AS given(country, longurl)",
+        id,
+        &countries as &[&str],
+        &urls as &[&str]
+    )
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query_as!(
+        UrlRow,
+        r#"UPDATE urls SET geo_targeted = $2 WHERE id = $1
+        RETURNING id, shorturl, longurl, created_by,
+            urls.clicks + (SELECT COALESCE(SUM(clicks), 0) FROM url_click_shards
+                WHERE url_id = urls.id)::BIGINT AS "clicks!",
+            interstitial_seconds, single_use, consumed_at, submitted_url, expires_at,
+            max_clicks, passphrase_hash, created_at, redirect_status, description,
+            deleted_at, rotates, geo_targeted, active_from, bot_clicks, rollout_until"#,
+        id,
+        (!targets.is_empty()).then_some(true)
+    )
+    .fetch_one(&mut *conn)
+    .await
+}
 
-        let url_row: UrlRow = test_short;
-        assert_eq!(url_row.longurl, "https://example.com");
-        assert_eq!(url_row.created_by, None);
-        let url_row: String = retrieve_url(url_row.short_url().as_str(), &pool)
-            .await
-            .unwrap();
-        assert_eq!(url_row, "https://example.com");
-    }
+/// The destinations of a link by country code. Empty for links that aren't geo-targeted.
+pub async fn geo_targets(
+    url_id: i64,
+    db: impl PgExecutor<'_>,
+) -> Result<BTreeMap<String, String>, sqlx::Error> {
+    let rows = sqlx::query!(
+        "SELECT country, longurl FROM url_geo_targets WHERE url_id = $1",
+        url_id
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.country, row.longurl))
+        .collect())
+}
 
-    #[sqlx::test]
-    async fn test_make_and_retrieve() {
-        let row = test_make_url().await;
-        test_retrieve_url(row).await;
-    }
+/// Where a link sends visitors from `country`, None when that is its long url
+pub async fn geo_target(
+    url_id: i64,
+    country: &str,
+    db: impl PgExecutor<'_>,
+) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar!(
+        "SELECT longurl FROM url_geo_targets WHERE url_id = $1 AND country = $2",
+        url_id,
+        country
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// Clicks on a geo-targeted link per branch they took, see [crate::geo::DEFAULT_BRANCH]. Clicks
+/// from before the link was targeted aren't in it.
+pub async fn clicks_by_geo_branch(
+    url_id: i64,
+    db: impl PgExecutor<'_>,
+) -> Result<BTreeMap<String, i64>, sqlx::Error> {
+    let rows = sqlx::query!(
+        "SELECT branch, count FROM url_geo_clicks WHERE url_id = $1",
+        url_id
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.branch, row.count))
+        .collect())
+}
+
+/// Which links a query covers, by who created them. Every query that filters or groups on
+/// created_by takes one of these instead of comparing the column itself: `created_by = $1` is never
+/// true for a NULL owner, so anonymous links would silently drop out of anything written that way.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OwnerFilter {
+    User(i64),
+    /// Links without an owner, whether made while signed out or left behind by a deleted user
+    Anonymous,
+    Any,
+}
+
+impl OwnerFilter {
+    /// Whether a link with this owner is covered, for checks done outside the database
+    pub fn matches(self, created_by: Option<i64>) -> bool {
+        match self {
+            OwnerFilter::User(id) => created_by == Some(id),
+            OwnerFilter::Anonymous => created_by.is_none(),
+            OwnerFilter::Any => true,
+        }
+    }
+    /// Parameters for the `($1 OR created_by = $2 OR ($2 IS NULL AND created_by IS NULL))` clause
+    /// every owner filtered query uses: whether to take every row, and the owner to match with
+    /// None meaning anonymous.
+    fn params(self) -> (bool, Option<i64>) {
+        match self {
+            OwnerFilter::User(id) => (false, Some(id)),
+            OwnerFilter::Anonymous => (false, None),
+            OwnerFilter::Any => (true, None),
+        }
+    }
+}
+
+/// Link count and total clicks for one owner. `owner` is None for the anonymous links, which are
+/// always reported as a single group.
+#[allow(dead_code)]
+#[derive(Debug, PartialEq)]
+pub struct OwnerTotals {
+    pub owner: Option<i64>,
+    pub links: i64,
+    pub clicks: i64,
+}
+
+/// Retrieves every UrlRow the filter covers, ordered by short url
+pub async fn retrieve_urls_by_owner(
+    owner: OwnerFilter,
+    db: impl PgExecutor<'_>,
+) -> Result<Vec<UrlRow>, sqlx::Error> {
+    let (any, created_by) = owner.params();
+    sqlx::query_as!(
+        UrlRow,
+        r#"SELECT id, shorturl, longurl, created_by,
+            urls.clicks + (SELECT COALESCE(SUM(clicks), 0) FROM url_click_shards
+                WHERE url_id = urls.id)::BIGINT AS "clicks!",
+            interstitial_seconds, single_use, consumed_at, submitted_url, expires_at,
+            max_clicks, passphrase_hash, created_at, redirect_status, description,
+            deleted_at, rotates, geo_targeted, active_from, bot_clicks, rollout_until
+        FROM urls WHERE ($1 OR created_by = $2 OR ($2 IS NULL AND created_by IS NULL))
+        ORDER BY shorturl"#,
+        any,
+        created_by
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// One page of a user's links, newest first, only those tagged `tag` if one is given. `archived`
+/// lists the archived links instead of the live ones. Pages start at 1 and `per_page` is capped at
+/// [MAX_PAGE_SIZE]; out of range values are clamped rather than rejected.
+pub async fn list_urls_for_user(
+    user_id: i64,
+    tag: Option<&str>,
+    archived: bool,
+    page: i64,
+    per_page: i64,
+    db: impl PgExecutor<'_>,
+) -> Result<Vec<UrlRow>, sqlx::Error> {
+    let per_page = per_page.clamp(1, MAX_PAGE_SIZE);
+    let offset = (page.max(1) - 1).saturating_mul(per_page);
+    sqlx::query_as!(
+        UrlRow,
+        r#"SELECT id, shorturl, longurl, created_by,
+            urls.clicks + (SELECT COALESCE(SUM(clicks), 0) FROM url_click_shards
+                WHERE url_id = urls.id)::BIGINT AS "clicks!",
+            interstitial_seconds, single_use, consumed_at, submitted_url, expires_at,
+            max_clicks, passphrase_hash, created_at, redirect_status, description,
+            deleted_at, rotates, geo_targeted, active_from, bot_clicks, rollout_until
+        FROM urls
+        WHERE created_by = $1
+            AND ($4::text IS NULL
+                OR EXISTS (SELECT 1 FROM url_tags WHERE url_id = urls.id AND tag = $4))
+            AND (deleted_at IS NOT NULL) = $5
+        ORDER BY id DESC
+        LIMIT $2 OFFSET $3"#,
+        user_id,
+        per_page,
+        offset,
+        tag,
+        archived
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Finds a link among the ones the filter covers that goes to exactly `long_url` and just
+/// redirects, so the same destination isn't shortened twice. Destinations are compared as stored,
+/// so pass the normalized form.
+pub async fn find_duplicate(
+    long_url: &str,
+    owner: OwnerFilter,
+    db: impl PgExecutor<'_>,
+) -> Result<Option<UrlRow>, sqlx::Error> {
+    let (any, created_by) = owner.params();
+    sqlx::query_as!(
+        UrlRow,
+        r#"SELECT id, shorturl, longurl, created_by,
+            urls.clicks + (SELECT COALESCE(SUM(clicks), 0) FROM url_click_shards
+                WHERE url_id = urls.id)::BIGINT AS "clicks!",
+            interstitial_seconds, single_use, consumed_at, submitted_url, expires_at,
+            max_clicks, passphrase_hash, created_at, redirect_status, description,
+            deleted_at, rotates, geo_targeted, active_from, bot_clicks, rollout_until
+        FROM urls
+        WHERE longurl = $1
+            AND ($2 OR created_by = $3 OR ($3 IS NULL AND created_by IS NULL))
+            AND single_use IS NOT TRUE AND COALESCE(interstitial_seconds, 0) = 0
+            AND expires_at IS NULL AND active_from IS NULL AND max_clicks IS NULL
+            AND passphrase_hash IS NULL
+            AND redirect_status IS NULL AND description IS NULL AND deleted_at IS NULL
+            AND rotates IS NOT TRUE AND geo_targeted IS NOT TRUE
+        ORDER BY id LIMIT 1"#,
+        long_url,
+        any,
+        created_by
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// Counts the links the filter covers, only those tagged `tag` if one is given and the archived
+/// ones instead of the live ones with `archived`
+pub async fn count_urls(
+    owner: OwnerFilter,
+    tag: Option<&str>,
+    archived: bool,
+    db: impl PgExecutor<'_>,
+) -> Result<i64, sqlx::Error> {
+    let (any, created_by) = owner.params();
+    sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM urls
+        WHERE ($1 OR created_by = $2 OR ($2 IS NULL AND created_by IS NULL))
+            AND ($3::text IS NULL
+                OR EXISTS (SELECT 1 FROM url_tags WHERE url_id = urls.id AND tag = $3))
+            AND (deleted_at IS NOT NULL) = $4"#,
+        any,
+        created_by,
+        tag,
+        archived
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// Links and clicks per owner for the links the filter covers, anonymous links first
+#[allow(dead_code)]
+pub async fn owner_totals(
+    owner: OwnerFilter,
+    db: impl PgExecutor<'_>,
+) -> Result<Vec<OwnerTotals>, sqlx::Error> {
+    let (any, created_by) = owner.params();
+    sqlx::query_as!(
+        OwnerTotals,
+        r#"SELECT created_by AS owner, COUNT(*) AS "links!",
+            SUM(urls.clicks + COALESCE(shards.clicks, 0))::BIGINT AS "clicks!"
+        FROM urls LEFT JOIN (
+            SELECT url_id, SUM(clicks) AS clicks FROM url_click_shards GROUP BY url_id
+        ) shards ON shards.url_id = urls.id
+        WHERE ($1 OR created_by = $2 OR ($2 IS NULL AND created_by IS NULL))
+        GROUP BY created_by
+        ORDER BY created_by NULLS FIRST"#,
+        any,
+        created_by
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Retrieves the UrlRows using any of the given short urls
+pub async fn retrieve_url_objs(
+    urls: &[String],
+    db: impl PgExecutor<'_>,
+) -> Result<Vec<UrlRow>, sqlx::Error> {
+    sqlx::query_as!(
+        UrlRow,
+        r#"SELECT id, shorturl, longurl, created_by,
+            urls.clicks + (SELECT COALESCE(SUM(clicks), 0) FROM url_click_shards
+                WHERE url_id = urls.id)::BIGINT AS "clicks!",
+            interstitial_seconds, single_use, consumed_at, submitted_url, expires_at,
+            max_clicks, passphrase_hash, created_at, redirect_status, description,
+            deleted_at, rotates, geo_targeted, active_from, bot_clicks, rollout_until
+        FROM urls WHERE shorturl = ANY($1)"#,
+        urls
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Retrieves a Long Url from the database from a Short Url. This is a more efficient function than
+/// retriving the object because the filtering is done on the PostgreSQL server. Archived links
+/// aren't found.
+#[allow(dead_code)]
+pub async fn retrieve_url(
+    url: &str,
+    case: SlugCase,
+    db: impl PgExecutor<'_>,
+) -> Result<std::string::String, sqlx::Error> {
+    let response = match case {
+        SlugCase::Sensitive => {
+            sqlx::query_scalar!(
+                "SELECT longurl FROM urls WHERE shorturl = $1 AND deleted_at IS NULL",
+                url
+            )
+            .fetch_one(db)
+            .await?
+        }
+        SlugCase::Insensitive => {
+            sqlx::query_scalar!(
+                "SELECT longurl FROM urls WHERE LOWER(shorturl) = LOWER($1) AND deleted_at IS NULL
+                ORDER BY shorturl = $1 DESC, id LIMIT 1",
+                url
+            )
+            .fetch_one(db)
+            .await?
+        }
+    };
+    Ok(response)
+}
+
+/// Whether a link uses `code`, or one differing from it only in case as `case` allows. Archived
+/// links keep their code until they are purged, so they count.
+pub async fn is_code_taken(
+    code: &str,
+    case: SlugCase,
+    db: impl PgExecutor<'_>,
+) -> Result<bool, sqlx::Error> {
+    match case {
+        SlugCase::Sensitive => {
+            sqlx::query_scalar!(
+                r#"SELECT EXISTS (SELECT 1 FROM urls WHERE shorturl = $1) AS "taken!""#,
+                code
+            )
+            .fetch_one(db)
+            .await
+        }
+        SlugCase::Insensitive => {
+            sqlx::query_scalar!(
+                r#"SELECT EXISTS (SELECT 1 FROM urls WHERE LOWER(shorturl) = LOWER($1))
+                    AS "taken!""#,
+                code
+            )
+            .fetch_one(db)
+            .await
+        }
+    }
+}
+
+/// Counts a click on the link unless it has used up its click limit. The check and the increment
+/// are one statement, so concurrent visits can't take it past the limit. The clicks of hot links,
+/// see [crate::hot_links], go to one of their shards picked at random instead, as long as they
+/// have no limit. The same statement adds the click to [clicks_by_day], [top_referrers],
+/// [clicks_by_device] and the destination it went to, and remembers the visitor for
+/// [crate::conversion::record] when there is one. Returns whether the click was counted.
+pub async fn incr_url_clicks(
+    row: &mut UrlRow,
+    source: &ClickSource,
+    db: impl PgExecutor<'_>,
+) -> Result<bool, sqlx::Error> {
+    let counted = sqlx::query_scalar!(
+        r#"WITH sharded AS (
+            UPDATE url_click_shards SET clicks = clicks + 1
+            WHERE url_id = $1
+                AND shard = $7 % (SELECT max(shard) + 1 FROM url_click_shards WHERE url_id = $1)
+                AND NOT EXISTS (SELECT 1 FROM urls WHERE id = $1 AND max_clicks IS NOT NULL)
+            RETURNING url_id AS id
+        ), unsharded AS (
+            UPDATE urls
+            SET clicks = clicks + 1
+            WHERE id = $1 AND NOT EXISTS (SELECT 1 FROM sharded)
+                AND (max_clicks IS NULL OR clicks + (
+                    SELECT COALESCE(SUM(clicks), 0) FROM url_click_shards WHERE url_id = $1
+                ) < max_clicks)
+            RETURNING id
+        ), counted AS (
+            SELECT id FROM sharded UNION ALL SELECT id FROM unsharded
+        ), daily AS (
+            INSERT INTO url_clicks_daily (url_id, day, count)
+            SELECT id, (now() AT TIME ZONE 'UTC')::date, 1 FROM counted
+            ON CONFLICT (url_id, day) DO UPDATE SET count = url_clicks_daily.count + 1
+        ), referred AS (
+            INSERT INTO url_referrers (url_id, referrer, count)
+            SELECT id, $2, 1 FROM counted
+            ON CONFLICT (url_id, referrer) DO UPDATE SET count = url_referrers.count + 1
+        ), visited AS (
+            INSERT INTO url_click_visitors (url_id, visitor)
+            SELECT id, $3::text FROM counted WHERE $3::text IS NOT NULL
+        ), devices AS (
+            INSERT INTO url_devices (url_id, device, count)
+            SELECT id, $4::text, 1 FROM counted WHERE $4::text IS NOT NULL
+            ON CONFLICT (url_id, device) DO UPDATE SET count = url_devices.count + 1
+        ), rotated AS (
+            UPDATE url_destinations SET clicks = url_destinations.clicks + 1
+            FROM counted WHERE url_destinations.id = $5 AND url_destinations.url_id = counted.id
+        ), targeted AS (
+            INSERT INTO url_geo_clicks (url_id, branch, count)
+            SELECT id, $6::text, 1 FROM counted WHERE $6::text IS NOT NULL
+            ON CONFLICT (url_id, branch) DO UPDATE SET count = url_geo_clicks.count + 1
+        )
+        SELECT EXISTS (SELECT 1 FROM counted) AS "counted!""#,
+        row.id(),
+        source.referrer,
+        source.visitor.as_deref(),
+        source.device.map(Device::as_str),
+        source.destination,
+        source.geo_branch.as_deref(),
+        i32::from(rand::random::<u16>())
+    )
+    .fetch_one(db)
+    .await?;
+    if counted {
+        row.incr_click();
+    }
+    Ok(counted)
+}
+
+/// Counts a visit from a bot, see [crate::user_agent::is_bot], apart from the link's clicks. Bots
+/// don't use up a click limit and aren't in the daily, referrer or destination counts, only in
+/// [clicks_by_device]. They are still turned away once humans have used the limit up, checked in
+/// the same statement as for [incr_url_clicks]. Returns whether the visit was counted.
+pub async fn incr_bot_clicks(id: i64, db: impl PgExecutor<'_>) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"WITH counted AS (
+            UPDATE urls SET bot_clicks = COALESCE(bot_clicks, 0) + 1
+            WHERE id = $1 AND (max_clicks IS NULL OR clicks + (
+                SELECT COALESCE(SUM(clicks), 0) FROM url_click_shards WHERE url_id = $1
+            ) < max_clicks)
+            RETURNING id
+        ), devices AS (
+            INSERT INTO url_devices (url_id, device, count)
+            SELECT id, $2, 1 FROM counted
+            ON CONFLICT (url_id, device) DO UPDATE SET count = url_devices.count + 1
+        )
+        SELECT EXISTS (SELECT 1 FROM counted) AS "counted!""#,
+        id,
+        Device::Bot.as_str()
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// The database's clock. Links scheduled ahead of time go live by it rather than by the clock of
+/// whichever server handles the visit, so they all agree.
+pub async fn database_now(db: impl PgExecutor<'_>) -> Result<DateTime<Utc>, sqlx::Error> {
+    sqlx::query_scalar!(r#"SELECT now() AS "now!""#)
+        .fetch_one(db)
+        .await
+}
+
+/// Marks a single use link as consumed and counts the click, returning its destination. Returns
+/// None if it was already consumed or has expired since it was looked up, so of any number of
+/// concurrent claims at most one succeeds.
+pub async fn claim_single_use(
+    id: i64,
+    source: &ClickSource,
+    db: impl PgExecutor<'_>,
+) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"WITH claimed AS (
+            UPDATE urls SET consumed_at = now(), clicks = clicks + 1
+            WHERE id = $1 AND consumed_at IS NULL AND (expires_at IS NULL OR expires_at > now())
+                AND (active_from IS NULL OR active_from <= now())
+            RETURNING id, longurl
+        ), daily AS (
+            INSERT INTO url_clicks_daily (url_id, day, count)
+            SELECT id, (now() AT TIME ZONE 'UTC')::date, 1 FROM claimed
+            ON CONFLICT (url_id, day) DO UPDATE SET count = url_clicks_daily.count + 1
+        ), referred AS (
+            INSERT INTO url_referrers (url_id, referrer, count)
+            SELECT id, $2, 1 FROM claimed
+            ON CONFLICT (url_id, referrer) DO UPDATE SET count = url_referrers.count + 1
+        ), visited AS (
+            INSERT INTO url_click_visitors (url_id, visitor)
+            SELECT id, $3::text FROM claimed WHERE $3::text IS NOT NULL
+        ), devices AS (
+            INSERT INTO url_devices (url_id, device, count)
+            SELECT id, $4::text, 1 FROM claimed WHERE $4::text IS NOT NULL
+            ON CONFLICT (url_id, device) DO UPDATE SET count = url_devices.count + 1
+        )
+        SELECT longurl AS "longurl!" FROM claimed"#,
+        id,
+        source.referrer,
+        source.visitor.as_deref(),
+        source.device.map(Device::as_str)
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// Clicks on one day, in UTC
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DailyClicks {
+    pub day: NaiveDate,
+    pub count: i64,
+    /// The day is still to be recomputed by a [crate::rollup] rebuild, so the count may be off
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub rebuilding: bool,
+}
+
+/// Clicks on a link for every day from `from` to `to`, both included and in order. Days without
+/// clicks are there with a count of 0.
+pub async fn clicks_by_day(
+    url_id: i64,
+    from: NaiveDate,
+    to: NaiveDate,
+    db: impl PgExecutor<'_>,
+) -> Result<Vec<DailyClicks>, sqlx::Error> {
+    sqlx::query_as!(
+        DailyClicks,
+        r#"SELECT d::date AS "day!", COALESCE(c.count, 0) AS "count!",
+            EXISTS (
+                SELECT 1 FROM rollup_rebuilds r
+                WHERE r.finished_at IS NULL AND d::date BETWEEN r.next_day AND r.to_day
+                    AND (r.url_id IS NULL OR r.url_id = $1)
+                    AND (r.user_id IS NULL
+                        OR r.user_id = (SELECT created_by FROM urls WHERE id = $1))
+            ) AS "rebuilding!"
+        FROM generate_series($2::date, $3::date, interval '1 day') AS d
+        LEFT JOIN url_clicks_daily c ON c.url_id = $1 AND c.day = d::date
+        ORDER BY d"#,
+        url_id,
+        from,
+        to
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Clicks from one referring origin
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ReferrerClicks {
+    pub referrer: String,
+    pub count: i64,
+}
+
+/// The `limit` origins that sent the most clicks to a link, most first
+pub async fn top_referrers(
+    url_id: i64,
+    limit: i64,
+    db: impl PgExecutor<'_>,
+) -> Result<Vec<ReferrerClicks>, sqlx::Error> {
+    sqlx::query_as!(
+        ReferrerClicks,
+        "SELECT referrer, count FROM url_referrers
+        WHERE url_id = $1
+        ORDER BY count DESC, referrer
+        LIMIT $2",
+        url_id,
+        limit
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Clicks on a link per [Device], over its whole life. Every class is there, with 0 when no click
+/// came from one.
+pub async fn clicks_by_device(
+    url_id: i64,
+    db: impl PgExecutor<'_>,
+) -> Result<BTreeMap<&'static str, i64>, sqlx::Error> {
+    let counted = sqlx::query!(
+        "SELECT device, count FROM url_devices WHERE url_id = $1",
+        url_id
+    )
+    .fetch_all(db)
+    .await?;
+    let mut devices: BTreeMap<&'static str, i64> = Device::ALL
+        .iter()
+        .map(|device| (device.as_str(), 0))
+        .collect();
+    for row in counted {
+        if let Some(count) = devices.get_mut(row.device.as_str()) {
+            *count = row.count;
+        }
+    }
+    Ok(devices)
+}
+
+/// Deletes a url entry in the databse by id, with its clicks and history. Returns a
+/// sqlx::PgQueryResult on success and sqlx::Error on failure. Links are archived with
+/// [archive_url] unless an admin purges them.
+pub async fn delete_url(id: i64, db: impl PgExecutor<'_>) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!("DELETE FROM urls WHERE id = $1", id)
+        .execute(db)
+        .await
+}
+
+/// Archives a live link: it stops redirecting and drops out of listings, but keeps its clicks and
+/// its code until [restore_url] or a purge. Returns the archived row, or sqlx::Error::RowNotFound
+/// if there is no live row with `id`.
+pub async fn archive_url(id: i64, db: impl PgExecutor<'_>) -> Result<UrlRow, sqlx::Error> {
+    sqlx::query_as!(
+        UrlRow,
+        r#"UPDATE urls SET deleted_at = now() WHERE id = $1 AND deleted_at IS NULL
+        RETURNING id, shorturl, longurl, created_by,
+            urls.clicks + (SELECT COALESCE(SUM(clicks), 0) FROM url_click_shards
+                WHERE url_id = urls.id)::BIGINT AS "clicks!",
+            interstitial_seconds, single_use, consumed_at, submitted_url, expires_at,
+            max_clicks, passphrase_hash, created_at, redirect_status, description,
+            deleted_at, rotates, geo_targeted, active_from, bot_clicks, rollout_until"#,
+        id
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// Brings an archived link back as it was. Returns the restored row, or
+/// sqlx::Error::RowNotFound if there is no archived row with `id`.
+pub async fn restore_url(id: i64, db: impl PgExecutor<'_>) -> Result<UrlRow, sqlx::Error> {
+    sqlx::query_as!(
+        UrlRow,
+        r#"UPDATE urls SET deleted_at = NULL WHERE id = $1 AND deleted_at IS NOT NULL
+        RETURNING id, shorturl, longurl, created_by,
+            urls.clicks + (SELECT COALESCE(SUM(clicks), 0) FROM url_click_shards
+                WHERE url_id = urls.id)::BIGINT AS "clicks!",
+            interstitial_seconds, single_use, consumed_at, submitted_url, expires_at,
+            max_clicks, passphrase_hash, created_at, redirect_status, description,
+            deleted_at, rotates, geo_targeted, active_from, bot_clicks, rollout_until"#,
+        id
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// Archives the anonymous links that expired at least `grace` ago. Links with an owner stay until
+/// the owner deals with them. Returns how many were archived.
+pub async fn archive_expired_anonymous(
+    grace: Duration,
+    db: impl PgExecutor<'_>,
+) -> Result<u64, sqlx::Error> {
+    let grace_secs = i64::try_from(grace.as_secs()).unwrap_or(i64::MAX) as f64;
+    let archived = sqlx::query!(
+        "UPDATE urls SET deleted_at = now()
+        WHERE created_by IS NULL AND deleted_at IS NULL
+            AND expires_at <= now() - make_interval(secs => $1)",
+        grace_secs
+    )
+    .execute(db)
+    .await?;
+    Ok(archived.rows_affected())
+}
+
+/// The ids and codes of the links archived at least `retention` ago, oldest first. They are
+/// locked until the transaction ends, so they are still archived when [delete_urls] purges them.
+pub async fn archived_past(
+    retention: Duration,
+    db: impl PgExecutor<'_>,
+) -> Result<Vec<(i64, String)>, sqlx::Error> {
+    let retention_secs = i64::try_from(retention.as_secs()).unwrap_or(i64::MAX) as f64;
+    let rows = sqlx::query!(
+        "SELECT id, shorturl FROM urls WHERE deleted_at <= now() - make_interval(secs => $1)
+        ORDER BY deleted_at, id FOR UPDATE",
+        retention_secs
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows.into_iter().map(|row| (row.id, row.shorturl)).collect())
+}
+
+/// Deletes the links with `ids` for good, like [delete_url]. Returns how many were deleted.
+pub async fn delete_urls(ids: &[i64], db: impl PgExecutor<'_>) -> Result<u64, sqlx::Error> {
+    let deleted = sqlx::query!("DELETE FROM urls WHERE id = ANY($1)", ids)
+        .execute(db)
+        .await?;
+    Ok(deleted.rows_affected())
+}
+
+/// Retrieve a UrlRow object WHERE shorturl = $url, or differs from it only in case as `case` allows
+/// This will return a UrlRow, or a sqlx::Error upon failure. Archived links aren't found.
+pub async fn retrieve_url_obj(
+    url: &str,
+    case: SlugCase,
+    db: impl PgExecutor<'_>,
+) -> Result<UrlRow, sqlx::Error> {
+    let response = match case {
+        SlugCase::Sensitive => {
+            sqlx::query_as!(
+                UrlRow,
+                r#"SELECT id, shorturl, longurl, created_by,
+                    urls.clicks + (SELECT COALESCE(SUM(clicks), 0) FROM url_click_shards
+                        WHERE url_id = urls.id)::BIGINT AS "clicks!",
+                    interstitial_seconds, single_use, consumed_at, submitted_url, expires_at,
+                    max_clicks, passphrase_hash, created_at, redirect_status, description,
+                    deleted_at, rotates, geo_targeted, active_from, bot_clicks, rollout_until
+                FROM urls WHERE shorturl = $1 AND deleted_at IS NULL"#,
+                url
+            )
+            .fetch_one(db)
+            .await?
+        }
+        SlugCase::Insensitive => {
+            sqlx::query_as!(
+                UrlRow,
+                r#"SELECT id, shorturl, longurl, created_by,
+                    urls.clicks + (SELECT COALESCE(SUM(clicks), 0) FROM url_click_shards
+                        WHERE url_id = urls.id)::BIGINT AS "clicks!",
+                    interstitial_seconds, single_use, consumed_at, submitted_url, expires_at,
+                    max_clicks, passphrase_hash, created_at, redirect_status, description,
+                    deleted_at, rotates, geo_targeted, active_from, bot_clicks, rollout_until
+                FROM urls WHERE LOWER(shorturl) = LOWER($1) AND deleted_at IS NULL
+                ORDER BY shorturl = $1 DESC, id LIMIT 1"#,
+                url
+            )
+            .fetch_one(db)
+            .await?
+        }
+    };
+    Ok(response)
+}
+
+/// [retrieve_url_obj] for archived links, which are the only ones it finds
+pub async fn retrieve_archived_url_obj(
+    url: &str,
+    case: SlugCase,
+    db: impl PgExecutor<'_>,
+) -> Result<UrlRow, sqlx::Error> {
+    match case {
+        SlugCase::Sensitive => {
+            sqlx::query_as!(
+                UrlRow,
+                r#"SELECT id, shorturl, longurl, created_by,
+                    urls.clicks + (SELECT COALESCE(SUM(clicks), 0) FROM url_click_shards
+                        WHERE url_id = urls.id)::BIGINT AS "clicks!",
+                    interstitial_seconds, single_use, consumed_at, submitted_url, expires_at,
+                    max_clicks, passphrase_hash, created_at, redirect_status, description,
+                    deleted_at, rotates, geo_targeted, active_from, bot_clicks, rollout_until
+                FROM urls WHERE shorturl = $1 AND deleted_at IS NOT NULL"#,
+                url
+            )
+            .fetch_one(db)
+            .await
+        }
+        SlugCase::Insensitive => {
+            sqlx::query_as!(
+                UrlRow,
+                r#"SELECT id, shorturl, longurl, created_by,
+                    urls.clicks + (SELECT COALESCE(SUM(clicks), 0) FROM url_click_shards
+                        WHERE url_id = urls.id)::BIGINT AS "clicks!",
+                    interstitial_seconds, single_use, consumed_at, submitted_url, expires_at,
+                    max_clicks, passphrase_hash, created_at, redirect_status, description,
+                    deleted_at, rotates, geo_targeted, active_from, bot_clicks, rollout_until
+                FROM urls WHERE LOWER(shorturl) = LOWER($1) AND deleted_at IS NOT NULL
+                ORDER BY shorturl = $1 DESC, id LIMIT 1"#,
+                url
+            )
+            .fetch_one(db)
+            .await
+        }
+    }
+}
+
+/// Fills in a column on an existing table a batch at a time so no single UPDATE holds row locks on
+/// the hot tables for long. Columns on `urls` are added as nullable first, written by every insert,
+/// backfilled with this, and only then given constraints; see the lint in this module's tests.
+pub struct Backfill<'a> {
+    pub table: &'a str,
+    /// The column to fill in, on the rows where it is still NULL. Those stop matching once they
+    /// are done, which is what makes an interrupted run resumable.
+    pub column: &'a str,
+    /// SQL for the value, e.g. `old_col * 2`. Only ever a constant written here, never input.
+    pub value: &'static str,
+    pub batch_size: i64,
+    /// Time to wait between batches so other writers get a turn
+    pub pause: Duration,
+}
+
+/// The backfills [backfill_urls] runs, oldest first
+const URL_BACKFILLS: [Backfill<'static>; 1] = [
+    // Counted as 0 while NULL, see [UrlRow::bot_clicks]
+    Backfill {
+        table: "urls",
+        column: "bot_clicks",
+        value: "0",
+        batch_size: 1000,
+        pause: Duration::from_millis(100),
+    },
+];
+
+/// Runs the backfills on `urls` for links made before their columns were written on insert. Runs
+/// in the background from startup; every one is a no-op once done, so it is safe on each start
+/// and on every instance at once.
+pub async fn backfill_urls(pool: sqlx::PgPool) {
+    for backfill in &URL_BACKFILLS {
+        if let Err(e) = run_backfill(backfill, &pool).await {
+            warn!(
+                "Couldn't backfill {}.{}: {e}",
+                backfill.table, backfill.column
+            );
+        }
+    }
+}
+
+/// Runs a [Backfill] until no pending rows are left and returns how many rows it updated. It can
+/// be cancelled at any point and simply started again.
+pub async fn run_backfill(
+    backfill: &Backfill<'_>,
+    pool: &sqlx::PgPool,
+) -> Result<u64, sqlx::Error> {
+    let table = quoted_ident(backfill.table)?;
+    let column = quoted_ident(backfill.column)?;
+    let mut total = 0;
+    loop {
+        let mut query = QueryBuilder::<Postgres>::new("UPDATE ");
+        query
+            .push(&table)
+            .push(" SET ")
+            .push(&column)
+            .push(" = ")
+            .push(backfill.value)
+            .push(" WHERE id IN (SELECT id FROM ")
+            .push(&table)
+            .push(" WHERE ")
+            .push(&column)
+            .push(" IS NULL ORDER BY id LIMIT ")
+            .push_bind(backfill.batch_size)
+            .push(")");
+        let updated = query.build().execute(pool).await?.rows_affected();
+        if updated == 0 {
+            break;
+        }
+        total += updated;
+        info!("Backfill of {}: {total} rows updated", backfill.table);
+        tokio::time::sleep(backfill.pause).await;
+    }
+    Ok(total)
+}
+
+/// `name` quoted for use as an identifier in SQL. Only plain lowercase names are taken, so nothing
+/// in one can end the quotes or change what it refers to.
+fn quoted_ident(name: &str) -> Result<String, sqlx::Error> {
+    let plain = name.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if !plain {
+        return Err(sqlx::Error::Configuration(
+            format!("{name:?} isn't a plain identifier").into(),
+        ));
+    }
+    Ok(format!("\"{name}\""))
+}
+
+/// Creates a long string from which we can use to create a short url
+fn gen_url_longword(long_url: &str) -> Vec<u8> {
+    let long_word = general_purpose::STANDARD_NO_PAD.encode(long_url.as_bytes());
+    return Vec::from(long_word.as_bytes());
+}
+
+/// Creates the UrlRow object in the PostgreSQL database and fills in the id and creation time the
+/// database gave it
+async fn url_db_create(new_row: &mut UrlRow, db: impl PgExecutor<'_>) -> Result<(), sqlx::Error> {
+    let created = sqlx::query!(
+        "INSERT INTO urls (shorturl, longurl, created_by, clicks, interstitial_seconds, single_use,
+            submitted_url, expires_at, max_clicks, passphrase_hash, redirect_status, description,
+            active_from, bot_clicks)
+        VALUES ($1, $2, $3, 0, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+        RETURNING id, created_at",
+        new_row.shorturl,
+        new_row.longurl,
+        new_row.created_by,
+        new_row.interstitial_seconds,
+        new_row.single_use,
+        new_row.submitted_url,
+        new_row.expires_at,
+        new_row.max_clicks,
+        new_row.passphrase_hash,
+        new_row.redirect_status,
+        new_row.description,
+        new_row.active_from,
+        new_row.bot_clicks
+    )
+    .fetch_one(db)
+    .await?;
+    new_row.id = created.id;
+    new_row.created_at = created.created_at;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use chrono::{SubsecRound, TimeDelta};
+    use sqlx::{postgres::PgPoolOptions, Column, Either, Executor, PgPool, TypeInfo};
+    use tracing::debug;
+
+    use crate::preferences::Preferences;
+
+    use super::*;
+
+    async fn pool_init() -> (PgPool, Preferences) {
+        eprintln!("Current dir: {:#?}", env::current_dir().unwrap());
+        let prefs =
+            Preferences::load_config("./config.toml").expect("Error loading config from TOML");
+        let conn_url = format!(
+            "postgres://{}:{}@172.17.0.2/{}",
+            prefs.db_user(),
+            prefs.db_pass(),
+            prefs.db_name(),
+        );
+        let pool = PgPoolOptions::new()
+            .max_connections(prefs.db_pool_size())
+            .connect(&conn_url)
+            .await
+            .expect("Couldn't create connection pool. Are your credentials correct?");
+
+        sqlx::migrate!("./migrations")
+            .run(&pool)
+            .await
+            .unwrap_or_else(|_| debug!("Migration already exists, skipping"));
+
+        return (pool, prefs);
+    }
+
+    async fn test_make_url() -> UrlRow {
+        let (pool, prefs) = pool_init().await;
+        let short_row: UrlRow = create_url(
+            "https://example.com",
+            None,
+            &pool,
+            prefs.code_rules(),
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        println!("{:#?}", short_row);
+
+        assert_eq!(short_row.longurl, "https://example.com");
+        assert_eq!(short_row.created_by, None);
+        assert_eq!(short_row.clicks, 0);
+        return short_row;
+    }
+
+    #[test]
+    fn reserved_codes_ignore_case() {
+        let extra = vec![String::from("Pricing")];
+        for code in ["api", "API", "Index.HTML", "favicon.ico", "pricing"] {
+            assert!(is_reserved(code, &extra), "{code}");
+        }
+        assert!(!is_reserved("pricing", &[]));
+        assert!(!is_reserved("apis", &extra));
+    }
+
+    #[sqlx::test]
+    async fn generated_codes_skip_reserved_ones() {
+        let (pool, _) = pool_init().await;
+        let long_url = format!("https://example.com/reserved/{}", rand::random::<u32>());
+        let first_window = gen_url_longword(&long_url)[..8].to_vec();
+        let first_window = String::from_utf8(first_window).unwrap();
+
+        let row = create_url(
+            &long_url,
+            None,
+            &pool,
+            CodeRules {
+                reserved: &[first_window.to_uppercase()],
+                ..CodeRules::of_len(8)
+            },
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_ne!(row.short_url(), &first_window);
+        assert_eq!(row.short_url().len(), 8);
+    }
+
+    #[sqlx::test]
+    async fn generated_codes_start_with_a_free_window() {
+        let (pool, _) = pool_init().await;
+        let mut tx = pool.begin().await.unwrap();
+        // Hex digits encode to letters and digits only, and the random ones keep the window free
+        let long_url = format!("{:016x}.example.com", rand::random::<u64>());
+        let first_window = gen_url_longword(&long_url)[..8].to_vec();
+        let first_window = String::from_utf8(first_window).unwrap();
+        let rules = CodeRules::of_len(8);
+
+        let row = create_url_on(&long_url, None, &mut tx, rules, &UrlOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(row.short_url(), &rules.case.generated(first_window));
+        tx.rollback().await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn generated_codes_skip_windows_that_arent_codes() {
+        let (pool, _) = pool_init().await;
+        let mut tx = pool.begin().await.unwrap();
+        // `~~~` encodes to `fn5+`, so the first windows all have a `+`
+        let long_url = format!("~~~~~~{:016x}.example.com", rand::random::<u64>());
+        let rules = CodeRules::of_len(8);
+
+        let row = create_url_on(&long_url, None, &mut tx, rules, &UrlOptions::default())
+            .await
+            .unwrap();
+        assert!(row
+            .short_url()
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric()));
+        assert!(rules
+            .case
+            .generated(String::from_utf8(gen_url_longword(&long_url)).unwrap())
+            .contains(row.short_url()));
+        tx.rollback().await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn random_codes_give_up_when_all_collide() {
+        let (pool, _) = pool_init().await;
+        // Every one character code ignoring case, some of which other runs may already have made
+        for digit in &CODE_DIGITS[..36] {
+            let code = String::from(*digit as char);
+            let _ = create_url_with_alias(
+                &code,
+                "https://example.com/collision",
+                None,
+                &pool,
+                &UrlOptions::default(),
+            )
+            .await;
+        }
+        let result = create_url(
+            "https://example.com/collision",
+            None,
+            &pool,
+            CodeRules {
+                case: SlugCase::Insensitive,
+                ..CodeRules::of_len(1)
+            },
+            &UrlOptions::default(),
+        )
+        .await;
+        assert!(
+            matches!(result, Err(UrlCreateError::Exhausted)),
+            "{result:?}"
+        );
+    }
+
+    #[sqlx::test]
+    async fn oversized_destinations_are_refused() {
+        let (pool, _) = pool_init().await;
+        let long_url = format!("https://example.com/{}", "a".repeat(MAX_LONG_URL_LEN));
+        let result = create_url(
+            &long_url,
+            None,
+            &pool,
+            CodeRules::of_len(8),
+            &UrlOptions::default(),
+        )
+        .await;
+        match result {
+            Err(UrlCreateError::Database(e)) => assert!(e
+                .as_database_error()
+                .is_some_and(|e| e.is_check_violation())),
+            other => panic!("{other:?}"),
+        }
+    }
+
+    #[test]
+    fn sequential_codes_are_padded() {
+        let code = |value, len, case| sequential_code(value, len, case);
+        assert_eq!(code(0, 4, SlugCase::Sensitive).as_deref(), Some("0000"));
+        assert_eq!(code(61, 4, SlugCase::Sensitive).as_deref(), Some("000Z"));
+        assert_eq!(code(62, 4, SlugCase::Sensitive).as_deref(), Some("0010"));
+        assert_eq!(
+            code(62u64.pow(4) - 1, 4, SlugCase::Sensitive).as_deref(),
+            Some("ZZZZ")
+        );
+        assert_eq!(code(62u64.pow(4), 4, SlugCase::Sensitive), None);
+        assert_eq!(code(35, 2, SlugCase::Insensitive).as_deref(), Some("0z"));
+        assert_eq!(code(36, 2, SlugCase::Insensitive).as_deref(), Some("10"));
+    }
+
+    #[sqlx::test]
+    async fn sequential_codes_skip_taken_ones() {
+        let (pool, _) = pool_init().await;
+        let rules = CodeRules {
+            strategy: SlugStrategy::Sequential,
+            ..CodeRules::of_len(8)
+        };
+        let options = UrlOptions::default();
+        let create = || {
+            create_url(
+                "https://example.com/sequential",
+                None,
+                &pool,
+                rules,
+                &options,
+            )
+        };
+        let code = |value| sequential_code(value, 8, SlugCase::Sensitive).unwrap();
+
+        let first = create().await.unwrap();
+        let value = first.short_url().bytes().fold(0, |value, digit| {
+            let digit = CODE_DIGITS
+                .iter()
+                .position(|known| *known == digit)
+                .unwrap();
+            value * 62 + digit as u64
+        });
+        assert_eq!(first.short_url(), &code(value));
+        // Taken by an alias before the sequence got to it
+        create_url_with_alias(
+            &code(value + 1),
+            "https://example.com/alias",
+            None,
+            &pool,
+            &options,
+        )
+        .await
+        .unwrap();
+        let second = create().await.unwrap();
+        assert_eq!(second.short_url(), &code(value + 2));
+    }
+
+    async fn test_retrieve_url(test_short: UrlRow) {
+        let (pool, _) = pool_init().await;
+
+        let url_row: UrlRow = test_short;
+        assert_eq!(url_row.longurl, "https://example.com");
+        assert_eq!(url_row.created_by, None);
+        let url_row: String =
+            retrieve_url(url_row.short_url().as_str(), SlugCase::Sensitive, &pool)
+                .await
+                .unwrap();
+        assert_eq!(url_row, "https://example.com");
+    }
+
+    #[sqlx::test]
+    async fn test_make_and_retrieve() {
+        let row = test_make_url().await;
+        test_retrieve_url(row).await;
+    }
+
+    #[sqlx::test]
+    async fn test_owner_filters() {
+        let (pool, _) = pool_init().await;
+        // Other tests add links alongside this one, so everything is read from one snapshot and
+        // checked against what was there before seeding rather than against the whole table
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ")
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        let anonymous_before = owner_totals(OwnerFilter::Anonymous, &mut *tx)
+            .await
+            .unwrap();
+        let mut counts_before = Vec::new();
+        for filter in [OwnerFilter::Anonymous, OwnerFilter::Any] {
+            counts_before.push(count_urls(filter, None, false, &mut *tx).await.unwrap());
+        }
+        let mut users = Vec::new();
+        for name in ["owner_filter_a", "owner_filter_b"] {
+            let id: i64 = sqlx::query_scalar(
+                "INSERT INTO users (username, hashed_pw, email) VALUES ($1, '', '') RETURNING id",
+            )
+            .bind(name)
+            .fetch_one(&mut *tx)
+            .await
+            .unwrap();
+            users.push(id);
+        }
+        let seeded = [
+            ("a1", Some(users[0]), 5),
+            ("a2", Some(users[0]), 7),
+            ("b1", Some(users[1]), 1),
+            ("anon1", None, 2),
+            ("anon2", None, 3),
+        ];
+        let mut seeded_ids = Vec::new();
+        for (alias, owner, clicks) in seeded {
+            let row = create_url_with_alias(
+                alias,
+                "https://example.com",
+                owner,
+                &mut *tx,
+                &UrlOptions::default(),
+            )
+            .await
+            .unwrap();
+            sqlx::query("UPDATE urls SET clicks = $1 WHERE id = $2")
+                .bind(clicks)
+                .bind(row.id())
+                .execute(&mut *tx)
+                .await
+                .unwrap();
+            seeded_ids.push(row.id());
+        }
+
+        let filters = [
+            (OwnerFilter::User(users[0]), vec!["a1", "a2"], 2),
+            (
+                OwnerFilter::Anonymous,
+                vec!["anon1", "anon2"],
+                counts_before[0] + 2,
+            ),
+            (
+                OwnerFilter::Any,
+                vec!["a1", "a2", "anon1", "anon2", "b1"],
+                counts_before[1] + 5,
+            ),
+        ];
+        for (filter, expected, count) in filters {
+            let listed = retrieve_urls_by_owner(filter, &mut *tx).await.unwrap();
+            assert!(listed.iter().all(|row| filter.matches(row.created_by())));
+            let aliases: Vec<&str> = listed
+                .iter()
+                .filter(|row| seeded_ids.contains(&row.id()))
+                .map(|row| row.short_url().as_str())
+                .collect();
+            assert_eq!(aliases, expected, "{filter:?}");
+            assert_eq!(
+                count_urls(filter, None, false, &mut *tx).await.unwrap(),
+                count
+            );
+        }
+
+        let totals = |owner, links, clicks| OwnerTotals {
+            owner,
+            links,
+            clicks,
+        };
+        let anonymous_after = match anonymous_before.first() {
+            Some(before) => totals(None, before.links + 2, before.clicks + 5),
+            None => totals(None, 2, 5),
+        };
+        let all = owner_totals(OwnerFilter::Any, &mut *tx).await.unwrap();
+        assert_eq!(all.first(), Some(&anonymous_after));
+        assert!(all.contains(&totals(Some(users[0]), 2, 12)));
+        assert!(all.contains(&totals(Some(users[1]), 1, 1)));
+        assert_eq!(
+            owner_totals(OwnerFilter::Anonymous, &mut *tx)
+                .await
+                .unwrap(),
+            vec![anonymous_after]
+        );
+        assert_eq!(
+            owner_totals(OwnerFilter::User(users[1]), &mut *tx)
+                .await
+                .unwrap(),
+            vec![totals(Some(users[1]), 1, 1)]
+        );
+        assert_eq!(
+            owner_totals(OwnerFilter::User(-1), &mut *tx).await.unwrap(),
+            vec![]
+        );
+        tx.rollback().await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn test_stale_transfers_fail() {
+        let (pool, _) = pool_init().await;
+        let mut tx = pool.begin().await.unwrap();
+        let mut users = Vec::new();
+        for name in ["transfer_from", "transfer_to_a", "transfer_to_b"] {
+            let id: i64 = sqlx::query_scalar(
+                "INSERT INTO users (username, hashed_pw, email) VALUES ($1, '', '') RETURNING id",
+            )
+            .bind(name)
+            .fetch_one(&mut *tx)
+            .await
+            .unwrap();
+            users.push(id);
+        }
+        let row = create_url_on(
+            "https://example.com",
+            Some(users[0]),
+            &mut tx,
+            CodeRules::of_len(8),
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let moved = update_url_owner(row.id(), users[1], Some(users[0]), &mut *tx)
+            .await
+            .unwrap();
+        assert_eq!(moved.created_by(), Some(users[1]));
+        // A second transfer from the owner it was looked up with lost the race
+        assert!(matches!(
+            update_url_owner(row.id(), users[2], Some(users[0]), &mut *tx).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+        archive_url(row.id(), &mut *tx).await.unwrap();
+        assert!(matches!(
+            update_url_owner(row.id(), users[2], Some(users[1]), &mut *tx).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+        tx.rollback().await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn test_list_urls_for_user() {
+        let (pool, _) = pool_init().await;
+        let mut tx = pool.begin().await.unwrap();
+        let user: i64 = sqlx::query_scalar(
+            "INSERT INTO users (username, hashed_pw, email) VALUES ('list_pages', '', '') RETURNING id",
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .unwrap();
+        for alias in ["page1", "page2", "page3", "page4", "page5"] {
+            create_url_with_alias(
+                alias,
+                "https://example.com",
+                Some(user),
+                &mut *tx,
+                &UrlOptions::default(),
+            )
+            .await
+            .unwrap();
+        }
+        create_url_with_alias(
+            "page_anon",
+            "https://example.com",
+            None,
+            &mut *tx,
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let mut pages = Vec::new();
+        for page in 1..=3 {
+            let listed = list_urls_for_user(user, None, false, page, 2, &mut *tx)
+                .await
+                .unwrap();
+            pages.push(
+                listed
+                    .iter()
+                    .map(|row| row.short_url().clone())
+                    .collect::<Vec<_>>(),
+            );
+        }
+        assert_eq!(
+            pages,
+            [
+                vec!["page5", "page4"],
+                vec!["page3", "page2"],
+                vec!["page1"]
+            ]
+        );
+        // Out of range values are clamped
+        let first = list_urls_for_user(user, None, false, 0, 0, &mut *tx)
+            .await
+            .unwrap();
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].short_url(), "page5");
+        let all = list_urls_for_user(user, None, false, -3, i64::MAX, &mut *tx)
+            .await
+            .unwrap();
+        assert_eq!(all.len(), 5);
+        assert!(list_urls_for_user(user, None, false, i64::MAX, 2, &mut *tx)
+            .await
+            .unwrap()
+            .is_empty());
+        tx.rollback().await.unwrap();
+    }
+
+    #[test]
+    fn test_pick_destination_follows_weights() {
+        let destinations: Vec<Destination> = [(1, 3), (2, 1)]
+            .into_iter()
+            .map(|(id, weight)| Destination {
+                id,
+                long_url: format!("https://example.com/{id}"),
+                weight,
+                clicks: 0,
+            })
+            .collect();
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(7);
+        let picks = 4000;
+        let first = (0..picks)
+            .filter(|_| pick_destination(&destinations, &mut rng).unwrap().id == 1)
+            .count();
+        assert!((2800..3200).contains(&first), "{first} of {picks}");
+        assert_eq!(pick_destination(&[], &mut rng), None);
+        assert_eq!(
+            pick_destination(&destinations[1..], &mut rng).map(|d| d.id),
+            Some(2)
+        );
+    }
+
+    #[sqlx::test]
+    async fn test_set_destinations_keeps_clicks() {
+        let (pool, _) = pool_init().await;
+        let mut tx = pool.begin().await.unwrap();
+        let row = create_url_with_alias(
+            "rotating1",
+            "https://example.com/single",
+            None,
+            &mut *tx,
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let weighted = |urls: &[(&str, i32)]| -> Vec<WeightedUrl> {
+            urls.iter()
+                .map(|(long_url, weight)| WeightedUrl {
+                    long_url: long_url.to_string(),
+                    weight: *weight,
+                })
+                .collect()
+        };
+
+        let set = weighted(&[("https://example.com/a", 3), ("https://example.com/b", 1)]);
+        let mut rotating = set_destinations(row.id(), &set, &mut tx).await.unwrap();
+        assert!(rotating.rotates());
+        assert_eq!(rotating.long_url(), "https://example.com/a");
+        let [a, _] = &destinations(row.id(), &mut *tx).await.unwrap()[..] else {
+            panic!("Expected two destinations")
+        };
+        let source = ClickSource {
+            destination: Some(a.id),
+            ..ClickSource::default()
+        };
+        assert!(incr_url_clicks(&mut rotating, &source, &mut *tx)
+            .await
+            .unwrap());
+
+        // Reordered, reweighted, one dropped and one added
+        let set = weighted(&[("https://example.com/c", 2), ("https://example.com/a", 5)]);
+        let rotating = set_destinations(row.id(), &set, &mut tx).await.unwrap();
+        assert_eq!(rotating.long_url(), "https://example.com/c");
+        let listed: Vec<(String, i32, i64)> = destinations(row.id(), &mut *tx)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|d| (d.long_url, d.weight, d.clicks))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                (String::from("https://example.com/c"), 2, 0),
+                (String::from("https://example.com/a"), 5, 1),
+            ]
+        );
+        assert!(
+            find_duplicate("https://example.com/c", OwnerFilter::Anonymous, &mut *tx)
+                .await
+                .unwrap()
+                .is_none_or(|found| found.id() != row.id())
+        );
+
+        let single = set_destinations(row.id(), &[], &mut tx).await.unwrap();
+        assert!(!single.rotates());
+        assert_eq!(single.long_url(), "https://example.com/c");
+        assert!(destinations(row.id(), &mut *tx).await.unwrap().is_empty());
+        tx.rollback().await.unwrap();
+    }
+
+    #[test]
+    fn test_rollouts_split_by_percent() {
+        let destinations: Vec<Destination> =
+            rollout_arms("https://example.com/old", "https://example.com/new", 20)
+                .into_iter()
+                .zip(1..)
+                .map(|(arm, id)| Destination {
+                    id,
+                    long_url: arm.long_url,
+                    weight: arm.weight,
+                    clicks: 0,
+                })
+                .collect();
+        assert_eq!(destinations[0].long_url, "https://example.com/old");
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(7);
+        let picks = 5000;
+        let new = (0..picks)
+            .filter(|_| pick_destination(&destinations, &mut rng).unwrap().id == 2)
+            .count();
+        assert!((900..1100).contains(&new), "{new} of {picks}");
+    }
+
+    #[sqlx::test]
+    async fn test_rollouts_finish_on_the_new_url() {
+        let (pool, _) = pool_init().await;
+        let mut tx = pool.begin().await.unwrap();
+        let row = create_url_with_alias(
+            "rollout1",
+            "https://example.com/old",
+            None,
+            &mut *tx,
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        // Whole seconds, as the column doesn't keep nanoseconds
+        let until = (Utc::now() + TimeDelta::days(7)).trunc_subsecs(0);
+        assert!(matches!(
+            finish_rollout(row.id(), &mut tx).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+
+        let arms = rollout_arms(row.long_url(), "https://example.com/new", 10);
+        let mut rolling = start_rollout(row.id(), &arms, until, &mut tx)
+            .await
+            .unwrap();
+        assert!(rolling.rotates());
+        assert_eq!(rolling.long_url(), "https://example.com/old");
+        assert_eq!(rolling.rollout_until(), Some(until));
+        let [_, new] = &destinations(row.id(), &mut *tx).await.unwrap()[..] else {
+            panic!("Expected two destinations")
+        };
+        let source = ClickSource {
+            destination: Some(new.id),
+            ..ClickSource::default()
+        };
+        assert!(incr_url_clicks(&mut rolling, &source, &mut *tx)
+            .await
+            .unwrap());
+
+        let arms = rollout_arms(row.long_url(), "https://example.com/new", 50);
+        start_rollout(row.id(), &arms, until, &mut tx)
+            .await
+            .unwrap();
+        let split: Vec<(String, i32, i64)> = destinations(row.id(), &mut *tx)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|d| (d.long_url, d.weight, d.clicks))
+            .collect();
+        assert_eq!(
+            split,
+            [
+                (String::from("https://example.com/old"), 50, 0),
+                (String::from("https://example.com/new"), 50, 1),
+            ]
+        );
+        assert!(!due_rollouts(until - TimeDelta::days(1), &mut *tx)
+            .await
+            .unwrap()
+            .contains(&row.id()));
+        assert!(due_rollouts(until, &mut *tx)
+            .await
+            .unwrap()
+            .contains(&row.id()));
+
+        let finished = finish_rollout(row.id(), &mut tx).await.unwrap();
+        assert!(!finished.rotates());
+        assert_eq!(finished.long_url(), "https://example.com/new");
+        assert_eq!(finished.rollout_until(), None);
+        assert_eq!(finished.clicks(), 1);
+        assert!(destinations(row.id(), &mut *tx).await.unwrap().is_empty());
+
+        // Setting destinations some other way ends a rollout too
+        let arms = rollout_arms(finished.long_url(), "https://example.com/newer", 10);
+        start_rollout(row.id(), &arms, until, &mut tx)
+            .await
+            .unwrap();
+        let stopped = set_destinations(row.id(), &[], &mut tx).await.unwrap();
+        assert_eq!(stopped.rollout_until(), None);
+        assert_eq!(stopped.long_url(), "https://example.com/new");
+        tx.rollback().await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn test_archived_urls_keep_their_code() {
+        let (pool, _) = pool_init().await;
+        let mut tx = pool.begin().await.unwrap();
+        let user: i64 = sqlx::query_scalar(
+            "INSERT INTO users (username, hashed_pw, email) VALUES ('archiver', '', '') RETURNING id",
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .unwrap();
+        let row = create_url_with_alias(
+            "Archived1",
+            "https://example.com/archived",
+            Some(user),
+            &mut *tx,
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let archived = archive_url(row.id(), &mut *tx).await.unwrap();
+        assert!(archived.deleted_at().is_some());
+        assert!(matches!(
+            archive_url(row.id(), &mut *tx).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+        for case in [SlugCase::Sensitive, SlugCase::Insensitive] {
+            assert!(matches!(
+                retrieve_url_obj("Archived1", case, &mut *tx).await,
+                Err(sqlx::Error::RowNotFound)
+            ));
+            assert!(matches!(
+                retrieve_url("Archived1", case, &mut *tx).await,
+                Err(sqlx::Error::RowNotFound)
+            ));
+            assert!(is_code_taken("Archived1", case, &mut *tx).await.unwrap());
+        }
+        assert!(is_code_taken("archived1", SlugCase::Insensitive, &mut *tx)
+            .await
+            .unwrap());
+        let found = retrieve_archived_url_obj("archived1", SlugCase::Insensitive, &mut *tx)
+            .await
+            .unwrap();
+        assert_eq!(found.id(), row.id());
+        assert!(list_urls_for_user(user, None, false, 1, 10, &mut *tx)
+            .await
+            .unwrap()
+            .is_empty());
+        let listed = list_urls_for_user(user, None, true, 1, 10, &mut *tx)
+            .await
+            .unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(
+            count_urls(OwnerFilter::User(user), None, true, &mut *tx)
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(find_duplicate(
+            "https://example.com/archived",
+            OwnerFilter::User(user),
+            &mut *tx
+        )
+        .await
+        .unwrap()
+        .is_none());
+
+        let restored = restore_url(row.id(), &mut *tx).await.unwrap();
+        assert_eq!(restored.deleted_at(), None);
+        assert!(matches!(
+            restore_url(row.id(), &mut *tx).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+        retrieve_url_obj("Archived1", SlugCase::Sensitive, &mut *tx)
+            .await
+            .unwrap();
+
+        archive_url(row.id(), &mut *tx).await.unwrap();
+        let past = archived_past(Duration::from_secs(60 * 60), &mut *tx)
+            .await
+            .unwrap();
+        assert!(!past.iter().any(|(id, _)| *id == row.id()));
+        // Archived at the start of the transaction, which is now() for all of it
+        let past = archived_past(Duration::ZERO, &mut *tx).await.unwrap();
+        assert!(past.contains(&(row.id(), "Archived1".to_string())));
+        assert_eq!(delete_urls(&[row.id()], &mut *tx).await.unwrap(), 1);
+        assert!(!is_code_taken("Archived1", SlugCase::Sensitive, &mut *tx)
+            .await
+            .unwrap());
+        tx.rollback().await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn test_archive_expired_anonymous() {
+        let (pool, _) = pool_init().await;
+        let mut tx = pool.begin().await.unwrap();
+        let user: i64 = sqlx::query_scalar(
+            "INSERT INTO users (username, hashed_pw, email) VALUES ('expirer', '', '') RETURNING id",
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .unwrap();
+        let expired = |days| UrlOptions {
+            expires_at: Some(Utc::now() - chrono::TimeDelta::days(days)),
+            ..UrlOptions::default()
+        };
+        let mut rows = Vec::new();
+        for (code, owner, options) in [
+            ("ExpiredAnon1", None, expired(2)),
+            ("ExpiredAnon2", None, expired(0)),
+            ("ExpiredAnon3", Some(user), expired(2)),
+            ("ExpiredAnon4", None, UrlOptions::default()),
+        ] {
+            let row = create_url_with_alias(
+                code,
+                "https://example.com/expired",
+                owner,
+                &mut *tx,
+                &options,
+            )
+            .await
+            .unwrap();
+            rows.push(row);
+        }
+
+        let day = Duration::from_secs(60 * 60 * 24);
+        assert!(archive_expired_anonymous(day, &mut *tx).await.unwrap() >= 1);
+        let archived = retrieve_archived_url_obj("ExpiredAnon1", SlugCase::Sensitive, &mut *tx)
+            .await
+            .unwrap();
+        assert_eq!(archived.id(), rows[0].id());
+        // Still within the grace, owned or never expiring
+        for code in ["ExpiredAnon2", "ExpiredAnon3", "ExpiredAnon4"] {
+            retrieve_url_obj(code, SlugCase::Sensitive, &mut *tx)
+                .await
+                .unwrap();
+        }
+        tx.rollback().await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn test_clicks_by_day() {
+        let (pool, _) = pool_init().await;
+        let mut row = create_url(
+            "https://example.com/daily",
+            None,
+            &pool,
+            CodeRules::of_len(8),
+            &UrlOptions::default(),
+        )
+        .await
+        .unwrap();
+        let single = create_url(
+            "https://example.com/daily-once",
+            None,
+            &pool,
+            CodeRules::of_len(8),
+            &UrlOptions {
+                single_use: true,
+                ..UrlOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+        for _ in 0..3 {
+            assert!(incr_url_clicks(&mut row, &ClickSource::default(), &pool)
+                .await
+                .unwrap());
+        }
+        assert!(
+            claim_single_use(single.id(), &ClickSource::default(), &pool)
+                .await
+                .unwrap()
+                .is_some()
+        );
+        assert!(
+            claim_single_use(single.id(), &ClickSource::default(), &pool)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        let today = Utc::now().date_naive();
+        let yesterday = today.pred_opt().unwrap();
+        let day = |day, count| DailyClicks {
+            day,
+            count,
+            rebuilding: false,
+        };
+        assert_eq!(
+            clicks_by_day(row.id(), yesterday, today, &pool)
+                .await
+                .unwrap(),
+            [day(yesterday, 0), day(today, 3)]
+        );
+        assert_eq!(
+            clicks_by_day(single.id(), today, today, &pool)
+                .await
+                .unwrap(),
+            [day(today, 1)]
+        );
+        delete_url(row.id(), &pool).await.unwrap();
+        delete_url(single.id(), &pool).await.unwrap();
+    }
 
     #[sqlx::test]
     async fn test_delete_url() {
@@ -292,13 +2810,238 @@ mod tests {
         delete_url(1, &pool).await.expect("Error deleting row");
     }
 
+    #[sqlx::test]
+    async fn test_backfill_batches_and_resumes() {
+        let (pool, _) = pool_init().await;
+        let table = format!("backfill_test_{}", rand::random::<u32>());
+        sqlx::query(&format!(
+            "CREATE TABLE {table} (id bigserial PRIMARY KEY, old_val BIGINT NOT NULL, new_val BIGINT NULL)"
+        ))
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query(&format!(
+            "INSERT INTO {table} (old_val) SELECT generate_series(1, 25)"
+        ))
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let backfill = Backfill {
+            table: &table,
+            column: "new_val",
+            value: "old_val * 2",
+            batch_size: 10,
+            pause: Duration::from_millis(200),
+        };
+
+        // Cancelled after the first batch, like a deploy killing the process
+        let interrupted =
+            tokio::time::timeout(Duration::from_millis(100), run_backfill(&backfill, &pool)).await;
+        assert!(interrupted.is_err());
+        let done: i64 = sqlx::query_scalar(&format!(
+            "SELECT COUNT(*) FROM {table} WHERE new_val IS NOT NULL"
+        ))
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(done, 10);
+
+        let resumed = run_backfill(&backfill, &pool).await.unwrap();
+        assert_eq!(resumed, 15);
+        let wrong: i64 = sqlx::query_scalar(&format!(
+            "SELECT COUNT(*) FROM {table} WHERE new_val IS DISTINCT FROM old_val * 2"
+        ))
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(wrong, 0);
+
+        sqlx::query(&format!("DROP TABLE {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        // Names are quoted, and anything that would need escaping is refused outright
+        for (table, column) in [
+            ("urls; DROP TABLE urls", "bot_clicks"),
+            ("urls", "bot_clicks\" = 0 --"),
+            ("Urls", "bot_clicks"),
+        ] {
+            let backfill = Backfill {
+                table,
+                column,
+                ..URL_BACKFILLS[0]
+            };
+            assert!(matches!(
+                run_backfill(&backfill, &pool).await,
+                Err(sqlx::Error::Configuration(_))
+            ));
+        }
+    }
+
+    /// Migrations run against a live instance, so anything that locks `urls` for the length of a
+    /// table rewrite or scan stalls every redirect. The rules:
+    /// - indexes on `urls` are built with CREATE INDEX CONCURRENTLY in a file starting with
+    ///   `-- no-transaction` (CONCURRENTLY can't run inside a transaction)
+    /// - columns are added as nullable without a DEFAULT, written on insert, filled in for older
+    ///   rows by an entry in [URL_BACKFILLS], and constrained in a later migration
+    ///
+    /// The migration that creates `urls` is exempt since the table is empty at that point.
+    #[test]
+    fn test_migration_lint() {
+        let mut violations = Vec::new();
+        let mut entries: Vec<_> = std::fs::read_dir("./migrations")
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        entries.sort();
+
+        for path in entries {
+            let contents = std::fs::read_to_string(&path).unwrap();
+            let name = path.display();
+            if contents.contains("CREATE TABLE \"urls\"") {
+                continue;
+            }
+            let no_transaction = contents.trim_start().starts_with("-- no-transaction");
+            let statements: Vec<String> = contents
+                .lines()
+                .filter(|line| !line.trim_start().starts_with("--"))
+                .collect::<Vec<_>>()
+                .join(" ")
+                .split(';')
+                .map(|statement| statement.split_whitespace().collect::<Vec<_>>().join(" "))
+                .map(|statement| statement.to_uppercase())
+                .collect();
+
+            for statement in statements {
+                let on_urls = statement.contains("\"URLS\"") || statement.contains(" URLS");
+                let creates_index = statement.starts_with("CREATE INDEX")
+                    || statement.starts_with("CREATE UNIQUE INDEX");
+                if creates_index && on_urls && !statement.contains("CONCURRENTLY") {
+                    violations.push(format!("{name}: index on urls without CONCURRENTLY"));
+                }
+                if statement.contains("CONCURRENTLY") && !no_transaction {
+                    violations.push(format!(
+                        "{name}: CONCURRENTLY needs `-- no-transaction` on the first line"
+                    ));
+                }
+                if on_urls && statement.contains("ADD COLUMN") && statement.contains("DEFAULT") {
+                    violations.push(format!("{name}: column added to urls with a DEFAULT"));
+                }
+            }
+        }
+
+        assert!(violations.is_empty(), "{violations:#?}");
+    }
+
+    #[sqlx::test]
+    async fn test_offline_query_data_is_current() {
+        let (pool, _) = pool_init().await;
+        let mut sources = String::new();
+        let mut dirs = vec![std::path::PathBuf::from("./src")];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if path.extension().is_some_and(|ext| ext == "rs") {
+                    sources.push_str(&std::fs::read_to_string(path).unwrap());
+                }
+            }
+        }
+
+        let mut stale = Vec::new();
+        for entry in std::fs::read_dir("./.sqlx").unwrap() {
+            let path = entry.unwrap().path();
+            let cached: serde_json::Value =
+                serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+            let query = cached["query"].as_str().unwrap();
+            let describe = &cached["describe"];
+
+            if !sources.contains(query) {
+                stale.push(format!("{}: query is no longer used", path.display()));
+                continue;
+            }
+            let hash = hex::encode(<sha2::Sha256 as sha2::Digest>::digest(query.as_bytes()));
+            if cached["hash"] != hash.as_str() {
+                stale.push(format!("{}: hash doesn't match the query", path.display()));
+            }
+
+            let live = match (&pool).describe(query).await {
+                Ok(live) => live,
+                Err(err) => {
+                    stale.push(format!("{}: {err}", path.display()));
+                    continue;
+                }
+            };
+            let columns: Vec<(String, String, Option<bool>)> = live
+                .columns()
+                .iter()
+                .enumerate()
+                .map(|(i, column)| {
+                    (
+                        column.name().to_string(),
+                        type_name(column.type_info().name()),
+                        live.nullable(i),
+                    )
+                })
+                .collect();
+            let cached_columns: Vec<(String, String, Option<bool>)> = describe["columns"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .enumerate()
+                .map(|(i, column)| {
+                    (
+                        column["name"].as_str().unwrap().to_string(),
+                        type_name(column["type_info"].as_str().unwrap()),
+                        describe["nullable"][i].as_bool(),
+                    )
+                })
+                .collect();
+            if columns != cached_columns {
+                stale.push(format!("{}: columns changed", path.display()));
+            }
+
+            let parameters: Vec<String> = match live.parameters() {
+                Some(Either::Left(types)) => types.iter().map(|t| type_name(t.name())).collect(),
+                _ => Vec::new(),
+            };
+            let cached_parameters: Vec<String> = describe["parameters"]["Left"]
+                .as_array()
+                .map(|types| {
+                    types
+                        .iter()
+                        .map(|t| type_name(t.as_str().unwrap()))
+                        .collect()
+                })
+                .unwrap_or_default();
+            if parameters != cached_parameters {
+                stale.push(format!("{}: parameters changed", path.display()));
+            }
+        }
+
+        assert!(
+            stale.is_empty(),
+            "Offline query data is out of date, regenerate it (see README):\n{}",
+            stale.join("\n")
+        );
+    }
+
+    /// Type names as stored in .sqlx (`TextArray`) and as reported by Postgres (`TEXT[]`) in one
+    /// spelling
+    fn type_name(name: &str) -> String {
+        name.replace("[]", "Array").replace(' ', "").to_uppercase()
+    }
+
     #[sqlx::test]
     async fn test_retrieve_enonexistant_url() {
         let (pool, _) = pool_init().await;
 
         let url = "247eadf89a518526cd34fd24aaaaaaaaaa";
 
-        retrieve_url_obj(url, &pool)
+        retrieve_url_obj(url, SlugCase::Sensitive, &pool)
             .await
             .expect_err("This url shouldn't exist");
     }
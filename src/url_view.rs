@@ -0,0 +1,359 @@
+use askama::Template;
+use chrono::{DateTime, Utc};
+
+use crate::{display::filters, url_db::UrlRow};
+
+/// Who a row is being shown to. Decides which fields make it into the view.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Viewer {
+    /// The user who created the link, or whoever just created it anonymously
+    Owner,
+    Admin,
+    /// Anyone else. Only sees where the link goes.
+    Public,
+}
+
+/// Everything url-table-row.html shows, already resolved for one viewer. Rows are only ever
+/// rendered through this so templates never depend on which optional columns a row has.
+#[derive(Template, Debug)]
+#[template(path = "url-table-row.html")]
+pub struct UrlRowView {
+    short_url: String,
+    /// Short url with the instance's domain, as people would type it
+    short_link: String,
+    destination: String,
+    /// Shown on hover: the destination as it was typed if that differs from the stored one
+    title: String,
+    clicks: Option<i64>,
+    countdown_seconds: Option<i16>,
+    single_use: Option<SingleUse>,
+    expiry: Option<Expiry>,
+    /// When a scheduled link starts redirecting, only until it does
+    goes_live: Option<DateTime<Utc>>,
+    max_clicks: Option<i64>,
+    protected: bool,
+    /// The owner's notes, only ever shown to them
+    description: Option<String>,
+}
+
+/// Where a single use link stands, as shown to its owner
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SingleUse {
+    Unused,
+    UsedAt(DateTime<Utc>),
+}
+
+/// When a link with an expiry stops working, as shown to its owner
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Expiry {
+    Until(DateTime<Utc>),
+    Since(DateTime<Utc>),
+}
+
+impl UrlRowView {
+    /// Builds the view of a row for a viewer. Anything the viewer shouldn't see is left out here.
+    pub fn assemble(row: &UrlRow, domain_name: &str, viewer: Viewer) -> Self {
+        let can_see_stats = matches!(viewer, Viewer::Owner | Viewer::Admin);
+        let countdown = row.interstitial_seconds();
+        UrlRowView {
+            short_url: row.clone_short_url(),
+            short_link: format!("{}/{}", domain_name.trim_end_matches('/'), row.short_url()),
+            destination: row.long_url().clone(),
+            title: row
+                .submitted_url()
+                .unwrap_or(row.long_url().as_str())
+                .to_string(),
+            clicks: can_see_stats.then(|| row.clicks()),
+            // Single use links skip the countdown, so it isn't worth mentioning
+            countdown_seconds: (countdown > 0 && !row.single_use()).then_some(countdown),
+            single_use: (can_see_stats && row.single_use()).then(|| match row.consumed_at() {
+                Some(at) => SingleUse::UsedAt(at),
+                None => SingleUse::Unused,
+            }),
+            expiry: row.expires_at().filter(|_| can_see_stats).map(|at| {
+                if row.is_expired(Utc::now()) {
+                    Expiry::Since(at)
+                } else {
+                    Expiry::Until(at)
+                }
+            }),
+            goes_live: row
+                .active_from()
+                .filter(|_| can_see_stats && row.is_pending(Utc::now())),
+            max_clicks: row.max_clicks().filter(|_| can_see_stats),
+            protected: can_see_stats && row.is_protected(),
+            description: row
+                .description()
+                .filter(|_| viewer == Viewer::Owner)
+                .map(String::from),
+        }
+    }
+
+    /// Click count with thousands separators, e.g. `1,000,000`
+    pub fn clicks_display(&self) -> Option<String> {
+        self.clicks.map(thousands)
+    }
+
+    /// Short label for links that don't redirect straight away. An expired or not yet live link
+    /// says only that, and an upcoming expiry is only mentioned when there is nothing else to say.
+    pub fn status_badge(&self) -> Option<String> {
+        if let Some(Expiry::Since(at)) = self.expiry {
+            return Some(format!("Expired {}", at.format("%Y-%m-%d %H:%M UTC")));
+        }
+        if let Some(at) = self.goes_live {
+            return Some(format!("Goes live {}", at.format("%Y-%m-%d %H:%M UTC")));
+        }
+        if let (Some(max), Some(clicks)) = (self.max_clicks, self.clicks) {
+            if clicks >= max {
+                return Some(String::from("Used up"));
+            }
+        }
+        match self.single_use {
+            Some(SingleUse::Unused) => Some(String::from("Single use")),
+            Some(SingleUse::UsedAt(at)) => {
+                Some(format!("Used {}", at.format("%Y-%m-%d %H:%M UTC")))
+            }
+            None => self
+                .countdown_seconds
+                .map(|seconds| format!("{seconds}s countdown"))
+                .or_else(|| self.protected.then(|| String::from("Passphrase")))
+                .or_else(|| match self.expiry {
+                    Some(Expiry::Until(at)) => {
+                        Some(format!("Expires {}", at.format("%Y-%m-%d %H:%M UTC")))
+                    }
+                    _ => None,
+                })
+                .or_else(|| {
+                    self.max_clicks
+                        .map(|max| format!("Max {} clicks", thousands(max)))
+                }),
+        }
+    }
+}
+
+fn thousands(number: i64) -> String {
+    let digits = number.unsigned_abs().to_string();
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3 + 1);
+    if number < 0 {
+        grouped.push('-');
+    }
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(row: &UrlRow, viewer: Viewer) -> String {
+        UrlRowView::assemble(row, "sho.rt", viewer)
+            .render()
+            .unwrap()
+    }
+
+    #[test]
+    fn formats_clicks() {
+        assert_eq!(thousands(0), "0");
+        assert_eq!(thousands(1), "1");
+        assert_eq!(thousands(999), "999");
+        assert_eq!(thousands(1_000), "1,000");
+        assert_eq!(thousands(1_000_000), "1,000,000");
+        assert_eq!(thousands(-12_345), "-12,345");
+    }
+
+    #[test]
+    fn renders_all_fields() {
+        let row = UrlRow::test_row("abc", "https://example.com")
+            .with_clicks(1_000_000)
+            .with_interstitial(3)
+            .with_owner(Some(7));
+
+        assert_eq!(
+            render(&row, Viewer::Admin),
+            "<tr id=\"url-output-data\">\n\
+             \t<td><a href=\"abc\">sho.rt/abc</a></td>\n\
+             \t<td title=\"https://example.com\">https://example.com <span class=\"badge\">3s countdown</span></td>\n\
+             \t<td>1,000,000</td>\n\
+             </tr>\n\
+             <tr id=\"replace-htmx-row\"></tr>"
+        );
+    }
+
+    #[test]
+    fn renders_without_optional_fields() {
+        let row = UrlRow::test_row("abc", "https://example.com").with_clicks(1);
+
+        assert_eq!(
+            render(&row, Viewer::Owner),
+            "<tr id=\"url-output-data\">\n\
+             \t<td><a href=\"abc\">sho.rt/abc</a></td>\n\
+             \t<td title=\"https://example.com\">https://example.com</td>\n\
+             \t<td>1</td>\n\
+             </tr>\n\
+             <tr id=\"replace-htmx-row\"></tr>"
+        );
+    }
+
+    #[test]
+    fn redacts_for_public() {
+        let row = UrlRow::test_row("abc", "https://example.com")
+            .with_clicks(0)
+            .with_owner(Some(7));
+        let owner = UrlRowView::assemble(&row, "sho.rt", Viewer::Owner);
+        let public = UrlRowView::assemble(&row, "sho.rt", Viewer::Public);
+
+        assert_eq!(owner.clicks_display().as_deref(), Some("0"));
+        assert_eq!(public.clicks_display(), None);
+        assert!(render(&row, Viewer::Public).contains("\t<td></td>\n"));
+    }
+
+    #[test]
+    fn shows_description_to_owner_only() {
+        let row = UrlRow::test_row("abc", "https://example.com")
+            .with_description(&format!("<i>Spring</i> mailing {}", "x".repeat(100)));
+
+        let rendered = render(&row, Viewer::Owner);
+        assert!(rendered.contains(&format!(
+            "<br><small class=\"description\">&lt;i&gt;Spring&lt;/i&gt; mailing {}…</small></td>",
+            "x".repeat(57)
+        )));
+        for viewer in [Viewer::Admin, Viewer::Public] {
+            assert!(!render(&row, viewer).contains("Spring"), "{viewer:?}");
+        }
+    }
+
+    #[test]
+    fn truncates_and_escapes_destination() {
+        let long_url = format!("https://example.com/?q=<b>{}", "ü".repeat(100));
+        let rendered = render(&UrlRow::test_row("abc", &long_url), Viewer::Owner);
+
+        assert!(rendered.contains("<td title=\"https://example.com/?q=&lt;b&gt;"));
+        assert!(rendered.contains(&format!("{}…</td>", "ü".repeat(33))));
+        assert!(!rendered.contains("<b>"));
+    }
+
+    #[test]
+    fn shows_single_use_state_to_owner() {
+        let unused = UrlRow::test_row("abc", "https://example.com")
+            .with_interstitial(3)
+            .with_single_use(None);
+        let used = unused
+            .clone()
+            .with_single_use(DateTime::from_timestamp(1_700_000_000, 0));
+        let badge =
+            |row: &UrlRow, viewer| UrlRowView::assemble(row, "sho.rt", viewer).status_badge();
+
+        assert_eq!(badge(&unused, Viewer::Owner).as_deref(), Some("Single use"));
+        assert_eq!(
+            badge(&used, Viewer::Owner).as_deref(),
+            Some("Used 2023-11-14 22:13 UTC")
+        );
+        assert_eq!(badge(&used, Viewer::Public), None);
+    }
+
+    #[test]
+    fn shows_expiry_to_owner() {
+        let past = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let future = DateTime::from_timestamp(4_102_444_800, 0).unwrap();
+        let row = UrlRow::test_row("abc", "https://example.com");
+        let badge =
+            |row: &UrlRow, viewer| UrlRowView::assemble(row, "sho.rt", viewer).status_badge();
+
+        assert_eq!(
+            badge(&row.clone().with_expiry(future), Viewer::Owner).as_deref(),
+            Some("Expires 2100-01-01 00:00 UTC")
+        );
+        assert_eq!(
+            badge(
+                &row.clone().with_interstitial(3).with_expiry(future),
+                Viewer::Owner
+            )
+            .as_deref(),
+            Some("3s countdown")
+        );
+        assert_eq!(
+            badge(
+                &row.clone().with_interstitial(3).with_expiry(past),
+                Viewer::Owner
+            )
+            .as_deref(),
+            Some("Expired 2023-11-14 22:13 UTC")
+        );
+        assert_eq!(
+            badge(&row.clone().with_expiry(future), Viewer::Public),
+            None
+        );
+    }
+
+    #[test]
+    fn shows_schedule_to_owner() {
+        let past = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let future = DateTime::from_timestamp(4_102_444_800, 0).unwrap();
+        let row = UrlRow::test_row("abc", "https://example.com").with_interstitial(3);
+        let badge =
+            |row: &UrlRow, viewer| UrlRowView::assemble(row, "sho.rt", viewer).status_badge();
+
+        assert_eq!(
+            badge(&row.clone().with_active_from(future), Viewer::Owner).as_deref(),
+            Some("Goes live 2100-01-01 00:00 UTC")
+        );
+        assert_eq!(
+            badge(&row.clone().with_active_from(past), Viewer::Owner).as_deref(),
+            Some("3s countdown")
+        );
+        assert_eq!(
+            badge(&row.with_active_from(future), Viewer::Public).as_deref(),
+            Some("3s countdown")
+        );
+    }
+
+    #[test]
+    fn shows_click_limit_to_owner() {
+        let row = UrlRow::test_row("abc", "https://example.com").with_max_clicks(1_000);
+        let badge =
+            |row: &UrlRow, viewer| UrlRowView::assemble(row, "sho.rt", viewer).status_badge();
+
+        assert_eq!(
+            badge(&row.clone().with_clicks(999), Viewer::Owner).as_deref(),
+            Some("Max 1,000 clicks")
+        );
+        assert_eq!(
+            badge(&row.clone().with_clicks(1_000), Viewer::Owner).as_deref(),
+            Some("Used up")
+        );
+        assert_eq!(
+            badge(
+                &row.clone().with_interstitial(3).with_clicks(1_000),
+                Viewer::Owner
+            )
+            .as_deref(),
+            Some("Used up")
+        );
+        assert_eq!(badge(&row.with_clicks(1_000), Viewer::Public), None);
+    }
+
+    #[test]
+    fn shows_passphrase_to_owner() {
+        let row = UrlRow::test_row("abc", "https://example.com").with_passphrase("pw");
+        let badge = |viewer| UrlRowView::assemble(&row, "sho.rt", viewer).status_badge();
+
+        assert_eq!(badge(Viewer::Owner).as_deref(), Some("Passphrase"));
+        assert_eq!(badge(Viewer::Public), None);
+    }
+
+    #[test]
+    fn titles_with_submitted_destination() {
+        let row = UrlRow::test_row("abc", "https://example.com/a")
+            .with_submitted_url("https://example.com/a?utm_source=mail");
+
+        assert!(render(&row, Viewer::Owner).contains(
+            "<td title=\"https://example.com/a?utm_source=mail\">https://example.com/a</td>"
+        ));
+    }
+}
@@ -1,12 +1,65 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
 use rand::{distributions::Alphanumeric, Rng, SeedableRng};
 use rand_chacha::ChaChaRng;
 use sha2::{Digest, Sha512};
+use sqlx::PgExecutor;
 use tracing::{debug, instrument};
 use zeroize::Zeroizing;
 
-use crate::url_db::UserRow;
+use crate::{locked::Locked, url_db::UserRow};
 
+pub mod cookie;
 pub mod jwt;
+pub mod session;
+
+const DELETED_USER_TTL: Duration = Duration::from_secs(10 * 60);
+/// Unless sized otherwise, see [crate::resources]
+pub const DELETED_USER_CAPACITY: usize = 1024;
+
+/// Remembers user ids that turned out not to exist, so a deleted user's client retrying with its
+/// old token doesn't cost a users query every time. Ids are never reused, so a stale entry can only
+/// ever be a deleted user; the TTL and capacity just keep the map small.
+pub struct DeletedUsers {
+    entries: Locked<HashMap<i64, Instant>>,
+    capacity: usize,
+}
+
+impl Default for DeletedUsers {
+    fn default() -> Self {
+        DeletedUsers::with_capacity(DELETED_USER_CAPACITY)
+    }
+}
+
+impl DeletedUsers {
+    pub fn with_capacity(capacity: usize) -> Self {
+        DeletedUsers {
+            entries: Locked::default(),
+            capacity,
+        }
+    }
+    pub fn contains(&self, id: i64) -> bool {
+        self.entries.with(|entries| {
+            entries
+                .get(&id)
+                .is_some_and(|added| added.elapsed() < DELETED_USER_TTL)
+        })
+    }
+    pub fn insert(&self, id: i64) {
+        self.entries.with(|entries| {
+            if entries.len() >= self.capacity {
+                entries.retain(|_, added| added.elapsed() < DELETED_USER_TTL);
+            }
+            if entries.len() >= self.capacity {
+                entries.clear();
+            }
+            entries.insert(id, Instant::now());
+        })
+    }
+}
 
 /// Creates a new user from user, pass, and email, inserts into DB, and returns the created row or
 /// sql error
@@ -17,7 +70,7 @@ pub async fn new_user(
     pool: &sqlx::PgPool,
 ) -> Result<UserRow, sqlx::Error> {
     let mut new_user = create_user_for_db(username, plain_pw, email).await?;
-    let new_user_id = add_user_to_db(&new_user, &pool).await?;
+    let new_user_id = add_user_to_db(&new_user, pool).await?;
 
     new_user.update_id(new_user_id);
 
@@ -35,40 +88,38 @@ pub async fn create_user_for_db(
     Ok(user)
 }
 
-async fn add_user_to_db(user: &UserRow, pool: &sqlx::PgPool) -> Result<i64, sqlx::Error> {
-    let mut transaction = pool.begin().await?;
-
-    sqlx::query("INSERT INTO users (username, hashed_pw, email) VALUES ($1, $2, $3);")
-        .bind(user.username())
-        .bind(user.hashed_pw())
-        .bind(user.email())
-        .execute(&mut *transaction)
-        .await?;
-
-    let id: i64 = sqlx::query_scalar("SELECT currval('users_id_seq')")
-        .fetch_one(&mut *transaction)
-        .await?;
-
-    transaction.commit().await?;
-
-    Ok(id)
+pub async fn add_user_to_db(user: &UserRow, db: impl PgExecutor<'_>) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        "INSERT INTO users (username, hashed_pw, email) VALUES ($1, $2, $3) RETURNING id",
+        user.username(),
+        user.hashed_pw(),
+        user.email()
+    )
+    .fetch_one(db)
+    .await
 }
 
-pub async fn retrieve_user_by_id(id: i64, pool: &sqlx::PgPool) -> Result<UserRow, sqlx::Error> {
-    sqlx::query_as("SELECT * FROM users WHERE id=$1 LIMIT 1")
-        .bind(id)
-        .fetch_one(pool)
-        .await
+pub async fn retrieve_user_by_id(id: i64, db: impl PgExecutor<'_>) -> Result<UserRow, sqlx::Error> {
+    sqlx::query_as!(
+        UserRow,
+        "SELECT id, username, hashed_pw, email FROM users WHERE id=$1 LIMIT 1",
+        id
+    )
+    .fetch_one(db)
+    .await
 }
 
 pub async fn retrieve_user_by_name(
     username: &str,
-    pool: &sqlx::PgPool,
+    db: impl PgExecutor<'_>,
 ) -> Result<UserRow, sqlx::Error> {
-    sqlx::query_as("SELECT * FROM users WHERE username=$1 LIMIT 1")
-        .bind(username)
-        .fetch_one(pool)
-        .await
+    sqlx::query_as!(
+        UserRow,
+        "SELECT id, username, hashed_pw, email FROM users WHERE username=$1 LIMIT 1",
+        username
+    )
+    .fetch_one(db)
+    .await
 }
 
 fn hash_unsalted_password(password: Zeroizing<String>) -> String {
@@ -108,12 +159,22 @@ fn salt_password(password: Zeroizing<String>) -> (Zeroizing<String>, String) {
 
 #[instrument]
 pub async fn verify_pw(password: &str, user: &UserRow) -> bool {
+    password_matches(password, user.hashed_pw())
+}
+
+/// Salts and hashes a password for storage, in the `salt#hash` form [password_matches] reads
+pub fn hash_password(password: &str) -> String {
+    hash_unsalted_password(Zeroizing::new(password.to_string()))
+}
+
+/// Whether `password` is the one `stored` was made from by [hash_password]
+pub fn password_matches(password: &str, stored: &str) -> bool {
     let mut salted_password = Zeroizing::new(String::new());
     salted_password.reserve(14);
 
     // Get salt from hashed_pw
     let mut delimiter_index: usize = 0;
-    for (i, letter) in user.hashed_pw().as_bytes().iter().enumerate() {
+    for (i, letter) in stored.as_bytes().iter().enumerate() {
         if *letter != b'#' {
             let letter = char::from(*letter);
             salted_password.push(letter);
@@ -125,20 +186,25 @@ pub async fn verify_pw(password: &str, user: &UserRow) -> bool {
 
     salted_password.push_str(password);
     let hashed_pw = hash_salted_password(salted_password);
-    debug!("Whole hash in db is {}", user.hashed_pw());
-    let stored_hash = user.hashed_pw().as_str().split_at(delimiter_index).1;
+    debug!("Whole hash in db is {}", stored);
+    let stored_hash = stored.split_at(delimiter_index).1;
     debug!(
         "Comparing passwords --
          Input hash: {hashed_pw}
         Stored hash: {stored_hash}"
     );
-    return hashed_pw == stored_hash;
+    // Every byte is compared so the time taken doesn't say how much of the hash matched
+    hashed_pw.len() == stored_hash.len()
+        && hashed_pw
+            .bytes()
+            .zip(stored_hash.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
 }
 
-pub async fn delete_user_from_db(id: i64, pool: &sqlx::PgPool) -> Result<u64, sqlx::Error> {
-    let result = sqlx::query("DELETE FROM users WHERE id=$1")
-        .bind(id)
-        .execute(pool)
+pub async fn delete_user_from_db(id: i64, db: impl PgExecutor<'_>) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!("DELETE FROM users WHERE id=$1", id)
+        .execute(db)
         .await?;
     Ok(result.rows_affected())
 }
@@ -192,6 +258,16 @@ mod tests {
         assert!(super::verify_pw(&clear_pass, &user).await);
     }
 
+    #[test]
+    fn hashes_round_trip() {
+        let stored = hash_password("correct horse");
+        assert!(password_matches("correct horse", &stored));
+        assert!(!password_matches("correct horse ", &stored));
+        assert!(!password_matches("", &stored));
+        // Salted, so the same password never hashes the same twice
+        assert_ne!(stored, hash_password("correct horse"));
+    }
+
     #[sqlx::test]
     async fn create_user() {
         let (pool, _) = pool_init().await;
@@ -0,0 +1,159 @@
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use toml::value::Datetime;
+
+/// Name of the session cookie. The `__Host-` prefix makes browsers refuse it unless it is Secure,
+/// has `Path=/` and no Domain.
+pub const AUTH_COOKIE_NAME: &str = "__Host-jwt";
+/// What the session cookie used to be called. Only read while the migration window is open.
+pub const LEGACY_AUTH_COOKIE_NAME: &str = "Bearer";
+
+/// Where the session token of a request came from
+#[derive(Debug, PartialEq)]
+pub enum FoundToken<'a> {
+    Current(&'a str),
+    /// Only the old cookie was sent. The response should move it to the new name.
+    Legacy(&'a str),
+}
+
+/// Counts sessions moved from the legacy cookie, so operators can tell when that traffic stops
+#[derive(Default)]
+pub struct LegacyMigrations {
+    count: AtomicU64,
+}
+
+impl LegacyMigrations {
+    /// Counts one more migrated session and returns the total so far
+    pub fn record(&self) -> u64 {
+        self.count.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+/// Picks the session token out of the request cookies. The current name always wins; the legacy
+/// name is only looked at while `legacy_allowed`.
+pub fn find_token<'a>(
+    cookies: &BTreeMap<&'a str, &'a str>,
+    legacy_allowed: bool,
+) -> Option<FoundToken<'a>> {
+    if let Some(token) = cookies.get(AUTH_COOKIE_NAME) {
+        return Some(FoundToken::Current(token));
+    }
+    if legacy_allowed {
+        return cookies
+            .get(LEGACY_AUTH_COOKIE_NAME)
+            .map(|token| FoundToken::Legacy(token));
+    }
+    None
+}
+
+/// Whether the legacy cookie is still accepted at `now`. Without a configured end date it isn't.
+pub fn legacy_window_open(until: Option<&Datetime>, now: SystemTime) -> bool {
+    let Some(until) = until.and_then(datetime_to_unix) else {
+        return false;
+    };
+    now.duration_since(UNIX_EPOCH)
+        .is_ok_and(|now| now.as_secs() < until)
+}
+
+/// Set-Cookie value storing a session token under the current name
+pub fn session_cookie(token: &str) -> String {
+    format!("{AUTH_COOKIE_NAME}={token}; Path=/; Secure; HttpOnly; SameSite=Lax")
+}
+
+/// Set-Cookie value telling the browser to drop a cookie
+pub fn expired_cookie(name: &str) -> String {
+    format!("{name}=; Max-Age=0; Path=/; Secure; HttpOnly")
+}
+
+/// Seconds since the epoch for a TOML date or date-time. A bare date means midnight UTC and a
+/// missing offset is read as UTC.
+fn datetime_to_unix(datetime: &Datetime) -> Option<u64> {
+    let date = datetime.date?;
+    let (hour, minute, second) = datetime
+        .time
+        .map(|time| (time.hour, time.minute, time.second))
+        .unwrap_or((0, 0, 0));
+    let offset_minutes = match datetime.offset {
+        Some(toml::value::Offset::Custom { minutes }) => i64::from(minutes),
+        _ => 0,
+    };
+
+    // Days since 1970-01-01 in the proleptic Gregorian calendar
+    let (year, month) = if date.month <= 2 {
+        (i64::from(date.year) - 1, i64::from(date.month) + 9)
+    } else {
+        (i64::from(date.year), i64::from(date.month) - 3)
+    };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * month + 2) / 5 + i64::from(date.day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let seconds =
+        days * 86_400 + i64::from(hour) * 3_600 + i64::from(minute) * 60 + i64::from(second)
+            - offset_minutes * 60;
+    u64::try_from(seconds).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn cookies<'a>(pairs: &[(&'a str, &'a str)]) -> BTreeMap<&'a str, &'a str> {
+        pairs.iter().copied().collect()
+    }
+
+    fn date(text: &str) -> Datetime {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn picks_cookie_by_name() {
+        let old_only = cookies(&[(LEGACY_AUTH_COOKIE_NAME, "old")]);
+        let new_only = cookies(&[(AUTH_COOKIE_NAME, "new")]);
+        let both = cookies(&[(LEGACY_AUTH_COOKIE_NAME, "old"), (AUTH_COOKIE_NAME, "new")]);
+
+        assert_eq!(find_token(&old_only, true), Some(FoundToken::Legacy("old")));
+        assert_eq!(
+            find_token(&new_only, true),
+            Some(FoundToken::Current("new"))
+        );
+        assert_eq!(find_token(&both, true), Some(FoundToken::Current("new")));
+        assert_eq!(find_token(&old_only, false), None);
+        assert_eq!(find_token(&both, false), Some(FoundToken::Current("new")));
+    }
+
+    #[test]
+    fn window_closes_at_configured_date() {
+        let until = date("2026-11-01");
+        let end = UNIX_EPOCH + Duration::from_secs(1_793_491_200);
+
+        assert!(legacy_window_open(
+            Some(&until),
+            end - Duration::from_secs(1)
+        ));
+        assert!(!legacy_window_open(Some(&until), end));
+        assert!(!legacy_window_open(None, UNIX_EPOCH));
+    }
+
+    #[test]
+    fn converts_toml_dates() {
+        assert_eq!(datetime_to_unix(&date("1970-01-01")), Some(0));
+        assert_eq!(datetime_to_unix(&date("2000-03-01")), Some(951_868_800));
+        assert_eq!(
+            datetime_to_unix(&date("2024-02-29T12:30:00Z")),
+            Some(1_709_209_800)
+        );
+        assert_eq!(
+            datetime_to_unix(&date("2024-02-29T14:30:00+02:00")),
+            Some(1_709_209_800)
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use core::str;
-use std::{fmt::Display, str::FromStr};
+use std::fmt::Display;
 
 use askama::Result;
 use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
@@ -145,10 +145,7 @@ impl Jwt {
     /// Creates a JWT object from a base64 string. This is *NOT* the implementation for the FromStr trait
     /// because it returns a tuple with the calculated signature for convience when comparing with
     /// the signature in the provided JWT
-    pub fn from_str_secret(
-        token: &str,
-        secret: &str,
-    ) -> Result<(Self, String), impl serde::de::Error> {
+    pub fn from_str_secret(token: &str, secret: &str) -> Result<(Self, String), JwtError> {
         let parts: Vec<&str> = token.split_terminator('.').collect();
         if parts.len() != 3 {
             return Err(JwtError::IncorrectLength);
@@ -156,19 +153,17 @@ impl Jwt {
 
         let mut test_hash: HmacSha256 =
             HmacSha256::new_from_slice(secret.as_bytes()).expect("Error setting secret key");
-        test_hash.update(format!("{{{}}}.{{{}}}", parts[0], parts[1]).as_bytes());
+        test_hash.update(format!("{}.{}", parts[0], parts[1]).as_bytes());
+        let test_hash = hex::encode(test_hash.finalize().into_bytes());
+        let provided_hash = String::from(parts[2]);
 
-        let Ok(test_hash) = String::from_utf8(test_hash.finalize().into_bytes().to_vec()) else {
+        let Ok(header_decoded) = STANDARD_NO_PAD.decode(parts[0]) else {
             return Err(JwtError::ParsingError);
         };
-        let provided_hash = String::from(parts[2]);
-
-        let header_decoded = STANDARD_NO_PAD.decode(parts[0]).unwrap();
-        let header: JwtHeader =
-            match serde_json::from_str(str::from_utf8(header_decoded.as_slice()).unwrap()) {
-                Ok(val) => val,
-                Err(e) => return Err(JwtError::SerdeError(e.to_string())),
-            };
+        let header: JwtHeader = match serde_json::from_slice(header_decoded.as_slice()) {
+            Ok(val) => val,
+            Err(e) => return Err(JwtError::SerdeError(e.to_string())),
+        };
         let Ok(payload_decoded) = STANDARD_NO_PAD.decode(parts[1]) else {
             return Err(JwtError::ParsingError);
         };
@@ -189,6 +184,25 @@ impl Jwt {
         return Ok((supplied_token, test_hash));
     }
 
+    /// Parses a token and checks its signature against the secret, returning
+    /// [JwtError::IncorrectSignature] if it doesn't match.
+    pub fn from_str_verified(token: &str, secret: &str) -> Result<Self, JwtError> {
+        let (token, computed_sig) = Self::from_str_secret(token, secret)?;
+        let provided_sig = token.signature.as_deref().unwrap_or_default();
+
+        // Compare every byte so the time taken doesn't leak how much of the signature matched
+        let matches = provided_sig.len() == computed_sig.len()
+            && provided_sig
+                .bytes()
+                .zip(computed_sig.bytes())
+                .fold(0, |acc, (provided, computed)| acc | (provided ^ computed))
+                == 0;
+        if !matches {
+            return Err(JwtError::IncorrectSignature);
+        }
+        Ok(token)
+    }
+
     pub fn verify(&self, secret: &str) -> Result<bool, JwtError> {
         let self_sig: String = self.signature.clone().unwrap_or(String::new());
         let finalized = self.finalize(secret);
@@ -204,12 +218,6 @@ impl Jwt {
     }
 }
 
-impl FromStr for Jwt {
-    type Err = JwtError::ParsingError;
-
-    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {}
-}
-
 impl Clone for Jwt {
     fn clone(&self) -> Self {
         Jwt {
@@ -223,6 +231,7 @@ impl Clone for Jwt {
 #[derive(Debug, PartialEq, Deserialize)]
 pub struct JwtHeader {
     alg: SigAlgo,
+    #[serde(rename = "typ")]
     r#type: String,
 }
 
@@ -266,31 +275,68 @@ impl Clone for JwtHeader {
 
 #[derive(Debug, PartialEq, Deserialize)]
 pub struct JwtPayload {
+    #[serde(deserialize_with = "sub_from_str")]
     sub: i64,
     name: String,
     email: String,
     iat: u64,
+    /// When the user signed in. Stays the same when the token is reissued, while `iat` moves up.
+    /// Tokens from before this was added don't have it and count `iat` instead.
+    #[serde(default)]
+    auth_time: Option<u64>,
 }
 
 impl JwtPayload {
-    /// Creates a new payload with a provided subscriber, name, email, and the issued at time.
+    /// Creates a new payload with a provided subscriber, name, email, and the issued at time, for a
+    /// session starting at that time.
     pub fn new(sub: i64, name: String, email: String, iat: u64) -> Self {
         Self {
             sub,
             name,
             email,
             iat,
+            auth_time: Some(iat),
+        }
+    }
+    pub fn sub(&self) -> i64 {
+        self.sub
+    }
+    pub fn iat(&self) -> u64 {
+        self.iat
+    }
+    pub fn auth_time(&self) -> u64 {
+        self.auth_time.unwrap_or(self.iat)
+    }
+    /// The same session, reissued at `now`
+    pub fn refreshed(&self, now: u64) -> Self {
+        JwtPayload {
+            iat: now,
+            auth_time: Some(self.auth_time()),
+            ..self.clone()
         }
     }
 }
 
+/// The subject is written as a string (as the JWT spec asks), so it has to be read back from one
+fn sub_from_str<'de, D>(deserializer: D) -> std::result::Result<i64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let sub = String::deserialize(deserializer)?;
+    sub.parse().map_err(serde::de::Error::custom)
+}
+
 impl Display for JwtPayload {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let sub_pair = format!("\"sub\":\"{}\"", self.sub);
         let name_pair = format!("\"name\":\"{}\"", self.name);
         let email_pair = format!("\"email\":\"{}\"", self.email);
         let iat_pair = format!("\"iat\":{}", self.iat);
-        write!(f, "{{{sub_pair},{name_pair},{email_pair},{iat_pair}}}")
+        write!(f, "{{{sub_pair},{name_pair},{email_pair},{iat_pair}")?;
+        if let Some(auth_time) = self.auth_time {
+            write!(f, ",\"auth_time\":{auth_time}")?;
+        }
+        write!(f, "}}")
     }
 }
 
@@ -301,6 +347,7 @@ impl Clone for JwtPayload {
             name: self.name.clone(),
             email: self.email.clone(),
             iat: self.iat,
+            auth_time: self.auth_time,
         }
     }
 }
@@ -335,6 +382,7 @@ mod tests {
             name,
             email,
             iat,
+            auth_time: Some(iat),
         };
         assert_eq!(control_payload, constructor_payload);
     }
@@ -392,4 +440,54 @@ mod tests {
         token.payload.iat = 182;
         assert_eq!(token.verify(SECRET).unwrap(), false);
     }
+
+    #[test]
+    fn parse_finalized_token() {
+        let payload = JwtPayload::new(
+            143,
+            String::from("John"),
+            String::from("test@example.com"),
+            1700000000,
+        );
+        let token = Jwt::new(JwtHeader::defaults(), payload.clone()).finalize(SECRET);
+
+        let parsed = Jwt::from_str_verified(&token, SECRET).unwrap();
+        assert_eq!(parsed.payload(), &payload);
+        assert_eq!(parsed.header(), &JwtHeader::defaults());
+
+        assert_eq!(
+            Jwt::from_str_verified(&token, "Sad Test"),
+            Err(JwtError::IncorrectSignature)
+        );
+        let (head, _) = token.rsplit_once('.').unwrap();
+        let tampered = format!("{head}.{}", "0".repeat(64));
+        assert_eq!(
+            Jwt::from_str_verified(&tampered, SECRET),
+            Err(JwtError::IncorrectSignature)
+        );
+    }
+
+    #[test]
+    fn refreshing_keeps_the_sign_in_time() {
+        let payload = JwtPayload::new(
+            143,
+            String::from("John"),
+            String::from("test@example.com"),
+            1700000000,
+        );
+        let refreshed = payload.refreshed(1700000600);
+        assert_eq!(refreshed.iat(), 1700000600);
+        assert_eq!(refreshed.auth_time(), 1700000000);
+
+        let token = Jwt::new(JwtHeader::defaults(), refreshed.clone()).finalize(SECRET);
+        let parsed = Jwt::from_str_verified(&token, SECRET).unwrap();
+        assert_eq!(parsed.payload(), &refreshed);
+
+        // Issued before auth_time existed
+        let old: JwtPayload = serde_json::from_str(
+            r#"{"sub":"143","name":"John","email":"test@example.com","iat":1690000000}"#,
+        )
+        .unwrap();
+        assert_eq!(old.auth_time(), 1690000000);
+    }
 }
@@ -0,0 +1,98 @@
+use crate::preferences::SessionPrefs;
+
+use super::jwt::JwtPayload;
+
+/// Tokens older than this are reissued on the next request, which is how activity is recorded.
+/// Idle time is measured from the last reissue, so it can be up to this much early.
+const REFRESH_AFTER_SECS: u64 = 60;
+
+/// What to do with a session token presented at a given time
+#[derive(Debug, PartialEq)]
+pub enum SessionState {
+    Active,
+    /// Still valid, but the client should get a reissued token recording this request
+    Refresh,
+    /// Signed out after going unused for the idle timeout
+    Idle,
+    /// Signed out for reaching the absolute lifetime
+    Expired,
+}
+
+/// Checks a session against both limits at `now`, in seconds since the epoch. The absolute
+/// lifetime wins over everything, so reissuing can't keep a session alive past it.
+pub fn check(payload: &JwtPayload, prefs: &SessionPrefs, now: u64) -> SessionState {
+    let age = now.saturating_sub(payload.auth_time());
+    let unused = now.saturating_sub(payload.iat());
+    if age >= prefs.absolute_lifetime().as_secs() {
+        SessionState::Expired
+    } else if unused >= prefs.idle_timeout().as_secs() {
+        SessionState::Idle
+    } else if unused >= REFRESH_AFTER_SECS {
+        SessionState::Refresh
+    } else {
+        SessionState::Active
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: u64 = 60;
+    const SIGNED_IN: u64 = 1_700_000_000;
+
+    fn prefs() -> SessionPrefs {
+        toml::from_str("idle_timeout_mins = 30\nabsolute_lifetime_mins = 720").unwrap()
+    }
+
+    fn signed_in() -> JwtPayload {
+        JwtPayload::new(7, String::from("name"), String::from("email"), SIGNED_IN)
+    }
+
+    #[test]
+    fn unused_sessions_go_idle() {
+        let session = signed_in();
+        assert_eq!(check(&session, &prefs(), SIGNED_IN), SessionState::Active);
+        assert_eq!(
+            check(&session, &prefs(), SIGNED_IN + 29 * MINUTE),
+            SessionState::Refresh
+        );
+        assert_eq!(
+            check(&session, &prefs(), SIGNED_IN + 30 * MINUTE),
+            SessionState::Idle
+        );
+    }
+
+    #[test]
+    fn activity_keeps_sessions_alive() {
+        let mut session = signed_in();
+        let mut now = SIGNED_IN;
+        // A request every 20 minutes for 10 hours
+        for _ in 0..30 {
+            now += 20 * MINUTE;
+            assert_eq!(check(&session, &prefs(), now), SessionState::Refresh);
+            session = session.refreshed(now);
+            assert_eq!(check(&session, &prefs(), now), SessionState::Active);
+        }
+        assert_eq!(session.auth_time(), SIGNED_IN);
+    }
+
+    #[test]
+    fn absolute_lifetime_overrides_activity() {
+        let mut session = signed_in();
+        let mut now = SIGNED_IN;
+        while now < SIGNED_IN + 12 * 60 * MINUTE - 10 * MINUTE {
+            now += 10 * MINUTE;
+            if check(&session, &prefs(), now) == SessionState::Refresh {
+                session = session.refreshed(now);
+            }
+        }
+        now += 10 * MINUTE;
+        assert_eq!(check(&session, &prefs(), now), SessionState::Expired);
+        // Expired wins even when the session also went idle
+        assert_eq!(
+            check(&session, &prefs(), now + 60 * MINUTE),
+            SessionState::Expired
+        );
+    }
+}
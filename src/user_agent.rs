@@ -0,0 +1,208 @@
+use std::sync::OnceLock;
+
+use axum::http::{header::USER_AGENT, HeaderMap};
+use regex::Regex;
+
+/// Lowercase fragments of user agents that belong to crawlers, link unfurlers and scripts rather
+/// than people
+const BOT_PATTERNS: [&str; 21] = [
+    "bot",
+    "crawler",
+    "spider",
+    "slurp",
+    "facebookexternalhit",
+    "embedly",
+    "preview",
+    "whatsapp",
+    "skypeuripreview",
+    "headless",
+    "curl",
+    "wget",
+    "python-requests",
+    "go-http-client",
+    "node-fetch",
+    "libwww-perl",
+    "mastodon",
+    "iframely",
+    "vkshare",
+    "inspectiontool",
+    "lighthouse",
+];
+
+/// Fragments added to [BOT_PATTERNS] by the preferences, lowercased
+static EXTRA_PATTERNS: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Adds `patterns` to the user agents counted as bots. Call it once at startup; later calls are
+/// ignored.
+pub fn load_bot_patterns(patterns: &[String]) {
+    let patterns = patterns
+        .iter()
+        .map(|pattern| pattern.trim().to_lowercase())
+        .filter(|pattern| !pattern.is_empty())
+        .collect();
+    let _ = EXTRA_PATTERNS.set(patterns);
+}
+
+pub fn user_agent(headers: &HeaderMap) -> Option<&str> {
+    headers.get(USER_AGENT)?.to_str().ok()
+}
+
+/// Whether the user agent looks like an automated client. Requests without a user agent aren't
+/// counted as bots.
+pub fn is_bot(headers: &HeaderMap) -> bool {
+    let Some(agent) = user_agent(headers) else {
+        return false;
+    };
+    is_bot_agent(agent, EXTRA_PATTERNS.get().map_or(&[], Vec::as_slice))
+}
+
+/// Whether `agent` contains one of [BOT_PATTERNS] or of `extra`, ignoring case
+fn is_bot_agent(agent: &str, extra: &[String]) -> bool {
+    let agent = agent.to_lowercase();
+    BOT_PATTERNS.iter().any(|pattern| agent.contains(pattern))
+        || extra.iter().any(|pattern| agent.contains(pattern.as_str()))
+}
+
+/// A rough guess at what kind of client a request came from, for the stats. Tablets count as
+/// mobile.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Device {
+    Desktop,
+    Mobile,
+    Bot,
+    /// No user agent, or one that doesn't say
+    Other,
+}
+
+impl Device {
+    pub const ALL: [Device; 4] = [Device::Desktop, Device::Mobile, Device::Bot, Device::Other];
+
+    /// How the class is stored and shown
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Device::Desktop => "desktop",
+            Device::Mobile => "mobile",
+            Device::Bot => "bot",
+            Device::Other => "other",
+        }
+    }
+
+    pub fn classify(headers: &HeaderMap) -> Self {
+        static MOBILE: OnceLock<Regex> = OnceLock::new();
+        static DESKTOP: OnceLock<Regex> = OnceLock::new();
+        let Some(agent) = user_agent(headers) else {
+            return Device::Other;
+        };
+        let mobile = MOBILE.get_or_init(|| {
+            Regex::new(r"(?i)mobi|android|iphone|ipad|ipod|windows phone|blackberry|opera mini")
+                .expect("Error creating Regex match")
+        });
+        let desktop = DESKTOP.get_or_init(|| {
+            Regex::new(r"(?i)windows nt|macintosh|mac os x|x11|linux|cros")
+                .expect("Error creating Regex match")
+        });
+        if is_bot(headers) {
+            Device::Bot
+        } else if mobile.is_match(agent) {
+            Device::Mobile
+        } else if desktop.is_match(agent) {
+            Device::Desktop
+        } else {
+            Device::Other
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn headers_with(agent: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(USER_AGENT, HeaderValue::from_str(agent).unwrap());
+        headers
+    }
+
+    #[test]
+    fn detects_crawlers() {
+        for agent in [
+            "Slackbot-LinkExpanding 1.0 (+https://api.slack.com/robots)",
+            "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+            "facebookexternalhit/1.1",
+            "curl/8.5.0",
+            "Mozilla/5.0 (compatible; bingbot/2.0; +http://www.bing.com/bingbot.htm)",
+            "Twitterbot/1.0",
+            "LinkedInBot/1.0 (compatible; Mozilla/5.0; Apache-HttpClient +http://www.linkedin.com)",
+            "Mozilla/5.0 (compatible; Discordbot/2.0; +https://discordapp.com)",
+            "TelegramBot (like TwitterBot)",
+            "Mastodon/4.3.0 (http.rb/5.2.0; +https://mastodon.social/)",
+            "Mozilla/5.0 (compatible; DuckDuckBot-Https/1.1; https://duckduckgo.com/duckduckbot)",
+            "Mozilla/5.0 (compatible; YandexBot/3.0; +http://yandex.com/bots)",
+            "Mozilla/5.0 (compatible; Yahoo! Slurp; http://help.yahoo.com/help/us/ysearch/slurp)",
+            "WhatsApp/2.23.20.0",
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) HeadlessChrome/130.0.0.0 Safari/537.36",
+            "python-requests/2.32.3",
+        ] {
+            assert!(is_bot(&headers_with(agent)), "{agent}");
+        }
+    }
+
+    #[test]
+    fn preferences_add_patterns() {
+        let agent = "Mozilla/5.0 (compatible; Uptime-Kuma/1.23.0)";
+        assert!(!is_bot_agent(agent, &[]));
+        assert!(is_bot_agent(agent, &["uptime-kuma".to_string()]));
+        assert!(!is_bot_agent(
+            "Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0",
+            &["uptime-kuma".to_string()]
+        ));
+    }
+
+    #[test]
+    fn browsers_are_not_bots() {
+        let firefox = "Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0";
+        assert!(!is_bot(&headers_with(firefox)));
+        assert!(!is_bot(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn classifies_devices() {
+        for (agent, device) in [
+            (
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/130.0.0.0 Safari/537.36",
+                Device::Desktop,
+            ),
+            (
+                "Mozilla/5.0 (Macintosh; Intel Mac OS X 14_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/18.0 Safari/605.1.15",
+                Device::Desktop,
+            ),
+            (
+                "Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0",
+                Device::Desktop,
+            ),
+            (
+                "Mozilla/5.0 (iPhone; CPU iPhone OS 18_0 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/18.0 Mobile/15E148 Safari/604.1",
+                Device::Mobile,
+            ),
+            (
+                "Mozilla/5.0 (Linux; Android 14; Pixel 8) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/130.0.0.0 Mobile Safari/537.36",
+                Device::Mobile,
+            ),
+            (
+                "Mozilla/5.0 (iPad; CPU OS 17_6 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko)",
+                Device::Mobile,
+            ),
+            (
+                "Mozilla/5.0 (Linux; Android 14) AppleWebKit/537.36 (KHTML, like Gecko; compatible; Googlebot/2.1)",
+                Device::Bot,
+            ),
+            ("curl/8.5.0", Device::Bot),
+            ("SomeApp/1.0", Device::Other),
+        ] {
+            assert_eq!(Device::classify(&headers_with(agent)), device, "{agent}");
+        }
+        assert_eq!(Device::classify(&HeaderMap::new()), Device::Other);
+    }
+}
@@ -0,0 +1,686 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use rand::{distributions::Alphanumeric, thread_rng, Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::{PgConnection, PgPool};
+use zeroize::Zeroizing;
+
+use crate::{
+    link_template::{self, CreateError, NewTemplate},
+    preferences::Preferences,
+    resolve::RedirectStatus,
+    tags,
+    url_db::{self, OwnerFilter, UrlCreateError, UrlOptions, UserRow, WeightedUrl},
+    user,
+};
+
+/// Starts every archive, with the version of the format
+const MAGIC: &[u8; 8] = b"USRARCH1";
+/// PBKDF2-HMAC-SHA256 rounds the key of new archives is derived with. Archives record their own,
+/// so this can go up without breaking older ones.
+const PBKDF2_ROUNDS: u32 = 600_000;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+/// The magic, the rounds, the salt and the nonce. Authenticated along with the contents, so none
+/// of it can be changed without the archive failing to open.
+const HEADER_LEN: usize = MAGIC.len() + 4 + SALT_LEN + NONCE_LEN;
+/// Length of the passwords made for users whose password hash couldn't be carried over
+const NEW_PASSWORD_LEN: usize = 20;
+
+/// A user with everything they own, to move them to another instance: their links with tags,
+/// destinations and click counts, and their link templates with the clicks on each value.
+/// Sessions aren't stored, so none are carried and the user signs in again.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UserArchive {
+    pub username: String,
+    pub email: String,
+    /// `salt#hash`, see [user::hash_password]
+    pub hashed_pw: String,
+    pub exported_at: DateTime<Utc>,
+    pub links: Vec<ArchivedLink>,
+    pub templates: Vec<ArchivedTemplate>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ArchivedLink {
+    pub code: String,
+    pub long_url: String,
+    pub submitted_url: Option<String>,
+    pub description: Option<String>,
+    pub interstitial_seconds: i16,
+    pub single_use: bool,
+    pub consumed_at: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub active_from: Option<DateTime<Utc>>,
+    pub max_clicks: Option<i64>,
+    pub passphrase_hash: Option<String>,
+    pub redirect_status: Option<RedirectStatus>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub archived_at: Option<DateTime<Utc>>,
+    pub rollout_until: Option<DateTime<Utc>>,
+    /// Including those still in the shards of a hot link
+    pub clicks: i64,
+    pub bot_clicks: i64,
+    pub tags: Vec<String>,
+    pub destinations: Vec<ArchivedDestination>,
+    /// Destinations by country code
+    pub geo_targets: BTreeMap<String, String>,
+    pub daily_clicks: BTreeMap<NaiveDate, i64>,
+    pub referrers: BTreeMap<String, i64>,
+    pub devices: BTreeMap<String, i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ArchivedDestination {
+    pub long_url: String,
+    pub weight: i32,
+    pub clicks: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ArchivedTemplate {
+    /// As it was written, e.g. `inv-{customer}`
+    pub alias: String,
+    pub destination: String,
+    pub value_pattern: Option<String>,
+    pub max_value_len: i32,
+    pub clicks: i64,
+    pub value_clicks: BTreeMap<String, i64>,
+}
+
+/// Reads everything `username` owns. Read in one snapshot, so clicks counted meanwhile are either
+/// in every count or in none.
+pub async fn export(username: &str, pool: &PgPool) -> Result<UserArchive, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ")
+        .execute(&mut *tx)
+        .await?;
+    let user = user::retrieve_user_by_name(username, &mut *tx).await?;
+    let rows = url_db::retrieve_urls_by_owner(OwnerFilter::User(*user.id()), &mut *tx).await?;
+    let ids: Vec<i64> = rows.iter().map(|row| row.id()).collect();
+    let mut tags = tags::for_links(&ids, &mut *tx).await?;
+    let mut daily: BTreeMap<i64, BTreeMap<NaiveDate, i64>> = BTreeMap::new();
+    for row in sqlx::query!(
+        "SELECT url_id, day, count FROM url_clicks_daily WHERE url_id = ANY($1)",
+        &ids
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    {
+        daily
+            .entry(row.url_id)
+            .or_default()
+            .insert(row.day, row.count);
+    }
+    let mut referrers: BTreeMap<i64, BTreeMap<String, i64>> = BTreeMap::new();
+    for row in sqlx::query!(
+        "SELECT url_id, referrer, count FROM url_referrers WHERE url_id = ANY($1)",
+        &ids
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    {
+        referrers
+            .entry(row.url_id)
+            .or_default()
+            .insert(row.referrer, row.count);
+    }
+    let mut devices: BTreeMap<i64, BTreeMap<String, i64>> = BTreeMap::new();
+    for row in sqlx::query!(
+        "SELECT url_id, device, count FROM url_devices WHERE url_id = ANY($1)",
+        &ids
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    {
+        devices
+            .entry(row.url_id)
+            .or_default()
+            .insert(row.device, row.count);
+    }
+
+    let mut links = Vec::with_capacity(rows.len());
+    for row in &rows {
+        let id = row.id();
+        let destinations = url_db::destinations(id, &mut *tx)
+            .await?
+            .into_iter()
+            .map(|destination| ArchivedDestination {
+                long_url: destination.long_url,
+                weight: destination.weight,
+                clicks: destination.clicks,
+            })
+            .collect();
+        links.push(ArchivedLink {
+            code: row.clone_short_url(),
+            long_url: row.long_url().clone(),
+            submitted_url: row.submitted_url().map(String::from),
+            description: row.description().map(String::from),
+            interstitial_seconds: row.interstitial_seconds(),
+            single_use: row.single_use(),
+            consumed_at: row.consumed_at(),
+            expires_at: row.expires_at(),
+            active_from: row.active_from(),
+            max_clicks: row.max_clicks(),
+            passphrase_hash: row.passphrase_hash().map(String::from),
+            redirect_status: row.redirect_status(),
+            created_at: row.created_at(),
+            archived_at: row.deleted_at(),
+            rollout_until: row.rollout_until(),
+            clicks: row.clicks(),
+            bot_clicks: row.bot_clicks(),
+            tags: tags.remove(&id).unwrap_or_default(),
+            destinations,
+            geo_targets: url_db::geo_targets(id, &mut *tx).await?,
+            daily_clicks: daily.remove(&id).unwrap_or_default(),
+            referrers: referrers.remove(&id).unwrap_or_default(),
+            devices: devices.remove(&id).unwrap_or_default(),
+        });
+    }
+
+    let template_rows = sqlx::query!(
+        "SELECT id, prefix, placeholder, suffix, destination, value_pattern, max_value_len, clicks
+        FROM url_templates WHERE owner = $1 ORDER BY id",
+        user.id()
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let template_ids: Vec<i64> = template_rows.iter().map(|row| row.id).collect();
+    let mut value_clicks: BTreeMap<i64, BTreeMap<String, i64>> = BTreeMap::new();
+    for row in sqlx::query!(
+        "SELECT template_id, value, clicks FROM url_template_clicks WHERE template_id = ANY($1)",
+        &template_ids
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    {
+        value_clicks
+            .entry(row.template_id)
+            .or_default()
+            .insert(row.value, row.clicks);
+    }
+    let templates = template_rows
+        .into_iter()
+        .map(|row| ArchivedTemplate {
+            alias: format!("{}{{{}}}{}", row.prefix, row.placeholder, row.suffix),
+            destination: row.destination,
+            value_pattern: row.value_pattern,
+            max_value_len: row.max_value_len,
+            clicks: row.clicks,
+            value_clicks: value_clicks.remove(&row.id).unwrap_or_default(),
+        })
+        .collect();
+    tx.commit().await?;
+
+    Ok(UserArchive {
+        username: user.username().clone(),
+        email: user.email().clone(),
+        hashed_pw: user.hashed_pw().clone(),
+        exported_at: Utc::now(),
+        links,
+        templates,
+    })
+}
+
+/// The AES-256-GCM key for `passphrase`
+fn key(passphrase: &str, salt: &[u8], rounds: u32) -> Zeroizing<[u8; 32]> {
+    let mut key = Zeroizing::new([0; 32]);
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, rounds, key.as_mut());
+    key
+}
+
+/// Encrypts `archive` with a key derived from `passphrase`, under a fresh salt and nonce
+pub fn seal(archive: &UserArchive, passphrase: &str) -> Vec<u8> {
+    seal_with(archive, passphrase, PBKDF2_ROUNDS)
+}
+
+fn seal_with(archive: &UserArchive, passphrase: &str, rounds: u32) -> Vec<u8> {
+    let mut salt = [0; SALT_LEN];
+    let mut nonce = [0; NONCE_LEN];
+    thread_rng().fill_bytes(&mut salt);
+    thread_rng().fill_bytes(&mut nonce);
+    let mut sealed = Vec::with_capacity(HEADER_LEN);
+    sealed.extend_from_slice(MAGIC);
+    sealed.extend_from_slice(&rounds.to_be_bytes());
+    sealed.extend_from_slice(&salt);
+    sealed.extend_from_slice(&nonce);
+
+    let contents = Zeroizing::new(serde_json::to_vec(archive).expect("Archives always serialize"));
+    let key = key(passphrase, &salt, rounds);
+    let encrypted = Aes256Gcm::new(key.as_ref().into())
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &contents,
+                aad: &sealed,
+            },
+        )
+        .expect("Archives are far smaller than AES-GCM's limit");
+    sealed.extend_from_slice(&encrypted);
+    sealed
+}
+
+#[derive(Debug)]
+pub enum OpenError {
+    /// Not something [seal] made
+    NotAnArchive,
+    /// The passphrase is wrong, or the archive was changed since it was made
+    Undecryptable,
+    /// Decrypted, but not an archive this version can read
+    Unreadable(serde_json::Error),
+}
+
+impl Display for OpenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OpenError::NotAnArchive => f.write_str("Not a user archive"),
+            OpenError::Undecryptable => {
+                f.write_str("Wrong passphrase, or the archive was changed since it was made")
+            }
+            OpenError::Unreadable(e) => write!(f, "Couldn't read the archive: {e}"),
+        }
+    }
+}
+
+/// Decrypts an archive [seal] made with `passphrase`
+pub fn open(sealed: &[u8], passphrase: &str) -> Result<UserArchive, OpenError> {
+    if sealed.len() < HEADER_LEN || !sealed.starts_with(MAGIC) {
+        return Err(OpenError::NotAnArchive);
+    }
+    let (header, encrypted) = sealed.split_at(HEADER_LEN);
+    let (rounds, rest) = header[MAGIC.len()..].split_at(4);
+    let (salt, nonce) = rest.split_at(SALT_LEN);
+    let rounds = u32::from_be_bytes(rounds.try_into().expect("Split at 4 bytes"));
+    let key = key(passphrase, salt, rounds);
+    let contents = Aes256Gcm::new(key.as_ref().into())
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: encrypted,
+                aad: header,
+            },
+        )
+        .map(Zeroizing::new)
+        .map_err(|_| OpenError::Undecryptable)?;
+    serde_json::from_slice(&contents).map_err(OpenError::Unreadable)
+}
+
+/// What to do when the archive's username or email is already some user's here
+#[derive(Debug, Clone, PartialEq)]
+pub enum OnConflict {
+    /// Give up without importing anything
+    Refuse,
+    /// Add the links and templates to that user, leaving the user as it is
+    Merge,
+    /// Import the user under this name instead, which has to be free
+    Rename(String),
+}
+
+/// How an import went
+#[derive(Debug, PartialEq)]
+pub struct Imported {
+    pub username: String,
+    pub user_id: i64,
+    /// Whether the user was made, rather than merged into
+    pub created: bool,
+    pub links: usize,
+    /// The links whose code was taken here, with the code each got instead
+    pub recoded: Vec<(String, String)>,
+    pub templates: usize,
+    /// Templates that weren't imported, with why
+    pub skipped_templates: Vec<(String, String)>,
+    /// Made up for a new user whose password hash couldn't be carried over, or when asked to
+    pub password: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    /// The user with this name or email is already here, and the import was told to refuse
+    Conflict(String),
+    /// The name to rename the user to is taken
+    RenameTaken(String),
+    /// No new code could be found for a link whose code was taken
+    CodesExhausted(String),
+    Database(sqlx::Error),
+}
+
+impl Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportError::Conflict(username) => write!(
+                f,
+                "User `{username}` already has that name or email here. Run again with --merge to add the links to it, or --rename <name> to import as a new user."
+            ),
+            ImportError::RenameTaken(username) => {
+                write!(f, "User `{username}` already exists here")
+            }
+            ImportError::CodesExhausted(code) => write!(
+                f,
+                "`{code}` is taken here and no free code could be found for it"
+            ),
+            ImportError::Database(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<sqlx::Error> for ImportError {
+    fn from(e: sqlx::Error) -> Self {
+        ImportError::Database(e)
+    }
+}
+
+/// Stores `archive` as a user of this instance, in one transaction so a failed import leaves
+/// nothing behind. Ids are this instance's own. Links keep their codes unless taken here, in which
+/// case they get new ones made by the instance's rules, reported in [Imported::recoded]. Templates
+/// overlapping one here are left out. `new_password` makes up a password for a new user rather than
+/// carrying the hash over.
+pub async fn import(
+    archive: &UserArchive,
+    on_conflict: &OnConflict,
+    new_password: bool,
+    prefs: &Preferences,
+    pool: &PgPool,
+) -> Result<Imported, ImportError> {
+    let mut tx = pool.begin().await?;
+    let existing = sqlx::query_as!(
+        UserRow,
+        "SELECT id, username, hashed_pw, email FROM users WHERE username = $1 OR email = $2
+        ORDER BY username = $1 DESC, id LIMIT 1",
+        archive.username,
+        archive.email
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let (user, created, password) = match (existing, on_conflict) {
+        (Some(existing), OnConflict::Refuse) => {
+            return Err(ImportError::Conflict(existing.username().clone()))
+        }
+        (Some(existing), OnConflict::Merge) => (existing, false, None),
+        (_, OnConflict::Rename(username)) => {
+            if user::retrieve_user_by_name(username, &mut *tx)
+                .await
+                .is_ok()
+            {
+                return Err(ImportError::RenameTaken(username.clone()));
+            }
+            let (user, password) = add_user(archive, username, new_password, &mut tx).await?;
+            (user, true, password)
+        }
+        (None, _) => {
+            let (user, password) =
+                add_user(archive, &archive.username, new_password, &mut tx).await?;
+            (user, true, password)
+        }
+    };
+    let owner = *user.id();
+
+    let mut templates = 0;
+    let mut skipped_templates = Vec::new();
+    for template in &archive.templates {
+        match add_template(owner, template, &mut tx).await? {
+            Ok(()) => templates += 1,
+            Err(reason) => skipped_templates.push((template.alias.clone(), reason)),
+        }
+    }
+    let mut recoded = Vec::new();
+    for link in &archive.links {
+        let code = add_link(owner, link, prefs, &mut tx).await?;
+        if code != link.code {
+            recoded.push((link.code.clone(), code));
+        }
+    }
+    tx.commit().await?;
+
+    Ok(Imported {
+        username: user.username().clone(),
+        user_id: owner,
+        created,
+        links: archive.links.len(),
+        recoded,
+        templates,
+        skipped_templates,
+        password,
+    })
+}
+
+/// Adds the archive's user as `username`, with the password hash from the archive when it is one
+/// this instance reads and `new_password` isn't set, or with a new password that is returned
+async fn add_user(
+    archive: &UserArchive,
+    username: &str,
+    new_password: bool,
+    conn: &mut PgConnection,
+) -> Result<(UserRow, Option<String>), sqlx::Error> {
+    let (hashed_pw, password) = if new_password || !archive.hashed_pw.contains('#') {
+        let password: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(NEW_PASSWORD_LEN)
+            .map(char::from)
+            .collect();
+        (user::hash_password(&password), Some(password))
+    } else {
+        (archive.hashed_pw.clone(), None)
+    };
+    let mut user = UserRow::new(-1, username.to_string(), hashed_pw, archive.email.clone());
+    let id = user::add_user_to_db(&user, &mut *conn).await?;
+    user.update_id(id);
+    Ok((user, password))
+}
+
+/// Adds a template and its clicks for `owner`, or answers why it can't be
+async fn add_template(
+    owner: i64,
+    template: &ArchivedTemplate,
+    conn: &mut PgConnection,
+) -> Result<Result<(), String>, sqlx::Error> {
+    let parsed = match NewTemplate::parse(
+        &template.alias,
+        &template.destination,
+        template.value_pattern.as_deref(),
+        Some(template.max_value_len),
+    ) {
+        Ok(parsed) => parsed,
+        Err(problem) => return Ok(Err(problem)),
+    };
+    let created = match link_template::create_template_on(owner, &parsed, &mut *conn).await {
+        Ok(created) => created,
+        Err(CreateError::OverlapsTemplate(other)) => {
+            return Ok(Err(format!("It overlaps the template {other} here")))
+        }
+        Err(CreateError::OverlapsCode(code)) => {
+            return Ok(Err(format!("The link {code} here is one of its codes")))
+        }
+        Err(CreateError::Database(e)) => return Err(e),
+    };
+    let values: Vec<&str> = template.value_clicks.keys().map(String::as_str).collect();
+    let clicks: Vec<i64> = template.value_clicks.values().copied().collect();
+    sqlx::query!(
+        "UPDATE url_templates SET clicks = $2 WHERE id = $1",
+        created.id(),
+        template.clicks
+    )
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO url_template_clicks (template_id, value, clicks)
+        SELECT $1, * FROM UNNEST($2::text[], $3::bigint[])",
+        created.id(),
+        &values as &[&str],
+        &clicks
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(Ok(()))
+}
+
+/// Adds a link and its counts for `owner`, under its own code unless that is taken or reserved
+/// here. Returns the code it got.
+async fn add_link(
+    owner: i64,
+    link: &ArchivedLink,
+    prefs: &Preferences,
+    conn: &mut PgConnection,
+) -> Result<String, ImportError> {
+    let rules = prefs.code_rules();
+    let options = UrlOptions {
+        interstitial_seconds: Some(link.interstitial_seconds),
+        single_use: link.single_use,
+        submitted_url: link.submitted_url.clone(),
+        expires_at: link.expires_at,
+        active_from: link.active_from,
+        max_clicks: link.max_clicks,
+        passphrase_hash: link.passphrase_hash.clone(),
+        redirect_status: link.redirect_status,
+        description: link.description.clone(),
+    };
+    let taken = url_db::is_reserved(&link.code, rules.reserved)
+        || url_db::is_code_taken(&link.code, rules.case, &mut *conn).await?;
+    let row = if taken {
+        url_db::create_url_on(&link.long_url, Some(owner), &mut *conn, rules, &options)
+            .await
+            .map_err(|e| match e {
+                UrlCreateError::Exhausted => ImportError::CodesExhausted(link.code.clone()),
+                UrlCreateError::Database(e) => ImportError::Database(e),
+            })?
+    } else {
+        url_db::create_url_with_alias(
+            &link.code,
+            &link.long_url,
+            Some(owner),
+            &mut *conn,
+            &options,
+        )
+        .await?
+    };
+    let id = row.id();
+
+    if !link.destinations.is_empty() {
+        let weighted: Vec<WeightedUrl> = link
+            .destinations
+            .iter()
+            .map(|destination| WeightedUrl {
+                long_url: destination.long_url.clone(),
+                weight: destination.weight,
+            })
+            .collect();
+        url_db::set_destinations(id, &weighted, &mut *conn).await?;
+        let urls: Vec<&str> = weighted.iter().map(|d| d.long_url.as_str()).collect();
+        let clicks: Vec<i64> = link.destinations.iter().map(|d| d.clicks).collect();
+        sqlx::query!(
+            "UPDATE url_destinations SET clicks = given.clicks
+            FROM UNNEST($2::text[], $3::bigint[]) AS given(longurl, clicks)
+            WHERE url_id = $1 AND url_destinations.longurl = given.longurl",
+            id,
+            &urls as &[&str],
+            &clicks
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+    if !link.geo_targets.is_empty() {
+        url_db::set_geo_targets(id, &link.geo_targets, &mut *conn).await?;
+    }
+    // What setting the destinations changed, and what links are never made with
+    sqlx::query!(
+        "UPDATE urls SET longurl = $2, clicks = $3, bot_clicks = $4, consumed_at = $5,
+            created_at = $6, deleted_at = $7, rollout_until = $8
+        WHERE id = $1",
+        id,
+        link.long_url,
+        link.clicks,
+        link.bot_clicks,
+        link.consumed_at,
+        link.created_at,
+        link.archived_at,
+        link.rollout_until
+    )
+    .execute(&mut *conn)
+    .await?;
+    tags::set(id, &link.tags, &mut *conn).await?;
+
+    let days: Vec<NaiveDate> = link.daily_clicks.keys().copied().collect();
+    let counts: Vec<i64> = link.daily_clicks.values().copied().collect();
+    sqlx::query!(
+        "INSERT INTO url_clicks_daily (url_id, day, count)
+        SELECT $1, * FROM UNNEST($2::date[], $3::bigint[])",
+        id,
+        &days,
+        &counts
+    )
+    .execute(&mut *conn)
+    .await?;
+    let referrers: Vec<&str> = link.referrers.keys().map(String::as_str).collect();
+    let counts: Vec<i64> = link.referrers.values().copied().collect();
+    sqlx::query!(
+        "INSERT INTO url_referrers (url_id, referrer, count)
+        SELECT $1, * FROM UNNEST($2::text[], $3::bigint[])",
+        id,
+        &referrers as &[&str],
+        &counts
+    )
+    .execute(&mut *conn)
+    .await?;
+    let devices: Vec<&str> = link.devices.keys().map(String::as_str).collect();
+    let counts: Vec<i64> = link.devices.values().copied().collect();
+    sqlx::query!(
+        "INSERT INTO url_devices (url_id, device, count)
+        SELECT $1, * FROM UNNEST($2::text[], $3::bigint[])",
+        id,
+        &devices as &[&str],
+        &counts
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(row.clone_short_url())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive() -> UserArchive {
+        UserArchive {
+            username: String::from("mover"),
+            email: String::from("mover@example.com"),
+            hashed_pw: user::hash_password("pw"),
+            exported_at: Utc::now(),
+            links: Vec::new(),
+            templates: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn archives_only_open_with_their_passphrase() {
+        let archive = archive();
+        let sealed = seal_with(&archive, "correct horse", 1000);
+        assert_eq!(open(&sealed, "correct horse").unwrap(), archive);
+        assert!(matches!(
+            open(&sealed, "wrong horse"),
+            Err(OpenError::Undecryptable)
+        ));
+        assert!(matches!(
+            open(b"links.toml", "correct horse"),
+            Err(OpenError::NotAnArchive)
+        ));
+
+        // The header is authenticated too, so fewer rounds can't be slipped in
+        let mut tampered = sealed.clone();
+        tampered[MAGIC.len() + 3] ^= 1;
+        assert!(matches!(
+            open(&tampered, "correct horse"),
+            Err(OpenError::Undecryptable)
+        ));
+        let mut tampered = sealed;
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            open(&tampered, "correct horse"),
+            Err(OpenError::Undecryptable)
+        ));
+    }
+}